@@ -0,0 +1,78 @@
+/*
+ * capture.rs
+ * ----------
+ * Benchmarks of the capture and demux paths on synthetic packets, run with
+ * cargo bench --bench capture
+*/
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rsllm::stream_data::{
+    process_mpegts_packet, process_smpte2110_packet, update_pid_map, TS_PACKET_SIZE,
+};
+use rsllm::ts_generator::{
+    smpte2110_datagram, TsGenerator, TsGeneratorConfig, TS_PACKETS_PER_DATAGRAM,
+};
+use std::sync::Arc;
+
+fn bench_mpegts(c: &mut Criterion) {
+    let mut ts = TsGenerator::new(TsGeneratorConfig::new());
+    let datagrams: Vec<Arc<Vec<u8>>> = (0..64).map(|_| Arc::new(ts.next_datagram())).collect();
+
+    let mut group = c.benchmark_group("demux");
+    group.throughput(Throughput::Bytes(
+        (TS_PACKET_SIZE * TS_PACKETS_PER_DATAGRAM) as u64,
+    ));
+    let mut index = 0;
+    group.bench_function("process_mpegts_packet", |b| {
+        b.iter(|| {
+            index = (index + 1) % datagrams.len();
+            black_box(process_mpegts_packet(
+                0,
+                Arc::clone(&datagrams[index]),
+                TS_PACKET_SIZE,
+                0,
+                0,
+            ))
+        })
+    });
+
+    let datagram = Arc::new(smpte2110_datagram(1, 90000, 1, 1200));
+    group.throughput(Throughput::Bytes(datagram.len() as u64));
+    group.bench_function("process_smpte2110_packet", |b| {
+        b.iter(|| {
+            black_box(process_smpte2110_packet(
+                0,
+                Arc::clone(&datagram),
+                datagram.len(),
+                0,
+                0,
+                false,
+            ))
+        })
+    });
+    group.finish();
+}
+
+fn bench_pid_map(c: &mut Criterion) {
+    let mut ts = TsGenerator::new(TsGeneratorConfig::new());
+    let pat = ts.pat();
+    let pmt = ts.pmt();
+    let datagram = ts.next_datagram();
+
+    c.bench_function("update_pid_map", |b| {
+        b.iter(|| update_pid_map(black_box(&pmt), black_box(&pat)))
+    });
+
+    // the demux looks up the stream types in the pid map filled by the PMT
+    update_pid_map(&pmt, &pat);
+    c.bench_function("process_mpegts_packet_mapped", |b| {
+        b.iter_batched(
+            || Arc::new(datagram.clone()),
+            |packet| black_box(process_mpegts_packet(0, packet, TS_PACKET_SIZE, 0, 0)),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_mpegts, bench_pid_map);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+/*
+ * generation.rs
+ * -------------
+ * Benchmarks of the generation side, paragraph segmentation of a token stream and scaling of
+ * the output frames, run with cargo bench --bench generation
+*/
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use image::{Rgb, RgbImage};
+use rsllm::image_ops::{resize_rgb, rgb_to_rgba};
+use rsllm::segmenter::{
+    ParagraphSegmenter, Segmenter, SemanticSegmenter, SentenceSegmenter, TokenCountSegmenter,
+};
+
+// Tokens shaped like the LLM stream, words with leading spaces and paragraph breaks
+fn synthetic_tokens(paragraphs: usize) -> Vec<String> {
+    let sentences = [
+        "The stream carries a steady video program with a few audio tracks.",
+        "Bitrate stays close to the average while the packets arrive on time.",
+        "A short burst of errors shows up on the audio pid and clears again.",
+        "Viewers in the chat ask about the weather over the mountains tonight.",
+    ];
+    let mut tokens = Vec::new();
+    for paragraph in 0..paragraphs {
+        for sentence in 0..4 {
+            for (i, word) in sentences[(paragraph + sentence) % sentences.len()]
+                .split(' ')
+                .enumerate()
+            {
+                tokens.push(if i == 0 && sentence == 0 {
+                    word.to_string()
+                } else {
+                    format!(" {}", word)
+                });
+            }
+        }
+        tokens.push("\n\n".to_string());
+    }
+    tokens
+}
+
+fn run_segmenter(segmenter: &mut dyn Segmenter, tokens: &[String]) -> usize {
+    for token in tokens {
+        black_box(segmenter.push_token(token));
+    }
+    black_box(segmenter.finish());
+    segmenter.paragraph_count()
+}
+
+fn bench_segmentation(c: &mut Criterion) {
+    let tokens = synthetic_tokens(32);
+    let mut group = c.benchmark_group("segmentation");
+    group.throughput(Throughput::Elements(tokens.len() as u64));
+    group.bench_function("paragraph", |b| {
+        b.iter(|| run_segmenter(&mut ParagraphSegmenter::new(77), &tokens))
+    });
+    group.bench_function("sentence", |b| {
+        b.iter(|| run_segmenter(&mut SentenceSegmenter::new(2, 77), &tokens))
+    });
+    group.bench_function("tokens", |b| {
+        b.iter(|| run_segmenter(&mut TokenCountSegmenter::new(77), &tokens))
+    });
+    group.bench_function("semantic", |b| {
+        b.iter(|| run_segmenter(&mut SemanticSegmenter::new(0.2, 4, 77), &tokens))
+    });
+    group.finish();
+}
+
+fn bench_scaling(c: &mut Criterion) {
+    let image = RgbImage::from_fn(1024, 1024, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    });
+    let frame = RgbImage::from_pixel(1920, 1080, Rgb([16, 32, 64]));
+
+    let mut group = c.benchmark_group("scaling");
+    group.sample_size(20);
+    group.bench_function("resize_rgb_1024_to_1080p", |b| {
+        b.iter(|| resize_rgb(black_box(&image), 1920, 1080))
+    });
+    group.bench_function("resize_rgb_1024_to_512", |b| {
+        b.iter(|| resize_rgb(black_box(&image), 512, 512))
+    });
+    group.bench_function("rgb_to_rgba_1080p", |b| {
+        b.iter(|| rgb_to_rgba(black_box(&frame)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_segmentation, bench_scaling);
+criterion_main!(benches);
@@ -150,7 +150,7 @@ DYLD_LIBRARY_PATH=`pwd`:/usr/local/lib:$DYLD_LIBRARY_PATH \
     $USE_CANDLE_CMD \
     --sd-text-min $SD_TEXT_MIN \
     --sd-max-length $SD_MAX_LENGTH \
-    --llm-history-size $CONTEXT_SIZE \
+    --llm-context-windows "$MODEL=$CONTEXT_SIZE" \
     --chat-format $CHAT_FORMAT \
     --model-id $MODEL_ID \
     --temperature $TEMPERATURE \
@@ -0,0 +1,274 @@
+/*
+ * ad_break.rs
+ * -----------
+ * Ad breaks of the AI channel, following the SCTE-35 splice inserts of the monitored feed or
+ * a schedule of its own. A splice out starts a break for its break duration, the splice back
+ * in or a cancel ends it early. During a break the host is quiet, a be right back card is
+ * shown and the commentary picks up again once the break is over. The break starts when the
+ * splice insert arrives, the pre-roll to its splice time is not waited for.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::event_log::log_event;
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static AD_BREAK: Lazy<Mutex<Option<(AdBreak, Instant)>>> = Lazy::new(|| Mutex::new(None));
+
+const SCTE35_TABLE_ID: u8 = 0xFC;
+const SPLICE_INSERT: u8 = 0x05;
+// Bytes of the splice info section before the splice command
+const SPLICE_COMMAND_OFFSET: usize = 14;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdBreak {
+    pub source: String, // scte35 or schedule
+    pub event_id: Option<u32>,
+    pub duration_secs: f64,
+    pub started_ms: u64,
+}
+
+// The break in progress
+pub fn ad_break() -> Option<AdBreak> {
+    AD_BREAK
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(_, ends)| Instant::now() < *ends)
+        .map(|(ad_break, _)| ad_break.clone())
+}
+
+pub fn start_ad_break(source: &str, event_id: Option<u32>, duration: Duration) {
+    let ad_break = AdBreak {
+        source: source.to_string(),
+        event_id,
+        duration_secs: duration.as_secs_f64(),
+        started_ms: current_unix_timestamp_ms().unwrap_or(0),
+    };
+    info!(
+        "Ad break from the {} for {:.0} seconds",
+        source, ad_break.duration_secs
+    );
+    log_event("control", "ad_break", json!(ad_break));
+    *AD_BREAK.lock().unwrap() = Some((ad_break, Instant::now() + duration));
+}
+
+// End the break early, only the break of the event when an event is given
+pub fn end_ad_break(event_id: Option<u32>) {
+    let mut current = AD_BREAK.lock().unwrap();
+    let ends = current.as_ref().is_some_and(|(ad_break, ends)| {
+        Instant::now() < *ends && (event_id.is_none() || ad_break.event_id == event_id)
+    });
+    if ends {
+        info!("Ad break ended early");
+        log_event("control", "ad_break_end", json!({ "event_id": event_id }));
+        *current = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SpliceInsert {
+    pub event_id: u32,
+    pub cancel: bool,
+    pub out_of_network: bool,
+    pub immediate: bool,
+    pub pts: Option<u64>, // 90 kHz splice time of a program splice, before the PTS adjustment
+    pub duration_ms: Option<u64>,
+    pub auto_return: bool,
+}
+
+// 33 bit time in the 5 bytes of a splice_time or break_duration, None when the flag is off
+fn read_time(data: &[u8]) -> Option<u64> {
+    if data.len() < 5 || data[0] & 0x80 == 0 {
+        return None;
+    }
+    let mut time = (data[0] & 0x01) as u64;
+    for byte in &data[1..5] {
+        time = (time << 8) | *byte as u64;
+    }
+    Some(time)
+}
+
+// Splice insert of an SCTE-35 splice info section, other commands and encrypted sections are
+// left out
+pub fn parse_splice_insert(section: &[u8]) -> Option<SpliceInsert> {
+    if section.len() < SPLICE_COMMAND_OFFSET + 5
+        || section[0] != SCTE35_TABLE_ID
+        || section[4] & 0x80 != 0
+        || section[13] != SPLICE_INSERT
+    {
+        return None;
+    }
+    let command = &section[SPLICE_COMMAND_OFFSET..];
+    let event_id = u32::from_be_bytes([command[0], command[1], command[2], command[3]]);
+    let cancel = command[4] & 0x80 != 0;
+    let mut insert = SpliceInsert {
+        event_id,
+        cancel,
+        out_of_network: false,
+        immediate: false,
+        pts: None,
+        duration_ms: None,
+        auto_return: false,
+    };
+    if cancel {
+        return Some(insert);
+    }
+
+    let flags = *command.get(5)?;
+    insert.out_of_network = flags & 0x80 != 0;
+    let program_splice = flags & 0x40 != 0;
+    let has_duration = flags & 0x20 != 0;
+    insert.immediate = flags & 0x10 != 0;
+    let mut offset = 6;
+    // a splice_time is 5 bytes with the time and 1 without
+    let splice_time_len = |data: &[u8]| if data[0] & 0x80 != 0 { 5 } else { 1 };
+    if program_splice && !insert.immediate {
+        let data = command.get(offset..)?;
+        insert.pts = read_time(data);
+        offset += splice_time_len(data);
+    } else if !program_splice {
+        let components = *command.get(offset)? as usize;
+        offset += 1;
+        for _ in 0..components {
+            offset += 1;
+            if !insert.immediate {
+                offset += splice_time_len(command.get(offset..)?);
+            }
+        }
+    }
+    if has_duration {
+        let data = command.get(offset..offset + 5)?;
+        insert.auto_return = data[0] & 0x80 != 0;
+        let mut duration = (data[0] & 0x01) as u64;
+        for byte in &data[1..5] {
+            duration = (duration << 8) | *byte as u64;
+        }
+        insert.duration_ms = Some(duration / 90);
+    }
+    Some(insert)
+}
+
+// Collects the splice info sections of the SCTE-35 PID from its TS packets
+#[derive(Default)]
+pub struct Scte35Sections {
+    buffer: Vec<u8>,
+}
+
+impl Scte35Sections {
+    pub fn new() -> Self {
+        Scte35Sections::default()
+    }
+
+    // Add a TS packet, the sections completed by it
+    pub fn push(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        if packet.len() < 5 || packet[0] != 0x47 {
+            return Vec::new();
+        }
+        let unit_start = packet[1] & 0x40 != 0;
+        let adaptation = (packet[3] >> 4) & 0x03;
+        if adaptation & 0x01 == 0 {
+            return Vec::new();
+        }
+        let mut offset = 4;
+        if adaptation & 0x02 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+        let Some(payload) = packet.get(offset..) else {
+            return Vec::new();
+        };
+        if unit_start {
+            let Some(&pointer) = payload.first() else {
+                return Vec::new();
+            };
+            let pointer = pointer as usize;
+            // the end of the previous section comes before the pointed to start
+            if let Some(rest) = payload.get(1..1 + pointer) {
+                if !self.buffer.is_empty() {
+                    self.buffer.extend_from_slice(rest);
+                }
+            }
+            let mut sections = self.complete_sections();
+            self.buffer.clear();
+            if let Some(start) = payload.get(1 + pointer..) {
+                self.buffer.extend_from_slice(start);
+            }
+            sections.extend(self.complete_sections());
+            sections
+        } else if !self.buffer.is_empty() {
+            self.buffer.extend_from_slice(payload);
+            self.complete_sections()
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Take the whole sections from the buffer, stuffing ends the packet's sections
+    fn complete_sections(&mut self) -> Vec<Vec<u8>> {
+        let mut sections = Vec::new();
+        while self.buffer.len() >= 3 && self.buffer[0] != 0xFF {
+            let length = 3 + ((((self.buffer[1] & 0x0F) as usize) << 8) | self.buffer[2] as usize);
+            if self.buffer.len() < length {
+                return sections;
+            }
+            sections.push(self.buffer.drain(..length).collect());
+        }
+        if self.buffer.first() == Some(&0xFF) {
+            self.buffer.clear();
+        }
+        sections
+    }
+}
+
+// Start or end the break of a splice insert of the monitored feed, the default duration is
+// used when the splice out has no break duration
+pub fn handle_splice_insert(insert: &SpliceInsert, default_duration: Duration) {
+    debug!("SCTE-35 splice insert {:?}", insert);
+    if insert.cancel || !insert.out_of_network {
+        end_ad_break(Some(insert.event_id));
+        return;
+    }
+    let duration = insert
+        .duration_ms
+        .map(Duration::from_millis)
+        .unwrap_or(default_duration);
+    start_ad_break("scte35", Some(insert.event_id), duration);
+}
+
+// Breaks of the channel's own at a fixed interval
+pub struct AdBreakSchedule {
+    interval: Duration, // zero is off
+    duration: Duration,
+    next: Instant,
+}
+
+impl AdBreakSchedule {
+    pub fn new(interval: Duration, duration: Duration) -> Self {
+        AdBreakSchedule {
+            interval,
+            duration,
+            next: Instant::now() + interval,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        AdBreakSchedule::new(
+            Duration::from_secs(args.ad_break_interval_mins * 60),
+            Duration::from_secs(args.ad_break_secs),
+        )
+    }
+
+    // Start the scheduled break when it is due
+    pub fn check(&mut self) {
+        if self.interval.is_zero() || Instant::now() < self.next {
+            return;
+        }
+        self.next = Instant::now() + self.interval + self.duration;
+        start_ad_break("schedule", None, self.duration);
+    }
+}
@@ -0,0 +1,165 @@
+/*
+ * api_auth.rs
+ * -----------
+ * Bearer tokens and roles of the control api. The --api-tokens JSON file lists the tokens,
+ * each with a name and the viewer or operator role. Viewers read the stats and ask about
+ * them, operators also change the persona, the ticker and the history. Only the SHA-256 of
+ * a token is kept in memory, and the file can hold the digest instead of the token. Without
+ * the file every request is allowed, as before there were tokens.
+*/
+
+use crate::args::Args;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ApiRole {
+    Viewer,
+    Operator, // can do all a viewer can
+}
+
+impl std::str::FromStr for ApiRole {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, String> {
+        match role.trim().to_lowercase().as_str() {
+            "viewer" | "read" => Ok(ApiRole::Viewer),
+            "operator" | "admin" => Ok(ApiRole::Operator),
+            _ => Err(format!("Invalid api role {}", role)),
+        }
+    }
+}
+
+impl ApiRole {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ApiRole::Viewer => "viewer",
+            ApiRole::Operator => "operator",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenEntry {
+    name: String,
+    role: String,
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    sha256: String, // hex digest of the token, in place of the token
+}
+
+#[derive(Debug, Deserialize)]
+struct TokensFile {
+    tokens: Vec<TokenEntry>,
+}
+
+#[derive(Debug, Clone)]
+struct ApiToken {
+    name: String,
+    role: ApiRole,
+    sha256: String,
+}
+
+pub fn token_digest(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Who made a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiClient {
+    pub name: String,
+    pub role: ApiRole,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiTokens {
+    tokens: Vec<ApiToken>,
+}
+
+impl ApiTokens {
+    pub fn new() -> Self {
+        ApiTokens::default()
+    }
+
+    pub fn with_token(mut self, name: &str, token: &str, role: ApiRole) -> Self {
+        self.tokens.push(ApiToken {
+            name: name.to_string(),
+            role,
+            sha256: token_digest(token),
+        });
+        self
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read api tokens {}: {}", path, e))?;
+        let file: TokensFile = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Failed to parse api tokens {}: {}", path, e))?;
+        let mut tokens = ApiTokens::new();
+        for entry in file.tokens {
+            let role = entry.role.parse::<ApiRole>().map_err(|e| anyhow!(e))?;
+            let sha256 = if !entry.token.is_empty() {
+                token_digest(&entry.token)
+            } else if entry.sha256.len() == 64
+                && entry.sha256.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                entry.sha256.to_lowercase()
+            } else {
+                return Err(anyhow!(
+                    "Api token {} has no token or sha256 in {}",
+                    entry.name,
+                    path
+                ));
+            };
+            tokens.tokens.push(ApiToken {
+                name: entry.name,
+                role,
+                sha256,
+            });
+        }
+        if tokens.tokens.is_empty() {
+            return Err(anyhow!("No api tokens in {}", path));
+        }
+        Ok(tokens)
+    }
+
+    // The tokens of --api-tokens, an error keeps the api from starting open
+    pub fn from_args(args: &Args) -> Result<Self> {
+        if args.api_tokens.is_empty() {
+            return Ok(ApiTokens::new());
+        }
+        ApiTokens::load(&args.api_tokens)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    // The client of an Authorization header, None for a missing or unknown token
+    pub fn client(&self, authorization: Option<&str>) -> Option<ApiClient> {
+        let token = authorization?
+            .trim()
+            .strip_prefix("Bearer ")
+            .map(|token| token.trim())?;
+        let sha256 = token_digest(token);
+        self.tokens
+            .iter()
+            .find(|entry| entry.sha256 == sha256)
+            .map(|entry| ApiClient {
+                name: entry.name.clone(),
+                role: entry.role,
+            })
+    }
+}
+
+// Role a route needs, None for the health check the container runtime polls without a token
+pub fn required_role(method: &str, segments: &[&str]) -> Option<ApiRole> {
+    match (method, segments) {
+        (_, ["healthz"]) => None,
+        // questions about the stats and their sessions only read
+        ("GET", _) | (_, ["ask"]) | (_, ["ask", _]) => Some(ApiRole::Viewer),
+        _ => Some(ApiRole::Operator),
+    }
+}
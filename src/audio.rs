@@ -1,4 +1,6 @@
+use crate::args::Args;
 use minimp3::{Decoder, Frame};
+use rubato::{FftFixedInOut, Resampler};
 use std::io::Cursor;
 use std::io::Result;
 
@@ -67,3 +69,331 @@ pub fn mp3_to_f32(mp3_data: Vec<u8>) -> Result<Vec<f32>> {
 
     Ok(samples_f32)
 }
+
+/// Resamples mono audio with the FFT resampler of rubato, so the speech of TTS backends
+/// with different sample rates reaches the outputs at one rate.
+///
+/// # Arguments
+/// * `samples` - The samples at `from_rate`.
+///
+/// # Returns
+/// The samples at `to_rate`, a copy of the input when the rates are the same.
+pub fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Result<Vec<f32>> {
+    if from_rate == to_rate || from_rate == 0 || to_rate == 0 || samples.is_empty() {
+        return Ok(samples.to_vec());
+    }
+    let to_io_error =
+        |e: &dyn std::error::Error| std::io::Error::new(std::io::ErrorKind::Other, e.to_string());
+    let mut resampler = FftFixedInOut::<f32>::new(from_rate as usize, to_rate as usize, 1024, 1)
+        .map_err(|e| to_io_error(&e))?;
+
+    // the resampler output starts after its delay, it is cut off and the end flushed
+    let delay = resampler.output_delay();
+    let expected = (samples.len() as u64 * to_rate as u64 / from_rate as u64) as usize;
+    let mut resampled = Vec::with_capacity(expected + delay + resampler.output_frames_max());
+    let mut position = 0;
+    while samples.len() - position >= resampler.input_frames_next() {
+        let end = position + resampler.input_frames_next();
+        let output = resampler
+            .process(&[&samples[position..end]], None)
+            .map_err(|e| to_io_error(&e))?;
+        resampled.extend_from_slice(&output[0]);
+        position = end;
+    }
+    if position < samples.len() {
+        let output = resampler
+            .process_partial(Some(&[&samples[position..]]), None)
+            .map_err(|e| to_io_error(&e))?;
+        resampled.extend_from_slice(&output[0]);
+    }
+    while resampled.len() < expected + delay {
+        let output = resampler
+            .process_partial::<&[f32]>(None, None)
+            .map_err(|e| to_io_error(&e))?;
+        if output[0].is_empty() {
+            break;
+        }
+        resampled.extend_from_slice(&output[0]);
+    }
+    resampled.drain(..delay.min(resampled.len()));
+    resampled.truncate(expected);
+    Ok(resampled)
+}
+
+/// Trim and fade settings of the speech audio post-chain.
+#[derive(Debug, Clone)]
+pub struct AudioPostOptions {
+    /// Level in dBFS under which the ends are trimmed as silence, 0 keeps the silence.
+    pub trim_threshold_db: f32,
+    /// Silence kept around the speech when trimming.
+    pub trim_pad_ms: u64,
+    pub fade_in_ms: u64,
+    pub fade_out_ms: u64,
+}
+
+impl AudioPostOptions {
+    pub fn new() -> Self {
+        AudioPostOptions {
+            trim_threshold_db: -50.0,
+            trim_pad_ms: 50,
+            fade_in_ms: 10,
+            fade_out_ms: 30,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        AudioPostOptions {
+            trim_threshold_db: args.audio_trim_threshold_db,
+            trim_pad_ms: args.audio_trim_pad_ms,
+            fade_in_ms: args.audio_fade_in_ms,
+            fade_out_ms: args.audio_fade_out_ms,
+        }
+    }
+
+    /// True if the chain changes the audio at all.
+    pub fn is_enabled(&self) -> bool {
+        self.trim_threshold_db < 0.0 || self.fade_in_ms > 0 || self.fade_out_ms > 0
+    }
+}
+
+impl Default for AudioPostOptions {
+    fn default() -> Self {
+        AudioPostOptions::new()
+    }
+}
+
+fn ms_to_samples(ms: u64, sample_rate: u32) -> usize {
+    (ms * sample_rate as u64 / 1000) as usize
+}
+
+/// Cuts the silence at the start and the end of the audio.
+///
+/// # Arguments
+/// * `threshold_db` - Samples quieter than this level in dBFS count as silence.
+/// * `pad_ms` - Silence kept before the first and after the last sound.
+///
+/// # Returns
+/// The part of the samples with sound, empty if it is all silence.
+pub fn trim_silence(samples: &[f32], sample_rate: u32, threshold_db: f32, pad_ms: u64) -> &[f32] {
+    let threshold = 10f32.powf(threshold_db / 20.0);
+    let Some(first) = samples.iter().position(|sample| sample.abs() > threshold) else {
+        return &[];
+    };
+    let last = samples
+        .iter()
+        .rposition(|sample| sample.abs() > threshold)
+        .unwrap_or(first);
+    let pad = ms_to_samples(pad_ms, sample_rate);
+    let start = first.saturating_sub(pad);
+    let end = (last + 1 + pad).min(samples.len());
+    &samples[start..end]
+}
+
+/// Applies linear fades to the start and the end of the audio so it doesn't click when
+/// the paragraphs are played back to back.
+pub fn apply_fades(samples: &mut [f32], sample_rate: u32, fade_in_ms: u64, fade_out_ms: u64) {
+    let len = samples.len();
+    let fade_in = ms_to_samples(fade_in_ms, sample_rate).min(len);
+    for (index, sample) in samples[..fade_in].iter_mut().enumerate() {
+        *sample *= index as f32 / fade_in as f32;
+    }
+    let fade_out = ms_to_samples(fade_out_ms, sample_rate).min(len);
+    for (index, sample) in samples[len - fade_out..].iter_mut().rev().enumerate() {
+        *sample *= index as f32 / fade_out as f32;
+    }
+}
+
+/// Encodes mono f32 samples as a 16 bit WAV file.
+pub fn f32_to_wav(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let to_io_error = |e: hound::Error| std::io::Error::new(std::io::ErrorKind::Other, e);
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut buffer, spec).map_err(to_io_error)?;
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_sample(sample).map_err(to_io_error)?;
+    }
+    writer.finalize().map_err(to_io_error)?;
+    Ok(buffer.into_inner())
+}
+
+/// Runs the TTS audio through the post-chain, trimming the silence at the ends and fading
+/// in and out.
+///
+/// # Arguments
+/// * `audio_data` - The bytes of a WAV or MP3 file from the TTS backend.
+///
+/// # Returns
+/// The processed audio as a WAV file, the input unchanged if the chain is disabled or the
+/// audio can not be decoded.
+pub fn post_process_speech(
+    audio_data: Vec<u8>,
+    default_sample_rate: u32,
+    options: &AudioPostOptions,
+) -> Vec<u8> {
+    if !options.is_enabled() || audio_data.is_empty() {
+        return audio_data;
+    }
+    let (samples, sample_rate) = match decode_audio(audio_data.clone(), default_sample_rate) {
+        Ok((samples, sample_rate)) if !samples.is_empty() => (samples, sample_rate),
+        _ => return audio_data,
+    };
+    let mut samples = if options.trim_threshold_db < 0.0 {
+        trim_silence(
+            &samples,
+            sample_rate,
+            options.trim_threshold_db,
+            options.trim_pad_ms,
+        )
+        .to_vec()
+    } else {
+        samples
+    };
+    apply_fades(
+        &mut samples,
+        sample_rate,
+        options.fade_in_ms,
+        options.fade_out_ms,
+    );
+    f32_to_wav(&samples, sample_rate).unwrap_or(audio_data)
+}
+
+/// Duration of WAV or MP3 audio in milliseconds, treating the samples as mono like the
+/// NDI audio output does.
+pub fn audio_duration_ms(audio_data: &[u8], default_sample_rate: u32) -> u64 {
+    match decode_audio(audio_data.to_vec(), default_sample_rate) {
+        Ok((samples, sample_rate)) if sample_rate > 0 => {
+            samples.len() as u64 * 1000 / sample_rate as u64
+        }
+        _ => 0,
+    }
+}
+
+/// Decodes WAV or MP3 audio to f32 samples, detecting the format from the data.
+///
+/// # Arguments
+/// * `audio_data` - The bytes of a WAV or MP3 file.
+///
+/// # Returns
+/// A `Result` containing the normalized samples and the sample rate, the sample rate
+/// is `default_sample_rate` if it can not be read from the data.
+pub fn decode_audio(audio_data: Vec<u8>, default_sample_rate: u32) -> Result<(Vec<f32>, u32)> {
+    if audio_data.starts_with(b"RIFF") {
+        let sample_rate = hound::WavReader::new(Cursor::new(&audio_data))
+            .map(|reader| reader.spec().sample_rate)
+            .unwrap_or(default_sample_rate);
+        Ok((wav_to_f32(audio_data)?, sample_rate))
+    } else {
+        let sample_rate = match Decoder::new(Cursor::new(&audio_data)).next_frame() {
+            Ok(frame) => frame.sample_rate as u32,
+            Err(_) => default_sample_rate,
+        };
+        Ok((mp3_to_f32(audio_data)?, sample_rate))
+    }
+}
+
+/// Video frame rate as an exact fraction, like 30000/1001 for 29.97 fps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameRate {
+    pub num: u64,
+    pub den: u64,
+}
+
+impl FrameRate {
+    /// Parses a fraction like `30000/1001` or a rate like `29.97`, the NTSC rates 23.976,
+    /// 29.97 and 59.94 map to their exact 1001 fractions.
+    pub fn parse(rate: &str) -> Option<Self> {
+        let rate = rate.trim();
+        if let Some((num, den)) = rate.split_once('/') {
+            let num = num.trim().parse::<u64>().ok()?;
+            let den = den.trim().parse::<u64>().ok()?;
+            return FrameRate::new(num, den);
+        }
+        match rate {
+            "23.976" | "23.98" => FrameRate::new(24000, 1001),
+            "29.97" => FrameRate::new(30000, 1001),
+            "59.94" => FrameRate::new(60000, 1001),
+            _ => {
+                let fps = rate.parse::<f64>().ok()?;
+                FrameRate::new((fps * 1000.0).round() as u64, 1000)
+            }
+        }
+    }
+
+    pub fn new(num: u64, den: u64) -> Option<Self> {
+        if num == 0 || den == 0 {
+            None
+        } else {
+            Some(FrameRate { num, den })
+        }
+    }
+
+    /// Time from the first frame to the start of `frame`, exact to the nanosecond so long
+    /// sessions don't accumulate rounding drift.
+    pub fn frame_time(&self, frame: u64) -> std::time::Duration {
+        let nanos = frame as u128 * 1_000_000_000 * self.den as u128 / self.num as u128;
+        std::time::Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// Audio samples per video frame, following the sample cadence of the frame rate like the
+/// five frame pattern of 1601 and 1602 samples at 48 kHz and 29.97 fps.
+#[derive(Debug, Clone)]
+pub struct AudioCadence {
+    sample_rate: u64,
+    frame_rate: FrameRate,
+    frame: u64,
+}
+
+impl AudioCadence {
+    pub fn new(sample_rate: u32, frame_rate: FrameRate) -> Self {
+        AudioCadence {
+            sample_rate: sample_rate as u64,
+            frame_rate,
+            frame: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    /// Samples from the start of the cadence to the start of `frame`.
+    pub fn samples_before(&self, frame: u64) -> u64 {
+        (frame as u128 * self.sample_rate as u128 * self.frame_rate.den as u128
+            / self.frame_rate.num as u128) as u64
+    }
+
+    /// Samples in `frame`, the integer rounding is carried so no samples are lost or added.
+    pub fn samples_in(&self, frame: u64) -> usize {
+        (self.samples_before(frame + 1) - self.samples_before(frame)) as usize
+    }
+
+    /// Samples in the next frame, advancing the cadence.
+    pub fn next_frame_samples(&mut self) -> usize {
+        let samples = self.samples_in(self.frame);
+        self.frame += 1;
+        samples
+    }
+
+    /// Splits the samples into frame sized chunks continuing the cadence, the last chunk is
+    /// padded with silence to a full frame.
+    pub fn chunk(&mut self, samples: &[f32]) -> Vec<Vec<f32>> {
+        let mut chunks = Vec::new();
+        let mut position = 0;
+        while position < samples.len() {
+            let size = self.next_frame_samples();
+            let end = (position + size).min(samples.len());
+            let mut chunk = samples[position..end].to_vec();
+            chunk.resize(size, 0.0);
+            chunks.push(chunk);
+            position = end;
+        }
+        chunks
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ * audio_class.rs
+ * --------------
+ * Music, speech and silence classification of the monitored service's audio. The decoded
+ * program audio is cut into 20 ms frames and judged in segments of one second. Speech has
+ * pauses between syllables, so many of its frames are well under the segment's average
+ * energy and its zero crossing rate jumps between voiced and unvoiced sounds, music is
+ * steadier. A class has to hold for a few segments before it changes, long silence is dead
+ * air and a class other than the expected one is a wrong source, both raise alerts.
+*/
+
+use crate::args::Args;
+use crate::event_log::log_event;
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use std::sync::Mutex;
+
+static PROGRAM_AUDIO_CLASS: Lazy<Mutex<Option<AudioClassStatus>>> = Lazy::new(|| Mutex::new(None));
+
+const FRAME_SECS: f64 = 0.02;
+const SEGMENT_SECS: f64 = 1.0;
+// Segments of a new class before the class changes
+const CLASS_HOLD_SEGMENTS: usize = 3;
+// Frames under this share of the average energy are pauses
+const LOW_ENERGY_SHARE: f64 = 0.5;
+// Shares of pause frames for speech, the lower one when the zero crossings vary
+const SPEECH_LOW_ENERGY_RATIO: f64 = 0.35;
+const SPEECH_LOW_ENERGY_RATIO_VARYING: f64 = 0.2;
+// Spread of the zero crossing rate over its mean of varying speech
+const SPEECH_ZCR_VARIATION: f64 = 0.6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioClass {
+    Speech,
+    Music,
+    Silence,
+}
+
+impl std::str::FromStr for AudioClass {
+    type Err = String;
+
+    fn from_str(class: &str) -> Result<Self, String> {
+        match class.trim().to_lowercase().as_str() {
+            "speech" => Ok(AudioClass::Speech),
+            "music" => Ok(AudioClass::Music),
+            "silence" => Ok(AudioClass::Silence),
+            _ => Err(format!("Invalid audio class {}", class)),
+        }
+    }
+}
+
+impl fmt::Display for AudioClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AudioClass::Speech => "speech",
+            AudioClass::Music => "music",
+            AudioClass::Silence => "silence",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Class of the monitored service with the alarms
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioClassStatus {
+    pub pid: u16,
+    pub class: AudioClass,
+    pub seconds: f64, // audio time in this class
+    pub dead_air: bool,
+    pub wrong_source: bool,
+}
+
+pub fn program_audio_class() -> Option<AudioClassStatus> {
+    PROGRAM_AUDIO_CLASS.lock().unwrap().clone()
+}
+
+pub struct AudioClassLimits {
+    pub silence_dbfs: f64,
+    pub dead_air_secs: f64, // 0 is no dead air alerts
+    pub expected: Option<AudioClass>,
+    pub wrong_source_secs: f64,
+}
+
+impl AudioClassLimits {
+    pub fn new(
+        silence_dbfs: f64,
+        dead_air_secs: f64,
+        expected: Option<AudioClass>,
+        wrong_source_secs: f64,
+    ) -> Self {
+        AudioClassLimits {
+            silence_dbfs,
+            dead_air_secs,
+            expected,
+            wrong_source_secs,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        let expected = if args.expected_audio_class.is_empty() {
+            None
+        } else {
+            match args.expected_audio_class.parse::<AudioClass>() {
+                Ok(class) => Some(class),
+                Err(e) => {
+                    error!("{}, no wrong source alerts", e);
+                    None
+                }
+            }
+        };
+        AudioClassLimits::new(
+            args.audio_silence_dbfs as f64,
+            args.dead_air_secs as f64,
+            expected,
+            args.wrong_source_secs as f64,
+        )
+    }
+}
+
+// Energy and zero crossing rate of one frame
+#[derive(Debug, Clone, Copy)]
+struct FrameFeatures {
+    energy: f64,
+    zcr: f64,
+}
+
+fn mean(values: impl Iterator<Item = f64> + Clone) -> f64 {
+    let count = values.clone().count();
+    if count == 0 {
+        0.0
+    } else {
+        values.sum::<f64>() / count as f64
+    }
+}
+
+// Class of a segment of frames
+fn classify_segment(frames: &[FrameFeatures], silence_dbfs: f64) -> AudioClass {
+    let energy = mean(frames.iter().map(|frame| frame.energy));
+    if energy <= 0.0 || 10.0 * energy.log10() < silence_dbfs {
+        return AudioClass::Silence;
+    }
+    let low_energy_ratio = frames
+        .iter()
+        .filter(|frame| frame.energy < energy * LOW_ENERGY_SHARE)
+        .count() as f64
+        / frames.len() as f64;
+    let zcr = mean(frames.iter().map(|frame| frame.zcr));
+    let zcr_spread = mean(frames.iter().map(|frame| (frame.zcr - zcr).powi(2))).sqrt();
+    let zcr_variation = if zcr > 0.0 { zcr_spread / zcr } else { 0.0 };
+
+    if low_energy_ratio >= SPEECH_LOW_ENERGY_RATIO
+        || (low_energy_ratio >= SPEECH_LOW_ENERGY_RATIO_VARYING
+            && zcr_variation >= SPEECH_ZCR_VARIATION)
+    {
+        AudioClass::Speech
+    } else {
+        AudioClass::Music
+    }
+}
+
+pub struct AudioClassifier {
+    sample_rate: u32,
+    channels: usize,
+    frame_len: usize,
+    segment_frames: usize,
+    frame: Vec<f32>, // mono samples of the frame being filled
+    frames: Vec<FrameFeatures>,
+    class: Option<AudioClass>,
+    candidate: Option<(AudioClass, usize)>,
+    class_segments: usize,
+}
+
+impl AudioClassifier {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let frame_len = ((sample_rate as f64 * FRAME_SECS) as usize).max(1);
+        AudioClassifier {
+            sample_rate,
+            channels: channels.max(1),
+            frame_len,
+            segment_frames: (SEGMENT_SECS / FRAME_SECS) as usize,
+            frame: Vec::with_capacity(frame_len),
+            frames: Vec::new(),
+            class: None,
+            candidate: None,
+            class_segments: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    // Add interleaved samples, the stable class and its audio time when a segment is done
+    pub fn push(&mut self, samples: &[f32], silence_dbfs: f64) -> Option<(AudioClass, f64)> {
+        let mut result = None;
+        for frame in samples.chunks(self.channels) {
+            self.frame
+                .push(frame.iter().sum::<f32>() / frame.len() as f32);
+            if self.frame.len() < self.frame_len {
+                continue;
+            }
+            let energy = mean(self.frame.iter().map(|s| (*s as f64).powi(2)));
+            let crossings = self
+                .frame
+                .windows(2)
+                .filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0))
+                .count();
+            self.frames.push(FrameFeatures {
+                energy,
+                zcr: crossings as f64 / self.frame_len as f64,
+            });
+            self.frame.clear();
+
+            if self.frames.len() >= self.segment_frames {
+                let segment = classify_segment(&self.frames, silence_dbfs);
+                self.frames.clear();
+                result = Some(self.segment(segment));
+            }
+        }
+        result
+    }
+
+    // Follow the segment classes, a new class takes over after it held for a few segments
+    fn segment(&mut self, segment: AudioClass) -> (AudioClass, f64) {
+        match self.class {
+            None => {
+                self.class = Some(segment);
+                self.class_segments = 1;
+            }
+            Some(class) if class == segment => {
+                self.class_segments += 1;
+                self.candidate = None;
+            }
+            Some(_) => {
+                let held = match self.candidate {
+                    Some((candidate, held)) if candidate == segment => held + 1,
+                    _ => 1,
+                };
+                if held >= CLASS_HOLD_SEGMENTS {
+                    self.class = Some(segment);
+                    self.class_segments = held;
+                    self.candidate = None;
+                } else {
+                    self.candidate = Some((segment, held));
+                    self.class_segments += 1;
+                }
+            }
+        }
+        (
+            self.class.unwrap_or(segment),
+            self.class_segments as f64 * SEGMENT_SECS,
+        )
+    }
+}
+
+// Keep the class of the monitored service, log the class changes and warn when dead air or a
+// wrong source starts
+pub fn update_program_audio_class(
+    pid: u16,
+    class: AudioClass,
+    seconds: f64,
+    limits: &AudioClassLimits,
+) -> AudioClassStatus {
+    let dead_air = limits.dead_air_secs > 0.0
+        && class == AudioClass::Silence
+        && seconds >= limits.dead_air_secs;
+    let wrong_source = limits.expected.is_some_and(|expected| {
+        class != expected && class != AudioClass::Silence && seconds >= limits.wrong_source_secs
+    });
+    let status = AudioClassStatus {
+        pid,
+        class,
+        seconds,
+        dead_air,
+        wrong_source,
+    };
+
+    let mut current = PROGRAM_AUDIO_CLASS.lock().unwrap();
+    let previous = current.as_ref().filter(|previous| previous.pid == pid);
+    let changed = previous.is_none_or(|previous| previous.class != class);
+    let was_dead_air = previous.is_some_and(|previous| previous.dead_air);
+    let was_wrong_source = previous.is_some_and(|previous| previous.wrong_source);
+    if dead_air && !was_dead_air {
+        warn!(
+            "Program audio PID {} is dead air, silent for {:.0} seconds",
+            pid, seconds
+        );
+        log_event("analyzer", "dead_air", json!(status));
+    }
+    if wrong_source && !was_wrong_source {
+        warn!(
+            "Program audio PID {} is {} for {:.0} seconds, expected {}",
+            pid,
+            class,
+            seconds,
+            limits.expected.map(|e| e.to_string()).unwrap_or_default()
+        );
+        log_event("analyzer", "wrong_source", json!(status));
+    }
+    if changed {
+        log_event("analyzer", "audio_class", json!(status));
+    }
+    *current = Some(status.clone());
+    status
+}
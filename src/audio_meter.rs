@@ -0,0 +1,143 @@
+/*
+ * audio_meter.rs
+ * --------------
+ * Level meter of the final mixed output audio. Each paragraph's audio is measured for its
+ * peak and RMS level in dBFS and the samples at or over the clip level are counted. The
+ * totals are kept for the control API and a paragraph that clips or is much too quiet is
+ * logged as a warning, the usual sign of a TTS backend with a broken gain.
+*/
+
+use crate::args::Args;
+use crate::event_log::log_event;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Mutex;
+
+// Level of silence in dBFS, the meter reads this for no signal
+const SILENCE_DBFS: f32 = -120.0;
+
+static LEVELS: Lazy<Mutex<AudioLevels>> = Lazy::new(|| Mutex::new(AudioLevels::default()));
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MeterReading {
+    pub peak_dbfs: f32,
+    pub rms_dbfs: f32,
+    pub clipped: usize,
+    pub samples: usize,
+}
+
+// Totals since the start for the control API
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AudioLevels {
+    pub paragraphs: u64,
+    pub clipped_paragraphs: u64,
+    pub quiet_paragraphs: u64,
+    pub samples: u64,
+    pub clipped_samples: u64,
+    pub max_peak_dbfs: Option<f32>,
+    pub last: Option<MeterReading>,
+}
+
+pub fn audio_levels() -> AudioLevels {
+    LEVELS.lock().unwrap().clone()
+}
+
+fn dbfs(amplitude: f32) -> f32 {
+    if amplitude <= 0.0 {
+        SILENCE_DBFS
+    } else {
+        (20.0 * amplitude.log10()).max(SILENCE_DBFS)
+    }
+}
+
+pub struct AudioMeter {
+    clip_level: f32,
+    quiet_rms_dbfs: f32, // 0 is off
+}
+
+impl AudioMeter {
+    pub fn new(clip_level: f32, quiet_rms_dbfs: f32) -> Self {
+        AudioMeter {
+            clip_level,
+            quiet_rms_dbfs,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        AudioMeter::new(args.audio_clip_level, args.audio_quiet_rms_dbfs)
+    }
+
+    pub fn measure(&self, samples: &[f32]) -> MeterReading {
+        let mut peak: f32 = 0.0;
+        let mut sum_squares = 0.0f64;
+        let mut clipped = 0;
+        for sample in samples {
+            let level = sample.abs();
+            peak = peak.max(level);
+            sum_squares += (*sample as f64) * (*sample as f64);
+            if level >= self.clip_level {
+                clipped += 1;
+            }
+        }
+        let rms = if samples.is_empty() {
+            0.0
+        } else {
+            (sum_squares / samples.len() as f64).sqrt() as f32
+        };
+        MeterReading {
+            peak_dbfs: dbfs(peak),
+            rms_dbfs: dbfs(rms),
+            clipped,
+            samples: samples.len(),
+        }
+    }
+
+    // Measure the paragraph's audio, add it to the totals and warn about clipping or a level
+    // too low to hear
+    pub fn meter(&self, samples: &[f32], paragraph_count: usize) -> MeterReading {
+        let reading = self.measure(samples);
+        let quiet = self.quiet_rms_dbfs < 0.0
+            && reading.samples > 0
+            && reading.rms_dbfs < self.quiet_rms_dbfs;
+        if reading.clipped > 0 {
+            warn!(
+                "Output audio of paragraph {} clipped {} of {} samples, peak {:.1} dBFS",
+                paragraph_count, reading.clipped, reading.samples, reading.peak_dbfs
+            );
+        }
+        if quiet {
+            warn!(
+                "Output audio of paragraph {} is quiet at {:.1} dBFS RMS, under {:.1} dBFS",
+                paragraph_count, reading.rms_dbfs, self.quiet_rms_dbfs
+            );
+        }
+        if reading.clipped > 0 || quiet {
+            log_event(
+                "pipeline",
+                "audio_level",
+                json!({
+                    "paragraph_count": paragraph_count,
+                    "reading": reading,
+                    "clipped": reading.clipped > 0,
+                    "quiet": quiet,
+                }),
+            );
+        }
+
+        let mut levels = LEVELS.lock().unwrap();
+        levels.paragraphs += 1;
+        levels.clipped_paragraphs += (reading.clipped > 0) as u64;
+        levels.quiet_paragraphs += quiet as u64;
+        levels.samples += reading.samples as u64;
+        levels.clipped_samples += reading.clipped as u64;
+        levels.max_peak_dbfs = Some(
+            levels
+                .max_peak_dbfs
+                .map_or(reading.peak_dbfs, |peak| peak.max(reading.peak_dbfs)),
+        );
+        levels.last = Some(reading);
+        reading
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * rsllm-probe
+ * -----------
+ * Stream probe without the AI stack, captures the MPEG-TS or SMPTE 2110 source, runs the
+ * TR 101 290 checks and prints the stream and system stats as a JSON line every poll interval.
+ * With --ts-generator it analyzes a synthetic stream instead, --ts-generator-udp sends one.
+ * With --probe-central the stats also go to a central rsllm that runs the LLM for all sites,
+ * with --publish-url they are published to NATS or MQTT and with --kafka-brokers kept in Kafka.
+ *
+ * Build with: cargo build --release --no-default-features --bin rsllm-probe
+*/
+
+use clap::Parser;
+use log::{error, info};
+use rsllm::args::Args;
+use rsllm::capabilities::print_version;
+use rsllm::current_unix_timestamp_ms;
+use rsllm::event_log::init_event_log;
+use rsllm::event_publish::EventPublisher;
+use rsllm::external_apis::set_no_external_apis;
+#[cfg(feature = "kafka")]
+use rsllm::kafka_sink::KafkaSink;
+use rsllm::network_capture::{network_capture, CapturedPacket};
+use rsllm::probe::{network_capture_config, StreamAnalyzer};
+use rsllm::probe_link::ProbeLink;
+use rsllm::ts_generator::{send_udp, ts_generator_capture, TsGeneratorConfig};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+#[tokio::main]
+async fn main() {
+    // Read .env file
+    dotenv::dotenv().ok();
+
+    // Initialize logging
+    let _ = env_logger::try_init();
+
+    // Parse command line arguments, the probe uses the capture and analysis args
+    let args = Args::parse();
+    if args.version {
+        print_version(&args);
+        return;
+    }
+
+    match args.loglevel.to_lowercase().as_str() {
+        "error" => log::set_max_level(log::LevelFilter::Error),
+        "warn" => log::set_max_level(log::LevelFilter::Warn),
+        "debug" => log::set_max_level(log::LevelFilter::Debug),
+        "trace" => log::set_max_level(log::LevelFilter::Trace),
+        _ => log::set_max_level(log::LevelFilter::Info),
+    }
+
+    // Local only mode, the stats are not sent to other hosts
+    set_no_external_apis(args.no_external_apis);
+
+    if let Err(e) = init_event_log(&args) {
+        error!("Error opening event log {}: {}", args.event_log, e);
+        std::process::exit(1);
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let running_ctrlc = running.clone();
+    ctrlc::set_handler(move || {
+        running_ctrlc.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl+C handler");
+
+    let start_time = current_unix_timestamp_ms().unwrap_or(0);
+    let mut analyzer = StreamAnalyzer::new(&args, start_time);
+
+    let (ptx, mut prx) = mpsc::channel::<CapturedPacket>(args.pcap_channel_size);
+    let mut network_capture_config = network_capture_config(&args);
+    if args.ts_generator {
+        ts_generator_capture(
+            &mut network_capture_config,
+            TsGeneratorConfig::from_args(&args),
+            ptx,
+        );
+    } else {
+        network_capture(&mut network_capture_config, ptx);
+
+        let devices = network_capture_config.source_devices.join(", ");
+        info!(
+            "Probing {}://{}:{} on {}",
+            args.source_protocol,
+            args.source_ip,
+            args.source_port,
+            if devices.is_empty() {
+                "the default device"
+            } else {
+                &devices
+            }
+        );
+    }
+
+    // the synthetic stream sent over UDP, captured as the source when it is sent to it
+    if !args.ts_generator_udp.is_empty() {
+        let config = TsGeneratorConfig::from_args(&args);
+        let target = args.ts_generator_udp.clone();
+        let running_generator = running.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_udp(config, &target, running_generator).await {
+                error!("Failed to send the synthetic MPEG-TS to {}: {}", target, e);
+            }
+        });
+    }
+
+    // reports to the central rsllm
+    let probe_link = ProbeLink::from_args(&args);
+    // and the stats with the events on NATS or MQTT
+    let event_publisher = EventPublisher::from_args(&args);
+    // and the PID, program and event records kept in Kafka
+    #[cfg(feature = "kafka")]
+    let _kafka_sink = KafkaSink::from_args(&args);
+
+    let poll_interval = Duration::from_millis(args.poll_interval.max(100));
+    let mut last_report = Instant::now();
+    let mut packets: u64 = 0;
+    while running.load(Ordering::SeqCst) {
+        match timeout(Duration::from_millis(100), prx.recv()).await {
+            Ok(Some(packet)) => {
+                packets += analyzer.process(packet).len() as u64;
+            }
+            Ok(None) => {
+                error!("Network capture stopped");
+                break;
+            }
+            Err(_) => {} // no packets, check if we are still running
+        }
+
+        if last_report.elapsed() >= poll_interval {
+            last_report = Instant::now();
+            let stats = analyzer.stats(packets);
+            println!("{}", stats.to_json());
+            if let Some(publisher) = event_publisher.as_ref() {
+                publisher.publish_stats(&stats);
+            }
+            if let Some(probe_link) = probe_link.as_ref() {
+                probe_link.send(stats);
+            }
+        }
+    }
+
+    println!("{}", analyzer.stats(packets).to_json());
+    network_capture_config
+        .running
+        .store(false, Ordering::SeqCst);
+}
@@ -0,0 +1,179 @@
+/*
+ * bumpers.rs
+ * ----------
+ * The bumpers of the show from the --bumpers-config JSON file: the intro read when the show
+ * starts, the outro that closes every answer and the stingers dropped between the answers
+ * every few iterations. Each has its own text, voice and image prompt. Any field left empty
+ * is the persona's, so without a file the persona greets and signs off with its greeting.
+*/
+
+use crate::args::Args;
+use crate::persona::Persona;
+use crate::pipeline::{MessageData, Priority};
+use crate::runtime::build_sd_config;
+use anyhow::{anyhow, Result};
+use log::error;
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Bumper {
+    pub text: String,  // {persona} is the persona name, empty is the persona greeting
+    pub voice: String, // empty is the persona voice
+    pub image_prompt: String, // empty is the persona image prompt
+}
+
+impl Bumper {
+    fn text(&self, persona: &Persona) -> String {
+        if self.text.is_empty() {
+            persona.greeting.clone()
+        } else {
+            self.text.replace("{persona}", &persona.name)
+        }
+    }
+
+    fn voice<'a>(&'a self, persona: &'a Persona) -> &'a str {
+        if self.voice.is_empty() {
+            &persona.voice
+        } else {
+            &self.voice
+        }
+    }
+
+    // The announcement of the bumper, default_image_prompt when it has no image prompt
+    fn message(
+        &self,
+        persona: &Persona,
+        default_image_prompt: &str,
+        output_id: &str,
+        args: &Args,
+    ) -> MessageData {
+        let image_prompt = if self.image_prompt.is_empty() {
+            default_image_prompt
+        } else {
+            &self.image_prompt
+        };
+        MessageData::announcement(
+            &self.text(persona),
+            output_id,
+            build_sd_config(args, image_prompt),
+            args,
+        )
+        .with_voice(self.voice(persona))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BumpersConfig {
+    pub intro: Option<Bumper>, // null is no intro
+    pub outro: Bumper,
+    pub stingers: Vec<Bumper>, // taken in turn
+    pub stinger_every: u64,    // answers between stingers, 0 is none
+}
+
+impl Default for BumpersConfig {
+    fn default() -> Self {
+        BumpersConfig {
+            intro: Some(Bumper::default()),
+            outro: Bumper::default(),
+            stingers: Vec::new(),
+            stinger_every: 0,
+        }
+    }
+}
+
+impl BumpersConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read bumpers config {}: {}", path, e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Failed to parse bumpers config {}: {}", path, e))
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        if args.bumpers_config.is_empty() {
+            return BumpersConfig::default();
+        }
+        BumpersConfig::load(&args.bumpers_config).unwrap_or_else(|e| {
+            error!("{}, using the persona greeting", e);
+            BumpersConfig::default()
+        })
+    }
+}
+
+pub struct Bumpers {
+    config: BumpersConfig,
+    next_stinger: usize,
+}
+
+impl Bumpers {
+    pub fn new(config: BumpersConfig) -> Self {
+        Bumpers {
+            config,
+            next_stinger: 0,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        Bumpers::new(BumpersConfig::from_args(args))
+    }
+
+    // Opening of the show, shown with the persona image
+    pub fn intro(&self, persona: &Persona, args: &Args) -> Option<MessageData> {
+        let intro = self.config.intro.as_ref()?;
+        let output_id = Uuid::new_v4().simple().to_string();
+        Some(intro.message(persona, &persona.image_prompt, &output_id, args))
+    }
+
+    // Close of the answer, it marks the last message of the answer for the pipeline. Without
+    // an image prompt the text is drawn. It is queued at normal priority so it plays after
+    // the paragraphs of the answer still waiting for the output.
+    pub fn outro(&self, persona: &Persona, output_id: &str, args: &Args) -> MessageData {
+        let outro = &self.config.outro;
+        outro
+            .message(persona, &outro.text(persona), output_id, args)
+            .with_priority(Priority::Normal)
+            .with_last_message(true)
+    }
+
+    // The stinger due after the answer, if it is one that gets one
+    fn due_stinger(&self, answer: u64) -> Option<&Bumper> {
+        if self.config.stinger_every == 0
+            || self.config.stingers.is_empty()
+            || !answer.is_multiple_of(self.config.stinger_every)
+        {
+            return None;
+        }
+        Some(&self.config.stingers[self.next_stinger % self.config.stingers.len()])
+    }
+
+    // The next stinger when the answer is one that gets one
+    pub fn stinger(&mut self, answer: u64, persona: &Persona, args: &Args) -> Option<MessageData> {
+        let output_id = Uuid::new_v4().simple().to_string();
+        // after the outro in the output order, like the outro
+        let message = self
+            .due_stinger(answer)?
+            .message(persona, &persona.image_prompt, &output_id, args)
+            .with_priority(Priority::Normal);
+        self.next_stinger += 1;
+        Some(message)
+    }
+
+    // The outro and stinger that will close the answer, for the prefetch. The stinger is not
+    // taken, stinger() still hands it out after the answer.
+    pub fn upcoming(
+        &self,
+        answer: u64,
+        persona: &Persona,
+        output_id: &str,
+        args: &Args,
+    ) -> Vec<MessageData> {
+        let mut upcoming = vec![self.outro(persona, output_id, args)];
+        if let Some(stinger) = self.due_stinger(answer) {
+            upcoming.push(stinger.message(persona, &persona.image_prompt, output_id, args));
+        }
+        upcoming
+    }
+}
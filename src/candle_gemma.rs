@@ -2,15 +2,18 @@ use anyhow::{Error as E, Result};
 use log::{debug, info};
 use std::io::Write;
 
+use crate::run_record::record_model_files;
+use crate::sampling::{Sampler, SamplingConfig};
+use crate::token_stream::{coalesce_tokens, TokenBatch, TokenBatching};
 use candle_transformers::models::gemma::{Config, Model};
 use tokio::sync::mpsc::Sender;
 
 use candle_core::{DType, Device, Tensor};
 use candle_examples::token_output_stream::TokenOutputStream;
-use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
 use candle_hf_hub::{api::sync::Api, Repo, RepoType};
+use candle_nn::VarBuilder;
 use safetensors::tensor::View;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
@@ -19,10 +22,11 @@ struct TextGeneration {
     model: Model,
     device: Device,
     tokenizer: TokenOutputStream,
-    logits_processor: LogitsProcessor,
+    sampler: Sampler,
     repeat_penalty: f32,
     repeat_last_n: usize,
     internal_token_sender: Sender<String>,
+    cancel: Arc<AtomicBool>, // set by the llm service when it gives up on the answer
 }
 
 impl TextGeneration {
@@ -31,26 +35,28 @@ impl TextGeneration {
         model: Model,
         tokenizer: Tokenizer,
         seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
+        sampling: SamplingConfig,
         repeat_penalty: f32,
         repeat_last_n: usize,
         device: &Device,
         internal_token_sender: Sender<String>,
+        cancel: Arc<AtomicBool>,
     ) -> Self {
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
+        let sampler = Sampler::new(seed, sampling);
         Self {
             model,
             tokenizer: TokenOutputStream::new(tokenizer),
-            logits_processor,
+            sampler,
             repeat_penalty,
             repeat_last_n,
             device: device.clone(),
             internal_token_sender,
+            cancel,
         }
     }
 
-    async fn run(&mut self, prompt: &str, sample_len: usize) -> Result<()> {
+    // Runs on a blocking thread, the forward passes don't yield to the runtime
+    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<()> {
         let verbose_prompt: bool = false;
         let clear_kv_cache = true;
 
@@ -89,6 +95,11 @@ impl TextGeneration {
             None => anyhow::bail!("cannot find the <eos> token"),
         };
         for index in 0..sample_len {
+            // the llm service gave up on the answer, no more forward passes for it
+            if self.cancel.load(Ordering::SeqCst) {
+                info!("Generation cancelled at token {}", index);
+                break;
+            }
             let context_size = if index > 0 { 1 } else { tokens.len() };
             let start_pos = tokens.len().saturating_sub(context_size);
             let ctxt = &tokens[start_pos..];
@@ -166,16 +177,20 @@ impl TextGeneration {
                 )?
             };
 
-            let next_token = self.logits_processor.sample(&logits)?;
+            let next_token = self.sampler.sample(&logits)?;
             tokens.push(next_token);
             if next_token == eos_token {
                 break;
             }
             if let Some(t) = self.tokenizer.next_token(next_token)? {
-                self.internal_token_sender
-                    .send(t.clone())
-                    .await
-                    .expect("Failed to send token internally");
+                // a closed receiver cancelled the answer, the watchdog gave up on it
+                if self.internal_token_sender.blocking_send(t.clone()).is_err() {
+                    info!(
+                        "Token receiver closed, generation cancelled at token {}",
+                        index
+                    );
+                    break;
+                }
             }
         }
 
@@ -183,24 +198,165 @@ impl TextGeneration {
     }
 }
 
+// Gemma weights, tokenizer and device, loaded once and kept by the llm service
+pub struct GemmaModel {
+    model: Arc<std::sync::Mutex<Option<Model>>>, // None while a generation has it
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+// Hub repo of the model id like 2b-it
+pub fn gemma_repo(model_id: Option<String>) -> String {
+    match &model_id {
+        Some(model_id) => match model_id.as_str() {
+            "7b" => "google/gemma-7b".to_string(),
+            "7b-it" => "google/gemma-7b-it".to_string(),
+            "2b" => "google/gemma-2b".to_string(),
+            "2b-it" => "google/gemma-2b-it".to_string(),
+            "auto" => "google/gemma-2b-it".to_string(),
+            _ => model_id.to_string(),
+        },
+        None => "google/gemma-2b-it".to_string(),
+    }
+}
+
+impl GemmaModel {
+    pub fn load(model_id: Option<String>) -> Result<Self> {
+        let cpu = false;
+        let revision: String = "main".to_string();
+        let tokenizer_file: Option<String> = None;
+        let config_file: Option<String> = None;
+        let weight_files: Option<String> = None;
+
+        let start = std::time::Instant::now();
+        let api = Api::new()?;
+        let model_id = gemma_repo(model_id);
+        let repo = api.repo(Repo::with_revision(
+            model_id.clone(),
+            RepoType::Model,
+            revision,
+        ));
+        let tokenizer_filename = match tokenizer_file {
+            Some(file) => std::path::PathBuf::from(file),
+            None => repo.get("tokenizer.json")?,
+        };
+        let config_filename = match config_file {
+            Some(file) => std::path::PathBuf::from(file),
+            None => repo.get("config.json")?,
+        };
+        let filenames = match weight_files {
+            Some(files) => files
+                .split(',')
+                .map(std::path::PathBuf::from)
+                .collect::<Vec<_>>(),
+            None => candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json")?,
+        };
+        info!("retrieved the files in {:?}", start.elapsed());
+        record_model_files(&model_id, &filenames);
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        let config: Config = serde_json::from_reader(std::fs::File::open(config_filename)?)?;
+
+        let start = std::time::Instant::now();
+        let device = candle_examples::device(cpu)?;
+        let dtype = if device.is_cuda() {
+            DType::BF16
+        } else {
+            DType::F32
+        };
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
+        let model = Model::new(false, &config, vb)?;
+
+        info!("loaded the model in {:?}", start.elapsed());
+
+        Ok(GemmaModel {
+            model: Arc::new(std::sync::Mutex::new(Some(model))),
+            tokenizer,
+            device,
+        })
+    }
+
+    // Whether an abandoned generation still has the model
+    pub fn is_busy(&self) -> bool {
+        self.model.lock().unwrap().is_none()
+    }
+
+    // Generate for the prompt and return once all the tokens are sent
+    pub async fn generate(
+        &mut self,
+        prompt: &str,
+        sample_len: usize,
+        sampling: SamplingConfig,
+        external_sender: Sender<TokenBatch>,
+        batching: TokenBatching,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let seed = sampling.seed.unwrap_or_else(rand::random);
+        let repeat_penalty = 1.1;
+        let repeat_last_n = 64; //(sample_len / 4) + prompt.len();
+        info!(
+            "temp: {:.2} top-k: {} top-p: {:.2} min-p: {:.2} typical-p: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
+            sampling.temperature,
+            sampling.top_k,
+            sampling.top_p,
+            sampling.min_p,
+            sampling.typical_p,
+            repeat_penalty,
+            repeat_last_n
+        );
+
+        let model = self
+            .model
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| E::msg("the gemma model is not loaded"))?;
+        let (internal_sender, internal_receiver) = tokio::sync::mpsc::channel::<String>(32);
+        let coalescer = tokio::spawn(coalesce_tokens(
+            internal_receiver,
+            external_sender,
+            batching,
+        ));
+
+        // the forward passes block, they run on a blocking thread the llm service can walk
+        // away from when they hang, the model goes back to its slot when they are done. The
+        // pipeline holds the token sender, dropping it with the pipeline ends the coalescer.
+        let mut pipeline = TextGeneration::new(
+            model,
+            self.tokenizer.clone(),
+            seed,
+            sampling,
+            repeat_penalty,
+            repeat_last_n,
+            &self.device,
+            internal_sender,
+            cancel,
+        );
+        let slot = Arc::clone(&self.model);
+        let prompt = prompt.to_string();
+        let result = tokio::task::spawn_blocking(move || {
+            let result = pipeline.run(&prompt, sample_len);
+            *slot.lock().unwrap() = Some(pipeline.model);
+            result
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("the generation failed: {}", e))
+        .and_then(|result| result);
+
+        let _ = coalescer.await;
+        result
+    }
+}
+
 pub fn gemma(
     prompt: String,
     sample_len: usize,
     temperature: f64,
     _quantized: bool,
     model_id: Option<String>,
-    external_sender: Sender<String>,
+    external_sender: Sender<TokenBatch>,
+    batching: TokenBatching,
 ) -> Result<()> {
-    let cpu = false;
     let tracing = false;
-    let top_p: Option<f64> = None;
-    let seed = rand::random();
-    let revision: String = "main".to_string();
-    let tokenizer_file: Option<String> = None;
-    let config_file: Option<String> = None;
-    let weight_files: Option<String> = None;
-    let repeat_penalty = 1.1;
-    let repeat_last_n = 64; //(sample_len / 4) + prompt.len();
 
     use tracing_chrome::ChromeLayerBuilder;
     use tracing_subscriber::prelude::*;
@@ -219,93 +375,28 @@ pub fn gemma(
         candle_core::utils::with_simd128(),
         candle_core::utils::with_f16c()
     );
-    info!(
-        "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-        temperature, repeat_penalty, repeat_last_n
-    );
-
-    let start = std::time::Instant::now();
-    let api = Api::new()?;
-    let model_id = match &model_id {
-        Some(model_id) => match model_id.as_str() {
-            "7b" => "google/gemma-7b".to_string(),
-            "7b-it" => "google/gemma-7b-it".to_string(),
-            "2b" => "google/gemma-2b".to_string(),
-            "2b-it" => "google/gemma-2b-it".to_string(),
-            "auto" => "google/gemma-2b-it".to_string(),
-            _ => model_id.to_string(),
-        },
-        None => "google/gemma-2b-it".to_string(),
-    };
-    let repo = api.repo(Repo::with_revision(model_id, RepoType::Model, revision));
-    let tokenizer_filename = match tokenizer_file {
-        Some(file) => std::path::PathBuf::from(file),
-        None => repo.get("tokenizer.json")?,
-    };
-    let config_filename = match config_file {
-        Some(file) => std::path::PathBuf::from(file),
-        None => repo.get("config.json")?,
-    };
-    let filenames = match weight_files {
-        Some(files) => files
-            .split(',')
-            .map(std::path::PathBuf::from)
-            .collect::<Vec<_>>(),
-        None => candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json")?,
-    };
-    info!("retrieved the files in {:?}", start.elapsed());
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-    let config: Config = serde_json::from_reader(std::fs::File::open(config_filename)?)?;
-
-    let start = std::time::Instant::now();
-    let device = candle_examples::device(cpu)?;
-    let dtype = if device.is_cuda() {
-        DType::BF16
-    } else {
-        DType::F32
-    };
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-    let model = Model::new(false, &config, vb)?;
-
-    info!("loaded the model in {:?}", start.elapsed());
-
-    let (internal_sender, mut internal_receiver) = tokio::sync::mpsc::channel::<String>(32); // Example buffer size
-
-    let pipeline = TextGeneration::new(
-        model,
-        tokenizer,
-        seed,
-        Some(temperature),
-        top_p,
-        repeat_penalty,
-        repeat_last_n,
-        &device,
-        internal_sender,
-    );
 
-    let pipeline = Arc::new(Mutex::new(pipeline));
+    let model = GemmaModel::load(model_id)?;
+    let model = Arc::new(Mutex::new(model));
 
     // Start the text generation in a separate thread
-    let pipeline_clone = pipeline.clone();
-    let prompt_clone = prompt.clone();
     tokio::spawn(async move {
-        let mut pipeline = pipeline_clone.lock().await;
-        match pipeline.run(&prompt_clone, sample_len).await {
+        let mut model = model.lock().await;
+        match model
+            .generate(
+                &prompt,
+                sample_len,
+                SamplingConfig::default().with_temperature(temperature),
+                external_sender,
+                batching,
+                Arc::new(AtomicBool::new(false)),
+            )
+            .await
+        {
             Ok(_) => {}
             Err(e) => log::error!("Failed to run the pipeline: {}", e),
         }
     });
 
-    // Set up a thread to listen on the internal receiver and forward messages to the external sender
-    let external_sender_clone = external_sender.clone();
-    tokio::spawn(async move {
-        while let Some(token) = internal_receiver.recv().await {
-            if let Err(e) = external_sender_clone.send(token).await {
-                log::error!("Failed to send token externally: {}", e);
-                break;
-            }
-        }
-    });
-
     Ok(())
 }
@@ -1,34 +1,132 @@
+/*
+ * candle_metavoice.rs
+ * -------------------
+ * MetaVoice text to speech with candle. The models are loaded once, the text is synthesized in
+ * sentence chunks that are handed on as each one is done, the voice comes from a speaker
+ * embedding or a reference WAV and the audio is resampled to the configured rate.
+*/
+
+use crate::args::Args;
+#[cfg(feature = "metavoice")]
+use crate::audio::{decode_audio, resample};
+use crate::segmenter::sentence_end_position;
 #[cfg(feature = "metavoice")]
 use anyhow::{Error, Result};
 #[cfg(feature = "metavoice")]
 use bytes::Bytes;
 #[cfg(feature = "metavoice")]
 use std::io::Cursor;
-#[cfg(feature = "metavoice")]
-use std::io::Write;
 
 #[cfg(feature = "metavoice")]
 use candle_transformers::generation::LogitsProcessor;
 #[cfg(feature = "metavoice")]
 use candle_transformers::models::encodec;
 #[cfg(feature = "metavoice")]
-use candle_transformers::models::metavoice::{adapters, gpt, tokenizers, transformer};
+use candle_transformers::models::metavoice::{
+    adapters, gpt, speaker_encoder, tokenizers, transformer,
+};
 #[cfg(feature = "metavoice")]
 use candle_transformers::models::quantized_metavoice::transformer as qtransformer;
 
 #[cfg(feature = "metavoice")]
-use candle_core::{DType, IndexOp, Tensor};
-#[cfg(feature = "metavoice")]
-use candle_nn::VarBuilder;
+use candle_core::{DType, Device, IndexOp, Tensor};
 #[cfg(feature = "metavoice")]
 use candle_hf_hub::api::sync::Api;
 #[cfg(feature = "metavoice")]
-use rand::Rng;
+use candle_nn::VarBuilder;
+#[cfg(feature = "metavoice")]
+use once_cell::sync::Lazy;
 #[cfg(feature = "metavoice")]
 use rand::{distributions::Distribution, SeedableRng};
+#[cfg(feature = "metavoice")]
+use std::collections::HashMap;
+#[cfg(feature = "metavoice")]
+use std::sync::Mutex;
 
 pub const ENCODEC_NTOKENS: u32 = 1024;
 
+// Sample rate of the encodec audio
+pub const METAVOICE_SAMPLE_RATE: u32 = 24_000;
+
+// MetaVoice settings from the command line
+#[derive(Debug, Clone)]
+pub struct MetaVoiceOptions {
+    pub speaker: String, // speaker embedding .safetensors or reference .wav, empty for the default
+    pub speaker_encoder: String, // speaker encoder weights used for a reference .wav
+    pub sample_rate: u32,
+    pub chunk_chars: usize,
+    pub guidance_scale: f64,
+    pub temperature: f64,
+    pub seed: u64, // 0 for a random seed per paragraph
+    pub max_tokens: usize,
+    pub quantized: bool,
+    pub cpu: bool,
+}
+
+impl MetaVoiceOptions {
+    pub fn new() -> Self {
+        MetaVoiceOptions {
+            speaker: String::new(),
+            speaker_encoder: String::new(),
+            sample_rate: METAVOICE_SAMPLE_RATE,
+            chunk_chars: 200,
+            guidance_scale: 3.0,
+            temperature: 1.0,
+            seed: 299792458,
+            max_tokens: 2000,
+            quantized: true,
+            cpu: false,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        MetaVoiceOptions {
+            speaker: args.metavoice_speaker.clone(),
+            speaker_encoder: args.metavoice_speaker_encoder.clone(),
+            sample_rate: args.metavoice_sample_rate,
+            chunk_chars: args.metavoice_chunk_chars,
+            guidance_scale: args.metavoice_guidance_scale,
+            temperature: args.metavoice_temperature,
+            seed: args.metavoice_seed,
+            ..MetaVoiceOptions::new()
+        }
+    }
+}
+
+impl Default for MetaVoiceOptions {
+    fn default() -> Self {
+        MetaVoiceOptions::new()
+    }
+}
+
+// Split the text at sentence ends into chunks of up to max_chars, a longer sentence is a chunk
+// of its own and 0 keeps the text in one chunk
+pub fn text_chunks(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = sentence_end_position(rest).unwrap_or(rest.len()).max(1);
+        let (sentence, tail) = rest.split_at(end);
+        rest = tail;
+        let sentence = sentence.trim();
+        if sentence.is_empty() {
+            continue;
+        }
+        if max_chars > 0 && !current.is_empty() && current.len() + 1 + sentence.len() > max_chars {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(sentence);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
 #[cfg(feature = "metavoice")]
 enum Transformer {
     Normal(transformer::Model),
@@ -36,206 +134,362 @@ enum Transformer {
 }
 
 #[cfg(feature = "metavoice")]
-pub async fn metavoice(prompt: String) -> Result<Bytes, Error> {
-    use tracing_chrome::ChromeLayerBuilder;
-    use tracing_subscriber::prelude::*;
-
-    let show_status = false;
-    let tracing = false;
-    let cpu = false;
-    let guidance_scale = 3.0;
-    let temperature = 1.0;
-    // Override seed for now
-    let mut seed: Option<u64> = Some(299792458);
-    let max_tokens = 2000;
-    let first_stage_meta: Option<String> = None;
-    let first_stage_weights: Option<String> = None;
-    let second_stage_weights: Option<String> = None;
-    let encodec_weights: Option<String> = None;
-    let spk_emb: Option<String> = None;
-    let dtype = DType::F32;
-    let quantized = true;
-
-    if seed.is_none() {
-        seed = Some(rand::random());
+impl Transformer {
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        spk_emb: &Tensor,
+        pos: usize,
+    ) -> candle_core::Result<Tensor> {
+        match self {
+            Transformer::Normal(m) => m.forward(xs, spk_emb, pos),
+            Transformer::Quantized(m) => m.forward(xs, spk_emb, pos),
+        }
     }
 
-    let _guard = if tracing {
-        let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
-        tracing_subscriber::registry().with(chrome_layer).init();
-        Some(guard)
-    } else {
-        None
-    };
+    fn clear_kv_cache(&mut self) {
+        match self {
+            Transformer::Normal(m) => m.clear_kv_cache(),
+            Transformer::Quantized(m) => m.clear_kv_cache(),
+        }
+    }
+}
 
-    let device = candle_examples::device(cpu)?;
-    let api = Api::new()?;
-    let repo = api.model("lmz/candle-metavoice".to_string());
-    let first_stage_meta = match &first_stage_meta {
-        Some(w) => std::path::PathBuf::from(w),
-        None => repo.get("first_stage.meta.json")?,
-    };
-    let first_stage_meta: serde_json::Value =
-        serde_json::from_reader(&std::fs::File::open(first_stage_meta)?)?;
-    let first_stage_tokenizer = match first_stage_meta.as_object() {
-        None => anyhow::bail!("not a json object"),
-        Some(j) => match j.get("tokenizer") {
-            None => anyhow::bail!("no tokenizer key"),
-            Some(j) => j,
-        },
-    };
-    let fs_tokenizer = tokenizers::BPE::from_json(first_stage_tokenizer, 512)?;
+// Models kept loaded between paragraphs
+#[cfg(feature = "metavoice")]
+struct MetaVoiceModels {
+    quantized: bool,
+    cpu: bool,
+    device: Device,
+    dtype: DType,
+    fs_tokenizer: tokenizers::BPE,
+    first_stage: Transformer,
+    second_stage: gpt::Model,
+    second_stage_config: gpt::Config,
+    encodec_device: Device,
+    encodec: encodec::Model,
+    default_spk_emb: Tensor,
+    speakers: HashMap<String, Tensor>,
+}
 
-    let second_stage_weights = match &second_stage_weights {
-        Some(w) => std::path::PathBuf::from(w),
-        None => repo.get("second_stage.safetensors")?,
-    };
-    let encodec_weights = match encodec_weights {
-        Some(w) => std::path::PathBuf::from(w),
-        None => Api::new()?
+#[cfg(feature = "metavoice")]
+static METAVOICE_MODELS: Lazy<Mutex<Option<MetaVoiceModels>>> = Lazy::new(|| Mutex::new(None));
+
+#[cfg(feature = "metavoice")]
+impl MetaVoiceModels {
+    fn load(options: &MetaVoiceOptions) -> Result<Self> {
+        let dtype = DType::F32;
+        let device = candle_examples::device(options.cpu)?;
+        let api = Api::new()?;
+        let repo = api.model("lmz/candle-metavoice".to_string());
+
+        let first_stage_meta: serde_json::Value =
+            serde_json::from_reader(&std::fs::File::open(repo.get("first_stage.meta.json")?)?)?;
+        let first_stage_tokenizer = match first_stage_meta.as_object() {
+            None => anyhow::bail!("not a json object"),
+            Some(j) => match j.get("tokenizer") {
+                None => anyhow::bail!("no tokenizer key"),
+                Some(j) => j,
+            },
+        };
+        let fs_tokenizer = tokenizers::BPE::from_json(first_stage_tokenizer, 512)?;
+
+        let first_stage_config = transformer::Config::cfg1b_v0_1();
+        let first_stage = if options.quantized {
+            let filename = repo.get("first_stage_q4k.gguf")?;
+            let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
+                filename, &device,
+            )?;
+            Transformer::Quantized(qtransformer::Model::new(&first_stage_config, vb)?)
+        } else {
+            let first_stage_weights = repo.get("first_stage.safetensors")?;
+            let first_stage_vb = unsafe {
+                VarBuilder::from_mmaped_safetensors(&[first_stage_weights], dtype, &device)?
+            };
+            Transformer::Normal(transformer::Model::new(
+                &first_stage_config,
+                first_stage_vb,
+            )?)
+        };
+
+        let second_stage_weights = repo.get("second_stage.safetensors")?;
+        let second_stage_vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[second_stage_weights], dtype, &device)?
+        };
+        let second_stage_config = gpt::Config::cfg1b_v0_1();
+        let second_stage = gpt::Model::new(second_stage_config.clone(), second_stage_vb)?;
+
+        // encodec doesn't run on metal yet
+        let encodec_device = if device.is_metal() {
+            Device::Cpu
+        } else {
+            device.clone()
+        };
+        let encodec_weights = api
             .model("facebook/encodec_24khz".to_string())
-            .get("model.safetensors")?,
-    };
-    let first_stage_config = transformer::Config::cfg1b_v0_1();
-    let mut first_stage_model = if quantized {
-        let filename = match &first_stage_weights {
-            Some(w) => std::path::PathBuf::from(w),
-            None => repo.get("first_stage_q4k.gguf")?,
+            .get("model.safetensors")?;
+        let encodec_vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[encodec_weights], dtype, &encodec_device)?
         };
-        let vb =
-            candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename, &device)?;
-        let first_stage_model = qtransformer::Model::new(&first_stage_config, vb)?;
-        Transformer::Quantized(first_stage_model)
-    } else {
-        let first_stage_weights = match &first_stage_weights {
-            Some(w) => std::path::PathBuf::from(w),
-            None => repo.get("first_stage.safetensors")?,
+        let encodec = encodec::Model::new(&encodec::Config::default(), encodec_vb)?;
+
+        let default_spk_emb =
+            load_spk_emb(&repo.get("spk_emb.safetensors")?.to_string_lossy(), dtype)?
+                .to_device(&device)?;
+
+        Ok(MetaVoiceModels {
+            quantized: options.quantized,
+            cpu: options.cpu,
+            device,
+            dtype,
+            fs_tokenizer,
+            first_stage,
+            second_stage,
+            second_stage_config,
+            encodec_device,
+            encodec,
+            default_spk_emb,
+            speakers: HashMap::new(),
+        })
+    }
+
+    // Speaker embedding of the voice, computed once per file
+    fn speaker(&mut self, options: &MetaVoiceOptions) -> Result<Tensor> {
+        if options.speaker.is_empty() {
+            return Ok(self.default_spk_emb.clone());
+        }
+        if let Some(spk_emb) = self.speakers.get(&options.speaker) {
+            return Ok(spk_emb.clone());
+        }
+        let spk_emb = if options.speaker.ends_with(".safetensors") {
+            load_spk_emb(&options.speaker, self.dtype)?
+        } else {
+            self.embed_reference_wav(options)?
         };
-        let first_stage_vb =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[first_stage_weights], dtype, &device)? };
-        let first_stage_model = transformer::Model::new(&first_stage_config, first_stage_vb)?;
-        Transformer::Normal(first_stage_model)
-    };
+        let spk_emb = spk_emb
+            .reshape(self.default_spk_emb.shape())?
+            .to_device(&self.device)?;
+        log::info!("MetaVoice: using the voice of {}", options.speaker);
+        self.speakers
+            .insert(options.speaker.clone(), spk_emb.clone());
+        Ok(spk_emb)
+    }
 
-    let second_stage_vb =
-        unsafe { VarBuilder::from_mmaped_safetensors(&[second_stage_weights], dtype, &device)? };
-    let second_stage_config = gpt::Config::cfg1b_v0_1();
-    let second_stage_model = gpt::Model::new(second_stage_config.clone(), second_stage_vb)?;
+    fn embed_reference_wav(&self, options: &MetaVoiceOptions) -> Result<Tensor> {
+        let config = speaker_encoder::Config::cfg();
+        let weights = if options.speaker_encoder.is_empty() {
+            Api::new()?
+                .model("lmz/candle-metavoice".to_string())
+                .get("speaker_encoder.safetensors")
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "no speaker encoder weights for {}, set --metavoice-speaker-encoder: {}",
+                        options.speaker,
+                        e
+                    )
+                })?
+        } else {
+            std::path::PathBuf::from(&options.speaker_encoder)
+        };
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DType::F32, &Device::Cpu)? };
+        let model = speaker_encoder::Model::new(config.clone(), vb)?;
 
-    let encodec_device = if device.is_metal() {
-        &candle_core::Device::Cpu
-    } else {
-        &device
-    };
-    let encodec_vb =
-        unsafe { VarBuilder::from_mmaped_safetensors(&[encodec_weights], dtype, encodec_device)? };
-    let encodec_config = encodec::Config::default();
-    let encodec_model = encodec::Model::new(&encodec_config, encodec_vb)?;
-
-    log::debug!("prompt: '{}'", prompt);
-    let prompt_tokens = fs_tokenizer.encode(&prompt)?;
-    let mut tokens = prompt_tokens.clone();
-    log::debug!("{tokens:?}");
-    let spk_emb_file = match &spk_emb {
-        Some(w) => std::path::PathBuf::from(w),
-        None => repo.get("spk_emb.safetensors")?,
-    };
-    let spk_emb = candle_core::safetensors::load(&spk_emb_file, &candle_core::Device::Cpu)?;
-    let spk_emb = match spk_emb.get("spk_emb") {
-        None => anyhow::bail!("missing spk_emb tensor in {spk_emb_file:?}"),
-        Some(spk_emb) => spk_emb.to_dtype(dtype)?,
-    };
-    let spk_emb = spk_emb.to_device(&device)?;
-    let seed_u64 = seed.unwrap_or_else(|| rand::thread_rng().gen());
-    let mut logits_processor = LogitsProcessor::new(seed_u64, Some(temperature), Some(0.95));
-
-    // First stage generation.
-    for index in 0..max_tokens {
-        let context_size = if index > 0 { 1 } else { tokens.len() };
-        let start_pos = tokens.len().saturating_sub(context_size);
-        let ctxt = &tokens[start_pos..];
-        let input = Tensor::new(ctxt, &device)?;
-        let input = Tensor::stack(&[&input, &input], 0)?;
-        let logits = match &mut first_stage_model {
-            Transformer::Normal(m) => m.forward(&input, &spk_emb, tokens.len() - context_size)?,
-            Transformer::Quantized(m) => {
-                m.forward(&input, &spk_emb, tokens.len() - context_size)?
+        let (samples, sample_rate) = decode_audio(std::fs::read(&options.speaker)?, 16_000)?;
+        let samples = resample(&samples, sample_rate, config.sampling_rate as u32)?;
+        let filters = mel_filters(
+            config.sampling_rate,
+            config.mel_window_length,
+            config.mel_n_channels,
+        );
+        let spk_emb = model.embed_utterance(&samples, &filters, 1.3, 0.75, &Device::Cpu)?;
+        Ok(spk_emb.to_dtype(self.dtype)?)
+    }
+
+    // Synthesize one chunk of text to 24 kHz samples
+    fn synthesize(
+        &mut self,
+        prompt: &str,
+        spk_emb: &Tensor,
+        options: &MetaVoiceOptions,
+        seed: u64,
+    ) -> Result<Vec<f32>> {
+        let device = self.device.clone();
+        log::debug!("prompt: '{}'", prompt);
+        let prompt_tokens = self.fs_tokenizer.encode(prompt)?;
+        let mut tokens = prompt_tokens.clone();
+        log::debug!("{tokens:?}");
+        let mut logits_processor =
+            LogitsProcessor::new(seed, Some(options.temperature), Some(0.95));
+
+        // First stage generation, the cache of the previous chunk is dropped
+        self.first_stage.clear_kv_cache();
+        for index in 0..options.max_tokens {
+            let context_size = if index > 0 { 1 } else { tokens.len() };
+            let start_pos = tokens.len().saturating_sub(context_size);
+            let ctxt = &tokens[start_pos..];
+            let input = Tensor::new(ctxt, &device)?;
+            let input = Tensor::stack(&[&input, &input], 0)?;
+            let logits = self
+                .first_stage
+                .forward(&input, spk_emb, tokens.len() - context_size)?;
+            let logits0 = logits.i((0, 0))?;
+            let logits1 = logits.i((1, 0))?;
+            let logits =
+                ((logits0 * options.guidance_scale)? + logits1 * (1. - options.guidance_scale))?;
+            let logits = logits.to_dtype(DType::F32)?;
+            let next_token = logits_processor.sample(&logits)?;
+            tokens.push(next_token);
+            if next_token == 2048 {
+                break;
             }
-        };
-        let logits0 = logits.i((0, 0))?;
-        let logits1 = logits.i((1, 0))?;
-        let logits = ((logits0 * guidance_scale)? + logits1 * (1. - guidance_scale))?;
-        let logits = logits.to_dtype(DType::F32)?;
-        let next_token = logits_processor.sample(&logits)?;
-        tokens.push(next_token);
-        if show_status {
-            print!(".");
         }
-        std::io::stdout().flush()?;
-        if next_token == 2048 {
-            break;
+        let fie2c = adapters::FlattenedInterleavedEncodec2Codebook::new(ENCODEC_NTOKENS);
+        let (text_ids, ids1, ids2) = fie2c.decode(&tokens);
+        log::debug!("text ids len: {}", text_ids.len());
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed + 1337);
+        // TODO: Use the config rather than hardcoding the offset here.
+        let encoded_text: Vec<_> = prompt_tokens.iter().map(|v| v - 1024).collect();
+        let mut hierarchies_in1 =
+            [encoded_text.as_slice(), ids1.as_slice(), &[ENCODEC_NTOKENS]].concat();
+        let mut hierarchies_in2 = [
+            vec![ENCODEC_NTOKENS; encoded_text.len()].as_slice(),
+            ids2.as_slice(),
+            &[ENCODEC_NTOKENS],
+        ]
+        .concat();
+        hierarchies_in1.resize(self.second_stage_config.block_size, ENCODEC_NTOKENS);
+        hierarchies_in2.resize(self.second_stage_config.block_size, ENCODEC_NTOKENS);
+        let in_x1 = Tensor::new(hierarchies_in1, &device)?;
+        let in_x2 = Tensor::new(hierarchies_in2, &device)?;
+        let in_x = Tensor::stack(&[in_x1, in_x2], 0)?.unsqueeze(0)?;
+        let logits = self.second_stage.forward(&in_x)?;
+        log::debug!("sampling from logits...");
+        let mut codes = vec![];
+        for logits in logits.iter() {
+            let logits = logits.squeeze(0)?;
+            let (seq_len, _) = logits.dims2()?;
+            let mut codes_ = Vec::with_capacity(seq_len);
+            for step in 0..seq_len {
+                let logits = logits.i(step)?.to_dtype(DType::F32)?;
+                let logits = &(&logits / 1.0)?;
+                let prs = candle_nn::ops::softmax_last_dim(logits)?.to_vec1::<f32>()?;
+                let distr = rand::distributions::WeightedIndex::new(prs.as_slice())?;
+                let sample = distr.sample(&mut rng) as u32;
+                codes_.push(sample)
+            }
+            codes.push(codes_)
         }
+
+        let codes = Tensor::new(codes, &device)?.unsqueeze(0)?;
+        let codes = Tensor::cat(&[in_x, codes], 1)?;
+        log::debug!("codes: {codes}");
+        let tilted_encodec = adapters::TiltedEncodec::new(ENCODEC_NTOKENS);
+        let codes = codes.i(0)?.to_vec2::<u32>()?;
+        let (text_ids, audio_ids) = tilted_encodec.decode(&codes);
+        log::debug!("text_ids len: {:?}", text_ids.len());
+        let audio_ids = Tensor::new(audio_ids, &self.encodec_device)?.unsqueeze(0)?;
+        log::debug!("audio_ids shape: {:?}", audio_ids.shape());
+        let pcm = self.encodec.decode(&audio_ids)?;
+        log::debug!("output pcm shape: {:?}", pcm.shape());
+        let pcm = pcm.i(0)?.i(0)?.to_dtype(DType::F32)?;
+        let pcm = candle_examples::audio::normalize_loudness(&pcm, METAVOICE_SAMPLE_RATE, true)?;
+        Ok(pcm.to_vec1::<f32>()?)
     }
-    if show_status {
-        println!("");
+}
+
+#[cfg(feature = "metavoice")]
+fn load_spk_emb(path: &str, dtype: DType) -> Result<Tensor> {
+    let spk_emb = candle_core::safetensors::load(path, &Device::Cpu)?;
+    match spk_emb.get("spk_emb") {
+        None => anyhow::bail!("missing spk_emb tensor in {path}"),
+        Some(spk_emb) => Ok(spk_emb.to_dtype(dtype)?),
     }
-    let fie2c = adapters::FlattenedInterleavedEncodec2Codebook::new(ENCODEC_NTOKENS);
-    let (text_ids, ids1, ids2) = fie2c.decode(&tokens);
-    log::debug!("text ids len: {}", text_ids.len());
-    let mut rng = rand::rngs::StdRng::seed_from_u64(seed_u64 + 1337);
-    // TODO: Use the config rather than hardcoding the offset here.
-    let encoded_text: Vec<_> = prompt_tokens.iter().map(|v| v - 1024).collect();
-    let mut hierarchies_in1 =
-        [encoded_text.as_slice(), ids1.as_slice(), &[ENCODEC_NTOKENS]].concat();
-    let mut hierarchies_in2 = [
-        vec![ENCODEC_NTOKENS; encoded_text.len()].as_slice(),
-        ids2.as_slice(),
-        &[ENCODEC_NTOKENS],
-    ]
-    .concat();
-    hierarchies_in1.resize(second_stage_config.block_size, ENCODEC_NTOKENS);
-    hierarchies_in2.resize(second_stage_config.block_size, ENCODEC_NTOKENS);
-    let in_x1 = Tensor::new(hierarchies_in1, &device)?;
-    let in_x2 = Tensor::new(hierarchies_in2, &device)?;
-    let in_x = Tensor::stack(&[in_x1, in_x2], 0)?.unsqueeze(0)?;
-    let logits = second_stage_model.forward(&in_x)?;
-    log::debug!("sampling from logits...");
-    let mut codes = vec![];
-    for logits in logits.iter() {
-        let logits = logits.squeeze(0)?;
-        let (seq_len, _) = logits.dims2()?;
-        let mut codes_ = Vec::with_capacity(seq_len);
-        for step in 0..seq_len {
-            let logits = logits.i(step)?.to_dtype(DType::F32)?;
-            let logits = &(&logits / 1.0)?;
-            let prs = candle_nn::ops::softmax_last_dim(logits)?.to_vec1::<f32>()?;
-            let distr = rand::distributions::WeightedIndex::new(prs.as_slice())?;
-            let sample = distr.sample(&mut rng) as u32;
-            codes_.push(sample)
+}
+
+// Triangular mel filter bank, n_mels rows of n_fft / 2 + 1 frequency bins
+#[cfg(feature = "metavoice")]
+fn mel_filters(sample_rate: usize, n_fft: usize, n_mels: usize) -> Vec<f32> {
+    let n_freqs = n_fft / 2 + 1;
+    let hz_to_mel = |hz: f64| 2595.0 * (1.0 + hz / 700.0).log10();
+    let mel_to_hz = |mel: f64| 700.0 * (10f64.powf(mel / 2595.0) - 1.0);
+    let max_mel = hz_to_mel(sample_rate as f64 / 2.0);
+    let points: Vec<f64> = (0..n_mels + 2)
+        .map(|index| mel_to_hz(max_mel * index as f64 / (n_mels + 1) as f64))
+        .collect();
+
+    let mut filters = vec![0f32; n_mels * n_freqs];
+    for mel in 0..n_mels {
+        let (low, center, high) = (points[mel], points[mel + 1], points[mel + 2]);
+        let norm = 2.0 / (high - low);
+        for bin in 0..n_freqs {
+            let freq = bin as f64 * sample_rate as f64 / n_fft as f64;
+            let weight = if freq >= low && freq <= center {
+                (freq - low) / (center - low)
+            } else if freq > center && freq <= high {
+                (high - freq) / (high - center)
+            } else {
+                0.0
+            };
+            filters[mel * n_freqs + bin] = (weight * norm) as f32;
         }
-        codes.push(codes_)
     }
+    filters
+}
+
+// Synthesize the text a chunk at a time, each chunk's samples at the configured rate are passed
+// to on_chunk as soon as it is done
+#[cfg(feature = "metavoice")]
+pub fn metavoice_chunks(
+    prompt: &str,
+    options: &MetaVoiceOptions,
+    mut on_chunk: impl FnMut(&[f32]),
+) -> Result<()> {
+    let mut models = METAVOICE_MODELS.lock().unwrap();
+    let reload = match models.as_ref() {
+        Some(loaded) => loaded.quantized != options.quantized || loaded.cpu != options.cpu,
+        None => true,
+    };
+    if reload {
+        *models = None;
+        *models = Some(MetaVoiceModels::load(options)?);
+    }
+    let models = models.as_mut().unwrap();
+    let spk_emb = models.speaker(options)?;
 
-    let codes = Tensor::new(codes, &device)?.unsqueeze(0)?;
-    let codes = Tensor::cat(&[in_x, codes], 1)?;
-    log::debug!("codes: {codes}");
-    let tilted_encodec = adapters::TiltedEncodec::new(ENCODEC_NTOKENS);
-    let codes = codes.i(0)?.to_vec2::<u32>()?;
-    let (text_ids, audio_ids) = tilted_encodec.decode(&codes);
-    log::debug!("text_ids len: {:?}", text_ids.len());
-    let audio_ids = Tensor::new(audio_ids, encodec_device)?.unsqueeze(0)?;
-    log::debug!("audio_ids shape: {:?}", audio_ids.shape());
-    let pcm = encodec_model.decode(&audio_ids)?;
-    log::debug!("output pcm shape: {:?}", pcm.shape());
-    let pcm = pcm.i(0)?.i(0)?.to_dtype(DType::F32)?;
-    let pcm = candle_examples::audio::normalize_loudness(&pcm, 24_000, true)?;
-    let pcm = pcm.to_vec1::<f32>()?;
+    let seed = if options.seed == 0 {
+        rand::random()
+    } else {
+        options.seed
+    };
+    let chunks = text_chunks(prompt, options.chunk_chars);
+    for (index, chunk) in chunks.iter().enumerate() {
+        let start = std::time::Instant::now();
+        let pcm = models.synthesize(chunk, &spk_emb, options, seed)?;
+        let pcm = resample(&pcm, METAVOICE_SAMPLE_RATE, options.sample_rate)?;
+        log::debug!(
+            "MetaVoice: chunk {}/{} of {} samples in {:.2}s",
+            index + 1,
+            chunks.len(),
+            pcm.len(),
+            start.elapsed().as_secs_f32()
+        );
+        on_chunk(&pcm);
+    }
+    Ok(())
+}
+
+// Synthesize the whole text as a WAV file
+#[cfg(feature = "metavoice")]
+pub async fn metavoice(prompt: String, options: &MetaVoiceOptions) -> Result<Bytes, Error> {
+    let mut pcm = Vec::new();
+    metavoice_chunks(&prompt, options, |chunk| pcm.extend_from_slice(chunk))?;
 
     // Create a buffer to hold the WAV data
     let mut buffer = Cursor::new(Vec::new());
 
     // Write the PCM data to the buffer as a WAV file
-    candle_examples::wav::write_pcm_as_wav(&mut buffer, &pcm, 24_000)?;
+    candle_examples::wav::write_pcm_as_wav(&mut buffer, &pcm, options.sample_rate)?;
 
     // Get the buffer's content as Bytes
     let bytes = buffer.into_inner().into();
@@ -1,3 +1,6 @@
+use crate::run_record::record_model_files;
+use crate::sampling::{Sampler, SamplingConfig};
+use crate::token_stream::{coalesce_tokens, TokenBatch, TokenBatching};
 use anyhow::{Error as E, Result};
 use safetensors::tensor::View;
 use std::io::Write;
@@ -11,10 +14,10 @@ use candle_transformers::models::quantized_mistral::Model as QMistral;
 
 use candle_core::{DType, Device, Tensor};
 use candle_examples::token_output_stream::TokenOutputStream;
-use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
 use candle_hf_hub::{api::sync::Api, Repo, RepoType};
+use candle_nn::VarBuilder;
 use log::{debug, info};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
@@ -28,10 +31,11 @@ struct TextGeneration {
     model: Model,
     device: Device,
     tokenizer: TokenOutputStream,
-    logits_processor: LogitsProcessor,
+    sampler: Sampler,
     repeat_penalty: f32,
     repeat_last_n: usize,
     internal_token_sender: Sender<String>,
+    cancel: Arc<AtomicBool>, // set by the llm service when it gives up on the answer
 }
 
 impl TextGeneration {
@@ -40,26 +44,28 @@ impl TextGeneration {
         model: Model,
         tokenizer: Tokenizer,
         seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
+        sampling: SamplingConfig,
         repeat_penalty: f32,
         repeat_last_n: usize,
         device: &Device,
         internal_token_sender: Sender<String>,
+        cancel: Arc<AtomicBool>,
     ) -> Self {
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
+        let sampler = Sampler::new(seed, sampling);
         Self {
             model,
             tokenizer: TokenOutputStream::new(tokenizer),
-            logits_processor,
+            sampler,
             repeat_penalty,
             repeat_last_n,
             device: device.clone(),
             internal_token_sender,
+            cancel,
         }
     }
 
-    async fn run(&mut self, prompt: &str, sample_len: usize) -> Result<()> {
+    // Runs on a blocking thread, the forward passes don't yield to the runtime
+    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<()> {
         let verbose_prompt: bool = false;
         let clear_kv_cache = true;
         if clear_kv_cache {
@@ -100,6 +106,11 @@ impl TextGeneration {
             None => anyhow::bail!("cannot find the </s> token"),
         };
         for index in 0..sample_len {
+            // the llm service gave up on the answer, no more forward passes for it
+            if self.cancel.load(Ordering::SeqCst) {
+                info!("Generation cancelled at token {}", index);
+                break;
+            }
             let context_size = if index > 0 { 1 } else { tokens.len() };
             let start_pos = tokens.len().saturating_sub(context_size);
             let ctxt = &tokens[start_pos..];
@@ -203,16 +214,20 @@ impl TextGeneration {
                 )?
             };
 
-            let next_token = self.logits_processor.sample(&logits)?;
+            let next_token = self.sampler.sample(&logits)?;
             tokens.push(next_token);
             if next_token == eos_token {
                 break;
             }
             if let Some(t) = self.tokenizer.next_token(next_token)? {
-                self.internal_token_sender
-                    .send(t.clone())
-                    .await
-                    .expect("Failed to send token internally");
+                // a closed receiver cancelled the answer, the watchdog gave up on it
+                if self.internal_token_sender.blocking_send(t.clone()).is_err() {
+                    info!(
+                        "Token receiver closed, generation cancelled at token {}",
+                        index
+                    );
+                    break;
+                }
             }
         }
 
@@ -220,47 +235,16 @@ impl TextGeneration {
     }
 }
 
-pub fn mistral(
-    prompt: String,
-    sample_len: usize,
-    temperature: f64,
-    quantized: bool,
-    model_id: Option<String>,
-    external_sender: Sender<String>,
-) -> Result<()> {
-    let cpu = false;
-    let tracing = false;
-    let use_flash_attn = false;
-    let top_p: Option<f64> = None;
-    let seed = rand::random();
-    let revision: String = "main".to_string();
-    let tokenizer_file: Option<String> = None;
-    let weight_files: Option<String> = None;
-    let repeat_penalty = 1.1;
-    let repeat_last_n = (sample_len / 4) + prompt.len();
-
-    let _guard = if tracing {
-        let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
-        tracing_subscriber::registry().with(chrome_layer).init();
-        Some(guard)
-    } else {
-        None
-    };
-    debug!(
-        "avx: {}, neon: {}, simd128: {}, f16c: {}",
-        candle_core::utils::with_avx(),
-        candle_core::utils::with_neon(),
-        candle_core::utils::with_simd128(),
-        candle_core::utils::with_f16c()
-    );
-    info!(
-        "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-        temperature, repeat_penalty, repeat_last_n
-    );
+// Mistral weights, tokenizer and device, loaded once and kept by the llm service
+pub struct MistralModel {
+    model: Arc<std::sync::Mutex<Option<Model>>>, // None while a generation has it
+    tokenizer: Tokenizer,
+    device: Device,
+}
 
-    let start = std::time::Instant::now();
-    let api = Api::new()?;
-    let model_id = match &model_id {
+// Hub repo of the model id, auto or empty picks the default for the quantization
+pub fn mistral_repo(quantized: bool, model_id: Option<String>) -> String {
+    match &model_id {
         Some(model_id) => {
             if model_id.is_empty() || model_id.to_string() == "auto" {
                 if quantized {
@@ -283,89 +267,196 @@ pub fn mistral(
                 "mistralai/Mistral-7B-Instruct-v0.2".to_string()
             }
         }
-    };
+    }
+}
 
-    let repo = api.repo(Repo::with_revision(model_id, RepoType::Model, revision));
-    let tokenizer_filename = match tokenizer_file {
-        Some(file) => std::path::PathBuf::from(file),
-        None => repo.get("tokenizer.json")?,
-    };
-    let filenames = match weight_files {
-        Some(files) => files
-            .split(',')
-            .map(std::path::PathBuf::from)
-            .collect::<Vec<_>>(),
-        None => {
-            if quantized {
-                vec![repo.get("model-q4k.gguf")?]
-            } else {
-                candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json")?
+impl MistralModel {
+    pub fn load(quantized: bool, model_id: Option<String>) -> Result<Self> {
+        let cpu = false;
+        let use_flash_attn = false;
+        let revision: String = "main".to_string();
+        let tokenizer_file: Option<String> = None;
+        let weight_files: Option<String> = None;
+
+        let start = std::time::Instant::now();
+        let api = Api::new()?;
+        let model_id = mistral_repo(quantized, model_id);
+
+        let repo = api.repo(Repo::with_revision(
+            model_id.clone(),
+            RepoType::Model,
+            revision,
+        ));
+        let tokenizer_filename = match tokenizer_file {
+            Some(file) => std::path::PathBuf::from(file),
+            None => repo.get("tokenizer.json")?,
+        };
+        let filenames = match weight_files {
+            Some(files) => files
+                .split(',')
+                .map(std::path::PathBuf::from)
+                .collect::<Vec<_>>(),
+            None => {
+                if quantized {
+                    vec![repo.get("model-q4k.gguf")?]
+                } else {
+                    candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json")?
+                }
             }
-        }
-    };
-    info!("retrieved the files in {:?}", start.elapsed());
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-
-    let start = std::time::Instant::now();
-    let config = Config::config_7b_v0_1(use_flash_attn);
-    let device = candle_examples::device(cpu)?;
-    let (model, device) = if quantized {
-        let filename = &filenames[0];
-        let vb =
-            candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename, &device)?;
-        let model = QMistral::new(&config, vb)?;
-        (Model::Quantized(model), device)
-    } else {
-        let dtype = if device.is_cuda() {
-            DType::BF16
+        };
+        info!("retrieved the files in {:?}", start.elapsed());
+        record_model_files(&model_id, &filenames);
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+
+        let start = std::time::Instant::now();
+        let config = Config::config_7b_v0_1(use_flash_attn);
+        let device = candle_examples::device(cpu)?;
+        let (model, device) = if quantized {
+            let filename = &filenames[0];
+            let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
+                filename, &device,
+            )?;
+            let model = QMistral::new(&config, vb)?;
+            (Model::Quantized(model), device)
         } else {
-            DType::F32
+            let dtype = if device.is_cuda() {
+                DType::BF16
+            } else {
+                DType::F32
+            };
+            let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
+            let model = Mistral::new(&config, vb)?;
+            (Model::Mistral(model), device)
         };
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-        let model = Mistral::new(&config, vb)?;
-        (Model::Mistral(model), device)
-    };
 
-    info!("loaded the model in {:?}", start.elapsed());
-
-    let (internal_sender, mut internal_receiver) = mpsc::channel(32768);
-
-    // Pass both the internal and external senders to TextGeneration
-    let pipeline = TextGeneration::new(
-        model,
-        tokenizer,
-        seed,              // seed
-        Some(temperature), // temp
-        top_p,             // top_p
-        repeat_penalty,    // repeat_penalty
-        repeat_last_n,     // repeat_last_n
-        &device,
-        internal_sender,
+        info!("loaded the model in {:?}", start.elapsed());
+
+        Ok(MistralModel {
+            model: Arc::new(std::sync::Mutex::new(Some(model))),
+            tokenizer,
+            device,
+        })
+    }
+
+    // Whether an abandoned generation still has the model
+    pub fn is_busy(&self) -> bool {
+        self.model.lock().unwrap().is_none()
+    }
+
+    // Generate for the prompt and return once all the tokens are sent
+    pub async fn generate(
+        &mut self,
+        prompt: &str,
+        sample_len: usize,
+        sampling: SamplingConfig,
+        external_sender: Sender<TokenBatch>,
+        batching: TokenBatching,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let seed = sampling.seed.unwrap_or_else(rand::random);
+        let repeat_penalty = 1.1;
+        let repeat_last_n = (sample_len / 4) + prompt.len();
+        info!(
+            "temp: {:.2} top-k: {} top-p: {:.2} min-p: {:.2} typical-p: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
+            sampling.temperature,
+            sampling.top_k,
+            sampling.top_p,
+            sampling.min_p,
+            sampling.typical_p,
+            repeat_penalty,
+            repeat_last_n
+        );
+
+        let model = self
+            .model
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| E::msg("the mistral model is not loaded"))?;
+        let (internal_sender, internal_receiver) = mpsc::channel(32768);
+        let coalescer = tokio::spawn(coalesce_tokens(
+            internal_receiver,
+            external_sender,
+            batching,
+        ));
+
+        // the forward passes block, they run on a blocking thread the llm service can walk
+        // away from when they hang, the model goes back to its slot when they are done. The
+        // pipeline holds the token sender, dropping it with the pipeline ends the coalescer.
+        let mut pipeline = TextGeneration::new(
+            model,
+            self.tokenizer.clone(),
+            seed,
+            sampling,
+            repeat_penalty,
+            repeat_last_n,
+            &self.device,
+            internal_sender,
+            cancel,
+        );
+        let slot = Arc::clone(&self.model);
+        let prompt = prompt.to_string();
+        let result = tokio::task::spawn_blocking(move || {
+            let result = pipeline.run(&prompt, sample_len);
+            *slot.lock().unwrap() = Some(pipeline.model);
+            result
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("the generation failed: {}", e))
+        .and_then(|result| result);
+
+        let _ = coalescer.await;
+        result
+    }
+}
+
+pub fn mistral(
+    prompt: String,
+    sample_len: usize,
+    temperature: f64,
+    quantized: bool,
+    model_id: Option<String>,
+    external_sender: Sender<TokenBatch>,
+    batching: TokenBatching,
+) -> Result<()> {
+    let tracing = false;
+
+    let _guard = if tracing {
+        let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
+        tracing_subscriber::registry().with(chrome_layer).init();
+        Some(guard)
+    } else {
+        None
+    };
+    debug!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle_core::utils::with_avx(),
+        candle_core::utils::with_neon(),
+        candle_core::utils::with_simd128(),
+        candle_core::utils::with_f16c()
     );
 
-    let pipeline = Arc::new(Mutex::new(pipeline));
+    let model = MistralModel::load(quantized, model_id)?;
+    let model = Arc::new(Mutex::new(model));
 
     // Start the text generation in a separate thread
-    let pipeline_clone = pipeline.clone();
-    let prompt_clone = prompt.clone();
     tokio::spawn(async move {
-        let mut pipeline = pipeline_clone.lock().await;
-        match pipeline.run(&prompt_clone, sample_len).await {
+        let mut model = model.lock().await;
+        match model
+            .generate(
+                &prompt,
+                sample_len,
+                SamplingConfig::default().with_temperature(temperature),
+                external_sender,
+                batching,
+                Arc::new(AtomicBool::new(false)),
+            )
+            .await
+        {
             Ok(_) => {}
             Err(e) => log::error!("Failed to run the pipeline: {}", e),
         }
     });
 
-    // Set up a thread to listen on the internal receiver and forward messages to the external sender
-    let external_sender_clone = external_sender.clone();
-    tokio::spawn(async move {
-        while let Some(token) = internal_receiver.recv().await {
-            if let Err(e) = external_sender_clone.send(token).await {
-                log::error!("Failed to send token externally: {}", e);
-                break;
-            }
-        }
-    });
-
     Ok(())
 }
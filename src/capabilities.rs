@@ -0,0 +1,138 @@
+/*
+ * capabilities.rs
+ * ---------------
+ * What this build and machine can do, for `--version --features`. The report is JSON with
+ * the cargo features compiled in, the compute devices candle can use, the capture devices
+ * pcap sees and where the models are cached, so a user's install can be looked at from one
+ * paste in an issue.
+*/
+
+use crate::args::Args;
+use clap::CommandFactory;
+use serde_json::{json, Value};
+
+// Cargo features of this build
+pub fn enabled_features() -> Vec<&'static str> {
+    let features = [
+        ("ai", cfg!(feature = "ai")),
+        ("audioplayer", cfg!(feature = "audioplayer")),
+        ("discord", cfg!(feature = "discord")),
+        ("dpdk_enabled", cfg!(feature = "dpdk_enabled")),
+        ("fonts", cfg!(feature = "fonts")),
+        ("kafka", cfg!(feature = "kafka")),
+        ("local_only", cfg!(feature = "local_only")),
+        ("metavoice", cfg!(feature = "metavoice")),
+        ("mps", cfg!(feature = "mps")),
+        ("mqtt", cfg!(feature = "mqtt")),
+        ("nats", cfg!(feature = "nats")),
+        ("ndi", cfg!(feature = "ndi")),
+        ("program_audio", cfg!(feature = "program_audio")),
+        ("secrets", cfg!(feature = "secrets")),
+        ("thumbnails", cfg!(feature = "thumbnails")),
+    ];
+    features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+// Version of the command line, the one --version always printed
+pub fn version() -> String {
+    Args::command()
+        .get_version()
+        .unwrap_or(env!("CARGO_PKG_VERSION"))
+        .to_string()
+}
+
+#[cfg(feature = "ai")]
+fn compute_devices() -> Value {
+    use candle_core::utils;
+    json!({
+        "cuda": utils::cuda_is_available(),
+        "metal": utils::metal_is_available(),
+        "mkl": utils::has_mkl(),
+        "avx": utils::with_avx(),
+    })
+}
+
+#[cfg(not(feature = "ai"))]
+fn compute_devices() -> Value {
+    Value::Null
+}
+
+fn capture_devices() -> Value {
+    match pcap::Device::list() {
+        Ok(devices) => json!(devices
+            .iter()
+            .map(|device| json!({
+                "name": device.name,
+                "description": device.desc,
+                "addresses": device
+                    .addresses
+                    .iter()
+                    .map(|address| address.addr.to_string())
+                    .collect::<Vec<String>>(),
+                "up": device.flags.is_up(),
+                "running": device.flags.is_running(),
+                "loopback": device.flags.is_loopback(),
+                "wireless": device.flags.is_wireless(),
+            }))
+            .collect::<Vec<Value>>()),
+        Err(e) => json!({ "error": e.to_string() }),
+    }
+}
+
+// The Hugging Face cache and the model repos in it
+#[cfg(feature = "ai")]
+fn model_cache() -> Value {
+    let path = candle_hf_hub::Cache::default().path().clone();
+    let mut models: Vec<String> = std::fs::read_dir(&path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    // models--org--name holds the repo org/name
+                    name.strip_prefix("models--")
+                        .map(|repo| repo.replacen("--", "/", 1))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    models.sort();
+    json!({
+        "huggingface": path.to_string_lossy(),
+        "models": models,
+    })
+}
+
+#[cfg(not(feature = "ai"))]
+fn model_cache() -> Value {
+    Value::Null
+}
+
+pub fn capabilities_report() -> Value {
+    json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": version(),
+        "features": enabled_features(),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "compute_devices": compute_devices(),
+        "capture_devices": capture_devices(),
+        "model_cache": model_cache(),
+    })
+}
+
+// Print the version, with --features the capabilities report instead
+pub fn print_version(args: &Args) {
+    if args.features {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&capabilities_report()).unwrap_or_default()
+        );
+    } else {
+        println!("{} {}", Args::command().get_name(), version());
+    }
+}
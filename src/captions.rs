@@ -0,0 +1,239 @@
+/*
+ * captions.rs
+ * -----------
+ * Live captions of the monitored stream. The decoded program audio is collected into chunks
+ * of a few seconds on a thread of their own and transcribed with Whisper, each transcript is
+ * shown as a caption on the output frames and written as a cue of a WebVTT sidecar file
+ * timed by the PTS of the audio. With diarization the captions name their speaker and the
+ * lines go to the transcript for the prompts.
+*/
+
+use crate::args::Args;
+use crate::audio::resample;
+use crate::diarization::{voice_print, Diarizer};
+use crate::event_log::log_event;
+use crate::overlay::set_live_caption;
+use crate::program_audio::{subscribe_program_audio, ProgramAudio};
+use crate::transcript::{push_transcript_line, TranscriptLine};
+use crate::whisper::{Whisper, MEL_HOP_LENGTH, WHISPER_SAMPLE_RATE};
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+
+// Decoded audio packets queued for the captioner, enough for a chunk while one is transcribed
+const AUDIO_QUEUE_SIZE: usize = 1024;
+// PTS wrap around of the 33 bit 90 kHz clock
+const PTS_MASK: u64 = (1 << 33) - 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Caption {
+    pub pid: u16,
+    pub start_ms: u64, // from the first captioned audio
+    pub end_ms: u64,
+    pub speaker: Option<usize>,
+    pub text: String,
+}
+
+// WebVTT time like 00:01:02.345
+fn vtt_timestamp(ms: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+pub struct WebVttWriter {
+    writer: BufWriter<File>,
+}
+
+impl WebVttWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"WEBVTT\n\n")?;
+        writer.flush()?;
+        Ok(WebVttWriter { writer })
+    }
+
+    // Append the cue, flushed so the sidecar can be followed while it is written
+    pub fn write_cue(&mut self, caption: &Caption) -> Result<()> {
+        // the speaker goes in a voice span
+        let text = match caption.speaker {
+            Some(speaker) => format!("<v Speaker {}>{}", speaker, caption.text),
+            None => caption.text.clone(),
+        };
+        writeln!(
+            self.writer,
+            "{} --> {}\n{}\n",
+            vtt_timestamp(caption.start_ms),
+            vtt_timestamp(caption.end_ms),
+            text
+        )?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return samples.to_vec();
+    }
+    samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+pub struct Captioner {
+    whisper: Whisper,
+    diarizer: Option<Diarizer>,
+    webvtt: Option<WebVttWriter>,
+    chunk_secs: f32,
+    overlay: bool,
+    first_pts: Option<u64>,
+    pid: u16,
+    sample_rate: u32,
+    chunk: Vec<f32>, // mono audio at the decoded rate
+    chunk_start_ms: u64,
+    position_ms: u64, // audio time when there is no PTS
+}
+
+impl Captioner {
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let whisper = Whisper::load(&args.captions_model, &args.captions_language, false)?;
+        let webvtt = if args.captions_webvtt.is_empty() {
+            None
+        } else {
+            info!("Writing the live captions to {}", args.captions_webvtt);
+            Some(WebVttWriter::create(&args.captions_webvtt)?)
+        };
+        Ok(Captioner {
+            whisper,
+            diarizer: Diarizer::from_args(args),
+            webvtt,
+            chunk_secs: args.captions_chunk_secs.clamp(1.0, 30.0),
+            overlay: !args.captions_position.is_empty(),
+            first_pts: None,
+            pid: 0,
+            sample_rate: 0,
+            chunk: Vec::new(),
+            chunk_start_ms: 0,
+            position_ms: 0,
+        })
+    }
+
+    // Milliseconds of the audio from the first captioned PTS, or the audio played so far
+    fn audio_time_ms(&mut self, pts: Option<u64>) -> u64 {
+        match pts {
+            Some(pts) => {
+                let first = *self.first_pts.get_or_insert(pts);
+                (pts.wrapping_sub(first) & PTS_MASK) / 90
+            }
+            None => self.position_ms,
+        }
+    }
+
+    pub fn push(&mut self, audio: ProgramAudio) {
+        if audio.pid != self.pid || audio.sample_rate != self.sample_rate {
+            self.pid = audio.pid;
+            self.sample_rate = audio.sample_rate;
+            self.chunk.clear();
+        }
+        let time_ms = self.audio_time_ms(audio.pts);
+        if self.chunk.is_empty() {
+            self.chunk_start_ms = time_ms;
+        }
+        let mono = downmix(&audio.samples, audio.channels);
+        self.position_ms = time_ms + mono.len() as u64 * 1000 / audio.sample_rate.max(1) as u64;
+        self.chunk.extend(mono);
+
+        if self.chunk.len() as f32 >= self.chunk_secs * self.sample_rate as f32 {
+            let chunk = std::mem::take(&mut self.chunk);
+            let duration_ms = chunk.len() as u64 * 1000 / self.sample_rate.max(1) as u64;
+            match self.transcribe(&chunk) {
+                Ok((text, speaker)) if !text.is_empty() => self.caption(Caption {
+                    pid: self.pid,
+                    start_ms: self.chunk_start_ms,
+                    end_ms: self.chunk_start_ms + duration_ms,
+                    speaker,
+                    text,
+                }),
+                Ok(_) => debug!("No speech in the program audio of PID {}", self.pid),
+                Err(e) => error!("Failed to caption the program audio: {}", e),
+            }
+        }
+    }
+
+    // Transcript of the chunk and its speaker when diarizing
+    fn transcribe(&mut self, chunk: &[f32]) -> Result<(String, Option<usize>)> {
+        let samples = resample(chunk, self.sample_rate, WHISPER_SAMPLE_RATE)?;
+        let text = self.whisper.transcribe(&samples)?;
+        let Some(diarizer) = self.diarizer.as_mut().filter(|_| !text.is_empty()) else {
+            return Ok((text, None));
+        };
+        let print = voice_print(
+            &self.whisper.log_mel(&samples),
+            self.whisper.num_mel_bins(),
+            samples.len() / MEL_HOP_LENGTH,
+        );
+        Ok((text, print.map(|print| diarizer.assign(&print))))
+    }
+
+    fn caption(&mut self, caption: Caption) {
+        let line = TranscriptLine {
+            pid: caption.pid,
+            start_ms: caption.start_ms,
+            end_ms: caption.end_ms,
+            speaker: caption.speaker,
+            text: caption.text.clone(),
+        };
+        debug!("Caption of PID {}: {}", caption.pid, line);
+        if self.overlay {
+            // held until the next caption would be in
+            let hold = Duration::from_secs_f32(self.chunk_secs * 2.0);
+            set_live_caption(&line.to_string(), hold);
+        }
+        if let Some(webvtt) = self.webvtt.as_mut() {
+            if let Err(e) = webvtt.write_cue(&caption) {
+                error!("Failed to write the WebVTT caption: {}", e);
+            }
+        }
+        log_event("analyzer", "caption", json!(caption));
+        push_transcript_line(line);
+    }
+}
+
+// Caption the program audio on a thread of its own until the audio stops
+pub fn spawn_captions(args: &Args) -> Option<std::thread::JoinHandle<()>> {
+    if !args.captions {
+        return None;
+    }
+    if !args.program_audio || !args.ai_network_stats {
+        warn!("Live captions need --program-audio and --ai-network-stats, captions are off");
+        return None;
+    }
+    let receiver = subscribe_program_audio(AUDIO_QUEUE_SIZE);
+    let args = args.clone();
+    Some(std::thread::spawn(move || {
+        let mut captioner = match Captioner::from_args(&args) {
+            Ok(captioner) => captioner,
+            Err(e) => {
+                error!("{}, no live captions of the monitored stream", e);
+                return;
+            }
+        };
+        info!(
+            "Captioning the monitored program audio every {} seconds",
+            captioner.chunk_secs
+        );
+        for audio in receiver {
+            captioner.push(audio);
+        }
+    }))
+}
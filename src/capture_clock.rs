@@ -0,0 +1,132 @@
+/*
+ * capture_clock.rs
+ * ----------------
+ * Where the packet arrival times come from. The system clock when the capture reads the
+ * packet, the kernel receive time at nanosecond precision from an NTP disciplined clock, or
+ * the NIC hardware time when the adapter clock is disciplined by PTP (ptp4l and phc2sys).
+*/
+
+use crate::current_unix_timestamp_ns;
+use pcap::{Precision, TimestampType};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampSource {
+    System,
+    Ntp,
+    Ptp,
+}
+
+impl std::str::FromStr for TimestampSource {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, String> {
+        match source.trim().to_lowercase().as_str() {
+            "system" => Ok(TimestampSource::System),
+            "ntp" => Ok(TimestampSource::Ntp),
+            "ptp" => Ok(TimestampSource::Ptp),
+            _ => Err(format!("Invalid timestamp source {}", source)),
+        }
+    }
+}
+
+impl TimestampSource {
+    // Timestamp type to request from pcap, None keeps the default of the device
+    pub fn tstamp_type(&self) -> Option<TimestampType> {
+        match self {
+            TimestampSource::System => None,
+            TimestampSource::Ntp => Some(TimestampType::Host),
+            TimestampSource::Ptp => Some(TimestampType::Adapter),
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        match self {
+            TimestampSource::System => Precision::Micro,
+            _ => Precision::Nano,
+        }
+    }
+
+    // Arrival time in ns of a packet with the pcap header time, seconds and micro or
+    // nanoseconds depending on the precision
+    pub fn arrival_time_ns(&self, tv_sec: u64, tv_frac: u64) -> u64 {
+        match self {
+            TimestampSource::System => current_unix_timestamp_ns().unwrap_or(0),
+            _ => tv_sec * 1_000_000_000 + tv_frac,
+        }
+    }
+}
+
+impl fmt::Display for TimestampSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimestampSource::System => write!(f, "system"),
+            TimestampSource::Ntp => write!(f, "ntp"),
+            TimestampSource::Ptp => write!(f, "ptp"),
+        }
+    }
+}
+
+// If the kernel clock is NTP synchronized, None where it can't be asked
+#[cfg(target_os = "linux")]
+pub fn clock_synchronized() -> Option<bool> {
+    // modes 0 only reads the clock state
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+    if state < 0 {
+        return None;
+    }
+    Some(state != libc::TIME_ERROR)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clock_synchronized() -> Option<bool> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_displays_the_sources() {
+        for source in [
+            TimestampSource::System,
+            TimestampSource::Ntp,
+            TimestampSource::Ptp,
+        ] {
+            assert_eq!(source.to_string().parse::<TimestampSource>(), Ok(source));
+        }
+        assert_eq!(" PTP ".parse::<TimestampSource>(), Ok(TimestampSource::Ptp));
+        assert!("gps".parse::<TimestampSource>().is_err());
+    }
+
+    #[test]
+    fn requests_the_clock_of_the_source() {
+        assert!(TimestampSource::System.tstamp_type().is_none());
+        assert!(matches!(
+            TimestampSource::Ntp.tstamp_type(),
+            Some(TimestampType::Host)
+        ));
+        assert!(matches!(
+            TimestampSource::Ptp.tstamp_type(),
+            Some(TimestampType::Adapter)
+        ));
+        assert!(matches!(
+            TimestampSource::System.precision(),
+            Precision::Micro
+        ));
+        assert!(matches!(TimestampSource::Ptp.precision(), Precision::Nano));
+    }
+
+    #[test]
+    fn uses_the_header_time_for_ntp_and_ptp() {
+        assert_eq!(
+            TimestampSource::Ntp.arrival_time_ns(1_700_000_000, 123_456_789),
+            1_700_000_000_123_456_789
+        );
+        assert_eq!(TimestampSource::Ptp.arrival_time_ns(2, 5), 2_000_000_005);
+        // the system clock ignores the header time
+        assert!(TimestampSource::System.arrival_time_ns(0, 0) > 0);
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * capture_paths.rs
+ * ----------------
+ * Redundant A/B feeds captured on more than one NIC. Every device is a path and its packets
+ * carry the path number, only one path is analyzed at a time since the feeds are copies of
+ * each other. When the analyzed path goes quiet the first path still receiving takes over,
+ * a standby path that stops or comes back is reported too so a lost redundancy is noticed
+ * before it is needed.
+*/
+
+use crate::args::Args;
+use crate::event_log::log_event;
+use crate::network_capture::{capture_devices, CapturedPacket};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathStats {
+    pub path: usize,
+    pub device: String,
+    pub packets: u64,
+    pub bytes: u64,
+    pub last_packet_ns: Option<u64>,
+    pub active: bool, // the analyzed path
+    pub lost: bool,
+}
+
+pub struct CapturePaths {
+    paths: Vec<PathStats>,
+    active: usize,
+    failover_ns: u64,
+    first_packet_ns: Option<u64>,
+}
+
+impl CapturePaths {
+    pub fn new(devices: Vec<String>, failover_ms: u64) -> Self {
+        let paths = devices
+            .into_iter()
+            .enumerate()
+            .map(|(path, device)| PathStats {
+                path,
+                device,
+                packets: 0,
+                bytes: 0,
+                last_packet_ns: None,
+                active: path == 0,
+                lost: false,
+            })
+            .collect();
+        CapturePaths {
+            paths,
+            active: 0,
+            failover_ns: failover_ms.max(1) * 1_000_000,
+            first_packet_ns: None,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        CapturePaths::new(
+            capture_devices(&args.source_device, &args.source_devices),
+            args.path_failover_ms,
+        )
+    }
+
+    // Name of a path like A for the first device
+    fn name(path: usize) -> String {
+        match u8::try_from(path).ok().filter(|path| *path < 26) {
+            Some(path) => ((b'A' + path) as char).to_string(),
+            None => path.to_string(),
+        }
+    }
+
+    // A path that never received counts from the first packet of any path, so the first
+    // device gets a chance to start
+    fn quiet(&self, path: usize, now_ns: u64) -> bool {
+        self.paths[path]
+            .last_packet_ns
+            .or(self.first_packet_ns)
+            .is_none_or(|last| now_ns.saturating_sub(last) > self.failover_ns)
+    }
+
+    // Whether the packet is of the analyzed path, a quiet analyzed path hands over to the path
+    // of the packet
+    pub fn accept(&mut self, packet: &CapturedPacket) -> bool {
+        if self.paths.len() <= 1 {
+            return true;
+        }
+        let path = packet.path;
+        if path >= self.paths.len() {
+            return false;
+        }
+        let now_ns = packet.timestamp_ns;
+        self.first_packet_ns.get_or_insert(now_ns);
+
+        if self.paths[path].lost {
+            self.paths[path].lost = false;
+            info!(
+                "Capture path {} on {} is receiving again",
+                Self::name(path),
+                self.paths[path].device
+            );
+            log_event("analyzer", "path_restored", json!(self.paths[path]));
+        }
+        // the other paths that went quiet, each reported once
+        for other in 0..self.paths.len() {
+            if other != path
+                && !self.paths[other].lost
+                && self.paths[other].last_packet_ns.is_some()
+                && self.quiet(other, now_ns)
+            {
+                self.paths[other].lost = true;
+                warn!(
+                    "Capture path {} on {} stopped receiving",
+                    Self::name(other),
+                    self.paths[other].device
+                );
+                log_event("analyzer", "path_lost", json!(self.paths[other]));
+            }
+        }
+        if path != self.active && self.quiet(self.active, now_ns) {
+            warn!(
+                "Capture path {} on {} is quiet, analyzing path {} on {}",
+                Self::name(self.active),
+                self.paths[self.active].device,
+                Self::name(path),
+                self.paths[path].device
+            );
+            log_event(
+                "analyzer",
+                "path_failover",
+                json!({
+                    "from": Self::name(self.active),
+                    "from_device": self.paths[self.active].device,
+                    "to": Self::name(path),
+                    "to_device": self.paths[path].device,
+                }),
+            );
+            self.paths[self.active].active = false;
+            self.paths[path].active = true;
+            self.active = path;
+        }
+
+        let stats = &mut self.paths[path];
+        stats.packets += 1;
+        stats.bytes += packet.data.len() as u64;
+        stats.last_packet_ns = Some(now_ns);
+        path == self.active
+    }
+
+    // Stats of each path, None with a single device
+    pub fn stats(&self) -> Option<&[PathStats]> {
+        if self.paths.len() <= 1 {
+            None
+        } else {
+            Some(&self.paths)
+        }
+    }
+}
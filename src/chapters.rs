@@ -0,0 +1,221 @@
+/*
+ * chapters.rs
+ * -----------
+ * Chapter markers for long running streams. Topic shifts are detected by comparing the
+ * keywords of each paragraph with the recent paragraphs, and the chapters are written to
+ * the transcript and as EXT-X-DATERANGE tags for an HLS playlist. They are not sent as NDI
+ * metadata frames, ndi-sdk-rsllm has no NDIlib_send_send_metadata to carry them yet.
+*/
+
+use crate::current_unix_timestamp_ms;
+use chrono::{TimeZone, Utc};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+// words that carry no topic
+pub const STOP_WORDS: [&str; 48] = [
+    "the", "and", "that", "this", "with", "from", "have", "they", "their", "there", "were", "what",
+    "when", "where", "which", "while", "will", "would", "could", "should", "about", "into", "your",
+    "you", "our", "are", "was", "for", "but", "not", "all", "can", "her", "his", "him", "she",
+    "its", "them", "then", "than", "been", "just", "like", "some", "very", "more", "also", "each",
+];
+
+// number of previous paragraphs a new paragraph is compared with
+const WINDOW_SIZE: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub index: usize,
+    pub title: String,
+    pub paragraph_count: usize,
+    pub timestamp_ms: u64, // unix time in ms
+    pub offset_ms: u64,    // time since the stream started in ms
+}
+
+impl Chapter {
+    // hh:mm:ss offset from the start of the stream
+    pub fn offset_string(&self) -> String {
+        format_offset(self.offset_ms)
+    }
+
+    // EXT-X-DATERANGE tag for an HLS playlist
+    pub fn hls_daterange(&self) -> String {
+        let start_date = Utc
+            .timestamp_millis_opt(self.timestamp_ms as i64)
+            .single()
+            .unwrap_or_else(Utc::now)
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ");
+        format!(
+            "#EXT-X-DATERANGE:ID=\"chapter-{}\",CLASS=\"com.groovybits.rsllm.chapter\",START-DATE=\"{}\",X-TITLE=\"{}\"",
+            self.index,
+            start_date,
+            self.title.replace('"', "'")
+        )
+    }
+}
+
+pub fn format_offset(offset_ms: u64) -> String {
+    let seconds = offset_ms / 1000;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+// Lowercase topic words of the text, skipping short and stop words
+pub fn topic_words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|w| w.to_lowercase())
+        .filter(|w| w.chars().count() > 3 && !STOP_WORDS.contains(&w.as_str()))
+        .collect()
+}
+
+// Jaccard similarity of two word sets
+pub fn similarity(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let intersection = a.intersection(b).count();
+    let union = a.union(b).count();
+    intersection as f32 / union as f32
+}
+
+// Short title from the first sentence of the paragraph
+pub fn chapter_title(paragraph: &str) -> String {
+    let sentence = paragraph
+        .split(['.', '!', '?', '\n'])
+        .map(|s| s.trim())
+        .find(|s| !s.is_empty())
+        .unwrap_or("");
+    let words: Vec<&str> = sentence.split_whitespace().collect();
+    let mut title = words.iter().take(8).copied().collect::<Vec<_>>().join(" ");
+    if words.len() > 8 {
+        title.push_str("...");
+    }
+    title
+}
+
+pub struct ChapterDetector {
+    window: VecDeque<HashSet<String>>,
+    threshold: f32,
+    min_paragraphs: usize,
+    since_last: usize,
+    chapters: usize,
+    start_ms: u64,
+}
+
+impl ChapterDetector {
+    pub fn new(threshold: f32, min_paragraphs: usize, start_ms: u64) -> Self {
+        ChapterDetector {
+            window: VecDeque::with_capacity(WINDOW_SIZE),
+            threshold,
+            min_paragraphs,
+            since_last: 0,
+            chapters: 0,
+            start_ms,
+        }
+    }
+
+    // Feed the next paragraph, returns a chapter if it starts a new topic
+    pub fn process(&mut self, paragraph: &str, paragraph_count: usize) -> Option<Chapter> {
+        let words = topic_words(paragraph);
+        if words.is_empty() {
+            return None;
+        }
+
+        let recent: HashSet<String> = self.window.iter().flatten().cloned().collect();
+        let score = similarity(&words, &recent);
+
+        let new_chapter = self.chapters == 0
+            || (self.since_last >= self.min_paragraphs && score < self.threshold);
+
+        if self.window.len() == WINDOW_SIZE {
+            self.window.pop_front();
+        }
+        self.window.push_back(words);
+        self.since_last += 1;
+
+        if !new_chapter {
+            return None;
+        }
+
+        log::debug!(
+            "Topic shift at paragraph {} with similarity {:.3}",
+            paragraph_count,
+            score
+        );
+
+        self.chapters += 1;
+        self.since_last = 0;
+        let timestamp_ms = current_unix_timestamp_ms().unwrap_or(self.start_ms);
+        Some(Chapter {
+            index: self.chapters,
+            title: chapter_title(paragraph),
+            paragraph_count,
+            timestamp_ms,
+            offset_ms: timestamp_ms.saturating_sub(self.start_ms),
+        })
+    }
+}
+
+// Writes the transcript and the HLS chapter tags, empty paths disable the output
+pub struct ChapterWriter {
+    transcript: Option<File>,
+    hls: Option<File>,
+    start_ms: u64,
+}
+
+fn open_append(path: &str) -> Option<File> {
+    if path.is_empty() {
+        return None;
+    }
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            log::error!("Failed to open {}: {}", path, e);
+            None
+        }
+    }
+}
+
+impl ChapterWriter {
+    pub fn new(transcript_file: &str, hls_file: &str, start_ms: u64) -> Self {
+        ChapterWriter {
+            transcript: open_append(transcript_file),
+            hls: open_append(hls_file),
+            start_ms,
+        }
+    }
+
+    pub fn write_paragraph(&mut self, paragraph: &str) {
+        if let Some(file) = self.transcript.as_mut() {
+            let now = current_unix_timestamp_ms().unwrap_or(self.start_ms);
+            let offset = format_offset(now.saturating_sub(self.start_ms));
+            if let Err(e) = writeln!(file, "[{}] {}", offset, paragraph.trim()) {
+                log::error!("Failed to write transcript: {}", e);
+            }
+        }
+    }
+
+    pub fn write_chapter(&mut self, chapter: &Chapter) {
+        if let Some(file) = self.transcript.as_mut() {
+            if let Err(e) = writeln!(
+                file,
+                "\n## [{}] Chapter {}: {}\n",
+                chapter.offset_string(),
+                chapter.index,
+                chapter.title
+            ) {
+                log::error!("Failed to write transcript chapter: {}", e);
+            }
+        }
+        if let Some(file) = self.hls.as_mut() {
+            if let Err(e) = writeln!(file, "{}", chapter.hls_daterange()) {
+                log::error!("Failed to write HLS chapter: {}", e);
+            }
+        }
+    }
+}
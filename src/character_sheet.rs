@@ -0,0 +1,138 @@
+/*
+ * character_sheet.rs
+ * ------------------
+ * Character sheet of the host for the image prompts. The --character-sheet JSON file holds
+ * the LoRA triggers and appearance tokens put in front of every stable diffusion prompt, the
+ * outfits worn over them, the style tokens put after and the tokens added to the negative
+ * prompt. The host then renders the same in every image instead of depending on the paragraph
+ * describing them. An outfit is picked by its keywords in the paragraph, else the one named by
+ * --character-outfit, else the first. The file is read again on a reload.
+*/
+
+use crate::args::Args;
+use anyhow::{anyhow, Result};
+use log::info;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::sync::Mutex;
+
+static CHARACTER_SHEET: Lazy<Mutex<CharacterSheet>> =
+    Lazy::new(|| Mutex::new(CharacterSheet::default()));
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Outfit {
+    pub name: String,
+    pub tokens: String,
+    pub keywords: Vec<String>, // words of a paragraph the outfit is worn for
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CharacterSheet {
+    pub name: String,
+    pub lora_triggers: Vec<String>, // trigger words and <lora:name:weight> tags, first
+    pub appearance: String,
+    pub outfits: Vec<Outfit>,
+    pub style: String,    // after the scene
+    pub negative: String, // added to the negative prompt
+}
+
+// Joins the parts that are not empty
+fn join_tokens<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+    parts
+        .into_iter()
+        .map(|part| part.trim().trim_matches(','))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl CharacterSheet {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read character sheet {}: {}", path, e))?;
+        let sheet: CharacterSheet = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Failed to parse character sheet {}: {}", path, e))?;
+        if let Some(outfit) = sheet.outfits.iter().find(|outfit| outfit.name.is_empty()) {
+            return Err(anyhow!(
+                "Outfit {} has no name in character sheet {}",
+                outfit.tokens,
+                path
+            ));
+        }
+        Ok(sheet)
+    }
+
+    // The sheet of --character-sheet, empty when it is not set
+    pub fn from_args(args: &Args) -> Result<Self> {
+        if args.character_sheet.is_empty() {
+            return Ok(CharacterSheet::default());
+        }
+        CharacterSheet::load(&args.character_sheet)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lora_triggers.is_empty()
+            && self.appearance.is_empty()
+            && self.outfits.is_empty()
+            && self.style.is_empty()
+            && self.negative.is_empty()
+    }
+
+    // The outfit with a keyword in the paragraph, else the named one, else the first
+    pub fn outfit(&self, paragraph: &str, name: &str) -> Option<&Outfit> {
+        let paragraph = paragraph.to_lowercase();
+        self.outfits
+            .iter()
+            .find(|outfit| {
+                outfit.keywords.iter().any(|keyword| {
+                    !keyword.is_empty() && paragraph.contains(&keyword.to_lowercase())
+                })
+            })
+            .or_else(|| {
+                self.outfits
+                    .iter()
+                    .find(|outfit| outfit.name.eq_ignore_ascii_case(name))
+            })
+            .or_else(|| self.outfits.first())
+    }
+
+    // The scene of a paragraph with the host around it, the scene alone without a sheet
+    pub fn prompt(&self, scene: &str, paragraph: &str, outfit: &str) -> String {
+        if self.is_empty() {
+            return scene.to_string();
+        }
+        let outfit = self
+            .outfit(paragraph, outfit)
+            .map_or("", |outfit| outfit.tokens.as_str());
+        join_tokens(
+            self.lora_triggers
+                .iter()
+                .map(|trigger| trigger.as_str())
+                .chain([self.appearance.as_str(), outfit, scene, self.style.as_str()]),
+        )
+    }
+
+    pub fn negative_prompt(&self, negative: &str) -> String {
+        join_tokens([negative, self.negative.as_str()])
+    }
+}
+
+// Read the --character-sheet, at start and on every reload
+pub fn init_character_sheet(args: &Args) -> Result<()> {
+    let sheet = CharacterSheet::from_args(args)?;
+    if !sheet.is_empty() {
+        info!(
+            "Character sheet {} with {} outfits",
+            sheet.name,
+            sheet.outfits.len()
+        );
+    }
+    *CHARACTER_SHEET.lock().unwrap() = sheet;
+    Ok(())
+}
+
+pub fn character_sheet() -> CharacterSheet {
+    CHARACTER_SHEET.lock().unwrap().clone()
+}
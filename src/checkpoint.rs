@@ -0,0 +1,148 @@
+/*
+ * checkpoint.rs
+ * -------------
+ * Checkpoint of the show for resuming after a restart: the paragraph counter, the paragraphs
+ * sent to the pipeline that were not output yet, the presentation time of the output and the
+ * persona. It is written to --checkpoint-file as paragraphs are sent and output, a restart
+ * continues the counters and timeline and sends the unfinished paragraphs again.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::pipeline::{MessageData, Priority};
+use crate::runtime::build_sd_config;
+use crate::stream_state::stream_state;
+use anyhow::{anyhow, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// A paragraph sent to the pipeline and not output yet
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PendingParagraph {
+    pub paragraph_count: usize,
+    pub paragraph: String,
+    pub image_prompt: String,
+    pub voice: String,
+    pub high_priority: bool,
+    pub requested_by: Option<String>,
+}
+
+impl PendingParagraph {
+    pub fn from_message(message: &MessageData) -> Self {
+        PendingParagraph {
+            paragraph_count: message.paragraph_count,
+            paragraph: message.paragraph.clone(),
+            image_prompt: message.sd_config.prompt.clone(),
+            voice: message.mimic3_voice.clone(),
+            high_priority: message.priority == Priority::High,
+            requested_by: message.requested_by.clone(),
+        }
+    }
+
+    // The message to send again, it is rendered from scratch with the current args
+    pub fn to_message(&self, args: &Args) -> MessageData {
+        let output_id = Uuid::new_v4().simple().to_string();
+        let sd_config = build_sd_config(args, &self.image_prompt);
+        let message = match &self.requested_by {
+            Some(requester) => {
+                MessageData::viewer_image(&self.paragraph, requester, &output_id, sd_config, args)
+            }
+            None if self.high_priority => {
+                MessageData::announcement(&self.paragraph, &output_id, sd_config, args)
+            }
+            None => MessageData::new(&self.paragraph, &output_id, sd_config, args),
+        };
+        message.with_voice(&self.voice)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShowCheckpoint {
+    pub total_paragraph_count: usize,
+    pub next_pts_ms: u64, // presentation time of the next paragraph out
+    pub persona: String,
+    pub pending: Vec<PendingParagraph>,
+    pub saved_ms: u64,
+}
+
+impl ShowCheckpoint {
+    // None when there is no checkpoint to resume from
+    pub fn load(path: &str) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow!("Failed to read checkpoint {}: {}", path, e)),
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| anyhow!("Failed to parse checkpoint {}: {}", path, e))
+    }
+
+    // Paragraph count the resumed show starts at, the unfinished paragraphs are numbered from it
+    pub fn resume_count(&self) -> usize {
+        self.pending
+            .iter()
+            .map(|pending| pending.paragraph_count)
+            .min()
+            .unwrap_or(self.total_paragraph_count)
+    }
+}
+
+// Keeps the checkpoint of the dispatcher and output up to date on disk
+#[derive(Clone)]
+pub struct Checkpointer {
+    path: String,
+    checkpoint: Arc<Mutex<ShowCheckpoint>>,
+}
+
+impl Checkpointer {
+    pub fn new(path: &str, total_paragraph_count: usize, next_pts_ms: u64) -> Self {
+        Checkpointer {
+            path: path.to_string(),
+            checkpoint: Arc::new(Mutex::new(ShowCheckpoint {
+                total_paragraph_count,
+                next_pts_ms,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // The message was sent to the pipeline, shutdown and last messages are not resumed
+    pub fn dispatched(&self, message: &MessageData) {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        checkpoint.total_paragraph_count = message.paragraph_count + 1;
+        if !message.shutdown && !message.last_message {
+            checkpoint
+                .pending
+                .push(PendingParagraph::from_message(message));
+        }
+        self.save(&mut checkpoint);
+    }
+
+    // The paragraph went out, it and any before the oldest key still in the store are done.
+    // A high priority paragraph goes out ahead of normal ones with lower keys, those stay.
+    pub fn output(&self, paragraph_count: usize, oldest_key: usize, next_pts_ms: u64) {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        checkpoint.pending.retain(|pending| {
+            pending.paragraph_count != paragraph_count && pending.paragraph_count >= oldest_key
+        });
+        checkpoint.next_pts_ms = next_pts_ms;
+        self.save(&mut checkpoint);
+    }
+
+    // Written to a temporary file and renamed so a crash can't leave half a checkpoint
+    fn save(&self, checkpoint: &mut ShowCheckpoint) {
+        checkpoint.persona = stream_state().persona;
+        checkpoint.saved_ms = current_unix_timestamp_ms().unwrap_or(0);
+        let temp_path = format!("{}.tmp", self.path);
+        let result = serde_json::to_string_pretty(&*checkpoint)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(&temp_path, json).map_err(|e| e.to_string()))
+            .and_then(|_| std::fs::rename(&temp_path, &self.path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Failed to write checkpoint {}: {}", self.path, e);
+        }
+    }
+}
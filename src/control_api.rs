@@ -0,0 +1,519 @@
+/*
+ * control_api.rs
+ * --------------
+ * Minimal HTTP control interface for changing settings of a running RsLLM instance.
+ * Commands are forwarded to the main loop over a channel in the same "!command args"
+ * format the Twitch client uses. Questions about the captured stats are answered here.
+ * With --api-tokens the requests need a bearer token of a role allowed on the route, and
+ * with --api-tls-cert and --api-tls-key the server speaks HTTPS.
+*/
+
+use crate::api_auth::{required_role, ApiTokens};
+use crate::args::Args;
+use crate::audio_meter::audio_levels;
+use crate::event_log::log_event;
+use crate::history::{parse_rewind_command, HistoryTree};
+use crate::liveness::liveness;
+use crate::multicast::multicast_memberships;
+use crate::overlay::{ticker_items, ticker_set};
+use crate::pcap_stats::pcap_stats;
+use crate::probe_link::{remote_site, site_stats};
+use crate::prompts::RELOAD_COMMAND;
+use crate::runtime::ProcessedDataStore;
+use crate::stats_qa::StatsAnalyst;
+#[cfg(feature = "thumbnails")]
+use crate::thumbnails::{latest_thumbnail, thumbnail_at, thumbnails};
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+
+// Largest request body accepted by the control api
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Parsed HTTP request
+pub struct ApiRequest {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub authorization: Option<String>, // the Authorization header
+    pub body: String,
+}
+
+// HTTP response with a JSON body
+pub struct ApiResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl ApiResponse {
+    pub fn new(status: u16, body: Value) -> Self {
+        ApiResponse { status, body }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        ApiResponse::new(status, json!({ "error": message }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+// State shared with the request handlers
+#[derive(Clone)]
+pub struct ControlApiState {
+    pub command_tx: mpsc::Sender<String>,
+    pub personas: Arc<Mutex<Vec<String>>>, // updated when the personas are reloaded
+    pub processed_data_store: Arc<Mutex<ProcessedDataStore>>,
+    pub stats_analyst: Arc<Mutex<StatsAnalyst>>,
+    pub history: Arc<Mutex<HistoryTree>>,
+    pub args: Args,
+}
+
+// Run the control api server until running is set to false
+pub async fn control_api(
+    address: String,
+    running: Arc<AtomicBool>,
+    state: ControlApiState,
+) -> Result<()> {
+    let tokens = Arc::new(ApiTokens::from_args(&state.args)?);
+    let tls = tls_acceptor(&state.args)?;
+    let listener = TcpListener::bind(&address)
+        .await
+        .map_err(|e| anyhow!("Failed to bind control api to {}: {}", address, e))?;
+    info!(
+        "Control API listening on {}://{}",
+        if tls.is_some() { "https" } else { "http" },
+        address
+    );
+    if !tokens.is_enabled() && !is_loopback(&address) {
+        warn!(
+            "Control API on {} has no --api-tokens, anyone who reaches it can control rsllm",
+            address
+        );
+    }
+
+    while running.load(Ordering::SeqCst) {
+        let accepted =
+            tokio::time::timeout(std::time::Duration::from_millis(500), listener.accept()).await;
+        let (stream, peer) = match accepted {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(e)) => {
+                error!("Control API accept error: {}", e);
+                continue;
+            }
+            // timeout, check the running flag again
+            Err(_) => continue,
+        };
+
+        debug!("Control API connection from {}", peer);
+        let state = state.clone();
+        let tokens = tokens.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => handle_connection(stream, state, &tokens, peer).await,
+                    Err(e) => Err(anyhow!("TLS handshake failed: {}", e)),
+                },
+                None => handle_connection(stream, state, &tokens, peer).await,
+            };
+            if let Err(e) = result {
+                error!("Control API connection error from {}: {}", peer, e);
+            }
+        });
+    }
+
+    info!("Control API shutting down.");
+    Ok(())
+}
+
+// The server certificate chain and key of --api-tls-cert and --api-tls-key, PEM files
+fn tls_acceptor(args: &Args) -> Result<Option<TlsAcceptor>> {
+    if args.api_tls_cert.is_empty() && args.api_tls_key.is_empty() {
+        return Ok(None);
+    }
+    if args.api_tls_cert.is_empty() || args.api_tls_key.is_empty() {
+        return Err(anyhow!("TLS needs both --api-tls-cert and --api-tls-key"));
+    }
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path, e))
+    };
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut open(&args.api_tls_cert)?)
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|e| anyhow!("Invalid certificate {}: {}", args.api_tls_cert, e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate in {}", args.api_tls_cert));
+    }
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut open(&args.api_tls_key)?)
+        .map_err(|e| anyhow!("Invalid key {}: {}", args.api_tls_key, e))?
+        .ok_or_else(|| anyhow!("No private key in {}", args.api_tls_key))?;
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+fn is_loopback(address: &str) -> bool {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost"
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+// A request the token allows on the route, or the 401 or 403 answer
+fn authorize(request: &ApiRequest, tokens: &ApiTokens, peer: &str) -> Option<ApiResponse> {
+    if !tokens.is_enabled() {
+        return None;
+    }
+    let segments: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let role = required_role(&request.method, &segments)?;
+    let client = tokens.client(request.authorization.as_deref());
+    let denied = match client.as_ref() {
+        Some(client) if client.role >= role => return None,
+        Some(_) => ApiResponse::error(
+            403,
+            &format!("{} needs the {} role", request.path, role.name()),
+        ),
+        None => ApiResponse::error(401, "Missing or invalid bearer token"),
+    };
+    log_event(
+        "control",
+        "api_denied",
+        json!({
+            "peer": peer,
+            "client": client.map(|client| client.name),
+            "method": request.method,
+            "path": request.path,
+            "status": denied.status,
+        }),
+    );
+    Some(denied)
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    state: ControlApiState,
+    tokens: &ApiTokens,
+    peer: std::net::SocketAddr,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    let response = match read_request(&mut reader).await {
+        Ok(request) => match authorize(&request, tokens, &peer.to_string()) {
+            Some(denied) => denied,
+            None => route(request, &state).await,
+        },
+        Err(e) => ApiResponse::error(400, &e.to_string()),
+    };
+
+    let body = response.body.to_string();
+    let header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        body.len(),
+        if response.status == 401 {
+            "WWW-Authenticate: Bearer\r\n"
+        } else {
+            ""
+        }
+    );
+
+    let stream = reader.get_mut();
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<S>,
+) -> Result<ApiRequest> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts
+        .next()
+        .ok_or_else(|| anyhow!("Missing request method"))?
+        .to_uppercase();
+    let target = parts
+        .next()
+        .ok_or_else(|| anyhow!("Missing request path"))?
+        .to_string();
+
+    // read headers, only the content length and the authorization are needed
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>()?;
+            } else if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(anyhow!("Request body too large"));
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).await?;
+
+    let (path, query_string) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (target, String::new()),
+    };
+
+    let query = query_string
+        .split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if key.is_empty() {
+                return None;
+            }
+            let value = urlencoding::decode(&value.replace('+', " "))
+                .map(|v| v.into_owned())
+                .unwrap_or_else(|_| value.to_string());
+            Some((key.to_string(), value))
+        })
+        .collect();
+
+    Ok(ApiRequest {
+        method,
+        path,
+        query,
+        authorization,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+async fn route(request: ApiRequest, state: &ControlApiState) -> ApiResponse {
+    let segments: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["healthz"]) => {
+            // 503 when an expected subsystem stalls, for container health checks
+            let (healthy, report) = liveness(state.args.healthz_stall_secs * 1000);
+            ApiResponse::new(if healthy { 200 } else { 503 }, report)
+        }
+        ("GET", ["personas"]) => {
+            let personas = state.personas.lock().await.clone();
+            ApiResponse::new(200, json!({ "personas": personas }))
+        }
+        ("GET", ["pipeline"]) => {
+            let metrics = state.processed_data_store.lock().await.metrics();
+            ApiResponse::new(
+                200,
+                json!({ "output_buffer": metrics, "audio_levels": audio_levels() }),
+            )
+        }
+        ("POST", ["persona"]) | ("POST", ["persona", _]) => {
+            // persona name from the path, the ?name= query or the request body
+            let name = match segments.get(1) {
+                Some(name) => urlencoding::decode(name)
+                    .map(|n| n.into_owned())
+                    .unwrap_or_else(|_| name.to_string()),
+                None => request
+                    .query
+                    .get("name")
+                    .cloned()
+                    .unwrap_or_else(|| request.body.trim().to_string()),
+            };
+            let known = state
+                .personas
+                .lock()
+                .await
+                .iter()
+                .any(|p| p.eq_ignore_ascii_case(&name));
+            if !known {
+                return ApiResponse::error(404, &format!("Persona {} not found", name));
+            }
+            send_command(state, format!("!persona {}", name)).await
+        }
+        ("GET", ["ticker", source]) => ApiResponse::new(
+            200,
+            json!({ "source": source, "items": ticker_items(source) }),
+        ),
+        ("POST", ["ticker", source]) => {
+            // one item per line of the body replaces the items of the ticker source
+            if *source == "stats" {
+                return ApiResponse::error(400, "The stats ticker is read from the system");
+            }
+            let items: Vec<String> = request
+                .body
+                .lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect();
+            ticker_set(source, items);
+            ApiResponse::new(
+                200,
+                json!({ "source": source, "items": ticker_items(source) }),
+            )
+        }
+        #[cfg(feature = "thumbnails")]
+        ("GET", ["thumbnails"]) => ApiResponse::new(200, json!({ "thumbnails": thumbnails() })),
+        #[cfg(feature = "thumbnails")]
+        ("GET", ["thumbnails", which]) => {
+            // latest or the last one at or before a unix time in ms
+            let thumbnail = match *which {
+                "latest" => latest_thumbnail(),
+                timestamp => match timestamp.parse::<u64>() {
+                    Ok(timestamp_ms) => thumbnail_at(timestamp_ms),
+                    Err(_) => return ApiResponse::error(400, "Invalid thumbnail timestamp"),
+                },
+            };
+            match thumbnail {
+                Some(thumbnail) => ApiResponse::new(
+                    200,
+                    json!({ "thumbnail": thumbnail, "data_url": thumbnail.data_url() }),
+                ),
+                None => ApiResponse::error(404, "No thumbnail"),
+            }
+        }
+        ("GET", ["capture"]) => ApiResponse::new(
+            200,
+            json!({ "pcap": pcap_stats(), "multicast": multicast_memberships() }),
+        ),
+        // the remote probes, the stats of every site or the latest report of one
+        ("GET", ["probes"]) => ApiResponse::new(
+            200,
+            json!({ "sites": site_stats(state.args.probe_stale_secs) }),
+        ),
+        ("GET", ["probes", site]) => {
+            let site = urlencoding::decode(site)
+                .map(|s| s.into_owned())
+                .unwrap_or_else(|_| site.to_string());
+            match remote_site(&site) {
+                Some(remote) => ApiResponse::new(200, json!(remote)),
+                None => ApiResponse::error(404, &format!("Site {} not found", site)),
+            }
+        }
+        ("GET", ["history"]) => ApiResponse::new(200, state.history.lock().await.summary()),
+        ("POST", ["rewind"]) => {
+            // ?checkpoint=<id> rewinds to a checkpoint, ?steps=<n> or a number in the body
+            // goes back n iterations, one without either
+            let command = match (request.query.get("checkpoint"), request.query.get("steps")) {
+                (Some(id), _) => format!("!rewind #{}", id.trim()),
+                (None, Some(steps)) => format!("!rewind {}", steps.trim()),
+                (None, None) => format!("!rewind {}", request.body.trim()),
+            };
+            if parse_rewind_command(&command).is_none() {
+                return ApiResponse::error(400, "Invalid rewind target");
+            }
+            send_command(state, command.trim().to_string()).await
+        }
+        // prompts and personas files read again by the main loop
+        ("POST", ["reload"]) => send_command(state, RELOAD_COMMAND.to_string()).await,
+        ("POST", ["ask"]) => ask(&request, state).await,
+        ("DELETE", ["ask", session]) => {
+            if state.stats_analyst.lock().await.reset(session) {
+                ApiResponse::new(200, json!({ "session": session, "reset": true }))
+            } else {
+                ApiResponse::error(404, &format!("Session {} not found", session))
+            }
+        }
+        (_, ["healthz"])
+        | (_, ["personas"])
+        | (_, ["persona"])
+        | (_, ["persona", _])
+        | (_, ["pipeline"])
+        | (_, ["ticker", _])
+        | (_, ["ask"])
+        | (_, ["ask", _])
+        | (_, ["history"])
+        | (_, ["capture"])
+        | (_, ["probes"])
+        | (_, ["probes", _])
+        | (_, ["rewind"])
+        | (_, ["reload"]) => ApiResponse::error(405, "Method not allowed"),
+        _ => ApiResponse::error(404, "Not found"),
+    }
+}
+
+// Forward a command to the main loop
+async fn send_command(state: &ControlApiState, command: String) -> ApiResponse {
+    match state.command_tx.send(command.clone()).await {
+        Ok(_) => ApiResponse::new(202, json!({ "queued": command })),
+        Err(e) => ApiResponse::error(500, &format!("Failed to queue command: {}", e)),
+    }
+}
+
+// Answer a question about the stats, the body is {"question": "...", "session": "..."} or
+// the question as plain text, a new session is started without a session id
+async fn ask(request: &ApiRequest, state: &ControlApiState) -> ApiResponse {
+    let (question, session) = match serde_json::from_str::<Value>(&request.body) {
+        Ok(body) if body.is_object() => (
+            body["question"].as_str().unwrap_or("").trim().to_string(),
+            body["session"].as_str().map(|session| session.to_string()),
+        ),
+        _ => (request.body.trim().to_string(), None),
+    };
+    if question.is_empty() {
+        return ApiResponse::error(400, "Missing question");
+    }
+    let session = session
+        .or_else(|| request.query.get("session").cloned())
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+
+    let answer = state
+        .stats_analyst
+        .lock()
+        .await
+        .ask(&session, &question, &state.args)
+        .await;
+    match answer {
+        Ok(answer) => ApiResponse::new(200, json!({ "session": session, "answer": answer })),
+        Err(e) => ApiResponse::error(500, &format!("Failed to answer: {}", e)),
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * diarization.rs
+ * --------------
+ * Basic speaker diarization of the transcribed program audio. Each caption chunk gets a
+ * voice print from its log mel spectrogram, the mean and spread of the louder frames in each
+ * band with the overall level taken out. Prints are clustered online against the speakers
+ * heard so far by cosine similarity, a chunk that matches none starts a new speaker until
+ * the limit. A chunk is one speaker, shorter chunks follow the turns more closely.
+*/
+
+use crate::args::Args;
+
+// Share of the frames by energy used for the print, the quieter ones are pauses and noise
+const VOICED_SHARE: f32 = 0.5;
+// Frames needed for a print, 10 ms each
+const MIN_FRAMES: usize = 50;
+
+struct Speaker {
+    centroid: Vec<f32>,
+    chunks: usize,
+}
+
+pub struct Diarizer {
+    speakers: Vec<Speaker>,
+    threshold: f32,
+    max_speakers: usize,
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+// Voice print of a log mel spectrogram of n_mels rows by frames columns
+pub fn voice_print(mel: &[f32], n_mels: usize, frames: usize) -> Option<Vec<f32>> {
+    let stride = mel.len() / n_mels.max(1);
+    let frames = frames.min(stride);
+    if frames < MIN_FRAMES {
+        return None;
+    }
+    let energy = |frame: usize| {
+        (0..n_mels)
+            .map(|band| mel[band * stride + frame])
+            .sum::<f32>()
+    };
+    let mut by_energy: Vec<usize> = (0..frames).collect();
+    by_energy.sort_by(|a, b| energy(*b).total_cmp(&energy(*a)));
+    by_energy.truncate(((frames as f32 * VOICED_SHARE) as usize).max(MIN_FRAMES / 2));
+
+    let count = by_energy.len() as f32;
+    let mut print = Vec::with_capacity(n_mels * 2);
+    let mut means = Vec::with_capacity(n_mels);
+    for band in 0..n_mels {
+        let values = by_energy.iter().map(|frame| mel[band * stride + frame]);
+        means.push(values.sum::<f32>() / count);
+    }
+    // the level of the speaker's voice says little about who it is
+    let level = means.iter().sum::<f32>() / n_mels as f32;
+    print.extend(means.iter().map(|mean| mean - level));
+    for (band, mean) in means.iter().enumerate() {
+        let variance = by_energy
+            .iter()
+            .map(|frame| (mel[band * stride + frame] - mean).powi(2))
+            .sum::<f32>()
+            / count;
+        print.push(variance.sqrt());
+    }
+    normalize(&mut print);
+    Some(print)
+}
+
+impl Diarizer {
+    pub fn new(threshold: f32, max_speakers: usize) -> Self {
+        Diarizer {
+            speakers: Vec::new(),
+            threshold,
+            max_speakers: max_speakers.max(1),
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if !args.diarization {
+            return None;
+        }
+        Some(Diarizer::new(
+            args.diarization_threshold,
+            args.diarization_max_speakers,
+        ))
+    }
+
+    // Speaker number from 1 of the voice print, the closest speaker when the limit is reached
+    pub fn assign(&mut self, print: &[f32]) -> usize {
+        let best = self
+            .speakers
+            .iter()
+            .enumerate()
+            .map(|(index, speaker)| (index, cosine(&speaker.centroid, print)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let index = match best {
+            Some((index, similarity))
+                if similarity >= self.threshold || self.speakers.len() >= self.max_speakers =>
+            {
+                index
+            }
+            _ => {
+                self.speakers.push(Speaker {
+                    centroid: vec![0.0; print.len()],
+                    chunks: 0,
+                });
+                self.speakers.len() - 1
+            }
+        };
+
+        // running mean of the prints of the speaker
+        let speaker = &mut self.speakers[index];
+        speaker.chunks += 1;
+        let weight = 1.0 / speaker.chunks as f32;
+        for (centroid, value) in speaker.centroid.iter_mut().zip(print) {
+            *centroid += (value - *centroid) * weight;
+        }
+        normalize(&mut speaker.centroid);
+        index + 1
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * discord_voice.rs
+ * ----------------
+ * Discord voice channel output sink. A bot logs in with the DISCORD_TOKEN, joins the voice
+ * channel of --discord-guild-id and --discord-channel-id and plays the speech of the show
+ * there while the video goes out on NDI. songbird encodes the audio to Opus and sends it,
+ * the sink hands it a live track reading from a buffer the output fills in real time. The
+ * track plays silence between the answers so it never ends.
+*/
+
+use crate::args::Args;
+use crate::audio::resample;
+use crate::external_apis::check_url;
+use crate::output::{AudioFrame, OutputMetadata, OutputSink, VideoFrame};
+use crate::podcast::mono_samples;
+use crate::secrets::secret;
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use serenity::async_trait;
+use serenity::client::{Client, Context, EventHandler};
+use serenity::model::gateway::{GatewayIntents, Ready};
+use serenity::model::id::{ChannelId, GuildId};
+use songbird::input::core::io::MediaSource;
+use songbird::input::{Input, RawAdapter};
+use songbird::{SerenityInit, Songbird};
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+// The gateway and voice servers the speech goes to, for the local only check
+const DISCORD_API: &str = "https://discord.com/api";
+// Discord voice runs at 48 kHz, the speech is resampled to it
+const DISCORD_SAMPLE_RATE: u32 = 48000;
+// Silence queued at a time when there is no speech, 20 ms is one Opus frame
+const SILENCE_SAMPLES: usize = 960;
+// Speech kept while no one reads it, the oldest is dropped past this
+const MAX_BUFFER_SECS: usize = 30;
+
+// Speech waiting to be played, shared by the sink and the track
+#[derive(Default)]
+struct VoiceBuffer {
+    samples: VecDeque<f32>,
+    partial: Vec<u8>, // bytes of a sample a read stopped in the middle of
+}
+
+// The live track, reads never block and return silence when the buffer is empty
+struct VoiceSource {
+    buffer: Arc<Mutex<VoiceBuffer>>,
+}
+
+impl Read for VoiceSource {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.partial.is_empty() && buffer.samples.is_empty() {
+            buffer
+                .samples
+                .extend(std::iter::repeat(0.0).take(SILENCE_SAMPLES));
+        }
+        let mut written = 0;
+        while written < out.len() {
+            if buffer.partial.is_empty() {
+                match buffer.samples.pop_front() {
+                    Some(sample) => buffer.partial = sample.to_le_bytes().to_vec(),
+                    None => break,
+                }
+            }
+            let count = buffer.partial.len().min(out.len() - written);
+            out[written..written + count].copy_from_slice(&buffer.partial[..count]);
+            buffer.partial.drain(..count);
+            written += count;
+        }
+        Ok(written)
+    }
+}
+
+impl Seek for VoiceSource {
+    fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the Discord voice track is live",
+        ))
+    }
+}
+
+impl MediaSource for VoiceSource {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+// Joins the voice channel once the bot is connected and starts the live track
+struct VoiceHandler {
+    manager: Arc<Songbird>,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    buffer: Arc<Mutex<VoiceBuffer>>,
+}
+
+#[async_trait]
+impl EventHandler for VoiceHandler {
+    async fn ready(&self, _ctx: Context, ready: Ready) {
+        info!("Discord connected as {}", ready.user.name);
+        match self.manager.join(self.guild_id, self.channel_id).await {
+            Ok(call) => {
+                let source = VoiceSource {
+                    buffer: Arc::clone(&self.buffer),
+                };
+                let input: Input = RawAdapter::new(source, DISCORD_SAMPLE_RATE, 1).into();
+                call.lock().await.play_input(input);
+                info!(
+                    "Discord voice joined channel {} of guild {}",
+                    self.channel_id, self.guild_id
+                );
+            }
+            Err(e) => error!(
+                "Discord voice failed to join channel {} of guild {}: {}",
+                self.channel_id, self.guild_id, e
+            ),
+        }
+    }
+}
+
+pub struct DiscordVoiceSink {
+    manager: Arc<Songbird>,
+    guild_id: GuildId,
+    buffer: Arc<Mutex<VoiceBuffer>>,
+}
+
+impl DiscordVoiceSink {
+    // Log in and join the channel in the background, the speech is buffered until then
+    pub fn from_args(args: &Args) -> Result<Self> {
+        check_url(DISCORD_API).map_err(|e| anyhow!(e))?;
+        let token = secret("DISCORD_TOKEN").ok_or_else(|| anyhow!("DISCORD_TOKEN is not set"))?;
+        if args.discord_guild_id == 0 || args.discord_channel_id == 0 {
+            return Err(anyhow!(
+                "Discord voice needs --discord-guild-id and --discord-channel-id"
+            ));
+        }
+        let handle = tokio::runtime::Handle::try_current()
+            .map_err(|e| anyhow!("Discord voice needs the tokio runtime: {}", e))?;
+
+        let manager = Songbird::serenity();
+        let guild_id = GuildId::new(args.discord_guild_id);
+        let buffer = Arc::new(Mutex::new(VoiceBuffer::default()));
+        let handler = VoiceHandler {
+            manager: Arc::clone(&manager),
+            guild_id,
+            channel_id: ChannelId::new(args.discord_channel_id),
+            buffer: Arc::clone(&buffer),
+        };
+        let client_manager = Arc::clone(&manager);
+        handle.spawn(async move {
+            let intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_VOICE_STATES;
+            let client = Client::builder(token.expose(), intents)
+                .event_handler(handler)
+                .register_songbird_with(client_manager)
+                .await;
+            match client {
+                Ok(mut client) => {
+                    if let Err(e) = client.start().await {
+                        error!("Discord client stopped: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to create the Discord client: {}", e),
+            }
+        });
+
+        Ok(DiscordVoiceSink {
+            manager,
+            guild_id,
+            buffer,
+        })
+    }
+}
+
+impl OutputSink for DiscordVoiceSink {
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    fn send_video(&mut self, _frame: &VideoFrame) -> Result<()> {
+        Ok(())
+    }
+
+    fn send_audio(&mut self, audio: &AudioFrame) -> Result<()> {
+        let samples = resample(&mono_samples(audio), audio.sample_rate, DISCORD_SAMPLE_RATE)?;
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.samples.extend(samples);
+        let max_samples = MAX_BUFFER_SECS * DISCORD_SAMPLE_RATE as usize;
+        if buffer.samples.len() > max_samples {
+            let dropped = buffer.samples.len() - max_samples;
+            buffer.samples.drain(..dropped);
+            warn!(
+                "Discord voice is not playing, dropped {} ms of speech",
+                dropped * 1000 / DISCORD_SAMPLE_RATE as usize
+            );
+        }
+        Ok(())
+    }
+
+    fn send_metadata(&mut self, _metadata: &OutputMetadata) -> Result<()> {
+        Ok(())
+    }
+
+    // Leave the voice channel
+    fn finish(&mut self) -> Result<()> {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return Ok(());
+        };
+        let manager = Arc::clone(&self.manager);
+        let guild_id = self.guild_id;
+        handle.spawn(async move {
+            if let Err(e) = manager.remove(guild_id).await {
+                warn!("Discord voice failed to leave: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+/*
+ * dry_run.rs
+ * ----------
+ * Startup validation with --dry-run. The parts of the configuration that usually fail only
+ * once the show is running are tried up front: the capture devices and their multicast
+ * joins, the models on the Hugging Face hub, NDI, the Twitch token and the LLM, TTS and
+ * image servers. Only what the args turn on is checked, the report goes to stdout and the
+ * exit code is nonzero when a check failed.
+*/
+
+use crate::args::Args;
+use crate::candle_gemma::gemma_repo;
+use crate::candle_mistral::mistral_repo;
+use crate::external_apis::check_url;
+use crate::icecast::IcecastConfig;
+use crate::image_generator::image_backend_name;
+use crate::mimic3_tts::ENDPOINT as MIMIC3_ENDPOINT;
+use crate::multicast::MulticastMembership;
+use crate::network_capture::{capture_devices, parse_ssm_sources};
+use crate::output::output_sink_names;
+use crate::secrets::{secret, secret_provider};
+use crate::twitch_helix::validate_token;
+use candle_hf_hub::api::sync::Api;
+use candle_hf_hub::{Cache, Repo, RepoType};
+use pcap::{ConnectionStatus, Device};
+use std::net::IpAddr;
+use std::time::Duration;
+
+// Time given to each server to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const OPENAI_URL: &str = "https://api.openai.com";
+
+pub struct CheckResult {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn new(name: &str, result: Result<String, String>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        CheckResult {
+            name: name.to_string(),
+            passed,
+            detail,
+        }
+    }
+}
+
+// The device by name, the first one when no name is given like the capture does
+fn find_device(name: &str) -> Result<Device, String> {
+    let devices = Device::list().map_err(|e| format!("the devices can't be listed: {}", e))?;
+    devices
+        .into_iter()
+        .find(|device| device.name == name || name.is_empty())
+        .ok_or_else(|| "no such device".to_string())
+}
+
+fn check_device(device: &Device) -> Result<String, String> {
+    if !device.flags.is_up() {
+        return Err(format!("{} is down", device.name));
+    }
+    if matches!(
+        device.flags.connection_status,
+        ConnectionStatus::Disconnected
+    ) {
+        return Err(format!("{} has no link", device.name));
+    }
+    Ok(format!("{} is up", device.name))
+}
+
+// Join and leave the group on the device
+fn check_multicast(args: &Args, device: &Device) -> Result<String, String> {
+    let group = args
+        .source_ip
+        .parse::<IpAddr>()
+        .map_err(|e| format!("invalid --source-ip {}: {}", args.source_ip, e))?;
+    if !group.is_multicast() {
+        return Ok(format!("{} is not a multicast group, no join", group));
+    }
+    let sources = parse_ssm_sources(&args.source_ssm);
+    MulticastMembership::join(&group, &sources, device).map_err(|e| e.to_string())?;
+    Ok(format!("joined {} on {}", group, device.name))
+}
+
+// A model repo is resolvable when it is in the cache or the hub knows it
+async fn check_model(repo_id: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        let repo = Repo::new(repo_id.clone(), RepoType::Model);
+        if Cache::default()
+            .repo(repo.clone())
+            .get("config.json")
+            .is_some()
+        {
+            return Ok(format!("{} is in the cache", repo_id));
+        }
+        check_url("https://huggingface.co")?;
+        let api = Api::new().map_err(|e| e.to_string())?;
+        api.repo(repo)
+            .info()
+            .map(|_| format!("{} is on the hub", repo_id))
+            .map_err(|e| format!("{} can't be resolved: {}", repo_id, e))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Any HTTP answer means the server is up
+async fn check_server(url: &str) -> Result<String, String> {
+    check_url(url)?;
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    match client.get(url).send().await {
+        Ok(response) => Ok(format!("{} answered {}", url, response.status())),
+        Err(e) => Err(format!("{} is not reachable: {}", url, e)),
+    }
+}
+
+// A secret of the providers, with the one it comes from
+fn check_secret(name: &str) -> Result<String, String> {
+    match secret_provider(name) {
+        Some(provider) => Ok(format!("{} is set from {}", name, provider.name())),
+        None => Err(format!("{} is not set", name)),
+    }
+}
+
+// Run the checks the args call for
+pub async fn dry_run_checks(args: &Args) -> Vec<CheckResult> {
+    let mut results = Vec::new();
+
+    if args.ai_network_stats && !args.ts_generator {
+        for name in capture_devices(&args.source_device, &args.source_devices) {
+            let label = if name.is_empty() { "default" } else { &name };
+            match find_device(&name) {
+                Ok(device) => {
+                    results.push(CheckResult::new(
+                        &format!("capture device {}", label),
+                        check_device(&device),
+                    ));
+                    results.push(CheckResult::new(
+                        &format!("multicast join on {}", label),
+                        check_multicast(args, &device),
+                    ));
+                }
+                Err(e) => results.push(CheckResult::new(
+                    &format!("capture device {}", label),
+                    Err(e),
+                )),
+            }
+        }
+    }
+
+    if args.use_openai {
+        results.push(CheckResult::new(
+            "OpenAI API key",
+            check_secret("OPENAI_API_KEY"),
+        ));
+        results.push(CheckResult::new(
+            "LLM server",
+            check_server(OPENAI_URL).await,
+        ));
+    } else if args.use_api {
+        results.push(CheckResult::new(
+            "LLM server",
+            check_server(&args.llm_host).await,
+        ));
+    } else {
+        let repo = match args.candle_llm.as_str() {
+            "gemma" => Ok(gemma_repo(Some(args.model_id.clone()))),
+            "mistral" => Ok(mistral_repo(args.quantized, Some(args.model_id.clone()))),
+            llm => Err(format!("{} is not a supported LLM", llm)),
+        };
+        let result = match repo {
+            Ok(repo) => check_model(repo).await,
+            Err(e) => Err(e),
+        };
+        results.push(CheckResult::new("LLM model", result));
+    }
+    if args.captions {
+        results.push(CheckResult::new(
+            "captions model",
+            check_model(args.captions_model.clone()).await,
+        ));
+    }
+
+    if args.ndi_images || args.ndi_audio {
+        #[cfg(feature = "ndi")]
+        let result = crate::ndi::ndi_available().map(|_| "the NDI runtime loads".to_string());
+        #[cfg(not(feature = "ndi"))]
+        let result = Err("built without the ndi feature".to_string());
+        results.push(CheckResult::new("NDI", result));
+    }
+
+    if args.twitch_client {
+        let result = match secret("TWITCH_AUTH") {
+            Some(token) => validate_token(token.expose())
+                .await
+                .map(|login| format!("valid for {}", login))
+                .map_err(|e| e.to_string()),
+            None => Err("TWITCH_AUTH is not set".to_string()),
+        };
+        results.push(CheckResult::new("Twitch token", result));
+    }
+
+    if args.mimic3_tts {
+        results.push(CheckResult::new(
+            "Mimic3 TTS server",
+            check_server(MIMIC3_ENDPOINT).await,
+        ));
+    }
+    if args.oai_tts {
+        results.push(CheckResult::new(
+            "OpenAI API key",
+            check_secret("OPENAI_API_KEY"),
+        ));
+        results.push(CheckResult::new(
+            "OpenAI TTS server",
+            check_server(OPENAI_URL).await,
+        ));
+    }
+
+    if output_sink_names(args).iter().any(|name| name == "icecast") {
+        let result = match IcecastConfig::from_args(args) {
+            Ok(config) => check_server(&config.server_url()).await,
+            Err(e) => Err(e.to_string()),
+        };
+        results.push(CheckResult::new("Icecast server", result));
+    }
+
+    if args.sd_image {
+        let backend = image_backend_name(args);
+        let result = match backend.as_str() {
+            "automatic1111" => Some(check_server(&args.sd_api_host).await),
+            "comfyui" => Some(check_server(&args.comfyui_host).await),
+            "openai" => Some(check_secret("OPENAI_API_KEY")),
+            _ => None, // candle loads the models when the first image is made
+        };
+        if let Some(result) = result {
+            results.push(CheckResult::new(
+                &format!("{} image backend", backend),
+                result,
+            ));
+        }
+    }
+    results
+}
+
+// Print the report, true when every check passed
+pub async fn dry_run(args: &Args) -> bool {
+    let results = dry_run_checks(args).await;
+    println!("Dry run of the configuration:");
+    if results.is_empty() {
+        println!("  nothing to check with these args");
+    }
+    for result in &results {
+        println!(
+            "  [{}] {}: {}",
+            if result.passed { " ok " } else { "FAIL" },
+            result.name,
+            result.detail
+        );
+    }
+    let failed = results.iter().filter(|result| !result.passed).count();
+    println!("{} checks, {} failed", results.len(), failed);
+    failed == 0
+}
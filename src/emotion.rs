@@ -0,0 +1,323 @@
+/*
+ * emotion.rs
+ * ----------
+ * Emotion tagging for paragraphs, used to pick the TTS prosody and the mood of the
+ * generated images. The LLM can emit a tag like {happy} in a paragraph, when no tag is
+ * present a small keyword classifier is used instead.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Emotion {
+    Neutral,
+    Happy,
+    Excited,
+    Calm,
+    Sad,
+    Angry,
+    Fearful,
+    Surprised,
+}
+
+// TTS prosody settings for an emotion
+#[derive(Clone, Copy, Debug)]
+pub struct Prosody {
+    pub length_scale: f32, // mimic3, higher is slower speech
+    pub noise_scale: f32,  // mimic3, voice variability
+    pub noise_w: f32,      // mimic3, phoneme duration variability
+    pub speed: f32,        // openai, 1.0 is normal speed
+}
+
+pub const EMOTIONS: [Emotion; 8] = [
+    Emotion::Neutral,
+    Emotion::Happy,
+    Emotion::Excited,
+    Emotion::Calm,
+    Emotion::Sad,
+    Emotion::Angry,
+    Emotion::Fearful,
+    Emotion::Surprised,
+];
+
+impl Emotion {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Emotion::Neutral => "neutral",
+            Emotion::Happy => "happy",
+            Emotion::Excited => "excited",
+            Emotion::Calm => "calm",
+            Emotion::Sad => "sad",
+            Emotion::Angry => "angry",
+            Emotion::Fearful => "fearful",
+            Emotion::Surprised => "surprised",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Emotion> {
+        let name = name.trim().to_lowercase();
+        EMOTIONS.iter().copied().find(|e| e.name() == name)
+    }
+
+    pub fn prosody(&self) -> Prosody {
+        let (length_scale, noise_scale, noise_w, speed) = match self {
+            Emotion::Neutral => (1.0, 0.333, 0.333, 1.0),
+            Emotion::Happy => (0.95, 0.5, 0.5, 1.05),
+            Emotion::Excited => (0.85, 0.667, 0.6, 1.15),
+            Emotion::Calm => (1.15, 0.25, 0.25, 0.9),
+            Emotion::Sad => (1.25, 0.25, 0.2, 0.85),
+            Emotion::Angry => (0.9, 0.6, 0.667, 1.1),
+            Emotion::Fearful => (0.9, 0.667, 0.8, 1.1),
+            Emotion::Surprised => (0.9, 0.6, 0.5, 1.1),
+        };
+        Prosody {
+            length_scale,
+            noise_scale,
+            noise_w,
+            speed,
+        }
+    }
+
+    // pleasantness from -1.0 to 1.0 and energy from 0.0 to 1.0 of the emotion
+    pub fn valence(&self) -> f32 {
+        match self {
+            Emotion::Neutral => 0.0,
+            Emotion::Happy => 0.8,
+            Emotion::Excited => 0.6,
+            Emotion::Calm => 0.4,
+            Emotion::Sad => -0.7,
+            Emotion::Angry => -0.8,
+            Emotion::Fearful => -0.6,
+            Emotion::Surprised => 0.2,
+        }
+    }
+
+    pub fn arousal(&self) -> f32 {
+        match self {
+            Emotion::Neutral => 0.4,
+            Emotion::Happy => 0.6,
+            Emotion::Excited => 0.9,
+            Emotion::Calm => 0.15,
+            Emotion::Sad => 0.2,
+            Emotion::Angry => 0.85,
+            Emotion::Fearful => 0.75,
+            Emotion::Surprised => 0.8,
+        }
+    }
+
+    // Mood modifier appended to the stable diffusion prompt
+    pub fn sd_modifier(&self) -> &'static str {
+        match self {
+            Emotion::Neutral => "",
+            Emotion::Happy => "joyful mood, warm bright colors, soft sunlight",
+            Emotion::Excited => "energetic mood, vibrant saturated colors, dynamic composition",
+            Emotion::Calm => "serene mood, soft pastel colors, gentle lighting",
+            Emotion::Sad => "melancholic mood, muted cold colors, overcast light",
+            Emotion::Angry => "intense mood, harsh red tones, dramatic shadows",
+            Emotion::Fearful => "ominous mood, dark tones, low key lighting",
+            Emotion::Surprised => "astonished mood, striking contrast, wide open scene",
+        }
+    }
+
+    // keywords for the fallback classifier
+    fn keywords(&self) -> &'static [&'static str] {
+        match self {
+            Emotion::Neutral => &[],
+            Emotion::Happy => &[
+                "happy",
+                "joy",
+                "glad",
+                "smile",
+                "laugh",
+                "delight",
+                "love",
+                "wonderful",
+                "cheer",
+            ],
+            Emotion::Excited => &[
+                "excited",
+                "amazing",
+                "awesome",
+                "incredible",
+                "thrill",
+                "wow",
+                "fantastic",
+            ],
+            Emotion::Calm => &[
+                "calm", "peace", "quiet", "gentle", "serene", "relax", "breathe", "meditat",
+            ],
+            Emotion::Sad => &[
+                "sad", "sorrow", "cry", "tears", "grief", "lonely", "loss", "mourn", "regret",
+            ],
+            Emotion::Angry => &[
+                "angry", "rage", "fury", "furious", "hate", "shout", "outrage",
+            ],
+            Emotion::Fearful => &[
+                "afraid", "fear", "scared", "terror", "dread", "panic", "horror", "danger",
+            ],
+            Emotion::Surprised => &["surprise", "sudden", "unexpected", "shock", "gasp"],
+        }
+    }
+}
+
+impl fmt::Display for Emotion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Instruction appended to the system prompt so the LLM tags its paragraphs
+pub fn emotion_tag_instructions() -> String {
+    let names: Vec<&str> = EMOTIONS.iter().map(|e| e.name()).collect();
+    format!(
+        " Begin each paragraph with an emotion tag in curly braces describing its mood, one of {{{}}}, for example {{happy}}.",
+        names.join("}, {")
+    )
+}
+
+// Remove emotion tags like {happy} or {emotion: happy} from the text, returns the first tag found
+pub fn extract_emotion_tag(text: &str) -> (Option<Emotion>, String) {
+    let mut emotion = None;
+    let mut cleaned = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let inner = &rest[start + 1..start + len];
+        let name = inner
+            .split_once(':')
+            .map(|(_, value)| value)
+            .unwrap_or(inner);
+        match Emotion::from_name(name) {
+            Some(tag) => {
+                if emotion.is_none() {
+                    emotion = Some(tag);
+                }
+                cleaned.push_str(&rest[..start]);
+            }
+            None => cleaned.push_str(&rest[..start + len + 1]),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    cleaned.push_str(rest);
+
+    // collapse the whitespace left behind by removed tags
+    let cleaned = if emotion.is_some() {
+        cleaned.split_whitespace().collect::<Vec<_>>().join(" ")
+    } else {
+        cleaned
+    };
+
+    (emotion, cleaned)
+}
+
+// Keyword based classifier, returns Neutral when nothing matches
+pub fn classify_emotion(text: &str) -> Emotion {
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    let mut best = Emotion::Neutral;
+    let mut best_score = 0;
+    for emotion in EMOTIONS.iter() {
+        let score = words
+            .iter()
+            .filter(|w| emotion.keywords().iter().any(|k| w.starts_with(k)))
+            .count();
+        if score > best_score {
+            best = *emotion;
+            best_score = score;
+        }
+    }
+
+    // exclamations lift a neutral paragraph into an excited one
+    if best == Emotion::Neutral && text.matches('!').count() >= 2 {
+        return Emotion::Excited;
+    }
+
+    best
+}
+
+// Tag from the LLM if present otherwise classify, returns the text with tags removed
+pub fn detect_emotion(text: &str) -> (Emotion, String) {
+    let (tag, cleaned) = extract_emotion_tag(text);
+    match tag {
+        Some(emotion) => (emotion, cleaned),
+        None => (classify_emotion(&cleaned), cleaned),
+    }
+}
+
+// Sentiment of a paragraph for the pacing of the show
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sentiment {
+    pub valence: f32, // -1.0 negative to 1.0 positive
+    pub arousal: f32, // 0.0 calm to 1.0 intense
+}
+
+const POSITIVE_WORDS: [&str; 14] = [
+    "good",
+    "great",
+    "best",
+    "win",
+    "success",
+    "beautiful",
+    "bright",
+    "hope",
+    "kind",
+    "stable",
+    "recover",
+    "improv",
+    "thank",
+    "perfect",
+];
+const NEGATIVE_WORDS: [&str; 14] = [
+    "bad", "worst", "fail", "error", "loss", "dark", "broken", "drop", "problem", "crash", "storm",
+    "war", "pain", "wrong",
+];
+
+impl Sentiment {
+    // Speaking rate around 1.0, intense paragraphs are faster and calm ones slower by up to
+    // the range
+    pub fn speaking_rate(&self, range: f32) -> f32 {
+        1.0 + (self.arousal - 0.5) * 2.0 * range
+    }
+}
+
+// Sentiment from the emotion of the paragraph and its positive and negative words, the
+// exclamations and capitalized words raise the intensity
+pub fn classify_sentiment(text: &str, emotion: Emotion) -> Sentiment {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+    let count = |lexicon: &[&str]| {
+        words
+            .iter()
+            .filter(|w| lexicon.iter().any(|k| w.starts_with(k)))
+            .count() as f32
+    };
+    let (positive, negative) = (count(&POSITIVE_WORDS), count(&NEGATIVE_WORDS));
+    let valence = if positive + negative > 0.0 {
+        (emotion.valence() + (positive - negative) / (positive + negative)) / 2.0
+    } else {
+        emotion.valence()
+    };
+
+    let exclamations = text.matches('!').count() as f32;
+    let shouted = text
+        .split_whitespace()
+        .filter(|w| w.chars().count() > 2 && w.chars().all(|c| c.is_uppercase()))
+        .count() as f32;
+    let arousal = emotion.arousal() + (exclamations * 0.1).min(0.3) + (shouted * 0.05).min(0.15);
+
+    Sentiment {
+        valence: valence.clamp(-1.0, 1.0),
+        arousal: arousal.clamp(0.0, 1.0),
+    }
+}
@@ -0,0 +1,217 @@
+/*
+ * event_log.rs
+ * ------------
+ * Structured JSONL log of the analyzer and pipeline events, one JSON object per line with the
+ * schema version so other tools can follow the stream and the generated media without the LLM.
+ * The file is rotated to .1, .2, ... when it reaches the size limit. The latest events are also
+ * kept in memory for the health reports and the stats questions, and handed to the subscribers
+ * like the publisher to NATS or MQTT.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+// Bump when a field of the event line changes meaning or is removed
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+// Events kept in memory whether or not the log file is enabled
+pub const RECENT_EVENTS_MAX: usize = 1000;
+
+static EVENT_LOG: Lazy<Mutex<Option<EventLog>>> = Lazy::new(|| Mutex::new(None));
+static RECENT_EVENTS: Lazy<Mutex<VecDeque<Value>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+static SUBSCRIBERS: Lazy<Mutex<Vec<Sender<Value>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// One line of the event log
+#[derive(Debug, Clone, Serialize)]
+pub struct Event<'a> {
+    pub schema: u32,
+    pub timestamp: u64,
+    pub source: &'a str, // analyzer or pipeline
+    pub event: &'a str,
+    pub data: Value,
+}
+
+pub struct EventLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl EventLog {
+    pub fn new(path: &str, max_bytes: u64, max_files: usize) -> Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(EventLog {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            written,
+        })
+    }
+
+    pub fn write(&mut self, event: &Event) -> Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        if self.max_bytes > 0
+            && self.written > 0
+            && self.written + line.len() as u64 > self.max_bytes
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    // events.jsonl -> events.jsonl.1 -> events.jsonl.2 ..., the oldest is removed
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+// Open the event log from --event-log, events are dropped when it isn't set
+pub fn init_event_log(args: &Args) -> Result<()> {
+    if args.event_log.is_empty() {
+        return Ok(());
+    }
+    let log = EventLog::new(
+        &args.event_log,
+        args.event_log_max_mb * 1024 * 1024,
+        args.event_log_max_files,
+    )?;
+    *EVENT_LOG.lock().unwrap() = Some(log);
+    Ok(())
+}
+
+// Append an event, write failures are logged and don't stop the caller
+pub fn log_event(source: &str, event: &str, data: Value) {
+    let event = Event {
+        schema: EVENT_SCHEMA_VERSION,
+        timestamp: current_unix_timestamp_ms().unwrap_or(0),
+        source,
+        event,
+        data,
+    };
+
+    if let Ok(value) = serde_json::to_value(&event) {
+        SUBSCRIBERS
+            .lock()
+            .unwrap()
+            .retain(|subscriber| match subscriber.try_send(value.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Closed(_)) => false,
+            });
+        let mut recent = RECENT_EVENTS.lock().unwrap();
+        if recent.len() >= RECENT_EVENTS_MAX {
+            recent.pop_front();
+        }
+        recent.push_back(value);
+    }
+
+    let mut event_log = EVENT_LOG.lock().unwrap();
+    let Some(log) = event_log.as_mut() else {
+        return;
+    };
+    if let Err(e) = log.write(&event) {
+        log::error!(
+            "Failed to write event {} to the event log: {}",
+            event.event,
+            e
+        );
+    }
+}
+
+// Receive the events as they are logged, events are dropped while the receiver is behind
+pub fn subscribe_events(capacity: usize) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel(capacity.max(1));
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+// Events since the timestamp in ms, oldest first, an empty source matches all sources
+pub fn recent_events(source: &str, since_ms: u64) -> Vec<Value> {
+    RECENT_EVENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|event| source.is_empty() || event["source"] == source)
+        .filter(|event| event["timestamp"].as_u64().unwrap_or(0) >= since_ms)
+        .cloned()
+        .collect()
+}
+
+// Events between the timestamps from the log file and its rotations, or from memory when
+// there is no log file, oldest first
+pub fn events_between(path: &str, since_ms: u64, until_ms: u64) -> Vec<Value> {
+    let in_range = |event: &Value| {
+        let timestamp = event["timestamp"].as_u64().unwrap_or(0);
+        timestamp >= since_ms && timestamp <= until_ms
+    };
+    if path.is_empty() {
+        return recent_events("", since_ms)
+            .into_iter()
+            .filter(in_range)
+            .collect();
+    }
+
+    let path = Path::new(path);
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|index| rotated_path(path, index))
+        .take_while(|rotated| rotated.exists())
+        .collect();
+    files.reverse();
+    files.push(path.to_path_buf());
+
+    files
+        .iter()
+        .filter_map(|file| fs::read_to_string(file).ok())
+        .flat_map(|contents| {
+            contents
+                .lines()
+                .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+                .filter(in_range)
+                .collect::<Vec<Value>>()
+        })
+        .collect()
+}
@@ -0,0 +1,289 @@
+/*
+ * event_publish.rs
+ * ----------------
+ * Publishes the events of the event log and the stats of every interval to a NATS server or
+ * an MQTT broker, so a broadcast monitoring stack subscribes to the analysis instead of
+ * polling the control api. The payloads are the JSON of the event log lines and the stats
+ * messages. Events go to <topic>.events.<source>.<event> on NATS and
+ * <topic>/events/<source>/<event> on MQTT, the stats to <topic>.stats and <topic>/stats.
+ * NATS needs the nats feature and MQTT the mqtt feature.
+*/
+
+use crate::args::Args;
+use crate::event_log::subscribe_events;
+use crate::external_apis::check_url;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+#[cfg(any(feature = "nats", feature = "mqtt"))]
+use futures::FutureExt;
+use log::{error, info, warn};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+// Events and stats waiting for the broker before new ones are dropped
+const PUBLISH_QUEUE_SIZE: usize = 1024;
+#[cfg(feature = "mqtt")]
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Broker {
+    Nats,
+    Mqtt,
+}
+
+impl std::str::FromStr for Broker {
+    type Err = String;
+
+    // From the scheme of --publish-url
+    fn from_str(scheme: &str) -> Result<Self, String> {
+        match scheme.to_lowercase().as_str() {
+            "nats" => Ok(Broker::Nats),
+            "mqtt" | "tcp" => Ok(Broker::Mqtt),
+            _ => Err(format!("Invalid broker {}", scheme)),
+        }
+    }
+}
+
+impl Broker {
+    // Separator of the levels of a topic
+    fn separator(&self) -> &'static str {
+        match self {
+            Broker::Nats => ".",
+            Broker::Mqtt => "/",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PublishConfig {
+    pub broker: Broker,
+    pub url: Url,
+    pub topic: String, // prefix of the topics
+}
+
+impl PublishConfig {
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let url = Url::parse(&args.publish_url)
+            .map_err(|e| anyhow!("Invalid --publish-url {}: {}", args.publish_url, e))?;
+        let broker = url.scheme().parse::<Broker>().map_err(|e| anyhow!(e))?;
+        if url.host_str().is_none() {
+            return Err(anyhow!("No host in --publish-url {}", args.publish_url));
+        }
+        let config = PublishConfig {
+            broker,
+            url,
+            topic: args.publish_topic.clone(),
+        };
+        // the events carry the analysis of the captured streams
+        check_url(&config.server()).map_err(|e| anyhow!(e))?;
+        Ok(config)
+    }
+
+    // The prefix and the levels, characters that separate or match levels are replaced in
+    // the levels
+    pub fn topic(&self, levels: &[&str]) -> String {
+        std::iter::once(self.topic.clone())
+            .chain(
+                levels
+                    .iter()
+                    .map(|level| level.replace(['.', '/', ' ', '*', '>', '+', '#'], "_")),
+            )
+            .filter(|level| !level.is_empty())
+            .collect::<Vec<_>>()
+            .join(self.broker.separator())
+    }
+
+    // The topic of an event from its source and name
+    fn event_topic(&self, event: &Value) -> String {
+        self.topic(&[
+            "events",
+            event["source"].as_str().unwrap_or_default(),
+            event["event"].as_str().unwrap_or_default(),
+        ])
+    }
+
+    // The server without the credentials, for the logs
+    fn server(&self) -> String {
+        format!(
+            "{}://{}:{}",
+            self.url.scheme(),
+            self.url.host_str().unwrap_or_default(),
+            self.url.port().unwrap_or(match self.broker {
+                Broker::Nats => 4222,
+                Broker::Mqtt => 1883,
+            })
+        )
+    }
+}
+
+trait BrokerClient: Send + Sync {
+    fn publish(&self, topic: String, payload: Vec<u8>) -> BoxFuture<'_, Result<()>>;
+}
+
+#[cfg(feature = "nats")]
+struct NatsClient(async_nats::Client);
+
+#[cfg(feature = "nats")]
+impl BrokerClient for NatsClient {
+    fn publish(&self, topic: String, payload: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.0
+                .publish(topic, payload.into())
+                .await
+                .map_err(|e| anyhow!(e))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(feature = "mqtt")]
+struct MqttClient(rumqttc::AsyncClient);
+
+#[cfg(feature = "mqtt")]
+impl BrokerClient for MqttClient {
+    fn publish(&self, topic: String, payload: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.0
+                .publish(topic, rumqttc::QoS::AtLeastOnce, false, payload)
+                .await
+                .map_err(|e| anyhow!(e))
+        }
+        .boxed()
+    }
+}
+
+// The client reconnects by itself, publishing while the broker is away queues or fails
+async fn connect(config: &PublishConfig) -> Result<Box<dyn BrokerClient>> {
+    check_url(&config.server()).map_err(|e| anyhow!(e))?;
+    match config.broker {
+        #[cfg(feature = "nats")]
+        Broker::Nats => {
+            let mut options = async_nats::ConnectOptions::new()
+                .name("rsllm")
+                .retry_on_initial_connect();
+            if !config.url.username().is_empty() {
+                options = options.user_and_password(
+                    config.url.username().to_string(),
+                    config.url.password().unwrap_or_default().to_string(),
+                );
+            }
+            let client = options.connect(config.server()).await?;
+            Ok(Box::new(NatsClient(client)))
+        }
+        #[cfg(not(feature = "nats"))]
+        Broker::Nats => Err(anyhow!(
+            "Publishing to NATS needs the nats feature, build with --features nats"
+        )),
+        #[cfg(feature = "mqtt")]
+        Broker::Mqtt => {
+            let mut options = rumqttc::MqttOptions::new(
+                format!("rsllm-{}", uuid::Uuid::new_v4().simple()),
+                config.url.host_str().unwrap_or_default(),
+                config.url.port().unwrap_or(1883),
+            );
+            options.set_keep_alive(std::time::Duration::from_secs(30));
+            if !config.url.username().is_empty() {
+                options.set_credentials(
+                    config.url.username(),
+                    config.url.password().unwrap_or_default(),
+                );
+            }
+            let (client, mut eventloop) = rumqttc::AsyncClient::new(options, PUBLISH_QUEUE_SIZE);
+            // the event loop sends the publishes and connects again after an error
+            let server = config.server();
+            tokio::spawn(async move {
+                let mut connected = true;
+                loop {
+                    match eventloop.poll().await {
+                        Ok(_) => connected = true,
+                        Err(e) => {
+                            if connected {
+                                warn!("MQTT lost {}: {}", server, e);
+                            }
+                            connected = false;
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                        }
+                    }
+                }
+            });
+            Ok(Box::new(MqttClient(client)))
+        }
+        #[cfg(not(feature = "mqtt"))]
+        Broker::Mqtt => Err(anyhow!(
+            "Publishing to MQTT needs the mqtt feature, build with --features mqtt"
+        )),
+    }
+}
+
+async fn publish_all(
+    config: PublishConfig,
+    mut events: Receiver<Value>,
+    mut stats: Receiver<Value>,
+) {
+    let client = match connect(&config).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to publish to {}: {}", config.server(), e);
+            return;
+        }
+    };
+    info!(
+        "Publishing the events and stats to {} under {}",
+        config.server(),
+        config.topic
+    );
+    let mut failing = false;
+    loop {
+        let (topic, payload) = tokio::select! {
+            Some(event) = events.recv() => (config.event_topic(&event), event),
+            Some(stats) = stats.recv() => (config.topic(&["stats"]), stats),
+            else => break,
+        };
+        match client
+            .publish(topic, payload.to_string().into_bytes())
+            .await
+        {
+            Ok(()) => failing = false,
+            Err(e) => {
+                // once per outage, the client keeps trying to reconnect
+                if !failing {
+                    warn!("Failed to publish to {}: {}", config.server(), e);
+                }
+                failing = true;
+            }
+        }
+    }
+}
+
+pub struct EventPublisher {
+    stats: Sender<Value>,
+}
+
+impl EventPublisher {
+    // Start publishing to --publish-url, None when it is not set or invalid
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.publish_url.is_empty() {
+            return None;
+        }
+        let config = match PublishConfig::from_args(args) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}, not publishing", e);
+                return None;
+            }
+        };
+        let (stats, stats_receiver) = mpsc::channel(PUBLISH_QUEUE_SIZE);
+        let events = subscribe_events(PUBLISH_QUEUE_SIZE);
+        tokio::spawn(publish_all(config, events, stats_receiver));
+        Some(EventPublisher { stats })
+    }
+
+    // The stats of an interval, dropped while the broker is behind
+    pub fn publish_stats(&self, stats: &impl Serialize) {
+        if let Ok(stats) = serde_json::to_value(stats) {
+            let _ = self.stats.try_send(stats);
+        }
+    }
+}
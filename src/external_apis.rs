@@ -0,0 +1,50 @@
+/*
+ * external_apis.rs
+ * ----------------
+ * Local only mode with --no-external-apis or the local_only feature. Every HTTP API call is
+ * checked here first and refused unless the server is on this host, so prompts with the
+ * captured stream data, speech and images never leave the machine.
+*/
+
+use reqwest::Url;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static NO_EXTERNAL_APIS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_no_external_apis(enabled: bool) {
+    NO_EXTERNAL_APIS.store(enabled, Ordering::SeqCst);
+}
+
+// Built with the local_only feature it can't be turned off
+pub fn no_external_apis() -> bool {
+    cfg!(feature = "local_only") || NO_EXTERNAL_APIS.load(Ordering::SeqCst)
+}
+
+// localhost, 127.0.0.0/8 and ::1
+fn is_local_host(url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    match url.host_str() {
+        Some(host) if host.eq_ignore_ascii_case("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+// Err when local only mode is on and the url is not on this host
+pub fn check_url(url: &str) -> Result<(), String> {
+    if no_external_apis() && !is_local_host(url) {
+        return Err(format!(
+            "Request to {} blocked, external APIs are disabled with --no-external-apis",
+            url.split('?').next().unwrap_or(url)
+        ));
+    }
+    Ok(())
+}
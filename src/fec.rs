@@ -0,0 +1,352 @@
+/*
+ * fec.rs
+ * ------
+ * RTP wrapped MPEG-TS (SMPTE 2022-2) and its SMPTE 2022-1/5 FEC. With --source-fec the column
+ * and row FEC streams arrive on the media port + 2 and + 4, they are counted as protection
+ * instead of being analyzed as media, and the media sequence gaps they can repair are
+ * reported as recovered losses. Without it only the RTP header of 2022-2 is stripped.
+*/
+
+use log::{debug, info};
+use rtp_rs::RtpReader;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+const RTP_PAYLOAD_TYPE_MP2T: u8 = 33;
+const FEC_HEADER_LEN: usize = 16;
+const COLUMN_PORT_OFFSET: u16 = 2;
+const ROW_PORT_OFFSET: u16 = 4;
+
+// Sequence numbers further back than this are no longer tracked as missing
+const LOSS_WINDOW: usize = 2048;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FecStats {
+    pub rtp_packets: u64,
+    pub lost_packets: u64,
+    pub recovered_packets: u64,
+    pub unrecovered_packets: u64,
+    pub column_fec_packets: u64,
+    pub row_fec_packets: u64,
+    pub columns: u8, // L of the FEC matrix
+    pub rows: u8,    // D of the FEC matrix
+    pub column_coverage_percent: f64,
+    pub row_coverage_percent: f64,
+}
+
+// What the payload of a packet turned out to be
+pub enum FecPayload {
+    // Media, with the MPEG-TS starting this many bytes into the payload
+    Media(usize),
+    // Column or row FEC, no MPEG-TS in it
+    Protection,
+}
+
+// Offset of the MPEG-TS in a 2022-2 RTP payload, 0 when the payload is not MPEG-TS over RTP
+pub fn rtp_ts_offset(payload: &[u8]) -> usize {
+    let Ok(rtp) = RtpReader::new(payload) else {
+        return 0;
+    };
+    let offset = rtp.payload_offset();
+    if rtp.payload_type() == RTP_PAYLOAD_TYPE_MP2T && payload.get(offset) == Some(&0x47) {
+        offset
+    } else {
+        0
+    }
+}
+
+pub struct FecTracker {
+    media_port: u16,
+    last_sequence: Option<u16>,
+    missing: VecDeque<u16>,
+    stats: FecStats,
+}
+
+impl FecTracker {
+    pub fn new(media_port: u16) -> Self {
+        FecTracker {
+            media_port,
+            last_sequence: None,
+            missing: VecDeque::new(),
+            stats: FecStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &FecStats {
+        &self.stats
+    }
+
+    // Sort out the UDP payload by port, tracking RTP sequence numbers of the media and
+    // repairing them with the FEC packets
+    pub fn process(&mut self, port: Option<u16>, payload: &[u8]) -> FecPayload {
+        let Ok(rtp) = RtpReader::new(payload) else {
+            return FecPayload::Media(0);
+        };
+        match port.map(|port| port.wrapping_sub(self.media_port)) {
+            Some(COLUMN_PORT_OFFSET) => {
+                self.stats.column_fec_packets += 1;
+                self.fec_packet(rtp.payload());
+                FecPayload::Protection
+            }
+            Some(ROW_PORT_OFFSET) => {
+                self.stats.row_fec_packets += 1;
+                self.fec_packet(rtp.payload());
+                FecPayload::Protection
+            }
+            _ => {
+                // 2022-2 MPEG-TS over RTP, anything else stays an RTP stream like SMPTE 2110
+                let offset = rtp_ts_offset(payload);
+                if offset > 0 {
+                    self.media_packet(rtp.sequence_number().into());
+                }
+                FecPayload::Media(offset)
+            }
+        }
+    }
+
+    fn media_packet(&mut self, sequence: u16) {
+        self.stats.rtp_packets += 1;
+        if let Some(index) = self.missing.iter().position(|&missing| missing == sequence) {
+            // reordered rather than lost
+            self.missing.remove(index);
+            self.stats.lost_packets = self.stats.lost_packets.saturating_sub(1);
+            self.update_totals();
+            return;
+        }
+        if let Some(last) = self.last_sequence {
+            let step = sequence.wrapping_sub(last);
+            if step == 0 || step >= 0x8000 {
+                // duplicate or too late to be reordered
+                return;
+            }
+            let gap = step as usize - 1;
+            if gap >= LOSS_WINDOW {
+                debug!("RTP sequence jumped from {} to {}", last, sequence);
+            } else if gap > 0 {
+                self.stats.lost_packets += gap as u64;
+                for missing in 1..=gap as u16 {
+                    self.missing.push_back(last.wrapping_add(missing));
+                }
+                while self.missing.len() > LOSS_WINDOW {
+                    self.missing.pop_front();
+                }
+            }
+        }
+        self.last_sequence = Some(sequence);
+        self.update_totals();
+    }
+
+    // The 2022-1 FEC header, 2022-5 protection packets carry the same SNBase, offset and NA
+    fn fec_packet(&mut self, header: &[u8]) {
+        if header.len() < FEC_HEADER_LEN {
+            debug!("FEC packet too short for its header");
+            return;
+        }
+        let sn_base = u16::from_be_bytes([header[0], header[1]]);
+        let row = header[12] & 0x40 != 0;
+        let offset = header[13];
+        let count = header[14];
+        if row {
+            self.stats.columns = count;
+        } else {
+            self.stats.columns = offset;
+            self.stats.rows = count;
+        }
+
+        // XOR parity repairs a single missing packet of the protected ones
+        let protected: Vec<u16> = (0..count as u16)
+            .map(|index| sn_base.wrapping_add(index.wrapping_mul(offset.max(1) as u16)))
+            .collect();
+        let missing: Vec<usize> = self
+            .missing
+            .iter()
+            .enumerate()
+            .filter(|(_, sequence)| protected.contains(sequence))
+            .map(|(index, _)| index)
+            .collect();
+        if let [index] = missing[..] {
+            let sequence = self.missing.remove(index).unwrap_or_default();
+            self.stats.recovered_packets += 1;
+            info!(
+                "STATUS::FEC:RECOVERED: packet {} by the {} FEC at SNBase {}",
+                sequence,
+                if row { "row" } else { "column" },
+                sn_base
+            );
+        }
+        self.update_totals();
+    }
+
+    fn update_totals(&mut self) {
+        let stats = &mut self.stats;
+        stats.unrecovered_packets = stats.lost_packets.saturating_sub(stats.recovered_packets);
+        // one column FEC packet is expected per D media packets and one row per L
+        stats.column_coverage_percent =
+            coverage_percent(stats.column_fec_packets, stats.rows, stats.rtp_packets);
+        stats.row_coverage_percent =
+            coverage_percent(stats.row_fec_packets, stats.columns, stats.rtp_packets);
+    }
+}
+
+fn coverage_percent(fec_packets: u64, media_per_fec: u8, media_packets: u64) -> f64 {
+    if media_per_fec == 0 || media_packets == 0 {
+        return 0.0;
+    }
+    (fec_packets as f64 * media_per_fec as f64 * 100.0 / media_packets as f64).min(100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEDIA_PORT: u16 = 5000;
+
+    fn rtp(payload_type: u8, sequence: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, payload_type];
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]); // timestamp and SSRC
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn media(sequence: u16) -> Vec<u8> {
+        rtp(RTP_PAYLOAD_TYPE_MP2T, sequence, &[0x47, 0x00, 0x11, 0x10])
+    }
+
+    // 2022-1 FEC header with the D bit set for a row, protecting `count` packets `offset`
+    // apart from SNBase
+    fn fec(sn_base: u16, row: bool, offset: u8, count: u8) -> Vec<u8> {
+        let mut header = vec![0; FEC_HEADER_LEN];
+        header[..2].copy_from_slice(&sn_base.to_be_bytes());
+        header[12] = if row { 0x40 } else { 0x00 };
+        header[13] = offset;
+        header[14] = count;
+        rtp(96, 0, &header)
+    }
+
+    fn tracker(sequences: impl IntoIterator<Item = u16>) -> FecTracker {
+        let mut tracker = FecTracker::new(MEDIA_PORT);
+        for sequence in sequences {
+            tracker.process(Some(MEDIA_PORT), &media(sequence));
+        }
+        tracker
+    }
+
+    fn missing(tracker: &FecTracker) -> Vec<u16> {
+        tracker.missing.iter().copied().collect()
+    }
+
+    #[test]
+    fn strips_the_rtp_header_of_mpegts() {
+        assert_eq!(rtp_ts_offset(&media(1)), 12);
+        // 2110 and other RTP payloads, and plain MPEG-TS, start at the payload
+        assert_eq!(rtp_ts_offset(&rtp(96, 1, &[0x47, 0x00])), 0);
+        assert_eq!(
+            rtp_ts_offset(&rtp(RTP_PAYLOAD_TYPE_MP2T, 1, &[0x00, 0x47])),
+            0
+        );
+        assert_eq!(rtp_ts_offset(&[0x47, 0x00, 0x11, 0x10]), 0);
+    }
+
+    #[test]
+    fn parses_the_column_fec_header() {
+        let mut tracker = tracker([0, 1]);
+        let payload = tracker.process(Some(MEDIA_PORT + COLUMN_PORT_OFFSET), &fec(0, false, 5, 4));
+        assert!(matches!(payload, FecPayload::Protection));
+        let stats = tracker.stats();
+        assert_eq!(stats.column_fec_packets, 1);
+        assert_eq!((stats.columns, stats.rows), (5, 4));
+        assert_eq!(stats.rtp_packets, 2);
+    }
+
+    #[test]
+    fn parses_the_row_fec_header() {
+        let mut tracker = tracker([0]);
+        let payload = tracker.process(Some(MEDIA_PORT + ROW_PORT_OFFSET), &fec(0, true, 1, 5));
+        assert!(matches!(payload, FecPayload::Protection));
+        let stats = tracker.stats();
+        assert_eq!(stats.row_fec_packets, 1);
+        assert_eq!((stats.columns, stats.rows), (5, 0));
+    }
+
+    #[test]
+    fn ignores_a_short_fec_header() {
+        let mut tracker = tracker([0, 2]);
+        let short = rtp(96, 0, &[0; FEC_HEADER_LEN - 1]);
+        tracker.process(Some(MEDIA_PORT + ROW_PORT_OFFSET), &short);
+        assert_eq!(tracker.stats().row_fec_packets, 1);
+        assert_eq!(tracker.stats().columns, 0);
+        assert_eq!(tracker.stats().recovered_packets, 0);
+    }
+
+    #[test]
+    fn tracks_missing_sequences_across_the_wrap() {
+        let tracker = tracker([0xFFFD, 0xFFFE, 0x0001, 0x0002]);
+        assert_eq!(missing(&tracker), vec![0xFFFF, 0x0000]);
+        assert_eq!(tracker.stats().lost_packets, 2);
+        assert_eq!(tracker.stats().rtp_packets, 4);
+    }
+
+    #[test]
+    fn reordered_packets_are_not_lost() {
+        let tracker = tracker([0xFFFE, 0x0001, 0xFFFF, 0x0000]);
+        assert!(missing(&tracker).is_empty());
+        assert_eq!(tracker.stats().lost_packets, 0);
+    }
+
+    #[test]
+    fn duplicates_and_late_packets_are_ignored() {
+        let tracker = tracker([10, 10, 11, 2]);
+        assert!(missing(&tracker).is_empty());
+        assert_eq!(tracker.stats().lost_packets, 0);
+        assert_eq!(tracker.last_sequence, Some(11));
+    }
+
+    #[test]
+    fn counts_a_single_loss_as_recovered() {
+        // column of 4 packets 5 apart from 0xFFFB: 0xFFFB, 0x0000, 0x0005, 0x000A
+        let mut tracker = tracker((0xFFFAu16..=0xFFFF).chain(1..=11));
+        assert_eq!(missing(&tracker), vec![0x0000]);
+        tracker.process(
+            Some(MEDIA_PORT + COLUMN_PORT_OFFSET),
+            &fec(0xFFFB, false, 5, 4),
+        );
+        let stats = tracker.stats();
+        assert_eq!(stats.lost_packets, 1);
+        assert_eq!(stats.recovered_packets, 1);
+        assert_eq!(stats.unrecovered_packets, 0);
+        assert!(missing(&tracker).is_empty());
+    }
+
+    #[test]
+    fn two_losses_in_a_row_are_not_recovered() {
+        let mut tracker = tracker([0, 1, 4, 5]);
+        assert_eq!(missing(&tracker), vec![2, 3]);
+        tracker.process(Some(MEDIA_PORT + ROW_PORT_OFFSET), &fec(0, true, 1, 6));
+        let stats = tracker.stats();
+        assert_eq!(stats.recovered_packets, 0);
+        assert_eq!(stats.unrecovered_packets, 2);
+        // the column through one of them repairs it
+        tracker.process(Some(MEDIA_PORT + COLUMN_PORT_OFFSET), &fec(3, false, 6, 2));
+        let stats = tracker.stats();
+        assert_eq!(stats.recovered_packets, 1);
+        assert_eq!(stats.unrecovered_packets, 1);
+        assert_eq!(missing(&tracker), vec![2]);
+    }
+
+    #[test]
+    fn other_rtp_streams_are_not_counted() {
+        let mut tracker = FecTracker::new(MEDIA_PORT);
+        let payload = tracker.process(Some(MEDIA_PORT), &rtp(96, 1, &[0x00; 8]));
+        assert!(matches!(payload, FecPayload::Media(0)));
+        assert_eq!(tracker.stats().rtp_packets, 0);
+    }
+
+    #[test]
+    fn coverage_of_the_fec_streams() {
+        assert_eq!(coverage_percent(0, 0, 100), 0.0);
+        assert_eq!(coverage_percent(5, 4, 0), 0.0);
+        assert_eq!(coverage_percent(5, 4, 40), 50.0);
+        assert_eq!(coverage_percent(20, 4, 40), 100.0);
+    }
+}
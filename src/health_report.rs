@@ -0,0 +1,237 @@
+/*
+ * health_report.rs
+ * ----------------
+ * Periodic operator health reports. Every interval the system stats, the TR 101 290 counters,
+ * their change since the last report and the analyzer events are filled into a prompt
+ * template and the LLM writes a short report, separate from the continuous commentary.
+*/
+
+use crate::args::Args;
+use crate::event_log::{log_event, recent_events};
+use crate::openai_api::{chat_completion, Message};
+use crate::probe::tr101290_totals;
+use crate::{current_unix_timestamp_ms, get_system_stats, ApiError};
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+
+// Events listed in a report, the newest are kept
+const REPORT_MAX_EVENTS: usize = 50;
+
+pub const DEFAULT_REPORT_TEMPLATE: &str = "\
+Write a concise health report for the broadcast operator covering the last {interval} minutes. \
+Start with an overall status of OK, WARNING or CRITICAL, then list only what changed or needs \
+attention. Use plain sentences without markdown.
+
+System stats: {stats}
+System stats change: {stats_delta}
+TR 101 290 error counts: {tr101290}
+TR 101 290 change: {tr101290_delta}
+Alerts and stream events: {alerts}";
+
+const REPORT_SYSTEM_PROMPT: &str = "You are a broadcast monitoring engineer writing short, factual health reports for the operators. Never invent numbers that are not in the data.";
+
+// Numeric difference of the values in the two JSON documents, fields that didn't change are
+// left out, arrays are compared by index
+pub fn json_delta(before: &Value, after: &Value) -> Option<Value> {
+    match (before, after) {
+        (Value::Number(before), Value::Number(after)) => {
+            let (before, after) = (before.as_f64()?, after.as_f64()?);
+            let delta = after - before;
+            if delta == 0.0 {
+                None
+            } else if delta.fract() == 0.0 {
+                Some(json!(delta as i64))
+            } else {
+                Some(json!((delta * 100.0).round() / 100.0))
+            }
+        }
+        (Value::Object(before), Value::Object(after)) => {
+            let delta: Map<String, Value> = after
+                .iter()
+                .filter_map(|(key, value)| {
+                    json_delta(before.get(key)?, value).map(|delta| (key.clone(), delta))
+                })
+                .collect();
+            if delta.is_empty() {
+                None
+            } else {
+                Some(Value::Object(delta))
+            }
+        }
+        (Value::Array(before), Value::Array(after)) => {
+            let delta: Map<String, Value> = after
+                .iter()
+                .zip(before.iter())
+                .enumerate()
+                .filter_map(|(index, (after, before))| {
+                    json_delta(before, after).map(|delta| (index.to_string(), delta))
+                })
+                .collect();
+            if delta.is_empty() {
+                None
+            } else {
+                Some(Value::Object(delta))
+            }
+        }
+        _ => None,
+    }
+}
+
+// Replace the {name} fields of the template
+pub fn fill_template(template: &str, fields: &[(&str, String)]) -> String {
+    fields
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+pub struct HealthReporter {
+    interval_minutes: u64,
+    template: String,
+    max_tokens: usize,
+    last_stats: Option<Value>,
+    last_tr101290: Option<Value>,
+    last_report_ms: u64,
+}
+
+impl HealthReporter {
+    pub fn new(args: &Args) -> Self {
+        let template = if args.health_report_template.is_empty() {
+            DEFAULT_REPORT_TEMPLATE.to_string()
+        } else {
+            std::fs::read_to_string(&args.health_report_template).unwrap_or_else(|e| {
+                error!(
+                    "Failed to read health report template {}, using the default: {}",
+                    args.health_report_template, e
+                );
+                DEFAULT_REPORT_TEMPLATE.to_string()
+            })
+        };
+        HealthReporter {
+            interval_minutes: args.health_report_interval,
+            template,
+            max_tokens: args.health_report_max_tokens,
+            last_stats: None,
+            last_tr101290: None,
+            last_report_ms: current_unix_timestamp_ms().unwrap_or(0),
+        }
+    }
+
+    // Prompt with the current stats and the change since the last report
+    pub fn build_prompt(&mut self) -> String {
+        let stats = json!(get_system_stats());
+        let tr101290 = tr101290_totals().map(|errors| json!(errors));
+        let now = current_unix_timestamp_ms().unwrap_or(0);
+
+        let delta = |last: &Option<Value>, current: &Value| match last {
+            Some(last) => json_delta(last, current)
+                .map(|delta| delta.to_string())
+                .unwrap_or_else(|| "no change".to_string()),
+            None => "first report".to_string(),
+        };
+        let stats_delta = delta(&self.last_stats, &stats);
+        let (tr101290_text, tr101290_delta) = match tr101290.as_ref() {
+            Some(tr101290) => (tr101290.to_string(), delta(&self.last_tr101290, tr101290)),
+            None => ("no stream analyzed".to_string(), "none".to_string()),
+        };
+
+        let events = recent_events("analyzer", self.last_report_ms);
+        let alerts = if events.is_empty() {
+            "none".to_string()
+        } else {
+            let skip = events.len().saturating_sub(REPORT_MAX_EVENTS);
+            events[skip..]
+                .iter()
+                .map(|event| event.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+
+        let prompt = fill_template(
+            &self.template,
+            &[
+                ("interval", self.interval_minutes.to_string()),
+                ("stats", stats.to_string()),
+                ("stats_delta", stats_delta),
+                ("tr101290", tr101290_text),
+                ("tr101290_delta", tr101290_delta),
+                ("alerts", alerts),
+            ],
+        );
+
+        self.last_stats = Some(stats);
+        self.last_tr101290 = tr101290;
+        self.last_report_ms = now;
+        prompt
+    }
+
+    pub async fn generate(&mut self, args: &Args) -> Result<String, ApiError> {
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: REPORT_SYSTEM_PROMPT.to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: self.build_prompt(),
+            },
+        ];
+        chat_completion(messages, args, self.max_tokens, args.temperature).await
+    }
+}
+
+fn append_report(path: &str, report: &str) {
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| {
+            writeln!(
+                file,
+                "=== {} ===\n{}\n",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                report
+            )
+        });
+    if let Err(e) = result {
+        error!("Failed to write health report to {}: {}", path, e);
+    }
+}
+
+// Generate a report every --health-report-interval minutes and send it to the main loop
+pub fn spawn_health_reports(
+    args: Args,
+    report_tx: mpsc::Sender<String>,
+    running: Arc<AtomicBool>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut reporter = HealthReporter::new(&args);
+        let interval = Duration::from_secs(args.health_report_interval * 60);
+        while running.load(Ordering::SeqCst) {
+            tokio::time::sleep(interval).await;
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+            match reporter.generate(&args).await {
+                Ok(report) => {
+                    info!("Health report:\n{}", report);
+                    log_event("pipeline", "health_report", json!({ "text": report }));
+                    if !args.health_report_file.is_empty() {
+                        append_report(&args.health_report_file, &report);
+                    }
+                    if report_tx.send(report).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => error!("Failed to generate health report: {}", e),
+            }
+        }
+    })
+}
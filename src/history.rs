@@ -0,0 +1,153 @@
+/*
+ * history.rs
+ * ----------
+ * Conversation history as a tree of iteration checkpoints. Each iteration of the main loop
+ * saves the messages as a checkpoint under the one it continued from, rewinding moves back to
+ * an earlier checkpoint and the next iteration starts a new branch from there, the abandoned
+ * branch is kept so it can be checked out again.
+*/
+
+use crate::openai_api::Message;
+use serde_json::{json, Value};
+
+struct Checkpoint {
+    id: usize,
+    parent: Option<usize>,
+    iteration: u64,
+    timestamp_ms: u64,
+    messages: Vec<Message>,
+}
+
+pub struct HistoryTree {
+    checkpoints: Vec<Checkpoint>,
+    head: Option<usize>, // checkpoint the current messages continue from
+    next_id: usize,
+    max_checkpoints: usize,
+}
+
+// Rewind target of a !rewind command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RewindTarget {
+    Steps(usize),      // back along the current branch
+    Checkpoint(usize), // a checkpoint id on any branch
+}
+
+// "!rewind" goes back one iteration, "!rewind 3" three and "!rewind #12" to checkpoint 12
+pub fn parse_rewind_command(command: &str) -> Option<RewindTarget> {
+    let argument = command.strip_prefix("!rewind")?.trim();
+    if argument.is_empty() {
+        Some(RewindTarget::Steps(1))
+    } else if let Some(id) = argument.strip_prefix('#') {
+        id.trim().parse().ok().map(RewindTarget::Checkpoint)
+    } else {
+        argument.parse().ok().map(RewindTarget::Steps)
+    }
+}
+
+impl HistoryTree {
+    pub fn new(max_checkpoints: usize) -> Self {
+        HistoryTree {
+            checkpoints: Vec::new(),
+            head: None,
+            next_id: 0,
+            max_checkpoints: max_checkpoints.max(1),
+        }
+    }
+
+    fn get(&self, id: usize) -> Option<&Checkpoint> {
+        self.checkpoints
+            .iter()
+            .find(|checkpoint| checkpoint.id == id)
+    }
+
+    // Save the messages at the end of an iteration as a child of the head
+    pub fn checkpoint(&mut self, iteration: u64, timestamp_ms: u64, messages: &[Message]) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.checkpoints.push(Checkpoint {
+            id,
+            parent: self.head,
+            iteration,
+            timestamp_ms,
+            messages: messages.to_vec(),
+        });
+        self.head = Some(id);
+        self.prune();
+        id
+    }
+
+    // Drop the oldest checkpoints off the current branch first, then the oldest of the branch
+    fn prune(&mut self) {
+        while self.checkpoints.len() > self.max_checkpoints {
+            let branch = self.branch();
+            let index = self
+                .checkpoints
+                .iter()
+                .position(|checkpoint| !branch.contains(&checkpoint.id))
+                .unwrap_or(0);
+            let removed = self.checkpoints.remove(index);
+            // children of the removed checkpoint hang off its parent
+            for checkpoint in self.checkpoints.iter_mut() {
+                if checkpoint.parent == Some(removed.id) {
+                    checkpoint.parent = removed.parent;
+                }
+            }
+        }
+    }
+
+    // Checkpoint ids from the head back to the root
+    fn branch(&self) -> Vec<usize> {
+        let mut branch = Vec::new();
+        let mut current = self.head;
+        while let Some(id) = current {
+            branch.push(id);
+            current = self.get(id).and_then(|checkpoint| checkpoint.parent);
+        }
+        branch
+    }
+
+    // Move the head to the target and return its messages, the next checkpoint branches off
+    // from there
+    pub fn rewind(&mut self, target: RewindTarget) -> Option<Vec<Message>> {
+        let id = match target {
+            RewindTarget::Steps(steps) => *self.branch().get(steps)?,
+            RewindTarget::Checkpoint(id) => self.get(id)?.id,
+        };
+        self.head = Some(id);
+        self.get(id).map(|checkpoint| checkpoint.messages.clone())
+    }
+
+    pub fn head(&self) -> Option<usize> {
+        self.head
+    }
+
+    // The checkpoints with their parents and the current branch for the control api
+    pub fn summary(&self) -> Value {
+        let branch = self.branch();
+        let checkpoints: Vec<Value> = self
+            .checkpoints
+            .iter()
+            .map(|checkpoint| {
+                let last = checkpoint
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|message| message.role == "assistant")
+                    .map(|message| message.content.chars().take(80).collect::<String>());
+                json!({
+                    "id": checkpoint.id,
+                    "parent": checkpoint.parent,
+                    "iteration": checkpoint.iteration,
+                    "timestamp_ms": checkpoint.timestamp_ms,
+                    "messages": checkpoint.messages.len(),
+                    "on_branch": branch.contains(&checkpoint.id),
+                    "last_answer": last,
+                })
+            })
+            .collect();
+        json!({
+            "head": self.head,
+            "checkpoints": checkpoints,
+        })
+    }
+}
@@ -0,0 +1,195 @@
+/*
+ * image_generator.rs
+ * ------------------
+ * Image generation backends behind one trait, the backend is selected by name with
+ * --image-backend so process_image doesn't need to know about each of them.
+*/
+
+use crate::args::Args;
+use crate::sd_automatic::{parse_loras, sd_auto, AutomaticOptions};
+use crate::sd_comfyui::sd_comfyui;
+use crate::sd_openai::{sd_openai, OpenAIImageOptions};
+use crate::stable_diffusion::{sd, SDConfig};
+use futures::future::BoxFuture;
+use image::{ImageBuffer, Rgb};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type GeneratedImages = anyhow::Result<Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>>;
+
+pub trait ImageGenerator: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn generate(&self, config: SDConfig) -> BoxFuture<'_, GeneratedImages>;
+}
+
+// Stable diffusion running locally with candle
+pub struct CandleGenerator;
+
+impl ImageGenerator for CandleGenerator {
+    fn name(&self) -> &str {
+        "candle"
+    }
+
+    fn generate(&self, config: SDConfig) -> BoxFuture<'_, GeneratedImages> {
+        Box::pin(sd(config))
+    }
+}
+
+// Automatic1111 stable diffusion web ui api
+pub struct AutomaticGenerator {
+    host: String,
+    options: AutomaticOptions,
+}
+
+impl AutomaticGenerator {
+    pub fn new(host: &str, options: AutomaticOptions) -> Self {
+        AutomaticGenerator {
+            host: host.to_string(),
+            options,
+        }
+    }
+}
+
+impl ImageGenerator for AutomaticGenerator {
+    fn name(&self) -> &str {
+        "automatic1111"
+    }
+
+    fn generate(&self, config: SDConfig) -> BoxFuture<'_, GeneratedImages> {
+        Box::pin(sd_auto(config, &self.host, &self.options))
+    }
+}
+
+// ComfyUI api with the default or a custom workflow
+pub struct ComfyUIGenerator {
+    host: String,
+    workflow_file: String,
+}
+
+impl ComfyUIGenerator {
+    pub fn new(host: &str, workflow_file: &str) -> Self {
+        ComfyUIGenerator {
+            host: host.to_string(),
+            workflow_file: workflow_file.to_string(),
+        }
+    }
+}
+
+impl ImageGenerator for ComfyUIGenerator {
+    fn name(&self) -> &str {
+        "comfyui"
+    }
+
+    fn generate(&self, config: SDConfig) -> BoxFuture<'_, GeneratedImages> {
+        Box::pin(sd_comfyui(config, &self.host, &self.workflow_file))
+    }
+}
+
+// OpenAI image api, for running without a local GPU
+pub struct OpenAIGenerator {
+    options: OpenAIImageOptions,
+}
+
+impl OpenAIGenerator {
+    pub fn new(options: OpenAIImageOptions) -> Self {
+        OpenAIGenerator { options }
+    }
+}
+
+impl ImageGenerator for OpenAIGenerator {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn generate(&self, config: SDConfig) -> BoxFuture<'_, GeneratedImages> {
+        Box::pin(sd_openai(config, &self.options))
+    }
+}
+
+// Image generators by name
+pub struct ImageGeneratorRegistry {
+    generators: HashMap<String, Arc<dyn ImageGenerator>>,
+}
+
+impl ImageGeneratorRegistry {
+    pub fn new() -> Self {
+        ImageGeneratorRegistry {
+            generators: HashMap::new(),
+        }
+    }
+
+    // Registry with the built in backends configured from the arguments
+    pub fn from_args(args: &Args) -> Self {
+        let mut registry = ImageGeneratorRegistry::new();
+        registry.register(Arc::new(CandleGenerator));
+        registry.register(Arc::new(AutomaticGenerator::new(
+            &args.sd_api_host,
+            AutomaticOptions {
+                sampler: args.sd_automatic_sampler.clone(),
+                steps: args.sd_automatic_steps,
+                cfg_scale: args.sd_automatic_cfg,
+                model: args.sd_automatic_model.clone(),
+                loras: parse_loras(&args.sd_automatic_loras),
+                retries: args.sd_automatic_retries,
+                timeout: Duration::from_secs(args.sd_automatic_timeout),
+            },
+        )));
+        registry.register(Arc::new(ComfyUIGenerator::new(
+            &args.comfyui_host,
+            &args.comfyui_workflow,
+        )));
+        registry.register(Arc::new(OpenAIGenerator::new(OpenAIImageOptions {
+            model: args.openai_image_model.clone(),
+            size: args.openai_image_size.clone(),
+            quality: args.openai_image_quality.clone(),
+        })));
+        registry
+    }
+
+    pub fn register(&mut self, generator: Arc<dyn ImageGenerator>) {
+        self.generators
+            .insert(generator.name().to_string(), generator);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ImageGenerator>> {
+        self.generators.get(&name.to_lowercase()).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.generators.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+impl Default for ImageGeneratorRegistry {
+    fn default() -> Self {
+        ImageGeneratorRegistry::new()
+    }
+}
+
+// Name of the selected backend, --sd-api selects automatic1111 when no backend is given
+pub fn image_backend_name(args: &Args) -> String {
+    if !args.image_backend.is_empty() {
+        args.image_backend.to_lowercase()
+    } else if args.sd_api {
+        "automatic1111".to_string()
+    } else {
+        "candle".to_string()
+    }
+}
+
+// The image generator selected by the arguments
+pub fn image_generator(args: &Args) -> anyhow::Result<Arc<dyn ImageGenerator>> {
+    let registry = ImageGeneratorRegistry::from_args(args);
+    let name = image_backend_name(args);
+    registry.get(&name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Unknown image backend {}, available backends: {}",
+            name,
+            registry.names().join(", ")
+        )
+    })
+}
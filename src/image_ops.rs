@@ -0,0 +1,134 @@
+/*
+ * image_ops.rs
+ * ------------
+ * Image resize and color conversion for the output frames. The rows are processed in parallel
+ * with rayon and the inner loops work on flat slices the compiler can vectorize, a 1080p frame
+ * has to be scaled and converted to RGBA well within a frame time for NDI.
+*/
+
+use image::{ImageBuffer, Rgb, RgbImage, RgbaImage};
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+fn lanczos3(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else if x.abs() < 3.0 {
+        let px = PI * x;
+        3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+    } else {
+        0.0
+    }
+}
+
+// First source index and normalized filter weights of each destination index, the filter is
+// widened when downscaling so every source pixel contributes
+fn filter_weights(src_size: u32, dst_size: u32) -> Vec<(usize, Vec<f32>)> {
+    let ratio = src_size as f32 / dst_size as f32;
+    let scale = ratio.max(1.0);
+    let support = 3.0 * scale;
+    (0..dst_size)
+        .map(|index| {
+            let center = (index as f32 + 0.5) * ratio;
+            let start = (center - support).floor().max(0.0) as usize;
+            let end = ((center + support).ceil() as usize).min(src_size as usize);
+            let mut weights: Vec<f32> = (start..end)
+                .map(|src| lanczos3((src as f32 + 0.5 - center) / scale))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            if sum != 0.0 {
+                weights.iter_mut().for_each(|weight| *weight /= sum);
+            }
+            (start, weights)
+        })
+        .collect()
+}
+
+// Lanczos3 resize, horizontal then vertical pass
+pub fn resize_rgb(image: &RgbImage, width: u32, height: u32) -> RgbImage {
+    let (src_width, src_height) = image.dimensions();
+    if (src_width, src_height) == (width, height) {
+        return image.clone();
+    }
+    if width == 0 || height == 0 || src_width == 0 || src_height == 0 {
+        return ImageBuffer::new(width, height);
+    }
+
+    let src_stride = src_width as usize * 3;
+    let dst_stride = width as usize * 3;
+
+    let columns = filter_weights(src_width, width);
+    let mut horizontal = vec![0f32; dst_stride * src_height as usize];
+    horizontal
+        .par_chunks_mut(dst_stride)
+        .zip(image.as_raw().par_chunks(src_stride))
+        .for_each(|(dst, src)| {
+            for (pixel, (start, weights)) in dst.chunks_exact_mut(3).zip(columns.iter()) {
+                let mut sum = [0f32; 3];
+                for (offset, weight) in weights.iter().enumerate() {
+                    let index = (start + offset) * 3;
+                    sum[0] += src[index] as f32 * weight;
+                    sum[1] += src[index + 1] as f32 * weight;
+                    sum[2] += src[index + 2] as f32 * weight;
+                }
+                pixel.copy_from_slice(&sum);
+            }
+        });
+
+    let rows = filter_weights(src_height, height);
+    let mut resized = vec![0u8; dst_stride * height as usize];
+    resized
+        .par_chunks_mut(dst_stride)
+        .zip(rows.par_iter())
+        .for_each(|(dst, (start, weights))| {
+            let mut sum = vec![0f32; dst_stride];
+            for (offset, weight) in weights.iter().enumerate() {
+                let row_start = (start + offset) * dst_stride;
+                let row = &horizontal[row_start..row_start + dst_stride];
+                for (acc, value) in sum.iter_mut().zip(row.iter()) {
+                    *acc += value * weight;
+                }
+            }
+            for (out, value) in dst.iter_mut().zip(sum.iter()) {
+                *out = value.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+
+    ImageBuffer::from_raw(width, height, resized).expect("resized buffer size")
+}
+
+// Copy the image onto the frame at the offset, the part outside the frame is cut off
+pub fn paste_rgb(frame: &mut RgbImage, image: &RgbImage, x_offset: u32, y_offset: u32) {
+    let (frame_width, frame_height) = frame.dimensions();
+    let width = image.width();
+    if x_offset >= frame_width || y_offset >= frame_height || width == 0 {
+        return;
+    }
+    let copy_width = width.min(frame_width - x_offset) as usize * 3;
+    let frame_stride = frame_width as usize * 3;
+    let image_stride = width as usize * 3;
+    let x_start = x_offset as usize * 3;
+
+    let frame_rows = frame.par_chunks_mut(frame_stride).skip(y_offset as usize);
+    frame_rows
+        .zip(image.as_raw().par_chunks(image_stride))
+        .for_each(|(dst, src)| {
+            dst[x_start..x_start + copy_width].copy_from_slice(&src[..copy_width]);
+        });
+}
+
+// Opaque RGBA copy of the image for the overlay and the NDI frames
+pub fn rgb_to_rgba(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let mut rgba = vec![255u8; width as usize * height as usize * 4];
+    if width > 0 {
+        rgba.par_chunks_mut(width as usize * 4)
+            .zip(image.as_raw().par_chunks(width as usize * 3))
+            .for_each(|(dst, src)| {
+                for (dst, src) in dst.chunks_exact_mut(4).zip(src.chunks_exact(3)) {
+                    dst[..3].copy_from_slice(src);
+                }
+            });
+    }
+    ImageBuffer::from_raw(width, height, rgba).expect("rgba buffer size")
+}
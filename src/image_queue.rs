@@ -0,0 +1,240 @@
+/*
+ * image_queue.rs
+ * --------------
+ * Stable diffusion job queue in front of process_image. Jobs for the same prompt share one
+ * generation and recent results are reused, so the greeting repeated every iteration is only
+ * rendered once. The GPU jobs have their own concurrency limit apart from the pipeline
+ * semaphore and the time each job waited in the queue is reported. A prefetched job is started
+ * before its message reaches the pipeline and handed over when it does.
+*/
+
+use crate::event_log::log_event;
+use crate::pipeline::{process_image, MessageData};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use image::{ImageBuffer, Rgb};
+use log::{debug, info};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Semaphore;
+
+type Images = Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>;
+type ImageJob = Shared<BoxFuture<'static, Images>>;
+
+// Prefetched jobs waiting for their message, the oldest is dropped past this
+const PREFETCH_MAX: usize = 4;
+
+#[derive(Debug, Default, Clone)]
+struct QueueStats {
+    submitted: u64,
+    deduped: u64,
+    prefetched: u64,
+    generated: u64,
+    total_wait_ms: u64,
+    max_wait_ms: u64,
+    last_wait_ms: u64,
+}
+
+pub struct ImageQueue {
+    gpu_sem: Arc<Semaphore>,
+    cache_size: usize,
+    in_flight: Mutex<HashMap<String, ImageJob>>,
+    cache: Mutex<VecDeque<(String, Images)>>,
+    prefetched: Mutex<VecDeque<(String, ImageJob)>>,
+    stats: Arc<Mutex<QueueStats>>,
+}
+
+// Prompt and image settings that make two jobs produce the same image, the seed is left out
+fn job_key(data: &MessageData) -> String {
+    let config = &data.sd_config;
+    format!(
+        "{}|{}|{}|{:?}x{:?}|{:?}x{:?}|{}",
+        data.args.image_backend,
+        config.prompt,
+        config.uncond_prompt,
+        config.width,
+        config.height,
+        config.scaled_width,
+        config.scaled_height,
+        data.args.sd_model
+    )
+}
+
+impl ImageQueue {
+    pub fn new(concurrency: usize, cache_size: usize) -> Self {
+        ImageQueue {
+            gpu_sem: Arc::new(Semaphore::new(concurrency.max(1))),
+            cache_size,
+            in_flight: Mutex::new(HashMap::new()),
+            cache: Mutex::new(VecDeque::new()),
+            prefetched: Mutex::new(VecDeque::new()),
+            stats: Arc::new(Mutex::new(QueueStats::default())),
+        }
+    }
+
+    // Images for the message, from the cache, a running job for the same prompt or a new job
+    pub async fn generate(&self, data: MessageData) -> Images {
+        if !data.args.sd_image {
+            return process_image(data).await;
+        }
+        let key = job_key(&data);
+        self.stats.lock().unwrap().submitted += 1;
+
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(cached_key, _)| *cached_key == key)
+            .map(|(_, images)| images.clone());
+        if let Some(images) = cached {
+            self.stats.lock().unwrap().deduped += 1;
+            debug!(
+                "Image queue: reusing images for paragraph {}",
+                data.paragraph_count
+            );
+            return images;
+        }
+
+        let prefetched = self.take_prefetched(&key);
+        let job = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(job) => {
+                    self.stats.lock().unwrap().deduped += 1;
+                    debug!(
+                        "Image queue: paragraph {} joins the running job for the same prompt",
+                        data.paragraph_count
+                    );
+                    job.clone()
+                }
+                None => {
+                    let job = match prefetched {
+                        Some(job) => {
+                            self.stats.lock().unwrap().prefetched += 1;
+                            debug!(
+                                "Image queue: paragraph {} takes over the prefetched job",
+                                data.paragraph_count
+                            );
+                            job
+                        }
+                        None => self.spawn_job(data),
+                    };
+                    in_flight.insert(key.clone(), job.clone());
+                    job
+                }
+            }
+        };
+
+        let images = job.await;
+        self.in_flight.lock().unwrap().remove(&key);
+        if self.cache_size > 0 && !images.is_empty() {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|(cached_key, _)| *cached_key != key);
+            if cache.len() >= self.cache_size {
+                cache.pop_front();
+            }
+            cache.push_back((key, images.clone()));
+        }
+        images
+    }
+
+    // Start the job of a message ahead of the pipeline, the pipeline takes it over when the
+    // message arrives. Prompts already cached or running are left alone.
+    pub fn prefetch(&self, data: MessageData) {
+        if !data.args.sd_image {
+            return;
+        }
+        let key = job_key(&data);
+        let known = self.cache.lock().unwrap().iter().any(|(k, _)| *k == key)
+            || self.in_flight.lock().unwrap().contains_key(&key)
+            || self
+                .prefetched
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(k, _)| *k == key);
+        if known {
+            return;
+        }
+        debug!("Image queue: prefetching {}", data.sd_config.prompt);
+        let job = self.spawn_job(data);
+        // a shared job only runs while polled, the task drives it until it is taken over
+        tokio::spawn(job.clone());
+        let mut prefetched = self.prefetched.lock().unwrap();
+        if prefetched.len() >= PREFETCH_MAX {
+            prefetched.pop_front();
+        }
+        prefetched.push_back((key, job));
+    }
+
+    fn take_prefetched(&self, key: &str) -> Option<ImageJob> {
+        let mut prefetched = self.prefetched.lock().unwrap();
+        let index = prefetched.iter().position(|(k, _)| k == key)?;
+        prefetched.remove(index).map(|(_, job)| job)
+    }
+
+    fn spawn_job(&self, data: MessageData) -> ImageJob {
+        let gpu_sem = Arc::clone(&self.gpu_sem);
+        let stats = Arc::clone(&self.stats);
+        let queued = Instant::now();
+        async move {
+            let _permit = gpu_sem
+                .acquire()
+                .await
+                .expect("failed to acquire image queue permit");
+            let wait_ms = queued.elapsed().as_millis() as u64;
+            let started = Instant::now();
+            let paragraph_count = data.paragraph_count;
+            let images = process_image(data).await;
+            let run_ms = started.elapsed().as_millis() as u64;
+
+            let summary = {
+                let mut stats = stats.lock().unwrap();
+                stats.generated += 1;
+                stats.total_wait_ms += wait_ms;
+                stats.max_wait_ms = stats.max_wait_ms.max(wait_ms);
+                stats.last_wait_ms = wait_ms;
+                summary(&stats)
+            };
+            info!(
+                "Image queue: paragraph {} waited {} ms, generated {} images in {} ms",
+                paragraph_count,
+                wait_ms,
+                images.len(),
+                run_ms
+            );
+            log_event(
+                "pipeline",
+                "image_queue",
+                json!({
+                    "paragraph_count": paragraph_count,
+                    "wait_ms": wait_ms,
+                    "run_ms": run_ms,
+                    "images": images.len(),
+                    "queue": summary,
+                }),
+            );
+            images
+        }
+        .boxed()
+        .shared()
+    }
+
+    pub fn stats(&self) -> Value {
+        summary(&self.stats.lock().unwrap())
+    }
+}
+
+fn summary(stats: &QueueStats) -> Value {
+    json!({
+        "submitted": stats.submitted,
+        "deduped": stats.deduped,
+        "prefetched": stats.prefetched,
+        "generated": stats.generated,
+        "avg_wait_ms": stats.total_wait_ms.checked_div(stats.generated).unwrap_or(0),
+        "max_wait_ms": stats.max_wait_ms,
+        "last_wait_ms": stats.last_wait_ms,
+    })
+}
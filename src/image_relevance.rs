@@ -0,0 +1,126 @@
+/*
+ * image_relevance.rs
+ * ------------------
+ * CLIP score of how well a generated image matches the paragraph it illustrates, the cosine
+ * similarity of the image and text embeddings. Images that score under the threshold are
+ * generated again with the prompt rewritten to lead with the paragraph's keywords, every
+ * score is logged for tracking the image quality.
+*/
+
+use crate::args::Args;
+use crate::keywords::{extract as extract_keywords, EntityKind};
+use crate::safety_checker::{clip_pixels, load_clip};
+use crate::stable_diffusion::CLIP_MAX_TOKENS;
+use anyhow::{anyhow, Error as E, Result};
+use candle_core::{Device, Tensor};
+use candle_transformers::models::clip;
+use image::{ImageBuffer, Rgb};
+use log::info;
+use once_cell::sync::OnceCell;
+use std::sync::Mutex;
+use tokenizers::Tokenizer;
+
+// Keywords put in front of a rewritten prompt
+const REWRITE_KEYWORDS: usize = 6;
+
+pub struct RelevanceScorer {
+    model: clip::ClipModel,
+    tokenizer: Tokenizer,
+    eos_id: u32,
+    device: Device,
+    image_size: usize,
+}
+
+// Loaded on first use and shared between the pipeline tasks
+static RELEVANCE_SCORER: OnceCell<Mutex<RelevanceScorer>> = OnceCell::new();
+
+impl RelevanceScorer {
+    pub fn new(cpu: bool) -> Result<Self> {
+        let device = candle_examples::device(cpu)?;
+        let (model, tokenizer, image_size) = load_clip(&device)?;
+        let eos_id = *tokenizer
+            .get_vocab(true)
+            .get("<|endoftext|>")
+            .ok_or_else(|| anyhow!("No end of text token in the CLIP tokenizer"))?;
+        info!("Image relevance scorer loaded the CLIP model");
+
+        Ok(RelevanceScorer {
+            model,
+            tokenizer,
+            eos_id,
+            device,
+            image_size,
+        })
+    }
+
+    // Text embedding, a long paragraph is cut to the tokens CLIP takes and keeps its end token
+    fn text_features(&self, text: &str) -> Result<Tensor> {
+        let encoding = self.tokenizer.encode(text, true).map_err(E::msg)?;
+        let mut ids = encoding.get_ids().to_vec();
+        if ids.len() > CLIP_MAX_TOKENS {
+            ids.truncate(CLIP_MAX_TOKENS - 1);
+            ids.push(self.eos_id);
+        }
+        let input_ids = Tensor::new(vec![ids], &self.device)?;
+        Ok(clip::div_l2_norm(
+            &self.model.get_text_features(&input_ids)?,
+        )?)
+    }
+
+    // Cosine similarity of each image with the text, usually 0.15 for unrelated to 0.35 for a
+    // close match
+    pub fn score(&self, images: &[ImageBuffer<Rgb<u8>, Vec<u8>>], text: &str) -> Result<Vec<f32>> {
+        let text_features = self.text_features(text)?;
+        let mut scores = Vec::with_capacity(images.len());
+        for image in images {
+            let pixels = clip_pixels(image, self.image_size, &self.device)?;
+            let image_features = clip::div_l2_norm(&self.model.get_image_features(&pixels)?)?;
+            let score = (image_features * &text_features)?
+                .sum_all()?
+                .to_scalar::<f32>()?;
+            scores.push(score);
+        }
+        Ok(scores)
+    }
+}
+
+// Score the images with the shared scorer, loading the model on first use
+pub fn score_images(
+    images: &[ImageBuffer<Rgb<u8>, Vec<u8>>],
+    text: &str,
+    cpu: bool,
+) -> Result<Vec<f32>> {
+    let scorer = RELEVANCE_SCORER.get_or_try_init(|| RelevanceScorer::new(cpu).map(Mutex::new))?;
+    let scorer = scorer
+        .lock()
+        .map_err(|e| anyhow!("Image relevance scorer lock poisoned: {}", e))?;
+    scorer.score(images, text)
+}
+
+// Prompt for another try at a low scoring image, led by the names and keywords of the
+// paragraph that the prompt does not have yet
+pub fn rewrite_prompt(prompt: &str, paragraph: &str, args: &Args) -> String {
+    let keywords = extract_keywords(paragraph, args);
+    let lower_prompt = prompt.to_lowercase();
+    let mut lead: Vec<String> = Vec::new();
+    let candidates = keywords
+        .entities
+        .iter()
+        .filter(|entity| entity.kind != EntityKind::Program)
+        .map(|entity| entity.text.clone())
+        .chain(keywords.keywords.iter().cloned());
+    for candidate in candidates {
+        let lower = candidate.to_lowercase();
+        if !lower_prompt.contains(&lower) && !lead.iter().any(|word| word.to_lowercase() == lower) {
+            lead.push(candidate);
+        }
+        if lead.len() >= REWRITE_KEYWORDS {
+            break;
+        }
+    }
+    if lead.is_empty() {
+        prompt.to_string()
+    } else {
+        format!("{}, {}", lead.join(", "), prompt)
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * kafka_sink.rs
+ * -------------
+ * Kafka producer for keeping the telemetry of the stream for months. Every interval a record
+ * of each PID goes to <topic>.pids and of each program to <topic>.programs, and every event
+ * of the event log to <topic>.events, all JSON keyed by the site. Facilities run their own
+ * analytics on the topics next to the summaries of the LLM. librdkafka buffers the records
+ * and retries while the brokers are away, they are flushed when the sink is dropped.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::event_log::subscribe_events;
+use crate::external_apis::check_url;
+use crate::probe::tr101290_totals;
+use crate::probe_link::site_name;
+use crate::stream_data::{pid_map_snapshot, program_summaries};
+use log::{error, info, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::message::DeliveryResult;
+use rdkafka::producer::{BaseRecord, Producer, ProducerContext, ThreadedProducer};
+use rdkafka::types::RDKafkaErrorCode;
+use rdkafka::ClientContext;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Events waiting for the producer before new ones are dropped
+const EVENT_QUEUE_SIZE: usize = 1024;
+// Time given to the records still queued when the sink is dropped
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Logs failed deliveries once until a record makes it again
+#[derive(Default)]
+struct DeliveryContext {
+    failing: AtomicBool,
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+        match result {
+            Ok(_) => self.failing.store(false, Ordering::Relaxed),
+            Err((e, _)) => {
+                if !self.failing.swap(true, Ordering::Relaxed) {
+                    warn!("Kafka failed to deliver a record: {}", e);
+                }
+            }
+        }
+    }
+}
+
+type KafkaProducer = ThreadedProducer<DeliveryContext>;
+
+// Queue a record, a full queue drops it
+fn produce(producer: &KafkaProducer, topic: &str, key: &str, payload: &Value) {
+    let payload = payload.to_string();
+    let record = BaseRecord::to(topic).key(key).payload(&payload);
+    match producer.send(record) {
+        Ok(()) => {}
+        Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) => {
+            warn!("Kafka queue is full, dropped a record for {}", topic)
+        }
+        Err((e, _)) => error!("Kafka failed to queue a record for {}: {}", topic, e),
+    }
+}
+
+// The PIDs and programs of the stream, one record each
+fn produce_interval(producer: &KafkaProducer, topic: &str, site: &str) {
+    let timestamp = current_unix_timestamp_ms().unwrap_or(0);
+    let pids_topic = format!("{}.pids", topic);
+    for pid in pid_map_snapshot() {
+        let record = json!({ "site": site, "timestamp": timestamp, "pid": pid });
+        produce(producer, &pids_topic, site, &record);
+    }
+    let programs_topic = format!("{}.programs", topic);
+    let tr101290 = tr101290_totals();
+    for program in program_summaries() {
+        let record = json!({
+            "site": site,
+            "timestamp": timestamp,
+            "program": program,
+            "tr101290": tr101290,
+        });
+        produce(producer, &programs_topic, site, &record);
+    }
+}
+
+// Every bootstrap server has to be on this host in local only mode, host:port with an optional
+// PLAINTEXT:// or SSL:// listener name
+fn check_brokers(brokers: &str) -> Result<(), String> {
+    brokers
+        .split(',')
+        .map(|broker| broker.trim())
+        .filter(|broker| !broker.is_empty())
+        .try_for_each(|broker| {
+            let address = broker
+                .split_once("://")
+                .map_or(broker, |(_, address)| address);
+            check_url(&format!("tcp://{}", address))
+        })
+}
+
+pub struct KafkaSink {
+    producer: Arc<KafkaProducer>,
+}
+
+impl KafkaSink {
+    // Produce to --kafka-brokers, None when it is not set or the producer fails
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.kafka_brokers.is_empty() {
+            return None;
+        }
+        if let Err(e) = check_brokers(&args.kafka_brokers) {
+            error!("{}, not producing to Kafka", e);
+            return None;
+        }
+        let producer: KafkaProducer = match ClientConfig::new()
+            .set("bootstrap.servers", &args.kafka_brokers)
+            .set("client.id", "rsllm")
+            .set("message.timeout.ms", "300000")
+            .create_with_context(DeliveryContext::default())
+        {
+            Ok(producer) => producer,
+            Err(e) => {
+                error!("Failed to create the Kafka producer: {}", e);
+                return None;
+            }
+        };
+        let producer = Arc::new(producer);
+        let topic = args.kafka_topic.clone();
+        let site = site_name(args);
+        info!(
+            "Producing the stats and events of {} to {} under {}",
+            site, args.kafka_brokers, topic
+        );
+
+        let mut events = subscribe_events(EVENT_QUEUE_SIZE);
+        let events_producer = Arc::clone(&producer);
+        let events_topic = format!("{}.events", topic);
+        let events_site = site.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let record = json!({ "site": events_site, "event": event });
+                produce(&events_producer, &events_topic, &events_site, &record);
+            }
+        });
+
+        if args.kafka_interval_ms > 0 {
+            let interval_producer = Arc::clone(&producer);
+            let interval = Duration::from_millis(args.kafka_interval_ms);
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    produce_interval(&interval_producer, &topic, &site);
+                }
+            });
+        }
+
+        Some(KafkaSink { producer })
+    }
+}
+
+impl Drop for KafkaSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.producer.flush(FLUSH_TIMEOUT) {
+            warn!("Kafka records left unsent: {}", e);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+/*
+ * keywords.rs
+ * -----------
+ * Lightweight keyword and named entity extraction for paragraphs. Capitalized word runs are
+ * taken as entities and typed from the words around them, the configured program names are
+ * matched as programs. The entities lead the image prompt so they survive its truncation and
+ * the paragraph events are tagged with the programs and places mentioned.
+*/
+
+use crate::args::Args;
+use crate::chapters::STOP_WORDS;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntityKind {
+    Person,
+    Place,
+    Organization,
+    Program,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entity {
+    pub text: String,
+    pub kind: EntityKind,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Keywords {
+    pub entities: Vec<Entity>,
+    pub keywords: Vec<String>,
+}
+
+// words before a name that tell what it is
+const PERSON_TITLES: [&str; 12] = [
+    "mr",
+    "mrs",
+    "ms",
+    "dr",
+    "sir",
+    "lady",
+    "president",
+    "king",
+    "queen",
+    "prince",
+    "captain",
+    "professor",
+];
+const PLACE_PREPOSITIONS: [&str; 8] = [
+    "in", "at", "from", "near", "to", "across", "around", "visit",
+];
+const ORGANIZATION_SUFFIXES: [&str; 10] = [
+    "inc",
+    "corp",
+    "ltd",
+    "company",
+    "university",
+    "agency",
+    "news",
+    "network",
+    "tv",
+    "group",
+];
+const PLACE_SUFFIXES: [&str; 10] = [
+    "city",
+    "river",
+    "mountain",
+    "mountains",
+    "lake",
+    "island",
+    "street",
+    "valley",
+    "bay",
+    "park",
+];
+
+// capitalized words that start sentences or are too common to be names
+const COMMON_CAPITALIZED: [&str; 16] = [
+    "i", "a", "an", "it", "we", "he", "in", "on", "at", "as", "so", "if", "my", "no", "yes", "oh",
+];
+
+impl Keywords {
+    pub fn new() -> Self {
+        Keywords::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty() && self.keywords.is_empty()
+    }
+
+    // Entities of the kind, for tagging events
+    pub fn names(&self, kind: EntityKind) -> Vec<String> {
+        self.entities
+            .iter()
+            .filter(|entity| entity.kind == kind)
+            .map(|entity| entity.text.clone())
+            .collect()
+    }
+}
+
+fn is_name_word(word: &str) -> bool {
+    let lower = word.to_lowercase();
+    word.chars().next().is_some_and(|c| c.is_uppercase())
+        && !COMMON_CAPITALIZED.contains(&lower.as_str())
+        && !STOP_WORDS.contains(&lower.as_str())
+}
+
+fn entity_kind(previous: Option<&str>, words: &[&str]) -> EntityKind {
+    let last = words
+        .last()
+        .map(|word| word.to_lowercase())
+        .unwrap_or_default();
+    let previous = previous.map(|word| word.to_lowercase());
+    if ORGANIZATION_SUFFIXES.contains(&last.as_str()) {
+        EntityKind::Organization
+    } else if PLACE_SUFFIXES.contains(&last.as_str()) {
+        EntityKind::Place
+    } else if let Some(previous) = previous.as_deref() {
+        if PERSON_TITLES.contains(&previous) {
+            EntityKind::Person
+        } else if PLACE_PREPOSITIONS.contains(&previous) {
+            EntityKind::Place
+        } else {
+            EntityKind::Other
+        }
+    } else {
+        EntityKind::Other
+    }
+}
+
+// Runs of capitalized words, a single word at the start of a sentence is skipped since it
+// is capitalized anyway
+pub fn extract_entities(text: &str, programs: &[String]) -> Vec<Entity> {
+    let mut entities: Vec<Entity> = Vec::new();
+    let mut push = |entity: Entity| {
+        if !entities
+            .iter()
+            .any(|e| e.text.eq_ignore_ascii_case(&entity.text))
+        {
+            entities.push(entity);
+        }
+    };
+
+    // the configured programs first so they are not typed from their context
+    let lower = text.to_lowercase();
+    for program in programs {
+        if !program.is_empty() && lower.contains(&program.to_lowercase()) {
+            push(Entity {
+                text: program.clone(),
+                kind: EntityKind::Program,
+            });
+        }
+    }
+
+    for sentence in text.split(['.', '!', '?', '\n']) {
+        let words: Vec<&str> = sentence
+            .split_whitespace()
+            .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+            .filter(|word| !word.is_empty())
+            .collect();
+        let mut index = 0;
+        while index < words.len() {
+            let start = index;
+            while index < words.len() && is_name_word(words[index]) {
+                index += 1;
+            }
+            if index == start {
+                index += 1;
+                continue;
+            }
+            let run = &words[start..index];
+            // a title before the name is part of the run, it is split off here
+            let (previous, run) = match run.split_first() {
+                Some((first, rest))
+                    if !rest.is_empty()
+                        && PERSON_TITLES.contains(&first.to_lowercase().as_str()) =>
+                {
+                    (Some(*first), rest)
+                }
+                _ => (start.checked_sub(1).map(|i| words[i]), run),
+            };
+            if start == 0 && run.len() == 1 && previous.is_none() {
+                continue;
+            }
+            let text = run.join(" ");
+            if programs
+                .iter()
+                .any(|program| program.eq_ignore_ascii_case(&text))
+            {
+                continue;
+            }
+            push(Entity {
+                kind: entity_kind(previous, run),
+                text,
+            });
+        }
+    }
+    entities
+}
+
+// Most frequent topic words, ties keep the order they first appear in
+pub fn extract_keywords(text: &str, max: usize) -> Vec<String> {
+    let mut counts: HashMap<String, (usize, usize)> = HashMap::new();
+    for (position, word) in text
+        .split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_lowercase())
+        .filter(|word| word.chars().count() > 3 && !STOP_WORDS.contains(&word.as_str()))
+        .enumerate()
+    {
+        counts.entry(word).or_insert((0, position)).0 += 1;
+    }
+    let mut words: Vec<(String, (usize, usize))> = counts.into_iter().collect();
+    words.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.1 .1.cmp(&b.1 .1)));
+    words.into_iter().take(max).map(|(word, _)| word).collect()
+}
+
+// Program names from --keywords-programs, separated by commas
+pub fn parse_programs(programs: &str) -> Vec<String> {
+    programs
+        .split(',')
+        .map(|program| program.trim().to_string())
+        .filter(|program| !program.is_empty())
+        .collect()
+}
+
+pub fn extract(text: &str, args: &Args) -> Keywords {
+    let programs = parse_programs(&args.keywords_programs);
+    let mut entities = extract_entities(text, &programs);
+    entities.truncate(args.keywords_max);
+    Keywords {
+        entities,
+        keywords: extract_keywords(text, args.keywords_max),
+    }
+}
+
+// Image prompt led by the entities, the start of the prompt has the most weight and is kept
+// when the prompt is truncated
+pub fn emphasize_prompt(prompt: &str, keywords: &Keywords) -> String {
+    let emphasis: Vec<String> = keywords
+        .entities
+        .iter()
+        .filter(|entity| entity.kind != EntityKind::Program)
+        .map(|entity| entity.text.clone())
+        .filter(|text| !prompt.starts_with(text.as_str()))
+        .collect();
+    if emphasis.is_empty() {
+        prompt.to_string()
+    } else {
+        format!("{}, {}", emphasis.join(", "), prompt)
+    }
+}
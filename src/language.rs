@@ -0,0 +1,108 @@
+/*
+ * language.rs
+ * -----------
+ * Language detection of paragraphs to route the TTS to a voice that speaks the language,
+ * instead of feeding non-English text to an English only voice.
+*/
+
+use std::collections::HashMap;
+use whatlang::{detect, Lang};
+
+// TTS backend and voice for a language
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoiceRoute {
+    Mimic3(String),
+    OpenAI(String),
+}
+
+// ISO 639-1 code for the common languages, the ISO 639-3 code otherwise
+pub fn language_code(lang: Lang) -> &'static str {
+    match lang {
+        Lang::Eng => "en",
+        Lang::Spa => "es",
+        Lang::Fra => "fr",
+        Lang::Deu => "de",
+        Lang::Ita => "it",
+        Lang::Por => "pt",
+        Lang::Nld => "nl",
+        Lang::Rus => "ru",
+        Lang::Ukr => "uk",
+        Lang::Pol => "pl",
+        Lang::Swe => "sv",
+        Lang::Fin => "fi",
+        Lang::Dan => "da",
+        Lang::Nob => "nb",
+        Lang::Ell => "el",
+        Lang::Tur => "tr",
+        Lang::Ara => "ar",
+        Lang::Heb => "he",
+        Lang::Hin => "hi",
+        Lang::Ben => "bn",
+        Lang::Vie => "vi",
+        Lang::Tha => "th",
+        Lang::Ind => "id",
+        Lang::Cmn => "zh",
+        Lang::Jpn => "ja",
+        Lang::Kor => "ko",
+        Lang::Hun => "hu",
+        Lang::Ces => "cs",
+        Lang::Ron => "ro",
+        _ => lang.code(),
+    }
+}
+
+// Detect the language of the text, None if the detection is not reliable
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let info = detect(text)?;
+    if !info.is_reliable() {
+        return None;
+    }
+    Some(language_code(info.lang()))
+}
+
+// Parse a voice map like "es=es_ES/m-ailabs_low,ja=openai:nova,*=openai:alloy"
+pub fn parse_voice_map(voice_map: &str) -> HashMap<String, VoiceRoute> {
+    voice_map
+        .split(',')
+        .filter_map(|entry| {
+            let (language, voice) = entry.split_once('=')?;
+            let language = language.trim().to_lowercase();
+            let voice = voice.trim();
+            if language.is_empty() || voice.is_empty() {
+                log::error!("Invalid TTS voice map entry: {}", entry);
+                return None;
+            }
+            let route = match voice.split_once(':') {
+                Some(("openai", voice)) => VoiceRoute::OpenAI(voice.to_string()),
+                Some(("mimic3", voice)) => VoiceRoute::Mimic3(voice.to_string()),
+                _ => VoiceRoute::Mimic3(voice.to_string()),
+            };
+            Some((language, route))
+        })
+        .collect()
+}
+
+// Pick the voice for the text, None keeps the default voice.
+// The "*" entry is used for any detected language other than the default language.
+pub fn route_voice(text: &str, voice_map: &str, default_language: &str) -> Option<VoiceRoute> {
+    let language = detect_language(text)?;
+    let routes = parse_voice_map(voice_map);
+
+    if let Some(route) = routes.get(language) {
+        log::debug!("TTS language {} routed to {:?}", language, route);
+        return Some(route.clone());
+    }
+
+    if language != default_language {
+        if let Some(route) = routes.get("*") {
+            log::debug!("TTS language {} routed to fallback {:?}", language, route);
+            return Some(route.clone());
+        }
+        log::warn!(
+            "No TTS voice for detected language {}, using the default voice",
+            language
+        );
+    }
+
+    None
+}
@@ -7,42 +7,176 @@
  * for RsLLM.
 */
 
+pub mod ad_break;
+#[cfg(feature = "ai")]
+pub mod api_auth;
 pub mod args;
+#[cfg(feature = "ai")]
 pub mod audio;
+pub mod audio_class;
+pub mod audio_meter;
+#[cfg(feature = "ai")]
+pub mod bumpers;
+#[cfg(feature = "ai")]
 pub mod candle_metavoice;
+#[cfg(feature = "ai")]
 pub mod candle_mistral;
+pub mod capabilities;
+#[cfg(feature = "program_audio")]
+pub mod captions;
+pub mod capture_clock;
+pub mod capture_paths;
+#[cfg(feature = "ai")]
+pub mod chapters;
+#[cfg(feature = "ai")]
+pub mod character_sheet;
+#[cfg(feature = "ai")]
+pub mod checkpoint;
+#[cfg(feature = "ai")]
+pub mod control_api;
+pub mod diarization;
+#[cfg(feature = "discord")]
+pub mod discord_voice;
+#[cfg(feature = "ai")]
+pub mod dry_run;
+#[cfg(feature = "ai")]
+pub mod emotion;
+pub mod event_log;
+pub mod event_publish;
+pub mod external_apis;
+pub mod fec;
+#[cfg(feature = "ai")]
+pub mod health_report;
+#[cfg(feature = "ai")]
+pub mod history;
+#[cfg(feature = "ai")]
+pub mod icecast;
+#[cfg(feature = "ai")]
+pub mod image_generator;
+#[cfg(feature = "ai")]
+pub mod image_ops;
+#[cfg(feature = "ai")]
+pub mod image_queue;
+#[cfg(feature = "ai")]
+pub mod image_relevance;
+#[cfg(feature = "kafka")]
+pub mod kafka_sink;
+#[cfg(feature = "ai")]
+pub mod keywords;
+#[cfg(feature = "ai")]
+pub mod language;
+pub mod liveness;
+#[cfg(feature = "ai")]
+pub mod llm_routing;
+#[cfg(feature = "ai")]
+pub mod llm_service;
+pub mod loudness;
+#[cfg(feature = "ai")]
 pub mod mimic3_tts;
+#[cfg(feature = "ai")]
+pub mod moderation;
 pub mod mpegts;
+pub mod multicast;
+#[cfg(feature = "ai")]
+pub mod music;
 #[cfg(feature = "ndi")]
 pub mod ndi;
 pub mod network_capture;
+#[cfg(feature = "ai")]
+pub mod network_prompt;
+#[cfg(feature = "ai")]
 pub mod openai_api;
+#[cfg(feature = "ai")]
 pub mod openai_tts;
+#[cfg(feature = "ai")]
+pub mod output;
+#[cfg(feature = "ai")]
+pub mod overlay;
+pub mod packet_headers;
+pub mod pcap_stats;
+pub mod pcap_writer;
+#[cfg(feature = "ai")]
+pub mod persona;
+#[cfg(feature = "ai")]
 pub mod pipeline;
+#[cfg(feature = "ai")]
+pub mod podcast;
+#[cfg(feature = "ai")]
+pub mod prefetch;
+pub mod probe;
+pub mod probe_link;
+#[cfg(feature = "program_audio")]
+pub mod program_audio;
+#[cfg(feature = "ai")]
+pub mod prompt_guard;
+pub mod prompts;
+pub mod response_cache;
+#[cfg(feature = "ai")]
+pub mod run_record;
+#[cfg(feature = "ai")]
+pub mod runtime;
+#[cfg(feature = "ai")]
+pub mod safety_checker;
+#[cfg(feature = "ai")]
+pub mod sampling;
+#[cfg(feature = "ai")]
 pub mod sd_automatic;
+#[cfg(feature = "ai")]
+pub mod sd_comfyui;
+#[cfg(feature = "ai")]
+pub mod sd_openai;
+pub mod secrets;
+#[cfg(feature = "ai")]
+pub mod segmenter;
+pub mod session_stats;
+pub mod shutdown;
+#[cfg(feature = "ai")]
 pub mod stable_diffusion;
+#[cfg(feature = "ai")]
+pub mod stats_qa;
+pub mod stats_schema;
 pub mod stream_data;
+pub mod stream_state;
+pub mod supervisor;
 pub mod system_stats;
+#[cfg(feature = "thumbnails")]
+pub mod thumbnails;
+#[cfg(feature = "ai")]
+pub mod token_budget;
+#[cfg(feature = "ai")]
+pub mod token_stream;
+pub mod transcript;
+#[cfg(feature = "ai")]
+pub mod translate;
+pub mod ts_generator;
+#[cfg(feature = "ai")]
+pub mod tts_text;
+#[cfg(feature = "ai")]
 pub mod twitch_client;
+#[cfg(feature = "ai")]
+pub mod twitch_helix;
+#[cfg(feature = "ai")]
+pub mod whisper;
 use serde_json::{json, Value};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 pub use system_stats::{get_system_stats, SystemStats};
+#[cfg(feature = "ai")]
 pub mod candle_gemma;
-use image::{
-    imageops::{resize, FilterType},
-    ImageBuffer, Rgb, Rgba,
-};
-#[cfg(feature = "fonts")]
-use imageproc::drawing::draw_text_mut;
+#[cfg(feature = "ai")]
+use image::{ImageBuffer, Rgb};
 #[cfg(feature = "fonts")]
 use rusttype::{Font, Scale};
-use std::io::Write;
+#[cfg(feature = "fonts")]
+use unicode_segmentation::UnicodeSegmentation;
+#[cfg(feature = "fonts")]
+use unicode_width::UnicodeWidthStr;
 
 #[derive(Debug)]
 pub enum ApiError {
     Error(String),
     RequestError(reqwest::Error),
+    Unavailable(String), // endpoint refused, timed out or answered 5xx/429 through the retries
 }
 
 impl From<reqwest::Error> for ApiError {
@@ -56,6 +190,7 @@ impl std::fmt::Display for ApiError {
         match self {
             ApiError::Error(msg) => write!(f, "{}", msg),
             ApiError::RequestError(e) => write!(f, "Request error: {}", e),
+            ApiError::Unavailable(msg) => write!(f, "Unavailable: {}", msg),
         }
     }
 }
@@ -83,6 +218,14 @@ pub fn current_unix_timestamp_ms() -> Result<u64, &'static str> {
         .map_err(|_| "System time is before the UNIX epoch")
 }
 
+// Function to get the current Unix timestamp in nanoseconds
+pub fn current_unix_timestamp_ns() -> Result<u64, &'static str> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .map_err(|_| "System time is before the UNIX epoch")
+}
+
 // Print a hexdump of the packet
 pub fn hexdump(packet_arc: &Arc<Vec<u8>>, packet_offset: usize, packet_len: usize) {
     let packet = &packet_arc[packet_offset..packet_offset + packet_len];
@@ -148,12 +291,17 @@ pub fn adjust_caps(paragraph: &str) -> String {
 ///
 /// # Arguments
 ///
+/// * `out` - The buffered terminal output the string is written to.
 /// * `received` - The string to potentially modify.
 /// * `terminal_token_len` - The current length of the terminal token, to be updated.
-pub fn handle_long_string(received: &str, terminal_token_len: &mut usize) {
+///
+/// The output is not flushed, the caller flushes once per batch of tokens.
+pub fn handle_long_string(
+    out: &mut impl std::io::Write,
+    received: &str,
+    terminal_token_len: &mut usize,
+) -> std::io::Result<()> {
     if *terminal_token_len >= 80 {
-        std::io::stdout().flush().unwrap();
-
         // Initialize split position to the end of the string by default
         let mut split_pos = received.len();
         let mut found = false;
@@ -177,16 +325,16 @@ pub fn handle_long_string(received: &str, terminal_token_len: &mut usize) {
 
         if found {
             let (first, second) = received.split_at(split_pos);
-            print!("{}\n{}", first, second); // Use println! for simplicity to handle the newline
+            writeln!(out, "{}", first)?;
+            write!(out, "{}", second)?;
             *terminal_token_len = 0; //second.len(); // Update terminal_token_len with the length of the second part
         } else {
-            print!("{}", received);
+            write!(out, "{}", received)?;
         }
-        std::io::stdout().flush().unwrap();
     } else {
-        print!("{}", received);
-        std::io::stdout().flush().unwrap();
+        write!(out, "{}", received)?;
     }
+    Ok(())
 }
 
 /// Truncate the input text to the specified number of tokens.
@@ -228,51 +376,51 @@ pub fn count_tokens(text: &str) -> usize {
     token_count
 }
 
-// Helper function to wrap text into lines
+// Helper function to wrap text into lines, the fonts after the first are fallbacks for
+// glyphs it doesn't have like CJK or emoji
 #[cfg(feature = "fonts")]
-pub fn wrap_text<'a>(text: &'a str, font: &Font, scale: Scale, max_width: i32) -> Vec<String> {
+pub fn wrap_text(text: &str, fonts: &[Font], scale: Scale, max_width: i32) -> Vec<String> {
+    let max_width = max_width as f32;
+    let space_width = grapheme_width(" ", fonts, scale);
     let mut lines = Vec::new();
     let mut current_line = String::new();
-    let space_width = font.glyph(' ').scaled(scale).h_metrics().advance_width;
-
-    for word in text.split_whitespace() {
-        let word_width = word
-            .chars()
-            .map(|c| font.glyph(c).scaled(scale).h_metrics().advance_width)
-            .sum::<f32>();
-        if current_line.is_empty()
-            || text_width(&current_line, font, scale) + space_width + word_width <= max_width as f32
-        {
-            if !current_line.is_empty() {
+    let mut current_width = 0.0;
+
+    for (word, spaced) in line_break_units(text) {
+        let word_width = text_width(&word, fonts, scale);
+        let gap = if spaced && !current_line.is_empty() {
+            space_width
+        } else {
+            0.0
+        };
+        if current_line.is_empty() || current_width + gap + word_width <= max_width {
+            if gap > 0.0 {
                 current_line.push(' ');
             }
-            current_line.push_str(word);
+            current_line.push_str(&word);
+            current_width += gap + word_width;
         } else {
-            lines.push(current_line);
-            current_line = String::from(word);
+            lines.push(std::mem::take(&mut current_line));
+            current_line = word;
+            current_width = word_width;
         }
-    }
 
-    // go through lines and break any that exceed our max length into smaller lines and adjust proceeding lines
-    // force break without wrap_text function since we are not breaking by spaces but by characters instead
-    let mut i = 0;
-    while i < lines.len() {
-        let line = &lines[i];
-        if text_width(line, font, scale) > max_width as f32 {
-            // break line into smaller lines by character not by spaces
-            let mut new_lines = Vec::new();
-            let mut current_line = String::new();
-            for c in line.chars() {
-                let char_width = font.glyph(c).scaled(scale).h_metrics().advance_width;
-                if text_width(&current_line, font, scale) + char_width <= max_width as f32 {
-                    current_line.push(c);
-                } else {
-                    new_lines.push(current_line);
-                    current_line = String::from(c);
+        // a word wider than the line on its own is force broken between grapheme clusters
+        if current_width > max_width {
+            let mut broken = String::new();
+            let mut broken_width = 0.0;
+            for grapheme in current_line.graphemes(true) {
+                let width = grapheme_width(grapheme, fonts, scale);
+                if !broken.is_empty() && broken_width + width > max_width {
+                    lines.push(std::mem::take(&mut broken));
+                    broken_width = 0.0;
                 }
+                broken.push_str(grapheme);
+                broken_width += width;
             }
+            current_line = broken;
+            current_width = broken_width;
         }
-        i += 1;
     }
 
     if !current_line.is_empty() {
@@ -282,190 +430,100 @@ pub fn wrap_text<'a>(text: &'a str, font: &Font, scale: Scale, max_width: i32) -
     lines
 }
 
-// Helper function to calculate text width
+// Words of the text for line breaking and if a space comes before them. Wide characters
+// are words on their own since CJK text can break between any of them.
 #[cfg(feature = "fonts")]
-pub fn text_width(text: &str, font: &Font, scale: Scale) -> f32 {
-    text.chars()
-        .map(|c| font.glyph(c).scaled(scale).h_metrics().advance_width)
-        .sum()
+fn line_break_units(text: &str) -> Vec<(String, bool)> {
+    let mut units = Vec::new();
+    let mut word = String::new();
+    let mut spaced = false;
+
+    for grapheme in text.graphemes(true) {
+        if grapheme.chars().all(char::is_whitespace) {
+            if !word.is_empty() {
+                units.push((std::mem::take(&mut word), spaced));
+            }
+            spaced = true;
+        } else if UnicodeWidthStr::width(grapheme) > 1 {
+            if !word.is_empty() {
+                units.push((std::mem::take(&mut word), spaced));
+                spaced = false;
+            }
+            units.push((grapheme.to_string(), spaced));
+            spaced = false;
+        } else {
+            word.push_str(grapheme);
+        }
+    }
+    if !word.is_empty() {
+        units.push((word, spaced));
+    }
+
+    units
 }
 
+// Index of the first font with a glyph for the grapheme cluster
 #[cfg(feature = "fonts")]
-pub fn convert_rgb_to_rgba_with_text(
-    image_buffer: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    text: &str,
-    font_size: f32,
-    start_pos: (i32, i32),
-) -> Vec<u8> {
-    let font_data = include_bytes!("../fonts/TrebuchetMSBold.ttf");
-    let font = Font::try_from_bytes(font_data as &[u8]).expect("Error constructing Font");
-
-    let mut image_rgba =
-        ImageBuffer::from_fn(image_buffer.width(), image_buffer.height(), |x, y| {
-            let pixel = image_buffer.get_pixel(x, y);
-            Rgba([pixel[0], pixel[1], pixel[2], 255])
-        });
-
-    let scale = Scale {
-        x: font_size,
-        y: font_size,
-    };
-    let text_color = Rgba([255, 255, 255, 0xff]);
-    let shadow_color = Rgba([0, 0, 0, 255]);
-    let shadow_top_offset = 2; // Shadow offset in pixels
-    let shadow_bottom_offset = 4; // Shadow offset in pixels
-
-    // Draw bottom shadow
-    let wrapped_text_shadow = wrap_text(
-        text,
-        &font,
-        scale,
-        image_buffer.width() as i32 - start_pos.0 * 2,
-    );
-    let mut current_height_bottom_shadow = start_pos.1 + shadow_bottom_offset / 2;
-    for line in &wrapped_text_shadow {
-        draw_text_mut(
-            &mut image_rgba,
-            shadow_color,
-            start_pos.0 + shadow_bottom_offset,
-            current_height_bottom_shadow,
-            scale,
-            &font,
-            line,
-        );
-        current_height_bottom_shadow += font_size as i32;
-    }
+pub fn font_index(grapheme: &str, fonts: &[Font]) -> Option<usize> {
+    let c = grapheme.chars().next()?;
+    fonts.iter().position(|font| font.glyph(c).id().0 != 0)
+}
 
-    // Draw top shadow
-    let wrapped_text_shadow = wrap_text(
-        text,
-        &font,
-        scale,
-        image_buffer.width() as i32 - start_pos.0 * 2,
-    );
-    let mut current_height_top_shadow = start_pos.1 - shadow_top_offset / 2;
-    for line in &wrapped_text_shadow {
-        draw_text_mut(
-            &mut image_rgba,
-            shadow_color,
-            start_pos.0 - shadow_top_offset,
-            current_height_top_shadow,
-            scale,
-            &font,
-            line,
-        );
-        current_height_top_shadow += font_size as i32;
+// Width of a grapheme cluster in the font that has it, when none of the fonts has the glyph
+// the display width is used so wide characters still take the space of two narrow ones
+#[cfg(feature = "fonts")]
+pub fn grapheme_width(grapheme: &str, fonts: &[Font], scale: Scale) -> f32 {
+    match font_index(grapheme, fonts) {
+        Some(index) => {
+            let font = &fonts[index];
+            grapheme
+                .chars()
+                .map(|c| font.glyph(c).scaled(scale).h_metrics().advance_width)
+                .sum()
+        }
+        None => UnicodeWidthStr::width(grapheme).max(1) as f32 * scale.x / 2.0,
     }
+}
 
-    // Draw text
-    let wrapped_text = wrap_text(
-        text,
-        &font,
-        scale,
-        image_buffer.width() as i32 - start_pos.0 * 2,
-    );
-    let mut current_height = start_pos.1;
-    for line in &wrapped_text {
-        draw_text_mut(
-            &mut image_rgba,
-            text_color,
-            start_pos.0,
-            current_height,
-            scale,
-            &font,
-            line,
-        );
-        current_height += font_size as i32;
+// Split the text into runs drawn with the same font, with the index of the font and the width
+#[cfg(feature = "fonts")]
+pub fn font_runs(text: &str, fonts: &[Font], scale: Scale) -> Vec<(usize, String, f32)> {
+    let mut runs: Vec<(usize, String, f32)> = Vec::new();
+    for grapheme in text.graphemes(true) {
+        let index = font_index(grapheme, fonts).unwrap_or(0);
+        let width = grapheme_width(grapheme, fonts, scale);
+        match runs.last_mut() {
+            Some((run_index, run, run_width)) if *run_index == index => {
+                run.push_str(grapheme);
+                *run_width += width;
+            }
+            _ => runs.push((index, grapheme.to_string(), width)),
+        }
     }
-
-    image_rgba
-        .pixels()
-        .flat_map(|pixel| {
-            let Rgba(data) = *pixel;
-            vec![data[0], data[1], data[2], data[3]]
-        })
-        .collect()
+    runs
 }
 
-#[cfg(not(feature = "fonts"))]
-pub fn convert_rgb_to_rgba(image_buffer: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<u8> {
-    let image_rgba = ImageBuffer::from_fn(image_buffer.width(), image_buffer.height(), |x, y| {
-        let pixel = image_buffer.get_pixel(x, y);
-        Rgba([pixel[0], pixel[1], pixel[2], 255])
-    });
-
-    image_rgba
-        .pixels()
-        .flat_map(|pixel| {
-            let Rgba(data) = *pixel;
-            vec![data[0], data[1], data[2], data[3]]
-        })
-        .collect()
+// Helper function to calculate text width
+#[cfg(feature = "fonts")]
+pub fn text_width(text: &str, fonts: &[Font], scale: Scale) -> f32 {
+    text.graphemes(true)
+        .map(|grapheme| grapheme_width(grapheme, fonts, scale))
+        .sum()
 }
 
+#[cfg(feature = "ai")]
 pub async fn clean_tts_input(input: String) -> String {
-    // remove strings of periods anywhere within the input text and replace with a single period.
-    // do it in a loop
-    let mut input = input.clone();
-    while input.contains("..") {
-        input = input.replace("..", ".");
-    }
-    while input.contains("**") {
-        input = input.replace("**", "");
-    }
-
-    // remove <|im_end|> string from input and replace with ""
-    let input = input.replace("<|im_end|>", "");
-
-    // remove all extra spaces besides 1 space between words, if all spaces left then reduce to '"
-    let input = input
-        .split_whitespace()
-        .collect::<Vec<&str>>()
-        .join(" ")
-        .replace(" .", ".")
-        .replace(" ,", ",")
-        .replace(" ?", "?")
-        .replace(" !", "!")
-        .replace(" :", ":")
-        .replace(" ;", ";");
-
-    // remove any special characters from the text except for normal punctuation ./,;:?
-    let input = input
-        .chars()
-        .filter(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_ascii_punctuation())
-        .collect::<String>();
-
-    // split into sentences and check if any begin with special characters, remove them
-    let input = input
-        .split('.')
-        .map(|s| {
-            let s = s.trim();
-            if s.starts_with(|c: char| c.is_ascii_punctuation()) {
-                &s[1..]
-            } else {
-                s
-            }
-        })
-        .collect::<Vec<&str>>()
-        .join(". ");
-
-    // remove any non ascii characters from the ending of the input text
-    let input = input
-        .chars()
-        .take_while(|c| c.is_ascii())
-        .collect::<String>();
-
-    // loop removing end punctuation until no more
-    let mut input = input.clone();
-    while input.ends_with(|c: char| !c.is_alphanumeric()) {
-        input = input
-            .trim_end_matches(|c: char| !c.is_alphanumeric())
-            .to_string();
-    }
+    clean_tts_text(&input)
+}
 
-    input
+// Synchronous version of clean_tts_input for use outside of async code, cleans with the
+// default options, the speech pipeline uses the options for its TTS backend
+#[cfg(feature = "ai")]
+pub fn clean_tts_text(input: &str) -> String {
+    tts_text::clean_text(input, &tts_text::TtsTextOptions::default())
 }
 
+#[cfg(feature = "ai")]
 pub fn scale_image(
     image: ImageBuffer<Rgb<u8>, Vec<u8>>,
     new_width: Option<u32>,
@@ -484,7 +542,7 @@ pub fn scale_image(
         let scaled_height = (orig_height as f32 * scale).round() as u32;
 
         // Scale the image while preserving the aspect ratio.
-        let scaled_image = resize(&image, scaled_width, scaled_height, FilterType::Lanczos3);
+        let scaled_image = image_ops::resize_rgb(&image, scaled_width, scaled_height);
 
         // Create a new image with the target dimensions filled with black pixels.
         let mut new_image = ImageBuffer::from_pixel(target_width, target_height, Rgb([0, 0, 0]));
@@ -498,12 +556,7 @@ pub fn scale_image(
         let y_offset = (target_height - scaled_height) / 2;
 
         // Copy the scaled image onto the new image at the calculated offset.
-        for (x, y, pixel) in scaled_image.enumerate_pixels() {
-            // Ensure the pixel is within the bounds of the target image dimensions.
-            if x + x_offset < target_width && y + y_offset < target_height {
-                new_image.put_pixel(x + x_offset, y + y_offset, *pixel);
-            }
-        }
+        image_ops::paste_rgb(&mut new_image, &scaled_image, x_offset, y_offset);
 
         new_image
     } else {
@@ -0,0 +1,146 @@
+/*
+ * liveness.rs
+ * -----------
+ * Last activity of each subsystem, capture, demux, LLM, TTS, stable diffusion and the NDI
+ * sender, for the /healthz endpoint of the control API. The subsystems enabled by the args
+ * are expected to be active, one silent for longer than the stall threshold makes the
+ * health check fail so an orchestrator can restart the container.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::supervisor::{supervised_tasks, TaskState};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Capture,
+    Demux,
+    Llm,
+    Tts,
+    Sd,
+    Ndi,
+}
+
+pub const SUBSYSTEMS: [Subsystem; 6] = [
+    Subsystem::Capture,
+    Subsystem::Demux,
+    Subsystem::Llm,
+    Subsystem::Tts,
+    Subsystem::Sd,
+    Subsystem::Ndi,
+];
+
+// unix time in ms of the latest activity, 0 before the first
+static LAST_ACTIVITY: [AtomicU64; 6] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static EXPECTED: [AtomicBool; 6] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+impl Subsystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subsystem::Capture => "capture",
+            Subsystem::Demux => "demux",
+            Subsystem::Llm => "llm",
+            Subsystem::Tts => "tts",
+            Subsystem::Sd => "sd",
+            Subsystem::Ndi => "ndi",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+pub fn mark_active(subsystem: Subsystem) {
+    LAST_ACTIVITY[subsystem.index()]
+        .store(current_unix_timestamp_ms().unwrap_or(0), Ordering::Relaxed);
+}
+
+// Expect activity from the subsystem, the stall threshold counts from now until its first
+pub fn expect_activity(subsystem: Subsystem) {
+    EXPECTED[subsystem.index()].store(true, Ordering::Relaxed);
+    let _ = LAST_ACTIVITY[subsystem.index()].compare_exchange(
+        0,
+        current_unix_timestamp_ms().unwrap_or(0),
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+}
+
+// The subsystems the args turn on
+pub fn expect_from_args(args: &Args) {
+    if args.ai_network_stats {
+        expect_activity(Subsystem::Capture);
+        expect_activity(Subsystem::Demux);
+    }
+    expect_activity(Subsystem::Llm);
+    if args.tts_enable || args.oai_tts || args.mimic3_tts || args.metavoice_tts {
+        expect_activity(Subsystem::Tts);
+    }
+    if args.sd_image {
+        expect_activity(Subsystem::Sd);
+    }
+    #[cfg(feature = "ai")]
+    if crate::output::output_sink_names(args)
+        .iter()
+        .any(|name| name == "ndi")
+    {
+        expect_activity(Subsystem::Ndi);
+    }
+}
+
+// Healthy or not and the report of each subsystem and supervised task, a stall_ms of 0 only
+// fails on tasks that gave up restarting
+pub fn liveness(stall_ms: u64) -> (bool, Value) {
+    let now = current_unix_timestamp_ms().unwrap_or(0);
+    let mut healthy = true;
+    let mut subsystems = serde_json::Map::new();
+    for subsystem in SUBSYSTEMS {
+        let expected = EXPECTED[subsystem.index()].load(Ordering::Relaxed);
+        let last = LAST_ACTIVITY[subsystem.index()].load(Ordering::Relaxed);
+        let idle_ms = if last > 0 {
+            Some(now.saturating_sub(last))
+        } else {
+            None
+        };
+        let stalled = expected && stall_ms > 0 && idle_ms.is_some_and(|idle| idle > stall_ms);
+        healthy &= !stalled;
+        subsystems.insert(
+            subsystem.name().to_string(),
+            json!({
+                "expected": expected,
+                "last_activity_ms": if last > 0 { Some(last) } else { None },
+                "idle_ms": idle_ms,
+                "stalled": stalled,
+            }),
+        );
+    }
+
+    let tasks = supervised_tasks();
+    healthy &= !tasks.iter().any(|task| task.state == TaskState::Failed);
+    (
+        healthy,
+        json!({
+            "status": if healthy { "ok" } else { "stalled" },
+            "stall_ms": stall_ms,
+            "subsystems": subsystems,
+            "tasks": tasks,
+        }),
+    )
+}
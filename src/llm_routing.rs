@@ -0,0 +1,323 @@
+/*
+ * llm_routing.rs
+ * --------------
+ * Which LLM answers which task. The --llm-routes JSON file names backends, candle models
+ * served by the LLM service or OpenAI compatible APIs, and picks one for each task: the
+ * stream analysis, the story and the Twitch chat. A task without a route keeps the backend
+ * of the command line, so the analysis can run on a local quantized model while the chat
+ * has gemma and the story goes to an API model.
+*/
+
+use crate::args::Args;
+use crate::llm_service::{LlmRequest, LlmService, LlmSource};
+use crate::openai_api::{stream_completion, Message, OpenAIRequest};
+use crate::sampling::SamplingConfig;
+use crate::secrets::secret;
+use crate::token_stream::{coalesce_tokens, TokenBatch, TokenBatching};
+use crate::ApiError;
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+const OPENAI_HOST: &str = "https://api.openai.com";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LlmTask {
+    Analysis, // prompts with the network stats
+    Story,
+    Chat, // Twitch chat answers
+}
+
+impl std::str::FromStr for LlmTask {
+    type Err = String;
+
+    fn from_str(task: &str) -> Result<Self, String> {
+        match task.trim().to_lowercase().as_str() {
+            "analysis" | "analyzer" => Ok(LlmTask::Analysis),
+            "story" => Ok(LlmTask::Story),
+            "chat" | "twitch" => Ok(LlmTask::Chat),
+            _ => Err(format!("Invalid llm task {}", task)),
+        }
+    }
+}
+
+impl LlmTask {
+    // The task of the main loop, the analysis when it is fed the stream stats of its own
+    // capture or of the remote probes
+    pub fn main(args: &Args) -> Self {
+        if args.ai_network_stats || !args.probe_listen.is_empty() {
+            LlmTask::Analysis
+        } else {
+            LlmTask::Story
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LlmBackend {
+    Candle {
+        model: String, // mistral or gemma
+        #[serde(default = "default_model_id")]
+        model_id: String,
+        #[serde(default)]
+        quantized: bool,
+        #[serde(default)]
+        chat_format: String, // empty is --chat-format
+    },
+    Api {
+        host: String,
+        #[serde(default = "default_llm_path")]
+        path: String,
+        model: String,
+    },
+    Openai {
+        model: String,
+    },
+}
+
+fn default_model_id() -> String {
+    "auto".to_string()
+}
+
+fn default_llm_path() -> String {
+    "/v1/chat/completions".to_string()
+}
+
+impl LlmBackend {
+    // The backend of --use-openai, --use-api or --candle-llm
+    pub fn main(args: &Args) -> Self {
+        if args.use_openai {
+            LlmBackend::Openai {
+                model: args.model.clone(),
+            }
+        } else if args.use_api {
+            LlmBackend::Api {
+                host: args.llm_host.clone(),
+                path: args.llm_path.clone(),
+                model: args.model.clone(),
+            }
+        } else {
+            LlmBackend::Candle {
+                model: args.candle_llm.clone(),
+                model_id: args.model_id.clone(),
+                quantized: args.quantized,
+                chat_format: String::new(),
+            }
+        }
+    }
+
+    // The model of --twitch-model, full precision
+    pub fn chat(args: &Args) -> Self {
+        let model_id = if args.twitch_model == "gemma" {
+            "2b-it"
+        } else {
+            "auto"
+        };
+        LlmBackend::Candle {
+            model: args.twitch_model.clone(),
+            model_id: model_id.to_string(),
+            quantized: false,
+            chat_format: String::new(),
+        }
+    }
+
+    // Name of the model, for its context window
+    pub fn model(&self) -> &str {
+        match self {
+            LlmBackend::Candle { model, .. }
+            | LlmBackend::Api { model, .. }
+            | LlmBackend::Openai { model } => model,
+        }
+    }
+
+    // Model id of a candle model, empty for an API
+    pub fn model_id(&self) -> &str {
+        match self {
+            LlmBackend::Candle { model_id, .. } => model_id,
+            _ => "",
+        }
+    }
+
+    pub fn quantized(&self) -> bool {
+        matches!(
+            self,
+            LlmBackend::Candle {
+                quantized: true,
+                ..
+            }
+        )
+    }
+
+    pub fn is_api(&self) -> bool {
+        !matches!(self, LlmBackend::Candle { .. })
+    }
+
+    pub fn is_openai(&self) -> bool {
+        matches!(self, LlmBackend::Openai { .. })
+    }
+
+    // Chat format of the prompt text given to a candle model
+    pub fn chat_format(&self, args: &Args) -> String {
+        match self {
+            LlmBackend::Candle { chat_format, .. } if !chat_format.is_empty() => {
+                chat_format.clone()
+            }
+            _ => args.chat_format.clone(),
+        }
+    }
+
+    // Generate the answer on a task of its own, an API backend gets the messages and a
+    // candle model the prompt text. The task ends with the error of an API endpoint that
+    // could not be reached, the answer is then empty.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn_generation(
+        &self,
+        source: LlmSource,
+        messages: Vec<Message>,
+        prompt: String,
+        max_tokens: usize,
+        sampling: SamplingConfig,
+        sender: Sender<TokenBatch>,
+        batching: TokenBatching,
+        llm_service: &LlmService,
+        args: &Args,
+    ) -> JoinHandle<Result<(), ApiError>> {
+        let (host, path) = match self {
+            LlmBackend::Candle {
+                model,
+                model_id,
+                quantized,
+                ..
+            } => {
+                let request = LlmRequest::new(
+                    source, model, model_id, *quantized, prompt, max_tokens, sampling, sender,
+                    batching,
+                );
+                let model = model.clone();
+                let llm_service = llm_service.clone();
+                return tokio::spawn(async move {
+                    if let Err(e) = llm_service.submit(request).await {
+                        error!("Error running {}: {}", model, e);
+                    }
+                    Ok(())
+                });
+            }
+            LlmBackend::Api { host, path, .. } => (host.clone(), path.clone()),
+            LlmBackend::Openai { .. } => (OPENAI_HOST.to_string(), args.llm_path.clone()),
+        };
+        let model = self.model().to_string();
+        let openai_key = secret("OPENAI_API_KEY")
+            .map(|key| key.expose().to_string())
+            .unwrap_or_else(|| "NO_API_KEY".into());
+        let args = args.clone();
+        tokio::spawn(async move {
+            let temperature = sampling.temperature as f32;
+            let top_p = sampling.top_p as f32;
+            let open_ai_request = OpenAIRequest {
+                model: &model,
+                max_tokens: &max_tokens,
+                messages,
+                temperature: &temperature,
+                top_p: &top_p,
+                presence_penalty: &args.presence_penalty,
+                frequency_penalty: &args.frequency_penalty,
+                stream: &!args.no_stream,
+            };
+
+            // the api streams single tokens, they are batched on the way to the output
+            let (token_sender, token_receiver) = tokio::sync::mpsc::channel::<String>(32768);
+            let coalescer = tokio::spawn(coalesce_tokens(token_receiver, sender, batching));
+
+            let result = stream_completion(
+                open_ai_request,
+                &openai_key,
+                &host,
+                &path,
+                args.debug_inline,
+                args.show_output_errors,
+                args.llm_retries,
+                token_sender,
+            )
+            .await;
+            let _ = coalescer.await;
+            result
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct RoutesFile {
+    backends: HashMap<String, LlmBackend>,
+    tasks: HashMap<String, String>, // task name to backend name
+}
+
+// The backend of each task
+#[derive(Debug, Clone, Default)]
+pub struct LlmRoutes {
+    routes: HashMap<LlmTask, LlmBackend>,
+}
+
+impl LlmRoutes {
+    pub fn new() -> Self {
+        LlmRoutes::default()
+    }
+
+    pub fn with_route(mut self, task: LlmTask, backend: LlmBackend) -> Self {
+        self.routes.insert(task, backend);
+        self
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read llm routes {}: {}", path, e))?;
+        let file: RoutesFile = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Failed to parse llm routes {}: {}", path, e))?;
+        let mut routes = LlmRoutes::new();
+        for (task, name) in &file.tasks {
+            let task = task.parse::<LlmTask>().map_err(|e| anyhow!(e))?;
+            let backend = file
+                .backends
+                .get(name)
+                .ok_or_else(|| anyhow!("No llm backend {} for the {:?} task", name, task))?;
+            info!(
+                "LLM {:?} task routed to {} ({})",
+                task,
+                name,
+                backend.model()
+            );
+            routes = routes.with_route(task, backend.clone());
+        }
+        Ok(routes)
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        if args.llm_routes.is_empty() {
+            return LlmRoutes::new();
+        }
+        LlmRoutes::load(&args.llm_routes).unwrap_or_else(|e| {
+            error!("{}, using the command line backends", e);
+            LlmRoutes::new()
+        })
+    }
+
+    // The routed backend, the one of the command line without a route
+    pub fn backend(&self, task: LlmTask, args: &Args) -> LlmBackend {
+        match self.routes.get(&task) {
+            Some(backend) => backend.clone(),
+            None if task == LlmTask::Chat => LlmBackend::chat(args),
+            None => LlmBackend::main(args),
+        }
+    }
+
+    // Whether any task goes to OpenAI, it needs OPENAI_API_KEY
+    pub fn uses_openai(&self, args: &Args) -> bool {
+        [LlmTask::Analysis, LlmTask::Story, LlmTask::Chat]
+            .iter()
+            .any(|task| self.backend(*task, args).is_openai())
+    }
+}
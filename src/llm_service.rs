@@ -0,0 +1,494 @@
+/*
+ * llm_service.rs
+ * --------------
+ * One owner of the candle models for everything that asks them for text. The main loop,
+ * Twitch chat, Discord and the REST API queue their prompts here instead of each loading a
+ * model in its own thread. Models stay loaded between prompts, each source has a priority
+ * and waiting prompts gain priority with age so a busy chat can't starve the story.
+*/
+
+use crate::args::Args;
+use crate::candle_gemma::GemmaModel;
+use crate::candle_mistral::MistralModel;
+use crate::event_log::log_event;
+use crate::sampling::SamplingConfig;
+use crate::token_stream::{TokenBatch, TokenBatching};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+// The answer being generated for each source, the watchdog doesn't count the time a prompt
+// waits in the queue or for its model to load
+static GENERATING: Lazy<Mutex<HashMap<LlmSource, Generation>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// How often the watchdog looks at a quiet generation
+const WATCHDOG_TICK: Duration = Duration::from_secs(1);
+// How often the service looks for a cancel of the generation
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+// Time a cancelled generation gets to finish its forward pass before it is left behind
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+struct Generation {
+    started: Instant,
+    cancel: Arc<AtomicBool>,
+}
+
+// Stop the answer being generated for the source at its next sampling step
+fn cancel_generation(source: LlmSource) {
+    if let Some(generation) = GENERATING.lock().unwrap().get(&source) {
+        generation.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+async fn cancelled(cancel: &AtomicBool) {
+    while !cancel.load(Ordering::SeqCst) {
+        tokio::time::sleep(CANCEL_POLL).await;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LlmSource {
+    Main,
+    Twitch,
+    Discord,
+    Rest,
+}
+
+impl std::str::FromStr for LlmSource {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, String> {
+        match source.trim().to_lowercase().as_str() {
+            "main" => Ok(LlmSource::Main),
+            "twitch" => Ok(LlmSource::Twitch),
+            "discord" => Ok(LlmSource::Discord),
+            "rest" | "api" => Ok(LlmSource::Rest),
+            _ => Err(format!("Invalid llm source {}", source)),
+        }
+    }
+}
+
+// A prompt for one of the candle models, the tokens are sent to the sender in batches and it
+// is dropped when the answer is done
+pub struct LlmRequest {
+    pub source: LlmSource,
+    pub model: String, // mistral or gemma
+    pub model_id: String,
+    pub quantized: bool,
+    pub prompt: String,
+    pub max_tokens: usize,
+    pub sampling: SamplingConfig,
+    pub sender: Sender<TokenBatch>,
+    pub batching: TokenBatching,
+    queued_at: Instant,
+}
+
+impl LlmRequest {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        source: LlmSource,
+        model: &str,
+        model_id: &str,
+        quantized: bool,
+        prompt: String,
+        max_tokens: usize,
+        sampling: SamplingConfig,
+        sender: Sender<TokenBatch>,
+        batching: TokenBatching,
+    ) -> Self {
+        LlmRequest {
+            source,
+            model: model.to_string(),
+            model_id: model_id.to_string(),
+            quantized,
+            prompt,
+            max_tokens,
+            sampling,
+            sender,
+            batching,
+            queued_at: Instant::now(),
+        }
+    }
+
+    fn model_key(&self) -> String {
+        format!("{}:{}:{}", self.model, self.model_id, self.quantized)
+    }
+}
+
+enum LoadedModel {
+    Mistral(MistralModel),
+    Gemma(GemmaModel),
+}
+
+impl LoadedModel {
+    fn load(model: &str, model_id: String, quantized: bool) -> anyhow::Result<Self> {
+        match model {
+            "mistral" => Ok(LoadedModel::Mistral(MistralModel::load(
+                quantized,
+                Some(model_id),
+            )?)),
+            "gemma" => Ok(LoadedModel::Gemma(GemmaModel::load(Some(model_id))?)),
+            model => Err(anyhow::anyhow!("Invalid candle model {}", model)),
+        }
+    }
+
+    // Reading the weights blocks, it runs on the blocking pool like the generation
+    async fn load_blocking(request: &LlmRequest) -> anyhow::Result<Self> {
+        let model = request.model.clone();
+        let model_id = request.model_id.clone();
+        let quantized = request.quantized;
+        tokio::task::spawn_blocking(move || LoadedModel::load(&model, model_id, quantized))
+            .await
+            .map_err(|e| anyhow::anyhow!("the model load failed: {}", e))
+            .and_then(|result| result)
+    }
+
+    // Whether an abandoned generation still has the weights
+    fn is_busy(&self) -> bool {
+        match self {
+            LoadedModel::Mistral(model) => model.is_busy(),
+            LoadedModel::Gemma(model) => model.is_busy(),
+        }
+    }
+
+    async fn generate(
+        &mut self,
+        request: LlmRequest,
+        cancel: Arc<AtomicBool>,
+    ) -> anyhow::Result<()> {
+        match self {
+            LoadedModel::Mistral(model) => {
+                model
+                    .generate(
+                        &request.prompt,
+                        request.max_tokens,
+                        request.sampling,
+                        request.sender,
+                        request.batching,
+                        cancel,
+                    )
+                    .await
+            }
+            LoadedModel::Gemma(model) => {
+                model
+                    .generate(
+                        &request.prompt,
+                        request.max_tokens,
+                        request.sampling,
+                        request.sender,
+                        request.batching,
+                        cancel,
+                    )
+                    .await
+            }
+        }
+    }
+}
+
+// Queue per source, the next request is the one with the highest priority plus age
+struct LlmScheduler {
+    queues: HashMap<LlmSource, VecDeque<LlmRequest>>,
+    priorities: HashMap<LlmSource, f64>,
+    aging: Duration, // waiting this long adds 1 to the priority, zero is strict priority
+    queue_size: usize,
+}
+
+impl LlmScheduler {
+    fn new(priorities: &str, aging: Duration, queue_size: usize) -> Self {
+        let mut scheduler = LlmScheduler {
+            queues: HashMap::new(),
+            priorities: HashMap::new(),
+            aging,
+            queue_size,
+        };
+        for pair in priorities.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let parsed = pair.split_once('=').and_then(|(source, priority)| {
+                Some((
+                    source.parse::<LlmSource>().ok()?,
+                    priority.trim().parse::<f64>().ok()?,
+                ))
+            });
+            match parsed {
+                Some((source, priority)) => {
+                    scheduler.priorities.insert(source, priority);
+                }
+                None => error!("Invalid llm priority {}, expected source=priority", pair),
+            }
+        }
+        scheduler
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.values().all(|queue| queue.is_empty())
+    }
+
+    // A full queue turns the request away, dropping it ends the answer empty
+    fn push(&mut self, request: LlmRequest) {
+        let queue = self.queues.entry(request.source).or_default();
+        if self.queue_size > 0 && queue.len() >= self.queue_size {
+            warn!(
+                "LLM queue for {:?} is full with {} prompts, dropping the prompt",
+                request.source,
+                queue.len()
+            );
+            return;
+        }
+        queue.push_back(request);
+    }
+
+    fn score(&self, request: &LlmRequest, now: Instant) -> f64 {
+        let priority = self.priorities.get(&request.source).copied().unwrap_or(1.0);
+        if self.aging.is_zero() {
+            return priority;
+        }
+        priority + now.duration_since(request.queued_at).as_secs_f64() / self.aging.as_secs_f64()
+    }
+
+    fn next(&mut self) -> Option<LlmRequest> {
+        let now = Instant::now();
+        let source = self
+            .queues
+            .iter()
+            .filter_map(|(source, queue)| queue.front().map(|request| (*source, request)))
+            .max_by(|(_, a), (_, b)| {
+                self.score(a, now)
+                    .total_cmp(&self.score(b, now))
+                    // the older one on a tie
+                    .then(b.queued_at.cmp(&a.queued_at))
+            })
+            .map(|(source, _)| source)?;
+        self.queues.get_mut(&source)?.pop_front()
+    }
+}
+
+#[derive(Clone)]
+pub struct LlmService {
+    sender: Sender<LlmRequest>,
+}
+
+impl LlmService {
+    // Start the service task, the models are loaded on their first prompt
+    pub fn spawn(args: &Args) -> Self {
+        let (sender, receiver) = mpsc::channel(100);
+        let scheduler = LlmScheduler::new(
+            &args.llm_priorities,
+            Duration::from_secs(args.llm_aging_secs),
+            args.llm_queue_size,
+        );
+        tokio::spawn(serve(receiver, scheduler));
+        LlmService { sender }
+    }
+
+    // Queue the prompt, the answer arrives on the sender of the request
+    pub async fn submit(&self, request: LlmRequest) -> Result<(), String> {
+        self.sender
+            .send(request)
+            .await
+            .map_err(|_| "The llm service has stopped".to_string())
+    }
+}
+
+async fn serve(mut receiver: Receiver<LlmRequest>, mut scheduler: LlmScheduler) {
+    let mut models: HashMap<String, LoadedModel> = HashMap::new();
+    loop {
+        if scheduler.is_empty() {
+            match receiver.recv().await {
+                Some(request) => scheduler.push(request),
+                None => break,
+            }
+        }
+        // everything that arrived during the last answer competes for the next one
+        while let Ok(request) = receiver.try_recv() {
+            scheduler.push(request);
+        }
+        let Some(request) = scheduler.next() else {
+            continue;
+        };
+
+        let key = request.model_key();
+        // a hung generation that was left behind still has the weights, another copy is
+        // loaded rather than waiting on it
+        if models.get(&key).is_some_and(|model| model.is_busy()) {
+            warn!(
+                "The {} model is still in an abandoned answer, loading it again",
+                request.model
+            );
+            models.remove(&key);
+        }
+        if !models.contains_key(&key) {
+            info!("Loading the {} model {}", request.model, request.model_id);
+            match LoadedModel::load_blocking(&request).await {
+                Ok(model) => {
+                    models.insert(key.clone(), model);
+                }
+                Err(e) => {
+                    error!("Failed to load the {} model: {}", request.model, e);
+                    continue;
+                }
+            }
+        }
+        let source = request.source;
+        let waited = request.queued_at.elapsed();
+        if let Some(model) = models.get_mut(&key) {
+            info!(
+                "LLM answering a {:?} prompt after waiting {} ms",
+                source,
+                waited.as_millis()
+            );
+            let cancel = Arc::new(AtomicBool::new(false));
+            GENERATING.lock().unwrap().insert(
+                source,
+                Generation {
+                    started: Instant::now(),
+                    cancel: Arc::clone(&cancel),
+                },
+            );
+            let generation = model.generate(request, Arc::clone(&cancel));
+            tokio::pin!(generation);
+            // a cancelled generation stops at its next sampling step, one stuck in a forward
+            // pass is left behind so the next prompt doesn't queue behind it
+            let result = tokio::select! {
+                result = &mut generation => Some(result),
+                _ = cancelled(&cancel) => tokio::time::timeout(CANCEL_GRACE, &mut generation)
+                    .await
+                    .ok(),
+            };
+            match result {
+                Some(Ok(())) => {}
+                Some(Err(e)) => error!("Failed to generate the {:?} answer: {}", source, e),
+                None => warn!(
+                    "The {:?} answer is stuck in a forward pass, leaving it behind",
+                    source
+                ),
+            }
+            GENERATING.lock().unwrap().remove(&source);
+        }
+    }
+    info!("LLM service stopped");
+}
+
+// Builds the request tried again after a stall, with the sender of the new answer
+pub type RetryRequest = Box<dyn FnOnce(Sender<TokenBatch>) -> LlmRequest + Send>;
+
+// Gives up on an answer when no token arrived for the timeout once the model started on it.
+// The generation is cancelled at its next sampling step, or left behind when its forward pass
+// hangs, the token receiver is closed and the prompt can be tried once more with a smaller
+// context.
+pub struct LlmWatchdog {
+    source: LlmSource,
+    timeout: Duration, // zero is off
+    last_token: Option<Instant>,
+    tokens: usize,
+    retry: Option<(LlmService, RetryRequest)>,
+    stalled: bool,
+}
+
+impl LlmWatchdog {
+    pub fn new(source: LlmSource, timeout_secs: u64) -> Self {
+        LlmWatchdog {
+            source,
+            timeout: Duration::from_secs(timeout_secs),
+            last_token: None,
+            tokens: 0,
+            retry: None,
+            stalled: false,
+        }
+    }
+
+    // Try the request once more when the answer stalls before its first token
+    pub fn with_retry(mut self, service: &LlmService, retry: RetryRequest) -> Self {
+        self.retry = Some((service.clone(), retry));
+        self
+    }
+
+    // Whether the last answer was given up on
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+
+    // Quiet since the generation started or the last token, None while it hasn't started
+    fn quiet_for(&self) -> Option<Duration> {
+        let started = GENERATING
+            .lock()
+            .unwrap()
+            .get(&self.source)
+            .map(|generation| generation.started);
+        let since = match (started, self.last_token) {
+            (Some(started), Some(last)) => Some(started.max(last)),
+            (started, last) => started.or(last),
+        };
+        since.map(|since| since.elapsed())
+    }
+
+    // The next batch of the answer, None when it is done or stalled
+    pub async fn recv(&mut self, receiver: &mut Receiver<TokenBatch>) -> Option<TokenBatch> {
+        if self.timeout.is_zero() {
+            return receiver.recv().await;
+        }
+        loop {
+            match tokio::time::timeout(WATCHDOG_TICK, receiver.recv()).await {
+                Ok(batch) => {
+                    if let Some(batch) = &batch {
+                        self.last_token = Some(Instant::now());
+                        self.tokens += batch.len();
+                    }
+                    return batch;
+                }
+                Err(_) => {
+                    if self.quiet_for().is_none_or(|quiet| quiet < self.timeout) {
+                        continue;
+                    }
+                }
+            }
+
+            receiver.close();
+            cancel_generation(self.source);
+            let retry = match self.retry.take() {
+                Some(retry) if self.tokens == 0 => Some(retry),
+                _ => None,
+            };
+            error!(
+                "LLM {:?} answer stalled, no token for {} seconds after {} tokens{}",
+                self.source,
+                self.timeout.as_secs(),
+                self.tokens,
+                if retry.is_some() {
+                    ", retrying with a smaller context"
+                } else {
+                    ""
+                }
+            );
+            log_event(
+                "pipeline",
+                "llm_stalled",
+                json!({
+                    "source": format!("{:?}", self.source).to_lowercase(),
+                    "timeout_secs": self.timeout.as_secs(),
+                    "tokens": self.tokens,
+                    "retry": retry.is_some(),
+                }),
+            );
+
+            let Some((service, retry)) = retry else {
+                self.stalled = true;
+                return None;
+            };
+            let (sender, new_receiver) = mpsc::channel(32768);
+            *receiver = new_receiver;
+            // quiet counts from the retry, the stalled generation gives way to it
+            self.last_token = Some(Instant::now());
+            if let Err(e) = service.submit(retry(sender)).await {
+                error!("Failed to retry the {:?} answer: {}", self.source, e);
+                self.stalled = true;
+                return None;
+            }
+        }
+    }
+}
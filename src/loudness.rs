@@ -0,0 +1,401 @@
+/*
+ * loudness.rs
+ * -----------
+ * EBU R128 loudness and true peak of the monitored service's audio. The decoded program
+ * audio is K-weighted and measured in 100 ms steps for the momentary (400 ms), short-term
+ * (3 s) and gated integrated loudness, the true peak is found with 4x oversampling. The
+ * latest reading and whether it is within the target are kept for the stats and alerts.
+*/
+
+use crate::args::Args;
+use crate::event_log::log_event;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::Mutex;
+
+static PROGRAM_LOUDNESS: Lazy<Mutex<Option<LoudnessStatus>>> = Lazy::new(|| Mutex::new(None));
+
+// Loudness of silence, reported when there is nothing to measure
+const SILENCE_LUFS: f64 = -120.0;
+// Blocks under this loudness are left out of the integrated loudness
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+// and so are those this far under the loudness of the blocks over the absolute gate
+const RELATIVE_GATE_LU: f64 = 10.0;
+// Histogram of the block loudness from the absolute gate up in 0.1 LU steps
+const HISTOGRAM_STEP: f64 = 0.1;
+const HISTOGRAM_BINS: usize = 1000;
+// Sub-blocks of 100 ms in the momentary and short-term windows
+const MOMENTARY_BLOCKS: usize = 4;
+const SHORT_TERM_BLOCKS: usize = 30;
+// Taps on each side of the interpolated sample for the true peak
+const TRUE_PEAK_TAPS: usize = 6;
+const OVERSAMPLING: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LoudnessReading {
+    pub momentary_lufs: f64,
+    pub short_term_lufs: f64,
+    pub integrated_lufs: f64,
+    pub true_peak_dbtp: f64,
+    pub seconds: f64, // audio measured
+}
+
+// Reading with the compliance of the monitored service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoudnessStatus {
+    pub pid: u16,
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub reading: LoudnessReading,
+    pub target_lufs: f64,
+    pub loudness_compliant: bool,
+    pub true_peak_compliant: bool,
+}
+
+pub fn program_loudness() -> Option<LoudnessStatus> {
+    PROGRAM_LOUDNESS.lock().unwrap().clone()
+}
+
+// Biquad filter in direct form II transposed
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad { b, a, z: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+// The two stages of the K-weighting filter of ITU-R BS.1770 for the sample rate
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    // high shelf for the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    // high pass of the revised low frequency B-curve
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+// Weight of a channel in the sum, the surrounds count more and the LFE not at all
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+fn lufs(energy: f64) -> f64 {
+    if energy <= 0.0 {
+        SILENCE_LUFS
+    } else {
+        (-0.691 + 10.0 * energy.log10()).max(SILENCE_LUFS)
+    }
+}
+
+fn dbtp(peak: f64) -> f64 {
+    if peak <= 0.0 {
+        SILENCE_LUFS
+    } else {
+        (20.0 * peak.log10()).max(SILENCE_LUFS)
+    }
+}
+
+// Windowed sinc filters of the oversampling phases
+fn interpolation_phases() -> Vec<Vec<f64>> {
+    let taps = TRUE_PEAK_TAPS * 2;
+    (1..OVERSAMPLING)
+        .map(|phase| {
+            let offset = phase as f64 / OVERSAMPLING as f64;
+            (0..taps)
+                .map(|tap| {
+                    let x = tap as f64 - (TRUE_PEAK_TAPS as f64 - 1.0) - offset;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    let window = 0.5 + 0.5 * (PI * x / (TRUE_PEAK_TAPS as f64 + 1.0)).cos();
+                    sinc * window
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub struct LoudnessMeter {
+    sample_rate: u32,
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    block_energy: Vec<f64>, // weighted sum of squares of each channel in the current block
+    block_samples: usize,
+    samples_per_block: usize,
+    blocks: VecDeque<Vec<f64>>,  // mean squares of the last 100 ms blocks
+    histogram: Vec<(u64, f64)>,  // count and energy sum of the gating blocks by loudness
+    history: Vec<VecDeque<f64>>, // last samples of each channel for the interpolation
+    phases: Vec<Vec<f64>>,
+    true_peak: f64,
+    total_samples: u64,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        LoudnessMeter {
+            sample_rate,
+            channels,
+            filters: vec![k_weighting(sample_rate); channels],
+            block_energy: vec![0.0; channels],
+            block_samples: 0,
+            samples_per_block: (sample_rate as usize / 10).max(1),
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+            histogram: vec![(0, 0.0); HISTOGRAM_BINS],
+            history: vec![VecDeque::from(vec![0.0; TRUE_PEAK_TAPS * 2]); channels],
+            phases: interpolation_phases(),
+            true_peak: 0.0,
+            total_samples: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    // Measure interleaved samples
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let weighted = high_pass.process(shelf.process(sample));
+                self.block_energy[channel] += weighted * weighted;
+                self.true_peak = self.true_peak.max(self.interpolated_peak(channel, sample));
+            }
+            self.block_samples += 1;
+            self.total_samples += 1;
+            if self.block_samples == self.samples_per_block {
+                self.finish_block();
+            }
+        }
+    }
+
+    // Highest of the sample and the samples interpolated between it and the one before it
+    fn interpolated_peak(&mut self, channel: usize, sample: f64) -> f64 {
+        let history = &mut self.history[channel];
+        history.pop_front();
+        history.push_back(sample);
+        let mut peak = sample.abs();
+        for phase in &self.phases {
+            let value: f64 = history.iter().zip(phase.iter()).map(|(x, h)| x * h).sum();
+            peak = peak.max(value.abs());
+        }
+        peak
+    }
+
+    fn finish_block(&mut self) {
+        let block: Vec<f64> = self
+            .block_energy
+            .iter()
+            .map(|energy| energy / self.samples_per_block as f64)
+            .collect();
+        self.block_energy
+            .iter_mut()
+            .for_each(|energy| *energy = 0.0);
+        self.block_samples = 0;
+        if self.blocks.len() == SHORT_TERM_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(block);
+
+        // gating blocks are 400 ms long every 100 ms
+        if self.blocks.len() >= MOMENTARY_BLOCKS {
+            let energy = self.energy(MOMENTARY_BLOCKS);
+            let loudness = lufs(energy);
+            if loudness > ABSOLUTE_GATE_LUFS {
+                let bin = (((loudness - ABSOLUTE_GATE_LUFS) / HISTOGRAM_STEP) as usize)
+                    .min(HISTOGRAM_BINS - 1);
+                self.histogram[bin].0 += 1;
+                self.histogram[bin].1 += energy;
+            }
+        }
+    }
+
+    // Weighted energy of the last blocks
+    fn energy(&self, blocks: usize) -> f64 {
+        let blocks: Vec<&Vec<f64>> = self.blocks.iter().rev().take(blocks).collect();
+        if blocks.is_empty() {
+            return 0.0;
+        }
+        (0..self.channels)
+            .map(|channel| {
+                let mean =
+                    blocks.iter().map(|block| block[channel]).sum::<f64>() / blocks.len() as f64;
+                channel_weight(channel, self.channels) * mean
+            })
+            .sum()
+    }
+
+    // Integrated loudness of the blocks over the absolute and relative gates
+    fn integrated(&self) -> f64 {
+        let gated_energy = |from_bin: usize| {
+            let (count, energy) = self.histogram[from_bin..]
+                .iter()
+                .fold((0u64, 0.0), |(count, energy), bin| {
+                    (count + bin.0, energy + bin.1)
+                });
+            (count > 0).then(|| energy / count as f64)
+        };
+        let Some(absolute) = gated_energy(0) else {
+            return SILENCE_LUFS;
+        };
+        let relative_gate = lufs(absolute) - RELATIVE_GATE_LU;
+        let from_bin = ((relative_gate - ABSOLUTE_GATE_LUFS).max(0.0) / HISTOGRAM_STEP) as usize;
+        gated_energy(from_bin.min(HISTOGRAM_BINS - 1)).map_or(SILENCE_LUFS, lufs)
+    }
+
+    pub fn reading(&self) -> LoudnessReading {
+        LoudnessReading {
+            momentary_lufs: lufs(self.energy(MOMENTARY_BLOCKS)),
+            short_term_lufs: lufs(self.energy(SHORT_TERM_BLOCKS)),
+            integrated_lufs: self.integrated(),
+            true_peak_dbtp: dbtp(self.true_peak),
+            seconds: self.total_samples as f64 / self.sample_rate.max(1) as f64,
+        }
+    }
+}
+
+// Targets of the loudness compliance
+#[derive(Debug, Clone, Copy)]
+pub struct LoudnessLimits {
+    pub target_lufs: f64,
+    pub tolerance_lu: f64, // 0 is no loudness alerts
+    pub max_true_peak_dbtp: f64,
+    pub min_seconds: f64, // audio measured before the integrated loudness is judged
+}
+
+impl LoudnessLimits {
+    pub fn new(
+        target_lufs: f64,
+        tolerance_lu: f64,
+        max_true_peak_dbtp: f64,
+        min_seconds: f64,
+    ) -> Self {
+        LoudnessLimits {
+            target_lufs,
+            tolerance_lu,
+            max_true_peak_dbtp,
+            min_seconds,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        LoudnessLimits::new(
+            args.loudness_target_lufs as f64,
+            args.loudness_tolerance_lu as f64,
+            args.loudness_max_true_peak_dbtp as f64,
+            args.loudness_min_secs as f64,
+        )
+    }
+}
+
+impl Default for LoudnessLimits {
+    fn default() -> Self {
+        // EBU R128 broadcast delivery
+        LoudnessLimits::new(-23.0, 1.0, -1.0, 30.0)
+    }
+}
+
+// Publish the reading of the service for the stats, the alerts are logged when the
+// compliance changes
+pub fn update_program_loudness(
+    pid: u16,
+    codec: &str,
+    meter: &LoudnessMeter,
+    limits: &LoudnessLimits,
+) -> LoudnessStatus {
+    let reading = meter.reading();
+    let judged = limits.tolerance_lu > 0.0 && reading.seconds >= limits.min_seconds;
+    let loudness_compliant = !judged
+        || reading.integrated_lufs <= SILENCE_LUFS
+        || (reading.integrated_lufs - limits.target_lufs).abs() <= limits.tolerance_lu;
+    let true_peak_compliant = reading.true_peak_dbtp <= limits.max_true_peak_dbtp;
+    let status = LoudnessStatus {
+        pid,
+        codec: codec.to_string(),
+        sample_rate: meter.sample_rate(),
+        channels: meter.channels(),
+        reading,
+        target_lufs: limits.target_lufs,
+        loudness_compliant,
+        true_peak_compliant,
+    };
+
+    let mut current = PROGRAM_LOUDNESS.lock().unwrap();
+    let (was_loudness, was_true_peak) = current
+        .as_ref()
+        .filter(|previous| previous.pid == pid)
+        .map_or((true, true), |previous| {
+            (previous.loudness_compliant, previous.true_peak_compliant)
+        });
+    if was_loudness && !loudness_compliant {
+        warn!(
+            "Program audio PID {} integrated loudness {:.1} LUFS is off the target {:.1} LUFS",
+            pid, reading.integrated_lufs, limits.target_lufs
+        );
+    }
+    if was_true_peak && !true_peak_compliant {
+        warn!(
+            "Program audio PID {} true peak {:.1} dBTP is over {:.1} dBTP",
+            pid, reading.true_peak_dbtp, limits.max_true_peak_dbtp
+        );
+    }
+    if was_loudness != loudness_compliant || was_true_peak != true_peak_compliant {
+        log_event("analyzer", "loudness", json!(status));
+    }
+    *current = Some(status.clone());
+    status
+}
@@ -17,30 +17,83 @@
 
 use clap::Parser;
 use ctrlc;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use rsllm::ad_break::{ad_break, AdBreakSchedule};
 use rsllm::args::Args;
-use rsllm::candle_gemma::gemma;
-use rsllm::candle_mistral::mistral;
-use rsllm::clean_tts_input;
-use rsllm::count_tokens;
+use rsllm::audio::audio_duration_ms;
+use rsllm::bumpers::Bumpers;
+use rsllm::capabilities::print_version;
+#[cfg(feature = "program_audio")]
+use rsllm::captions::spawn_captions;
+use rsllm::chapters::{ChapterDetector, ChapterWriter};
+use rsllm::character_sheet::init_character_sheet;
+use rsllm::checkpoint::{Checkpointer, ShowCheckpoint};
+use rsllm::control_api::{control_api, ControlApiState};
+use rsllm::current_unix_timestamp_ms;
+use rsllm::dry_run::dry_run;
+use rsllm::emotion::emotion_tag_instructions;
+use rsllm::event_log::{init_event_log, log_event};
+use rsllm::event_publish::EventPublisher;
+use rsllm::external_apis::{no_external_apis, set_no_external_apis};
+use rsllm::get_system_stats;
 use rsllm::handle_long_string;
-use rsllm::network_capture::{network_capture, NetworkCapture};
-use rsllm::openai_api::{format_messages_for_llm, stream_completion, Message, OpenAIRequest};
-#[cfg(feature = "ndi")]
-use rsllm::pipeline::send_to_ndi;
-use rsllm::pipeline::{process_image, process_speech, MessageData, ProcessedData};
-use rsllm::stable_diffusion::{SDConfig, StableDiffusionVersion};
-use rsllm::stream_data::{
-    get_pid_map, identify_video_pid, is_mpegts_or_smpte2110, parse_and_store_pat, process_packet,
-    update_pid_map, Codec, PmtInfo, StreamData, Tr101290Errors, PAT_PID,
+use rsllm::health_report::spawn_health_reports;
+use rsllm::history::{parse_rewind_command, HistoryTree};
+use rsllm::image_queue::ImageQueue;
+#[cfg(feature = "kafka")]
+use rsllm::kafka_sink::KafkaSink;
+use rsllm::keywords::EntityKind;
+use rsllm::liveness::{expect_from_args, mark_active, Subsystem};
+use rsllm::llm_routing::{LlmRoutes, LlmTask};
+use rsllm::llm_service::{LlmRequest, LlmService, LlmSource, LlmWatchdog};
+use rsllm::network_capture::{network_capture, CapturedPacket};
+use rsllm::network_prompt::NetworkPromptBuilder;
+use rsllm::openai_api::{format_messages_for_llm, Message};
+use rsllm::output::OutputSinks;
+use rsllm::overlay::{ticker_push, ticker_set};
+use rsllm::persona::{parse_persona_command, PersonaRegistry};
+use rsllm::pipeline::{
+    apply_emotion, apply_keywords, apply_pacing, tts_default_sample_rate, MessageData, Priority,
+    ProcessedData, AUDIO_LEAD_SILENCE_MS,
 };
-use rsllm::stream_data::{process_mpegts_packet, process_smpte2110_packet};
+use rsllm::podcast::apply_podcast_mode;
+use rsllm::prefetch::Prefetcher;
+use rsllm::probe::{network_capture_config, StreamAnalyzer};
+use rsllm::probe_link::{probe_listener, site_stats};
+#[cfg(feature = "program_audio")]
+use rsllm::program_audio::ProgramAudioFeed;
+use rsllm::prompt_guard::PromptGuard;
+use rsllm::prompts::{apply_prompts_file, spawn_sighup_reload, RELOAD_COMMAND};
+use rsllm::response_cache::{embed, replay_tokens, ResponseCache};
+use rsllm::run_record::{IterationRecord, RunRecorder, SamplingRecord};
+use rsllm::runtime::{
+    build_sd_config, pipeline_channel, pipeline_enabled, KeyGuard, NextOutput, PipelineCancel,
+    PresentationClock, ProcessedDataStore,
+};
+use rsllm::sampling::SamplingConfig;
+use rsllm::secrets::{init_secrets, secret};
+use rsllm::segmenter::{create_segmenter, Segment};
+use rsllm::session_stats::{
+    init_logger, log_session_summary, record_audio, record_generation, record_paragraph,
+};
+use rsllm::shutdown::{restart_process, ShutdownAction, ShutdownConfig, ShutdownSchedule};
+use rsllm::stats_qa::StatsAnalyst;
+use rsllm::stats_schema::{StatsMessage, StreamStats};
+#[cfg(feature = "thumbnails")]
+use rsllm::stream_data::Codec;
+use rsllm::stream_state::{
+    set_stream_chapter, set_stream_paragraph, set_stream_persona, set_stream_topic,
+};
+use rsllm::supervisor::{supervise, RestartPolicy};
+#[cfg(feature = "thumbnails")]
+use rsllm::thumbnails::ThumbnailFeed;
+use rsllm::token_budget::{fit_messages, BudgetPart, TokenBudget, MAIN_PARTS};
+use rsllm::token_stream::{TokenBatch, TokenBatching};
+use rsllm::ts_generator::{ts_generator_capture, TsGeneratorConfig};
 use rsllm::twitch_client::daemon as twitch_daemon;
-use rsllm::{current_unix_timestamp_ms, hexdump, hexdump_ascii};
-use rsllm::{get_stats_as_json, StatsType};
+use rsllm::twitch_helix::spawn_stream_info_updater;
+use rsllm::ApiError;
 use serde_json::{self, json};
-use std::collections::HashMap;
-use std::env;
 use std::io::Write;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -57,11 +110,17 @@ async fn main() {
     // Read .env file
     dotenv::dotenv().ok();
 
-    // Initialize logging
-    let _ = env_logger::try_init();
+    // Initialize logging, errors and warnings are counted for the session summary
+    init_logger();
 
     // Parse command line arguments
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if args.version {
+        print_version(&args);
+        return;
+    }
+    // audio only, the podcast sink takes the place of the images
+    apply_podcast_mode(&mut args);
 
     // Create an atomic bool to track if Ctrl+C is pressed
     let running_ctrlc = Arc::new(AtomicBool::new(true));
@@ -100,313 +159,741 @@ async fn main() {
         }
     }
 
-    let system_message = Message {
+    // Local only mode, API requests to other hosts are refused from here on
+    set_no_external_apis(args.no_external_apis);
+    if no_external_apis() {
+        info!("External APIs are disabled, only servers on this host are used.");
+        if args.use_openai || args.oai_tts || args.twitch_client {
+            error!("--use-openai, --oai-tts and --twitch-client need external APIs, which are disabled with --no-external-apis.");
+            std::process::exit(1);
+        }
+    }
+
+    // tokens and keys from the providers, redacted in the logs from here on
+    if let Err(e) = init_secrets(&args) {
+        error!("Error reading the secrets: {}", e);
+        std::process::exit(1);
+    }
+
+    // validate the configuration without starting the show
+    if args.dry_run {
+        let passed = dry_run(&args).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    // Structured event log for downstream tools
+    if let Err(e) = init_event_log(&args) {
+        error!("Error opening event log {}: {}", args.event_log, e);
+        std::process::exit(1);
+    }
+    // and the same events with the stats of each iteration on NATS or MQTT
+    let event_publisher = EventPublisher::from_args(&args);
+    // and the PID, program and event records kept in Kafka
+    #[cfg(feature = "kafka")]
+    let _kafka_sink = KafkaSink::from_args(&args);
+
+    // Prompts from the --prompts-file over the command line, reloaded with SIGHUP or /reload
+    let prompt_args = match apply_prompts_file(&args) {
+        Ok(prompt_args) => prompt_args,
+        Err(e) => {
+            error!("Error loading prompts: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut default_query = prompt_args.query.clone();
+
+    // Persona registry, the current persona provides the system prompt, voice, image prompt and greeting
+    let mut persona_registry = match PersonaRegistry::new(&prompt_args) {
+        Ok(registry) => registry,
+        Err(e) => {
+            error!("Error loading personas: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // the host looks the same in every image
+    if let Err(e) = init_character_sheet(&args) {
+        error!("Error loading the character sheet: {}", e);
+        std::process::exit(1);
+    }
+    // Checkpoint of the show to resume, the persona carries over unless one is asked for
+    let resume = if args.checkpoint_file.is_empty() {
+        None
+    } else {
+        match ShowCheckpoint::load(&args.checkpoint_file) {
+            Ok(resume) => resume,
+            Err(e) => {
+                error!("{}, starting a new show", e);
+                None
+            }
+        }
+    };
+    if let Some(checkpoint) = resume.as_ref() {
+        if args.persona.is_empty()
+            && !checkpoint.persona.is_empty()
+            && persona_registry.select(&checkpoint.persona).is_none()
+        {
+            warn!("Persona {} of the checkpoint not found", checkpoint.persona);
+        }
+    }
+    let mut persona = persona_registry.current().clone();
+    info!("Using persona {}", persona.name);
+    set_stream_persona(&persona.name);
+
+    // ask the LLM to tag each paragraph with its emotion
+    let emotion_instructions = if args.emotion && args.emotion_tags {
+        emotion_tag_instructions()
+    } else {
+        String::new()
+    };
+
+    // packets and chat are wrapped as data the LLM is told not to follow
+    let prompt_guard = PromptGuard::from_args(&args);
+    let system_instructions = format!("{}{}", emotion_instructions, prompt_guard.instructions());
+
+    let mut system_message = Message {
         role: "system".to_string(),
-        content: args.system_prompt.to_string(),
+        content: format!("{}{}", persona.system_prompt, system_instructions),
     };
 
-    let processed_data_store: Arc<Mutex<HashMap<usize, ProcessedData>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    // Processed paragraphs waiting for output, bounded so a stalled output can't grow it forever
+    let processed_data_store = Arc::new(Mutex::new(ProcessedDataStore::new(
+        args.output_buffer_size,
+        Duration::from_millis(args.output_max_wait_ms),
+    )));
+
+    // Headlines for the ticker, the control api can replace them later
+    if !args.ticker_headlines.is_empty() {
+        match std::fs::read_to_string(&args.ticker_headlines) {
+            Ok(contents) => ticker_set(
+                "headlines",
+                contents.lines().map(|line| line.to_string()).collect(),
+            ),
+            Err(e) => error!(
+                "Failed to read ticker headlines {}: {}",
+                args.ticker_headlines, e
+            ),
+        }
+    }
+
+    // Control API commands channel
+    let (control_tx, mut control_rx) = mpsc::channel::<String>(100);
+    spawn_sighup_reload(control_tx.clone());
+    let persona_names = Arc::new(Mutex::new(persona_registry.names()));
+    let history_tree = Arc::new(Mutex::new(HistoryTree::new(args.history_checkpoints)));
+    let running_processor_api = Arc::new(AtomicBool::new(true));
+    if args.api_server {
+        // the subsystems reported by /healthz
+        expect_from_args(&args);
+        let api_address = format!("{}:{}", args.api_host, args.api_port);
+        let api_state = ControlApiState {
+            command_tx: control_tx.clone(),
+            personas: persona_names.clone(),
+            processed_data_store: processed_data_store.clone(),
+            stats_analyst: Arc::new(Mutex::new(StatsAnalyst::new())),
+            history: history_tree.clone(),
+            args: args.clone(),
+        };
+        let running_processor_api_clone = running_processor_api.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control_api(api_address, running_processor_api_clone, api_state).await {
+                error!("Control API error: {}", e);
+            }
+        });
+    }
+
+    // Reports of the remote probes, their stats go to the LLM with the local ones
+    let running_probe_link = Arc::new(AtomicBool::new(true));
+    if !args.probe_listen.is_empty() {
+        let probe_address = args.probe_listen.clone();
+        let probe_token = args.probe_token.clone();
+        let probe_max_sites = args.probe_max_sites;
+        let running_probe_link_clone = running_probe_link.clone();
+        tokio::spawn(async move {
+            if let Err(e) = probe_listener(
+                probe_address,
+                probe_token,
+                probe_max_sites,
+                running_probe_link_clone,
+            )
+            .await
+            {
+                error!("Probe link error: {}", e);
+            }
+        });
+    }
+
+    // Periodic health reports, read out by the main loop
+    let (health_report_tx, mut health_report_rx) = mpsc::channel::<String>(10);
+    let running_health_report = Arc::new(AtomicBool::new(true));
+    if args.health_report_interval > 0 {
+        spawn_health_reports(
+            args.clone(),
+            health_report_tx,
+            running_health_report.clone(),
+        );
+    }
 
     // Channels for image and speech tasks
-    let (pipeline_task_sender, mut pipeline_task_receiver) =
-        mpsc::channel::<MessageData>(args.pipeline_concurrency);
+    let pipeline_cancel = PipelineCancel::new();
+    let (mut pipeline_dispatcher, pipeline_receiver) = pipeline_channel(
+        args.pipeline_concurrency,
+        processed_data_store.clone(),
+        pipeline_cancel.clone(),
+    );
+    if let Some(checkpoint) = resume.as_ref() {
+        let resume_count = checkpoint.resume_count();
+        processed_data_store.lock().await.resume_at(resume_count);
+    }
+    let checkpointer = if args.checkpoint_file.is_empty() {
+        None
+    } else {
+        let checkpointer = Checkpointer::new(
+            &args.checkpoint_file,
+            pipeline_dispatcher.total_paragraph_count().await,
+            resume
+                .as_ref()
+                .map_or(0, |checkpoint| checkpoint.next_pts_ms),
+        );
+        pipeline_dispatcher.set_checkpointer(checkpointer.clone());
+        Some(checkpointer)
+    };
+    let run_recorder = RunRecorder::from_args(&args);
+    if let Some(recorder) = run_recorder.as_ref() {
+        pipeline_dispatcher.set_recorder(recorder.clone());
+    }
 
-    // Channel to signal NDI is done
-    #[cfg(feature = "ndi")]
-    let (ndi_done_tx, mut ndi_done_rx) = mpsc::channel::<()>(1);
+    // Channel to signal the output is done
+    let (output_done_tx, mut output_done_rx) = mpsc::channel::<()>(1);
 
     let pipeline_sem = Arc::new(Semaphore::new(args.pipeline_concurrency));
+    // Image jobs are deduped and limited separately from the pipeline tasks
+    let image_queue = Arc::new(ImageQueue::new(
+        args.sd_queue_concurrency,
+        args.sd_queue_cache,
+    ));
+    // media of the outro and stinger rendered ahead of the pipeline
+    let prefetcher = Arc::new(Prefetcher::from_args(&args, Arc::clone(&image_queue)));
     // Pipeline processing task for image and speech together as a single task
-    // Pipeline processing task for image and speech together as a single task
+    let running_pipeline = Arc::new(AtomicBool::new(true));
     let pipeline_processing_task = {
         let pipeline_sem = Arc::clone(&pipeline_sem);
+        let image_queue = Arc::clone(&image_queue);
+        let prefetcher = Arc::clone(&prefetcher);
         let processed_data_store = processed_data_store.clone();
+        let pipeline_cancel = pipeline_cancel.clone();
         // create a black frame image in the vec[] to use initially as last_images
         // Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>
         let black_frame = image::ImageBuffer::from_fn(1920, 1080, |_, _| image::Rgb([0, 0, 0]));
         let last_images = Arc::new(Mutex::new(vec![black_frame.clone()]));
-        tokio::spawn(async move {
-            while let Some(message_data) = pipeline_task_receiver.recv().await {
-                let processed_data_store = processed_data_store.clone();
-                let message_data_clone = message_data.clone();
+        // chapter markers and transcript, paragraphs arrive here in order
+        let chapters_start_ms = current_unix_timestamp_ms().unwrap_or(0);
+        let chapter_detector = Arc::new(Mutex::new(ChapterDetector::new(
+            args.chapter_threshold,
+            args.chapter_min_paragraphs,
+            chapters_start_ms,
+        )));
+        let chapter_writer = Arc::new(Mutex::new(ChapterWriter::new(
+            &args.transcript_file,
+            &args.hls_chapters_file,
+            chapters_start_ms,
+        )));
+        // the queue, last images and chapters carry over to a restarted pipeline task
+        let pipeline_receiver = Arc::new(Mutex::new(pipeline_receiver));
+        let running_pipeline = running_pipeline.clone();
+        supervise(
+            "pipeline",
+            RestartPolicy::from_args(&args),
+            running_pipeline.clone(),
+            move || {
                 let pipeline_sem = Arc::clone(&pipeline_sem);
-                let last_images_clone = Arc::clone(&last_images);
-                // channels to pass images back for the last_images vec
-                let (image_tx, mut image_rx) =
-                    mpsc::channel::<Vec<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>>(100);
-                let image_task = tokio::spawn(async move {
-                    let _permit = pipeline_sem
-                        .acquire()
-                        .await
-                        .expect("failed to acquire pipeline semaphore permit");
-
-                    // Create a new black_frame for each iteration
-                    let black_frame =
-                        image::ImageBuffer::from_fn(1920, 1080, |_, _| image::Rgb([0, 0, 0]));
-
-                    // check length of message_data, if it is less than 80 characters, use last_images
-                    /*if message_data_clone.paragraph.len() < 80 {
-                    let last_images = last_images_clone.lock().await;
-                    let images = last_images.clone();
-                    }*/
-
-                    // process_image returns an empty vec if there are no images
-                    let mut images = process_image(message_data_clone.clone()).await;
-
-                    // check if image is all black
-                    let mut all_black = true;
-                    for img in images.iter() {
-                        for pixel in img.pixels() {
-                            if pixel[0] != 0 || pixel[1] != 0 || pixel[2] != 0 {
-                                all_black = false;
-                                break;
-                            }
+                let image_queue = Arc::clone(&image_queue);
+                let prefetcher = Arc::clone(&prefetcher);
+                let processed_data_store = processed_data_store.clone();
+                let pipeline_cancel = pipeline_cancel.clone();
+                let last_images = Arc::clone(&last_images);
+                let chapter_detector = Arc::clone(&chapter_detector);
+                let chapter_writer = Arc::clone(&chapter_writer);
+                let pipeline_receiver = Arc::clone(&pipeline_receiver);
+                let running_pipeline = running_pipeline.clone();
+                async move {
+                    let mut pipeline_receiver = pipeline_receiver.lock().await;
+                    while let Some(mut message_data) = pipeline_receiver.recv().await {
+                        if pipeline_cancel.is_cancelled(&message_data) {
+                            debug!(
+                                "Pipeline processing task: Dropping cancelled message {}",
+                                message_data.paragraph_count
+                            );
+                            processed_data_store
+                                .lock()
+                                .await
+                                .cancel(message_data.paragraph_count);
+                            continue;
                         }
-                    }
-                    if all_black {
-                        std::io::stdout().flush().unwrap();
-                        println!("");
-                        log::error!("Image is all black, skipping");
-                    }
 
-                    // Check if the processed images are empty
-                    if images.is_empty() || all_black {
-                        // If the processed images are empty, use the last_images
-                        let last_images_guard = last_images_clone.lock().await;
-                        if !last_images_guard.is_empty() {
-                            images = last_images_guard.clone();
-                            std::io::stdout().flush().unwrap();
-                            println!("");
-                            log::error!("Images is empty, using last images");
-                        } else {
-                            println!("");
-                            log::error!("Last Images is empty, using black image");
-                            images = vec![black_frame];
+                        // strip emotion tags and set the mood for the speech and image
+                        apply_emotion(&mut message_data);
+                        apply_keywords(&mut message_data);
+                        apply_pacing(&mut message_data);
+                        record_paragraph();
+                        log_event(
+                            "pipeline",
+                            "paragraph",
+                            json!({
+                                "output_id": message_data.output_id,
+                                "paragraph_count": message_data.paragraph_count,
+                                "text": message_data.paragraph,
+                                "emotion": message_data.emotion.map(|emotion| emotion.to_string()),
+                                "sentiment": message_data.sentiment,
+                                "keywords": message_data.keywords,
+                                "programs": message_data
+                                    .keywords
+                                    .as_ref()
+                                    .map(|keywords| keywords.names(EntityKind::Program)),
+                                "places": message_data
+                                    .keywords
+                                    .as_ref()
+                                    .map(|keywords| keywords.names(EntityKind::Place)),
+                                "high_priority": message_data.priority == Priority::High,
+                            }),
+                        );
+
+                        // greetings and shutdown messages are not part of the story
+                        let mut chapter = None;
+                        if message_data.priority == Priority::Normal
+                            && !message_data.last_message
+                            && !message_data.shutdown
+                        {
+                            if message_data.args.chapters {
+                                chapter = chapter_detector
+                                    .lock()
+                                    .await
+                                    .process(&message_data.paragraph, message_data.paragraph_count);
+                            }
+                            set_stream_paragraph(
+                                message_data.paragraph_count,
+                                &message_data.paragraph,
+                                &message_data.sd_config.prompt,
+                            );
+                            if let Some(chapter) = chapter.as_ref() {
+                                set_stream_chapter(&chapter.title);
+                                log_event(
+                                    "pipeline",
+                                    "chapter",
+                                    json!({
+                                        "index": chapter.index,
+                                        "title": chapter.title,
+                                        "offset": chapter.offset_string(),
+                                        "paragraph_count": message_data.paragraph_count,
+                                    }),
+                                );
+                                info!(
+                                    "Chapter {} at {}: {}",
+                                    chapter.index,
+                                    chapter.offset_string(),
+                                    chapter.title
+                                );
+                                chapter_writer.lock().await.write_chapter(chapter);
+                            }
+                            chapter_writer
+                                .lock()
+                                .await
+                                .write_paragraph(&message_data.paragraph);
                         }
-                    } else {
-                        // If the processed images are not empty, update the last_images
-                        let mut last_images_guard = last_images_clone.lock().await;
-                        *last_images_guard = images.clone();
-                    }
+                        let processed_data_store = processed_data_store.clone();
+                        let pipeline_cancel = pipeline_cancel.clone();
+                        let message_data_clone = message_data.clone();
+                        let pipeline_sem = Arc::clone(&pipeline_sem);
+                        let image_queue = Arc::clone(&image_queue);
+                        let prefetcher = Arc::clone(&prefetcher);
+                        let last_images_clone = Arc::clone(&last_images);
+                        // channels to pass images back for the last_images vec
+                        let (image_tx, mut image_rx) =
+                            mpsc::channel::<Vec<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>>(100);
+                        // a task that dies before storing the paragraph gives up its key
+                        let key_guard = KeyGuard::new(
+                            processed_data_store.clone(),
+                            message_data.paragraph_count,
+                        );
+                        let image_task = tokio::spawn(async move {
+                            let _permit = pipeline_sem
+                                .acquire()
+                                .await
+                                .expect("failed to acquire pipeline semaphore permit");
+
+                            // Create a new black_frame for each iteration
+                            let black_frame = image::ImageBuffer::from_fn(1920, 1080, |_, _| {
+                                image::Rgb([0, 0, 0])
+                            });
+
+                            // check length of message_data, if it is less than 80 characters, use last_images
+                            /*if message_data_clone.paragraph.len() < 80 {
+                            let last_images = last_images_clone.lock().await;
+                            let images = last_images.clone();
+                            }*/
+
+                            // partial images go to the output while the rest are still rendering
+                            let mut image_message = message_data_clone.clone();
+                            if image_message.args.sd_image_streaming
+                                && !image_message.args.nsfw_filter
+                            {
+                                let (frame_tx, mut frame_rx) = mpsc::unbounded_channel();
+                                image_message.sd_config.frame_tx = Some(frame_tx);
+                                let processed_data_store = processed_data_store.clone();
+                                let paragraph_count = image_message.paragraph_count;
+                                tokio::spawn(async move {
+                                    while let Some(image) = frame_rx.recv().await {
+                                        processed_data_store
+                                            .lock()
+                                            .await
+                                            .preview(paragraph_count, image);
+                                    }
+                                });
+                            }
+
+                            // the image queue returns an empty vec if there are no images
+                            let mut images = image_queue.generate(image_message).await;
+                            if !images.is_empty() {
+                                mark_active(Subsystem::Sd);
+                            }
+                            if pipeline_cancel.is_cancelled(&message_data_clone) {
+                                processed_data_store
+                                    .lock()
+                                    .await
+                                    .cancel(message_data_clone.paragraph_count);
+                                key_guard.release();
+                                return;
+                            }
 
-                    // send images to the image channel
-                    let _ = image_tx.send(images.clone()).await;
+                            // check if image is all black
+                            let mut all_black = true;
+                            for img in images.iter() {
+                                for pixel in img.pixels() {
+                                    if pixel[0] != 0 || pixel[1] != 0 || pixel[2] != 0 {
+                                        all_black = false;
+                                        break;
+                                    }
+                                }
+                            }
+                            if all_black {
+                                std::io::stdout().flush().unwrap();
+                                println!("");
+                                log::error!("Image is all black, skipping");
+                            }
+
+                            // Check if the processed images are empty
+                            if images.is_empty() || all_black {
+                                // If the processed images are empty, use the last_images
+                                let last_images_guard = last_images_clone.lock().await;
+                                if !last_images_guard.is_empty() {
+                                    images = last_images_guard.clone();
+                                    std::io::stdout().flush().unwrap();
+                                    println!("");
+                                    log::error!("Images is empty, using last images");
+                                } else {
+                                    println!("");
+                                    log::error!("Last Images is empty, using black image");
+                                    images = vec![black_frame];
+                                }
+                            } else {
+                                // If the processed images are not empty, update the last_images
+                                let mut last_images_guard = last_images_clone.lock().await;
+                                *last_images_guard = images.clone();
+                            }
 
-                    // update image cache images
-                    let speech_data = process_speech(message_data_clone.clone()).await;
-                    let mut store = processed_data_store.lock().await;
+                            // send images to the image channel
+                            let _ = image_tx.send(images.clone()).await;
 
-                    match store.entry(message_data_clone.paragraph_count) {
-                        std::collections::hash_map::Entry::Vacant(e) => {
-                            e.insert(ProcessedData {
-                                paragraph: message_data_clone.paragraph.clone(),
+                            // update image cache images
+                            // translate the speech and subtitles into their output languages
+                            let (subtitle_text, speech_data) =
+                                prefetcher.speech(message_data_clone.clone()).await;
+                            if !speech_data.is_empty() {
+                                mark_active(Subsystem::Tts);
+                            }
+                            let duration_ms = audio_duration_ms(
+                                &speech_data,
+                                tts_default_sample_rate(&message_data_clone.args),
+                            );
+                            record_audio(duration_ms);
+                            if pipeline_cancel.is_cancelled(&message_data_clone) {
+                                processed_data_store
+                                    .lock()
+                                    .await
+                                    .cancel(message_data_clone.paragraph_count);
+                                key_guard.release();
+                                return;
+                            }
+                            processed_data_store.lock().await.insert(ProcessedData {
+                                paragraph: subtitle_text,
                                 image_data: Some(images),
                                 audio_data: Some(speech_data),
                                 paragraph_count: message_data_clone.paragraph_count,
                                 subtitle_position: message_data_clone.subtitle_position.clone(),
                                 time_stamp: 0,
+                                duration_ms,
                                 shutdown: message_data_clone.shutdown.clone(),
                                 completed: true,
                                 last_message: message_data_clone.last_message.clone(),
+                                chapter,
+                                sentiment: message_data_clone.sentiment,
+                                requested_by: message_data_clone.requested_by.clone(),
                             });
-                        }
-                        std::collections::hash_map::Entry::Occupied(mut e) => {
-                            let entry = e.get_mut();
-                            entry.image_data = Some(images);
-                            entry.audio_data = Some(speech_data);
-                            entry.completed = true;
-                        }
-                    }
-                });
+                            key_guard.release();
+                        });
 
-                // wait for images and collect any in and put into the last_images vec
-                if let Some(images) = image_rx.recv().await {
-                    let mut last_images = last_images.lock().await;
-                    *last_images = images;
-                }
+                        // wait for images and collect any in and put into the last_images vec
+                        if let Some(images) = image_rx.recv().await {
+                            let mut last_images = last_images.lock().await;
+                            *last_images = images;
+                        }
 
-                // wait for the image task to finish
-                image_task.await.unwrap();
+                        // wait for the image task to finish
+                        image_task.await.unwrap();
 
-                // Check if this is the last message
-                if message_data.last_message {
-                    std::io::stdout().flush().unwrap();
-                    info!(
-                        "Pipeline processing task: Last message processed {}",
-                        message_data.paragraph_count
-                    );
-                }
+                        // Check if this is the last message
+                        if message_data.last_message {
+                            std::io::stdout().flush().unwrap();
+                            info!(
+                                "Pipeline processing task: Last message processed {}",
+                                message_data.paragraph_count
+                            );
+                        }
 
-                // check if shutdown is requested from the message shutdown flag
-                if message_data.shutdown {
-                    std::io::stdout().flush().unwrap();
-                    info!("Shutdown requested from message data for pipeline processing task.");
-                    break;
+                        // check if shutdown is requested from the message shutdown flag
+                        if message_data.shutdown {
+                            std::io::stdout().flush().unwrap();
+                            info!("Shutdown requested from message data for pipeline processing task.");
+                            running_pipeline.store(false, Ordering::SeqCst);
+                            break;
+                        }
+                    }
+                    Ok(())
                 }
-            }
-        })
+            },
+        )
     };
 
-    // NDI sync task
-    #[cfg(feature = "ndi")]
-    let processed_data_store_for_ndi = processed_data_store.clone();
-    #[cfg(feature = "ndi")]
-    let args_for_ndi = args.clone();
-
-    #[cfg(feature = "ndi")]
-    let running_processor_ndi = Arc::new(AtomicBool::new(true));
-    #[cfg(feature = "ndi")]
-    let running_processor_ndi_clone = running_processor_ndi.clone();
-    #[cfg(feature = "ndi")]
-    let ndi_sync_task = tokio::spawn(async move {
-        let mut current_key = 0;
-        let mut max_key = 0;
-
-        while running_processor_ndi_clone.load(Ordering::SeqCst) {
-            let mut data = {
-                let store = processed_data_store_for_ndi.lock().await;
-                store.get(&current_key).cloned()
-            };
+    // Output sync task, sends the paragraphs in order to the output sinks
+    let output_sinks = OutputSinks::from_args(&args);
+    let output_enabled = !output_sinks.is_empty();
+    let processed_data_store_for_output = processed_data_store.clone();
+    let args_for_output = args.clone();
+
+    let running_processor_output = Arc::new(AtomicBool::new(output_enabled));
+    let running_processor_output_clone = running_processor_output.clone();
+    // the presentation clock carries over to a restarted output task, the sinks are opened again
+    let mut presentation_clock = PresentationClock::new(AUDIO_LEAD_SILENCE_MS);
+    if let Some(checkpoint) = resume.as_ref() {
+        presentation_clock.resume_at(checkpoint.next_pts_ms);
+    }
+    let presentation_clock = Arc::new(Mutex::new(presentation_clock));
+    let mut first_output_sinks = Some(output_sinks);
+    let output_sync_task = supervise(
+        "output",
+        RestartPolicy::from_args(&args),
+        running_processor_output.clone(),
+        move || {
+            let mut output_sinks = first_output_sinks
+                .take()
+                .unwrap_or_else(|| OutputSinks::from_args(&args_for_output));
+            let processed_data_store_for_output = processed_data_store_for_output.clone();
+            let args_for_output = args_for_output.clone();
+            let running_processor_output_clone = running_processor_output_clone.clone();
+            let output_done_tx = output_done_tx.clone();
+            let presentation_clock = presentation_clock.clone();
+            let checkpointer = checkpointer.clone();
+            async move {
+                let mut presentation_clock = presentation_clock.lock().await;
+
+                while running_processor_output_clone.load(Ordering::SeqCst) {
+                    let (next_output, next_key, oldest_key, metrics) = {
+                        let mut store = processed_data_store_for_output.lock().await;
+                        let next_output = store.next_ready();
+                        (
+                            next_output,
+                            store.next_key(),
+                            store.oldest_key(),
+                            store.metrics(),
+                        )
+                    };
 
-            if let Some(ref mut data) = data {
-                if data.completed {
-                    // Update max_key if necessary
-                    max_key = max_key.max(data.paragraph_count);
+                    match next_output {
+                        NextOutput::Ready(mut data) => {
+                            presentation_clock.stamp(&mut data);
+                            presentation_clock.wait_for(data.time_stamp).await;
+
+                            // Check if this is the last message and send the output done signal
+                            if data.last_message {
+                                std::io::stdout().flush().unwrap();
+                                debug!(
+                                    "Output sync task: Last message {} processed, sending done signal.",
+                                    data.paragraph_count
+                                );
+                                // Send output done signal
+                                if let Err(e) = output_done_tx.send(()).await {
+                                    error!("Failed to send output done signal: {}", e);
+                                }
+                                std::io::stdout().flush().unwrap();
+                                debug!("Sent output done signal for {}.", data.paragraph_count);
+                            }
 
-                    // check if we are reset to paragraph count 1, if so, reset the max_key and current key back to 1 and set as last_message
-                    if data.paragraph_count == 0 && current_key > 0 {
-                        max_key = 0;
-                        current_key = 0;
-                        data.last_message = true;
-                    }
+                            debug!(
+                                "Output sync task: Sending message {} with {} buffered and {} pending, {} evicted {} skipped.",
+                                data.paragraph_count,
+                                metrics.buffered,
+                                metrics.pending,
+                                metrics.evicted,
+                                metrics.skipped
+                            );
 
-                    // Check if this is the last message and send the NDI done signal
-                    if data.last_message {
-                        std::io::stdout().flush().unwrap();
-                        debug!(
-                            "NDI sync task: Last message {} processed for key {}, sending done signal.",
-                            data.paragraph_count, current_key
-                        );
-                        // Send NDI done signal
-                        if let Err(e) = ndi_done_tx.send(()).await {
-                            error!("Failed to send NDI done signal: {}", e);
+                            // Send to the output sinks
+                            let shutdown = data.shutdown;
+                            let paragraph_count = data.paragraph_count;
+                            output_sinks.send(*data, &args_for_output).await;
+                            if let Some(checkpointer) = checkpointer.as_ref() {
+                                checkpointer.output(
+                                    paragraph_count,
+                                    oldest_key,
+                                    presentation_clock.next_pts_ms(),
+                                );
+                            }
+
+                            // SHUTDOWN Signal
+                            if shutdown {
+                                running_processor_output_clone.store(false, Ordering::SeqCst);
+                                std::io::stdout().flush().unwrap();
+                                info!("Shutting down output sync task on shutdown signal.");
+                                break;
+                            }
+                        }
+                        NextOutput::Pending => {
+                            let preview = processed_data_store_for_output
+                                .lock()
+                                .await
+                                .take_preview(next_key);
+                            if let Some(image) = preview {
+                                debug!("Output sync task: Showing a preview of {}", next_key);
+                                output_sinks.send_preview(
+                                    &image,
+                                    presentation_clock.next_pts_ms(),
+                                    &args_for_output,
+                                );
+                            }
+                            std::io::stdout().flush().unwrap();
+                            debug!(
+                                "Output sync task: Message data not completed for key {}",
+                                next_key
+                            );
+                            output_sinks
+                                .idle(tokio::time::Duration::from_millis(300), &args_for_output)
+                                .await;
+                        }
+                        NextOutput::Missing => {
+                            std::io::stdout().flush().unwrap();
+                            debug!("Output sync task: No data found for key {}", next_key);
+                            output_sinks
+                                .idle(tokio::time::Duration::from_millis(10), &args_for_output)
+                                .await;
                         }
-                        std::io::stdout().flush().unwrap();
-                        debug!(
-                            "Sent NDI Sending done signal for {} key {}.",
-                            data.paragraph_count, current_key
-                        );
                     }
+                }
 
-                    // Send to NDI
-                    #[cfg(feature = "ndi")]
-                    send_to_ndi(data.clone(), &args_for_ndi).await;
-                    {
-                        let mut store = processed_data_store_for_ndi.lock().await;
-                        store.remove(&current_key);
-                    }
-                    current_key += 1;
-                } else {
-                    std::io::stdout().flush().unwrap();
-                    debug!(
-                        "NDI sync task: Message {} data not completed for key {}",
-                        data.paragraph_count, current_key
-                    );
-                    tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+                // without output sinks there is nothing to wait for
+                if !output_enabled {
+                    return Ok(());
                 }
-            } else {
-                std::io::stdout().flush().unwrap();
-                debug!("NDI sync task: No data found for key {}", current_key);
-                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-
-                // If the current key is not found, check if it's less than the max key
-                /*if current_key < max_key {
-                    // If the current key is less than the max key, increment the current key and continue
-                    log::error!(
-                        "NDI sync task: Current key {} is less than max key {}",
-                        current_key,
-                        max_key
-                    );
-                    current_key += 1;
-                } else {
-                    // If the current key is equal to or greater than the max key, sleep and continue
-                    tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-                    }*/
-            }
 
-            // SHUTDOWN Signal
-            if data.is_some() && data.as_ref().unwrap().shutdown {
-                running_processor_ndi_clone.store(false, Ordering::SeqCst);
+                // main logs the session summary and exits or restarts once this returns
+                output_sinks.finish();
                 std::io::stdout().flush().unwrap();
-                info!("Shutting down NDI sync task on shutdown signal.");
-                break;
+                info!("Exiting output sync task.");
+                Ok(())
             }
-        }
+        },
+    );
 
-        // exit the loop
-        std::io::stdout().flush().unwrap();
-        info!("Exiting NDI sync task.");
-        std::process::exit(0);
-    });
-
-    let mut llm_host = args.llm_host.clone();
-    if args.use_openai {
-        // set the llm_host to the openai api
-        llm_host = "https://api.openai.com".to_string();
-    }
+    // the backend of each task, the command line one where --llm-routes has none
+    let llm_routes = LlmRoutes::from_args(&args);
 
     // start time
     let start_time = current_unix_timestamp_ms().unwrap_or(0);
-    let mut total_paragraph_count = 0;
-
-    // Perform TR 101 290 checks
-    let mut tr101290_errors = Tr101290Errors::new();
-    // calculate read size based on batch size and packet size
-    let read_size: i32 =
-        (args.packet_size as i32 * args.pcap_batch_size as i32) + args.payload_offset as i32; // pcap read size
-    let mut is_mpegts = true; // Default to true, update based on actual packet type
-
-    let (ptx, mut prx) = mpsc::channel::<Arc<Vec<u8>>>(args.pcap_channel_size);
-    let (batch_tx, mut batch_rx) = mpsc::channel::<String>(args.pcap_channel_size); // Channel for passing processed packets to main logic
-    let mut network_capture_config = NetworkCapture {
-        running: Arc::new(AtomicBool::new(true)),
-        dpdk: false,
-        use_wireless: args.use_wireless,
-        promiscuous: args.promiscuous,
-        immediate_mode: args.immediate_mode,
-        source_protocol: Arc::new(args.source_protocol.to_string()),
-        source_device: Arc::new(args.source_device.to_string()),
-        source_ip: Arc::new(args.source_ip.to_string()),
-        source_port: args.source_port,
-        read_time_out: 60_000,
-        read_size,
-        buffer_size: args.buffer_size,
-        pcap_stats: args.pcap_stats,
-        debug_on: args.hexdump,
-        capture_task: None,
-    };
+
+    // Stream analysis with the TR 101 290 checks
+    let mut analyzer = StreamAnalyzer::new(&args, start_time);
+    let (ptx, mut prx) = mpsc::channel::<CapturedPacket>(args.pcap_channel_size);
+    let (batch_tx, mut batch_rx) = mpsc::channel::<StreamStats>(args.pcap_channel_size); // Channel for passing processed packets to main logic
 
     // Initialize messages with system_message outside the loop
     let mut messages = vec![system_message.clone()];
 
     // Initialize the network capture if ai_network_stats is true
+    let running_capture = Arc::new(AtomicBool::new(true));
     if args.ai_network_stats {
-        network_capture(&mut network_capture_config, ptx);
+        let args = args.clone();
+        let running = running_capture.clone();
+        let mut restarted = false;
+        supervise(
+            "capture",
+            RestartPolicy::from_args(&args),
+            running_capture.clone(),
+            move || {
+                let mut network_capture_config = network_capture_config(&args);
+                // a pcap file without rotation would be overwritten by the restarted capture
+                if restarted
+                    && args.write_pcap_rotate_mb == 0
+                    && args.write_pcap_rotate_secs == 0
+                    && network_capture_config.pcap_writer.take().is_some()
+                {
+                    warn!("Restarted capture is not writing {}", args.write_pcap);
+                }
+                restarted = true;
+                if args.ts_generator {
+                    ts_generator_capture(
+                        &mut network_capture_config,
+                        TsGeneratorConfig::from_args(&args),
+                        ptx.clone(),
+                    );
+                } else {
+                    network_capture(&mut network_capture_config, ptx.clone());
+                }
+                let running = running.clone();
+                async move {
+                    let Some(capture_task) = network_capture_config.capture_task.take() else {
+                        return Err("the capture did not start".to_string());
+                    };
+                    while !capture_task.is_finished() {
+                        if !running.load(Ordering::SeqCst) {
+                            network_capture_config
+                                .running
+                                .store(false, Ordering::SeqCst);
+                        }
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                    capture_task.await.map_err(|e| e.to_string())?;
+                    if running.load(Ordering::SeqCst) {
+                        return Err("the capture stopped".to_string());
+                    }
+                    Ok(())
+                }
+            },
+        );
     }
 
     let running_processor_network = Arc::new(AtomicBool::new(true));
     let running_processor_network_clone = running_processor_network.clone();
 
+    let mut network_prompt = NetworkPromptBuilder::from_args(&args);
+    // thumbnails of the monitored video for the control api and the picture in picture
+    #[cfg(feature = "thumbnails")]
+    let mut thumbnail_feed = ThumbnailFeed::from_args(&args);
+    // and its audio for the loudness
+    #[cfg(feature = "program_audio")]
+    let mut program_audio_feed = ProgramAudioFeed::from_args(&args);
+    // and the live captions of that audio
+    #[cfg(feature = "program_audio")]
+    let _captions_handle = spawn_captions(&args);
     let processing_handle = tokio::spawn(async move {
         let mut decode_batch = Vec::new();
-        let mut video_pid: Option<u16> = Some(0xFFFF);
-        let mut video_codec: Option<Codec> = Some(Codec::NONE);
-        let mut current_video_frame = Vec::<StreamData>::new();
-        let mut pmt_info: PmtInfo = PmtInfo {
-            pid: 0xFFFF,
-            packet: Vec::new(),
-        };
 
         let mut packet_last_sent_ts = Instant::now();
         let mut count = 0;
@@ -418,121 +905,40 @@ async fn main() {
                     debug!(
                         "#{} --- Received packet with size: {} bytes",
                         count,
-                        packet.len()
+                        packet.data.len()
                     );
 
-                    // Check if chunk is MPEG-TS or SMPTE 2110
-                    let chunk_type = is_mpegts_or_smpte2110(&packet[args.payload_offset..]);
-                    if chunk_type != 1 {
-                        if chunk_type == 0 {
-                            hexdump(&packet, 0, packet.len());
-                            error!("Not MPEG-TS or SMPTE 2110");
-                        }
-                        is_mpegts = false;
-                    }
-
-                    // Process the packet here
-                    let chunks = if is_mpegts {
-                        process_mpegts_packet(
-                            args.payload_offset,
-                            packet,
-                            args.packet_size,
-                            start_time,
-                        )
-                    } else {
-                        process_smpte2110_packet(
-                            args.payload_offset,
-                            packet,
-                            args.packet_size,
-                            start_time,
-                            false,
-                        )
-                    };
-
-                    // Process each chunk
-                    for mut stream_data in chunks {
-                        // check for null packets of the pid 8191 0x1FFF and skip them
-                        if stream_data.pid >= 0x1FFF {
-                            debug!("Skipping null packet");
-                            continue;
-                        }
-
-                        if args.hexdump {
-                            hexdump(
-                                &stream_data.packet,
-                                stream_data.packet_start,
-                                stream_data.packet_len,
-                            );
+                    // Analyze the packet and collect the stream data of its chunks
+                    for stream_data in analyzer.process(packet) {
+                        count += 1;
+                        #[cfg(feature = "thumbnails")]
+                        if let Some(feed) = thumbnail_feed.as_mut() {
+                            if analyzer.video_pid() == Some(stream_data.pid)
+                                && analyzer.video_codec() == Some(&Codec::H264)
+                            {
+                                let start = stream_data.packet_start;
+                                feed.push(
+                                    stream_data.pid,
+                                    &stream_data.packet[start..start + stream_data.packet_len],
+                                );
+                            }
                         }
-
-                        // Extract the necessary slice for PID extraction and parsing
-                        let packet_chunk = &stream_data.packet[stream_data.packet_start
-                            ..stream_data.packet_start + stream_data.packet_len];
-
-                        if is_mpegts {
-                            let pid = stream_data.pid;
-                            // Handle PAT and PMT packets
-                            match pid {
-                                PAT_PID => {
-                                    debug!("ProcessPacket: PAT packet detected with PID {}", pid);
-                                    pmt_info = parse_and_store_pat(&packet_chunk);
-                                    // Print TR 101 290 errors
-                                    if args.show_tr101290 {
-                                        info!("STATUS::TR101290:ERRORS: {}", tr101290_errors);
-                                    }
-                                }
-                                _ => {
-                                    // Check if this is a PMT packet
-                                    if pid == pmt_info.pid {
-                                        debug!(
-                                            "ProcessPacket: PMT packet detected with PID {}",
-                                            pid
-                                        );
-                                        // Update PID_MAP with new stream types
-                                        update_pid_map(&packet_chunk, &pmt_info.packet);
-                                        // Identify the video PID (if not already identified)
-                                        if let Some((new_pid, new_codec)) =
-                                            identify_video_pid(&packet_chunk)
-                                        {
-                                            if video_pid.map_or(true, |vp| vp != new_pid) {
-                                                video_pid = Some(new_pid);
-                                                info!(
-                                                    "STATUS::VIDEO_PID:CHANGE: to {}/{} from {}/{}",
-                                                    new_pid,
-                                                    new_codec.clone(),
-                                                    video_pid.unwrap(),
-                                                    video_codec.unwrap()
-                                                );
-                                                video_codec = Some(new_codec.clone());
-                                                // Reset video frame as the video stream has changed
-                                                current_video_frame.clear();
-                                            } else if video_codec != Some(new_codec.clone()) {
-                                                info!(
-                                                    "STATUS::VIDEO_CODEC:CHANGE: to {} from {}",
-                                                    new_codec,
-                                                    video_codec.unwrap()
-                                                );
-                                                video_codec = Some(new_codec);
-                                                // Reset video frame as the codec has changed
-                                                current_video_frame.clear();
-                                            }
-                                        }
-                                    }
-                                }
+                        #[cfg(feature = "program_audio")]
+                        if let (Some(feed), Some(codec)) =
+                            (program_audio_feed.as_mut(), analyzer.audio_codec())
+                        {
+                            if analyzer.audio_pid() == Some(stream_data.pid) {
+                                let start = stream_data.packet_start;
+                                feed.push(
+                                    stream_data.pid,
+                                    codec,
+                                    &stream_data.packet[start..start + stream_data.packet_len],
+                                );
                             }
                         }
-
-                        // Check for TR 101 290 errors
-                        process_packet(
-                            &mut stream_data,
-                            &mut tr101290_errors,
-                            is_mpegts,
-                            pmt_info.pid,
-                        );
-                        count += 1;
-
                         decode_batch.push(stream_data);
                     }
+                    mark_active(Subsystem::Demux);
 
                     // check if it is 60 seconds since the last packet was sent
                     let last_packet_sent = packet_last_sent_ts.elapsed().as_secs();
@@ -542,45 +948,13 @@ async fn main() {
                         || (last_packet_sent > (args.poll_interval / 1000)
                             && decode_batch.len() > args.ai_network_packet_count)
                     {
-                        let mut network_packet_dump: String = String::new();
                         packet_last_sent_ts = Instant::now();
 
-                        network_packet_dump.push_str("\n");
-                        // fill network_packet_dump with the json of each stream_data plus hexdump of the packet payload
-                        for stream_data in &decode_batch {
-                            if args.ai_network_packets {
-                                let stream_data_json = serde_json::to_string(&stream_data).unwrap();
-                                network_packet_dump.push_str(&stream_data_json);
-                                network_packet_dump.push_str("\n");
-                            }
-
-                            // hex of the packet_chunk with ascii representation after | for each line
-                            if args.ai_network_hexdump {
-                                // Extract the necessary slice for PID extraction and parsing
-                                let packet_chunk = &stream_data.packet[stream_data.packet_start
-                                    ..stream_data.packet_start + stream_data.packet_len];
-
-                                network_packet_dump.push_str(&hexdump_ascii(
-                                    &packet_chunk,
-                                    0,
-                                    (stream_data.packet_start + stream_data.packet_len)
-                                        - stream_data.packet_start,
-                                ));
-                                network_packet_dump.push_str("\n");
-                            }
-                        }
-                        // get PID_MAP and each stream data in json format and send it to the main thread
-                        // get pretty date and time
-                        let pretty_date_time = format!(
-                            "#{}: {}",
-                            count,
-                            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f")
-                        );
-                        let pid_map = format!("{}: {}", pretty_date_time, get_pid_map());
-                        network_packet_dump.push_str(&pid_map);
+                        // the programs and the most anomalous PIDs in the token budget
+                        let stream_stats = network_prompt.build(count, &decode_batch);
 
-                        // Send the network packet dump to the Main thread
-                        if let Err(e) = batch_tx.send(network_packet_dump.clone()).await {
+                        // Send the stream stats to the Main thread
+                        if let Err(e) = batch_tx.send(stream_stats).await {
                             eprintln!("Failed to send decode batch: {}", e);
                         }
 
@@ -596,13 +970,16 @@ async fn main() {
         }
     });
 
-    let twitch_auth = env::var("TWITCH_AUTH")
-        .ok()
+    let twitch_auth = secret("TWITCH_AUTH")
+        .map(|token| token.expose().to_string())
         .unwrap_or_else(|| "NO_AUTH_KEY".to_string());
 
     let running_processor_twitch = Arc::new(AtomicBool::new(true));
     let (twitch_tx, mut twitch_rx) = mpsc::channel(100);
 
+    // the candle models are loaded once and shared by the story and the chat
+    let llm_service = LlmService::spawn(&args);
+
     if args.twitch_client {
         // Clone values before moving them into the closure
         let twitch_channel_clone = vec![args.twitch_channel.clone()];
@@ -612,53 +989,41 @@ async fn main() {
         // TODO: add mpsc channels for communication between the twitch setup and the main thread
         let running_processor_twitch_clone = running_processor_twitch.clone();
         let args_clone = args.clone();
-        let _twitch_handle = tokio::spawn(async move {
-            info!(
-                "Setting up Twitch channel {} for user {}",
-                twitch_channel_clone.join(", "), // Assuming it's a Vec<String>
-                twitch_username_clone
-            );
+        let llm_service_twitch = llm_service.clone();
+        info!(
+            "Setting up Twitch channel {} for user {}",
+            twitch_channel_clone.join(", "), // Assuming it's a Vec<String>
+            twitch_username_clone
+        );
 
-            if twitch_auth == "NO_AUTH_KEY" {
-                error!(
-                    "Twitch Auth key is not set. Please set the TWITCH_AUTH environment variable."
-                );
-                std::process::exit(1);
-            }
+        if twitch_auth == "NO_AUTH_KEY" {
+            error!("Twitch Auth key is not set. Please set TWITCH_AUTH in the environment, the --secrets-file or the keychain.");
+            std::process::exit(1);
+        }
 
-            loop {
-                match twitch_daemon(
+        // a dropped connection or chat error reconnects instead of exiting
+        let _twitch_handle = supervise(
+            "twitch",
+            RestartPolicy::from_args(&args),
+            running_processor_twitch.clone(),
+            move || {
+                let twitch = twitch_daemon(
                     twitch_username_clone.clone(),
                     twitch_auth_clone.clone(),
                     twitch_channel_clone.clone(),
                     running_processor_twitch_clone.clone(),
                     twitch_tx.clone(),
-                    args_clone,
-                )
-                .await
-                {
-                    Ok(_) => {
-                        info!(
-                            "Twitch client exiting for channel {} username {}",
-                            twitch_channel_clone.join(", "), // Assuming it's a Vec<String>
-                            twitch_username_clone
-                        );
-                        break;
-                    }
-                    Err(e) => {
-                        error!(
-                            "Error setting up Twitch channel {} for user {}: {}",
-                            twitch_channel_clone.join(", "), // Assuming it's a Vec<String>
-                            twitch_username_clone,
-                            e
-                        );
+                    llm_service_twitch.clone(),
+                    args_clone.clone(),
+                );
+                async move { twitch.await.map_err(|e| e.to_string()) }
+            },
+        );
 
-                        // exit the loop
-                        std::process::exit(1);
-                    }
-                }
-            }
-        });
+        // channel title and category follow the topic and chapters
+        if args.twitch_stream_info_interval > 0 {
+            spawn_stream_info_updater(args.clone(), running_processor_twitch.clone());
+        }
     }
     let poll_interval = args.poll_interval;
     let poll_interval_duration = Duration::from_millis(poll_interval);
@@ -674,77 +1039,74 @@ async fn main() {
     }
     let mut iterations = 0;
 
-    // Boot up message and image repeat of the query sent to the pipeline
-    if args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts {
-        let mut sd_config = SDConfig::new();
-        sd_config.prompt = args.assistant_image_prompt.clone();
-        sd_config.height = Some(args.sd_height);
-        sd_config.width = Some(args.sd_width);
-        sd_config.image_position = Some(args.image_alignment.clone());
-        sd_config.intermediary_images = args.sd_intermediary_images;
-        sd_config.custom_model = Some(args.sd_custom_model.clone());
-        // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-        sd_config.sd_version = if args.sd_model == "1.5" {
-            StableDiffusionVersion::V1_5
-        } else if args.sd_model == "2.1" {
-            StableDiffusionVersion::V2_1
-        } else if args.sd_model == "xl" {
-            StableDiffusionVersion::Xl
-        } else if args.sd_model == "turbo" {
-            StableDiffusionVersion::Turbo
-        } else if args.sd_model == "Custom" {
-            StableDiffusionVersion::Custom
-        } else {
-            StableDiffusionVersion::V1_5
-        };
-
-        let output_id = Uuid::new_v4().simple().to_string(); // Generates a UUID and converts it to a simple, hyphen-free string
-        if args.sd_scaled_height > 0 {
-            sd_config.scaled_height = Some(args.sd_scaled_height);
-        }
-        if args.sd_scaled_width > 0 {
-            sd_config.scaled_width = Some(args.sd_scaled_width);
+    // A resumed show renders its unfinished paragraphs again instead of greeting
+    if let Some(checkpoint) = resume.as_ref() {
+        info!(
+            "Resuming the show at paragraph {} with {} unfinished paragraphs",
+            checkpoint.resume_count(),
+            checkpoint.pending.len()
+        );
+        log_event(
+            "pipeline",
+            "resume",
+            json!({
+                "paragraph_count": checkpoint.resume_count(),
+                "pending": checkpoint.pending.len(),
+                "next_pts_ms": checkpoint.next_pts_ms,
+            }),
+        );
+        if pipeline_enabled(&args) {
+            for pending in checkpoint.pending.iter() {
+                pipeline_dispatcher
+                    .send(pending.to_message(&args))
+                    .await
+                    .expect("Failed to send resumed pipeline task");
+            }
         }
-        sd_config.n_steps = args.sd_n_steps;
-
-        /* clone args and set the cloned args args.subtitles to true */
-        let mut args_clone = args.clone();
-        /* set args_clone.subtitles to true */
-        args_clone.subtitles = true;
-        
-        // just send a message with the last_message field true to indicate the end of the response
-        let message_data_for_pipeline = MessageData {
-            paragraph: args.greeting.to_string(),
-            output_id: output_id.to_string(),
-            paragraph_count: total_paragraph_count,
-            sd_config,
-            mimic3_voice: args.mimic3_voice.to_string(),
-            subtitle_position: "center".to_string(),
-            args: args_clone,
-            shutdown: false,
-            last_message: false,
-        };
+    }
 
-        // For pipeline task
-        pipeline_task_sender
-            .send(message_data_for_pipeline)
-            .await
-            .expect("Failed to send bootup pipeline task");
+    // intro, outro and stingers of the show, the persona greeting by default
+    let mut bumpers = Bumpers::from_args(&args);
 
-        total_paragraph_count += 1;
+    // Boot up message and image repeat of the query sent to the pipeline
+    if pipeline_enabled(&args) && resume.is_none() {
+        if let Some(intro) = bumpers.intro(&persona, &args) {
+            // For pipeline task
+            pipeline_dispatcher
+                .send(intro)
+                .await
+                .expect("Failed to send bootup pipeline task");
+        }
     }
 
+    // answers of the daemon analyzer by stats window
+    let mut response_cache = ResponseCache::from_args(&args);
+
+    // goodbye sequence and the maintenance windows
+    let shutdown_config = ShutdownConfig::from_args(&args);
+    let mut shutdown_schedule =
+        ShutdownSchedule::new(shutdown_config.schedule.clone(), chrono::Local::now());
+
+    // ad breaks of the schedule and the start time of the break the card went up for
+    let mut ad_break_schedule = AdBreakSchedule::from_args(&args);
+    let mut ad_break_started: Option<u64> = None;
+
+    // chat question of an iteration the LLM endpoint was down for, asked again
+    let mut retry_query: Option<String> = None;
+
     loop {
         let mut twitch_query = false;
-        let mut query = args.query.clone();
+        let mut query = default_query.clone();
+        // stats window of this iteration without the timestamp, for the response cache
+        let mut stats_window: Option<String> = None;
 
-        let openai_key = env::var("OPENAI_API_KEY")
-            .ok()
+        let openai_key = secret("OPENAI_API_KEY")
+            .map(|key| key.expose().to_string())
             .unwrap_or_else(|| "NO_API_KEY".to_string());
 
-        if (args.use_openai || args.oai_tts) && openai_key == "NO_API_KEY" {
+        if (llm_routes.uses_openai(&args) || args.oai_tts) && openai_key == "NO_API_KEY" {
             error!(
-                "OpenAI API key is not set. Please set the OPENAI_API_KEY environment variable."
+                "OpenAI API key is not set. Please set OPENAI_API_KEY in the environment, the --secrets-file or the keychain."
             );
             std::process::exit(1);
         }
@@ -755,28 +1117,136 @@ async fn main() {
             messages.push(system_message.clone());
         }
 
+        // persona switch requests from the control api and twitch chat
+        let mut persona_commands: Vec<String> = Vec::new();
+        while let Ok(command) = control_rx.try_recv() {
+            persona_commands.push(command);
+        }
+
+        // health reports are read out ahead of the commentary
+        while let Ok(report) = health_report_rx.try_recv() {
+            println!("\n============= HEALTH REPORT ============\n{}\n", report);
+            if args.health_report_tts && pipeline_enabled(&args) {
+                let output_id = Uuid::new_v4().simple().to_string();
+                let sd_config = build_sd_config(&args, &persona.image_prompt);
+                let message_data_for_pipeline =
+                    MessageData::announcement(&report, &output_id, sd_config, &args)
+                        .with_voice(&persona.voice);
+                pipeline_dispatcher
+                    .send(message_data_for_pipeline)
+                    .await
+                    .expect("Failed to send health report pipeline task");
+            }
+        }
+
         if args.twitch_client {
             loop {
                 match tokio::time::timeout(Duration::from_millis(100), twitch_rx.recv()).await {
                     Ok(Some(msg)) => {
-                        if msg.starts_with("!message") {
+                        // chat shown on the ticker as "name: message"
+                        if let Some(chat) = msg
+                            .strip_prefix("!chat ")
+                            .or_else(|| msg.strip_prefix("!message "))
+                        {
+                            ticker_push("chat", &chat.replacen(" said ", ": ", 1));
+                        }
+                        if msg.starts_with("!persona") || msg.starts_with("!rewind") {
+                            persona_commands.push(msg.to_string());
+                            query = default_query.clone();
+                        } else if let Some(request) = msg.strip_prefix("!image ") {
+                            // viewer images go straight to the pipeline, the prompt was
+                            // moderated by the twitch client
+                            if let Some((requester, prompt)) = request.split_once(' ') {
+                                let output_id = Uuid::new_v4().simple().to_string();
+                                let sd_config = build_sd_config(&args, prompt);
+                                log_event(
+                                    "twitch",
+                                    "image_request",
+                                    json!({ "requester": requester, "prompt": prompt }),
+                                );
+                                pipeline_dispatcher
+                                    .send(
+                                        MessageData::viewer_image(
+                                            prompt, requester, &output_id, sd_config, &args,
+                                        )
+                                        .with_voice(&persona.voice),
+                                    )
+                                    .await
+                                    .expect("Failed to send viewer image pipeline task");
+                            }
+                            query = default_query.clone();
+                        } else if let Some(channel) = msg.strip_prefix("!raid ") {
+                            // raiders are greeted ahead of the story
+                            ticker_push("chat", &format!("Raid from {}!", channel));
+                            log_event("twitch", "raid", json!({ "channel": channel }));
+                            if !args.twitch_raid_greeting.is_empty() && pipeline_enabled(&args) {
+                                let output_id = Uuid::new_v4().simple().to_string();
+                                let greeting =
+                                    args.twitch_raid_greeting.replace("{channel}", channel);
+                                let image_prompt =
+                                    args.twitch_raid_image_prompt.replace("{channel}", channel);
+                                let sd_config = build_sd_config(&args, &image_prompt);
+                                pipeline_dispatcher
+                                    .send(
+                                        MessageData::announcement(
+                                            &greeting, &output_id, sd_config, &args,
+                                        )
+                                        .with_voice(&persona.voice),
+                                    )
+                                    .await
+                                    .expect("Failed to send raid greeting pipeline task");
+                            }
+                            query = default_query.clone();
+                        } else if let Some(request) = msg.strip_prefix("!tts ") {
+                            // viewer messages read aloud in their voice ahead of the story
+                            let mut parts = request.splitn(3, ' ');
+                            if let (Some(requester), Some(voice), Some(text)) =
+                                (parts.next(), parts.next(), parts.next())
+                            {
+                                let output_id = Uuid::new_v4().simple().to_string();
+                                let sd_config = build_sd_config(&args, &persona.image_prompt);
+                                log_event(
+                                    "twitch",
+                                    "tts_request",
+                                    json!({ "requester": requester, "voice": voice, "text": text }),
+                                );
+                                pipeline_dispatcher
+                                    .send(
+                                        MessageData::announcement(
+                                            text, &output_id, sd_config, &args,
+                                        )
+                                        .with_voice(voice),
+                                    )
+                                    .await
+                                    .expect("Failed to send viewer tts pipeline task");
+                            }
+                            query = default_query.clone();
+                        } else if msg.starts_with("!message") {
                             let message = msg.splitn(2, ' ').nth(1).unwrap_or("");
                             // set the current query to the message
-                            query = message.to_string();
-                            twitch_query = true;
+                            match prompt_guard.wrap("chat", message) {
+                                Some(wrapped) => {
+                                    set_stream_topic(message);
+                                    query = wrapped;
+                                    twitch_query = true;
+                                }
+                                None => query = default_query.clone(),
+                            }
                             break;
                         } else if msg.is_empty() || msg.starts_with("!") {
-                            query = args.query.clone();
+                            query = default_query.clone();
                         } else {
                             // add the message to the messages
-                            let twitch_message = Message {
-                                role: "user".to_string(),
-                                content: msg.to_string(),
-                            };
-                            // store in history for context of chat room
-                            messages.push(twitch_message);
+                            if let Some(content) = prompt_guard.wrap("chat", &msg) {
+                                let twitch_message = Message {
+                                    role: "user".to_string(),
+                                    content,
+                                };
+                                // store in history for context of chat room
+                                messages.push(twitch_message);
+                            }
                             // set the current query to the the default
-                            query = args.query.clone();
+                            query = default_query.clone();
                         }
                         break;
                     }
@@ -792,78 +1262,164 @@ async fn main() {
             }
         }
 
+        if let Some(retry) = retry_query.take() {
+            if !twitch_query {
+                query = retry;
+                twitch_query = true;
+            }
+        }
+
+        // switch personas, the system prompt is replaced in place to keep the history
+        for command in persona_commands {
+            // new prompts and personas, the current persona is kept if it still exists
+            if command == RELOAD_COMMAND {
+                let reloaded = apply_prompts_file(&args)
+                    .and_then(|prompt_args| Ok((PersonaRegistry::new(&prompt_args)?, prompt_args)));
+                match reloaded {
+                    Ok((mut registry, prompt_args)) => {
+                        if registry.select(&persona.name).is_none() {
+                            warn!(
+                                "Persona {} is gone after the reload, using {}",
+                                persona.name,
+                                registry.current().name
+                            );
+                        }
+                        persona_registry = registry;
+                        persona = persona_registry.current().clone();
+                        *persona_names.lock().await = persona_registry.names();
+                        set_stream_persona(&persona.name);
+                        default_query = prompt_args.query.clone();
+                        if !twitch_query {
+                            query = default_query.clone();
+                        }
+                        system_message.content =
+                            format!("{}{}", persona.system_prompt, system_instructions);
+                        for message in messages.iter_mut().filter(|m| m.role == "system") {
+                            message.content = system_message.content.clone();
+                        }
+                        info!(
+                            "Reloaded the prompts and {} personas, using persona {}",
+                            persona_registry.names().len(),
+                            persona.name
+                        );
+                        log_event(
+                            "control",
+                            "reload",
+                            json!({
+                                "persona": persona.name,
+                                "personas": persona_registry.names(),
+                            }),
+                        );
+                    }
+                    Err(e) => error!("Failed to reload, keeping the current prompts: {}", e),
+                }
+                if let Err(e) = init_character_sheet(&args) {
+                    error!("{}, keeping the current character sheet", e);
+                }
+                continue;
+            }
+            // rewind to an earlier checkpoint, the next answer branches off from there
+            if let Some(target) = parse_rewind_command(&command) {
+                match history_tree.lock().await.rewind(target) {
+                    Some(rewound) => {
+                        // the queued paragraphs belong to the abandoned branch
+                        pipeline_cancel.cancel();
+                        messages = rewound;
+                        for message in messages.iter_mut().filter(|m| m.role == "system") {
+                            message.content = system_message.content.clone();
+                        }
+                        info!(
+                            "Rewound the conversation with {} to {} messages",
+                            command,
+                            messages.len()
+                        );
+                        log_event(
+                            "pipeline",
+                            "rewind",
+                            json!({ "command": command, "messages": messages.len() }),
+                        );
+                    }
+                    None => error!("No checkpoint to rewind to for {}", command),
+                }
+                continue;
+            }
+            if let Some(name) = parse_persona_command(&command) {
+                match persona_registry.select(name) {
+                    Some(selected) => {
+                        persona = selected.clone();
+                        set_stream_persona(&persona.name);
+                        // the queued paragraphs belong to the previous persona
+                        pipeline_cancel.cancel();
+                        system_message.content =
+                            format!("{}{}", persona.system_prompt, system_instructions);
+                        for message in messages.iter_mut().filter(|m| m.role == "system") {
+                            message.content = system_message.content.clone();
+                        }
+                        info!("Switched to persona {}", persona.name);
+                    }
+                    None => {
+                        error!(
+                            "Persona {} not found, available personas: {}",
+                            name,
+                            persona_registry.names().join(", ")
+                        );
+                    }
+                }
+            }
+        }
+
+        let scheduled = shutdown_schedule.due(chrono::Local::now());
+        if let Some(action) = scheduled {
+            info!("Scheduled {:?} of the channel", action);
+            log_event(
+                "control",
+                "scheduled_shutdown",
+                json!({ "action": format!("{:?}", action).to_lowercase() }),
+            );
+        }
+
         // break the loop if we are not running as a daemon or hit max iterations
         let rctrlc_clone = running_ctrlc.clone();
-        if (!rctrlc_clone.load(Ordering::SeqCst)
-            || (!args.daemon && !args.interactive && args.max_iterations <= iterations))
+        if scheduled.is_some()
+            || (!rctrlc_clone.load(Ordering::SeqCst)
+                || (!args.daemon && !args.interactive && args.max_iterations <= iterations))
             || (!args.daemon
                 && !args.interactive
                 && args.max_iterations > 1
                 && args.max_iterations > iterations)
         {
             // stop the running threads
-            if args.ai_network_stats {
-                network_capture_config
-                    .running
-                    .store(false, Ordering::SeqCst);
-            }
+            running_capture.store(false, Ordering::SeqCst);
 
             // stop the running threads
             info!("Signaling background tasks to complete...");
             running_processor_network.store(false, Ordering::SeqCst);
             running_processor_twitch.store(false, Ordering::SeqCst);
+            running_processor_api.store(false, Ordering::SeqCst);
+            running_probe_link.store(false, Ordering::SeqCst);
+            running_health_report.store(false, Ordering::SeqCst);
 
             // Await the completion of background tasks
             info!("waiting for network capture handle to complete...");
             let _ = processing_handle.await;
             info!("Network Processing handle complete.");
 
+            // drop the queued paragraphs so the goodbye is not stuck behind them
+            pipeline_cancel.cancel();
+
             // set a flag to stop the pipeline processing task with the message shutdown field
             let output_id = Uuid::new_v4().simple().to_string(); // Generates a UUID and converts it to a simple, hyphen-free string
-            let mut sd_config = SDConfig::new();
-            sd_config.prompt = args.assistant_image_prompt.to_string();
-            sd_config.height = Some(args.sd_height);
-            sd_config.width = Some(args.sd_width);
-            sd_config.image_position = Some(args.image_alignment.clone());
-            sd_config.intermediary_images = args.sd_intermediary_images;
-            sd_config.custom_model = Some(args.sd_custom_model.clone());
-            if args.sd_scaled_height > 0 {
-                sd_config.scaled_height = Some(args.sd_scaled_height);
-            }
-            if args.sd_scaled_width > 0 {
-                sd_config.scaled_width = Some(args.sd_scaled_width);
-            }
-            // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-            sd_config.sd_version = if args.sd_model == "1.5" {
-                StableDiffusionVersion::V1_5
-            } else if args.sd_model == "2.1" {
-                StableDiffusionVersion::V2_1
-            } else if args.sd_model == "xl" {
-                StableDiffusionVersion::Xl
-            } else if args.sd_model == "turbo" {
-                StableDiffusionVersion::Turbo
-            } else if args.sd_model == "Custom" {
-                StableDiffusionVersion::Custom
-            } else {
-                StableDiffusionVersion::V1_5
-            };
-            sd_config.n_steps = args.sd_n_steps;
-            /* clone args and set the cloned args args.subtitles to true */
-            let mut args_clone = args.clone();
-            /* set args_clone.subtitles to true */
-            args_clone.subtitles = true;
-            pipeline_task_sender
-                .send(MessageData {
-                    paragraph: "Alice is Shutting Down the AI Channel, goodbye!".to_string(),
-                    output_id: output_id.to_string(),
-                    paragraph_count: total_paragraph_count,
-                    sd_config,
-                    mimic3_voice: args.mimic3_voice.to_string(),
-                    subtitle_position: "center".to_string(),
-                    args: args_clone,
-                    shutdown: true,
-                    last_message: true,
-                })
+            let action = scheduled.unwrap_or(ShutdownAction::Shutdown);
+            let sd_config =
+                build_sd_config(&args, shutdown_config.image_prompt(&persona.image_prompt));
+            let shutdown_message = shutdown_config.announcement(action, &persona.name);
+            pipeline_dispatcher
+                .send(
+                    MessageData::announcement(&shutdown_message, &output_id, sd_config, &args)
+                        .with_voice(&persona.voice)
+                        .with_last_message(true)
+                        .with_shutdown(true),
+                )
                 .await
                 .expect("Failed to send last audio/speech pipeline task");
 
@@ -871,20 +1427,72 @@ async fn main() {
             info!("waiting for pipline handle to complete...");
             let _ = pipeline_processing_task.await;
             info!("pipeline handle completed.");
+            info!("Image queue: {}", image_queue.stats());
 
-            // NDI await completion
-            #[cfg(feature = "ndi")]
-            info!("waiting for ndi handle to complete...");
-            #[cfg(feature = "ndi")]
-            let _ = ndi_sync_task.await;
-            #[cfg(feature = "ndi")]
-            info!("ndi handle completed.");
+            // Output await completion
+            if output_enabled {
+                info!("waiting for output handle to complete...");
+                let _ = output_sync_task.await;
+                info!("output handle completed.");
+            }
 
-            // exit here
+            // exit here, or start over for a scheduled restart
+            log_session_summary(&args);
+            if action == ShutdownAction::Restart {
+                restart_process();
+            }
             info!("Exiting main loop...");
             std::process::exit(0);
         }
 
+        // the host is quiet during an ad break, the queued paragraphs are dropped and the be
+        // right back card stays up until the break is over
+        ad_break_schedule.check();
+        match ad_break() {
+            Some(current) => {
+                if ad_break_started != Some(current.started_ms) {
+                    ad_break_started = Some(current.started_ms);
+                    pipeline_cancel.cancel();
+                    if pipeline_enabled(&args) {
+                        let output_id = Uuid::new_v4().simple().to_string();
+                        let sd_config = build_sd_config(&args, &args.ad_break_image_prompt);
+                        pipeline_dispatcher
+                            .send(MessageData::card(
+                                &args.ad_break_card_text,
+                                &output_id,
+                                sd_config,
+                                &args,
+                            ))
+                            .await
+                            .expect("Failed to send ad break card pipeline task");
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                continue;
+            }
+            None => {
+                if ad_break_started.take().is_some() {
+                    info!("Ad break is over, back to the commentary");
+                    if !args.ad_break_resume_text.is_empty() && pipeline_enabled(&args) {
+                        let output_id = Uuid::new_v4().simple().to_string();
+                        let sd_config = build_sd_config(&args, &persona.image_prompt);
+                        pipeline_dispatcher
+                            .send(
+                                MessageData::announcement(
+                                    &args.ad_break_resume_text,
+                                    &output_id,
+                                    sd_config,
+                                    &args,
+                                )
+                                .with_voice(&persona.voice),
+                            )
+                            .await
+                            .expect("Failed to send ad break resume pipeline task");
+                    }
+                }
+            }
+        }
+
         // Calculate elapsed time since last start
         let elapsed = poll_start_time.elapsed();
 
@@ -930,15 +1538,17 @@ async fn main() {
         poll_start_time = Instant::now();
 
         // OS and Network stats message
-        let system_stats_json = if args.ai_os_stats {
-            get_stats_as_json(StatsType::System).await
+        let system_stats = if args.ai_os_stats {
+            Some(get_system_stats())
         } else {
-            // Default input message
-            json!({})
+            None
         };
 
+        // history to go back to when the LLM endpoint is down
+        let history_len = messages.len();
+
         // Add the system stats to the messages
-        if !args.ai_os_stats && !args.ai_network_stats {
+        if !args.ai_os_stats && !args.ai_network_stats && args.probe_listen.is_empty() {
             if !args.interactive && !query.is_empty() {
                 let query_clone = query.clone();
                 let user_message = Message {
@@ -970,45 +1580,42 @@ async fn main() {
             // create nework packet dump message from collected stream_data in decode_batch
             // Try to receive new packet batches if available
             let mut msg_count = 0;
-            while let Ok(decode_batch) = batch_rx.try_recv() {
+            while let Ok(stream_stats) = batch_rx.try_recv() {
                 msg_count += 1;
-                //debug!("Received network packet dump message: {}", decode_batch);
-                // Handle the received decode_batch here...
-                // get current pretty date and time
-                let pretty_date_time = format!(
-                    "#{}: {} -",
-                    iterations,
-                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f")
-                );
+                let Some(stream_stats) =
+                    stream_stats.guarded(|text| prompt_guard.wrap("packets", text))
+                else {
+                    continue;
+                };
+                let stats_message = StatsMessage::new(iterations as u64, &query)
+                    .with_system(system_stats.clone())
+                    .with_stream(stream_stats)
+                    .with_sites(site_stats(args.probe_stale_secs));
+                stats_window = Some(stats_message.window());
+                if let Some(publisher) = event_publisher.as_ref() {
+                    publisher.publish_stats(&stats_message);
+                }
                 let network_stats_message = Message {
                     role: "user".to_string(),
-                    content: format!(
-                        "{} System Stats: {}\nPackets: {}\nInstructions: {}\n",
-                        pretty_date_time,
-                        system_stats_json.to_string(),
-                        decode_batch,
-                        query
-                    ),
+                    content: stats_message.to_json(),
                 };
                 messages.push(network_stats_message.clone());
                 if msg_count >= 1 {
                     break;
                 }
             }
-        } else if args.ai_os_stats {
-            let pretty_date_time = format!(
-                "#{}: {} - ",
-                iterations,
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f")
-            );
+        } else {
+            // the os stats, or only the remote sites on a central instance without a capture
+            let stats_message = StatsMessage::new(iterations as u64, &query)
+                .with_system(system_stats.clone())
+                .with_sites(site_stats(args.probe_stale_secs));
+            stats_window = Some(stats_message.window());
+            if let Some(publisher) = event_publisher.as_ref() {
+                publisher.publish_stats(&stats_message);
+            }
             let system_stats_message = Message {
                 role: "user".to_string(),
-                content: format!(
-                    "{} System Stats: {}\nInstructions: {}",
-                    pretty_date_time,
-                    system_stats_json.to_string(),
-                    query
-                ),
+                content: stats_message.to_json(),
             };
             messages.push(system_stats_message.clone());
         }
@@ -1027,59 +1634,40 @@ async fn main() {
             println!("============= NEW RESPONSE ============");
         }
 
+        // the model answering this iteration, from --llm-routes for the analysis or the story
+        let backend = llm_routes.backend(LlmTask::main(&args), &args);
+
         // measure size of messages in bytes and print it out
         let messages_size = bincode::serialize(&messages).unwrap().len();
         info!("Initial Messages size: {}", messages_size);
 
-        let llm_history_size_bytes: usize = args.llm_history_size; // max history size in bytes
-
         // Separate system messages to preserve them
         let (system_messages, mut non_system_messages): (Vec<_>, Vec<_>) =
             messages.into_iter().partition(|m| m.role == "system");
 
-        let total_non_system_size: usize =
-            non_system_messages.iter().map(|m| m.content.len()).sum();
-
-        // If non-system messages alone exceed the limit, we need to trim
-        if !args.no_history
-            && args.daemon
-            && llm_history_size_bytes > 0
-            && total_non_system_size > llm_history_size_bytes
-        {
-            let mut excess_size = total_non_system_size - llm_history_size_bytes;
-
-            info!(
-                "Pruning excess history size: removing {} of {} bytes to {} bytes.",
-                excess_size, total_non_system_size, llm_history_size_bytes
-            );
-
-            // Reverse iterate to trim from the end
-            for message in non_system_messages.iter_mut().rev() {
-                let message_size = message.content.len();
-                if excess_size == 0 {
-                    break;
-                }
-
-                if message_size <= excess_size {
-                    // Remove the whole message content if it's smaller than or equal to the excess
-                    excess_size -= message_size;
-                    message.content.clear();
-                } else {
-                    // Truncate the message content to fit within the limit
-                    let new_size = message_size - excess_size;
-                    message.content = message.content.chars().take(new_size).collect();
-                    break; // After truncation, we should be within the limit
-                }
+        // the history and the newest stats window share the context the answer and system
+        // prompt leave
+        let system_text = system_messages
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let token_budget =
+            TokenBudget::for_model(&args, backend.model(), max_tokens).reserve(&system_text);
+        let history_budget = token_budget.allocation(BudgetPart::History, &MAIN_PARTS)
+            + token_budget.allocation(BudgetPart::Stats, &MAIN_PARTS);
+        if !args.no_history {
+            let dropped = fit_messages(&mut non_system_messages, history_budget);
+            if dropped > 0 {
+                info!(
+                    "Pruned {} history messages to {} tokens of the {} token context of {}, {} messages left.",
+                    dropped,
+                    history_budget,
+                    token_budget.context_window,
+                    backend.model(),
+                    non_system_messages.len()
+                );
             }
-
-            info!(
-                "Pruning complete. New history size: {} bytes for {} messages.",
-                non_system_messages
-                    .iter()
-                    .map(|m| m.content.len())
-                    .sum::<usize>(),
-                non_system_messages.len()
-            );
         }
 
         // Reassemble messages, ensuring system messages are preserved at their original position
@@ -1118,9 +1706,9 @@ async fn main() {
         }
 
         // Setup mpsc channels for internal communication within the llm function
-        let (external_sender, mut external_receiver) = tokio::sync::mpsc::channel::<String>(32768);
-
-        let model_id = args.model_id.clone();
+        let (external_sender, mut external_receiver) =
+            tokio::sync::mpsc::channel::<TokenBatch>(32768);
+        let batching = TokenBatching::from_args(&args);
 
         iterations += 1;
 
@@ -1128,510 +1716,312 @@ async fn main() {
 
         // Capture the start time for performance metrics
         let start = Instant::now();
+        let started_ms = current_unix_timestamp_ms().unwrap_or(0);
 
-        let prompt = format_messages_for_llm(messages.clone(), args.chat_format.clone());
+        // a recorded run pins the candle seed so the answer can be generated again
+        let use_api = backend.is_api();
+        let mut sampling = SamplingConfig::from_args(&args);
+        if run_recorder.is_some() && !use_api && sampling.seed.is_none() {
+            sampling.seed = Some(rand::random());
+        }
+
+        let prompt = format_messages_for_llm(messages.clone(), backend.chat_format(&args));
 
         info!("\nPrompt: {}", prompt);
 
         // Spawn a thread to run the mistral function, to keep the UI responsive
-        if args.candle_llm != "mistral" && args.candle_llm != "gemma" {
+        if !use_api && backend.model() != "mistral" && backend.model() != "gemma" {
             // exit if the LLM is not supported
             error!("The specified LLM is not supported. Exiting...");
             std::process::exit(1);
         }
 
         let messages_clone = messages.clone();
-        let llm_host_clone = llm_host.clone();
-        let llm_path_clone = args.llm_path.clone();
-        let model_clone = args.model.clone();
 
-        let prompt_clone = prompt.clone();
-        let llm_thread = if args.use_api || args.use_openai {
-            tokio::spawn(async move {
-                let open_ai_request = OpenAIRequest {
-                    model: &model_clone,
-                    max_tokens: &max_tokens,
-                    messages: messages_clone,
-                    temperature: &args.temperature,
-                    top_p: &args.top_p,
-                    presence_penalty: &args.presence_penalty,
-                    frequency_penalty: &args.frequency_penalty,
-                    stream: &(args.no_stream == false),
-                };
+        // a window close enough to an analyzed one gets the same answer without the llm
+        let window_embedding = stats_window
+            .as_deref()
+            .filter(|_| args.daemon && response_cache.is_enabled())
+            .map(embed);
+        let cached_answer = window_embedding
+            .as_ref()
+            .and_then(|embedding| response_cache.lookup(embedding));
+
+        // a candle answer without a token for --llm-watchdog-secs is given up on so the loop
+        // goes on, and tried again with the system prompt and the newest message only
+        let mut watchdog = LlmWatchdog::new(
+            LlmSource::Main,
+            if cached_answer.is_none() && !use_api {
+                args.llm_watchdog_secs
+            } else {
+                0
+            },
+        );
+        if args.llm_watchdog_retry && !use_api {
+            let reduced: Vec<Message> = messages
+                .iter()
+                .filter(|m| m.role == "system")
+                .chain(messages.iter().rev().find(|m| m.role != "system"))
+                .cloned()
+                .collect();
+            let retry_prompt = format_messages_for_llm(reduced, backend.chat_format(&args));
+            let candle_llm = backend.model().to_string();
+            let model_id = backend.model_id().to_string();
+            let quantized = backend.quantized();
+            watchdog = watchdog.with_retry(
+                &llm_service,
+                Box::new(move |sender| {
+                    LlmRequest::new(
+                        LlmSource::Main,
+                        &candle_llm,
+                        &model_id,
+                        quantized,
+                        retry_prompt,
+                        max_tokens,
+                        sampling,
+                        sender,
+                        batching,
+                    )
+                }),
+            );
+        }
 
-                stream_completion(
-                    open_ai_request,
-                    &openai_key.clone(),
-                    &llm_host_clone,
-                    &llm_path_clone,
-                    args.debug_inline,
-                    args.show_output_errors,
-                    external_sender,
-                )
-                .await;
-            })
-        } else if args.candle_llm == "mistral" {
+        let prompt_clone = prompt.clone();
+        let llm_thread = if let Some((answer, similarity)) = cached_answer.clone() {
+            info!(
+                "Reusing the cached analysis, stats window similarity {:.3}",
+                similarity
+            );
+            log_event(
+                "pipeline",
+                "response_cache_hit",
+                json!({
+                    "iteration": iterations,
+                    "similarity": similarity,
+                }),
+            );
             tokio::spawn(async move {
-                let mistral_clone = mistral.clone();
-                if let Err(e) = mistral_clone(
-                    prompt_clone,
-                    max_tokens as usize,
-                    args.temperature as f64,
-                    args.quantized,
-                    Some(model_id),
-                    external_sender,
-                ) {
-                    eprintln!("Error running mistral: {}", e);
-                }
+                let _ = external_sender.send(replay_tokens(&answer)).await;
+                Ok(())
             })
         } else {
-            tokio::spawn(async move {
-                let gemma_clone = gemma.clone();
-                if let Err(e) = gemma_clone(
-                    prompt_clone,
-                    max_tokens as usize,
-                    args.temperature as f64,
-                    args.quantized,
-                    Some(model_id),
-                    external_sender,
-                ) {
-                    eprintln!("Error running gemma: {}", e);
-                }
-            })
+            backend.spawn_generation(
+                LlmSource::Main,
+                messages_clone,
+                prompt_clone,
+                max_tokens as usize,
+                sampling,
+                external_sender,
+                batching,
+                &llm_service,
+                &args,
+            )
         };
 
         // Count tokens and collect output
         let mut token_count = 0;
         let mut terminal_token_len = 0;
         let mut answers = Vec::new();
-        let mut paragraphs: Vec<String> = Vec::new();
-        let mut current_paragraph: Vec<String> = Vec::new();
-        let mut paragraph_count = 0;
+        let mut segmenter = create_segmenter(&args);
 
         // create uuid unique identifier for the output images
         let output_id = Uuid::new_v4().simple().to_string(); // Generates a UUID and converts it to a simple, hyphen-free string
 
+        // the outro and stinger are rendered while the answer is read
+        if pipeline_enabled(&args) {
+            for message in bumpers.upcoming(iterations as u64, &persona, &output_id, &args) {
+                prefetcher.prefetch(message);
+            }
+        }
+
         //  Initial repeat of the query sent to the pipeline
         if ((!args.continuous && args.twitch_client && twitch_query)
             || (args.twitch_client && twitch_query))
             && args.sd_image
             && (args.tts_enable || args.oai_tts || args.mimic3_tts)
         {
-            let mut sd_config = SDConfig::new();
-            sd_config.prompt = query.clone();
             // reduce prompt down to 300 characters max
-            if sd_config.prompt.len() > 300 {
-                sd_config.prompt = sd_config.prompt.chars().take(300).collect();
-            }
+            let mut prompt: String = query.chars().take(300).collect();
             // append "..." to the prompt if truncated
             if query.len() > 300 {
-                sd_config.prompt.push_str("...");
-            }
-            sd_config.height = Some(args.sd_height);
-            sd_config.width = Some(args.sd_width);
-            sd_config.image_position = Some(args.image_alignment.clone());
-            sd_config.intermediary_images = args.sd_intermediary_images;
-            sd_config.custom_model = Some(args.sd_custom_model.clone());
-            // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-            sd_config.sd_version = if args.sd_model == "1.5" {
-                StableDiffusionVersion::V1_5
-            } else if args.sd_model == "2.1" {
-                StableDiffusionVersion::V2_1
-            } else if args.sd_model == "xl" {
-                StableDiffusionVersion::Xl
-            } else if args.sd_model == "turbo" {
-                StableDiffusionVersion::Turbo
-            } else if args.sd_model == "Custom" {
-                StableDiffusionVersion::Custom
-            } else {
-                StableDiffusionVersion::V1_5
-            };
-            if args.sd_scaled_height > 0 {
-                sd_config.scaled_height = Some(args.sd_scaled_height);
-            }
-            if args.sd_scaled_width > 0 {
-                sd_config.scaled_width = Some(args.sd_scaled_width);
+                prompt.push_str("...");
             }
-            sd_config.n_steps = args.sd_n_steps;
-            // just send a message with the last_message field true to indicate the end of the response
-            let message_data_for_pipeline = MessageData {
-                paragraph: query.clone().to_string(),
-                output_id: output_id.clone(),
-                paragraph_count: total_paragraph_count,
-                sd_config,
-                mimic3_voice: args.mimic3_voice.to_string(),
-                subtitle_position: args.subtitle_position.to_string(),
-                args: args.clone(),
-                shutdown: false,
-                last_message: false,
-            };
+            let sd_config = build_sd_config(&args, &prompt);
+            let message_data_for_pipeline =
+                MessageData::new(&query, &output_id, sd_config, &args).with_voice(&persona.voice);
 
             // For pipeline task
-            pipeline_task_sender
+            pipeline_dispatcher
                 .send(message_data_for_pipeline)
                 .await
                 .expect("Failed to send q/a audio/speech pipeline task");
-
-            total_paragraph_count += 1; // Increment paragraph count for the next paragraph
         }
 
-        while let Some(received) = external_receiver.recv().await {
-            token_count += 1;
-            terminal_token_len += received.len();
-
-            // Store the received token
-            answers.push(received.clone());
-
-            // clean tts input
-            let tts_text = clean_tts_input(current_paragraph.join(""));
-
-            let token_len = count_tokens(&current_paragraph.join(""));
-            // If a newline is at the end of the token, process the accumulated paragraph for image generation
-            if !tts_text.await.is_empty()
-                && received.contains('\n')
-                && !current_paragraph.is_empty()
-                || (token_len as f32 > args.sd_max_length as f32 / 1.8
-                    && (received.contains('.')
-                        || received.contains('?')
-                        || received.contains('\n')
-                        || received.contains(']')
-                        || received.contains('!'))
-                    || (token_len >= (args.sd_max_length as f32) as usize
-                        && (received.contains(' '))))
-            {
-                debug!(
-                    "\nParagraph Token count: {} Character Count: {}",
-                    token_len,
-                    current_paragraph.join("").len()
-                );
-
-                // Join the current paragraph tokens into a single String without adding extra spaces
-                if !current_paragraph.is_empty() {
-                    // check if token has the new line character, split it at the new line into two parts, then put the first part onto
-                    // the current paragraph and the second part into the answers and current_paragraph later after we store the current paragraph
-                    // Safely handle split at the newline character
-                    let mut split_pos = received.len();
-                    let mut found_delimiter = false;
-                    for delimiter in ['\n', ',', '?', '!', ']'] {
-                        if let Some(pos) = received.find(delimiter) {
-                            // Adjust position to keep the delimiter with the first part, except for '\n'
-                            let end_pos = if delimiter == '\n' { pos } else { pos + 1 };
-                            split_pos = split_pos.min(end_pos);
-                            found_delimiter = true;
-                            break; // Break after finding the first delimiter
+        // the tokens go to the terminal through one buffer, flushed once per batch
+        let mut stdout = std::io::BufWriter::new(std::io::stdout());
+        while let Some(batch) = watchdog.recv(&mut external_receiver).await {
+            for received in batch {
+                token_count += 1;
+                terminal_token_len += received.len();
+
+                // Store the received token
+                answers.push(received.clone());
+
+                match segmenter.push_token(&received) {
+                    Segment::Paragraph {
+                        paragraph,
+                        head,
+                        tail,
+                        newline,
+                    } => {
+                        // Token output to stdout in real-time
+                        write!(stdout, "{}", head).unwrap();
+                        if newline {
+                            writeln!(stdout).unwrap();
+                            terminal_token_len = 0;
                         }
-                    }
-                    // Handle '.' and ' ' delimiter separately
-                    if found_delimiter == false {
-                        if split_pos == received.len() {
-                            if let Some(pos) = received.find('.') {
-                                // Adjust position to keep the delimiter with the first part, except for '\n'
-                                let end_pos = pos + 1;
-                                split_pos = split_pos.min(end_pos);
-                            }
-                        } else if split_pos == received.len() {
-                            if let Some(pos) = received.find(' ') {
-                                // Adjust position to keep the delimiter with the first part, except for '\n'
-                                let end_pos = pos + 1;
-                                split_pos = split_pos.min(end_pos);
-                            }
-                        }
-                    }
 
-                    // Split 'received' at the determined position, handling '\n' specifically
-                    let (mut first, mut second) = received.split_at(split_pos);
-
-                    // If splitting on '\n', adjust 'first' and 'second' to not include '\n' in 'first'
-                    let mut nl: bool = false;
-                    if first.ends_with('\n') {
-                        first = &first[..first.len() - 1];
-                        nl = true;
-                    } else if second.starts_with('\n') {
-                        second = &second[1..];
-                        nl = true;
-                    }
+                        // ** Start of TTS and Image Generation **
+                        // Check if image generation or speech is enabled and proceed
+                        if pipeline_enabled(&args) {
+                            let sd_config = build_sd_config(&args, &paragraph);
 
-                    // Store the first part of the split token into the current paragraph
-                    current_paragraph.push(first.to_string());
+                            debug!("Generating images with prompt: {}", sd_config.prompt);
 
-                    let paragraph_text = current_paragraph.join(""); // Join without spaces as indicated
-                    paragraphs.push(paragraph_text.clone());
+                            let message_data_for_pipeline =
+                                MessageData::new(&paragraph, &output_id, sd_config, &args)
+                                    .with_voice(&persona.voice);
 
-                    // Token output to stdout in real-time
-                    print!("{}", first);
-                    if nl {
-                        print!("\n");
-                        terminal_token_len = 0;
-                    } else if current_paragraph.len() >= 80 {
-                        print!("\n");
-                        terminal_token_len = 0;
-                    }
-                    std::io::stdout().flush().unwrap();
-
-                    // Clear current paragraph for the next one
-                    current_paragraph.clear(); // Clear current paragraph for the next one
-
-                    // Store the second part of the split token into the answers and current_paragraph
-                    current_paragraph.push(second.to_string());
-
-                    // ** Start of TTS and Image Generation **
-                    // Check if image generation or speech is enabled and proceed
-                    if args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts {
-                        // Clone necessary data for use in the async block
-                        let mut paragraph_clone = paragraphs[paragraph_count].clone();
-                        while paragraph_clone.contains("**") {
-                            paragraph_clone = paragraph_clone.replace("**", "");
-                        }
-                        let output_id_clone = output_id.clone();
-                        let mimic3_voice = args.mimic3_voice.clone().to_string();
-                        let image_alignment = args.image_alignment.clone();
-                        let subtitle_position = args.subtitle_position.clone();
-                        let args = args.clone();
-
-                        let pipeline_task_sender_clone = pipeline_task_sender.clone();
-
-                        let mut sd_config = SDConfig::new();
-                        sd_config.prompt = paragraph_clone;
-                        sd_config.height = Some(args.sd_height);
-                        sd_config.width = Some(args.sd_width);
-                        sd_config.image_position = Some(image_alignment);
-                        sd_config.intermediary_images = args.sd_intermediary_images;
-                        sd_config.custom_model = Some(args.sd_custom_model.clone());
-                        if args.sd_scaled_height > 0 {
-                            sd_config.scaled_height = Some(args.sd_scaled_height);
-                        }
-                        if args.sd_scaled_width > 0 {
-                            sd_config.scaled_width = Some(args.sd_scaled_width);
+                            // For image tasks
+                            pipeline_dispatcher
+                                .send(message_data_for_pipeline)
+                                .await
+                                .expect("Failed to send image/speech pipeline task");
                         }
-                        // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-                        sd_config.sd_version = if args.sd_model == "1.5" {
-                            StableDiffusionVersion::V1_5
-                        } else if args.sd_model == "2.1" {
-                            StableDiffusionVersion::V2_1
-                        } else if args.sd_model == "xl" {
-                            StableDiffusionVersion::Xl
-                        } else if args.sd_model == "turbo" {
-                            StableDiffusionVersion::Turbo
-                        } else if args.sd_model == "babes" {
-                            StableDiffusionVersion::Custom
-                        } else {
-                            StableDiffusionVersion::V1_5
-                        };
-                        sd_config.n_steps = args.sd_n_steps;
-
-                        let args_clone = args.clone();
-                        let mimic3_voice_clone = mimic3_voice.clone();
-                        let subtitle_position_clone = subtitle_position.clone();
-
-                        debug!("Generating images with prompt: {}", sd_config.prompt);
-
-                        // Create MessageData for image task
-                        let message_data_for_pipeline = MessageData {
-                            paragraph: sd_config.prompt.clone(),
-                            output_id: output_id_clone.clone(),
-                            paragraph_count: total_paragraph_count,
-                            sd_config: sd_config.clone(),
-                            mimic3_voice: mimic3_voice_clone.clone(),
-                            subtitle_position: subtitle_position_clone.clone(),
-                            args: args_clone.clone(),
-                            shutdown: false,
-                            last_message: false,
-                        };
-
-                        // For image tasks
-                        pipeline_task_sender_clone
-                            .send(message_data_for_pipeline)
-                            .await
-                            .expect("Failed to send image/speech pipeline task");
+                        // ** End of TTS and Image Generation **
 
-                        total_paragraph_count += 1; // Increment paragraph count for the next paragraph
+                        // Token output to stdout in real-time
+                        write!(stdout, "{}", tail).unwrap();
+                    }
+                    Segment::Token => {
+                        // Call the function to handle the string if it exceeds 80 characters
+                        handle_long_string(&mut stdout, &received, &mut terminal_token_len)
+                            .unwrap();
                     }
-                    // ** End of TTS and Image Generation **
-
-                    // Token output to stdout in real-time
-                    print!("{}", second);
-                    std::io::stdout().flush().unwrap();
-
-                    paragraph_count += 1; // Increment paragraph count for the next paragraph
-                } else {
-                    // store the token in the current paragraph
-                    current_paragraph.push(received.clone());
-
-                    // Call the function to handle the string if it exceeds 80 characters
-                    handle_long_string(&received, &mut terminal_token_len);
-
-                    std::io::stdout().flush().unwrap();
                 }
-            } else {
-                // store the token in the current paragraph
-                current_paragraph.push(received.clone());
-
-                // Call the function to handle the string if it exceeds 80 characters
-                handle_long_string(&received, &mut terminal_token_len);
-
-                std::io::stdout().flush().unwrap();
             }
+            // stdout is flushed once per batch instead of per token
+            stdout.flush().unwrap();
         }
 
-        // clean tts input
-        let tts_text = clean_tts_input(current_paragraph.join(""));
-
-        // Join the last paragraph tokens into a single String without adding extra spaces
-        if !tts_text.await.is_empty() && current_paragraph.len() > 0 {
+        // Send the last paragraph tokens to the pipeline
+        if let Some(paragraph) = segmenter.finish() {
             // ** Start of TTS and Image Generation **
             // Check if image generation is enabled and proceed
-            if args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts {
-                // Clone necessary data for use in the async block
-                let mut paragraph_text = current_paragraph.join(""); // Join without spaces as indicated
-                while paragraph_text.contains("**") {
-                    paragraph_text = paragraph_text.replace("**", "");
-                }
-                let paragraph_clone = paragraph_text.clone();
-                let output_id_clone = output_id.clone();
-                let mimic3_voice = args.mimic3_voice.clone().to_string();
-                let image_alignment = args.image_alignment.clone();
-                let subtitle_position = args.subtitle_position.clone();
-                let args = args.clone();
-
-                let pipeline_task_sender_clone = pipeline_task_sender.clone();
-
-                let mut sd_config = SDConfig::new();
-                sd_config.prompt = paragraph_clone;
-                sd_config.height = Some(args.sd_height);
-                sd_config.width = Some(args.sd_width);
-                sd_config.image_position = Some(image_alignment);
-                sd_config.intermediary_images = args.sd_intermediary_images;
-                sd_config.custom_model = Some(args.sd_custom_model.clone());
-                if args.sd_scaled_height > 0 {
-                    sd_config.scaled_height = Some(args.sd_scaled_height);
-                }
-                if args.sd_scaled_width > 0 {
-                    sd_config.scaled_width = Some(args.sd_scaled_width);
-                }
-                // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-                sd_config.sd_version = if args.sd_model == "1.5" {
-                    StableDiffusionVersion::V1_5
-                } else if args.sd_model == "2.1" {
-                    StableDiffusionVersion::V2_1
-                } else if args.sd_model == "xl" {
-                    StableDiffusionVersion::Xl
-                } else if args.sd_model == "turbo" {
-                    StableDiffusionVersion::Turbo
-                } else if args.sd_model == "babes" {
-                    StableDiffusionVersion::Custom
-                } else {
-                    StableDiffusionVersion::V1_5
-                };
-                sd_config.n_steps = args.sd_n_steps;
-
-                let args_clone = args.clone();
-                let mimic3_voice_clone = mimic3_voice.clone();
-                let subtitle_position_clone = subtitle_position.clone();
-
-                // Create MessageData for pipeline task
-                let message_data_for_pipeline = MessageData {
-                    paragraph: sd_config.prompt.clone(), // Clone for the image task
-                    output_id: output_id_clone.clone(),
-                    paragraph_count: total_paragraph_count,
-                    sd_config: sd_config.clone(), // Assuming SDConfig is set up previously and is cloneable
-                    mimic3_voice: mimic3_voice_clone.clone(),
-                    subtitle_position: subtitle_position_clone.clone(),
-                    args: args_clone.clone(),
-                    shutdown: false,
-                    last_message: false,
-                };
+            if pipeline_enabled(&args) {
+                let sd_config = build_sd_config(&args, &paragraph);
+                let message_data_for_pipeline =
+                    MessageData::new(&paragraph, &output_id, sd_config, &args)
+                        .with_voice(&persona.voice);
 
                 // For pipeline task
-                pipeline_task_sender_clone
+                pipeline_dispatcher
                     .send(message_data_for_pipeline)
                     .await
                     .expect("Failed to send last audio/speech pipeline task");
-
-                total_paragraph_count += 1; // Increment paragraph count for the next paragraph
             }
             // ** End of TTS and Image Generation **
-            paragraph_count += 1; // Increment paragraph count for the next paragraph
         }
 
-        // End of the response message to the pipeline
-        if args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts {
-            let mut sd_config = SDConfig::new();
-            sd_config.prompt = args.greeting.clone();
-            sd_config.height = Some(args.sd_height);
-            sd_config.width = Some(args.sd_width);
-            sd_config.image_position = Some(args.image_alignment.clone());
-            sd_config.intermediary_images = args.sd_intermediary_images;
-            sd_config.custom_model = Some(args.sd_custom_model.clone());
-            if args.sd_scaled_height > 0 {
-                sd_config.scaled_height = Some(args.sd_scaled_height);
+        info!("Waiting for LLM thread to finish...");
+        // Wait for the LLM thread to finish
+        let generation = llm_thread.await.unwrap();
+        info!("LLM thread finished.");
+
+        // an endpoint still down after the retries gave no answer, the iteration is tried
+        // again with the history it started with instead of closing an empty answer. Other
+        // errors, like a prompt over the context length, reset the history below.
+        let endpoint_down = match generation {
+            Err(ApiError::Unavailable(e)) if token_count == 0 => {
+                error!("No answer from the LLM, trying again: {}", e);
+                log_event(
+                    "pipeline",
+                    "llm_endpoint_down",
+                    json!({ "iteration": iterations, "error": e.to_string() }),
+                );
+                true
             }
-            if args.sd_scaled_width > 0 {
-                sd_config.scaled_width = Some(args.sd_scaled_width);
+            Err(e) if token_count == 0 => {
+                error!("The LLM request failed: {}", e);
+                false
             }
-            // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-            sd_config.sd_version = if args.sd_model == "1.5" {
-                StableDiffusionVersion::V1_5
-            } else if args.sd_model == "2.1" {
-                StableDiffusionVersion::V2_1
-            } else if args.sd_model == "xl" {
-                StableDiffusionVersion::Xl
-            } else if args.sd_model == "turbo" {
-                StableDiffusionVersion::Turbo
-            } else if args.sd_model == "babes" {
-                StableDiffusionVersion::Custom
-            } else {
-                StableDiffusionVersion::V1_5
-            };
-            sd_config.n_steps = args.sd_n_steps;
-
-            /* clone args and set the cloned args args.subtitles to true */
-            let mut args_clone = args.clone();
-            /* set args_clone.subtitles to true */
-            args_clone.subtitles = true;
-
-            // just send a message with the last_message field true to indicate the end of the response
-            let message_data_for_pipeline = MessageData {
-                paragraph: args.greeting.to_string(),
-                output_id: output_id.clone(),
-                paragraph_count: total_paragraph_count,
-                sd_config,
-                mimic3_voice: args.mimic3_voice.to_string(),
-                subtitle_position: "center".to_string(),
-                args: args_clone,
-                shutdown: false,
-                last_message: true,
-            };
+            Err(e) => {
+                error!("The LLM answer was cut off: {}", e);
+                false
+            }
+            Ok(()) => false,
+        };
 
-            // For pipeline task
-            pipeline_task_sender
-                .send(message_data_for_pipeline)
+        // End of the response message to the pipeline
+        if pipeline_enabled(&args) && !endpoint_down {
+            // the outro has the last_message field true to indicate the end of the response
+            pipeline_dispatcher
+                .send(bumpers.outro(&persona, &output_id, &args))
                 .await
                 .expect("Failed to send last audio/speech pipeline task");
 
-            total_paragraph_count += 1; // Increment paragraph count for the next paragraph
+            // a stinger between this answer and the next
+            if let Some(stinger) = bumpers.stinger(iterations as u64, &persona, &args) {
+                pipeline_dispatcher
+                    .send(stinger)
+                    .await
+                    .expect("Failed to send stinger pipeline task");
+            }
         }
 
         if loglevel != "error" {
             println!("\n");
             std::io::stdout().flush().unwrap();
         }
-        info!("Waiting for LLM thread to finish...");
-        // Wait for the LLM thread to finish
-        llm_thread.await.unwrap();
-        info!("LLM thread finished.");
-
         // Calculate elapsed time and tokens per second
         let elapsed = start.elapsed().as_secs_f64();
         let tokens_per_second = token_count as f64 / elapsed;
+        record_generation(token_count, elapsed, cached_answer.is_some(), use_api);
 
         let answers_str = answers.join("").to_string();
+        if let Some(embedding) = window_embedding {
+            if cached_answer.is_none() && token_count > 0 {
+                response_cache.insert(embedding, answers_str.clone());
+            }
+        }
+        if let Some(recorder) = run_recorder.as_ref() {
+            recorder.iteration(&IterationRecord {
+                iteration: iterations as u64,
+                output_id: output_id.clone(),
+                started_ms,
+                model: backend.model().to_string(),
+                model_id: backend.model_id().to_string(),
+                quantized: backend.quantized(),
+                max_tokens: max_tokens as usize,
+                sampling: SamplingRecord::new(&sampling),
+                // the api gets the messages, the candle models the formatted prompt
+                prompt: if use_api {
+                    serde_json::to_string(&messages).unwrap_or_default()
+                } else {
+                    prompt.clone()
+                },
+                answer: answers_str.clone(),
+                tokens: token_count,
+                cached: cached_answer.is_some(),
+            });
+        }
 
         println!("\n=======================================");
         println!(
             "#[{}] ({}) {}/{}/{} imgs/tkns/chrs in {:.2?}s @ {:.2}tps",
             iterations,
             output_id,
-            paragraph_count,
+            segmenter.paragraph_count(),
             token_count,
             answers_str.len(),
             elapsed,
@@ -1640,7 +2030,12 @@ async fn main() {
         println!("============= END RESPONSE ============");
 
         // check if we got any tokens, if not clear and reset message history
-        if token_count == 0 {
+        if endpoint_down {
+            messages.truncate(history_len);
+            if twitch_query {
+                retry_query = Some(query.clone());
+            }
+        } else if token_count == 0 {
             messages.clear();
             messages.push(system_message.clone());
         } else {
@@ -1650,30 +2045,32 @@ async fn main() {
                 content: answers_str.clone(),
             });
         }
+        // checkpoint of the iteration for !rewind
+        history_tree.lock().await.checkpoint(
+            iterations as u64,
+            current_unix_timestamp_ms().unwrap_or(0),
+            &messages,
+        );
 
-        #[cfg(feature = "ndi")]
-        if !args.async_concurrency
-            && (args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts)
-        {
-            // set a timer to wait for the NDI done signal only so long then if not sent then continue
-            //let ndi_done_rx = ndi_done_rx.clone();
-            let ndi_done_timeout =
+        if output_enabled && !args.async_concurrency && pipeline_enabled(&args) && !endpoint_down {
+            // set a timer to wait for the output done signal only so long then if not sent then continue
+            let output_done_timeout =
                 tokio::time::timeout(std::time::Duration::from_secs(args.ndi_timeout), async {
-                    // Wait for the NDI done signal
+                    // Wait for the output done signal
                     std::io::stdout().flush().unwrap();
                     info!(
-                        "Waiting for NDI done signal for LLM message {}...",
-                        total_paragraph_count - 1
+                        "Waiting for output done signal for LLM message {}...",
+                        pipeline_dispatcher.total_paragraph_count().await - 1
                     );
-                    ndi_done_rx.recv().await;
-                    info!("Received NDI done signal.");
+                    output_done_rx.recv().await;
+                    info!("Received output done signal.");
                 });
-            match ndi_done_timeout.await {
+            match output_done_timeout.await {
                 Ok(_) => {
-                    info!("NDI done signal received.");
+                    info!("Output done signal received.");
                 }
                 Err(_) => {
-                    info!("NDI done signal timeout.");
+                    info!("Output done signal timeout.");
                 }
             }
         }
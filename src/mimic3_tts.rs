@@ -4,7 +4,7 @@ use log::debug;
 use reqwest::Client;
 use serde::Serialize;
 
-const ENDPOINT: &str = "http://localhost:59125/api/tts"; // Mimic3 endpoint
+pub const ENDPOINT: &str = "http://localhost:59125/api/tts"; // Mimic3 endpoint
 
 #[derive(Serialize, Debug)]
 pub struct Request {
@@ -0,0 +1,111 @@
+/*
+ * moderation.rs
+ * -------------
+ * Moderation of viewer requests from chat, image prompts before they reach stable diffusion
+ * and text to be read out. Requests are refused for blocked terms, from a built in list and
+ * an optional blocklist file, for prompt injection phrases and for length, the images
+ * themselves still go through the safety checker.
+*/
+
+use crate::args::Args;
+use crate::event_log::log_event;
+use crate::prompt_guard::{escape, injection_score};
+use log::{error, warn};
+use serde_json::json;
+
+// Terms refused in any viewer request
+const DEFAULT_BLOCKED_TERMS: [&str; 12] = [
+    "nsfw", "nude", "naked", "nudity", "porn", "hentai", "topless", "lingerie", "gore", "gory",
+    "explicit", "erotic",
+];
+
+pub struct Moderation {
+    pub max_chars: usize,
+    pub blocked_terms: Vec<String>,
+    pub injection_threshold: f32,
+}
+
+impl Moderation {
+    pub fn new(max_chars: usize, injection_threshold: f32) -> Self {
+        Moderation {
+            max_chars,
+            blocked_terms: DEFAULT_BLOCKED_TERMS
+                .iter()
+                .map(|term| term.to_string())
+                .collect(),
+            injection_threshold,
+        }
+    }
+
+    // Built in terms plus the --twitch-image-blocklist file, one term or phrase per line
+    pub fn from_args(args: &Args, max_chars: usize) -> Self {
+        let mut moderation = Moderation::new(max_chars, args.prompt_guard_threshold);
+        if !args.twitch_image_blocklist.is_empty() {
+            match std::fs::read_to_string(&args.twitch_image_blocklist) {
+                Ok(contents) => moderation.blocked_terms.extend(
+                    contents
+                        .lines()
+                        .map(|line| line.trim().to_lowercase())
+                        .filter(|line| !line.is_empty() && !line.starts_with('#')),
+                ),
+                Err(e) => error!(
+                    "Failed to read the image blocklist {}: {}",
+                    args.twitch_image_blocklist, e
+                ),
+            }
+        }
+        moderation
+    }
+
+    fn blocked_term(&self, text: &str) -> Option<&str> {
+        let words: Vec<String> = text
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_string())
+            .collect();
+        let text = format!(" {} ", words.join(" "));
+        self.blocked_terms
+            .iter()
+            .find(|term| text.contains(&format!(" {} ", term)))
+            .map(|term| term.as_str())
+    }
+
+    // The text of the image or tts request ready to use, or the reason it was refused to tell
+    // the viewer
+    pub fn check(&self, kind: &str, requester: &str, text: &str) -> Result<String, String> {
+        let text = escape(text)
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let refusal = if text.is_empty() {
+            Some("there is nothing after the command".to_string())
+        } else if self.max_chars > 0 && text.chars().count() > self.max_chars {
+            Some(format!("it is longer than {} characters", self.max_chars))
+        } else if let Some(term) = self.blocked_term(&text) {
+            Some(format!("\"{}\" is not allowed", term))
+        } else if injection_score(&text) >= self.injection_threshold {
+            Some("it looks like instructions".to_string())
+        } else {
+            None
+        };
+
+        match refusal {
+            Some(reason) => {
+                warn!("Refused {} request from {}: {}", kind, requester, reason);
+                log_event(
+                    "moderation",
+                    "refused",
+                    json!({
+                        "kind": kind,
+                        "requester": requester,
+                        "text": text,
+                        "reason": reason,
+                    }),
+                );
+                Err(reason)
+            }
+            None => Ok(text),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::event_log::log_event;
 use crate::hexdump;
 use crate::stream_data::StreamData;
 use h264_reader::annexb::AnnexBReader;
@@ -13,6 +14,7 @@ use mpeg2ts_reader::pes;
 use mpeg2ts_reader::psi;
 use mpeg2ts_reader::StreamType;
 use scte35_reader;
+use serde_json::json;
 use std::cell;
 use std::cmp;
 use std::collections::HashMap;
@@ -137,6 +139,8 @@ impl scte35_reader::SpliceInfoProcessor for DumpSpliceInfoProcessor {
             }
             print!("{:?} {:#?}", header, command);
         }
+        let command_text = format!("{:?}", command);
+        let mut ms_after_pcr = None;
         if let scte35_reader::SpliceCommand::SpliceInsert { splice_detail, .. } = command {
             if let scte35_reader::SpliceInsert::Insert { splice_mode, .. } = splice_detail {
                 if let scte35_reader::SpliceMode::Program(scte35_reader::SpliceTime::Timed(t)) =
@@ -152,6 +156,7 @@ impl scte35_reader::SpliceInfoProcessor for DumpSpliceInfoProcessor {
                             if DEBUG_SCTE35 {
                                 print!(" {}ms after last PCR", diff / 90);
                             }
+                            ms_after_pcr = Some(diff / 90);
                         }
                     }
                 }
@@ -160,6 +165,15 @@ impl scte35_reader::SpliceInfoProcessor for DumpSpliceInfoProcessor {
         if DEBUG_SCTE35 {
             println!();
         }
+        log_event(
+            "analyzer",
+            "scte35",
+            json!({
+                "pid": self.elementary_pid.map(u16::from),
+                "command": command_text,
+                "ms_after_pcr": ms_after_pcr,
+            }),
+        );
         for d in &descriptors {
             if DEBUG_SCTE35 {
                 println!(" - {:#?}", d);
@@ -0,0 +1,382 @@
+/*
+ * multicast.rs
+ * ------------
+ * Multicast group membership of the capture devices. pcap only listens, the switches and
+ * routers forward a group to the port once a socket on the host has joined it, so each
+ * capture holds a membership for its device: an any-source join or IGMPv3/MLDv2
+ * source-specific joins for the SSM sources. The membership checks the device every few
+ * seconds and joins again when its address or index changed, it leaves the group when the
+ * capture stops. The state of each membership is kept for the control API.
+*/
+
+use crate::current_unix_timestamp_ms;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use pcap::Device;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::error::Error as StdError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static MEMBERSHIPS: Lazy<Mutex<Vec<MembershipState>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Time between the checks of the device
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MembershipState {
+    pub device: String,
+    pub group: String,
+    pub sources: Vec<String>,      // empty for any-source
+    pub interface: Option<String>, // address or index joined on
+    pub joined: bool,
+    pub joins: u32,
+    pub error: Option<String>,
+    pub changed_ms: u64,
+}
+
+// The memberships of the running captures
+pub fn multicast_memberships() -> Vec<MembershipState> {
+    MEMBERSHIPS.lock().unwrap().clone()
+}
+
+fn publish(state: &MembershipState) {
+    let mut memberships = MEMBERSHIPS.lock().unwrap();
+    memberships.retain(|entry| entry.device != state.device || entry.group != state.group);
+    memberships.push(state.clone());
+}
+
+fn unpublish(state: &MembershipState) {
+    MEMBERSHIPS
+        .lock()
+        .unwrap()
+        .retain(|entry| entry.device != state.device || entry.group != state.group);
+}
+
+// What a group is joined on, IPv4 joins name the interface by address and IPv6 by index
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Interface {
+    V4(Ipv4Addr),
+    V6(u32),
+}
+
+impl Interface {
+    fn of(group: &IpAddr, device: &Device) -> Option<Interface> {
+        match group {
+            IpAddr::V4(_) => device.addresses.iter().find_map(|addr| match addr.addr {
+                IpAddr::V4(address) => Some(Interface::V4(address)),
+                _ => None,
+            }),
+            IpAddr::V6(_) => match interface_index(&device.name) {
+                0 => None,
+                index => Some(Interface::V6(index)),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Interface {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Interface::V4(address) => write!(f, "{}", address),
+            Interface::V6(index) => write!(f, "index {}", index),
+        }
+    }
+}
+
+pub struct MulticastMembership {
+    socket: Socket,
+    group: IpAddr,
+    sources: Vec<IpAddr>,
+    interface: Option<Interface>,
+    state: MembershipState,
+}
+
+impl MulticastMembership {
+    // Join the group on the device, nothing is joined when the address is not a group
+    pub fn join(
+        group: &IpAddr,
+        sources: &[IpAddr],
+        device: &Device,
+    ) -> Result<Self, Box<dyn StdError>> {
+        let domain = if group.is_ipv6() {
+            Domain::IPV6
+        } else {
+            Domain::IPV4
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        let mut membership = MulticastMembership {
+            socket,
+            group: *group,
+            sources: sources
+                .iter()
+                .filter(|source| source.is_ipv6() == group.is_ipv6())
+                .copied()
+                .collect(),
+            interface: None,
+            state: MembershipState {
+                device: device.name.clone(),
+                group: group.to_string(),
+                sources: sources.iter().map(|source| source.to_string()).collect(),
+                interface: None,
+                joined: false,
+                joins: 0,
+                error: None,
+                changed_ms: current_unix_timestamp_ms().unwrap_or(0),
+            },
+        };
+        if !group.is_multicast() {
+            info!("multicast: {} is not a multicast group, no join", group);
+            return Ok(membership);
+        }
+        for source in sources.iter().filter(|s| s.is_ipv6() != group.is_ipv6()) {
+            error!(
+                "multicast: SSM source {} is not the family of {}, skipped",
+                source, group
+            );
+        }
+
+        let interface = Interface::of(group, device)
+            .ok_or_else(|| format!("No interface of {} to join {} on", device.name, group))?;
+        membership.join_on(interface)?;
+        Ok(membership)
+    }
+
+    // Name of the device the group is joined on
+    pub fn device(&self) -> &str {
+        &self.state.device
+    }
+
+    fn join_on(&mut self, interface: Interface) -> std::io::Result<()> {
+        let result = self.membership(interface, true);
+        self.state.changed_ms = current_unix_timestamp_ms().unwrap_or(0);
+        match &result {
+            Ok(()) => {
+                self.interface = Some(interface);
+                self.state.interface = Some(interface.to_string());
+                self.state.joined = true;
+                self.state.joins += 1;
+                self.state.error = None;
+            }
+            Err(e) => {
+                self.state.joined = false;
+                self.state.error = Some(e.to_string());
+            }
+        }
+        publish(&self.state);
+        result
+    }
+
+    fn leave(&mut self) {
+        let Some(interface) = self.interface.take() else {
+            return;
+        };
+        if let Err(e) = self.membership(interface, false) {
+            // the interface may be gone with its memberships
+            warn!(
+                "multicast: Failed to leave {} on {}: {}",
+                self.group, self.state.device, e
+            );
+        }
+        self.state.joined = false;
+        self.state.changed_ms = current_unix_timestamp_ms().unwrap_or(0);
+        publish(&self.state);
+    }
+
+    // Join or leave the group and its sources on the interface
+    fn membership(&self, interface: Interface, join: bool) -> std::io::Result<()> {
+        let action = if join { "Join" } else { "Leave" };
+        match (self.group, interface) {
+            (IpAddr::V4(group), Interface::V4(address)) => {
+                if self.sources.is_empty() {
+                    info!(
+                        "multicast: {} IGMP Multicast for {} on interface {}.",
+                        action, group, address
+                    );
+                    if join {
+                        self.socket.join_multicast_v4(&group, &address)?;
+                    } else {
+                        self.socket.leave_multicast_v4(&group, &address)?;
+                    }
+                }
+                for source in &self.sources {
+                    let IpAddr::V4(source) = source else {
+                        continue;
+                    };
+                    info!(
+                        "multicast: {} IGMPv3 SSM for {} from {} on interface {}.",
+                        action, group, source, address
+                    );
+                    if join {
+                        self.socket.join_ssm_v4(source, &group, &address)?;
+                    } else {
+                        self.socket.leave_ssm_v4(source, &group, &address)?;
+                    }
+                }
+            }
+            (IpAddr::V6(group), Interface::V6(index)) => {
+                if self.sources.is_empty() {
+                    info!(
+                        "multicast: {} MLD Multicast for {} on interface {} index {}.",
+                        action, group, self.state.device, index
+                    );
+                    if join {
+                        self.socket.join_multicast_v6(&group, index)?;
+                    } else {
+                        self.socket.leave_multicast_v6(&group, index)?;
+                    }
+                }
+                for source in &self.sources {
+                    let IpAddr::V6(source) = source else {
+                        continue;
+                    };
+                    info!(
+                        "multicast: {} MLDv2 SSM for {} from {} on interface {} index {}.",
+                        action, group, source, self.state.device, index
+                    );
+                    ssm_v6(&self.socket, source, &group, index, join)?;
+                }
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the interface is not of the group's address family",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Join again when the device's address or index changed, leave when it is gone
+    pub fn refresh(&mut self) {
+        if !self.group.is_multicast() {
+            return;
+        }
+        let device = match Device::list() {
+            Ok(devices) => devices.into_iter().find(|d| d.name == self.state.device),
+            Err(e) => {
+                warn!("multicast: Failed to list the devices: {}", e);
+                return;
+            }
+        };
+        let interface = device.and_then(|device| Interface::of(&self.group, &device));
+        if interface == self.interface {
+            return;
+        }
+        match interface {
+            Some(interface) => {
+                info!(
+                    "multicast: {} changed to {}, joining {} again",
+                    self.state.device, interface, self.group
+                );
+                self.leave();
+                if let Err(e) = self.join_on(interface) {
+                    error!(
+                        "multicast: Failed to join {} on {}: {}",
+                        self.group, self.state.device, e
+                    );
+                }
+            }
+            None => {
+                warn!(
+                    "multicast: {} has no interface for {}, left the group",
+                    self.state.device, self.group
+                );
+                self.leave();
+                self.state.error = Some("no interface".to_string());
+                publish(&self.state);
+            }
+        }
+    }
+
+    // Keep the membership up to date until the capture stops, then leave
+    pub async fn maintain(mut self, running: impl Fn() -> bool) {
+        let mut since_refresh = Duration::ZERO;
+        let tick = Duration::from_secs(1);
+        while running() {
+            tokio::time::sleep(tick).await;
+            since_refresh += tick;
+            if since_refresh >= REFRESH_INTERVAL {
+                since_refresh = Duration::ZERO;
+                self.refresh();
+            }
+        }
+    }
+}
+
+impl Drop for MulticastMembership {
+    fn drop(&mut self) {
+        self.leave();
+        unpublish(&self.state);
+    }
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> u32 {
+    match std::ffi::CString::new(name) {
+        Ok(name) => unsafe { libc::if_nametoindex(name.as_ptr()) },
+        Err(_) => 0,
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> u32 {
+    0 // the default interface
+}
+
+// socket2 only has the IPv4 source-specific membership, MCAST_JOIN_SOURCE_GROUP and
+// MCAST_LEAVE_SOURCE_GROUP cover IPv6
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn ssm_v6(
+    socket: &Socket,
+    source: &Ipv6Addr,
+    group: &Ipv6Addr,
+    interface_index: u32,
+    join: bool,
+) -> std::io::Result<()> {
+    use socket2::SockAddr;
+    use std::net::{SocketAddr, SocketAddrV6};
+    use std::os::fd::AsRawFd;
+
+    let request = libc::group_source_req {
+        gsr_interface: interface_index,
+        gsr_group: SockAddr::from(SocketAddr::V6(SocketAddrV6::new(*group, 0, 0, 0))).as_storage(),
+        gsr_source: SockAddr::from(SocketAddr::V6(SocketAddrV6::new(*source, 0, 0, 0)))
+            .as_storage(),
+    };
+    let option = if join {
+        libc::MCAST_JOIN_SOURCE_GROUP
+    } else {
+        libc::MCAST_LEAVE_SOURCE_GROUP
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            option,
+            &request as *const libc::group_source_req as *const libc::c_void,
+            std::mem::size_of::<libc::group_source_req>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn ssm_v6(
+    _socket: &Socket,
+    _source: &Ipv6Addr,
+    _group: &Ipv6Addr,
+    _interface_index: u32,
+    _join: bool,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "IPv6 source-specific multicast is not supported on this OS",
+    ))
+}
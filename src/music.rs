@@ -0,0 +1,214 @@
+/*
+ * music.rs
+ * --------
+ * Background music bed mixed under the speech. The tracks in --music-dir are sorted into low,
+ * medium and high intensity by their file or directory names and the intensity of each
+ * paragraph's sentiment picks the track, a new track fades in when the intensity changes.
+*/
+
+use crate::audio::{decode_audio, resample};
+use crate::emotion::Sentiment;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+// fade in of a new track
+const MUSIC_FADE_IN_MS: u64 = 1500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MusicIntensity {
+    Low,
+    Medium,
+    High,
+}
+
+impl MusicIntensity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MusicIntensity::Low => "low",
+            MusicIntensity::Medium => "medium",
+            MusicIntensity::High => "high",
+        }
+    }
+
+    pub fn from_sentiment(sentiment: &Sentiment) -> Self {
+        if sentiment.arousal < 0.35 {
+            MusicIntensity::Low
+        } else if sentiment.arousal < 0.7 {
+            MusicIntensity::Medium
+        } else {
+            MusicIntensity::High
+        }
+    }
+
+    // Intensity named in the path like calm/low/track.wav or track_high.mp3
+    fn from_path(path: &Path) -> Option<Self> {
+        let path = path.to_string_lossy().to_lowercase();
+        [
+            (MusicIntensity::High, ["high", "intense"]),
+            (MusicIntensity::Low, ["low", "calm"]),
+            (MusicIntensity::Medium, ["medium", "mid"]),
+        ]
+        .iter()
+        .find(|(_, names)| names.iter().any(|name| path.contains(name)))
+        .map(|(intensity, _)| *intensity)
+    }
+}
+
+struct Track {
+    name: String,
+    samples: Vec<f32>,
+    sample_rate: u32,
+}
+
+pub struct MusicBed {
+    tracks: HashMap<MusicIntensity, Vec<Track>>,
+    volume: f32,
+    playing: Option<(MusicIntensity, usize)>, // intensity and index of the track
+    position: usize,
+    fade_position: usize,
+}
+
+fn find_tracks(directory: &Path, tracks: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_tracks(&path, tracks)?;
+        } else if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("wav") | Some("mp3")
+        ) {
+            tracks.push(path);
+        }
+    }
+    Ok(())
+}
+
+impl MusicBed {
+    // Load the tracks of the directory, files without an intensity in their path are medium
+    pub fn load(directory: &str, volume: f32) -> std::io::Result<Self> {
+        let mut paths = Vec::new();
+        find_tracks(Path::new(directory), &mut paths)?;
+        paths.sort();
+
+        let mut tracks: HashMap<MusicIntensity, Vec<Track>> = HashMap::new();
+        for path in paths {
+            let intensity =
+                MusicIntensity::from_path(path.strip_prefix(directory).unwrap_or(&path))
+                    .unwrap_or(MusicIntensity::Medium);
+            match std::fs::read(&path).and_then(|data| decode_audio(data, 44100)) {
+                Ok((samples, sample_rate)) if !samples.is_empty() => {
+                    tracks.entry(intensity).or_default().push(Track {
+                        name: path.display().to_string(),
+                        samples,
+                        sample_rate,
+                    })
+                }
+                Ok(_) => error!("Music track {} has no audio", path.display()),
+                Err(e) => error!("Failed to load music track {}: {}", path.display(), e),
+            }
+        }
+        info!(
+            "Music bed loaded {} low, {} medium and {} high intensity tracks from {}",
+            tracks.get(&MusicIntensity::Low).map_or(0, |t| t.len()),
+            tracks.get(&MusicIntensity::Medium).map_or(0, |t| t.len()),
+            tracks.get(&MusicIntensity::High).map_or(0, |t| t.len()),
+            directory
+        );
+        Ok(MusicBed {
+            tracks,
+            volume,
+            playing: None,
+            position: 0,
+            fade_position: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    // Tracks of the intensity or the closest one there are tracks for
+    fn select(&self, intensity: MusicIntensity) -> Option<MusicIntensity> {
+        let order = match intensity {
+            MusicIntensity::Low => [
+                MusicIntensity::Low,
+                MusicIntensity::Medium,
+                MusicIntensity::High,
+            ],
+            MusicIntensity::Medium => [
+                MusicIntensity::Medium,
+                MusicIntensity::Low,
+                MusicIntensity::High,
+            ],
+            MusicIntensity::High => [
+                MusicIntensity::High,
+                MusicIntensity::Medium,
+                MusicIntensity::Low,
+            ],
+        };
+        order
+            .into_iter()
+            .find(|intensity| self.tracks.contains_key(intensity))
+    }
+
+    // Mix the music of the intensity into the samples, the track carries on across paragraphs
+    // with the same intensity and loops at its end
+    pub fn mix(&mut self, samples: &mut [f32], sample_rate: u32, intensity: MusicIntensity) {
+        let Some(selected) = self.select(intensity) else {
+            return;
+        };
+        let index = match self.playing {
+            Some((playing, index)) if playing == selected => index,
+            playing => {
+                // the next track of the intensity so the music varies
+                let count = self.tracks[&selected].len();
+                let index = match playing {
+                    Some((_, index)) => (index + 1) % count,
+                    None => 0,
+                };
+                self.playing = Some((selected, index));
+                self.position = 0;
+                self.fade_position = 0;
+                index
+            }
+        };
+
+        let track = &mut self.tracks.get_mut(&selected).unwrap()[index];
+        if track.sample_rate != sample_rate {
+            match resample(&track.samples, track.sample_rate, sample_rate) {
+                Ok(resampled) => {
+                    track.samples = resampled;
+                    track.sample_rate = sample_rate;
+                }
+                Err(e) => {
+                    error!("Failed to resample music track {}: {}", track.name, e);
+                    return;
+                }
+            }
+        }
+        debug!(
+            "Mixing {} intensity music {} at {}",
+            selected.name(),
+            track.name,
+            self.position
+        );
+
+        let fade_samples = (MUSIC_FADE_IN_MS * sample_rate as u64 / 1000) as usize;
+        for sample in samples.iter_mut() {
+            if self.position >= track.samples.len() {
+                self.position = 0;
+            }
+            let fade = if self.fade_position < fade_samples {
+                self.fade_position += 1;
+                self.fade_position as f32 / fade_samples as f32
+            } else {
+                1.0
+            };
+            *sample =
+                (*sample + track.samples[self.position] * self.volume * fade).clamp(-1.0, 1.0);
+            self.position += 1;
+        }
+    }
+}
@@ -1,8 +1,5 @@
-#[cfg(not(feature = "fonts"))]
-use crate::convert_rgb_to_rgba;
-#[cfg(feature = "fonts")]
-use crate::convert_rgb_to_rgba_with_text;
-use image::{ImageBuffer, Rgb};
+use crate::liveness::{mark_active, Subsystem};
+use crate::output::{AudioFrame, OutputMetadata, OutputSink, VideoFrame};
 #[cfg(feature = "ndi")]
 use ndi_sdk_rsllm::send::{SendColorFormat, SendInstance};
 #[cfg(feature = "ndi")]
@@ -27,72 +24,37 @@ static NDI_SENDER: Lazy<Mutex<SendInstance>> = Lazy::new(|| {
     Mutex::new(sender)
 });
 
+// Whether the NDI runtime library loads, for the startup checks
 #[cfg(feature = "ndi")]
-pub fn send_images_over_ndi(
-    images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
-    subtitle: &str,
-    font_size: f32,
-    subtitle_position: &str,
-) -> Result<()> {
-    let mut sender = NDI_SENDER.lock().unwrap();
-
-    for image_buffer in images {
-        let width = image_buffer.width();
-        let height = image_buffer.height();
-
-        // adjust height depending on subtitle_postion as top, center, bottom with respect to the image height
-        #[cfg(feature = "fonts")]
-        let mut subtitle_height = height as i32 - (height as i32 / 3);
-        #[cfg(feature = "fonts")]
-        if subtitle_position == "top" {
-            subtitle_height = 10;
-        } else if subtitle_position == "mid-top" {
-            subtitle_height = height as i32 - (height as i32 / 2) / 2;
-        } else if subtitle_position == "center" || subtitle_position == "middle" {
-            subtitle_height = height as i32 - (height as i32 / 2);
-        } else if subtitle_position == "low-center" {
-            subtitle_height = height as i32 - (height as i32 / 3);
-        } else if subtitle_position == "mid-bottom" {
-            subtitle_height = height as i32 - (height as i32 / 4);
-        } else if subtitle_position == "bottom" {
-            subtitle_height = height as i32 - (height as i32 / 5);
-        } else {
-            log::error!(
-                "Invalid subtitle position '{}', using default position bottom as value {} instead.",
-                subtitle_position,
-                subtitle_height
-            );
-        }
-
-        #[cfg(feature = "fonts")]
-        let start_pos = ((font_size as i32 * 1) as i32, subtitle_height); // Text start position (x, y)
-
-        #[cfg(feature = "fonts")]
-        let rgba_buffer =
-            convert_rgb_to_rgba_with_text(&image_buffer, subtitle, font_size, start_pos);
-        #[cfg(not(feature = "fonts"))]
-        let rgba_buffer = convert_rgb_to_rgba(&image_buffer);
-
-        let frame = ndi_sdk_rsllm::send::create_ndi_send_video_frame(
-            width as i32,
-            height as i32,
-            ndi_sdk_rsllm::send::FrameFormatType::Progressive,
-        )
-        .with_data(rgba_buffer, width as i32 * 4, SendColorFormat::Rgba)
-        .build()
-        .expect("Expected frame to be created");
-
-        log::debug!("Video sending over NDI: frame size {}x{}", width, height);
-
-        sender.send_video(frame);
-
-        // sleep for amount of a 60 fps frame
-        std::thread::sleep(std::time::Duration::from_millis(16));
-    }
+pub fn ndi_available() -> std::result::Result<(), String> {
+    ndi_sdk_rsllm::load()
+        .map(|_| ())
+        .map_err(|_| "the NDI runtime library did not load".to_string())
+}
 
+#[cfg(feature = "ndi")]
+pub fn send_video_frame_over_ndi(rgba: Vec<u8>, width: u32, height: u32) -> Result<()> {
+    let mut sender = NDI_SENDER.lock().unwrap();
+    send_rgba(&mut sender, rgba, width, height);
     Ok(())
 }
 
+#[cfg(feature = "ndi")]
+fn send_rgba(sender: &mut SendInstance, rgba: Vec<u8>, width: u32, height: u32) {
+    let frame = ndi_sdk_rsllm::send::create_ndi_send_video_frame(
+        width as i32,
+        height as i32,
+        ndi_sdk_rsllm::send::FrameFormatType::Progressive,
+    )
+    .with_data(rgba, width as i32 * 4, SendColorFormat::Rgba)
+    .build()
+    .expect("Expected frame to be created");
+
+    log::debug!("Video sending over NDI: frame size {}x{}", width, height);
+
+    sender.send_video(frame);
+}
+
 #[cfg(feature = "ndi")]
 pub fn send_audio_samples_over_ndi(
     samples: Vec<f32>,
@@ -127,3 +89,66 @@ pub fn send_audio_samples_over_ndi(
 
     Ok(())
 }
+
+// NDI output, video and audio are sent when enabled with --ndi-images and --ndi-audio. With
+// a key sender the overlay of the frames goes out as its own source.
+pub struct NdiSink {
+    images: bool,
+    audio: bool,
+    key_sender: Option<SendInstance>,
+}
+
+impl NdiSink {
+    pub fn new(images: bool, audio: bool) -> Self {
+        NdiSink {
+            images,
+            audio,
+            key_sender: None,
+        }
+    }
+
+    // Send the key of the frames as the NDI source of this name
+    pub fn with_key(mut self, name: &str) -> Self {
+        let instance = NDI_INSTANCE.lock().unwrap();
+        match instance.create_send_instance(name.to_string(), false, false) {
+            Ok(sender) => self.key_sender = Some(sender),
+            Err(e) => log::error!("Failed to create the NDI key sender {}: {:?}", name, e),
+        }
+        self
+    }
+}
+
+impl OutputSink for NdiSink {
+    fn name(&self) -> &str {
+        "ndi"
+    }
+
+    fn send_video(&mut self, frame: &VideoFrame) -> anyhow::Result<()> {
+        if self.images {
+            send_video_frame_over_ndi(frame.rgba.clone(), frame.width, frame.height)?;
+            if let (Some(sender), Some(key)) = (self.key_sender.as_mut(), frame.key.as_ref()) {
+                send_rgba(sender, key.clone(), frame.width, frame.height);
+            }
+            mark_active(Subsystem::Ndi);
+        }
+        Ok(())
+    }
+
+    fn send_audio(&mut self, audio: &AudioFrame) -> anyhow::Result<()> {
+        if self.audio {
+            send_audio_samples_over_ndi(
+                audio.samples.clone(),
+                audio.sample_rate as i32,
+                audio.channels as i32,
+            )?;
+            mark_active(Subsystem::Ndi);
+        }
+        Ok(())
+    }
+
+    // ndi-sdk-rsllm only sends video and audio frames, there is no metadata frame call to
+    // carry the chapter markers on the NDI stream
+    fn send_metadata(&mut self, _metadata: &OutputMetadata) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -6,6 +6,14 @@
  * This file contains the network capture module for RsLLM.
 */
 
+use crate::capture_clock::{clock_synchronized, TimestampSource};
+use crate::current_unix_timestamp_ns;
+use crate::event_log::log_event;
+use crate::liveness::{mark_active, Subsystem};
+use crate::multicast::MulticastMembership;
+use crate::packet_headers::LinkType;
+use crate::pcap_stats::PcapStatsMonitor;
+use crate::pcap_writer::PcapWriter;
 #[cfg(feature = "dpdk_enabled")]
 use capsule::config::{load_config, DPDKConfig};
 #[cfg(feature = "dpdk_enabled")]
@@ -13,25 +21,65 @@ use capsule::dpdk;
 #[cfg(all(feature = "dpdk_enabled", target_os = "linux"))]
 use capsule::prelude::*;
 use futures::stream::StreamExt;
-use log::{debug, error, info};
-use pcap::{Active, Capture, Device, PacketCodec};
+use log::{debug, error, info, warn};
+use pcap::{Active, Capture, ConnectionStatus, Device, PacketCodec};
+use serde_json::json;
 use std::error::Error as StdError;
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::{self};
 use tokio::task::JoinHandle;
-use tokio::time::Instant;
+use tokio::time::{timeout, Instant};
+
+// Time without packets before the link of the device is checked
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+// Time between tries to open a device again
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+// A captured frame with its arrival time in ns since the epoch
+#[derive(Clone)]
+pub struct CapturedPacket {
+    pub data: Arc<Vec<u8>>,
+    pub timestamp_ns: u64,
+    pub path: usize, // index of the capture device it arrived on
+    pub link_type: LinkType,
+}
+
+impl CapturedPacket {
+    // Packet arriving now on the system clock
+    pub fn new(data: Vec<u8>) -> Self {
+        CapturedPacket {
+            data: Arc::new(data),
+            timestamp_ns: current_unix_timestamp_ns().unwrap_or(0),
+            path: 0,
+            link_type: LinkType::Ethernet,
+        }
+    }
+}
 
 // Define your custom PacketCodec
-pub struct BoxCodec;
+pub struct TimestampCodec {
+    timestamp_source: TimestampSource,
+    path: usize,
+    link_type: LinkType,
+}
 
-impl PacketCodec for BoxCodec {
-    type Item = Box<[u8]>;
+impl PacketCodec for TimestampCodec {
+    type Item = CapturedPacket;
 
     fn decode(&mut self, packet: pcap::Packet) -> Self::Item {
-        packet.data.into()
+        CapturedPacket {
+            data: Arc::new(packet.data.to_vec()),
+            timestamp_ns: self.timestamp_source.arrival_time_ns(
+                packet.header.ts.tv_sec as u64,
+                packet.header.ts.tv_usec as u64,
+            ),
+            path: self.path,
+            link_type: self.link_type,
+        }
     }
 }
 
@@ -158,6 +206,101 @@ fn init_dpdk(
     Ok(Box::new(DummyDpdkPort))
 }
 
+// Source addresses of --source-ssm, separated by commas
+pub fn parse_ssm_sources(sources: &str) -> Vec<IpAddr> {
+    sources
+        .split(',')
+        .map(|source| source.trim())
+        .filter(|source| !source.is_empty())
+        .filter_map(|source| match source.parse::<IpAddr>() {
+            Ok(address) => Some(address),
+            Err(e) => {
+                error!("Invalid SSM source address {}: {}", source, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Devices to capture on, the --source-devices list or the single --source-device
+pub fn capture_devices(source_device: &str, source_devices: &str) -> Vec<String> {
+    let devices: Vec<String> = source_devices
+        .split(',')
+        .map(|device| device.trim())
+        .filter(|device| !device.is_empty())
+        .map(|device| device.to_string())
+        .collect();
+    if devices.is_empty() {
+        vec![source_device.to_string()]
+    } else {
+        devices
+    }
+}
+
+// BPF filter for the group and port, limited to the sources for source-specific multicast
+pub fn capture_filter(
+    protocol: &str,
+    port: i32,
+    fec: bool,
+    group: &IpAddr,
+    sources: &[IpAddr],
+) -> String {
+    let ip = if group.is_ipv6() { "ip6" } else { "ip" };
+    // the 2022-1/5 column and row FEC follow the media port
+    let ports = if fec {
+        format!("({} or {} or {})", port, port + 2, port + 4)
+    } else {
+        port.to_string()
+    };
+    let mut filter = format!(
+        "{} dst port {} and {} dst host {}",
+        protocol, ports, ip, group
+    );
+    let sources: Vec<String> = sources
+        .iter()
+        .filter(|source| source.is_ipv6() == group.is_ipv6())
+        .map(|source| format!("{} src host {}", ip, source))
+        .collect();
+    if !sources.is_empty() {
+        filter.push_str(&format!(" and ({})", sources.join(" or ")));
+    }
+    filter
+}
+
+// How --pcap-filter goes with the generated group and port filter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcapFilterMode {
+    Or,      // captures more, like other ports or VLAN tagged frames
+    And,     // narrows the capture
+    Replace, // only the expression
+}
+
+impl std::str::FromStr for PcapFilterMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode.trim().to_lowercase().as_str() {
+            "or" => Ok(PcapFilterMode::Or),
+            "and" => Ok(PcapFilterMode::And),
+            "replace" => Ok(PcapFilterMode::Replace),
+            _ => Err(format!("Invalid pcap filter mode {}", mode)),
+        }
+    }
+}
+
+// The generated filter with the user's expression
+pub fn combine_filters(generated: &str, expression: &str, mode: PcapFilterMode) -> String {
+    let expression = expression.trim();
+    if expression.is_empty() {
+        return generated.to_string();
+    }
+    match mode {
+        PcapFilterMode::Or => format!("({}) or ({})", generated, expression),
+        PcapFilterMode::And => format!("({}) and ({})", generated, expression),
+        PcapFilterMode::Replace => expression.to_string(),
+    }
+}
+
 fn init_pcap(
     source_device: &str,
     #[cfg(target_os = "linux")] _use_wireless: bool,
@@ -169,8 +312,13 @@ fn init_pcap(
     buffer_size: i64,
     source_protocol: &str,
     source_port: i32,
+    source_fec: bool,
     source_ip: &str,
-) -> Result<(Capture<Active>, UdpSocket), Box<dyn StdError>> {
+    source_ssm: &str,
+    pcap_filter: &str,
+    pcap_filter_mode: PcapFilterMode,
+    timestamp_source: TimestampSource,
+) -> Result<(Capture<Active>, MulticastMembership, LinkType), Box<dyn StdError>> {
     let devices = Device::list().map_err(|e| Box::new(e) as Box<dyn StdError>)?;
     debug!("init_pcap: devices: {:?}", devices);
     info!("init_pcap: specified source_device: {}", source_device);
@@ -188,50 +336,61 @@ fn init_pcap(
         .find(|d| {
             (d.name == source_device || source_device.is_empty())
                 && d.flags.is_up()
-                && !d.flags.is_loopback()
+                // a loopback device only when it is named, its NULL link type is understood
+                && (!d.flags.is_loopback() || d.name == source_device)
                 && d.flags.is_running()
                 && (!d.flags.is_wireless() || use_wireless)
         })
         .ok_or_else(|| Box::new(DeviceNotFoundError) as Box<dyn StdError>)?;
 
-    // Get the IP address of the target device
-    let interface_addr = target_device
-        .addresses
-        .iter()
-        .find_map(|addr| match addr.addr {
-            IpAddr::V4(ipv4_addr) => Some(ipv4_addr),
-            _ => None,
-        })
-        .ok_or_else(|| "No valid IPv4 address found for target device")?;
-
-    let multicast_addr = source_ip
-        .parse::<Ipv4Addr>()
+    let group = source_ip
+        .parse::<IpAddr>()
         .expect("Invalid IP address format for source_ip");
+    let sources = parse_ssm_sources(source_ssm);
 
-    info!(
-        "init_pcap: UDP Socket Binding to interface {} with Join IGMP Multicast for address:port udp://{}:{}.",
-        interface_addr, multicast_addr, source_port
-    );
-
-    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| Box::new(e) as Box<dyn StdError>)?;
-    socket
-        .join_multicast_v4(&multicast_addr, &interface_addr)
-        .map_err(|e| Box::new(e) as Box<dyn StdError>)?;
+    // join the group so the switches forward it
+    let membership = MulticastMembership::join(&group, &sources, &target_device)?;
 
-    let source_host_and_port = format!(
-        "{} dst port {} and ip dst host {}",
-        source_protocol, source_port, source_ip
+    let source_host_and_port = combine_filters(
+        &capture_filter(source_protocol, source_port, source_fec, &group, &sources),
+        pcap_filter,
+        pcap_filter_mode,
     );
 
-    let cap = Capture::from_device(target_device.clone())
+    let mut cap = Capture::from_device(target_device.clone())
         .map_err(|e| Box::new(e) as Box<dyn StdError>)?
         .promisc(promiscuous)
         .timeout(read_time_out)
         .snaplen(read_size)
         .immediate_mode(immediate_mode)
         .buffer_size(buffer_size as i32)
-        .open()
-        .map_err(|e| Box::new(e) as Box<dyn StdError>)?;
+        .precision(timestamp_source.precision());
+    if let Some(tstamp_type) = timestamp_source.tstamp_type() {
+        info!(
+            "init_pcap: {} timestamps, {:?} on capture device {}",
+            timestamp_source, tstamp_type, target_device.name
+        );
+        cap = cap.tstamp_type(tstamp_type);
+    }
+    if timestamp_source == TimestampSource::Ntp && clock_synchronized() == Some(false) {
+        warn!("init_pcap: the system clock is not NTP synchronized, packet times may drift");
+    }
+    let cap = cap.open().map_err(|e| Box::new(e) as Box<dyn StdError>)?;
+
+    // the UDP payload is found behind the link header of the device
+    let datalink = cap.get_datalink();
+    let link_type = LinkType::from_datalink(datalink.0).map_err(|e| {
+        format!(
+            "{} ({}) on capture device {}",
+            e,
+            datalink.get_name().unwrap_or_default(),
+            target_device.name
+        )
+    })?;
+    info!(
+        "init_pcap: link type {:?} on capture device {}",
+        link_type, target_device.name
+    );
 
     info!(
         "init_pcap: set non-blocking mode on capture device {}",
@@ -255,15 +414,19 @@ fn init_pcap(
         target_device.name
     );
 
-    Ok((cap, socket))
+    Ok((cap, membership, link_type))
 }
 
 pub struct NetworkCapture {
     pub running: Arc<AtomicBool>,
     pub source_ip: Arc<String>,
+    pub source_ssm: Arc<String>,
+    pub pcap_filter: Arc<String>,
+    pub pcap_filter_mode: PcapFilterMode,
     pub source_protocol: Arc<String>,
-    pub source_device: Arc<String>,
+    pub source_devices: Vec<String>, // a path each
     pub source_port: i32,
+    pub source_fec: bool,
     pub use_wireless: bool,
     pub promiscuous: bool,
     pub read_time_out: i32,
@@ -272,14 +435,71 @@ pub struct NetworkCapture {
     pub buffer_size: i64,
     pub dpdk: bool,
     pub pcap_stats: bool,
+    pub pcap_drop_alarm_percent: f64,
     pub debug_on: bool,
     pub capture_task: Option<JoinHandle<()>>,
+    pub pcap_writer: Option<PcapWriter>,
+    pub timestamp_source: TimestampSource,
 }
 
-pub fn network_capture(network_capture: &mut NetworkCapture, ptx: mpsc::Sender<Arc<Vec<u8>>>) {
-    let running = Arc::new(AtomicBool::new(true));
-    let running_capture = running.clone();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkState {
+    Up,
+    Down,
+    Gone,
+}
+
+// Link of the capture device, up when the devices can't be listed
+fn link_state(device: &str) -> LinkState {
+    let Ok(devices) = Device::list() else {
+        return LinkState::Up;
+    };
+    match devices.into_iter().find(|d| d.name == device) {
+        None => LinkState::Gone,
+        Some(d)
+            if !d.flags.is_up()
+                || matches!(d.flags.connection_status, ConnectionStatus::Disconnected) =>
+        {
+            LinkState::Down
+        }
+        Some(_) => LinkState::Up,
+    }
+}
+
+// Report the capture of a device paused, once until it resumes
+fn pause_capture(paused: &mut Option<String>, path: usize, device: &str, reason: &str) {
+    if paused.is_none() {
+        warn!("Capture on {} paused: {}", device, reason);
+        log_event(
+            "analyzer",
+            "capture_paused",
+            json!({ "path": path, "device": device, "reason": reason }),
+        );
+    }
+    *paused = Some(reason.to_string());
+}
+
+fn resume_capture(paused: &mut Option<String>, path: usize, device: &str) {
+    if let Some(reason) = paused.take() {
+        info!("Capture on {} resumed after {}", device, reason);
+        log_event(
+            "analyzer",
+            "capture_resumed",
+            json!({ "path": path, "device": device, "paused_for": reason }),
+        );
+    }
+}
 
+// Capture on one device, its packets tagged with the path. A device that goes away or loses
+// its link pauses the capture, it is opened again when the device is back
+fn spawn_pcap_capture(
+    network_capture: &NetworkCapture,
+    path: usize,
+    source_device: String,
+    mut pcap_writer: Option<PcapWriter>,
+    ptx: mpsc::Sender<CapturedPacket>,
+    running_capture: Arc<AtomicBool>,
+) -> JoinHandle<()> {
     let use_wireless = network_capture.use_wireless;
     let promiscuous = network_capture.promiscuous;
     let read_time_out = network_capture.read_time_out;
@@ -287,12 +507,169 @@ pub fn network_capture(network_capture: &mut NetworkCapture, ptx: mpsc::Sender<A
     let immediate_mode = network_capture.immediate_mode;
     let buffer_size = network_capture.buffer_size;
     let source_port = network_capture.source_port;
+    let source_fec = network_capture.source_fec;
     let source_protocol = Arc::clone(&network_capture.source_protocol);
     let source_ip = Arc::clone(&network_capture.source_ip);
-    let source_device = Arc::clone(&network_capture.source_device);
-    let dpdk = network_capture.dpdk;
+    let source_ssm = Arc::clone(&network_capture.source_ssm);
+    let pcap_filter = Arc::clone(&network_capture.pcap_filter);
+    let pcap_filter_mode = network_capture.pcap_filter_mode;
     let pcap_stats = network_capture.pcap_stats;
+    let mut stats_monitor = PcapStatsMonitor::new(
+        path,
+        &source_device,
+        network_capture.pcap_drop_alarm_percent,
+    );
     let debug_on = network_capture.debug_on;
+    let timestamp_source = network_capture.timestamp_source;
+
+    tokio::spawn(async move {
+        // why the capture is paused, it resumes once packets arrive again
+        let mut paused: Option<String> = None;
+        let mut count = 0;
+        let mut stats_last_sent_ts = Instant::now();
+
+        while running_capture.load(Ordering::SeqCst) {
+            // initialize the pcap
+            let (cap, membership, link_type) = match init_pcap(
+                source_device.as_str(),
+                use_wireless,
+                promiscuous,
+                read_time_out,
+                read_size,
+                immediate_mode,
+                buffer_size as i64,
+                source_protocol.as_str(),
+                source_port,
+                source_fec,
+                source_ip.as_str(),
+                source_ssm.as_str(),
+                pcap_filter.as_str(),
+                pcap_filter_mode,
+                timestamp_source,
+            )
+            .map_err(|e| e.to_string())
+            {
+                Ok(capture) => capture,
+                // a missing device is waited for, it may not be plugged in yet or again
+                Err(e) => {
+                    pause_capture(&mut paused, path, &source_device, &e);
+                    tokio::time::sleep(DEVICE_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+            let device = membership.device().to_string();
+            // the membership is left when this device stops capturing
+            let membership_running = Arc::new(AtomicBool::new(true));
+            let capturing = membership_running.clone();
+            let running_membership = running_capture.clone();
+            tokio::spawn(membership.maintain(move || {
+                running_membership.load(Ordering::SeqCst) && capturing.load(Ordering::SeqCst)
+            }));
+
+            // Create a PacketStream from the Capture
+            let mut stream = cap
+                .stream(TimestampCodec {
+                    timestamp_source,
+                    path,
+                    link_type,
+                })
+                .unwrap();
+            stats_monitor.restart();
+            let mut packets_dropped = 0;
+
+            while running_capture.load(Ordering::SeqCst) {
+                let packet = match timeout(LINK_CHECK_INTERVAL, stream.next()).await {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => break,
+                    Err(_) => {
+                        // no packets for a while, the link may be down or the device gone
+                        match link_state(&device) {
+                            LinkState::Up => {}
+                            LinkState::Down => {
+                                pause_capture(&mut paused, path, &device, "link down")
+                            }
+                            LinkState::Gone => {
+                                pause_capture(&mut paused, path, &device, "device removed");
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                };
+                match packet {
+                    Ok(packet) => {
+                        count += 1;
+                        resume_capture(&mut paused, path, &device);
+                        // keep a copy of the captured packet for Wireshark
+                        if let Some(writer) = pcap_writer.as_mut() {
+                            if let Err(e) = writer.write(&packet.data) {
+                                error!("Failed to write the pcap file: {}", e);
+                                pcap_writer = None;
+                            }
+                        }
+                        let packet_size = packet.data.len();
+                        ptx.send(packet).await.unwrap();
+                        mark_active(Subsystem::Capture);
+                        if !running_capture.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        if stats_monitor.due() {
+                            if let Ok(stats) = stream.capture_mut().stats() {
+                                stats_monitor.update(&stats);
+                            }
+                        }
+                        let current_ts = Instant::now();
+                        if pcap_stats
+                            && ((current_ts.duration_since(stats_last_sent_ts).as_secs() >= 30)
+                                || count == 1)
+                        {
+                            stats_last_sent_ts = current_ts;
+                            let stats = stream.capture_mut().stats().unwrap();
+                            info!(
+                            "#{} {} Current stats: Received: {}, Dropped: {}/{}, Interface Dropped: {} packet_size: {} bytes.",
+                            count, device, stats.received, stats.dropped - packets_dropped, stats.dropped, stats.if_dropped, packet_size,
+                        );
+                            packets_dropped = stats.dropped;
+                        }
+                    }
+                    Err(pcap::Error::TimeoutExpired) => {
+                        // Timeout expired, continue and try again
+                        continue;
+                    }
+                    Err(e) => {
+                        // the device went down or away, opened again after a pause while the
+                        // other devices keep capturing
+                        error!("PCap Capture Error occurred: {}", e);
+                        pause_capture(&mut paused, path, &device, &e.to_string());
+                        break;
+                    }
+                }
+            }
+
+            membership_running.store(false, Ordering::SeqCst);
+            if let Ok(stats) = stream.capture_mut().stats() {
+                stats_monitor.update(&stats);
+                if debug_on || !running_capture.load(Ordering::SeqCst) {
+                    info!("Packet capture statistics of {}:", device);
+                    info!("Received: {}", stats.received);
+                    info!("Dropped: {}", stats.dropped);
+                    info!("Interface Dropped: {}", stats.if_dropped);
+                }
+            }
+            if running_capture.load(Ordering::SeqCst) {
+                tokio::time::sleep(DEVICE_RETRY_INTERVAL).await;
+            }
+        }
+    })
+}
+
+pub fn network_capture(network_capture: &mut NetworkCapture, ptx: mpsc::Sender<CapturedPacket>) {
+    let running = Arc::new(AtomicBool::new(true));
+    let running_capture = running.clone();
+
+    let promiscuous = network_capture.promiscuous;
+    let dpdk = network_capture.dpdk;
+    let mut pcap_writer = network_capture.pcap_writer.take();
 
     // Spawn a new thread for packet capture
     let capture_task = if cfg!(feature = "dpdk_enabled") && dpdk {
@@ -321,11 +698,19 @@ pub fn network_capture(network_capture: &mut NetworkCapture, ptx: mpsc::Sender<A
                             // Extract data from the packet
                             let data = packet.data();
 
-                            // Convert to Arc<Vec<u8>> to maintain consistency with pcap logic
-                            let packet_data = Arc::new(data.to_vec());
+                            // No capture timestamps from DPDK, stamp it on the system clock
+                            let packet_data = CapturedPacket::new(data.to_vec());
+
+                            if let Some(writer) = pcap_writer.as_mut() {
+                                if let Err(e) = writer.write(data) {
+                                    error!("Failed to write the pcap file: {}", e);
+                                    pcap_writer = None;
+                                }
+                            }
 
                             // Send packet data to processing channel
                             ptx.send(packet_data).await.unwrap();
+                            mark_active(Subsystem::Capture);
 
                             // Here you can implement additional processing such as parsing the packet,
                             // updating statistics, handling specific packet types, etc.
@@ -345,91 +730,30 @@ pub fn network_capture(network_capture: &mut NetworkCapture, ptx: mpsc::Sender<A
             }
         })
     } else {
+        // one capture per device, the pcap file keeps the first path
+        let device_tasks: Vec<JoinHandle<()>> = network_capture
+            .source_devices
+            .clone()
+            .into_iter()
+            .enumerate()
+            .map(|(path, device)| {
+                let writer = if path == 0 { pcap_writer.take() } else { None };
+                spawn_pcap_capture(
+                    network_capture,
+                    path,
+                    device,
+                    writer,
+                    ptx.clone(),
+                    running_capture.clone(),
+                )
+            })
+            .collect();
         tokio::spawn(async move {
-            // initialize the pcap
-            let (cap, _socket) = init_pcap(
-                source_device.as_str(),
-                use_wireless,
-                promiscuous,
-                read_time_out,
-                read_size,
-                immediate_mode,
-                buffer_size as i64,
-                source_protocol.as_str(),
-                source_port,
-                source_ip.as_str(),
-            )
-            .expect("Failed to initialize pcap");
-
-            // Create a PacketStream from the Capture
-            let mut stream = cap.stream(BoxCodec).unwrap();
-            let mut count = 0;
-
-            let mut stats_last_sent_ts = Instant::now();
-            let mut packets_dropped = 0;
-
-            while running_capture.load(Ordering::SeqCst) {
-                while let Some(packet) = stream.next().await {
-                    if !running_capture.load(Ordering::SeqCst) {
-                        break;
-                    }
-                    match packet {
-                        Ok(data) => {
-                            count += 1;
-                            let packet_data = Arc::new(data.to_vec());
-                            ptx.send(packet_data).await.unwrap();
-                            if !running_capture.load(Ordering::SeqCst) {
-                                break;
-                            }
-                            let current_ts = Instant::now();
-                            if pcap_stats
-                                && ((current_ts.duration_since(stats_last_sent_ts).as_secs() >= 30)
-                                    || count == 1)
-                            {
-                                stats_last_sent_ts = current_ts;
-                                let stats = stream.capture_mut().stats().unwrap();
-                                info!(
-                                "#{} Current stats: Received: {}, Dropped: {}/{}, Interface Dropped: {} packet_size: {} bytes.",
-                                count, stats.received, stats.dropped - packets_dropped, stats.dropped, stats.if_dropped, data.len(),
-                            );
-                                packets_dropped = stats.dropped;
-                            }
-                        }
-                        Err(e) => {
-                            // Print error and information about it
-                            error!("PCap Capture Error occurred: {}", e);
-                            if e == pcap::Error::TimeoutExpired {
-                                // If timeout expired, check for running_capture
-                                if !running_capture.load(Ordering::SeqCst) {
-                                    break;
-                                }
-                                // Timeout expired, continue and try again
-                                continue;
-                            } else {
-                                // Exit the loop if an error occurs
-                                running_capture.store(false, Ordering::SeqCst);
-                                break;
-                            }
-                        }
-                    }
-                }
-                if debug_on {
-                    let stats = stream.capture_mut().stats().unwrap();
-                    info!(
-                        "Current stats: Received: {}, Dropped: {}, Interface Dropped: {}",
-                        stats.received, stats.dropped, stats.if_dropped
-                    );
-                }
-                if !running_capture.load(Ordering::SeqCst) {
-                    break;
+            for task in device_tasks {
+                if let Err(e) = task.await {
+                    error!("Capture on a device failed: {}", e);
                 }
             }
-
-            let stats = stream.capture_mut().stats().unwrap();
-            info!("Packet capture statistics:");
-            info!("Received: {}", stats.received);
-            info!("Dropped: {}", stats.dropped);
-            info!("Interface Dropped: {}", stats.if_dropped);
         })
     };
 
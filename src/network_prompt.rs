@@ -0,0 +1,278 @@
+/*
+ * network_prompt.rs
+ * -----------------
+ * Network mode stats from the analyzed packets. The programs and the most anomalous PIDs,
+ * scored against the previous stats so new errors and bitrate swings rank first, and the
+ * packet samples of those PIDs fill what is left of the token budget. The payload of the
+ * sampled packets can be redacted or hashed so only headers reach the LLM.
+*/
+
+use crate::args::Args;
+use crate::audio_class::program_audio_class;
+use crate::loudness::program_loudness;
+use crate::stats_schema::{PidStats, StreamStats};
+use crate::stream_data::{
+    payload_offset, pid_map_streams, program_summaries, stream_category, StreamData,
+};
+use crate::token_budget::{main_model, BudgetPart, TokenBudget, MAIN_PARTS};
+use crate::transcript::{recent_transcript, transcript_turns};
+use crate::{count_tokens, hexdump_ascii};
+use log::error;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// Captioned lines of the program audio in the prompt
+const PROMPT_TRANSCRIPT_LINES: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadMode {
+    Raw,
+    Redact,
+    Hash,
+}
+
+impl std::str::FromStr for PayloadMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode.trim().to_lowercase().as_str() {
+            "raw" => Ok(PayloadMode::Raw),
+            "redact" => Ok(PayloadMode::Redact),
+            "hash" => Ok(PayloadMode::Hash),
+            _ => Err(format!("Invalid network payload mode {}", mode)),
+        }
+    }
+}
+
+pub struct NetworkPromptBuilder {
+    pub top_pids: usize,
+    pub token_budget: usize, // 0 is unlimited
+    pub packets: bool,
+    pub hexdump: bool,
+    pub payload_mode: PayloadMode,
+    // random per run so hashes of short or well known payloads can't be looked up
+    hash_salt: [u8; 16],
+    last_errors: HashMap<u16, u32>,
+    last_bitrates: HashMap<u16, u32>,
+}
+
+impl NetworkPromptBuilder {
+    pub fn new(
+        top_pids: usize,
+        token_budget: usize,
+        packets: bool,
+        hexdump: bool,
+        payload_mode: PayloadMode,
+    ) -> Self {
+        NetworkPromptBuilder {
+            top_pids,
+            token_budget,
+            packets,
+            hexdump,
+            payload_mode,
+            hash_salt: rand::random(),
+            last_errors: HashMap::new(),
+            last_bitrates: HashMap::new(),
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        // the stats share of the main model context unless set
+        let token_budget = if args.ai_network_token_budget > 0 {
+            args.ai_network_token_budget
+        } else {
+            TokenBudget::for_model(args, main_model(args), args.max_tokens.max(0) as usize)
+                .reserve(&args.system_prompt)
+                .allocation(BudgetPart::Stats, &MAIN_PARTS)
+        };
+        NetworkPromptBuilder::new(
+            args.ai_network_top_pids,
+            token_budget,
+            args.ai_network_packets,
+            args.ai_network_hexdump,
+            args.ai_network_payload
+                .parse::<PayloadMode>()
+                .unwrap_or_else(|e| {
+                    error!("{}, redacting the payload", e);
+                    PayloadMode::Redact
+                }),
+        )
+    }
+
+    // Hexdump of the packet with the payload after the TS header and adaptation field kept,
+    // left out or replaced by its salted hash. Chunks that are not MPEG-TS are all payload.
+    fn packet_dump(&self, stream_data: &StreamData) -> String {
+        let packet = &stream_data.packet
+            [stream_data.packet_start..stream_data.packet_start + stream_data.packet_len];
+        if self.payload_mode == PayloadMode::Raw {
+            return hexdump_ascii(packet, 0, packet.len());
+        }
+        let header_len = if packet.first() == Some(&0x47) {
+            payload_offset(packet).unwrap_or(packet.len())
+        } else {
+            0
+        };
+        let (header, payload) = packet.split_at(header_len);
+        let mut dump = hexdump_ascii(header, 0, header.len());
+        if !dump.ends_with('\n') {
+            dump.push('\n');
+        }
+        match self.payload_mode {
+            PayloadMode::Hash => {
+                let digest = Sha256::new()
+                    .chain_update(self.hash_salt)
+                    .chain_update(payload)
+                    .finalize();
+                let hash: String = digest[..8]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                dump.push_str(&format!(
+                    "payload: {} bytes sha256 {}\n",
+                    payload.len(),
+                    hash
+                ));
+            }
+            _ => dump.push_str(&format!("payload: {} bytes redacted\n", payload.len())),
+        }
+        dump
+    }
+
+    // Higher is more anomalous: errors since the last prompt, IAT spikes over the average and
+    // bitrate changes since the last prompt
+    fn score(&self, stream: &StreamData) -> f64 {
+        let last_errors = self.last_errors.get(&stream.pid).copied().unwrap_or(0);
+        let new_errors = stream.error_count.saturating_sub(last_errors) as f64;
+        let iat_spike = if stream.iat_avg_ns > 0 {
+            (stream.iat_max_ns as f64 / stream.iat_avg_ns as f64 - 1.0).clamp(0.0, 10.0)
+        } else {
+            0.0
+        };
+        let bitrate_change = match self.last_bitrates.get(&stream.pid) {
+            Some(&last) if last > 0 => {
+                ((stream.bitrate as f64 - last as f64).abs() / last as f64).min(10.0)
+            }
+            _ => 0.0,
+        };
+        new_errors * 10.0 + stream.error_count as f64 * 0.1 + iat_spike + bitrate_change * 5.0
+    }
+
+    // Stats of the packets of the batch and the PID map since the last stats message
+    pub fn build(&mut self, packets: u64, decode_batch: &[StreamData]) -> StreamStats {
+        let mut budget = StatsBudget::new(self.token_budget);
+        let mut stats = StreamStats::new(packets);
+
+        for program in program_summaries() {
+            if budget.admit(&program) {
+                stats.programs.push(program);
+            }
+        }
+        stats.loudness = program_loudness().filter(|loudness| budget.admit(loudness));
+        stats.audio_class = program_audio_class().filter(|audio_class| budget.admit(audio_class));
+
+        let transcript = recent_transcript(PROMPT_TRANSCRIPT_LINES);
+        if !transcript.is_empty() {
+            for turn in transcript_turns(&transcript).lines() {
+                if budget.admit(&turn) {
+                    stats.transcript.push(turn.to_string());
+                }
+            }
+        }
+
+        let streams = pid_map_streams();
+        let mut scored: Vec<(f64, &StreamData)> = streams
+            .iter()
+            .map(|stream| (self.score(stream), stream))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(self.top_pids);
+
+        stats.pid_count = streams.len();
+        for (score, stream) in &scored {
+            let last_errors = self.last_errors.get(&stream.pid).copied().unwrap_or(0);
+            let pid = PidStats {
+                pid: stream.pid,
+                program_number: stream.program_number,
+                category: stream_category(&stream.stream_type).to_string(),
+                bitrate: stream.bitrate,
+                bitrate_avg: stream.bitrate_avg,
+                iat_avg_us: stream.iat_avg_ns / 1000,
+                iat_max_us: stream.iat_max_ns / 1000,
+                errors: stream.error_count,
+                new_errors: stream.error_count.saturating_sub(last_errors),
+                score: (score * 10.0).round() / 10.0,
+            };
+            if budget.admit(&pid) {
+                stats.pids.push(pid);
+            }
+        }
+
+        // samples of the selected PIDs with what is left of the budget
+        if self.packets || self.hexdump {
+            let selected: Vec<u16> = scored.iter().map(|(_, stream)| stream.pid).collect();
+            for stream_data in decode_batch
+                .iter()
+                .filter(|stream_data| selected.contains(&stream_data.pid))
+            {
+                if self.packets {
+                    let sample = serde_json::to_string(stream_data).unwrap_or_default();
+                    if budget.admit(&sample) {
+                        stats.samples.push(sample);
+                    }
+                }
+                if self.hexdump {
+                    let sample = self.packet_dump(stream_data);
+                    if budget.admit(&sample) {
+                        stats.samples.push(sample);
+                    }
+                }
+            }
+        }
+
+        self.last_errors = streams
+            .iter()
+            .map(|stream| (stream.pid, stream.error_count))
+            .collect();
+        self.last_bitrates = streams
+            .iter()
+            .map(|stream| (stream.pid, stream.bitrate))
+            .collect();
+        stats.omitted = budget.omitted;
+        stats
+    }
+}
+
+impl Default for NetworkPromptBuilder {
+    fn default() -> Self {
+        NetworkPromptBuilder::new(10, 2000, false, false, PayloadMode::Raw)
+    }
+}
+
+// Entries of the stats until the token budget is used up, the entries after the first one
+// that does not fit are left out too so the most important ones stay in order
+struct StatsBudget {
+    budget: usize,
+    tokens: usize,
+    omitted: usize,
+}
+
+impl StatsBudget {
+    fn new(budget: usize) -> Self {
+        StatsBudget {
+            budget,
+            tokens: 0,
+            omitted: 0,
+        }
+    }
+
+    fn admit(&mut self, entry: &impl Serialize) -> bool {
+        let tokens = count_tokens(&serde_json::to_string(entry).unwrap_or_default());
+        if self.omitted > 0 || (self.budget > 0 && self.tokens + tokens > self.budget) {
+            self.omitted += 1;
+            return false;
+        }
+        self.tokens += tokens;
+        true
+    }
+}
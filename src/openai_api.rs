@@ -3,12 +3,17 @@ Implement the OpenAI API generically for any LLM following it
 Chris Kennedy @2024 MIT license
 */
 
+use crate::args::Args;
+use crate::external_apis::check_url;
+use crate::secrets::secret;
+use crate::ApiError;
 use bytes::Bytes;
 use chrono::{TimeZone, Utc};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use serde_json::json;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{self};
 
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
@@ -225,8 +230,9 @@ pub async fn stream_completion(
     llm_path: &str,
     debug_inline: bool,
     show_output_errors: bool,
+    retries: usize,
     external_sender: tokio::sync::mpsc::Sender<String>,
-) {
+) -> Result<(), ApiError> {
     let client = Client::new();
 
     // measure messages member size of the content member of each pair of the messages array
@@ -235,21 +241,54 @@ pub async fn stream_completion(
         prompt_token_count += message.content.split_whitespace().count();
     }
 
+    let url = format!("{}{}", llm_host, llm_path);
+    check_url(&url).map_err(ApiError::Error)?;
+
     let start_time = Instant::now();
-    let response = client
-        .post(format!("{}{}", llm_host, llm_path))
-        .header("Authorization", format!("Bearer {}", openai_key))
-        .json(&open_ai_request)
-        .send()
-        .await;
-
-    // handle errors
-    let mut response = match response {
-        Ok(resp) => resp,
-        Err(e) => {
-            println!("Error: {}", e);
-            return;
+    // an endpoint that is restarting refuses the connection or answers with a server error
+    // until it is back, the request is sent again with a growing backoff
+    let mut attempt = 0;
+    let mut response = loop {
+        let error = match client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", openai_key))
+            .json(&open_ai_request)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => break response,
+            Ok(response) => {
+                let status = response.status();
+                let message = format!(
+                    "{} answered {}: {}",
+                    url,
+                    status,
+                    response.text().await.unwrap_or_default()
+                );
+                if status.as_u16() != 429 && !status.is_server_error() {
+                    return Err(ApiError::Error(message));
+                }
+                ApiError::Unavailable(message)
+            }
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                ApiError::Unavailable(format!("{}: {}", url, e))
+            }
+            Err(e) => return Err(ApiError::RequestError(e)),
+        };
+        if attempt >= retries {
+            return Err(error);
         }
+        attempt += 1;
+        let backoff = Duration::from_millis(1000 << attempt.min(5));
+        warn!(
+            "LLM endpoint {} is not answering: {}, retrying {}/{} in {} ms",
+            llm_host,
+            error,
+            attempt,
+            retries,
+            backoff.as_millis()
+        );
+        tokio::time::sleep(backoff).await;
     };
 
     let mut token_count = 0;
@@ -263,14 +302,13 @@ pub async fn stream_completion(
             Ok(text) => text,
             Err(e) => {
                 eprintln!("Failed to get response text: {}", e);
-                return;
+                return Err(ApiError::RequestError(e));
             }
         };
         println!("\nLLM Response:\n  {}\n---\n", text);
         // send back over mpsc channel
         if let Err(e) = external_sender.send(text).await {
             eprintln!("Failed to send text over mpsc channel: {}", e);
-            return;
         }
     } else {
         // Create an mpsc channel
@@ -547,4 +585,66 @@ pub async fn stream_completion(
             }
         }
     }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: Message,
+}
+
+// Non streaming chat completion with the OpenAI compatible API from the args, returns the
+// content of the first choice
+pub async fn chat_completion(
+    messages: Vec<Message>,
+    args: &Args,
+    max_tokens: usize,
+    temperature: f32,
+) -> Result<String, ApiError> {
+    let llm_host = if args.use_openai {
+        "https://api.openai.com".to_string()
+    } else {
+        args.llm_host.clone()
+    };
+    let openai_key = secret("OPENAI_API_KEY")
+        .map(|key| key.expose().to_string())
+        .unwrap_or_default();
+    let url = format!("{}{}", llm_host, args.llm_path);
+    check_url(&url).map_err(ApiError::Error)?;
+
+    let request = json!({
+        "model": args.model,
+        "messages": messages,
+        "max_tokens": max_tokens,
+        "temperature": temperature,
+        "stream": false,
+    });
+
+    let response = Client::new()
+        .post(url)
+        .bearer_auth(openai_key)
+        .json(&request)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(ApiError::Error(format!(
+            "Chat completion request failed {}: {}",
+            response.status(),
+            response.text().await.unwrap_or_default()
+        )));
+    }
+
+    let chat_response: ChatResponse = response.json().await?;
+    chat_response
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.message.content.trim().to_string())
+        .ok_or_else(|| ApiError::Error("Chat completion response has no choices".to_string()))
 }
@@ -27,8 +27,10 @@ use bytes::Bytes;
 use reqwest::Client;
 use serde::Serialize;
 const ENDPOINT: &str = "https://api.openai.com/v1/audio/speech";
+use crate::external_apis::check_url;
 use crate::ApiError;
-use log::debug;
+use log::{debug, warn};
+use std::time::Duration;
 
 impl std::fmt::Display for Voice {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -51,6 +53,8 @@ pub struct Request {
     input: String,
     voice: Voice,
     response_format: ResponseFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<f32>,
 }
 
 #[derive(Serialize)]
@@ -63,6 +67,18 @@ pub enum ResponseFormat {
     Aac,
     #[serde(rename = "flac")]
     Flac,
+    #[serde(rename = "wav")]
+    Wav,
+}
+impl ResponseFormat {
+    // only mp3 and wav can be decoded for the output
+    pub fn from_name(name: &str) -> Option<ResponseFormat> {
+        match name.trim().to_lowercase().as_str() {
+            "mp3" => Some(ResponseFormat::Mp3),
+            "wav" => Some(ResponseFormat::Wav),
+            _ => None,
+        }
+    }
 }
 #[derive(Serialize)]
 pub enum Voice {
@@ -79,6 +95,19 @@ pub enum Voice {
     #[serde(rename = "shimmer")]
     Shimmer,
 }
+impl Voice {
+    pub fn from_name(name: &str) -> Option<Voice> {
+        match name.trim().to_lowercase().as_str() {
+            "alloy" => Some(Voice::Alloy),
+            "echo" => Some(Voice::Echo),
+            "fable" => Some(Voice::Fable),
+            "onyx" => Some(Voice::Onyx),
+            "nova" => Some(Voice::Nova),
+            "shimmer" => Some(Voice::Shimmer),
+            _ => None,
+        }
+    }
+}
 impl Request {
     pub fn new(model: String, input: String, voice: Voice) -> Self {
         Request {
@@ -86,14 +115,21 @@ impl Request {
             input: input,
             voice: voice,
             response_format: ResponseFormat::Mp3,
+            speed: None,
         }
     }
     pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
         self.response_format = response_format;
         self
     }
+    // speed of the generated audio, 0.25 to 4.0 with 1.0 as the default
+    pub fn with_speed(mut self, speed: f32) -> Self {
+        self.speed = Some(speed.clamp(0.25, 4.0));
+        self
+    }
 }
-pub async fn tts(req: Request, api_key: &str) -> Result<Bytes, ApiError> {
+pub async fn tts(req: Request, api_key: &str, retries: usize) -> Result<Bytes, ApiError> {
+    check_url(ENDPOINT).map_err(ApiError::Error)?;
     let client = Client::new();
 
     // remove any special characters from the input for tts request
@@ -112,28 +148,51 @@ pub async fn tts(req: Request, api_key: &str) -> Result<Bytes, ApiError> {
         req.model,
         req.voice.to_string()
     );
-    let response = client
-        .post(ENDPOINT)
-        .bearer_auth(api_key)
-        .json(&req)
-        .send()
-        .await;
-    match response {
-        Ok(response) => {
-            if response.status().is_success() {
-                if let Ok(response) = response.bytes().await {
-                    Ok(response)
-                } else {
-                    Err(ApiError::Error(String::from("Error in posting data")))
+    // retry connection errors, rate limits and server errors with a growing backoff
+    let mut attempt = 0;
+    loop {
+        let result = match client
+            .post(ENDPOINT)
+            .bearer_auth(api_key)
+            .json(&req)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                let status = response.status();
+                if status.is_success() {
+                    return response
+                        .bytes()
+                        .await
+                        .map_err(|_| ApiError::Error(String::from("Error in posting data")));
                 }
-            } else {
-                Err(ApiError::Error(format!(
+                let error = ApiError::Error(format!(
                     "{}: {}",
-                    response.status().to_string(),
+                    status.to_string(),
                     response.text().await.unwrap_or_default()
-                )))
+                ));
+                if status.as_u16() == 429 || status.is_server_error() {
+                    Err(error)
+                } else {
+                    return Err(error);
+                }
+            }
+            Err(e) => Err(ApiError::RequestError(e)),
+        };
+        match result {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                let backoff = Duration::from_millis(500 << attempt.min(6));
+                warn!(
+                    "OpenAI TTS request failed: {}, retrying {}/{} in {} ms",
+                    e,
+                    attempt,
+                    retries,
+                    backoff.as_millis()
+                );
+                tokio::time::sleep(backoff).await;
             }
+            result => return result,
         }
-        Err(e) => Err(ApiError::RequestError(e)),
     }
 }
@@ -0,0 +1,718 @@
+/*
+ * output.rs
+ * ---------
+ * Output sinks for the processed paragraphs. The frames are composed and the audio decoded
+ * and paced once here, then handed to every configured sink so NDI, files and later stream
+ * outputs can run side by side.
+*/
+
+use crate::args::Args;
+use crate::audio::{decode_audio, resample, AudioCadence, FrameRate};
+use crate::audio_meter::AudioMeter;
+use crate::chapters::Chapter;
+use crate::event_log::log_event;
+use crate::image_ops::rgb_to_rgba;
+use crate::music::{MusicBed, MusicIntensity};
+use crate::overlay::{render_frame, render_key, OverlayContent, OverlayLayout};
+use crate::pipeline::{tts_default_sample_rate, ProcessedData, AUDIO_LEAD_SILENCE_MS};
+use crate::shutdown::ShutdownConfig;
+use anyhow::{anyhow, Result};
+use image::{ImageBuffer, Rgb};
+use log::{debug, error, info};
+use serde::Serialize;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+// Composed RGBA video frame
+pub struct VideoFrame {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+    pub key: Option<Vec<u8>>, // overlay with alpha when rgba is the clean feed, --key-fill
+    pub time_stamp: u64,      // presentation time in ms
+    pub preview: bool,        // partial image of a paragraph that is still rendering
+}
+
+impl VideoFrame {
+    // The image with the overlay drawn on, or with --key-fill the clean image and the
+    // overlay as its key
+    pub fn compose(
+        image_buffer: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        layout: &OverlayLayout,
+        content: &OverlayContent,
+        time_stamp: u64,
+        preview: bool,
+        args: &Args,
+    ) -> Self {
+        let (width, height) = image_buffer.dimensions();
+        let (rgba, key) = if args.key_fill {
+            (
+                rgb_to_rgba(image_buffer).into_raw(),
+                Some(render_key(width, height, layout, content)),
+            )
+        } else {
+            (render_frame(image_buffer, layout, content), None)
+        };
+        VideoFrame {
+            width,
+            height,
+            rgba,
+            key,
+            time_stamp,
+            preview,
+        }
+    }
+}
+
+// Interleaved f32 audio samples
+pub struct AudioFrame {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub time_stamp: u64,
+}
+
+// Chapter marker in the output metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterMarker {
+    pub index: usize,
+    pub title: String,
+    pub offset: String,
+}
+
+impl From<&Chapter> for ChapterMarker {
+    fn from(chapter: &Chapter) -> Self {
+        ChapterMarker {
+            index: chapter.index,
+            title: chapter.title.clone(),
+            offset: chapter.offset_string(),
+        }
+    }
+}
+
+// Metadata sent at the start of each paragraph
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputMetadata {
+    pub paragraph_count: usize,
+    pub time_stamp: u64,
+    pub duration_ms: u64,
+    pub text: String,
+    pub chapter: Option<ChapterMarker>,
+}
+
+pub trait OutputSink: Send {
+    fn name(&self) -> &str;
+
+    fn send_video(&mut self, frame: &VideoFrame) -> Result<()>;
+
+    fn send_audio(&mut self, audio: &AudioFrame) -> Result<()>;
+
+    fn send_metadata(&mut self, metadata: &OutputMetadata) -> Result<()>;
+
+    // flush anything buffered before the output shuts down
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Writes the frames as PNG, the audio as a WAV per paragraph and the metadata as JSON lines
+pub struct FileSink {
+    directory: PathBuf,
+    paragraph_count: usize,
+    frame_index: usize,
+    wav_writer: Option<hound::WavWriter<BufWriter<File>>>,
+    metadata: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn new(directory: &str) -> Result<Self> {
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory)?;
+        let metadata = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join("metadata.jsonl"))?;
+        Ok(FileSink {
+            directory,
+            paragraph_count: 0,
+            frame_index: 0,
+            wav_writer: None,
+            metadata: BufWriter::new(metadata),
+        })
+    }
+
+    fn finish_wav(&mut self) -> Result<()> {
+        if let Some(writer) = self.wav_writer.take() {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn send_video(&mut self, frame: &VideoFrame) -> Result<()> {
+        // only the final frames of a paragraph are kept
+        if frame.preview {
+            return Ok(());
+        }
+        let path = self.directory.join(format!(
+            "{:06}_{:02}.png",
+            self.paragraph_count, self.frame_index
+        ));
+        let key_path = self.directory.join(format!(
+            "{:06}_{:02}_key.png",
+            self.paragraph_count, self.frame_index
+        ));
+        self.frame_index += 1;
+        image::save_buffer(
+            &path,
+            &frame.rgba,
+            frame.width,
+            frame.height,
+            image::ColorType::Rgba8,
+        )?;
+        log_event(
+            "pipeline",
+            "media",
+            json!({
+                "kind": "image",
+                "sink": self.name(),
+                "paragraph_count": self.paragraph_count,
+                "path": path.to_string_lossy(),
+            }),
+        );
+        if let Some(key) = frame.key.as_ref() {
+            image::save_buffer(
+                &key_path,
+                key,
+                frame.width,
+                frame.height,
+                image::ColorType::Rgba8,
+            )?;
+            log_event(
+                "pipeline",
+                "media",
+                json!({
+                    "kind": "key",
+                    "sink": self.name(),
+                    "paragraph_count": self.paragraph_count,
+                    "path": key_path.to_string_lossy(),
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    fn send_audio(&mut self, audio: &AudioFrame) -> Result<()> {
+        if self.wav_writer.is_none() {
+            let spec = hound::WavSpec {
+                channels: audio.channels as u16,
+                sample_rate: audio.sample_rate,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            let path = self
+                .directory
+                .join(format!("{:06}.wav", self.paragraph_count));
+            self.wav_writer = Some(hound::WavWriter::create(&path, spec)?);
+            log_event(
+                "pipeline",
+                "media",
+                json!({
+                    "kind": "audio",
+                    "sink": self.name(),
+                    "paragraph_count": self.paragraph_count,
+                    "path": path.to_string_lossy(),
+                }),
+            );
+        }
+        if let Some(writer) = self.wav_writer.as_mut() {
+            for sample in &audio.samples {
+                writer.write_sample(*sample)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_metadata(&mut self, metadata: &OutputMetadata) -> Result<()> {
+        // a new paragraph starts new frame and audio files
+        self.finish_wav()?;
+        self.paragraph_count = metadata.paragraph_count;
+        self.frame_index = 0;
+        writeln!(self.metadata, "{}", serde_json::to_string(metadata)?)?;
+        self.metadata.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish_wav()?;
+        self.metadata.flush()?;
+        Ok(())
+    }
+}
+
+// Names of the sinks from --output-sinks, NDI by default when it is built in
+pub fn output_sink_names(args: &Args) -> Vec<String> {
+    if args.output_sinks.is_empty() {
+        if cfg!(feature = "ndi") {
+            return vec!["ndi".to_string()];
+        }
+        return Vec::new();
+    }
+    args.output_sinks
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// The speech audio goes to the output sinks instead of the local audio player
+pub fn output_audio(args: &Args) -> bool {
+    args.ndi_audio || output_sink_names(args).iter().any(|name| name != "ndi")
+}
+
+pub fn create_sink(name: &str, args: &Args) -> Result<Box<dyn OutputSink>> {
+    match name {
+        #[cfg(feature = "ndi")]
+        "ndi" => {
+            let mut sink = crate::ndi::NdiSink::new(args.ndi_images, args.ndi_audio);
+            if args.key_fill {
+                sink = sink.with_key(&args.ndi_key_name);
+            }
+            Ok(Box::new(sink))
+        }
+        #[cfg(not(feature = "ndi"))]
+        "ndi" => Err(anyhow!(
+            "NDI output needs the ndi feature, build with --features ndi"
+        )),
+        "file" => Ok(Box::new(FileSink::new(&args.output_dir)?)),
+        #[cfg(feature = "discord")]
+        "discord" => Ok(Box::new(crate::discord_voice::DiscordVoiceSink::from_args(
+            args,
+        )?)),
+        #[cfg(not(feature = "discord"))]
+        "discord" => Err(anyhow!(
+            "Discord voice output needs the discord feature, build with --features discord"
+        )),
+        "icecast" => Ok(Box::new(crate::icecast::IcecastSink::from_args(args)?)),
+        "podcast" => Ok(Box::new(crate::podcast::PodcastSink::from_args(args)?)),
+        _ => Err(anyhow!("Unknown output sink {}", name)),
+    }
+}
+
+// Samples of the outro music at the sample rate of the speech
+fn load_outro(path: &str, sample_rate: u32) -> Result<Vec<f32>> {
+    let (samples, outro_rate) = decode_audio(std::fs::read(path)?, sample_rate)?;
+    if outro_rate == sample_rate {
+        Ok(samples)
+    } else {
+        Ok(resample(&samples, outro_rate, sample_rate)?)
+    }
+}
+
+// The image on air with an animated overlay, composed again at the output frame rate
+struct HeldFrame {
+    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    layout: OverlayLayout,
+    content: OverlayContent,
+    time_stamp: u64,
+    preview: bool,
+    since: tokio::time::Instant,
+    frames: u64, // index of the next frame to compose
+}
+
+// All of the configured sinks, fed with the same frames and audio
+pub struct OutputSinks {
+    sinks: Vec<Box<dyn OutputSink>>,
+    cadence: Option<AudioCadence>,
+    held: Option<HeldFrame>,
+    music: Option<MusicBed>,
+    outro_music: String, // played after the shutdown announcement
+    meter: Option<AudioMeter>,
+}
+
+impl OutputSinks {
+    pub fn new() -> Self {
+        OutputSinks {
+            sinks: Vec::new(),
+            cadence: None,
+            held: None,
+            music: None,
+            outro_music: String::new(),
+            meter: None,
+        }
+    }
+
+    // Sinks from the arguments, the ones failing to start are logged and left out
+    pub fn from_args(args: &Args) -> Self {
+        let mut outputs = OutputSinks::new();
+        for name in output_sink_names(args) {
+            match create_sink(&name, args) {
+                Ok(sink) => {
+                    info!("Output sink {} enabled.", name);
+                    outputs.add(sink);
+                }
+                Err(e) => error!("Failed to start output sink {}: {}", name, e),
+            }
+        }
+        if !args.music_dir.is_empty() {
+            match MusicBed::load(&args.music_dir, args.music_volume) {
+                Ok(music) if !music.is_empty() => outputs.music = Some(music),
+                Ok(_) => error!("No music tracks found in {}", args.music_dir),
+                Err(e) => error!("Failed to load the music from {}: {}", args.music_dir, e),
+            }
+        }
+        outputs.outro_music = ShutdownConfig::from_args(args).outro_music;
+        outputs.meter = Some(AudioMeter::from_args(args));
+        outputs
+    }
+
+    pub fn add(&mut self, sink: Box<dyn OutputSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    fn each(&mut self, action: &str, mut send: impl FnMut(&mut dyn OutputSink) -> Result<()>) {
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = send(sink.as_mut()) {
+                error!("Output sink {} failed to {}: {}", sink.name(), action, e);
+            }
+        }
+    }
+
+    pub fn send_video(&mut self, frame: &VideoFrame) {
+        self.each("send video", |sink| sink.send_video(frame));
+    }
+
+    pub fn send_audio(&mut self, audio: &AudioFrame) {
+        self.each("send audio", |sink| sink.send_audio(audio));
+    }
+
+    pub fn send_metadata(&mut self, metadata: &OutputMetadata) {
+        self.each("send metadata", |sink| sink.send_metadata(metadata));
+    }
+
+    pub fn finish(&mut self) {
+        self.each("finish", |sink| sink.finish());
+    }
+
+    // Keep the image on air for the frame loop when its overlay is animated
+    fn hold(
+        &mut self,
+        image_buffer: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        layout: &OverlayLayout,
+        content: &OverlayContent,
+        time_stamp: u64,
+        preview: bool,
+    ) {
+        self.held = layout.is_animated().then(|| HeldFrame {
+            image: image_buffer.clone(),
+            layout: layout.clone(),
+            content: content.clone(),
+            time_stamp,
+            preview,
+            since: tokio::time::Instant::now(),
+            frames: 1, // the first frame was sent with the image
+        });
+    }
+
+    // Compose the held image again when the next frame of the frame rate is due, frames
+    // missed while busy are skipped
+    fn refresh(&mut self, frame_rate: FrameRate, args: &Args) {
+        let Some(held) = self.held.as_mut() else {
+            return;
+        };
+        let elapsed = held.since.elapsed();
+        if elapsed < frame_rate.frame_time(held.frames) {
+            return;
+        }
+        let frame = (elapsed.as_nanos() * frame_rate.num as u128
+            / (1_000_000_000 * frame_rate.den as u128)) as u64;
+        held.frames = frame + 1;
+        let frame = VideoFrame::compose(
+            &held.image,
+            &held.layout,
+            &held.content,
+            held.time_stamp + frame_rate.frame_time(frame).as_millis() as u64,
+            held.preview,
+            args,
+        );
+        self.send_video(&frame);
+    }
+
+    // Wait between paragraphs, the held image keeps going out at the output frame rate so
+    // the ticker scrolls
+    pub async fn idle(&mut self, wait: tokio::time::Duration, args: &Args) {
+        let deadline = tokio::time::Instant::now() + wait;
+        if let Some(frame_rate) = FrameRate::parse(&args.output_frame_rate) {
+            while let Some(held) = self.held.as_ref() {
+                let next_frame = held.since + frame_rate.frame_time(held.frames);
+                if next_frame >= deadline {
+                    break;
+                }
+                tokio::time::sleep_until(next_frame).await;
+                self.refresh(frame_rate, args);
+            }
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+
+    // Show an image of the next paragraph while its remaining images and audio are generated,
+    // the overlay is drawn without the subtitle
+    pub fn send_preview(
+        &mut self,
+        image_buffer: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        time_stamp: u64,
+        args: &Args,
+    ) {
+        let layout = OverlayLayout::from_args(args);
+        let content = OverlayContent::new("", "", args.hardsub_font_size);
+        let frame = VideoFrame::compose(image_buffer, &layout, &content, time_stamp, true, args);
+        self.send_video(&frame);
+        self.hold(image_buffer, &layout, &content, time_stamp, true);
+    }
+
+    // Send the frames and audio of a paragraph, the audio is paced in real time
+    pub async fn send(&mut self, processed_data: ProcessedData, args: &Args) {
+        let subtitle = if args.subtitles {
+            processed_data.paragraph.clone()
+        } else {
+            String::new()
+        };
+
+        let metadata = OutputMetadata {
+            paragraph_count: processed_data.paragraph_count,
+            time_stamp: processed_data.time_stamp,
+            duration_ms: processed_data.duration_ms,
+            text: processed_data.paragraph.clone(),
+            chapter: processed_data.chapter.as_ref().map(ChapterMarker::from),
+        };
+        log_event("pipeline", "output", json!(metadata));
+        self.send_metadata(&metadata);
+
+        if let Some(image_data) = processed_data.image_data {
+            debug!("Sending images to the output sinks");
+            let mut content = OverlayContent::new(
+                &subtitle,
+                &processed_data.subtitle_position,
+                args.hardsub_font_size,
+            );
+            if let Some(chapter) = processed_data.chapter.as_ref() {
+                content = content.with_field("chapter", &chapter.title);
+            }
+            let mut layout = OverlayLayout::from_args(args);
+            if let Some(requester) = processed_data.requested_by.as_ref() {
+                content = content
+                    .with_field("requester", requester)
+                    .with_field("request", &processed_data.paragraph);
+                layout.layers.push(OverlayLayout::lower_third(
+                    "Requested by {requester}\n{request}",
+                ));
+            }
+            for image_buffer in image_data {
+                let frame = VideoFrame::compose(
+                    &image_buffer,
+                    &layout,
+                    &content,
+                    processed_data.time_stamp,
+                    false,
+                    args,
+                );
+                self.send_video(&frame);
+                self.hold(
+                    &image_buffer,
+                    &layout,
+                    &content,
+                    processed_data.time_stamp,
+                    false,
+                );
+
+                // sleep for amount of a 60 fps frame
+                tokio::time::sleep(tokio::time::Duration::from_millis(16)).await;
+            }
+        }
+
+        if let Some(audio_data) = processed_data.audio_data {
+            // the format and sample rate come from the audio itself, the voice may be routed
+            // to a different TTS backend per paragraph
+            let samples_result = decode_audio(audio_data, tts_default_sample_rate(args));
+
+            if let Ok((mut samples_f32, mut sample_rate)) = samples_result {
+                // all the sinks get the audio at the output rate whatever the TTS backend
+                if args.audio_sample_rate > 0 && sample_rate != args.audio_sample_rate {
+                    match resample(&samples_f32, sample_rate, args.audio_sample_rate) {
+                        Ok(resampled) => {
+                            samples_f32 = resampled;
+                            sample_rate = args.audio_sample_rate;
+                        }
+                        Err(e) => error!(
+                            "Failed to resample the audio from {} to {} Hz: {}",
+                            sample_rate, args.audio_sample_rate, e
+                        ),
+                    }
+                }
+
+                // Calculate the number of samples needed for the lead in silence
+                let silence_samples =
+                    (AUDIO_LEAD_SILENCE_MS as f32 / 1000.0 * sample_rate as f32) as usize;
+
+                // Prepend the silence to the audio samples
+                samples_f32.splice(0..0, vec![0.0; silence_samples]);
+
+                // music bed under the speech at the intensity of the paragraph
+                if let Some(music) = self.music.as_mut() {
+                    let intensity = processed_data
+                        .sentiment
+                        .as_ref()
+                        .map(MusicIntensity::from_sentiment)
+                        .unwrap_or(MusicIntensity::Medium);
+                    music.mix(&mut samples_f32, sample_rate, intensity);
+                }
+
+                // the outro follows the goodbye
+                if processed_data.shutdown && !self.outro_music.is_empty() {
+                    match load_outro(&self.outro_music, sample_rate) {
+                        Ok(outro) => samples_f32.extend(outro),
+                        Err(e) => {
+                            error!("Failed to load the outro music {}: {}", self.outro_music, e)
+                        }
+                    }
+                }
+
+                // levels of what the viewers hear
+                if let Some(meter) = self.meter.as_ref() {
+                    meter.meter(&samples_f32, processed_data.paragraph_count);
+                }
+
+                match FrameRate::parse(&args.output_frame_rate) {
+                    Some(frame_rate) => {
+                        self.send_audio_frames(
+                            &samples_f32,
+                            sample_rate,
+                            frame_rate,
+                            processed_data.time_stamp,
+                            args,
+                        )
+                        .await
+                    }
+                    None => {
+                        self.send_audio_chunks(
+                            samples_f32,
+                            sample_rate,
+                            args.audio_chunk_size,
+                            processed_data.time_stamp,
+                        )
+                        .await
+                    }
+                }
+            }
+        }
+    }
+
+    // Send the audio in chunks of one video frame following the sample cadence of the frame
+    // rate, the cadence carries over between paragraphs so the audio stays on the video frames.
+    // The held image goes out again with each chunk.
+    async fn send_audio_frames(
+        &mut self,
+        samples: &[f32],
+        sample_rate: u32,
+        frame_rate: FrameRate,
+        time_stamp: u64,
+        args: &Args,
+    ) {
+        let cadence = match self.cadence.as_mut() {
+            Some(cadence) if cadence.sample_rate() == sample_rate => cadence,
+            _ => self
+                .cadence
+                .insert(AudioCadence::new(sample_rate, frame_rate)),
+        };
+        let chunks = cadence.chunk(samples);
+
+        debug!(
+            "Sending {} audio frames at {}/{} fps at presentation time {} ms",
+            chunks.len(),
+            frame_rate.num,
+            frame_rate.den,
+            time_stamp
+        );
+
+        // pace the frames against the start of the paragraph so the sleeps don't drift
+        let start = tokio::time::Instant::now();
+        for (index, samples) in chunks.into_iter().enumerate() {
+            let frame_time = frame_rate.frame_time(index as u64);
+            self.send_audio(&AudioFrame {
+                samples,
+                sample_rate,
+                channels: 1,
+                time_stamp: time_stamp + frame_time.as_millis() as u64,
+            });
+            self.refresh(frame_rate, args);
+            tokio::time::sleep_until(start + frame_rate.frame_time(index as u64 + 1)).await;
+        }
+    }
+
+    // Send the audio in chunks of --audio-chunk-size seconds
+    async fn send_audio_chunks(
+        &mut self,
+        mut samples_f32: Vec<f32>,
+        sample_rate: u32,
+        chunk_seconds: f32,
+        time_stamp: u64,
+    ) {
+        let channels: u32 = 1;
+        let chunk_size = chunk_seconds * sample_rate as f32 * channels as f32;
+
+        let chunk_duration = tokio::time::Duration::from_secs_f64(
+            chunk_size as f64 / channels as f64 / sample_rate as f64,
+        );
+
+        // make sure the last chunk is aligned to the chunk size
+        let last_chunk_size = samples_f32.len() as f32 % chunk_size;
+        let last_chunk_size = if last_chunk_size == 0.0 {
+            chunk_size
+        } else {
+            last_chunk_size
+        };
+        // Append silence to the last chunk to make it the same size as the other chunks
+        let silence_samples = (chunk_size - last_chunk_size) as usize;
+        samples_f32.extend(vec![0.0; silence_samples]);
+
+        debug!(
+            "Sending {} ms duration {} audio samples at presentation time {} ms",
+            chunk_duration.as_millis(),
+            chunk_size,
+            time_stamp
+        );
+
+        // pace the chunks against the start of the paragraph so the sleeps don't drift
+        let start = tokio::time::Instant::now();
+        for (index, chunk_samples) in samples_f32.chunks(chunk_size as usize).enumerate() {
+            let mut samples = chunk_samples.to_vec();
+            if chunk_samples.len() < chunk_size as usize {
+                samples.resize(chunk_size as usize, 0.0);
+            }
+            self.send_audio(&AudioFrame {
+                samples,
+                sample_rate,
+                channels,
+                time_stamp: time_stamp + (chunk_duration * index as u32).as_millis() as u64,
+            });
+            tokio::time::sleep_until(start + chunk_duration * (index as u32 + 1)).await;
+        }
+    }
+}
+
+impl Default for OutputSinks {
+    fn default() -> Self {
+        OutputSinks::new()
+    }
+}
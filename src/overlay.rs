@@ -0,0 +1,847 @@
+/*
+ * overlay.rs
+ * ----------
+ * Composition of the output frames. Layers like subtitles, lower thirds, logos, text,
+ * scrolling tickers and a picture in picture of the monitored stream are drawn over the
+ * generated image in the order of a declarative layout, loaded from the --overlay-layout
+ * JSON file or the plain subtitle layout by default.
+*/
+
+use crate::args::Args;
+use crate::image_ops::rgb_to_rgba;
+use crate::stream_data::program_summaries;
+use crate::system_stats::get_system_stats;
+#[cfg(feature = "fonts")]
+use crate::{font_runs, text_width, wrap_text};
+#[cfg(feature = "fonts")]
+use image::Rgba;
+use image::{imageops, ImageBuffer, Rgb, RgbaImage};
+#[cfg(feature = "fonts")]
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
+#[cfg(feature = "fonts")]
+use imageproc::rect::Rect;
+use once_cell::sync::Lazy;
+#[cfg(feature = "fonts")]
+use rusttype::{Font, Scale};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "fonts")]
+static FONT: Lazy<Font<'static>> = Lazy::new(|| {
+    let font_data = include_bytes!("../fonts/TrebuchetMSBold.ttf");
+    Font::try_from_bytes(font_data as &[u8]).expect("Error constructing Font")
+});
+
+// fallback fonts by file path for glyphs missing from the main font, loaded once
+#[cfg(feature = "fonts")]
+static FALLBACK_FONTS: Lazy<Mutex<HashMap<String, Option<Font<'static>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// layouts by file path and logos by image path, loaded once
+static LAYOUTS: Lazy<Mutex<HashMap<String, OverlayLayout>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static LOGOS: Lazy<Mutex<HashMap<String, Option<RgbaImage>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// ticker items by source, chat and headlines are pushed in while stats are read per frame
+static TICKER_ITEMS: Lazy<Mutex<HashMap<String, VecDeque<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// the scroll position of the tickers is derived from the time since this instant
+#[cfg(feature = "fonts")]
+static TICKER_START: Lazy<Instant> = Lazy::new(Instant::now);
+
+// latest decoded frame of the monitored stream for the picture in picture and when it came
+static MONITOR_FRAME: Lazy<Mutex<Option<(RgbaImage, Instant)>>> = Lazy::new(|| Mutex::new(None));
+
+// latest caption of the monitored stream's audio and until when it is shown
+static LIVE_CAPTION: Lazy<Mutex<Option<(String, Instant)>>> = Lazy::new(|| Mutex::new(None));
+
+// A monitor frame older than this is stale and the stats panel is shown in its place
+const MONITOR_FRAME_MAX_AGE: Duration = Duration::from_secs(10);
+// Border around the picture in picture in pixels
+const PIP_BORDER: u32 = 2;
+
+// Most recent items kept per ticker source
+const TICKER_MAX_ITEMS: usize = 10;
+const TICKER_SEPARATOR: &str = "   •   ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerKind {
+    #[default]
+    Subtitle,
+    LowerThird,
+    Logo,
+    Text,
+    Ticker,
+    Pip,
+}
+
+// One layer of the layout, text is a template with {field} values from the frame content
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LayerConfig {
+    pub kind: LayerKind,
+    pub text: String,
+    pub position: String, // named vertical position, empty uses x and y
+    pub x: f32,           // fraction of the frame width
+    pub y: f32,           // fraction of the frame height
+    pub width: f32,       // fraction of the frame width for logos and text wrapping
+    pub font_size: f32,   // 0 uses --hardsub-font-size
+    pub color: [u8; 4],
+    pub background: Option<[u8; 4]>,
+    pub shadow: bool,
+    pub path: String, // logo image
+    pub opacity: f32,
+    pub source: String, // ticker items: chat, stats or headlines, empty scrolls the text,
+    // picture in picture: stream or stats
+    pub speed: f32, // ticker scroll speed in pixels per second
+}
+
+impl Default for LayerConfig {
+    fn default() -> Self {
+        LayerConfig {
+            kind: LayerKind::Subtitle,
+            text: "{subtitle}".to_string(),
+            position: String::new(),
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            font_size: 0.0,
+            color: [255, 255, 255, 255],
+            background: None,
+            shadow: true,
+            path: String::new(),
+            opacity: 1.0,
+            source: String::new(),
+            speed: 120.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct OverlayLayout {
+    pub layers: Vec<LayerConfig>,
+    // font files tried in order for glyphs the main font doesn't have, like CJK or emoji
+    #[serde(default)]
+    pub fallback_fonts: Vec<String>,
+}
+
+impl OverlayLayout {
+    // Subtitles only, the output before layouts existed
+    pub fn subtitles() -> Self {
+        OverlayLayout {
+            layers: vec![LayerConfig::default()],
+            fallback_fonts: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    // Ticker band along the top or bottom of the frame
+    pub fn ticker(source: &str, position: &str) -> LayerConfig {
+        LayerConfig {
+            kind: LayerKind::Ticker,
+            text: String::new(),
+            position: position.to_string(),
+            shadow: false,
+            source: source.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Picture in picture of the monitored stream, or of its stats, in a corner of the frame
+    pub fn pip(source: &str, position: &str, width: f32) -> LayerConfig {
+        LayerConfig {
+            kind: LayerKind::Pip,
+            text: String::new(),
+            position: position.to_string(),
+            width,
+            shadow: false,
+            source: source.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Lower third with a title line and the text below it
+    pub fn lower_third(text: &str) -> LayerConfig {
+        LayerConfig {
+            kind: LayerKind::LowerThird,
+            text: text.to_string(),
+            background: Some([0, 0, 0, 160]),
+            ..Default::default()
+        }
+    }
+
+    // Live captions of the monitored stream in a band at the position
+    pub fn caption(position: &str) -> LayerConfig {
+        LayerConfig {
+            kind: LayerKind::Text,
+            text: "{caption}".to_string(),
+            position: position.to_string(),
+            background: Some([0, 0, 0, 160]),
+            ..Default::default()
+        }
+    }
+
+    // Layout from --overlay-layout, cached after the first load, with the --ticker, --pip
+    // and caption layers added
+    pub fn from_args(args: &Args) -> Self {
+        let mut layout = if args.overlay_layout.is_empty() {
+            OverlayLayout::subtitles()
+        } else {
+            OverlayLayout::load_cached(&args.overlay_layout)
+        };
+        if !args.ticker.is_empty() {
+            layout
+                .layers
+                .push(OverlayLayout::ticker(&args.ticker, &args.ticker_position));
+        }
+        // the picture in picture needs the capture running to have anything to show
+        if !args.pip.is_empty() && args.ai_network_stats {
+            layout.layers.push(OverlayLayout::pip(
+                &args.pip,
+                &args.pip_position,
+                args.pip_width,
+            ));
+        }
+        if args.captions && args.program_audio && !args.captions_position.is_empty() {
+            layout
+                .layers
+                .push(OverlayLayout::caption(&args.captions_position));
+        }
+        if layout.fallback_fonts.is_empty() {
+            layout.fallback_fonts = args
+                .fallback_fonts
+                .split(',')
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty())
+                .collect();
+        }
+        layout
+    }
+
+    // A ticker or picture in picture changes from frame to frame, the image is composed again
+    // for every output frame while it is on air
+    pub fn is_animated(&self) -> bool {
+        self.layers
+            .iter()
+            .any(|layer| matches!(layer.kind, LayerKind::Ticker | LayerKind::Pip))
+    }
+
+    fn load_cached(path: &str) -> Self {
+        let mut layouts = LAYOUTS.lock().unwrap();
+        layouts
+            .entry(path.to_string())
+            .or_insert_with(|| match OverlayLayout::load(path) {
+                Ok(layout) => layout,
+                Err(e) => {
+                    log::error!(
+                        "Failed to load overlay layout {}: {}, using subtitles only",
+                        path,
+                        e
+                    );
+                    OverlayLayout::subtitles()
+                }
+            })
+            .clone()
+    }
+}
+
+// Values for the layer templates of one frame
+#[derive(Debug, Clone, Default)]
+pub struct OverlayContent {
+    pub fields: HashMap<String, String>,
+    pub subtitle_position: String,
+    pub font_size: f32,
+}
+
+impl OverlayContent {
+    pub fn new(subtitle: &str, subtitle_position: &str, font_size: f32) -> Self {
+        let mut fields = HashMap::new();
+        fields.insert("subtitle".to_string(), subtitle.to_string());
+        fields.insert("caption".to_string(), live_caption().unwrap_or_default());
+        OverlayContent {
+            fields,
+            subtitle_position: subtitle_position.to_string(),
+            font_size,
+        }
+    }
+
+    pub fn with_field(mut self, name: &str, value: &str) -> Self {
+        self.fields.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    // Replace the {field} names in the template
+    pub fn fill(&self, template: &str) -> String {
+        let mut text = template.to_string();
+        for (name, value) in &self.fields {
+            text = text.replace(&format!("{{{}}}", name), value);
+        }
+        text
+    }
+}
+
+// Add an item to a ticker source, the oldest items scroll out when it is full
+pub fn ticker_push(source: &str, item: &str) {
+    let item = item.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if item.is_empty() {
+        return;
+    }
+    let mut tickers = TICKER_ITEMS.lock().unwrap();
+    let items = tickers.entry(source.to_string()).or_default();
+    items.push_back(item);
+    while items.len() > TICKER_MAX_ITEMS {
+        items.pop_front();
+    }
+}
+
+// Replace the items of a ticker source, like a new set of headlines
+pub fn ticker_set(source: &str, items: Vec<String>) {
+    let mut tickers = TICKER_ITEMS.lock().unwrap();
+    tickers.remove(source);
+    drop(tickers);
+    for item in items {
+        ticker_push(source, &item);
+    }
+}
+
+pub fn ticker_items(source: &str) -> Vec<String> {
+    let tickers = TICKER_ITEMS.lock().unwrap();
+    tickers
+        .get(source)
+        .map(|items| items.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+// Current text of a ticker source, stats are read fresh for every frame
+pub fn ticker_text(source: &str) -> String {
+    match source {
+        "stats" => get_system_stats().summary(),
+        _ => ticker_items(source).join(TICKER_SEPARATOR),
+    }
+}
+
+// Set the latest decoded frame of the monitored stream for the picture in picture
+pub fn set_monitor_frame(frame: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
+    *MONITOR_FRAME.lock().unwrap() = Some((rgb_to_rgba(frame), Instant::now()));
+}
+
+// The monitor frame unless it is stale
+pub fn monitor_frame() -> Option<RgbaImage> {
+    let frame = MONITOR_FRAME.lock().unwrap();
+    frame
+        .as_ref()
+        .filter(|(_, at)| at.elapsed() <= MONITOR_FRAME_MAX_AGE)
+        .map(|(frame, _)| frame.clone())
+}
+
+// Show a caption of the monitored stream's audio on the frames for the time given
+pub fn set_live_caption(text: &str, hold: Duration) {
+    *LIVE_CAPTION.lock().unwrap() = Some((text.to_string(), Instant::now() + hold));
+}
+
+// The caption unless its time is over
+pub fn live_caption() -> Option<String> {
+    let caption = LIVE_CAPTION.lock().unwrap();
+    caption
+        .as_ref()
+        .filter(|(_, until)| Instant::now() <= *until)
+        .map(|(text, _)| text.clone())
+}
+
+// Lines of the stats panel, the system and each program of the monitored stream
+pub fn stats_panel_lines() -> Vec<String> {
+    let mbps = |bitrate: u64| bitrate as f64 / 1_000_000.0;
+    let mut lines = vec![get_system_stats().summary()];
+    for program in program_summaries() {
+        lines.push(format!(
+            "Program {} | {:.2} Mbps | Video {:.2} Audio {:.2} | {} PIDs | {} errors",
+            program.program_number,
+            mbps(program.bitrate),
+            mbps(program.video_bitrate),
+            mbps(program.audio_bitrate),
+            program.pids,
+            program.error_count
+        ));
+    }
+    lines
+}
+
+// Top left of a box in a named corner like top-right, empty uses the x and y of the layer
+pub fn corner_position(
+    position: &str,
+    layer: &LayerConfig,
+    frame: (u32, u32),
+    size: (u32, u32),
+) -> (i64, i64) {
+    let (frame_width, frame_height) = (frame.0 as i64, frame.1 as i64);
+    let (width, height) = (size.0 as i64, size.1 as i64);
+    let margin = frame_width / 50;
+    let left = margin;
+    let right = frame_width - width - margin;
+    let top = margin;
+    let bottom = frame_height - height - margin;
+    match position {
+        "top-left" => (left, top),
+        "top-right" => (right, top),
+        "bottom-left" => (left, bottom),
+        "bottom-right" => (right, bottom),
+        "" => (
+            (frame.0 as f32 * layer.x) as i64,
+            (frame.1 as f32 * layer.y) as i64,
+        ),
+        _ => {
+            log::error!(
+                "Invalid picture in picture position '{}', using top-right instead.",
+                position
+            );
+            (right, top)
+        }
+    }
+}
+
+// Left edge of the ticker text after scrolling for elapsed_ms, the text enters at the right
+// edge of the frame and starts over once it has left on the left side
+pub fn ticker_offset(elapsed_ms: u128, speed: f32, text_width: i32, frame_width: i32) -> i32 {
+    let cycle = (text_width.max(0) + frame_width.max(1)) as u128;
+    let scrolled = (elapsed_ms as f64 * speed.max(0.0) as f64 / 1000.0) as u128 % cycle;
+    frame_width - scrolled as i32
+}
+
+// Text top for a named position like top, center or bottom
+pub fn vertical_position(position: &str, height: i32) -> i32 {
+    match position {
+        "top" => 10,
+        "mid-top" => height - (height / 2) / 2,
+        "center" | "middle" => height - (height / 2),
+        "low-center" => height - (height / 3),
+        "mid-bottom" => height - (height / 4),
+        "bottom" => height - (height / 5),
+        _ => {
+            log::error!(
+                "Invalid subtitle position '{}', using default position low-center instead.",
+                position
+            );
+            height - (height / 3)
+        }
+    }
+}
+
+// Draw the layers of the layout over the image
+pub fn compose(
+    image_buffer: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    layout: &OverlayLayout,
+    content: &OverlayContent,
+) -> RgbaImage {
+    let mut frame = rgb_to_rgba(image_buffer);
+    draw_layers(&mut frame, layout, content);
+    frame
+}
+
+// The layers alone on a transparent frame, the fill and alpha of a key for a downstream
+// keyer to put over the clean feed
+pub fn compose_key(
+    width: u32,
+    height: u32,
+    layout: &OverlayLayout,
+    content: &OverlayContent,
+) -> RgbaImage {
+    let mut frame = RgbaImage::new(width, height);
+    draw_layers(&mut frame, layout, content);
+    frame
+}
+
+fn draw_layers(frame: &mut RgbaImage, layout: &OverlayLayout, content: &OverlayContent) {
+    #[cfg(feature = "fonts")]
+    let fonts = layout_fonts(&layout.fallback_fonts);
+
+    for layer in &layout.layers {
+        match layer.kind {
+            LayerKind::Logo => draw_logo(frame, layer),
+            LayerKind::Pip => draw_pip(frame, layer, content),
+            LayerKind::Ticker => {
+                #[cfg(feature = "fonts")]
+                draw_ticker(frame, layer, content, &fonts);
+            }
+            LayerKind::Subtitle | LayerKind::LowerThird | LayerKind::Text => {
+                #[cfg(feature = "fonts")]
+                draw_text_layer(frame, layer, content, &fonts);
+                #[cfg(not(feature = "fonts"))]
+                let _ = content;
+            }
+        }
+    }
+}
+
+// Compose the frame and return the RGBA bytes for the output
+pub fn render_frame(
+    image_buffer: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    layout: &OverlayLayout,
+    content: &OverlayContent,
+) -> Vec<u8> {
+    compose(image_buffer, layout, content).into_raw()
+}
+
+// RGBA bytes of the key for the output
+pub fn render_key(
+    width: u32,
+    height: u32,
+    layout: &OverlayLayout,
+    content: &OverlayContent,
+) -> Vec<u8> {
+    compose_key(width, height, layout, content).into_raw()
+}
+
+// The main font followed by the fallback fonts that could be loaded
+#[cfg(feature = "fonts")]
+fn layout_fonts(paths: &[String]) -> Vec<Font<'static>> {
+    let mut fonts = vec![FONT.clone()];
+    let mut fallback_fonts = FALLBACK_FONTS.lock().unwrap();
+    for path in paths {
+        let font = fallback_fonts.entry(path.clone()).or_insert_with(|| {
+            let font = std::fs::read(path)
+                .ok()
+                .and_then(|data| Font::try_from_vec(data));
+            if font.is_none() {
+                log::error!("Failed to load fallback font {}", path);
+            }
+            font
+        });
+        if let Some(font) = font {
+            fonts.push(font.clone());
+        }
+    }
+    fonts
+}
+
+fn load_logo(path: &str) -> Option<RgbaImage> {
+    let mut logos = LOGOS.lock().unwrap();
+    logos
+        .entry(path.to_string())
+        .or_insert_with(|| match image::open(path) {
+            Ok(logo) => Some(logo.to_rgba8()),
+            Err(e) => {
+                log::error!("Failed to load overlay logo {}: {}", path, e);
+                None
+            }
+        })
+        .clone()
+}
+
+fn draw_logo(frame: &mut RgbaImage, layer: &LayerConfig) {
+    let Some(mut logo) = load_logo(&layer.path) else {
+        return;
+    };
+
+    if layer.width > 0.0 && layer.width < 1.0 {
+        let width = (frame.width() as f32 * layer.width).max(1.0) as u32;
+        let height = (logo.height() as f32 * width as f32 / logo.width() as f32).max(1.0) as u32;
+        logo = imageops::resize(&logo, width, height, imageops::FilterType::Triangle);
+    }
+    if layer.opacity < 1.0 {
+        for pixel in logo.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * layer.opacity.max(0.0)) as u8;
+        }
+    }
+
+    let x = (frame.width() as f32 * layer.x) as i64;
+    let y = (frame.height() as f32 * layer.y) as i64;
+    imageops::overlay(frame, &logo, x, y);
+}
+
+// The monitored stream's frame in a border, or the stats panel when there is no recent frame
+fn draw_pip(frame: &mut RgbaImage, layer: &LayerConfig, content: &OverlayContent) {
+    let width = (frame.width() as f32 * layer.width.clamp(0.05, 1.0)) as u32;
+    let monitor = match layer.source.as_str() {
+        "stats" => None,
+        _ => monitor_frame(),
+    };
+    let Some(monitor) = monitor else {
+        #[cfg(feature = "fonts")]
+        draw_stats_panel(frame, layer, content, width);
+        #[cfg(not(feature = "fonts"))]
+        let _ = content;
+        return;
+    };
+
+    let height = (monitor.height() as f32 * width as f32 / monitor.width().max(1) as f32) as u32;
+    let thumbnail = imageops::resize(
+        &monitor,
+        width.max(1),
+        height.max(1),
+        imageops::FilterType::Triangle,
+    );
+    let mut framed = RgbaImage::from_pixel(
+        thumbnail.width() + PIP_BORDER * 2,
+        thumbnail.height() + PIP_BORDER * 2,
+        image::Rgba(layer.color),
+    );
+    imageops::overlay(
+        &mut framed,
+        &thumbnail,
+        PIP_BORDER as i64,
+        PIP_BORDER as i64,
+    );
+    let (x, y) = corner_position(
+        &layer.position,
+        layer,
+        frame.dimensions(),
+        framed.dimensions(),
+    );
+    imageops::overlay(frame, &framed, x, y);
+}
+
+#[cfg(feature = "fonts")]
+fn draw_stats_panel(
+    frame: &mut RgbaImage,
+    layer: &LayerConfig,
+    content: &OverlayContent,
+    width: u32,
+) {
+    let font_size = if layer.font_size > 0.0 {
+        layer.font_size
+    } else {
+        content.font_size * 0.4
+    };
+    let fonts = [FONT.clone()];
+    let padding = (font_size / 2.0) as i32;
+    let lines: Vec<String> = stats_panel_lines()
+        .iter()
+        .flat_map(|line| wrap_text(line, &fonts, scale(font_size), width as i32 - padding * 2))
+        .collect();
+    let height = (lines.len() as f32 * font_size) as u32 + padding as u32 * 2;
+    let (x, y) = corner_position(&layer.position, layer, frame.dimensions(), (width, height));
+    let rect = Rect::at(x as i32, y as i32).of_size(width.max(1), height.max(1));
+    blend_rect(frame, rect, layer.background.unwrap_or([0, 0, 0, 180]));
+    draw_lines(
+        frame,
+        &lines,
+        x as i32 + padding,
+        y as i32 + padding,
+        font_size,
+        layer,
+        &fonts,
+    );
+}
+
+#[cfg(feature = "fonts")]
+fn draw_text_layer(
+    frame: &mut RgbaImage,
+    layer: &LayerConfig,
+    content: &OverlayContent,
+    fonts: &[Font],
+) {
+    let text = content.fill(&layer.text);
+    if text.trim().is_empty() {
+        return;
+    }
+
+    let font_size = if layer.font_size > 0.0 {
+        layer.font_size
+    } else {
+        content.font_size
+    };
+    let height = frame.height() as i32;
+    let left = if layer.x > 0.0 {
+        (frame.width() as f32 * layer.x) as i32
+    } else {
+        font_size as i32
+    };
+    let max_width = ((frame.width() as f32 * layer.width.clamp(0.0, 1.0)) as i32 - left * 2)
+        .max(font_size as i32);
+    let top = match (layer.kind, layer.position.as_str()) {
+        (LayerKind::Subtitle, "") => vertical_position(&content.subtitle_position, height),
+        (LayerKind::LowerThird, "") => height - height / 4,
+        (_, "") => (height as f32 * layer.y) as i32,
+        (_, position) => vertical_position(position, height),
+    };
+
+    // the lower third has a title line and smaller text below it
+    let (title, body) = match layer.kind {
+        LayerKind::LowerThird => match text.split_once('\n') {
+            Some((title, body)) => (title.to_string(), body.to_string()),
+            None => (text.clone(), String::new()),
+        },
+        _ => (text.clone(), String::new()),
+    };
+    let body_size = font_size * 0.7;
+    let title_lines = wrap_text(&title, fonts, scale(font_size), max_width);
+    let body_lines = wrap_text(&body, fonts, scale(body_size), max_width);
+
+    let background = layer.background.or(match layer.kind {
+        LayerKind::LowerThird => Some([0, 0, 0, 160]),
+        _ => None,
+    });
+    if let Some(background) = background {
+        let block_height =
+            title_lines.len() as f32 * font_size + body_lines.len() as f32 * body_size;
+        let padding = (font_size / 4.0) as i32;
+        let rect = Rect::at(left - padding, top - padding).of_size(
+            (max_width + padding * 2) as u32,
+            (block_height as i32 + padding * 2).max(1) as u32,
+        );
+        blend_rect(frame, rect, background);
+    }
+
+    let bottom = draw_lines(frame, &title_lines, left, top, font_size, layer, fonts);
+    draw_lines(frame, &body_lines, left, bottom, body_size, layer, fonts);
+}
+
+// Band across the frame with the ticker text scrolled to its position for this frame
+#[cfg(feature = "fonts")]
+fn draw_ticker(
+    frame: &mut RgbaImage,
+    layer: &LayerConfig,
+    content: &OverlayContent,
+    fonts: &[Font],
+) {
+    let text = if layer.source.is_empty() {
+        content.fill(&layer.text)
+    } else {
+        ticker_text(&layer.source)
+    };
+    if text.trim().is_empty() {
+        return;
+    }
+
+    let font_size = if layer.font_size > 0.0 {
+        layer.font_size
+    } else {
+        content.font_size * 0.6
+    };
+    let width = frame.width() as i32;
+    let height = frame.height() as i32;
+    let band_height = (font_size * 1.4) as i32;
+    let top = match layer.position.as_str() {
+        "top" => 0,
+        "" | "bottom" => height - band_height,
+        _ => (height as f32 * layer.y) as i32,
+    };
+
+    let background = layer.background.unwrap_or([0, 0, 0, 180]);
+    let rect = Rect::at(0, top).of_size(width.max(1) as u32, band_height.max(1) as u32);
+    blend_rect(frame, rect, background);
+
+    let text_scale = scale(font_size);
+    let left = ticker_offset(
+        TICKER_START.elapsed().as_millis(),
+        layer.speed,
+        text_width(&text, fonts, text_scale) as i32,
+        width,
+    );
+    let text_top = top + (band_height - font_size as i32) / 2;
+    draw_lines(frame, &[text], left, text_top, font_size, layer, fonts);
+}
+
+#[cfg(feature = "fonts")]
+fn scale(font_size: f32) -> Scale {
+    Scale {
+        x: font_size,
+        y: font_size,
+    }
+}
+
+// Draw wrapped lines with the drop shadow, returns the top of the next line
+#[cfg(feature = "fonts")]
+fn draw_lines(
+    frame: &mut RgbaImage,
+    lines: &[String],
+    left: i32,
+    top: i32,
+    font_size: f32,
+    layer: &LayerConfig,
+    fonts: &[Font],
+) -> i32 {
+    let text_scale = scale(font_size);
+    let shadow_color = Rgba([0, 0, 0, 255]);
+    let shadow_top_offset = 2; // Shadow offset in pixels
+    let shadow_bottom_offset = 4; // Shadow offset in pixels
+
+    if layer.shadow {
+        for (offset_x, offset_y) in [
+            (shadow_bottom_offset, shadow_bottom_offset / 2),
+            (-shadow_top_offset, -shadow_top_offset / 2),
+        ] {
+            let mut line_top = top + offset_y;
+            for line in lines {
+                draw_text_runs(
+                    frame,
+                    shadow_color,
+                    left + offset_x,
+                    line_top,
+                    text_scale,
+                    fonts,
+                    line,
+                );
+                line_top += font_size as i32;
+            }
+        }
+    }
+
+    let mut line_top = top;
+    for line in lines {
+        draw_text_runs(
+            frame,
+            Rgba(layer.color),
+            left,
+            line_top,
+            text_scale,
+            fonts,
+            line,
+        );
+        line_top += font_size as i32;
+    }
+    line_top
+}
+
+// Draw one line switching to the fallback fonts for the glyphs the main font doesn't have
+#[cfg(feature = "fonts")]
+fn draw_text_runs(
+    frame: &mut RgbaImage,
+    color: Rgba<u8>,
+    left: i32,
+    top: i32,
+    text_scale: Scale,
+    fonts: &[Font],
+    line: &str,
+) {
+    let mut x = left as f32;
+    for (index, run, width) in font_runs(line, fonts, text_scale) {
+        draw_text_mut(frame, color, x as i32, top, text_scale, &fonts[index], &run);
+        x += width;
+    }
+}
+
+// Fill a rectangle blending the color with its alpha over the frame, the frame's alpha is
+// blended too so the band shows in a key
+#[cfg(feature = "fonts")]
+fn blend_rect(frame: &mut RgbaImage, rect: Rect, color: [u8; 4]) {
+    if color[3] == 255 {
+        draw_filled_rect_mut(frame, rect, Rgba(color));
+        return;
+    }
+    let alpha = color[3] as f32 / 255.0;
+    let x_end = (rect.right() + 1).clamp(0, frame.width() as i32);
+    let y_end = (rect.bottom() + 1).clamp(0, frame.height() as i32);
+    for y in rect.top().max(0)..y_end {
+        for x in rect.left().max(0)..x_end {
+            let pixel = frame.get_pixel_mut(x as u32, y as u32);
+            let below = pixel[3] as f32 / 255.0 * (1.0 - alpha);
+            let out = alpha + below;
+            if out <= 0.0 {
+                continue;
+            }
+            for c in 0..3 {
+                pixel[c] = ((color[c] as f32 * alpha + pixel[c] as f32 * below) / out) as u8;
+            }
+            pixel[3] = (out * 255.0).round() as u8;
+        }
+    }
+}
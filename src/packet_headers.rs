@@ -0,0 +1,385 @@
+/*
+ * packet_headers.rs
+ * -----------------
+ * Find the UDP payload in a captured frame. The headers are walked per packet so
+ * 802.1Q/802.1ad VLAN tags, MPLS label stacks (with an Ethernet pseudowire inside), IPv4
+ * options and IPv6 extension headers all land on the start of the MPEG-TS or RTP payload.
+ * Besides Ethernet the loopback (NULL/LOOP), Linux cooked (SLL, SLL2) and raw IP link types
+ * of lo0, the Linux any device and tunnels are understood.
+*/
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const NULL_HEADER_LEN: usize = 4;
+const SLL_HEADER_LEN: usize = 16;
+const SLL2_HEADER_LEN: usize = 20;
+const VLAN_TAG_LEN: usize = 4;
+const MPLS_LABEL_LEN: usize = 4;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+
+// Longest headers expected in front of the payload, for the capture snap length
+pub const MAX_HEADER_LEN: usize = 128;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+const ETHERTYPE_QINQ_OLD: u16 = 0x9100;
+const ETHERTYPE_MPLS: u16 = 0x8847;
+const ETHERTYPE_MPLS_MULTICAST: u16 = 0x8848;
+
+const IP_PROTOCOL_UDP: u8 = 17;
+
+// Link layer of a capture, the pcap DLT_ of its device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkType {
+    Ethernet,
+    Null, // BSD loopback, a 4 byte address family
+    LinuxSll,
+    LinuxSll2,
+    Raw, // IPv4 or IPv6 without a link header
+}
+
+impl LinkType {
+    // From the link type of pcap, Err for the ones without UDP over IP to find
+    pub fn from_datalink(datalink: i32) -> Result<Self, String> {
+        match datalink {
+            1 => Ok(LinkType::Ethernet),
+            // DLT_NULL and DLT_LOOP
+            0 | 108 => Ok(LinkType::Null),
+            113 => Ok(LinkType::LinuxSll),
+            276 => Ok(LinkType::LinuxSll2),
+            // DLT_RAW is 12 or 14 depending on the platform, LINKTYPE_RAW, IPV4 and IPV6
+            12 | 14 | 101 | 228 | 229 => Ok(LinkType::Raw),
+            _ => Err(format!("Unsupported capture link type {}", datalink)),
+        }
+    }
+}
+
+fn read_u16(frame: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *frame.get(offset)?,
+        *frame.get(offset + 1)?,
+    ]))
+}
+
+// Offset of the UDP payload in a frame of the link type, None when it is not UDP over IP
+pub fn udp_payload_offset(frame: &[u8], link_type: LinkType) -> Option<usize> {
+    match link_type {
+        LinkType::Ethernet => ethernet_payload_offset(frame, 0),
+        // the address family is in host byte order for NULL, the IP version tells it anyway
+        LinkType::Null => ip_payload_offset(frame, NULL_HEADER_LEN),
+        LinkType::LinuxSll => {
+            ethertype_payload_offset(frame, read_u16(frame, SLL_HEADER_LEN - 2)?, SLL_HEADER_LEN)
+        }
+        LinkType::LinuxSll2 => {
+            ethertype_payload_offset(frame, read_u16(frame, 0)?, SLL2_HEADER_LEN)
+        }
+        LinkType::Raw => ip_payload_offset(frame, 0),
+    }
+}
+
+fn ip_payload_offset(frame: &[u8], offset: usize) -> Option<usize> {
+    match frame.get(offset)? >> 4 {
+        4 => ipv4_payload_offset(frame, offset),
+        6 => ipv6_payload_offset(frame, offset),
+        _ => None,
+    }
+}
+
+fn ethernet_payload_offset(frame: &[u8], start: usize) -> Option<usize> {
+    let offset = start + ETHERNET_HEADER_LEN;
+    ethertype_payload_offset(frame, read_u16(frame, offset - 2)?, offset)
+}
+
+// The payload after the ethertype and the VLAN tags that follow it
+fn ethertype_payload_offset(frame: &[u8], mut ethertype: u16, mut offset: usize) -> Option<usize> {
+    // stacked VLAN tags
+    while matches!(
+        ethertype,
+        ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_OLD
+    ) {
+        ethertype = read_u16(frame, offset + 2)?;
+        offset += VLAN_TAG_LEN;
+    }
+
+    match ethertype {
+        ETHERTYPE_IPV4 => ipv4_payload_offset(frame, offset),
+        ETHERTYPE_IPV6 => ipv6_payload_offset(frame, offset),
+        ETHERTYPE_MPLS | ETHERTYPE_MPLS_MULTICAST => mpls_payload_offset(frame, offset),
+        _ => None,
+    }
+}
+
+// Skip the label stack, the payload type has to be guessed from its first nibble
+fn mpls_payload_offset(frame: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let bottom_of_stack = frame.get(offset + 2)? & 0x01 != 0;
+        offset += MPLS_LABEL_LEN;
+        if bottom_of_stack {
+            break;
+        }
+    }
+    match frame.get(offset)? >> 4 {
+        4 => ipv4_payload_offset(frame, offset),
+        6 => ipv6_payload_offset(frame, offset),
+        // pseudowire control word in front of an Ethernet frame
+        0 => ethernet_payload_offset(frame, offset + 4),
+        _ => None,
+    }
+}
+
+fn ipv4_payload_offset(frame: &[u8], offset: usize) -> Option<usize> {
+    let version_ihl = *frame.get(offset)?;
+    if version_ihl >> 4 != 4 {
+        return None;
+    }
+    let header_len = (version_ihl & 0x0F) as usize * 4;
+    if *frame.get(offset + 9)? != IP_PROTOCOL_UDP || header_len < 20 {
+        return None;
+    }
+    // only the first fragment carries the UDP header
+    if read_u16(frame, offset + 6)? & 0x1FFF != 0 {
+        return None;
+    }
+    udp_offset(frame, offset + header_len)
+}
+
+fn ipv6_payload_offset(frame: &[u8], offset: usize) -> Option<usize> {
+    if frame.get(offset)? >> 4 != 6 {
+        return None;
+    }
+    let mut next_header = *frame.get(offset + 6)?;
+    let mut offset = offset + IPV6_HEADER_LEN;
+    loop {
+        match next_header {
+            IP_PROTOCOL_UDP => return udp_offset(frame, offset),
+            // hop-by-hop, routing and destination options
+            0 | 43 | 60 => {
+                next_header = *frame.get(offset)?;
+                offset += (*frame.get(offset + 1)? as usize + 1) * 8;
+            }
+            // fragment, only the first fragment carries the UDP header
+            44 => {
+                next_header = *frame.get(offset)?;
+                let fragment_offset = read_u16(frame, offset + 2)? >> 3;
+                if fragment_offset != 0 {
+                    return None;
+                }
+                offset += 8;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn udp_offset(frame: &[u8], offset: usize) -> Option<usize> {
+    let payload = offset + UDP_HEADER_LEN;
+    (payload <= frame.len()).then_some(payload)
+}
+
+// Destination port of the UDP header in front of the payload found by udp_payload_offset
+pub fn udp_destination_port(frame: &[u8], payload_offset: usize) -> Option<u16> {
+    read_u16(frame, payload_offset.checked_sub(UDP_HEADER_LEN - 2)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: [u8; 4] = [0x47, 0x00, 0x11, 0x10];
+
+    fn udp(port: u16) -> Vec<u8> {
+        let mut udp = vec![0x13, 0x88];
+        udp.extend_from_slice(&port.to_be_bytes());
+        udp.extend_from_slice(&[0x00, 0x0C, 0x00, 0x00]);
+        udp.extend_from_slice(&PAYLOAD);
+        udp
+    }
+
+    fn ipv4(options: usize, fragment: u16, protocol: u8) -> Vec<u8> {
+        let mut header = vec![0; 20 + options * 4];
+        header[0] = 0x45 + options as u8;
+        header[6..8].copy_from_slice(&fragment.to_be_bytes());
+        header[8] = 64;
+        header[9] = protocol;
+        header.extend(udp(5000));
+        header
+    }
+
+    // IPv6 with the extension headers of (type, length in 8 byte units after the first 8)
+    fn ipv6(extensions: &[(u8, u8)], fragment_offset: u16) -> Vec<u8> {
+        let mut header = vec![0; IPV6_HEADER_LEN];
+        header[0] = 0x60;
+        header[6] = extensions
+            .first()
+            .map_or(IP_PROTOCOL_UDP, |(kind, _)| *kind);
+        for (index, (kind, length)) in extensions.iter().enumerate() {
+            let next = extensions
+                .get(index + 1)
+                .map_or(IP_PROTOCOL_UDP, |(kind, _)| *kind);
+            let mut extension = vec![0; (*length as usize + 1) * 8];
+            extension[0] = next;
+            if *kind == 44 {
+                extension[2..4].copy_from_slice(&(fragment_offset << 3).to_be_bytes());
+            } else {
+                extension[1] = *length;
+            }
+            header.extend(extension);
+        }
+        header.extend(udp(5000));
+        header
+    }
+
+    fn ethernet(tags: &[u16], ethertype: u16, payload: Vec<u8>) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for tag in tags {
+            frame.extend_from_slice(&tag.to_be_bytes());
+            frame.extend_from_slice(&[0x00, 0x64]); // VLAN 100
+        }
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    fn mpls(labels: usize, payload: Vec<u8>) -> Vec<u8> {
+        let mut stack = Vec::new();
+        for index in 0..labels {
+            let bottom = if index + 1 == labels { 0x01 } else { 0x00 };
+            stack.extend_from_slice(&[0x00, 0x01, bottom, 0x40]);
+        }
+        stack.extend(payload);
+        stack
+    }
+
+    fn payload_of(frame: &[u8], link_type: LinkType) -> Option<&[u8]> {
+        udp_payload_offset(frame, link_type).map(|offset| &frame[offset..])
+    }
+
+    #[test]
+    fn plain_ethernet() {
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 0, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), Some(42));
+        assert_eq!(udp_destination_port(&frame, 42), Some(5000));
+    }
+
+    #[test]
+    fn vlan_and_qinq_tags() {
+        let frame = ethernet(
+            &[ETHERTYPE_VLAN],
+            ETHERTYPE_IPV4,
+            ipv4(0, 0, IP_PROTOCOL_UDP),
+        );
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), Some(46));
+        let frame = ethernet(
+            &[ETHERTYPE_QINQ, ETHERTYPE_VLAN],
+            ETHERTYPE_IPV4,
+            ipv4(0, 0, IP_PROTOCOL_UDP),
+        );
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), Some(50));
+        assert_eq!(payload_of(&frame, LinkType::Ethernet), Some(&PAYLOAD[..]));
+        let frame = ethernet(&[ETHERTYPE_QINQ_OLD], ETHERTYPE_IPV6, ipv6(&[], 0));
+        assert_eq!(payload_of(&frame, LinkType::Ethernet), Some(&PAYLOAD[..]));
+    }
+
+    #[test]
+    fn mpls_label_stacks() {
+        let frame = ethernet(&[], ETHERTYPE_MPLS, mpls(1, ipv4(0, 0, IP_PROTOCOL_UDP)));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), Some(46));
+        let frame = ethernet(&[], ETHERTYPE_MPLS_MULTICAST, mpls(3, ipv6(&[], 0)));
+        assert_eq!(payload_of(&frame, LinkType::Ethernet), Some(&PAYLOAD[..]));
+
+        // Ethernet pseudowire behind a control word
+        let mut pseudowire = vec![0x00; 4];
+        pseudowire.extend(ethernet(
+            &[ETHERTYPE_VLAN],
+            ETHERTYPE_IPV4,
+            ipv4(0, 0, IP_PROTOCOL_UDP),
+        ));
+        let frame = ethernet(&[], ETHERTYPE_MPLS, mpls(2, pseudowire));
+        assert_eq!(payload_of(&frame, LinkType::Ethernet), Some(&PAYLOAD[..]));
+
+        // a stack without its bottom runs off the frame
+        let frame = ethernet(&[], ETHERTYPE_MPLS, vec![0x00, 0x01, 0x00, 0x40]);
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+    }
+
+    #[test]
+    fn ipv4_options() {
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(2, 0, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), Some(50));
+        assert_eq!(payload_of(&frame, LinkType::Ethernet), Some(&PAYLOAD[..]));
+
+        // an IHL below 5 is not a header
+        let mut frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 0, IP_PROTOCOL_UDP));
+        frame[ETHERNET_HEADER_LEN] = 0x44;
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        // the first fragment has the UDP header, more fragments set or not
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 0x2000, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), Some(42));
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 0x4000, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), Some(42));
+        // the later ones don't
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 0x2000 | 185, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 185, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+    }
+
+    #[test]
+    fn ipv6_extension_chain() {
+        let frame = ethernet(&[], ETHERTYPE_IPV6, ipv6(&[(0, 0), (43, 1), (60, 0)], 0));
+        assert_eq!(
+            udp_payload_offset(&frame, LinkType::Ethernet),
+            Some(14 + 40 + 8 + 16 + 8 + 8)
+        );
+        assert_eq!(payload_of(&frame, LinkType::Ethernet), Some(&PAYLOAD[..]));
+        // an unknown extension ends the walk
+        let frame = ethernet(&[], ETHERTYPE_IPV6, ipv6(&[(50, 0)], 0));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+    }
+
+    #[test]
+    fn ipv6_fragments() {
+        let frame = ethernet(&[], ETHERTYPE_IPV6, ipv6(&[(0, 0), (44, 0)], 0));
+        assert_eq!(payload_of(&frame, LinkType::Ethernet), Some(&PAYLOAD[..]));
+        let frame = ethernet(&[], ETHERTYPE_IPV6, ipv6(&[(44, 0)], 185));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+    }
+
+    #[test]
+    fn not_udp() {
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 0, 6));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+        let frame = ethernet(&[], 0x0806, ipv4(0, 0, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame, LinkType::Ethernet), None);
+        // truncated in the UDP header
+        let frame = ethernet(&[], ETHERTYPE_IPV4, ipv4(0, 0, IP_PROTOCOL_UDP));
+        assert_eq!(udp_payload_offset(&frame[..38], LinkType::Ethernet), None);
+    }
+
+    #[test]
+    fn other_link_types() {
+        let mut frame = vec![0x02, 0x00, 0x00, 0x00];
+        frame.extend(ipv4(0, 0, IP_PROTOCOL_UDP));
+        assert_eq!(payload_of(&frame, LinkType::Null), Some(&PAYLOAD[..]));
+
+        let mut frame = vec![0; SLL_HEADER_LEN - 2];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend(ipv4(0, 0, IP_PROTOCOL_UDP));
+        assert_eq!(payload_of(&frame, LinkType::LinuxSll), Some(&PAYLOAD[..]));
+
+        let mut frame = ETHERTYPE_IPV6.to_be_bytes().to_vec();
+        frame.extend_from_slice(&[0; SLL2_HEADER_LEN - 2]);
+        frame.extend(ipv6(&[], 0));
+        assert_eq!(payload_of(&frame, LinkType::LinuxSll2), Some(&PAYLOAD[..]));
+
+        assert_eq!(payload_of(&ipv6(&[], 0), LinkType::Raw), Some(&PAYLOAD[..]));
+        assert_eq!(LinkType::from_datalink(1), Ok(LinkType::Ethernet));
+        assert!(LinkType::from_datalink(105).is_err());
+    }
+}
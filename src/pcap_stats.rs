@@ -0,0 +1,129 @@
+/*
+ * pcap_stats.rs
+ * -------------
+ * Counters of the pcap capture handles, one entry per capture device. The capture tasks read
+ * the received, dropped and interface dropped counters every few seconds and publish them
+ * here with the share of packets lost since the last read, for the stats, the control API
+ * and the health report. A drop rate over the threshold raises an alert until it is back
+ * under for a whole interval.
+*/
+
+use crate::current_unix_timestamp_ms;
+use crate::event_log::log_event;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static PCAP_STATS: Lazy<Mutex<Vec<PcapStats>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Time between reads of the counters of a capture handle
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PcapStats {
+    pub path: usize,
+    pub device: String,
+    pub received: u64,
+    pub dropped: u64,      // by the kernel buffer
+    pub if_dropped: u64,   // by the interface
+    pub drop_percent: f64, // of the packets of the last interval
+    pub alarm: bool,
+    pub timestamp: u64,
+}
+
+// The counters of each capture device
+pub fn pcap_stats() -> Vec<PcapStats> {
+    PCAP_STATS.lock().unwrap().clone()
+}
+
+fn publish(stats: &PcapStats) {
+    let mut all = PCAP_STATS.lock().unwrap();
+    match all.iter_mut().find(|entry| entry.path == stats.path) {
+        Some(entry) => *entry = stats.clone(),
+        None => {
+            all.push(stats.clone());
+            all.sort_by_key(|entry| entry.path);
+        }
+    }
+}
+
+// Follows the counters of one capture handle
+pub struct PcapStatsMonitor {
+    stats: PcapStats,
+    alarm_percent: f64, // 0 is no alarm
+    last_read: Option<Instant>,
+}
+
+impl PcapStatsMonitor {
+    pub fn new(path: usize, device: &str, alarm_percent: f64) -> Self {
+        PcapStatsMonitor {
+            stats: PcapStats {
+                path,
+                device: device.to_string(),
+                received: 0,
+                dropped: 0,
+                if_dropped: 0,
+                drop_percent: 0.0,
+                alarm: false,
+                timestamp: 0,
+            },
+            alarm_percent,
+            last_read: None,
+        }
+    }
+
+    // A new capture handle of the device, its counters start from zero
+    pub fn restart(&mut self) {
+        self.stats.received = 0;
+        self.stats.dropped = 0;
+        self.stats.if_dropped = 0;
+        self.last_read = None;
+    }
+
+    // Whether the counters are due to be read
+    pub fn due(&self) -> bool {
+        self.last_read
+            .is_none_or(|last| last.elapsed() >= STATS_INTERVAL)
+    }
+
+    // Publish the counters of the handle, alerting when the drops start or stop
+    pub fn update(&mut self, stat: &pcap::Stat) {
+        self.last_read = Some(Instant::now());
+        // the handle's counters are 32 bit and wrap
+        let received = stat.received.wrapping_sub(self.stats.received as u32) as u64;
+        let dropped = stat.dropped.wrapping_sub(self.stats.dropped as u32) as u64;
+        let if_dropped = stat.if_dropped.wrapping_sub(self.stats.if_dropped as u32) as u64;
+        // received counts the kernel drops on Linux, not the ones of the interface
+        let lost = dropped + if_dropped;
+        let offered = received.max(dropped) + if_dropped;
+        let drop_percent = if offered == 0 {
+            0.0
+        } else {
+            lost as f64 * 100.0 / offered as f64
+        };
+
+        self.stats.received = stat.received as u64;
+        self.stats.dropped = stat.dropped as u64;
+        self.stats.if_dropped = stat.if_dropped as u64;
+        self.stats.drop_percent = drop_percent;
+        self.stats.timestamp = current_unix_timestamp_ms().unwrap_or(0);
+
+        let alarm = self.alarm_percent > 0.0 && lost > 0 && drop_percent >= self.alarm_percent;
+        if alarm && !self.stats.alarm {
+            warn!(
+                "Capture on {} is dropping {:.2}% of the packets, {} by the kernel and {} by the interface",
+                self.stats.device, drop_percent, dropped, if_dropped
+            );
+            self.stats.alarm = true;
+            log_event("analyzer", "pcap_drops", json!(self.stats));
+        } else if !alarm && self.stats.alarm {
+            info!("Capture on {} stopped dropping packets", self.stats.device);
+            self.stats.alarm = false;
+            log_event("analyzer", "pcap_drops_cleared", json!(self.stats));
+        }
+        publish(&self.stats);
+    }
+}
@@ -0,0 +1,164 @@
+/*
+ * pcap_writer.rs
+ * --------------
+ * Write the captured packets back to pcap files for Wireshark or to share an interval with a
+ * vendor. Files rotate by size or age, each rotated file is named with its sequence number and
+ * start time and the oldest are removed past --write-pcap-max-files.
+*/
+
+use crate::args::Args;
+use log::{error, info};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4; // microsecond timestamps
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+pub struct PcapWriter {
+    path: PathBuf,
+    rotate_bytes: u64,
+    rotate_interval: Duration,
+    max_files: usize,
+    file: Option<BufWriter<File>>,
+    written: u64,
+    opened_at: Instant,
+    sequence: usize,
+    files: VecDeque<PathBuf>,
+}
+
+impl PcapWriter {
+    pub fn new(path: &str, rotate_mb: u64, rotate_secs: u64, max_files: usize) -> Self {
+        PcapWriter {
+            path: PathBuf::from(path),
+            rotate_bytes: rotate_mb * 1024 * 1024,
+            rotate_interval: Duration::from_secs(rotate_secs),
+            max_files,
+            file: None,
+            written: 0,
+            opened_at: Instant::now(),
+            sequence: 0,
+            files: VecDeque::new(),
+        }
+    }
+
+    // Writer for --write-pcap, None when it is not set
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.write_pcap.is_empty() {
+            return None;
+        }
+        Some(PcapWriter::new(
+            &args.write_pcap,
+            args.write_pcap_rotate_mb,
+            args.write_pcap_rotate_secs,
+            args.write_pcap_max_files,
+        ))
+    }
+
+    fn rotates(&self) -> bool {
+        self.rotate_bytes > 0 || !self.rotate_interval.is_zero()
+    }
+
+    // The path itself without rotation, else out_0001_20240301-120000.pcap
+    fn next_path(&mut self) -> PathBuf {
+        if !self.rotates() {
+            return self.path.clone();
+        }
+        self.sequence += 1;
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "capture".to_string());
+        let extension = self
+            .path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_else(|| "pcap".to_string());
+        let name = format!(
+            "{}_{:04}_{}.{}",
+            stem,
+            self.sequence,
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            extension
+        );
+        self.path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(name)
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        let path = self.next_path();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = BufWriter::new(File::create(&path)?);
+        file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?; // version 2.4
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&0i32.to_le_bytes())?; // GMT offset
+        file.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+        file.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        info!("Writing captured packets to {}", path.display());
+
+        self.file = Some(file);
+        self.written = 24;
+        self.opened_at = Instant::now();
+        self.files.push_back(path);
+        while self.max_files > 0 && self.files.len() > self.max_files {
+            if let Some(old) = self.files.pop_front() {
+                if let Err(e) = std::fs::remove_file(&old) {
+                    error!("Failed to remove old pcap file {}: {}", old.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> bool {
+        (self.rotate_bytes > 0 && self.written >= self.rotate_bytes)
+            || (!self.rotate_interval.is_zero() && self.opened_at.elapsed() >= self.rotate_interval)
+    }
+
+    // Append a packet with the current time as its timestamp
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.file.is_none() || self.needs_rotation() {
+            self.open()?;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured = data.len().min(PCAP_SNAPLEN as usize);
+        let file = self.file.as_mut().unwrap();
+        file.write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        file.write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        file.write_all(&(captured as u32).to_le_bytes())?;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(&data[..captured])?;
+        self.written += 16 + captured as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for PcapWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Failed to flush the pcap file: {}", e);
+        }
+    }
+}
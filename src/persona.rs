@@ -0,0 +1,141 @@
+/*
+ * persona.rs
+ * ----------
+ * Persona registry for switching the voice, system prompt, image prompt and greeting
+ * of the assistant at runtime, via CLI, the control API or the !persona chat command.
+*/
+
+use crate::args::Args;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+// A single persona, any empty field falls back to the command line arguments
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Persona {
+    pub name: String,
+    #[serde(default)]
+    pub system_prompt: String,
+    #[serde(default)]
+    pub voice: String,
+    #[serde(default)]
+    pub image_prompt: String,
+    #[serde(default)]
+    pub greeting: String,
+}
+
+impl Persona {
+    // Build a persona from the command line arguments
+    pub fn from_args(name: &str, args: &Args) -> Self {
+        Persona {
+            name: name.to_string(),
+            system_prompt: args.system_prompt.clone(),
+            voice: args.mimic3_voice.clone(),
+            image_prompt: args.assistant_image_prompt.clone(),
+            greeting: args.greeting.clone(),
+        }
+    }
+
+    // fill in any missing fields from the defaults
+    fn with_defaults(mut self, defaults: &Persona) -> Self {
+        if self.system_prompt.is_empty() {
+            self.system_prompt = defaults.system_prompt.clone();
+        }
+        if self.voice.is_empty() {
+            self.voice = defaults.voice.clone();
+        }
+        if self.image_prompt.is_empty() {
+            self.image_prompt = defaults.image_prompt.clone();
+        }
+        if self.greeting.is_empty() {
+            self.greeting = defaults.greeting.clone();
+        }
+        self
+    }
+}
+
+pub struct PersonaRegistry {
+    personas: Vec<Persona>,
+    current: usize,
+}
+
+impl PersonaRegistry {
+    // Load the registry, the "default" persona is always built from the command line arguments
+    pub fn new(args: &Args) -> Result<Self> {
+        let default_persona = Persona::from_args("default", args);
+        let mut personas = vec![default_persona.clone()];
+
+        if !args.personas_file.is_empty() {
+            let contents = std::fs::read_to_string(&args.personas_file).map_err(|e| {
+                anyhow!("Failed to read personas file {}: {}", args.personas_file, e)
+            })?;
+            let loaded: Vec<Persona> = serde_json::from_str(&contents).map_err(|e| {
+                anyhow!(
+                    "Failed to parse personas file {}: {}",
+                    args.personas_file,
+                    e
+                )
+            })?;
+            for persona in loaded {
+                if persona.name.is_empty() {
+                    return Err(anyhow!(
+                        "Persona in {} is missing a name",
+                        args.personas_file
+                    ));
+                }
+                let persona = persona.with_defaults(&default_persona);
+                // a persona named default replaces the command line one
+                match personas
+                    .iter()
+                    .position(|p| p.name.eq_ignore_ascii_case(&persona.name))
+                {
+                    Some(index) => personas[index] = persona,
+                    None => personas.push(persona),
+                }
+            }
+        }
+
+        let mut registry = PersonaRegistry {
+            personas,
+            current: 0,
+        };
+
+        if !args.persona.is_empty() && registry.select(&args.persona).is_none() {
+            return Err(anyhow!(
+                "Persona {} not found, available personas: {}",
+                args.persona,
+                registry.names().join(", ")
+            ));
+        }
+
+        Ok(registry)
+    }
+
+    pub fn current(&self) -> &Persona {
+        &self.personas[self.current]
+    }
+
+    // Switch to the persona by name (case insensitive), returns None if it does not exist
+    pub fn select(&mut self, name: &str) -> Option<&Persona> {
+        let name = name.trim();
+        let index = self
+            .personas
+            .iter()
+            .position(|p| p.name.eq_ignore_ascii_case(name))?;
+        self.current = index;
+        Some(&self.personas[index])
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.personas.iter().map(|p| p.name.clone()).collect()
+    }
+}
+
+// Parse a "!persona <name>" command, returns the requested persona name
+pub fn parse_persona_command(command: &str) -> Option<&str> {
+    let name = command.strip_prefix("!persona")?.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
@@ -1,27 +1,62 @@
 /*
-    Image and Speech generation pipeline for NDI output
+    Image and Speech generation pipeline for the output sinks
 */
 use crate::adjust_caps;
 use crate::args::Args;
-#[cfg(feature = "ndi")]
-use crate::audio::{mp3_to_f32, wav_to_f32};
+use crate::audio::{decode_audio, f32_to_wav, post_process_speech, AudioPostOptions};
 #[cfg(feature = "metavoice")]
-use crate::candle_metavoice::metavoice;
+use crate::candle_metavoice::{metavoice, MetaVoiceOptions};
+use crate::chapters::Chapter;
+use crate::character_sheet::{character_sheet, CharacterSheet};
+use crate::emotion::{classify_emotion, classify_sentiment, detect_emotion, Emotion, Sentiment};
+use crate::event_log::log_event;
+use crate::image_generator::{image_backend_name, image_generator};
+use crate::image_relevance::{rewrite_prompt, score_images};
+use crate::keywords::{emphasize_prompt, extract as extract_keywords, Keywords};
+use crate::language::{route_voice, VoiceRoute};
 use crate::mimic3_tts::tts as mimic3_tts;
 use crate::mimic3_tts::Request as Mimic3TTSRequest;
-#[cfg(feature = "ndi")]
-use crate::ndi::send_audio_samples_over_ndi;
-#[cfg(feature = "ndi")]
-use crate::ndi::send_images_over_ndi;
 use crate::openai_tts::tts as oai_tts;
 use crate::openai_tts::Request as OAITTSRequest;
+use crate::openai_tts::ResponseFormat as OAITTSResponseFormat;
 use crate::openai_tts::Voice as OAITTSVoice;
-use crate::sd_automatic::sd_auto;
-use crate::stable_diffusion::{sd, SDConfig};
-use crate::ApiError;
+use crate::output::output_audio;
+use crate::safety_checker::{filter_images, SafetyAction};
+use crate::secrets::secret;
+use crate::session_stats::{record_images, record_tts};
+use crate::stable_diffusion::{SDConfig, CLIP_MAX_TOKENS};
+use crate::tts_text::{clean_text, TtsTextOptions};
+use crate::{count_tokens, current_unix_timestamp_ms, truncate_tokens, ApiError};
 use image::ImageBuffer;
 use image::Rgb;
 use log::debug;
+use serde_json::json;
+use std::io::Write;
+
+// Pipeline priority, high priority messages skip ahead of the queued paragraphs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+// Images of a paragraph with the relevance score they got
+type ScoredImages = (f32, Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>);
+
+// Silence before each paragraph of audio on the output
+pub const AUDIO_LEAD_SILENCE_MS: u64 = 3000;
+
+// Sample rate of the TTS audio when it can't be read from the audio data
+pub fn tts_default_sample_rate(args: &Args) -> u32 {
+    if args.mimic3_tts {
+        22050
+    } else if args.metavoice_tts && args.metavoice_sample_rate > 0 {
+        args.metavoice_sample_rate
+    } else {
+        24000
+    }
+}
 
 // Message Data for Image and Speech generation functions to use
 #[derive(Clone)]
@@ -35,24 +70,268 @@ pub struct MessageData {
     pub args: Args,
     pub shutdown: bool,
     pub last_message: bool,
+    pub emotion: Option<Emotion>,
+    pub keywords: Option<Keywords>,
+    pub sentiment: Option<Sentiment>,
+    pub priority: Priority,
+    pub generation: u64,              // cancel generation the message was sent in
+    pub requested_by: Option<String>, // viewer who requested the image
+}
+
+// Detect the emotion of the paragraph, strip the tags and add the mood to the image prompt
+pub fn apply_emotion(data: &mut MessageData) {
+    if !data.args.emotion {
+        return;
+    }
+
+    let (emotion, paragraph) = detect_emotion(&data.paragraph);
+    let (_, prompt) = detect_emotion(&data.sd_config.prompt);
+    data.paragraph = paragraph;
+    data.sd_config.prompt = prompt;
+
+    let modifier = emotion.sd_modifier();
+    if !modifier.is_empty() {
+        data.sd_config.prompt = format!("{}, {}", data.sd_config.prompt, modifier);
+    }
+
+    debug!(
+        "Paragraph {} emotion: {}",
+        data.paragraph_count,
+        emotion.to_string()
+    );
+    data.emotion = Some(emotion);
+}
+
+// Classify the sentiment of the paragraph for its speaking rate and music intensity
+pub fn apply_pacing(data: &mut MessageData) {
+    if !data.args.pacing {
+        return;
+    }
+
+    let emotion = data
+        .emotion
+        .unwrap_or_else(|| classify_emotion(&data.paragraph));
+    let sentiment = classify_sentiment(&data.paragraph, emotion);
+    debug!(
+        "Paragraph {} sentiment: valence {:.2} arousal {:.2}",
+        data.paragraph_count, sentiment.valence, sentiment.arousal
+    );
+    data.sentiment = Some(sentiment);
+}
+
+// Extract the keywords and entities of the paragraph and lead the image prompt with the entities
+pub fn apply_keywords(data: &mut MessageData) {
+    if !data.args.keywords {
+        return;
+    }
+
+    let keywords = extract_keywords(&data.paragraph, &data.args);
+    data.sd_config.prompt = emphasize_prompt(&data.sd_config.prompt, &keywords);
+
+    debug!(
+        "Paragraph {} keywords: {:?} entities: {:?}",
+        data.paragraph_count, keywords.keywords, keywords.entities
+    );
+    data.keywords = Some(keywords);
+}
+
+// New seed so a regenerated image is different, following a set seed so a recorded run
+// regenerates the same way
+fn next_seed(seed: Option<i32>) -> Option<i32> {
+    Some(match seed {
+        Some(seed) if seed >= 0 => seed.wrapping_add(1) & i32::MAX,
+        _ => rand::random::<i32>().abs(),
+    })
+}
+
+// The scene cut to --sd-text-min tokens, and for candle to the tokens left by the sheet of
+// the CLIP_MAX_TOKENS it keeps, so the appearance and style tokens after it are not cut off
+fn fit_scene(sheet: &CharacterSheet, scene: &str, paragraph: &str, args: &Args) -> String {
+    let candle = image_backend_name(args) == "candle";
+    let mut max_tokens = args.sd_text_min;
+    if candle {
+        let sheet_tokens = count_tokens(&sheet.prompt("", paragraph, &args.character_outfit));
+        if sheet_tokens >= CLIP_MAX_TOKENS {
+            log::warn!(
+                "Character sheet takes {} tokens, candle keeps {} of the prompt",
+                sheet_tokens,
+                CLIP_MAX_TOKENS
+            );
+        }
+        max_tokens = max_tokens.min(CLIP_MAX_TOKENS.saturating_sub(sheet_tokens));
+    }
+    let mut scene = truncate_tokens(scene, max_tokens);
+    if candle {
+        // the comma joining the scene to the sheet can make a token more
+        let mut kept = count_tokens(&scene);
+        while kept > 0
+            && count_tokens(&sheet.prompt(&scene, paragraph, &args.character_outfit))
+                > CLIP_MAX_TOKENS
+        {
+            kept -= 1;
+            scene = truncate_tokens(&scene, kept);
+        }
+    }
+    scene
 }
 
 // Function to process image generation
 pub async fn process_image(mut data: MessageData) -> Vec<ImageBuffer<Rgb<u8>, Vec<u8>>> {
-    // truncate tokens for sd_config.prompt
-    data.sd_config.prompt = crate::truncate_tokens(&data.sd_config.prompt, data.args.sd_text_min);
+    // truncate tokens of the scene, the character sheet goes around it after so the
+    // description of the host is never cut off
+    let sheet = character_sheet();
+    let mut scene = fit_scene(&sheet, &data.sd_config.prompt, &data.paragraph, &data.args);
+    data.sd_config.prompt = sheet.prompt(&scene, &data.paragraph, &data.args.character_outfit);
+    data.sd_config.uncond_prompt = sheet.negative_prompt(&data.sd_config.uncond_prompt);
     if data.args.sd_image {
         debug!("Generating images with prompt: {}", data.sd_config.prompt);
 
-        let images = if data.args.sd_api {
-            sd_auto(data.sd_config).await
+        let nsfw_action = if data.args.nsfw_filter {
+            match data.args.nsfw_action.parse::<SafetyAction>() {
+                Ok(action) => Some(action),
+                Err(e) => {
+                    log::error!("{}, skipping unsafe images", e);
+                    Some(SafetyAction::Skip)
+                }
+            }
         } else {
-            sd(data.sd_config).await
+            None
+        };
+
+        let generator = match image_generator(&data.args) {
+            Ok(generator) => generator,
+            Err(e) => {
+                log::error!("{}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut attempt = 0;
+        let mut relevance_attempt = 0;
+        let mut best_relevance: Option<ScoredImages> = None;
+        let images = loop {
+            let images = match generator.generate(data.sd_config.clone()).await {
+                Ok(images) => images,
+                Err(e) => break Err(e),
+            };
+
+            // Check the images for unsafe content before they are saved or sent out
+            let images = match nsfw_action {
+                Some(action) => {
+                    // CLIP runs on the blocking pool so the runtime keeps serving the other tasks
+                    let count = images.len();
+                    let threshold = data.args.nsfw_threshold;
+                    let cpu = data.sd_config.cpu;
+                    let (images, flagged) = match tokio::task::spawn_blocking(move || {
+                        filter_images(images, action, threshold, cpu)
+                    })
+                    .await
+                    {
+                        Ok(filtered) => filtered,
+                        Err(e) => {
+                            log::error!("Safety checker failed, dropping images: {}", e);
+                            (Vec::new(), count)
+                        }
+                    };
+                    if flagged > 0
+                        && action == SafetyAction::Regenerate
+                        && attempt < data.args.nsfw_retries
+                    {
+                        attempt += 1;
+                        log::warn!(
+                            "Regenerating unsafe image for {} paragraph {} attempt {}/{}",
+                            data.output_id,
+                            data.paragraph_count,
+                            attempt,
+                            data.args.nsfw_retries
+                        );
+                        data.sd_config.seed = next_seed(data.sd_config.seed);
+                        continue;
+                    }
+                    images
+                }
+                None => images,
+            };
+
+            // Score how well the images match the paragraph, a low score gets another try
+            // with the rewritten prompt and the best scoring images are kept
+            if data.args.image_relevance_threshold <= 0.0 || images.is_empty() {
+                break Ok(images);
+            }
+            let scored_images = images.clone();
+            let paragraph = data.paragraph.clone();
+            let cpu = data.sd_config.cpu;
+            let score = match tokio::task::spawn_blocking(move || {
+                score_images(&scored_images, &paragraph, cpu)
+            })
+            .await
+            {
+                Ok(Ok(scores)) => scores.into_iter().fold(f32::MIN, f32::max),
+                // keep the best images of the earlier attempts when the scoring fails on a retry
+                Ok(Err(e)) => {
+                    log::error!("Image relevance scoring failed: {}", e);
+                    break Ok(best_relevance.map(|(_, images)| images).unwrap_or(images));
+                }
+                Err(e) => {
+                    log::error!("Image relevance scoring failed: {}", e);
+                    break Ok(best_relevance.map(|(_, images)| images).unwrap_or(images));
+                }
+            };
+            let relevant = score >= data.args.image_relevance_threshold;
+            log_event(
+                "pipeline",
+                "image_relevance",
+                json!({
+                    "output_id": data.output_id,
+                    "paragraph_count": data.paragraph_count,
+                    "attempt": relevance_attempt,
+                    "score": score,
+                    "threshold": data.args.image_relevance_threshold,
+                    "relevant": relevant,
+                    "prompt": data.sd_config.prompt,
+                }),
+            );
+            debug!(
+                "Image relevance for {} paragraph {} is {:.3}",
+                data.output_id, data.paragraph_count, score
+            );
+            if best_relevance
+                .as_ref()
+                .is_none_or(|(best, _)| score > *best)
+            {
+                best_relevance = Some((score, images));
+            }
+            if relevant || relevance_attempt >= data.args.image_relevance_retries {
+                break Ok(best_relevance
+                    .take()
+                    .map(|(_, images)| images)
+                    .unwrap_or_default());
+            }
+            relevance_attempt += 1;
+            scene = fit_scene(
+                &sheet,
+                &rewrite_prompt(&scene, &data.paragraph, &data.args),
+                &data.paragraph,
+                &data.args,
+            );
+            data.sd_config.prompt =
+                sheet.prompt(&scene, &data.paragraph, &data.args.character_outfit);
+            log::warn!(
+                "Regenerating image scoring {:.3} for {} paragraph {} attempt {}/{} with prompt: {}",
+                score,
+                data.output_id,
+                data.paragraph_count,
+                relevance_attempt,
+                data.args.image_relevance_retries,
+                data.sd_config.prompt
+            );
+            data.sd_config.seed = next_seed(data.sd_config.seed);
         };
 
         match images {
             // Ensure `sd` function is async and await its result
             Ok(images) => {
+                record_images(images.len(), &image_backend_name(&data.args));
                 // Save images to disk
                 if data.args.save_images {
                     for (index, image_bytes) in images.iter().enumerate() {
@@ -65,9 +344,19 @@ pub async fn process_image(mut data: MessageData) -> Vec<ImageBuffer<Rgb<u8>, Ve
                             data.output_id, data.paragraph_count, index, image_file
                         );
                         image_bytes
-                            .save(image_file)
+                            .save(&image_file)
                             .map_err(candle_core::Error::wrap)
                             .unwrap(); // And this as well
+                        log_event(
+                            "pipeline",
+                            "media",
+                            json!({
+                                "kind": "image",
+                                "output_id": data.output_id,
+                                "paragraph_count": data.paragraph_count,
+                                "path": image_file,
+                            }),
+                        );
                     }
                 }
                 return images.clone();
@@ -87,6 +376,16 @@ pub async fn process_image(mut data: MessageData) -> Vec<ImageBuffer<Rgb<u8>, Ve
 pub async fn process_speech(data: MessageData) -> Vec<u8> {
     if data.args.mimic3_tts || data.args.oai_tts || data.args.tts_enable || data.args.metavoice_tts
     {
+        // prosody from the paragraph emotion
+        let mut prosody = data.emotion.map(|emotion| emotion.prosody());
+        // the sentiment pacing speeds up or slows down the emotion prosody
+        if let Some(sentiment) = data.sentiment {
+            let rate = sentiment.speaking_rate(data.args.pacing_rate_range);
+            let prosody = prosody.get_or_insert_with(|| Emotion::Neutral.prosody());
+            prosody.speed *= rate;
+            prosody.length_scale /= rate;
+        }
+
         let input = data.paragraph.clone(); // Ensure this uses the appropriate text for TTS
 
         // use function to adjust caps pub fn adjust_caps(paragraph: &str) -> String {
@@ -94,19 +393,112 @@ pub async fn process_speech(data: MessageData) -> Vec<u8> {
 
         debug!("\nTTS Speech text input: {}", input);
 
-        let bytes_result = if data.args.oai_tts {
-            // OpenAI TTS request
-            let model = String::from("tts-1");
-            let voice = OAITTSVoice::Nova;
-            let oai_request = OAITTSRequest::new(model, input, voice);
+        // route the speech to a voice for the detected language
+        let mut mimic3_voice = data.mimic3_voice.clone();
+        let mut oai_voice = OAITTSVoice::from_name(&data.args.oai_tts_voice).unwrap_or_else(|| {
+            log::error!(
+                "Invalid OpenAI TTS voice {}, using nova",
+                data.args.oai_tts_voice
+            );
+            OAITTSVoice::Nova
+        });
+        let mut use_oai_tts = data.args.oai_tts;
+        let mimic3_enabled = data.args.mimic3_tts || data.args.tts_enable;
+        if !data.args.tts_voice_map.is_empty() {
+            match route_voice(
+                &input,
+                &data.args.tts_voice_map,
+                &data.args.tts_default_language,
+            ) {
+                Some(VoiceRoute::Mimic3(voice)) if mimic3_enabled => {
+                    mimic3_voice = voice;
+                    use_oai_tts = false;
+                }
+                // without Mimic3 the paragraph stays on the current backend
+                Some(VoiceRoute::Mimic3(voice)) => {
+                    log::warn!(
+                        "TTS voice map routes to Mimic3 voice {} but Mimic3 TTS is off",
+                        voice
+                    );
+                }
+                Some(VoiceRoute::OpenAI(voice)) if data.args.oai_tts => {
+                    oai_voice = OAITTSVoice::from_name(&voice).unwrap_or_else(|| {
+                        log::error!("Invalid OpenAI TTS voice {}, using nova", voice);
+                        OAITTSVoice::Nova
+                    });
+                    use_oai_tts = true;
+                }
+                // without OpenAI TTS the paragraph stays on the current backend
+                Some(VoiceRoute::OpenAI(voice)) => {
+                    log::warn!(
+                        "TTS voice map routes to OpenAI voice {} but OpenAI TTS is off",
+                        voice
+                    );
+                }
+                None => {}
+            }
+        }
 
-            let openai_key =
-                std::env::var("OPENAI_API_KEY").expect("TTS Thread: OPENAI_API_KEY not found");
+        // without an API key the paragraph falls back to the other backends
+        let openai_key = if use_oai_tts {
+            let openai_key = secret("OPENAI_API_KEY");
+            if openai_key.is_none() {
+                log::error!("TTS: OPENAI_API_KEY not found, not using OpenAI TTS");
+                use_oai_tts = false;
+            }
+            openai_key
+        } else {
+            None
+        };
+
+        // keep the characters the backend speaking the paragraph can pronounce
+        let backend = if use_oai_tts {
+            "openai"
+        } else if mimic3_enabled {
+            "mimic3"
+        } else {
+            "metavoice"
+        };
+        let input = clean_text(&input, &TtsTextOptions::from_args(&data.args, backend));
+        if input.is_empty() {
+            debug!(
+                "No speakable text left in paragraph {}",
+                data.paragraph_count
+            );
+            return Vec::new();
+        }
+        record_tts(input.chars().count(), use_oai_tts);
+
+        let bytes_result = if let Some(openai_key) = openai_key {
+            // OpenAI TTS request
+            let model = data.args.oai_tts_model.clone();
+            let voice = oai_voice;
+            let response_format = OAITTSResponseFormat::from_name(&data.args.oai_tts_format)
+                .unwrap_or_else(|| {
+                    log::error!(
+                        "Invalid OpenAI TTS response format {}, using mp3",
+                        data.args.oai_tts_format
+                    );
+                    OAITTSResponseFormat::Mp3
+                });
+            let mut oai_request =
+                OAITTSRequest::new(model, input, voice).with_response_format(response_format);
+            // the emotion prosody scales the configured speed
+            let speed = data.args.oai_tts_speed * prosody.map_or(1.0, |prosody| prosody.speed);
+            if speed != 1.0 {
+                oai_request = oai_request.with_speed(speed);
+            }
 
             // Directly await the TTS operation without spawning a new thread
-            oai_tts(oai_request, &openai_key).await
-        } else if data.args.mimic3_tts || data.args.tts_enable {
-            let api_request = Mimic3TTSRequest::new(input, data.mimic3_voice);
+            oai_tts(oai_request, openai_key.expose(), data.args.oai_tts_retries).await
+        } else if mimic3_enabled {
+            let mut api_request = Mimic3TTSRequest::new(input, mimic3_voice);
+            if let Some(prosody) = prosody {
+                api_request = api_request
+                    .length_scale(prosody.length_scale)
+                    .noise_scale(prosody.noise_scale)
+                    .noise_w(prosody.noise_w);
+            }
             // Mimic3 TTS request
             mimic3_tts(api_request)
                 .await
@@ -115,8 +507,8 @@ pub async fn process_speech(data: MessageData) -> Vec<u8> {
             // Candle TTS request
             #[cfg(feature = "metavoice")]
             {
-                match metavoice(input).await {
-                    Ok(bytes) => return bytes.to_vec(),
+                match metavoice(input, &MetaVoiceOptions::from_args(&data.args)).await {
+                    Ok(bytes) => return finish_speech(bytes.to_vec(), &data),
                     Err(e) => {
                         eprintln!("Metavoice TTS error: {}", e);
                         return Vec::new(); // Return an empty Vec<u8> in case of an error
@@ -135,8 +527,8 @@ pub async fn process_speech(data: MessageData) -> Vec<u8> {
 
         match bytes_result {
             Ok(bytes) => {
-                if data.args.ndi_audio {
-                    return bytes.to_vec();
+                if output_audio(&data.args) {
+                    return finish_speech(bytes.to_vec(), &data);
                 } else {
                     // Example code to play audio directly, replace with your actual audio playback logic
                     // TODO: Split out into the audio crate
@@ -161,6 +553,74 @@ pub async fn process_speech(data: MessageData) -> Vec<u8> {
     Vec::new()
 }
 
+// Trim the silence and fade the ends of the speech before it is timed and output
+fn finish_speech(audio_data: Vec<u8>, data: &MessageData) -> Vec<u8> {
+    let audio_data = post_process_speech(
+        audio_data,
+        tts_default_sample_rate(&data.args),
+        &AudioPostOptions::from_args(&data.args),
+    );
+    if data.args.save_audio {
+        if let Err(e) = save_speech(&audio_data, data) {
+            log::error!(
+                "Error saving audio for {} {}: {}",
+                data.output_id,
+                data.paragraph_count,
+                e
+            );
+        }
+    }
+    audio_data
+}
+
+// Save the speech as a WAV file and add it to the manifest with its text
+fn save_speech(audio_data: &[u8], data: &MessageData) -> std::io::Result<()> {
+    let (samples, sample_rate) =
+        decode_audio(audio_data.to_vec(), tts_default_sample_rate(&data.args))?;
+    if samples.is_empty() {
+        return Ok(());
+    }
+    let directory = std::path::Path::new(&data.args.save_audio_dir);
+    std::fs::create_dir_all(directory)?;
+    let file_name = format!("{}_{}.wav", data.output_id, data.paragraph_count);
+    let audio_file = directory.join(&file_name);
+    std::fs::write(&audio_file, f32_to_wav(&samples, sample_rate)?)?;
+
+    let duration_ms = samples.len() as u64 * 1000 / sample_rate.max(1) as u64;
+    let entry = json!({
+        "file": file_name,
+        "output_id": data.output_id,
+        "paragraph_count": data.paragraph_count,
+        "text": data.paragraph,
+        "sample_rate": sample_rate,
+        "duration_ms": duration_ms,
+        "timestamp_ms": current_unix_timestamp_ms().unwrap_or(0),
+    });
+    let mut manifest = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(directory.join("manifest.jsonl"))?;
+    writeln!(manifest, "{}", entry)?;
+
+    debug!(
+        "Audio {} {} saved to {}",
+        data.output_id,
+        data.paragraph_count,
+        audio_file.display()
+    );
+    log_event(
+        "pipeline",
+        "media",
+        json!({
+            "kind": "audio",
+            "output_id": data.output_id,
+            "paragraph_count": data.paragraph_count,
+            "path": audio_file.display().to_string(),
+        }),
+    );
+    Ok(())
+}
+
 // Struct to hold the processed audio and image data
 #[derive(Clone)]
 pub struct ProcessedData {
@@ -169,90 +629,12 @@ pub struct ProcessedData {
     pub audio_data: Option<Vec<u8>>,
     pub paragraph_count: usize,
     pub subtitle_position: String,
-    pub time_stamp: u64,
+    pub time_stamp: u64,  // presentation time in ms since the output started
+    pub duration_ms: u64, // duration of the audio
     pub shutdown: bool,
     pub completed: bool,
     pub last_message: bool,
-}
-
-// Function to send audio/video pairs to NDI
-#[cfg(feature = "ndi")]
-pub async fn send_to_ndi(processed_data: ProcessedData, args: &Args) {
-    // check if args.subtitles is true, if so defined the processed_data.paragraph as a variable, if not have it be an empty string
-    let subtitle = if args.subtitles {
-        processed_data.paragraph
-    } else {
-        String::new()
-    };
-
-    if let Some(image_data) = processed_data.image_data {
-        if args.ndi_images {
-            {
-                debug!("Sending images over NDI");
-                send_images_over_ndi(
-                    image_data,
-                    &subtitle,
-                    args.hardsub_font_size,
-                    &processed_data.subtitle_position,
-                )
-                .unwrap();
-            }
-        }
-    }
-
-    if let Some(audio_data) = processed_data.audio_data {
-        if args.ndi_audio {
-            let samples_result = if args.oai_tts {
-                mp3_to_f32(audio_data)
-            } else {
-                wav_to_f32(audio_data)
-            };
-
-            if let Ok(mut samples_f32) = samples_result {
-                let sample_rate = if args.mimic3_tts { 22050 } else { 24000 };
-                let channels: i32 = 1;
-                let chunk_size = args.audio_chunk_size * sample_rate as f32 * channels as f32;
-
-                let delay_ms =
-                    (chunk_size as f32 / channels as f32 / sample_rate as f32 * 1000.0) as u64;
-
-                // Calculate the number of samples needed for 3 seconds of silence
-                let silence_duration = 3.0; // Duration of silence in seconds
-                let silence_samples = (silence_duration * sample_rate as f32) as usize;
-
-                // Create a vector of silent samples
-                let silence_vec = vec![0.0; silence_samples];
-
-                // Prepend the silence to the audio samples
-                samples_f32.splice(0..0, silence_vec.clone());
-
-                // make sure the last chunk is aligned to the chunk size
-                let last_chunk_size = samples_f32.len() as f32 % chunk_size;
-                let last_chunk_size = if last_chunk_size == 0.0 {
-                    chunk_size
-                } else {
-                    last_chunk_size
-                };
-                // Append silence to the last chunk to make it the same size as the other chunks
-                let silence_samples = (chunk_size - last_chunk_size) as usize;
-                let silence_vec = vec![0.0; silence_samples];
-                samples_f32.extend(silence_vec);
-
-                debug!(
-                    "Sending {} ms duration {} audio samples",
-                    delay_ms, chunk_size
-                );
-
-                for chunk_samples in samples_f32.chunks(chunk_size as usize) {
-                    let mut chunk_vec = chunk_samples.to_vec();
-                    if chunk_samples.len() < chunk_size as usize {
-                        chunk_vec.resize(chunk_size as usize, 0.0);
-                    }
-                    send_audio_samples_over_ndi(chunk_vec, sample_rate, channels)
-                        .expect("Failed to send audio samples over NDI");
-                    tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
-                }
-            }
-        }
-    }
+    pub chapter: Option<Chapter>,
+    pub sentiment: Option<Sentiment>, // picks the intensity of the music bed
+    pub requested_by: Option<String>, // viewer named in the lower third
 }
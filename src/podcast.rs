@@ -0,0 +1,407 @@
+/*
+ * podcast.rs
+ * ----------
+ * Audio only podcast mode. With --podcast no images are generated, the speech of the show
+ * goes to the podcast sink which encodes it to MP3 or Opus with ffmpeg into a file or a live
+ * stream like an icecast:// URL. A live stream is kept continuous with silence while the
+ * next answer is generated. The chapters detected in the transcript are written as a JSON
+ * chapters file next to the episode, at the position of the audio they start at.
+*/
+
+use crate::args::Args;
+use crate::output::{AudioFrame, OutputMetadata, OutputSink, VideoFrame};
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use serde_json::json;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// How often a live stream is topped up with silence
+const SILENCE_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PodcastFormat {
+    Mp3,
+    Opus,
+}
+
+impl std::str::FromStr for PodcastFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        match format.trim().to_lowercase().as_str() {
+            "mp3" => Ok(PodcastFormat::Mp3),
+            "opus" | "ogg" => Ok(PodcastFormat::Opus),
+            _ => Err(format!("Invalid podcast format {}", format)),
+        }
+    }
+}
+
+impl PodcastFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PodcastFormat::Mp3 => "mp3",
+            PodcastFormat::Opus => "opus",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PodcastFormat::Mp3 => "audio/mpeg",
+            PodcastFormat::Opus => "audio/ogg",
+        }
+    }
+
+    // ffmpeg encoder and container of the format
+    fn encoder_args(&self, bitrate_kbps: u32) -> Vec<String> {
+        let (codec, container) = match self {
+            PodcastFormat::Mp3 => ("libmp3lame", "mp3"),
+            PodcastFormat::Opus => ("libopus", "ogg"),
+        };
+        vec![
+            "-c:a".to_string(),
+            codec.to_string(),
+            "-b:a".to_string(),
+            format!("{}k", bitrate_kbps),
+            "-f".to_string(),
+            container.to_string(),
+        ]
+    }
+}
+
+// Target of an encoder whose output is read back from its stdout
+pub const ENCODER_PIPE: &str = "pipe:1";
+
+// Live targets are URLs or the pipe, anything else is a file
+fn is_stream(target: &str) -> bool {
+    target.contains("://") || target == ENCODER_PIPE
+}
+
+// Turn the images off and the podcast sink on, the chapters come from the transcript
+pub fn apply_podcast_mode(args: &mut Args) {
+    if !args.podcast {
+        return;
+    }
+    args.sd_image = false;
+    args.chapters = true;
+    let has_sink = args
+        .output_sinks
+        .split(',')
+        .any(|name| name.trim().eq_ignore_ascii_case("podcast"));
+    if !has_sink {
+        args.output_sinks = if args.output_sinks.trim().is_empty() {
+            "podcast".to_string()
+        } else {
+            format!("{},podcast", args.output_sinks)
+        };
+    }
+    info!(
+        "Podcast mode, images are off and the audio is encoded to {}.",
+        args.podcast_format
+    );
+}
+
+// Chapter of the episode at its position in the audio
+struct PodcastChapter {
+    start_secs: f64,
+    title: String,
+}
+
+// The ffmpeg process encoding the PCM it reads from stdin, fed by a thread of its own so the
+// output loop never waits on the encoder
+pub struct AudioEncoder {
+    child: Child,
+    sender: Option<Sender<Vec<f32>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AudioEncoder {
+    // Encode mono audio at the sample rate to the target, a file, a URL or ENCODER_PIPE
+    pub fn start(
+        ffmpeg: &str,
+        format: PodcastFormat,
+        bitrate_kbps: u32,
+        sample_rate: u32,
+        target: &str,
+        silence_written: Arc<AtomicU64>,
+    ) -> Result<Self> {
+        let mut command = Command::new(ffmpeg);
+        command
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(["-f", "f32le", "-ar", &sample_rate.to_string(), "-ac", "1"])
+            .args(["-i", "pipe:0"])
+            .args(format.encoder_args(bitrate_kbps));
+        if target.contains("://") {
+            command.args(["-content_type", format.content_type()]);
+        }
+        let stdout = if target == ENCODER_PIPE {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        };
+        let mut child = command
+            .arg(target)
+            .stdin(Stdio::piped())
+            .stdout(stdout)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start {}: {}", ffmpeg, e))?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("No stdin of the {} encoder", ffmpeg))?;
+
+        let (sender, receiver) = channel();
+        let fill_silence = is_stream(target);
+        let writer = std::thread::spawn(move || {
+            write_pcm(stdin, receiver, sample_rate, fill_silence, silence_written)
+        });
+        Ok(AudioEncoder {
+            child,
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    // The encoded audio of an ENCODER_PIPE target
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    pub fn send(&self, samples: Vec<f32>) -> Result<()> {
+        self.sender
+            .as_ref()
+            .ok_or_else(|| anyhow!("the encoder is closed"))?
+            .send(samples)
+            .map_err(|_| anyhow!("the encoder stopped"))
+    }
+
+    // Close stdin and wait for ffmpeg to write the end of the file
+    pub fn finish(&mut self) -> Result<()> {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("the encoder exited with {}", status));
+        }
+        Ok(())
+    }
+}
+
+// The encoders take mono, more channels are mixed down
+pub fn mono_samples(audio: &AudioFrame) -> Vec<f32> {
+    if audio.channels <= 1 {
+        return audio.samples.clone();
+    }
+    audio
+        .samples
+        .chunks(audio.channels as usize)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+fn write_samples(stdin: &mut ChildStdin, samples: &[f32]) -> std::io::Result<()> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    stdin.write_all(&bytes)
+}
+
+// Write the audio to the encoder until the sink closes, a live stream gets silence whenever
+// it falls behind the clock
+fn write_pcm(
+    mut stdin: ChildStdin,
+    receiver: Receiver<Vec<f32>>,
+    sample_rate: u32,
+    fill_silence: bool,
+    silence_written: Arc<AtomicU64>,
+) {
+    let started = Instant::now();
+    let mut written: u64 = 0;
+    loop {
+        let mut samples = match receiver.recv_timeout(SILENCE_INTERVAL) {
+            Ok(samples) => samples,
+            Err(RecvTimeoutError::Timeout) => Vec::new(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let due = (started.elapsed().as_secs_f64() * sample_rate as f64) as u64;
+        if fill_silence && samples.is_empty() && written < due {
+            samples = vec![0.0; (due - written) as usize];
+            silence_written.fetch_add(samples.len() as u64, Ordering::SeqCst);
+        }
+        if samples.is_empty() {
+            continue;
+        }
+        if let Err(e) = write_samples(&mut stdin, &samples) {
+            error!("Podcast encoder failed to take the audio: {}", e);
+            return;
+        }
+        written += samples.len() as u64;
+    }
+}
+
+// Encodes the speech of the show to a podcast file or stream, the video is not used
+pub struct PodcastSink {
+    ffmpeg: String,
+    format: PodcastFormat,
+    bitrate_kbps: u32,
+    target: String,
+    chapters_path: PathBuf,
+    chapters: Vec<PodcastChapter>,
+    encoder: Option<AudioEncoder>,
+    sample_rate: u32,
+    samples_sent: u64,
+    silence_written: Arc<AtomicU64>, // by the encoder thread of a live stream
+}
+
+impl PodcastSink {
+    pub fn new(
+        ffmpeg: &str,
+        format: PodcastFormat,
+        bitrate_kbps: u32,
+        target: &str,
+        chapters_path: PathBuf,
+    ) -> Self {
+        PodcastSink {
+            ffmpeg: ffmpeg.to_string(),
+            format,
+            bitrate_kbps,
+            target: target.to_string(),
+            chapters_path,
+            chapters: Vec::new(),
+            encoder: None,
+            sample_rate: 0,
+            samples_sent: 0,
+            silence_written: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // The episode goes to --podcast-output, a file in the output directory by default
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let format = args
+            .podcast_format
+            .parse::<PodcastFormat>()
+            .unwrap_or_else(|e| {
+                error!("{}, encoding to mp3", e);
+                PodcastFormat::Mp3
+            });
+        let output_dir = PathBuf::from(&args.output_dir);
+        std::fs::create_dir_all(&output_dir)?;
+        let target = if args.podcast_output.is_empty() {
+            output_dir
+                .join(format!("podcast.{}", format.extension()))
+                .to_string_lossy()
+                .to_string()
+        } else {
+            args.podcast_output.clone()
+        };
+        let chapters_path = if is_stream(&target) {
+            output_dir.join("podcast.chapters.json")
+        } else {
+            PathBuf::from(&target).with_extension("chapters.json")
+        };
+        Ok(PodcastSink::new(
+            &args.ffmpeg_path,
+            format,
+            args.podcast_bitrate,
+            &target,
+            chapters_path,
+        ))
+    }
+
+    // Seconds of audio in the episode so far
+    fn position_secs(&self) -> f64 {
+        if self.sample_rate == 0 {
+            return 0.0;
+        }
+        let samples = self.samples_sent + self.silence_written.load(Ordering::SeqCst);
+        samples as f64 / self.sample_rate as f64
+    }
+
+    // JSON chapters of the podcast namespace, rewritten with each chapter so a live stream
+    // has them as it goes
+    fn write_chapters(&self) -> Result<()> {
+        let chapters: Vec<_> = self
+            .chapters
+            .iter()
+            .map(|chapter| json!({ "startTime": chapter.start_secs, "title": chapter.title }))
+            .collect();
+        let contents = serde_json::to_string_pretty(&json!({
+            "version": "1.2.0",
+            "chapters": chapters,
+        }))?;
+        std::fs::write(&self.chapters_path, contents)?;
+        Ok(())
+    }
+}
+
+impl OutputSink for PodcastSink {
+    fn name(&self) -> &str {
+        "podcast"
+    }
+
+    fn send_video(&mut self, _frame: &VideoFrame) -> Result<()> {
+        Ok(())
+    }
+
+    fn send_audio(&mut self, audio: &AudioFrame) -> Result<()> {
+        if self.encoder.is_none() {
+            // the encoder is opened at the rate of the first audio, later audio has to match
+            self.sample_rate = audio.sample_rate;
+            self.encoder = Some(AudioEncoder::start(
+                &self.ffmpeg,
+                self.format,
+                self.bitrate_kbps,
+                audio.sample_rate,
+                &self.target,
+                Arc::clone(&self.silence_written),
+            )?);
+            info!(
+                "Podcast encoding {} at {} Hz to {}",
+                self.format.extension(),
+                audio.sample_rate,
+                self.target
+            );
+        } else if audio.sample_rate != self.sample_rate {
+            warn!(
+                "Podcast audio at {} Hz while the encoder runs at {} Hz, set --audio-sample-rate",
+                audio.sample_rate, self.sample_rate
+            );
+        }
+        let samples = mono_samples(audio);
+        if let Some(encoder) = self.encoder.as_ref() {
+            self.samples_sent += samples.len() as u64;
+            encoder.send(samples)?;
+        }
+        Ok(())
+    }
+
+    fn send_metadata(&mut self, metadata: &OutputMetadata) -> Result<()> {
+        let Some(chapter) = metadata.chapter.as_ref() else {
+            return Ok(());
+        };
+        self.chapters.push(PodcastChapter {
+            start_secs: self.position_secs(),
+            title: chapter.title.clone(),
+        });
+        self.write_chapters()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(mut encoder) = self.encoder.take() {
+            encoder.finish()?;
+            info!(
+                "Podcast of {:.1} seconds with {} chapters written to {}",
+                self.position_secs(),
+                self.chapters.len(),
+                self.target
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ * prefetch.rs
+ * -----------
+ * Speculative prefetch of the media closing an answer. The outro and the stinger that lead
+ * into the next topic are known before the answer is read, so their image and speech are
+ * rendered while the paragraphs before them are still playing. The pipeline takes the
+ * prefetched results over when the messages reach it, a guess that does not come true, like
+ * a persona switch in between, just goes unused.
+*/
+
+use crate::args::Args;
+use crate::image_queue::ImageQueue;
+use crate::pipeline::{apply_emotion, apply_keywords, apply_pacing, process_speech, MessageData};
+use crate::translate::translate_outputs;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// Subtitles and speech of a message
+type SpeechJob = Shared<BoxFuture<'static, (String, Vec<u8>)>>;
+
+// Prefetched speech waiting for its message, the oldest is dropped past this
+const PREFETCH_MAX: usize = 4;
+
+// Text and settings that make two messages speak the same
+fn speech_key(data: &MessageData) -> String {
+    format!(
+        "{}|{}|{:?}|{:?}",
+        data.paragraph, data.mimic3_voice, data.emotion, data.sentiment
+    )
+}
+
+// Translated subtitles and speech of the message, as the pipeline makes them
+async fn render_speech(data: MessageData) -> (String, Vec<u8>) {
+    let (speech_text, subtitle_text) = translate_outputs(&data.paragraph, &data.args).await;
+    let mut speech_message = data;
+    speech_message.paragraph = speech_text;
+    (subtitle_text, process_speech(speech_message).await)
+}
+
+pub struct Prefetcher {
+    enabled: bool,
+    image_queue: Arc<ImageQueue>,
+    speech: Mutex<VecDeque<(String, SpeechJob)>>,
+}
+
+impl Prefetcher {
+    pub fn new(enabled: bool, image_queue: Arc<ImageQueue>) -> Self {
+        Prefetcher {
+            enabled,
+            image_queue,
+            speech: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn from_args(args: &Args, image_queue: Arc<ImageQueue>) -> Self {
+        Prefetcher::new(args.prefetch, image_queue)
+    }
+
+    // Start the image and speech of a message that is coming up. The saved media files are
+    // named by the paragraph number the message does not have yet, so saving leaves it out.
+    pub fn prefetch(&self, mut data: MessageData) {
+        if !self.enabled {
+            return;
+        }
+        apply_emotion(&mut data);
+        apply_keywords(&mut data);
+        apply_pacing(&mut data);
+
+        if !data.args.save_images {
+            self.image_queue.prefetch(data.clone());
+        }
+        if data.args.save_audio {
+            return;
+        }
+        let key = speech_key(&data);
+        let mut speech = self.speech.lock().unwrap();
+        if speech.iter().any(|(k, _)| *k == key) {
+            return;
+        }
+        debug!("Prefetching the speech of {}", data.paragraph);
+        let job = render_speech(data).boxed().shared();
+        tokio::spawn(job.clone());
+        if speech.len() >= PREFETCH_MAX {
+            speech.pop_front();
+        }
+        speech.push_back((key, job));
+    }
+
+    // Subtitles and speech of the message, prefetched or rendered now
+    pub async fn speech(&self, data: MessageData) -> (String, Vec<u8>) {
+        let key = speech_key(&data);
+        let prefetched = {
+            let mut speech = self.speech.lock().unwrap();
+            speech
+                .iter()
+                .position(|(k, _)| *k == key)
+                .and_then(|index| speech.remove(index))
+        };
+        match prefetched {
+            Some((_, job)) => {
+                debug!(
+                    "Paragraph {} takes over the prefetched speech",
+                    data.paragraph_count
+                );
+                job.await
+            }
+            None => render_speech(data).await,
+        }
+    }
+}
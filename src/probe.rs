@@ -0,0 +1,483 @@
+/*
+ * probe.rs
+ * --------
+ * Per packet MPEG-TS and SMPTE 2110 analysis: PAT/PMT tracking, video PID and codec changes
+ * and the TR 101 290 checks. Shared by the AI network stats and the rsllm-probe binary.
+*/
+
+use crate::ad_break::{handle_splice_insert, parse_splice_insert, Scte35Sections};
+use crate::args::Args;
+use crate::audio_class::program_audio_class;
+use crate::capture_clock::{clock_synchronized, TimestampSource};
+use crate::capture_paths::CapturePaths;
+use crate::event_log::log_event;
+use crate::fec::{rtp_ts_offset, FecPayload, FecTracker};
+use crate::hexdump;
+use crate::loudness::program_loudness;
+use crate::network_capture::{capture_devices, CapturedPacket, NetworkCapture, PcapFilterMode};
+use crate::packet_headers::{udp_destination_port, udp_payload_offset, MAX_HEADER_LEN};
+use crate::pcap_stats::pcap_stats;
+use crate::pcap_writer::PcapWriter;
+use crate::stats_schema::{ProbeStats, STATS_SCHEMA_VERSION};
+use crate::stream_data::{
+    get_pid_map, identify_audio_pid, identify_scte35_pid, identify_video_pid,
+    is_mpegts_or_smpte2110, parse_and_store_pat, pid_map_snapshot, process_mpegts_packet,
+    process_packet, process_smpte2110_packet, program_summaries, update_pid_map, AudioCodec, Codec,
+    PmtInfo, StreamData, Tr101290Errors, Tr101290Thresholds, Tr101290Timing, PAT_PID,
+};
+use crate::{current_unix_timestamp_ms, get_system_stats};
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Capture configuration for the source in the args
+pub fn network_capture_config(args: &Args) -> NetworkCapture {
+    // calculate read size based on batch size and packet size
+    let read_size: i32 =
+        (args.packet_size as i32 * args.pcap_batch_size as i32) + MAX_HEADER_LEN as i32; // pcap read size
+
+    NetworkCapture {
+        running: Arc::new(AtomicBool::new(true)),
+        dpdk: false,
+        use_wireless: args.use_wireless,
+        promiscuous: args.promiscuous,
+        immediate_mode: args.immediate_mode,
+        source_protocol: Arc::new(args.source_protocol.to_string()),
+        source_devices: capture_devices(&args.source_device, &args.source_devices),
+        source_ip: Arc::new(args.source_ip.to_string()),
+        source_ssm: Arc::new(args.source_ssm.to_string()),
+        pcap_filter: Arc::new(args.pcap_filter.to_string()),
+        pcap_filter_mode: args
+            .pcap_filter_mode
+            .parse::<PcapFilterMode>()
+            .unwrap_or_else(|e| {
+                error!("{}, using or", e);
+                PcapFilterMode::Or
+            }),
+        source_port: args.source_port,
+        source_fec: args.source_fec,
+        read_time_out: 60_000,
+        read_size,
+        buffer_size: args.buffer_size,
+        pcap_stats: args.pcap_stats,
+        pcap_drop_alarm_percent: args.pcap_drop_alarm_percent,
+        debug_on: args.hexdump,
+        capture_task: None,
+        pcap_writer: PcapWriter::from_args(args),
+        timestamp_source: timestamp_source(args),
+    }
+}
+
+// Latest TR 101 290 counters of the running analyzer
+static TR101290_TOTALS: Lazy<Mutex<Option<Tr101290Errors>>> = Lazy::new(|| Mutex::new(None));
+
+// Periodic snapshots of the PID rates and TR 101 290 counters, oldest first
+static STATS_HISTORY: Lazy<Mutex<VecDeque<Value>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// Stats snapshots between the timestamps in ms
+pub fn stats_history(since_ms: u64, until_ms: u64) -> Vec<Value> {
+    STATS_HISTORY
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|snapshot| {
+            let timestamp = snapshot["timestamp"].as_u64().unwrap_or(0);
+            timestamp >= since_ms && timestamp <= until_ms
+        })
+        .cloned()
+        .collect()
+}
+
+// TR 101 290 counters for reports, None when no stream is analyzed
+pub fn tr101290_totals() -> Option<Tr101290Errors> {
+    TR101290_TOTALS.lock().unwrap().clone()
+}
+
+// TR 101 290 repetition limits from the args
+pub fn tr101290_thresholds(args: &Args) -> Tr101290Thresholds {
+    Tr101290Thresholds {
+        pat_interval_ms: args.tr101290_pat_interval_ms,
+        pmt_interval_ms: args.tr101290_pmt_interval_ms,
+        pcr_interval_ms: args.tr101290_pcr_interval_ms,
+        pts_interval_ms: args.tr101290_pts_interval_ms,
+    }
+}
+
+// Clock for the packet arrival times, the system clock when the arg is invalid
+pub fn timestamp_source(args: &Args) -> TimestampSource {
+    args.timestamp_source
+        .parse::<TimestampSource>()
+        .unwrap_or_else(|e| {
+            error!("{}, using the system clock", e);
+            TimestampSource::System
+        })
+}
+
+pub struct StreamAnalyzer {
+    packet_size: usize,
+    start_time: u64,
+    hexdump: bool,
+    show_tr101290: bool,
+    is_mpegts: bool,
+    pmt_info: PmtInfo,
+    video_pid: Option<u16>,
+    video_codec: Option<Codec>,
+    audio_pid: Option<u16>,
+    audio_codec: Option<AudioCodec>,
+    scte35_pid: Option<u16>,
+    scte35_sections: Scte35Sections,
+    ad_breaks: bool, // follow the splice inserts
+    ad_break_duration: Duration,
+    tr101290_timing: Tr101290Timing,
+    totals_published: bool,
+    history_interval: Duration,
+    history_size: usize,
+    last_history: Option<Instant>,
+    fec: Option<FecTracker>, // with --source-fec
+    paths: CapturePaths,
+    timestamp_source: TimestampSource,
+    pub tr101290_errors: Tr101290Errors,
+}
+
+impl StreamAnalyzer {
+    pub fn new(args: &Args, start_time: u64) -> Self {
+        StreamAnalyzer {
+            packet_size: args.packet_size,
+            start_time,
+            hexdump: args.hexdump,
+            show_tr101290: args.show_tr101290,
+            is_mpegts: true, // Default to true, update based on actual packet type
+            pmt_info: PmtInfo {
+                pid: 0xFFFF,
+                packet: Vec::new(),
+            },
+            video_pid: Some(0xFFFF),
+            video_codec: Some(Codec::NONE),
+            audio_pid: None,
+            audio_codec: None,
+            scte35_pid: None,
+            scte35_sections: Scte35Sections::new(),
+            ad_breaks: args.ad_breaks,
+            ad_break_duration: Duration::from_secs(args.ad_break_secs),
+            tr101290_timing: Tr101290Timing::new(tr101290_thresholds(args)),
+            totals_published: false,
+            history_interval: Duration::from_millis(args.stats_history_interval_ms),
+            history_size: args.stats_history_size,
+            last_history: None,
+            fec: args
+                .source_fec
+                .then(|| FecTracker::new(args.source_port as u16)),
+            paths: CapturePaths::from_args(args),
+            timestamp_source: timestamp_source(args),
+            tr101290_errors: Tr101290Errors::new(),
+        }
+    }
+
+    pub fn is_mpegts(&self) -> bool {
+        self.is_mpegts
+    }
+
+    pub fn video_pid(&self) -> Option<u16> {
+        self.video_pid
+    }
+
+    pub fn video_codec(&self) -> Option<&Codec> {
+        self.video_codec.as_ref()
+    }
+
+    pub fn audio_pid(&self) -> Option<u16> {
+        self.audio_pid
+    }
+
+    pub fn audio_codec(&self) -> Option<AudioCodec> {
+        self.audio_codec
+    }
+
+    // Snapshot of the stream, TR 101 290 errors and system stats for export
+    pub fn stats(&self, packets: u64) -> ProbeStats {
+        ProbeStats {
+            schema_version: STATS_SCHEMA_VERSION,
+            timestamp: current_unix_timestamp_ms().unwrap_or(0),
+            packets,
+            is_mpegts: self.is_mpegts,
+            video_pid: self.video_pid,
+            video_codec: self.video_codec.as_ref().map(|codec| codec.to_string()),
+            audio_pid: self.audio_pid,
+            audio_codec: self.audio_codec.map(|codec| codec.to_string()),
+            loudness: program_loudness(),
+            audio_class: program_audio_class(),
+            tr101290: self.tr101290_errors.clone(),
+            tr101290_thresholds: self.tr101290_timing.thresholds,
+            fec: self
+                .fec
+                .as_ref()
+                .map(|fec| fec.stats().clone())
+                .unwrap_or_default(),
+            paths: self.paths.stats().map(|paths| paths.to_vec()),
+            pcap: pcap_stats(),
+            timestamp_source: self.timestamp_source.to_string(),
+            clock_synchronized: clock_synchronized(),
+            pid_map: get_pid_map(),
+            programs: program_summaries(),
+            system: get_system_stats(),
+        }
+    }
+
+    // Analyze a captured packet, returns the stream data of each chunk without null packets
+    pub fn process(&mut self, captured: CapturedPacket) -> Vec<StreamData> {
+        // the standby paths of a redundant feed are only counted
+        if !self.paths.accept(&captured) {
+            return Vec::new();
+        }
+        let errors_before = self.tr101290_errors.clone();
+        let packet = captured.data;
+        let arrival_time_ns = captured.timestamp_ns;
+        // Walk the link/VLAN/MPLS/IP/UDP headers of this packet to the payload
+        let Some(mut payload_offset) = udp_payload_offset(&packet, captured.link_type) else {
+            debug!("Skipping a packet without a UDP payload");
+            return Vec::new();
+        };
+        // FEC is protection, not media, and MPEG-TS over RTP starts after the RTP header
+        match self.fec.as_mut() {
+            Some(fec) => {
+                let port = udp_destination_port(&packet, payload_offset);
+                match fec.process(port, &packet[payload_offset..]) {
+                    FecPayload::Protection => return Vec::new(),
+                    FecPayload::Media(rtp_header_len) => payload_offset += rtp_header_len,
+                }
+            }
+            None => payload_offset += rtp_ts_offset(&packet[payload_offset..]),
+        }
+        // Check if chunk is MPEG-TS or SMPTE 2110
+        let chunk_type = is_mpegts_or_smpte2110(&packet[payload_offset..]);
+        if chunk_type != 1 {
+            if chunk_type == 0 {
+                hexdump(&packet, 0, packet.len());
+                error!("Not MPEG-TS or SMPTE 2110");
+            }
+            self.is_mpegts = false;
+        }
+
+        let chunks = if self.is_mpegts {
+            process_mpegts_packet(
+                payload_offset,
+                packet,
+                self.packet_size,
+                self.start_time,
+                arrival_time_ns,
+            )
+        } else {
+            process_smpte2110_packet(
+                payload_offset,
+                packet,
+                self.packet_size,
+                self.start_time,
+                arrival_time_ns,
+                false,
+            )
+        };
+
+        let mut stream_datas = Vec::with_capacity(chunks.len());
+        for mut stream_data in chunks {
+            // check for null packets of the pid 8191 0x1FFF and skip them
+            if stream_data.pid >= 0x1FFF {
+                debug!("Skipping null packet");
+                self.tr101290_timing.count_null_packet();
+                continue;
+            }
+
+            if self.hexdump {
+                hexdump(
+                    &stream_data.packet,
+                    stream_data.packet_start,
+                    stream_data.packet_len,
+                );
+            }
+
+            if self.is_mpegts {
+                // Extract the necessary slice for PID extraction and parsing
+                let packet_chunk = stream_data.packet
+                    [stream_data.packet_start..stream_data.packet_start + stream_data.packet_len]
+                    .to_vec();
+                self.process_tables(stream_data.pid, &packet_chunk);
+                if self.scte35_pid == Some(stream_data.pid) {
+                    self.process_scte35(stream_data.pid, &packet_chunk);
+                }
+
+                // PAT, PMT, PCR and PTS repetition
+                self.tr101290_timing.check(
+                    &packet_chunk,
+                    self.pmt_info.pid,
+                    arrival_time_ns / 1_000_000,
+                    &mut self.tr101290_errors,
+                );
+            }
+
+            // Check for TR 101 290 errors
+            process_packet(
+                &mut stream_data,
+                &mut self.tr101290_errors,
+                self.is_mpegts,
+                self.pmt_info.pid,
+            );
+
+            stream_datas.push(stream_data);
+        }
+
+        if self.tr101290_errors != errors_before {
+            log_tr101290_event(&errors_before, &self.tr101290_errors);
+        }
+        if self.tr101290_errors != errors_before || !self.totals_published {
+            *TR101290_TOTALS.lock().unwrap() = Some(self.tr101290_errors.clone());
+            self.totals_published = true;
+        }
+        if self.history_size > 0 {
+            self.record_history();
+        }
+        stream_datas
+    }
+
+    // Snapshot the PID map for the stats questions every history interval
+    fn record_history(&mut self) {
+        if self
+            .last_history
+            .is_some_and(|last| last.elapsed() < self.history_interval)
+        {
+            return;
+        }
+        self.last_history = Some(Instant::now());
+
+        let snapshot = json!({
+            "timestamp": current_unix_timestamp_ms().unwrap_or(0),
+            "video_pid": self.video_pid,
+            "tr101290": self.tr101290_errors,
+            "pids": pid_map_snapshot(),
+            "programs": program_summaries(),
+        });
+        let mut history = STATS_HISTORY.lock().unwrap();
+        while history.len() >= self.history_size {
+            history.pop_front();
+        }
+        history.push_back(snapshot);
+    }
+
+    // Splice inserts of the SCTE-35 PID, for the stream events and the ad breaks
+    fn process_scte35(&mut self, pid: u16, packet_chunk: &[u8]) {
+        for section in self.scte35_sections.push(packet_chunk) {
+            let Some(insert) = parse_splice_insert(&section) else {
+                continue;
+            };
+            log_event(
+                "analyzer",
+                "scte35_splice_insert",
+                json!({ "pid": pid, "insert": insert }),
+            );
+            if self.ad_breaks {
+                handle_splice_insert(&insert, self.ad_break_duration);
+            }
+        }
+    }
+
+    // Handle PAT and PMT packets
+    fn process_tables(&mut self, pid: u16, packet_chunk: &[u8]) {
+        if pid == PAT_PID {
+            debug!("ProcessPacket: PAT packet detected with PID {}", pid);
+            self.pmt_info = parse_and_store_pat(packet_chunk);
+            // Print TR 101 290 errors
+            if self.show_tr101290 {
+                info!("STATUS::TR101290:ERRORS: {}", self.tr101290_errors);
+            }
+            return;
+        }
+
+        // Check if this is a PMT packet
+        if pid != self.pmt_info.pid {
+            return;
+        }
+        debug!("ProcessPacket: PMT packet detected with PID {}", pid);
+        // Update PID_MAP with new stream types
+        update_pid_map(packet_chunk, &self.pmt_info.packet);
+        // Identify the video PID (if not already identified)
+        if let Some((new_pid, new_codec)) = identify_video_pid(packet_chunk) {
+            if self.video_pid.is_none_or(|vp| vp != new_pid) {
+                info!(
+                    "STATUS::VIDEO_PID:CHANGE: to {}/{} from {}/{}",
+                    new_pid,
+                    new_codec.clone(),
+                    self.video_pid.unwrap_or(0xFFFF),
+                    self.video_codec.clone().unwrap_or(Codec::NONE)
+                );
+                log_event(
+                    "analyzer",
+                    "video_pid",
+                    json!({
+                        "pid": new_pid,
+                        "codec": new_codec.to_string(),
+                        "previous_pid": self.video_pid,
+                    }),
+                );
+                self.video_pid = Some(new_pid);
+                self.video_codec = Some(new_codec);
+            } else if self.video_codec != Some(new_codec.clone()) {
+                info!(
+                    "STATUS::VIDEO_CODEC:CHANGE: to {} from {}",
+                    new_codec,
+                    self.video_codec.clone().unwrap_or(Codec::NONE)
+                );
+                log_event(
+                    "analyzer",
+                    "video_codec",
+                    json!({
+                        "pid": new_pid,
+                        "codec": new_codec.to_string(),
+                        "previous_codec": self.video_codec.as_ref().map(|codec| codec.to_string()),
+                    }),
+                );
+                self.video_codec = Some(new_codec);
+            }
+        }
+        // and the audio PID of the program audio
+        if let Some((new_pid, new_codec)) = identify_audio_pid(packet_chunk) {
+            if self.audio_pid != Some(new_pid) || self.audio_codec != Some(new_codec) {
+                info!(
+                    "STATUS::AUDIO_PID:CHANGE: to {}/{} from {:?}",
+                    new_pid, new_codec, self.audio_pid
+                );
+                log_event(
+                    "analyzer",
+                    "audio_pid",
+                    json!({
+                        "pid": new_pid,
+                        "codec": new_codec.to_string(),
+                        "previous_pid": self.audio_pid,
+                    }),
+                );
+                self.audio_pid = Some(new_pid);
+                self.audio_codec = Some(new_codec);
+            }
+        }
+        // and the splice information for the ad breaks
+        let scte35_pid = identify_scte35_pid(packet_chunk);
+        if scte35_pid.is_some() && scte35_pid != self.scte35_pid {
+            info!("STATUS::SCTE35_PID:CHANGE: to {:?}", scte35_pid);
+            self.scte35_pid = scte35_pid;
+            self.scte35_sections = Scte35Sections::new();
+        }
+    }
+}
+
+// Log the TR 101 290 counters that went up with their new totals
+fn log_tr101290_event(before: &Tr101290Errors, after: &Tr101290Errors) {
+    let (Value::Object(before), Value::Object(after)) = (json!(before), json!(after)) else {
+        return;
+    };
+    let increased: serde_json::Map<String, Value> = after
+        .into_iter()
+        .filter(|(name, count)| before.get(name) != Some(count))
+        .collect();
+    log_event("analyzer", "tr101290", Value::Object(increased));
+}
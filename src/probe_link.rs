@@ -0,0 +1,465 @@
+/*
+ * probe_link.rs
+ * -------------
+ * Link of the distributed probes to a central rsllm. A probe at each site sends a report of
+ * its stream every poll interval, the probe stats and the PID map, as MessagePack frames over
+ * TCP to --probe-central. The central instance listens on --probe-listen, keeps the latest
+ * report of every site and hands them to the LLM and the control api, so a single GPU box
+ * watches the streams of all the sites. Each connection starts with a hello frame carrying
+ * --probe-token, the central instance drops the probes with another token and keeps at most
+ * --probe-max-sites sites.
+*/
+
+use crate::api_auth::token_digest;
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::event_log::log_event;
+use crate::external_apis::check_url;
+use crate::stats_schema::{ProbeStats, SiteStats};
+use crate::stream_data::{pid_map_streams, StreamData};
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use sysinfo::{System, SystemExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+// Raised when a report can no longer be read by the other side
+pub const PROBE_LINK_VERSION: u32 = 1;
+
+// Largest report frame accepted, a PID map of a few thousand streams fits well within
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// The latest report of each site
+static REMOTE_SITES: Lazy<Mutex<BTreeMap<String, RemoteSite>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+// A snapshot of the stream of a site
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeReport {
+    pub version: u32,
+    pub site: String,
+    pub stats: ProbeStats,
+    pub streams: Vec<StreamData>, // the PID map without the packets
+}
+
+impl ProbeReport {
+    pub fn new(site: &str, stats: ProbeStats) -> Self {
+        ProbeReport {
+            version: PROBE_LINK_VERSION,
+            site: site.to_string(),
+            stats,
+            streams: pid_map_streams(),
+        }
+    }
+
+    // MessagePack with the field names, so both sides can add fields
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(self).map_err(|e| anyhow!("Failed to encode the report: {}", e))
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let report: ProbeReport = rmp_serde::from_slice(frame)
+            .map_err(|e| anyhow!("Failed to decode the report: {}", e))?;
+        if report.version != PROBE_LINK_VERSION {
+            return Err(anyhow!(
+                "Report of {} is version {}, expected {}",
+                report.site,
+                report.version,
+                PROBE_LINK_VERSION
+            ));
+        }
+        Ok(report)
+    }
+}
+
+// First frame of a connection, the probe proves it belongs to the central instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeHello {
+    pub version: u32,
+    pub token: String,
+}
+
+impl ProbeHello {
+    pub fn new(token: &str) -> Self {
+        ProbeHello {
+            version: PROBE_LINK_VERSION,
+            token: token.to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(self).map_err(|e| anyhow!("Failed to encode the hello: {}", e))
+    }
+
+    // The hello of a probe with the token of the central instance, any token when it has none
+    pub fn check(frame: &[u8], token: &str) -> Result<()> {
+        let hello: ProbeHello = rmp_serde::from_slice(frame)
+            .map_err(|e| anyhow!("Failed to decode the hello: {}", e))?;
+        if hello.version != PROBE_LINK_VERSION {
+            return Err(anyhow!(
+                "Hello is version {}, expected {}",
+                hello.version,
+                PROBE_LINK_VERSION
+            ));
+        }
+        // compare the digests so the time taken says nothing about the token
+        if !token.is_empty() && token_digest(&hello.token) != token_digest(token) {
+            return Err(anyhow!("Probe sent a wrong token"));
+        }
+        Ok(())
+    }
+}
+
+// The latest report of a site at the central instance
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteSite {
+    pub site: String,
+    pub peer: String,
+    pub received: u64, // unix time in ms
+    pub reports: u64,
+    pub report: ProbeReport,
+}
+
+pub fn remote_sites() -> Vec<RemoteSite> {
+    REMOTE_SITES.lock().unwrap().values().cloned().collect()
+}
+
+pub fn remote_site(site: &str) -> Option<RemoteSite> {
+    REMOTE_SITES.lock().unwrap().get(site).cloned()
+}
+
+// The stats of every site, an empty list when no probe reports to this instance
+pub fn site_stats(stale_secs: u64) -> Vec<SiteStats> {
+    let now = current_unix_timestamp_ms().unwrap_or(0);
+    REMOTE_SITES
+        .lock()
+        .unwrap()
+        .values()
+        .map(|remote| {
+            let age_ms = now.saturating_sub(remote.received);
+            SiteStats {
+                site: remote.site.clone(),
+                age_ms,
+                stale: stale_secs > 0 && age_ms > stale_secs * 1000,
+                stats: remote.report.stats.clone(),
+            }
+        })
+        .collect()
+}
+
+// Keep the report of a site, false when it is a new site past the limit
+fn store_report(
+    sites: &mut BTreeMap<String, RemoteSite>,
+    report: ProbeReport,
+    peer: &str,
+    max_sites: usize,
+) -> bool {
+    let reports = match sites.get(&report.site) {
+        Some(remote) => remote.reports,
+        None if sites.len() >= max_sites => return false,
+        None => 0,
+    };
+    sites.insert(
+        report.site.clone(),
+        RemoteSite {
+            site: report.site.clone(),
+            peer: peer.to_string(),
+            received: current_unix_timestamp_ms().unwrap_or(0),
+            reports: reports + 1,
+            report,
+        },
+    );
+    true
+}
+
+// A frame is the length as a big endian u32 followed by the report
+async fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> Result<()> {
+    stream
+        .write_all(&(frame.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(frame).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// The next frame, None when the other side closed the connection between frames
+async fn read_frame(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(anyhow!("Frame of {} bytes is too large", length));
+    }
+    let mut frame = vec![0u8; length];
+    stream.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+// Sends the reports of the probe to the central instance. Only the latest report is kept
+// while the link is down, the central one wants the current state of the site, not a backlog.
+pub struct ProbeLink {
+    site: String,
+    reports: watch::Sender<Option<ProbeReport>>,
+}
+
+impl ProbeLink {
+    pub fn new(central: &str, site: &str, token: &str) -> Self {
+        let (reports, receiver) = watch::channel(None);
+        tokio::spawn(send_reports(
+            central.to_string(),
+            token.to_string(),
+            receiver,
+        ));
+        ProbeLink {
+            site: site.to_string(),
+            reports,
+        }
+    }
+
+    // The link of --probe-central, named by --probe-site or the host name
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.probe_central.is_empty() {
+            return None;
+        }
+        if let Err(e) = check_central(&args.probe_central) {
+            error!("{}, not reporting", e);
+            return None;
+        }
+        let site = site_name(args);
+        info!("Probe {} reporting to {}", site, args.probe_central);
+        Some(ProbeLink::new(
+            &args.probe_central,
+            &site,
+            &args.probe_token,
+        ))
+    }
+
+    pub fn send(&self, stats: ProbeStats) {
+        let _ = self.reports.send(Some(ProbeReport::new(&self.site, stats)));
+    }
+}
+
+// Name of this site, --probe-site or the host name
+pub fn site_name(args: &Args) -> String {
+    if !args.probe_site.is_empty() {
+        return args.probe_site.clone();
+    }
+    System::new()
+        .host_name()
+        .unwrap_or_else(|| "probe".to_string())
+}
+
+// The central instance has the stats of the captured streams, in local only mode it has to
+// be on this host
+fn check_central(central: &str) -> Result<()> {
+    check_url(&format!("tcp://{}", central)).map_err(|e| anyhow!(e))
+}
+
+async fn send_reports(
+    central: String,
+    token: String,
+    mut reports: watch::Receiver<Option<ProbeReport>>,
+) {
+    if let Err(e) = check_central(&central) {
+        error!("{}, the probe link is off", e);
+        return;
+    }
+    let hello = match ProbeHello::new(&token).encode() {
+        Ok(hello) => hello,
+        Err(e) => {
+            error!("{}, the probe link is off", e);
+            return;
+        }
+    };
+    let mut connection: Option<TcpStream> = None;
+    while reports.changed().await.is_ok() {
+        let Some(report) = reports.borrow_and_update().clone() else {
+            continue;
+        };
+        let frame = match report.encode() {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        if connection.is_none() {
+            match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&central)).await {
+                Ok(Ok(mut stream)) => match write_frame(&mut stream, &hello).await {
+                    Ok(()) => {
+                        info!("Probe link connected to {}", central);
+                        log_event(
+                            "probe_link",
+                            "connected",
+                            json!({ "central": central, "site": report.site }),
+                        );
+                        connection = Some(stream);
+                    }
+                    Err(e) => warn!("Probe link failed to greet {}: {}", central, e),
+                },
+                Ok(Err(e)) => warn!("Probe link failed to connect to {}: {}", central, e),
+                Err(_) => warn!("Probe link timed out connecting to {}", central),
+            }
+        }
+        let Some(stream) = connection.as_mut() else {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            continue;
+        };
+        if let Err(e) = write_frame(stream, &frame).await {
+            warn!("Probe link lost {}: {}", central, e);
+            log_event(
+                "probe_link",
+                "disconnected",
+                json!({ "central": central, "error": e.to_string() }),
+            );
+            connection = None;
+        } else {
+            debug!("Probe link sent {} bytes to {}", frame.len(), central);
+        }
+    }
+}
+
+// Receive the reports of the probes with the token until running is set to false
+pub async fn probe_listener(
+    address: String,
+    token: String,
+    max_sites: usize,
+    running: Arc<AtomicBool>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&address)
+        .await
+        .map_err(|e| anyhow!("Failed to bind the probe link to {}: {}", address, e))?;
+    info!("Probe link listening on {}", address);
+    if token.is_empty() {
+        warn!(
+            "Probe link on {} takes the reports of any host, set --probe-token",
+            address
+        );
+    }
+
+    while running.load(Ordering::SeqCst) {
+        let accepted = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+        let (stream, peer) = match accepted {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(e)) => {
+                error!("Probe link accept error: {}", e);
+                continue;
+            }
+            // timeout, check the running flag again
+            Err(_) => continue,
+        };
+        let running = running.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            let peer = peer.to_string();
+            if let Err(e) = receive_reports(stream, peer.clone(), &token, max_sites, running).await
+            {
+                warn!("Probe link from {} closed: {}", peer, e);
+            }
+        });
+    }
+
+    info!("Probe link shutting down.");
+    Ok(())
+}
+
+async fn receive_reports(
+    mut stream: TcpStream,
+    peer: String,
+    token: &str,
+    max_sites: usize,
+    running: Arc<AtomicBool>,
+) -> Result<()> {
+    let Some(hello) = read_frame(&mut stream).await? else {
+        return Ok(());
+    };
+    if let Err(e) = ProbeHello::check(&hello, token) {
+        log_event(
+            "probe_link",
+            "rejected",
+            json!({ "peer": peer, "error": e.to_string() }),
+        );
+        return Err(e);
+    }
+    let mut site: Option<String> = None;
+    while running.load(Ordering::SeqCst) {
+        let Some(frame) = read_frame(&mut stream).await? else {
+            break;
+        };
+        let report = ProbeReport::decode(&frame)?;
+        if site.as_deref() != Some(report.site.as_str()) {
+            info!("Probe {} connected from {}", report.site, peer);
+            log_event(
+                "probe_link",
+                "site",
+                json!({ "site": report.site, "peer": peer }),
+            );
+            site = Some(report.site.clone());
+        }
+        let report_site = report.site.clone();
+        let stored = store_report(&mut REMOTE_SITES.lock().unwrap(), report, &peer, max_sites);
+        if !stored {
+            return Err(anyhow!(
+                "Site {} is past the limit of {} sites",
+                report_site,
+                max_sites
+            ));
+        }
+    }
+    if let Some(site) = site {
+        info!("Probe {} disconnected from {}", site, peer);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::StreamAnalyzer;
+    use clap::Parser;
+
+    fn report(site: &str) -> ProbeReport {
+        let args = Args::parse_from(["rsllm"]);
+        ProbeReport::new(site, StreamAnalyzer::new(&args, 0).stats(0))
+    }
+
+    #[test]
+    fn hello_needs_the_token_of_the_central() {
+        let hello = ProbeHello::new("secret").encode().unwrap();
+        assert!(ProbeHello::check(&hello, "secret").is_ok());
+        assert!(ProbeHello::check(&hello, "other").is_err());
+        // without a token the central takes every probe
+        assert!(ProbeHello::check(&hello, "").is_ok());
+        let no_token = ProbeHello::new("").encode().unwrap();
+        assert!(ProbeHello::check(&no_token, "secret").is_err());
+        // a report in place of the hello is not one
+        assert!(ProbeHello::check(&report("site").encode().unwrap(), "secret").is_err());
+    }
+
+    #[test]
+    fn keeps_the_sites_up_to_the_limit() {
+        let mut sites = BTreeMap::new();
+        assert!(store_report(&mut sites, report("a"), "10.0.0.1:4000", 2));
+        assert!(store_report(&mut sites, report("b"), "10.0.0.2:4000", 2));
+        assert!(!store_report(&mut sites, report("c"), "10.0.0.3:4000", 2));
+        // the known sites still report past the limit
+        assert!(store_report(&mut sites, report("a"), "10.0.0.1:4001", 2));
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites["a"].reports, 2);
+        assert_eq!(sites["a"].peer, "10.0.0.1:4001");
+    }
+}
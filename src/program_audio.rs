@@ -0,0 +1,593 @@
+/*
+ * program_audio.rs
+ * ----------------
+ * Decoded audio of the monitored service. The TS packets of the audio PID are collected into
+ * PES packets on a thread of their own, the ADTS frames in them are decoded with symphonia,
+ * measured by the loudness meter and classified as music, speech or silence. The decoded audio is also handed to the subscribers
+ * like the transcription. AAC with ADTS is decoded with symphonia, AC-3 and E-AC-3 are piped
+ * through an ffmpeg decoder, the other audio codecs are only reported as not supported.
+*/
+
+use crate::args::Args;
+use crate::audio_class::{update_program_audio_class, AudioClassLimits, AudioClassifier};
+use crate::loudness::{update_program_loudness, LoudnessLimits, LoudnessMeter};
+use crate::stream_data::{AudioCodec, PesAssembler};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_AAC};
+use symphonia::core::formats::Packet;
+
+static SUBSCRIBERS: Lazy<Mutex<Vec<SyncSender<ProgramAudio>>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+// TS packets queued for the decoder thread before new ones are dropped
+const FEED_QUEUE_SIZE: usize = 4096;
+// Time between the loudness updates of the stats
+const LOUDNESS_UPDATE: Duration = Duration::from_secs(1);
+const ADTS_HEADER_LEN: usize = 7;
+const ADTS_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+const AC3_SYNC_WORD: [u8; 2] = [0x0B, 0x77];
+const AC3_SAMPLE_RATES: [u32; 3] = [48000, 44100, 32000];
+const EAC3_REDUCED_SAMPLE_RATES: [u32; 3] = [24000, 22050, 16000];
+// Full range channels of the AC-3 audio coding modes
+const AC3_CHANNELS: [usize; 8] = [2, 1, 2, 3, 3, 4, 4, 5];
+// Samples per channel of an AC-3 frame, the decoded audio is read from ffmpeg in these blocks
+const AC3_FRAME_SAMPLES: usize = 1536;
+const PTS_MASK: u64 = (1 << 33) - 1;
+
+// Decoded audio of one PES packet, interleaved
+#[derive(Debug, Clone)]
+pub struct ProgramAudio {
+    pub pid: u16,
+    pub pts: Option<u64>, // 90 kHz PTS of the first sample
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>,
+}
+
+// Receive the decoded program audio, audio is dropped while the receiver is behind
+pub fn subscribe_program_audio(capacity: usize) -> Receiver<ProgramAudio> {
+    let (sender, receiver) = sync_channel(capacity.max(1));
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+fn publish(audio: &ProgramAudio) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.retain(|subscriber| match subscriber.try_send(audio.clone()) {
+        Ok(()) | Err(TrySendError::Full(_)) => true,
+        Err(TrySendError::Disconnected(_)) => false,
+    });
+}
+
+// Fields of an ADTS header that the decoder needs
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AdtsHeader {
+    object_type: u8,
+    sample_rate_index: u8,
+    channel_config: u8,
+    header_len: usize,
+    frame_len: usize,
+}
+
+impl AdtsHeader {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < ADTS_HEADER_LEN || data[0] != 0xFF || (data[1] & 0xF6) != 0xF0 {
+            return None;
+        }
+        let protection_absent = data[1] & 0x01 == 1;
+        let header = AdtsHeader {
+            object_type: (data[2] >> 6) + 1,
+            sample_rate_index: (data[2] >> 2) & 0x0F,
+            channel_config: ((data[2] & 0x01) << 2) | (data[3] >> 6),
+            header_len: if protection_absent { 7 } else { 9 },
+            frame_len: (((data[3] & 0x03) as usize) << 11)
+                | ((data[4] as usize) << 3)
+                | ((data[5] as usize) >> 5),
+        };
+        if header.frame_len <= header.header_len
+            || header.sample_rate_index as usize >= ADTS_SAMPLE_RATES.len()
+        {
+            return None;
+        }
+        Some(header)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        ADTS_SAMPLE_RATES[self.sample_rate_index as usize]
+    }
+
+    // AudioSpecificConfig of the stream for the decoder
+    fn audio_specific_config(&self) -> Vec<u8> {
+        vec![
+            (self.object_type << 3) | (self.sample_rate_index >> 1),
+            ((self.sample_rate_index & 0x01) << 7) | (self.channel_config << 3),
+        ]
+    }
+
+    // Same stream parameters, the frame length changes every frame
+    fn same_stream(&self, other: &AdtsHeader) -> bool {
+        self.object_type == other.object_type
+            && self.sample_rate_index == other.sample_rate_index
+            && self.channel_config == other.channel_config
+    }
+}
+
+// Stream parameters of an AC-3 or E-AC-3 sync frame, the ffmpeg decoder is started with them
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Ac3Header {
+    sample_rate: u32,
+    channels: usize,
+}
+
+impl Ac3Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 8 || data[..2] != AC3_SYNC_WORD {
+            return None;
+        }
+        let bsid = data[5] >> 3;
+        match bsid {
+            0..=10 => {
+                let sample_rate = *AC3_SAMPLE_RATES.get((data[4] >> 6) as usize)?;
+                // the LFE flag follows the mix levels that the coding mode has
+                let acmod = data[6] >> 5;
+                let mut bit = 3;
+                if acmod & 0x01 != 0 && acmod != 0x01 {
+                    bit += 2;
+                }
+                if acmod & 0x04 != 0 {
+                    bit += 2;
+                }
+                if acmod == 0x02 {
+                    bit += 2;
+                }
+                let bits = u16::from_be_bytes([data[6], data[7]]);
+                let lfeon = (bits >> (15 - bit)) & 0x01;
+                Some(Ac3Header {
+                    sample_rate,
+                    channels: AC3_CHANNELS[acmod as usize] + lfeon as usize,
+                })
+            }
+            11..=16 => {
+                let fscod = data[4] >> 6;
+                let sample_rate = if fscod == 3 {
+                    *EAC3_REDUCED_SAMPLE_RATES.get(((data[4] >> 4) & 0x03) as usize)?
+                } else {
+                    AC3_SAMPLE_RATES[fscod as usize]
+                };
+                let acmod = (data[4] >> 1) & 0x07;
+                let lfeon = data[4] & 0x01;
+                Some(Ac3Header {
+                    sample_rate,
+                    channels: AC3_CHANNELS[acmod as usize] + lfeon as usize,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // The first sync frame in the data
+    fn find(data: &[u8]) -> Option<Self> {
+        data.windows(2)
+            .enumerate()
+            .filter(|(_, window)| *window == AC3_SYNC_WORD)
+            .find_map(|(offset, _)| Ac3Header::parse(&data[offset..]))
+    }
+}
+
+// An ffmpeg process decoding the AC-3 or E-AC-3 elementary stream it reads from stdin to
+// interleaved f32 PCM, read back in whole AC-3 frames by a thread of its own
+struct Ac3Decoder {
+    header: Ac3Header,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    decoded: Receiver<Vec<f32>>,
+    pts: Option<u64>, // PTS of the first sample written
+    frames_out: u64,  // samples per channel read back
+}
+
+impl Ac3Decoder {
+    fn start(ffmpeg: &str, codec: AudioCodec, header: Ac3Header) -> Result<Self> {
+        let format = if codec == AudioCodec::Eac3 {
+            "eac3"
+        } else {
+            "ac3"
+        };
+        let mut child = Command::new(ffmpeg)
+            .args(["-hide_banner", "-loglevel", "error"])
+            .args(["-f", format, "-i", "pipe:0"])
+            .args(["-f", "f32le", "-ac", &header.channels.to_string()])
+            .args(["-ar", &header.sample_rate.to_string(), "pipe:1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start {}: {}", ffmpeg, e))?;
+        let stdin = child.stdin.take();
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("No stdout of the {} decoder", ffmpeg))?;
+
+        let (sender, decoded) = channel();
+        let block_len = AC3_FRAME_SAMPLES * header.channels * 4;
+        std::thread::spawn(move || {
+            let mut block = vec![0u8; block_len];
+            while stdout.read_exact(&mut block).is_ok() {
+                let samples = block
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                if sender.send(samples).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Ac3Decoder {
+            header,
+            child,
+            stdin,
+            decoded,
+            pts: None,
+            frames_out: 0,
+        })
+    }
+
+    fn write(&mut self, pts: Option<u64>, data: &[u8]) -> Result<()> {
+        if self.pts.is_none() {
+            self.pts = pts;
+        }
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| anyhow!("The AC-3 decoder is closed"))?;
+        stdin.write_all(data)?;
+        stdin.flush()?;
+        Ok(())
+    }
+
+    // Audio decoded so far, its PTS counted on from the first written one
+    fn read(&mut self, pid: u16) -> Option<ProgramAudio> {
+        let samples: Vec<f32> = self.decoded.try_iter().flatten().collect();
+        if samples.is_empty() {
+            return None;
+        }
+        let pts = self
+            .pts
+            .map(|pts| (pts + self.frames_out * 90000 / self.header.sample_rate as u64) & PTS_MASK);
+        self.frames_out += (samples.len() / self.header.channels) as u64;
+        Some(ProgramAudio {
+            pid,
+            pts,
+            sample_rate: self.header.sample_rate,
+            channels: self.header.channels,
+            samples,
+        })
+    }
+}
+
+impl Drop for Ac3Decoder {
+    fn drop(&mut self) {
+        self.stdin = None;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct ProgramAudioDecoder {
+    pes: Option<PesAssembler>,
+    codec: Option<AudioCodec>,
+    pending: Vec<u8>, // start of an ADTS frame continued in the next PES
+    decoder: Option<(AdtsHeader, Box<dyn Decoder>)>,
+    ffmpeg: String,
+    ac3_decoder: Option<Ac3Decoder>,
+    ac3_failed: bool,
+    meter: Option<LoudnessMeter>,
+    limits: LoudnessLimits,
+    classifier: Option<AudioClassifier>,
+    class_limits: AudioClassLimits,
+    last_update: Instant,
+    unsupported_warned: bool,
+}
+
+impl ProgramAudioDecoder {
+    pub fn new(limits: LoudnessLimits, class_limits: AudioClassLimits, ffmpeg: &str) -> Self {
+        ProgramAudioDecoder {
+            pes: None,
+            codec: None,
+            pending: Vec::new(),
+            decoder: None,
+            ffmpeg: ffmpeg.to_string(),
+            ac3_decoder: None,
+            ac3_failed: false,
+            meter: None,
+            limits,
+            classifier: None,
+            class_limits,
+            last_update: Instant::now(),
+            unsupported_warned: false,
+        }
+    }
+
+    // Add a TS packet of the audio PID, a change of PID or codec starts over
+    pub fn push(&mut self, pid: u16, codec: AudioCodec, packet: &[u8]) {
+        if self.pes.as_ref().map(|pes| pes.pid()) != Some(pid) || self.codec != Some(codec) {
+            self.pes = Some(PesAssembler::new(pid));
+            self.codec = Some(codec);
+            self.pending.clear();
+            self.decoder = None;
+            self.ac3_decoder = None;
+            self.ac3_failed = false;
+            self.meter = None;
+            self.classifier = None;
+            self.unsupported_warned = false;
+        }
+        if !matches!(
+            codec,
+            AudioCodec::AacAdts | AudioCodec::Ac3 | AudioCodec::Eac3
+        ) {
+            if !self.unsupported_warned {
+                warn!(
+                    "Program audio PID {} is {}, only AAC with ADTS, AC-3 and E-AC-3 are decoded for loudness",
+                    pid, codec
+                );
+                self.unsupported_warned = true;
+            }
+            return;
+        }
+        let Some(pes) = self.pes.as_mut() else {
+            return;
+        };
+        if let Some((pts, data)) = pes.push(packet) {
+            if codec == AudioCodec::AacAdts {
+                self.aac_packet(pid, pts, data);
+            } else {
+                self.ac3_packet(pid, codec, pts, data);
+            }
+        }
+    }
+
+    // The sync frames of the PES go to the ffmpeg decoder, started on the first sync frame
+    fn ac3_packet(&mut self, pid: u16, codec: AudioCodec, pts: Option<u64>, data: Vec<u8>) {
+        if self.ac3_failed {
+            return;
+        }
+        if self.ac3_decoder.is_none() {
+            let Some(header) = Ac3Header::find(&data) else {
+                return;
+            };
+            match Ac3Decoder::start(&self.ffmpeg, codec, header) {
+                Ok(decoder) => {
+                    info!(
+                        "Decoding the {} program audio of PID {} with {}, {} Hz {} channels",
+                        codec, pid, self.ffmpeg, header.sample_rate, header.channels
+                    );
+                    self.ac3_decoder = Some(decoder);
+                }
+                Err(e) => {
+                    warn!("No loudness of the {} program audio: {}", codec, e);
+                    self.ac3_failed = true;
+                    return;
+                }
+            }
+        }
+        let Some(decoder) = self.ac3_decoder.as_mut() else {
+            return;
+        };
+        if let Err(e) = decoder.write(pts, &data) {
+            warn!("The {} decoder of PID {} stopped: {}", codec, pid, e);
+            self.ac3_decoder = None;
+            self.ac3_failed = true;
+            return;
+        }
+        if let Some(audio) = decoder.read(pid) {
+            self.measure(audio);
+        }
+    }
+
+    fn aac_packet(&mut self, pid: u16, pts: Option<u64>, data: Vec<u8>) {
+        let mut data = [std::mem::take(&mut self.pending), data].concat();
+        let mut offset = 0;
+        let mut audio: Option<ProgramAudio> = None;
+        while offset + ADTS_HEADER_LEN <= data.len() {
+            let Some(header) = AdtsHeader::parse(&data[offset..]) else {
+                // lost the frame sync, look for the next one
+                offset += 1;
+                continue;
+            };
+            if offset + header.frame_len > data.len() {
+                break;
+            }
+            let frame = &data[offset + header.header_len..offset + header.frame_len];
+            match self.decode(header, frame) {
+                Ok((sample_rate, channels, samples)) => {
+                    let audio = audio.get_or_insert_with(|| ProgramAudio {
+                        pid,
+                        pts,
+                        sample_rate,
+                        channels,
+                        samples: Vec::new(),
+                    });
+                    audio.samples.extend_from_slice(&samples);
+                }
+                Err(e) => debug!("Failed to decode program audio of PID {}: {}", pid, e),
+            }
+            offset += header.frame_len;
+        }
+        self.pending = data.split_off(offset.min(data.len()));
+
+        if let Some(audio) = audio {
+            self.measure(audio);
+        }
+    }
+
+    // Loudness and class of the decoded audio, then handed to the subscribers
+    fn measure(&mut self, audio: ProgramAudio) {
+        let pid = audio.pid;
+        let meter = match self.meter.as_mut() {
+            Some(meter)
+                if meter.sample_rate() == audio.sample_rate
+                    && meter.channels() == audio.channels =>
+            {
+                meter
+            }
+            _ => self
+                .meter
+                .insert(LoudnessMeter::new(audio.sample_rate, audio.channels)),
+        };
+        meter.push(&audio.samples);
+        if self.last_update.elapsed() >= LOUDNESS_UPDATE {
+            self.last_update = Instant::now();
+            let codec = self
+                .codec
+                .map(|codec| codec.to_string())
+                .unwrap_or_default();
+            update_program_loudness(pid, &codec, meter, &self.limits);
+        }
+
+        let classifier = match self.classifier.as_mut() {
+            Some(classifier)
+                if classifier.sample_rate() == audio.sample_rate
+                    && classifier.channels() == audio.channels =>
+            {
+                classifier
+            }
+            _ => self
+                .classifier
+                .insert(AudioClassifier::new(audio.sample_rate, audio.channels)),
+        };
+        if let Some((class, seconds)) =
+            classifier.push(&audio.samples, self.class_limits.silence_dbfs)
+        {
+            update_program_audio_class(pid, class, seconds, &self.class_limits);
+        }
+        publish(&audio);
+    }
+
+    fn decode(&mut self, header: AdtsHeader, frame: &[u8]) -> Result<(u32, usize, Vec<f32>)> {
+        if !matches!(&self.decoder, Some((current, _)) if current.same_stream(&header)) {
+            let mut params = CodecParameters::new();
+            params
+                .for_codec(CODEC_TYPE_AAC)
+                .with_sample_rate(header.sample_rate())
+                .with_extra_data(header.audio_specific_config().into_boxed_slice());
+            let decoder = symphonia::default::get_codecs()
+                .make(&params, &DecoderOptions::default())
+                .map_err(|e| anyhow!("Failed to create the AAC decoder: {}", e))?;
+            self.decoder = Some((header, decoder));
+        }
+        let Some((_, decoder)) = self.decoder.as_mut() else {
+            return Err(anyhow!("No AAC decoder"));
+        };
+        let decoded = decoder.decode(&Packet::new_from_slice(0, 0, 0, frame))?;
+        let spec = *decoded.spec();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        Ok((spec.rate, spec.channels.count(), buffer.samples().to_vec()))
+    }
+}
+
+// Hands the audio packets from the capture loop to the decoder thread without blocking it
+pub struct ProgramAudioFeed {
+    sender: SyncSender<(u16, AudioCodec, Vec<u8>)>,
+    dropped: u64,
+}
+
+impl ProgramAudioFeed {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if !args.program_audio || !args.ai_network_stats {
+            return None;
+        }
+        let mut decoder = ProgramAudioDecoder::new(
+            LoudnessLimits::from_args(args),
+            AudioClassLimits::from_args(args),
+            &args.ffmpeg_path,
+        );
+        let (sender, receiver): (_, Receiver<(u16, AudioCodec, Vec<u8>)>) =
+            sync_channel(FEED_QUEUE_SIZE);
+        std::thread::spawn(move || {
+            for (pid, codec, packet) in receiver {
+                decoder.push(pid, codec, &packet);
+            }
+        });
+        info!("Decoding the monitored program audio for loudness and classification");
+        Some(ProgramAudioFeed { sender, dropped: 0 })
+    }
+
+    // Queue a TS packet of the audio PID, dropped when the decoder is behind
+    pub fn push(&mut self, pid: u16, codec: AudioCodec, packet: &[u8]) {
+        match self.sender.try_send((pid, codec, packet.to_vec())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped % 1000 == 1 {
+                    warn!(
+                        "Program audio decoder is behind, {} packets dropped",
+                        self.dropped
+                    );
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_frame(byte4: u8, byte5: u8, byte6: u8) -> Vec<u8> {
+        vec![0x0B, 0x77, 0x00, 0x00, byte4, byte5, byte6, 0x00]
+    }
+
+    #[test]
+    fn parses_ac3_channels_and_sample_rate() {
+        // 3/2 with the LFE after both mix levels
+        let header = Ac3Header::parse(&sync_frame(0x00, 0x40, 0xE1)).unwrap();
+        assert_eq!(
+            header,
+            Ac3Header {
+                sample_rate: 48000,
+                channels: 6
+            }
+        );
+        // 2/0 has the surround mode before the LFE
+        let header = Ac3Header::parse(&sync_frame(0x40, 0x40, 0x40)).unwrap();
+        assert_eq!((header.sample_rate, header.channels), (44100, 2));
+        let header = Ac3Header::parse(&sync_frame(0x80, 0x40, 0x44)).unwrap();
+        assert_eq!((header.sample_rate, header.channels), (32000, 3));
+    }
+
+    #[test]
+    fn parses_eac3_channels_and_sample_rate() {
+        let header = Ac3Header::parse(&sync_frame(0x3F, 0x80, 0x00)).unwrap();
+        assert_eq!((header.sample_rate, header.channels), (48000, 6));
+        let header = Ac3Header::parse(&sync_frame(0xD2, 0x80, 0x00)).unwrap();
+        assert_eq!((header.sample_rate, header.channels), (22050, 1));
+    }
+
+    #[test]
+    fn rejects_other_streams() {
+        assert_eq!(Ac3Header::parse(&sync_frame(0xC0, 0x40, 0x40)), None);
+        assert_eq!(Ac3Header::parse(&sync_frame(0x00, 0xA0, 0x40)), None);
+        assert_eq!(Ac3Header::parse(&[0x0B, 0x77, 0x00]), None);
+    }
+
+    #[test]
+    fn finds_the_first_sync_frame() {
+        let mut data = vec![0x00, 0x0B, 0x00, 0x0B];
+        data.extend(sync_frame(0x00, 0x40, 0xE1));
+        let header = Ac3Header::find(&data).unwrap();
+        assert_eq!(header.channels, 6);
+        assert_eq!(Ac3Header::find(&[0x00; 16]), None);
+    }
+}
@@ -0,0 +1,74 @@
+/*
+ * prompts.rs
+ * ----------
+ * Prompts that can be reloaded without a restart. The --prompts-file is a JSON object with
+ * any of system_prompt, query, greeting and image_prompt, the fields it sets replace the
+ * command line values. It is read at start and again, along with the personas file, on
+ * SIGHUP or a POST to /reload of the control API.
+*/
+
+use crate::args::Args;
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+// Command queued for the main loop to reload the prompts and personas
+pub const RELOAD_COMMAND: &str = "!reload";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PromptsFile {
+    pub system_prompt: Option<String>,
+    pub query: Option<String>,
+    pub greeting: Option<String>,
+    pub image_prompt: Option<String>,
+}
+
+// Args with the prompts of the --prompts-file, unchanged when it is not set
+pub fn apply_prompts_file(args: &Args) -> Result<Args> {
+    let mut args = args.clone();
+    if args.prompts_file.is_empty() {
+        return Ok(args);
+    }
+    let contents = std::fs::read_to_string(&args.prompts_file)
+        .map_err(|e| anyhow!("Failed to read prompts file {}: {}", args.prompts_file, e))?;
+    let prompts: PromptsFile = serde_json::from_str(&contents)
+        .map_err(|e| anyhow!("Failed to parse prompts file {}: {}", args.prompts_file, e))?;
+    if let Some(system_prompt) = prompts.system_prompt {
+        args.system_prompt = system_prompt;
+    }
+    if let Some(query) = prompts.query {
+        args.query = query;
+    }
+    if let Some(greeting) = prompts.greeting {
+        args.greeting = greeting;
+    }
+    if let Some(image_prompt) = prompts.image_prompt {
+        args.assistant_image_prompt = image_prompt;
+    }
+    Ok(args)
+}
+
+// Queue a reload on every SIGHUP
+#[cfg(unix)]
+pub fn spawn_sighup_reload(command_tx: mpsc::Sender<String>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading the prompts and personas");
+            if command_tx.send(RELOAD_COMMAND.to_string()).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_sighup_reload(_command_tx: mpsc::Sender<String>) {}
@@ -0,0 +1,188 @@
+/*
+ * response_cache.rs
+ * -----------------
+ * Cache of the daemon analyzer answers keyed by an embedding of the stats window they were
+ * asked about. The embedding is a hashed bag of the words with each number bucketed by its
+ * magnitude under the name before it, so a window where the counters only moved a little is
+ * close to the last one. A window as similar as the threshold to a cached one reuses that
+ * answer instead of calling the LLM, as long as none of its error and alarm counters changed.
+*/
+
+use crate::args::Args;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+const DIMENSIONS: usize = 512;
+
+// Bucket of a number, half steps of log2 so 1000 and 1100 share one and 1000 and 2000 don't
+fn magnitude_bucket(number: f64) -> i64 {
+    let bucket = ((number.abs() + 1.0).log2() * 2.0).round() as i64;
+    if number < 0.0 {
+        -bucket
+    } else {
+        bucket
+    }
+}
+
+fn add_feature(embedding: &mut [f32], feature: &str) {
+    let mut hasher = DefaultHasher::new();
+    feature.hash(&mut hasher);
+    let hash = hasher.finish();
+    // the top bit picks the sign so collisions cancel out instead of piling up
+    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+    embedding[(hash % DIMENSIONS as u64) as usize] += sign;
+}
+
+// The words and numbers of the text
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '.' || c == '_' || c == '-'))
+        .map(|word| word.trim_matches(|c| c == '.' || c == '-'))
+        .filter(|word| !word.is_empty())
+}
+
+// Fields counting errors or raising alarms, cc_errors, error_count, the TR 101 290 counters
+fn is_alarm_field(field: &str) -> bool {
+    field.contains("error") || field.contains("alarm")
+}
+
+// The error and alarm fields with their values in order. One counter going up barely moves
+// the embedding of a large window, so any change here is a different window.
+pub fn alarm_signature(text: &str) -> Vec<String> {
+    let mut signature = Vec::new();
+    let mut alarm_field: Option<String> = None;
+    for word in words(text) {
+        match alarm_field.take() {
+            Some(field) => signature.push(format!("{}={}", field, word.to_lowercase())),
+            None => {
+                let field = word.to_lowercase();
+                if is_alarm_field(&field) {
+                    alarm_field = Some(field);
+                }
+            }
+        }
+    }
+    signature
+}
+
+// Normalized embedding of a stats window and its alarms
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowEmbedding {
+    pub vector: Vec<f32>,
+    pub alarms: Vec<String>,
+}
+
+pub fn embed(text: &str) -> WindowEmbedding {
+    let mut embedding = vec![0.0; DIMENSIONS];
+    let mut field = String::new();
+    for word in words(text) {
+        match word.parse::<f64>() {
+            Ok(number) if number.is_finite() => {
+                add_feature(
+                    &mut embedding,
+                    &format!("{}={}", field, magnitude_bucket(number)),
+                );
+            }
+            _ => {
+                field = word.to_lowercase();
+                add_feature(&mut embedding, &field);
+            }
+        }
+    }
+
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+    WindowEmbedding {
+        vector: embedding,
+        alarms: alarm_signature(text),
+    }
+}
+
+// Cosine similarity of two normalized embeddings
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+// The answer as tokens for the segmenter, split after each whitespace like streamed tokens
+pub fn replay_tokens(answer: &str) -> Vec<String> {
+    answer
+        .split_inclusive(char::is_whitespace)
+        .map(|token| token.to_string())
+        .collect()
+}
+
+struct CachedResponse {
+    embedding: WindowEmbedding,
+    answer: String,
+    created: Instant,
+}
+
+pub struct ResponseCache {
+    entries: VecDeque<CachedResponse>,
+    capacity: usize, // 0 is off
+    threshold: f32,
+    max_age: Duration, // 0 never expires
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, threshold: f32, max_age: Duration) -> Self {
+        ResponseCache {
+            entries: VecDeque::new(),
+            capacity,
+            threshold,
+            max_age,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        ResponseCache::new(
+            args.response_cache_size,
+            args.response_cache_threshold,
+            Duration::from_secs(args.response_cache_max_age_secs),
+        )
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // The most similar cached answer at or above the threshold and its similarity, expired
+    // answers are dropped first so the commentary is refreshed now and then. Only answers
+    // about the same errors and alarms are candidates, a healthy stream's answer is never
+    // replayed during an outage.
+    pub fn lookup(&mut self, embedding: &WindowEmbedding) -> Option<(String, f32)> {
+        let max_age = self.max_age;
+        self.entries
+            .retain(|entry| max_age.is_zero() || entry.created.elapsed() <= max_age);
+        self.entries
+            .iter()
+            .filter(|entry| entry.embedding.alarms == embedding.alarms)
+            .map(|entry| {
+                (
+                    entry,
+                    cosine_similarity(&entry.embedding.vector, &embedding.vector),
+                )
+            })
+            .filter(|(_, similarity)| *similarity >= self.threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(entry, similarity)| (entry.answer.clone(), similarity))
+    }
+
+    // Keep the answer, the oldest goes when full
+    pub fn insert(&mut self, embedding: WindowEmbedding, answer: String) {
+        if !self.is_enabled() || answer.trim().is_empty() {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(CachedResponse {
+            embedding,
+            answer,
+            created: Instant::now(),
+        });
+    }
+}
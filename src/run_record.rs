@@ -0,0 +1,214 @@
+/*
+ * run_record.rs
+ * -------------
+ * Reproducibility bundle of a run for --record-run. The directory gets run.json with the
+ * command line and version, iterations.jsonl with the exact prompt, sampling parameters and
+ * seed, answer and model versions of each iteration, and paragraphs.jsonl with the text,
+ * image prompt, stable diffusion seed and voice of each paragraph. Seeds that would be
+ * random are picked up front so they can be recorded.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::pipeline::MessageData;
+use crate::sampling::SamplingConfig;
+use anyhow::{anyhow, Result};
+use log::error;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Version of each loaded model file by model
+static MODEL_VERSIONS: Lazy<Mutex<BTreeMap<String, BTreeMap<String, String>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+// Command line options whose values are left out of the bundle
+const SECRET_OPTIONS: [&str; 4] = ["token", "key", "password", "secret"];
+
+// The hub commit of the snapshot the file was downloaded in, else a hash of the path, size
+// and modification time of the local file
+pub fn file_version(path: &Path) -> String {
+    let components: Vec<String> = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect();
+    if let Some(index) = components.iter().position(|part| part == "snapshots") {
+        if let Some(commit) = components.get(index + 1) {
+            return commit.clone();
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(path.to_string_lossy().as_bytes());
+    if let Ok(metadata) = std::fs::metadata(path) {
+        hasher.update(metadata.len().to_le_bytes());
+        if let Ok(modified) = metadata.modified() {
+            hasher.update(format!("{:?}", modified).as_bytes());
+        }
+    }
+    hasher.finalize()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Remember the versions of the files a model was loaded from
+pub fn record_model_files(model: &str, files: &[PathBuf]) {
+    let mut versions = MODEL_VERSIONS.lock().unwrap();
+    let entry = versions.entry(model.to_string()).or_default();
+    for file in files {
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| file.to_string_lossy().to_string());
+        entry.insert(name, file_version(file));
+    }
+}
+
+pub fn model_versions() -> BTreeMap<String, BTreeMap<String, String>> {
+    MODEL_VERSIONS.lock().unwrap().clone()
+}
+
+// The command line with the values of secret options replaced
+fn redacted_command_line() -> Vec<String> {
+    let mut redact_next = false;
+    std::env::args()
+        .map(|arg| {
+            if redact_next {
+                redact_next = false;
+                return "<redacted>".to_string();
+            }
+            let lower = arg.to_lowercase();
+            if arg.starts_with("--") && SECRET_OPTIONS.iter().any(|word| lower.contains(word)) {
+                match arg.split_once('=') {
+                    Some((option, _)) => return format!("{}=<redacted>", option),
+                    None => redact_next = true,
+                }
+            }
+            arg
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IterationRecord {
+    pub iteration: u64,
+    pub output_id: String,
+    pub started_ms: u64,
+    pub model: String,
+    pub model_id: String,
+    pub quantized: bool,
+    pub max_tokens: usize,
+    pub sampling: SamplingRecord,
+    pub prompt: String,
+    pub answer: String,
+    pub tokens: usize,
+    pub cached: bool, // the answer came from the response cache
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SamplingRecord {
+    pub temperature: f64,
+    pub top_k: usize,
+    pub top_p: f64,
+    pub min_p: f64,
+    pub typical_p: f64,
+    pub seed: Option<u64>,
+}
+
+impl SamplingRecord {
+    pub fn new(sampling: &SamplingConfig) -> Self {
+        SamplingRecord {
+            temperature: sampling.temperature,
+            top_k: sampling.top_k,
+            top_p: sampling.top_p,
+            min_p: sampling.min_p,
+            typical_p: sampling.typical_p,
+            seed: sampling.seed,
+        }
+    }
+}
+
+// Appends the records of the run to the bundle directory
+#[derive(Clone)]
+pub struct RunRecorder {
+    dir: PathBuf,
+}
+
+impl RunRecorder {
+    pub fn new(dir: &str, args: &Args) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow!("Failed to create run record {}: {}", dir.display(), e))?;
+        let run = json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "started_ms": current_unix_timestamp_ms().unwrap_or(0),
+            "command_line": redacted_command_line(),
+            "llm": if args.use_api || args.use_openai { &args.model } else { &args.candle_llm },
+            "chat_format": args.chat_format,
+            "sd_model": args.sd_model,
+            "sd_custom_model": args.sd_custom_model,
+        });
+        std::fs::write(dir.join("run.json"), serde_json::to_string_pretty(&run)?)
+            .map_err(|e| anyhow!("Failed to write run record {}: {}", dir.display(), e))?;
+        Ok(RunRecorder { dir })
+    }
+
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.record_run.is_empty() {
+            return None;
+        }
+        RunRecorder::new(&args.record_run, args)
+            .map_err(|e| error!("{}, not recording the run", e))
+            .ok()
+    }
+
+    fn append(&self, file: &str, record: serde_json::Value) {
+        let path = self.dir.join(file);
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", record));
+        if let Err(e) = result {
+            error!("Failed to append to run record {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn iteration(&self, record: &IterationRecord) {
+        let mut value = serde_json::to_value(record).unwrap_or_default();
+        value["model_versions"] = json!(model_versions());
+        self.append("iterations.jsonl", value);
+    }
+
+    // Record the paragraph sent to the pipeline, a random image seed is picked here so it is
+    // the one recorded
+    pub fn paragraph(&self, message: &mut MessageData) {
+        if matches!(message.sd_config.seed, None | Some(-1)) {
+            message.sd_config.seed = Some(rand::random::<i32>() & i32::MAX);
+        }
+        self.append(
+            "paragraphs.jsonl",
+            json!({
+                "output_id": message.output_id,
+                "paragraph_count": message.paragraph_count,
+                "paragraph": message.paragraph,
+                "image_prompt": message.sd_config.prompt,
+                "negative_prompt": message.sd_config.uncond_prompt,
+                "sd_seed": message.sd_config.seed,
+                "sd_steps": message.sd_config.n_steps,
+                "width": message.sd_config.width,
+                "height": message.sd_config.height,
+                "voice": message.mimic3_voice,
+                "emotion": message.emotion,
+                "requested_by": message.requested_by,
+                "recorded_ms": current_unix_timestamp_ms().unwrap_or(0),
+            }),
+        );
+    }
+}
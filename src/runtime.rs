@@ -0,0 +1,734 @@
+/*
+ * runtime.rs
+ * ----------
+ * Dispatch of the segmented paragraphs to the image/speech pipeline and the ordered
+ * output stage, shared by the main loop.
+*/
+
+use crate::args::Args;
+use crate::checkpoint::Checkpointer;
+use crate::pipeline::{MessageData, Priority, ProcessedData};
+use crate::run_record::RunRecorder;
+use crate::stable_diffusion::{SDConfig, StableDiffusionVersion};
+use image::{ImageBuffer, Rgb};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+
+// Map the --sd-model string to the stable diffusion version
+pub fn sd_version_from_str(sd_model: &str) -> StableDiffusionVersion {
+    match sd_model {
+        "1.5" => StableDiffusionVersion::V1_5,
+        "2.1" => StableDiffusionVersion::V2_1,
+        "xl" => StableDiffusionVersion::Xl,
+        "turbo" => StableDiffusionVersion::Turbo,
+        "Custom" | "custom" => StableDiffusionVersion::Custom,
+        _ => StableDiffusionVersion::V1_5,
+    }
+}
+
+// Build the stable diffusion config for a prompt from the command line arguments
+pub fn build_sd_config(args: &Args, prompt: &str) -> SDConfig {
+    let mut sd_config = SDConfig::new();
+    sd_config.prompt = prompt.to_string();
+    sd_config.height = Some(args.sd_height);
+    sd_config.width = Some(args.sd_width);
+    sd_config.image_position = Some(args.image_alignment.clone());
+    sd_config.intermediary_images = args.sd_intermediary_images;
+    sd_config.custom_model = Some(args.sd_custom_model.clone());
+    if args.sd_scaled_height > 0 {
+        sd_config.scaled_height = Some(args.sd_scaled_height);
+    }
+    if args.sd_scaled_width > 0 {
+        sd_config.scaled_width = Some(args.sd_scaled_width);
+    }
+    sd_config.sd_version = sd_version_from_str(&args.sd_model);
+    sd_config.n_steps = args.sd_n_steps;
+    sd_config
+}
+
+// Check if any of the image or speech outputs are enabled
+pub fn pipeline_enabled(args: &Args) -> bool {
+    args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts
+}
+
+// Cancels the queued and in flight paragraphs. Each cancel starts a new generation,
+// normal priority messages from an older generation are dropped by the pipeline.
+#[derive(Clone, Default)]
+pub struct PipelineCancel {
+    generation: Arc<AtomicU64>,
+}
+
+impl PipelineCancel {
+    pub fn new() -> Self {
+        PipelineCancel::default()
+    }
+
+    pub fn cancel(&self) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        log::info!(
+            "Pipeline: cancelling queued paragraphs, generation {}",
+            generation
+        );
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self, message: &MessageData) -> bool {
+        message.priority == Priority::Normal && message.generation < self.generation()
+    }
+}
+
+// Create the pipeline channels, high priority messages get their own channel
+pub fn pipeline_channel(
+    capacity: usize,
+    store: Arc<Mutex<ProcessedDataStore>>,
+    cancel: PipelineCancel,
+) -> (PipelineDispatcher, PipelineReceiver) {
+    let (sender, receiver) = mpsc::channel::<MessageData>(capacity.max(1));
+    let (high_sender, high_receiver) = mpsc::channel::<MessageData>(capacity.max(1));
+    (
+        PipelineDispatcher {
+            sender,
+            high_sender,
+            store,
+            cancel,
+            checkpointer: None,
+            recorder: None,
+        },
+        PipelineReceiver {
+            receiver,
+            high_receiver,
+        },
+    )
+}
+
+// Receives the pipeline messages, high priority first
+pub struct PipelineReceiver {
+    receiver: mpsc::Receiver<MessageData>,
+    high_receiver: mpsc::Receiver<MessageData>,
+}
+
+impl PipelineReceiver {
+    pub async fn recv(&mut self) -> Option<MessageData> {
+        tokio::select! {
+            biased;
+            Some(message) = self.high_receiver.recv() => Some(message),
+            message = self.receiver.recv() => message,
+        }
+    }
+}
+
+// Sends paragraphs to the pipeline. The output ordering key of each message is allocated
+// by the processed data store, so clones of the dispatcher in other tasks share one sequence.
+#[derive(Clone)]
+pub struct PipelineDispatcher {
+    sender: mpsc::Sender<MessageData>,
+    high_sender: mpsc::Sender<MessageData>,
+    store: Arc<Mutex<ProcessedDataStore>>,
+    cancel: PipelineCancel,
+    checkpointer: Option<Checkpointer>,
+    recorder: Option<RunRecorder>,
+}
+
+impl PipelineDispatcher {
+    // The key the next message will be sent with
+    pub async fn total_paragraph_count(&self) -> usize {
+        self.store.lock().await.next_sequence()
+    }
+
+    // Record the sent messages in the checkpoint
+    pub fn set_checkpointer(&mut self, checkpointer: Checkpointer) {
+        self.checkpointer = Some(checkpointer);
+    }
+
+    // Record the sent messages in the run bundle
+    pub fn set_recorder(&mut self, recorder: RunRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub async fn send(
+        &mut self,
+        mut message: MessageData,
+    ) -> Result<(), mpsc::error::SendError<MessageData>> {
+        // reserved before sending so the output waits for it even if it is processed out of order
+        message.paragraph_count = self.store.lock().await.allocate(message.priority);
+        message.generation = self.cancel.generation();
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.paragraph(&mut message);
+        }
+        if let Some(checkpointer) = self.checkpointer.as_ref() {
+            checkpointer.dispatched(&message);
+        }
+        let key = message.paragraph_count;
+        let result = match message.priority {
+            Priority::High => self.high_sender.send(message).await,
+            Priority::Normal => self.sender.send(message).await,
+        };
+        if result.is_err() {
+            // the pipeline is gone, the output must not wait for the key
+            self.store.lock().await.cancel(key);
+        }
+        result
+    }
+}
+
+impl MessageData {
+    // Message for a paragraph, the subtitle position and voice come from the arguments
+    pub fn new(paragraph: &str, output_id: &str, sd_config: SDConfig, args: &Args) -> Self {
+        MessageData {
+            paragraph: paragraph.to_string(),
+            output_id: output_id.to_string(),
+            paragraph_count: 0,
+            sd_config,
+            mimic3_voice: args.mimic3_voice.clone(),
+            subtitle_position: args.subtitle_position.clone(),
+            args: args.clone(),
+            shutdown: false,
+            last_message: false,
+            emotion: None,
+            keywords: None,
+            sentiment: None,
+            priority: Priority::Normal,
+            generation: 0,
+            requested_by: None,
+        }
+    }
+
+    // Announcements like greetings are centered with subtitles always on and skip
+    // ahead of the queued paragraphs
+    pub fn announcement(text: &str, output_id: &str, sd_config: SDConfig, args: &Args) -> Self {
+        let mut message = MessageData::new(text, output_id, sd_config, args);
+        message.subtitle_position = "center".to_string();
+        message.args.subtitles = true;
+        message.priority = Priority::High;
+        message
+    }
+
+    // Viewer requested image, its own image job ahead of the story with the description
+    // read out and the images always run through the safety checker
+    pub fn viewer_image(
+        prompt: &str,
+        requester: &str,
+        output_id: &str,
+        sd_config: SDConfig,
+        args: &Args,
+    ) -> Self {
+        let mut message = MessageData::new(prompt, output_id, sd_config, args);
+        message.args.subtitles = false;
+        message.args.nsfw_filter = true;
+        message.priority = Priority::High;
+        message.requested_by = Some(requester.to_string());
+        message
+    }
+
+    // Title card like the be right back of an ad break, an announcement shown without speech
+    pub fn card(text: &str, output_id: &str, sd_config: SDConfig, args: &Args) -> Self {
+        let mut message = MessageData::announcement(text, output_id, sd_config, args);
+        message.args.tts_enable = false;
+        message.args.oai_tts = false;
+        message.args.mimic3_tts = false;
+        message.args.metavoice_tts = false;
+        message
+    }
+
+    pub fn with_voice(mut self, voice: &str) -> Self {
+        self.mimic3_voice = voice.to_string();
+        self
+    }
+
+    pub fn with_last_message(mut self, last_message: bool) -> Self {
+        self.last_message = last_message;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: bool) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+// Presentation timeline of the output. Each paragraph is stamped with the time it should
+// start at, from the accumulated audio durations, and the output waits for that time
+// instead of sending as fast as possible.
+pub struct PresentationClock {
+    start: Option<tokio::time::Instant>,
+    next_pts_ms: u64,
+    origin_pts_ms: u64, // presentation time at start, non zero for a resumed show
+    gap_ms: u64,
+}
+
+impl PresentationClock {
+    // gap_ms is added after each paragraph, like the lead in silence of the audio
+    pub fn new(gap_ms: u64) -> Self {
+        PresentationClock {
+            start: None,
+            next_pts_ms: 0,
+            origin_pts_ms: 0,
+            gap_ms,
+        }
+    }
+
+    // Stamp the paragraph with its presentation time and advance the timeline
+    pub fn stamp(&mut self, data: &mut ProcessedData) {
+        data.time_stamp = self.next_pts_ms;
+        let duration_ms = if data.duration_ms > 0 {
+            data.duration_ms + self.gap_ms
+        } else {
+            0
+        };
+        self.next_pts_ms += duration_ms;
+    }
+
+    // Continue the timeline of a resumed show, its first paragraph plays right away
+    pub fn resume_at(&mut self, pts_ms: u64) {
+        self.next_pts_ms = pts_ms;
+        self.origin_pts_ms = pts_ms;
+        self.start = None;
+    }
+
+    // Presentation time of the next paragraph to be stamped
+    pub fn next_pts_ms(&self) -> u64 {
+        self.next_pts_ms
+    }
+
+    // Wait for the presentation time, a late paragraph starts now and moves the timeline
+    pub async fn wait_for(&mut self, pts_ms: u64) {
+        let now = tokio::time::Instant::now();
+        let start = *self.start.get_or_insert(now);
+        let offset_ms = pts_ms.saturating_sub(self.origin_pts_ms);
+        let due = start + Duration::from_millis(offset_ms);
+        if due > now {
+            tokio::time::sleep_until(due).await;
+        } else if now - due > Duration::from_millis(100) {
+            log::debug!(
+                "Presentation clock: paragraph at {} ms is {} ms late",
+                pts_ms,
+                (now - due).as_millis()
+            );
+            self.start = Some(now - Duration::from_millis(offset_ms));
+        }
+    }
+}
+
+// Result of looking for the next output in order
+pub enum NextOutput {
+    // the next paragraph is ready to be sent
+    Ready(Box<ProcessedData>),
+    // the next paragraph is still being processed
+    Pending,
+    // the next paragraph has not reached the pipeline yet
+    Missing,
+}
+
+// Gauges for the processed data store
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StoreMetrics {
+    pub buffered: usize,
+    pub pending: usize,
+    pub max_buffered: usize,
+    pub next_key: usize,
+    pub inserted: u64,
+    pub delivered: u64,
+    pub evicted: u64,
+    pub skipped: u64,
+    pub cancelled: u64,
+}
+
+// Processed paragraphs waiting for the output stage, handed out in the order they were sent
+// with the high priority paragraphs ahead of the normal ones still waiting. Paragraphs are
+// reserved when they are sent to the pipeline so a paragraph that never arrives can be told
+// apart from one that is still being processed.
+pub struct ProcessedDataStore {
+    entries: BTreeMap<usize, ProcessedData>,
+    pending: BTreeMap<usize, Instant>,
+    cancelled: BTreeSet<usize>,
+    previews: BTreeMap<usize, ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    order: VecDeque<(usize, Priority)>, // reserved keys not output yet, in output order
+    capacity: usize,
+    max_wait: Duration,
+    next_sequence: usize, // key of the next paragraph sent to the pipeline
+    metrics: StoreMetrics,
+}
+
+impl ProcessedDataStore {
+    pub fn new(capacity: usize, max_wait: Duration) -> Self {
+        ProcessedDataStore {
+            entries: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            cancelled: BTreeSet::new(),
+            previews: BTreeMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+            max_wait,
+            next_sequence: 0,
+            metrics: StoreMetrics::default(),
+        }
+    }
+
+    // Start the sequence at the first paragraph of a resumed show
+    pub fn resume_at(&mut self, key: usize) {
+        self.next_sequence = key;
+    }
+
+    // Allocate the key of a paragraph sent to the pipeline and mark it as being processed.
+    // A high priority key is output after the high priority keys before it and ahead of the
+    // normal keys that were not output yet.
+    pub fn allocate(&mut self, priority: Priority) -> usize {
+        let key = self.next_sequence;
+        self.next_sequence += 1;
+        self.pending.insert(key, Instant::now());
+        let position = match priority {
+            Priority::High => self
+                .order
+                .iter()
+                .position(|&(_, priority)| priority == Priority::Normal)
+                .unwrap_or(self.order.len()),
+            Priority::Normal => self.order.len(),
+        };
+        self.order.insert(position, (key, priority));
+        key
+    }
+
+    pub fn next_sequence(&self) -> usize {
+        self.next_sequence
+    }
+
+    fn is_reserved(&self, key: usize) -> bool {
+        self.order.iter().any(|&(k, _)| k == key)
+    }
+
+    // Drop a cancelled paragraph, the output skips it
+    pub fn cancel(&mut self, key: usize) {
+        self.previews.remove(&key);
+        if self.pending.remove(&key).is_some() {
+            self.cancelled.insert(key);
+            self.metrics.cancelled += 1;
+        }
+    }
+
+    // Keep the latest image of a paragraph that is still being processed, the output shows it
+    // while it waits for the paragraph
+    pub fn preview(&mut self, key: usize, image: ImageBuffer<Rgb<u8>, Vec<u8>>) {
+        self.previews.retain(|k, _| self.pending.contains_key(k));
+        if self.pending.contains_key(&key) {
+            self.previews.insert(key, image);
+        }
+    }
+
+    pub fn take_preview(&mut self, key: usize) -> Option<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+        self.previews.remove(&key)
+    }
+
+    // Remove the keys up to and including the one at the position from the output order
+    fn drop_through(&mut self, position: usize) {
+        for (key, _) in self.order.drain(..=position) {
+            self.entries.remove(&key);
+            self.pending.remove(&key);
+            self.cancelled.remove(&key);
+            self.previews.remove(&key);
+        }
+    }
+
+    // Store a processed paragraph, evicting the oldest paragraphs when full
+    pub fn insert(&mut self, data: ProcessedData) {
+        let key = data.paragraph_count;
+        self.pending.remove(&key);
+        self.previews.remove(&key);
+        if !self.is_reserved(key) {
+            log::warn!(
+                "Processed data store: dropping stale paragraph {}, output is at {}",
+                key,
+                self.next_key()
+            );
+            self.metrics.evicted += 1;
+            return;
+        }
+
+        self.entries.insert(key, data);
+        self.metrics.inserted += 1;
+
+        while self.entries.len() > self.capacity {
+            // the first in output order, with the paragraphs still waiting before it
+            let Some(position) = self
+                .order
+                .iter()
+                .position(|(key, _)| self.entries.contains_key(key))
+            else {
+                break;
+            };
+            log::warn!(
+                "Processed data store: full with {} paragraphs, evicting paragraph {}",
+                self.capacity,
+                self.order[position].0
+            );
+            self.metrics.evicted += 1;
+            self.drop_through(position);
+        }
+        self.metrics.max_buffered = self.metrics.max_buffered.max(self.entries.len());
+    }
+
+    // Take the next paragraph in order, skipping paragraphs that were lost or took too long
+    pub fn next_ready(&mut self) -> NextOutput {
+        while let Some(&(key, _)) = self.order.front() {
+            if self.cancelled.remove(&key) {
+                self.order.pop_front();
+                continue;
+            }
+
+            if let Some(data) = self.entries.remove(&key) {
+                self.order.pop_front();
+                self.metrics.delivered += 1;
+                return NextOutput::Ready(Box::new(data));
+            }
+
+            if let Some(started) = self.pending.get(&key) {
+                if started.elapsed() < self.max_wait {
+                    return NextOutput::Pending;
+                }
+                log::error!(
+                    "Processed data store: paragraph {} not done after {:?}, skipping it",
+                    key,
+                    self.max_wait
+                );
+                self.pending.remove(&key);
+                self.order.pop_front();
+                self.metrics.skipped += 1;
+                continue;
+            }
+
+            // reserved but neither processing, stored nor cancelled, it was lost
+            log::error!(
+                "Processed data store: paragraph {} never arrived, skipping it",
+                key
+            );
+            self.order.pop_front();
+            self.metrics.skipped += 1;
+        }
+
+        NextOutput::Missing
+    }
+
+    // The key the output waits for next
+    pub fn next_key(&self) -> usize {
+        self.order
+            .front()
+            .map_or(self.next_sequence, |&(key, _)| key)
+    }
+
+    // Keys before this one are all output or skipped
+    pub fn oldest_key(&self) -> usize {
+        self.order
+            .iter()
+            .map(|&(key, _)| key)
+            .min()
+            .unwrap_or(self.next_sequence)
+    }
+
+    pub fn metrics(&self) -> StoreMetrics {
+        StoreMetrics {
+            buffered: self.entries.len(),
+            pending: self.pending.len(),
+            next_key: self.next_key(),
+            ..self.metrics.clone()
+        }
+    }
+}
+
+// Held by the task processing a paragraph. Dropped before the paragraph was stored, like when
+// the task panics, the key is cancelled so the output skips it right away instead of waiting
+// --output-max-wait-ms for it.
+pub struct KeyGuard {
+    store: Arc<Mutex<ProcessedDataStore>>,
+    key: usize,
+    armed: bool,
+}
+
+impl KeyGuard {
+    pub fn new(store: Arc<Mutex<ProcessedDataStore>>, key: usize) -> Self {
+        KeyGuard {
+            store,
+            key,
+            armed: true,
+        }
+    }
+
+    // The paragraph was stored or cancelled
+    pub fn release(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        log::warn!(
+            "Pipeline: paragraph {} was dropped before it was done, the output skips it",
+            self.key
+        );
+        let key = self.key;
+        match self.store.try_lock() {
+            Ok(mut store) => store.cancel(key),
+            Err(_) => {
+                let store = self.store.clone();
+                if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                    handle.spawn(async move { store.lock().await.cancel(key) });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processed(key: usize) -> ProcessedData {
+        ProcessedData {
+            paragraph: format!("paragraph {}", key),
+            image_data: None,
+            audio_data: None,
+            paragraph_count: key,
+            subtitle_position: String::new(),
+            time_stamp: 0,
+            duration_ms: 0,
+            shutdown: false,
+            completed: true,
+            last_message: false,
+            chapter: None,
+            sentiment: None,
+            requested_by: None,
+        }
+    }
+
+    fn store(capacity: usize) -> ProcessedDataStore {
+        ProcessedDataStore::new(capacity, Duration::from_secs(60))
+    }
+
+    fn next_key(store: &mut ProcessedDataStore) -> Option<usize> {
+        match store.next_ready() {
+            NextOutput::Ready(data) => Some(data.paragraph_count),
+            NextOutput::Pending | NextOutput::Missing => None,
+        }
+    }
+
+    #[test]
+    fn outputs_in_key_order() {
+        let mut store = store(8);
+        let keys: Vec<usize> = (0..3).map(|_| store.allocate(Priority::Normal)).collect();
+        assert_eq!(keys, vec![0, 1, 2]);
+
+        store.insert(processed(1));
+        assert!(matches!(store.next_ready(), NextOutput::Pending));
+        store.insert(processed(0));
+        assert_eq!(next_key(&mut store), Some(0));
+        assert_eq!(next_key(&mut store), Some(1));
+        assert!(matches!(store.next_ready(), NextOutput::Pending));
+        assert_eq!(store.next_key(), 2);
+    }
+
+    #[test]
+    fn missing_without_reserved_keys() {
+        let mut store = store(8);
+        assert!(matches!(store.next_ready(), NextOutput::Missing));
+        assert_eq!(store.next_key(), 0);
+    }
+
+    #[test]
+    fn high_priority_goes_ahead_of_waiting_normal_keys() {
+        let mut store = store(8);
+        store.allocate(Priority::Normal);
+        store.allocate(Priority::Normal);
+        store.allocate(Priority::High);
+        store.allocate(Priority::High);
+        for key in 0..4 {
+            store.insert(processed(key));
+        }
+        let order: Vec<Option<usize>> = (0..4).map(|_| next_key(&mut store)).collect();
+        assert_eq!(order, vec![Some(2), Some(3), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn cancelled_key_is_skipped() {
+        let mut store = store(8);
+        store.allocate(Priority::Normal);
+        store.allocate(Priority::Normal);
+        store.cancel(0);
+        store.insert(processed(1));
+        assert_eq!(next_key(&mut store), Some(1));
+        assert_eq!(store.metrics().cancelled, 1);
+    }
+
+    #[test]
+    fn key_waiting_too_long_is_skipped() {
+        let mut store = ProcessedDataStore::new(8, Duration::ZERO);
+        store.allocate(Priority::Normal);
+        store.allocate(Priority::Normal);
+        store.insert(processed(1));
+        assert_eq!(next_key(&mut store), Some(1));
+        assert_eq!(store.metrics().skipped, 1);
+
+        // the skipped paragraph arriving late is dropped
+        store.insert(processed(0));
+        assert!(matches!(store.next_ready(), NextOutput::Missing));
+        assert_eq!(store.metrics().evicted, 1);
+    }
+
+    #[test]
+    fn full_store_evicts_the_first_in_output_order() {
+        let mut store = store(1);
+        for _ in 0..3 {
+            store.allocate(Priority::Normal);
+        }
+        store.insert(processed(1));
+        store.insert(processed(2));
+        // paragraph 1 and the paragraph 0 still waiting before it are gone
+        assert_eq!(store.metrics().evicted, 1);
+        assert_eq!(next_key(&mut store), Some(2));
+        assert!(matches!(store.next_ready(), NextOutput::Missing));
+    }
+
+    #[test]
+    fn resumed_store_continues_the_sequence() {
+        let mut store = store(8);
+        store.resume_at(5);
+        assert_eq!(store.next_key(), 5);
+        assert_eq!(store.allocate(Priority::Normal), 5);
+        assert_eq!(store.next_sequence(), 6);
+    }
+
+    #[test]
+    fn oldest_key_stays_behind_a_high_priority_output() {
+        let mut store = store(8);
+        store.allocate(Priority::Normal);
+        store.allocate(Priority::High);
+        store.insert(processed(1));
+        assert_eq!(next_key(&mut store), Some(1));
+        assert_eq!(store.oldest_key(), 0);
+        store.insert(processed(0));
+        assert_eq!(next_key(&mut store), Some(0));
+        assert_eq!(store.oldest_key(), 2);
+    }
+
+    #[test]
+    fn preview_only_for_waiting_keys() {
+        let mut store = store(8);
+        store.allocate(Priority::Normal);
+        store.preview(0, ImageBuffer::new(1, 1));
+        store.preview(1, ImageBuffer::new(1, 1));
+        assert!(store.take_preview(0).is_some());
+        assert!(store.take_preview(1).is_none());
+    }
+}
@@ -0,0 +1,262 @@
+/*
+ * safety_checker.rs
+ * -----------------
+ * CLIP based NSFW / unsafe image detection for generated images before they are
+ * sent to NDI or saved. Images are scored against a list of unsafe and safe concepts,
+ * and flagged when the unsafe concepts are more likely than the threshold.
+*/
+
+use anyhow::{anyhow, Error as E, Result};
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::VarBuilder;
+use candle_transformers::models::clip;
+use image::{
+    imageops::{resize, FilterType},
+    ImageBuffer, Rgb,
+};
+use log::{debug, info};
+use once_cell::sync::OnceCell;
+use std::sync::Mutex;
+use tokenizers::Tokenizer;
+
+const CLIP_REPO: &str = "openai/clip-vit-base-patch32";
+const CLIP_REVISION: &str = "refs/pr/15";
+
+// concepts that flag an image as unsafe
+const UNSAFE_CONCEPTS: [&str; 6] = [
+    "a photo containing nudity",
+    "a naked person",
+    "sexually explicit content",
+    "graphic violence",
+    "gore and blood",
+    "a disturbing and gruesome image",
+];
+
+// concepts that compete with the unsafe ones for a normal image
+const SAFE_CONCEPTS: [&str; 4] = [
+    "a safe for work image",
+    "a normal photo",
+    "an illustration",
+    "a person wearing clothes",
+];
+
+// Action to take when an image is flagged as unsafe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafetyAction {
+    Blur,
+    Regenerate,
+    Skip,
+}
+
+impl std::str::FromStr for SafetyAction {
+    type Err = anyhow::Error;
+
+    fn from_str(action: &str) -> Result<Self> {
+        match action.to_lowercase().as_str() {
+            "blur" => Ok(SafetyAction::Blur),
+            "regenerate" => Ok(SafetyAction::Regenerate),
+            "skip" => Ok(SafetyAction::Skip),
+            _ => Err(anyhow!(
+                "Invalid NSFW action {}, options are blur, regenerate, skip",
+                action
+            )),
+        }
+    }
+}
+
+// Result of checking a single image
+#[derive(Debug, Clone)]
+pub struct SafetyResult {
+    pub nsfw: bool,
+    pub score: f32,
+    pub concept: String,
+}
+
+pub struct SafetyChecker {
+    model: clip::ClipModel,
+    input_ids: Tensor,
+    device: Device,
+    image_size: usize,
+}
+
+// Load the CLIP model and tokenizer, also used for the image relevance scores. Returns the
+// image size the model takes.
+pub fn load_clip(device: &Device) -> Result<(clip::ClipModel, Tokenizer, usize)> {
+    use candle_hf_hub::{api::sync::Api, Repo, RepoType};
+
+    let api = Api::new()?;
+    let repo = api.repo(Repo::with_revision(
+        CLIP_REPO.to_string(),
+        RepoType::Model,
+        CLIP_REVISION.to_string(),
+    ));
+    let model_file = repo.get("model.safetensors")?;
+    let tokenizer_file = repo.get("tokenizer.json")?;
+
+    let config = clip::ClipConfig::vit_base_patch32();
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], DType::F32, device)? };
+    let model = clip::ClipModel::new(vb, &config)?;
+    let tokenizer = Tokenizer::from_file(tokenizer_file).map_err(E::msg)?;
+    Ok((model, tokenizer, config.image_size))
+}
+
+// Pixel tensor of an image for CLIP, resized to the model size and scaled to -1 to 1
+pub fn clip_pixels(
+    image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    image_size: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let size = image_size as u32;
+    let resized = resize(image, size, size, FilterType::Triangle);
+    Ok(
+        Tensor::from_vec(resized.into_raw(), (image_size, image_size, 3), device)?
+            .permute((2, 0, 1))?
+            .to_dtype(DType::F32)?
+            .affine(2. / 255., -1.)?
+            .unsqueeze(0)?,
+    )
+}
+
+// The CLIP model is loaded once and shared between pipeline tasks, a failed load is kept
+// too so it is not tried again for every image
+static SAFETY_CHECKER: OnceCell<Result<Mutex<SafetyChecker>, String>> = OnceCell::new();
+
+impl SafetyChecker {
+    pub fn new(cpu: bool) -> Result<Self> {
+        let device = candle_examples::device(cpu)?;
+        let (model, tokenizer, image_size) = load_clip(&device)?;
+
+        // tokenize all concepts once, padded to the longest
+        let pad_id = *tokenizer
+            .get_vocab(true)
+            .get("<|endoftext|>")
+            .ok_or_else(|| anyhow!("No pad token in the CLIP tokenizer"))?;
+        let mut tokens = Vec::new();
+        for concept in UNSAFE_CONCEPTS.iter().chain(SAFE_CONCEPTS.iter()) {
+            let encoding = tokenizer.encode(*concept, true).map_err(E::msg)?;
+            tokens.push(encoding.get_ids().to_vec());
+        }
+        let max_len = tokens.iter().map(|t| t.len()).max().unwrap_or(0);
+        for token_vec in tokens.iter_mut() {
+            token_vec.resize(max_len, pad_id);
+        }
+        let input_ids = Tensor::new(tokens, &device)?;
+
+        info!("Safety checker loaded CLIP model {}", CLIP_REPO);
+
+        Ok(SafetyChecker {
+            model,
+            input_ids,
+            device,
+            image_size,
+        })
+    }
+
+    // Score an image against the concepts
+    pub fn check(
+        &self,
+        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        threshold: f32,
+    ) -> Result<SafetyResult> {
+        let pixels = clip_pixels(image, self.image_size, &self.device)?;
+
+        let (_logits_per_text, logits_per_image) = self.model.forward(&pixels, &self.input_ids)?;
+        let probs = candle_nn::ops::softmax(&logits_per_image, D::Minus1)?
+            .squeeze(0)?
+            .to_vec1::<f32>()?;
+
+        let (unsafe_probs, _safe_probs) = probs.split_at(UNSAFE_CONCEPTS.len());
+        let score: f32 = unsafe_probs.iter().sum();
+        let (index, _) = unsafe_probs
+            .iter()
+            .enumerate()
+            .fold(
+                (0, f32::MIN),
+                |best, (i, p)| if *p > best.1 { (i, *p) } else { best },
+            );
+
+        Ok(SafetyResult {
+            nsfw: score >= threshold,
+            score,
+            concept: UNSAFE_CONCEPTS[index].to_string(),
+        })
+    }
+}
+
+// Check an image with the shared safety checker, loading the model on first use and
+// failing right away once the load has failed
+pub fn check_image(
+    image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    threshold: f32,
+    cpu: bool,
+) -> Result<SafetyResult> {
+    let checker = SAFETY_CHECKER
+        .get_or_init(|| match SafetyChecker::new(cpu) {
+            Ok(checker) => Ok(Mutex::new(checker)),
+            Err(e) => {
+                log::error!("Failed to load the safety checker: {}", e);
+                Err(e.to_string())
+            }
+        })
+        .as_ref()
+        .map_err(|e| anyhow!("Safety checker failed to load: {}", e))?;
+    let checker = checker
+        .lock()
+        .map_err(|e| anyhow!("Safety checker lock poisoned: {}", e))?;
+    checker.check(image, threshold)
+}
+
+// Heavy blur by down scaling and scaling back up, fast enough for live output
+pub fn blur_image(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let small = resize(
+        image,
+        (width / 48).max(1),
+        (height / 48).max(1),
+        FilterType::Triangle,
+    );
+    resize(&small, width, height, FilterType::Triangle)
+}
+
+// The images kept by the filter and the number that were flagged
+pub type FilteredImages = (Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>, usize);
+
+// Filter the images, returns the images to keep and the number that were flagged.
+// Flagged images are blurred for the blur action and removed otherwise,
+// if the checker fails all images are removed so nothing unchecked goes out.
+pub fn filter_images(
+    images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    action: SafetyAction,
+    threshold: f32,
+    cpu: bool,
+) -> FilteredImages {
+    let mut kept = Vec::with_capacity(images.len());
+    let mut flagged = 0;
+
+    for image in images {
+        match check_image(&image, threshold, cpu) {
+            Ok(result) if result.nsfw => {
+                flagged += 1;
+                log::warn!(
+                    "Safety checker flagged image as {} with score {:.3}, action {:?}",
+                    result.concept,
+                    result.score,
+                    action
+                );
+                if action == SafetyAction::Blur {
+                    kept.push(blur_image(&image));
+                }
+            }
+            Ok(result) => {
+                debug!("Safety checker passed image with score {:.3}", result.score);
+                kept.push(image);
+            }
+            Err(e) => {
+                flagged += 1;
+                log::error!("Safety checker failed, dropping image: {}", e);
+            }
+        }
+    }
+
+    (kept, flagged)
+}
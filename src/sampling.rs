@@ -0,0 +1,275 @@
+/*
+ * sampling.rs
+ * -----------
+ * Token sampling of the candle models with the options of the llama.cpp server. The
+ * candidates are cut down by top-k, typical-p, top-p and min-p in that order, then one is
+ * drawn from what is left at the temperature. A temperature of 0 always takes the most
+ * likely token.
+*/
+
+use crate::args::Args;
+use anyhow::Result;
+use candle_core::{DType, Tensor};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingConfig {
+    pub temperature: f64,
+    pub top_k: usize,      // 0 is off
+    pub top_p: f64,        // 1.0 is off
+    pub min_p: f64,        // 0.0 is off
+    pub typical_p: f64,    // 1.0 is off
+    pub seed: Option<u64>, // None is random per prompt
+}
+
+impl SamplingConfig {
+    pub fn new(temperature: f64, top_k: usize, top_p: f64, min_p: f64, typical_p: f64) -> Self {
+        SamplingConfig {
+            temperature,
+            top_k,
+            top_p,
+            min_p,
+            typical_p,
+            seed: None,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        SamplingConfig::new(
+            args.temperature as f64,
+            args.top_k,
+            args.top_p as f64,
+            args.min_p as f64,
+            args.typical_p as f64,
+        )
+        .with_seed(if args.llm_seed > 0 {
+            Some(args.llm_seed)
+        } else {
+            None
+        })
+    }
+
+    pub fn with_temperature(mut self, temperature: f64) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig::new(0.8, 0, 1.0, 0.0, 1.0)
+    }
+}
+
+// Token and probability
+type Candidate = (u32, f64);
+
+// Probabilities of the logits, highest first
+fn softmax(logits: &[(u32, f64)]) -> Vec<Candidate> {
+    let max = logits
+        .iter()
+        .map(|(_, logit)| *logit)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let mut candidates: Vec<Candidate> = logits
+        .iter()
+        .map(|(token, logit)| (*token, (logit - max).exp()))
+        .collect();
+    let sum: f64 = candidates.iter().map(|(_, p)| p).sum();
+    candidates.iter_mut().for_each(|(_, p)| *p /= sum);
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+    candidates
+}
+
+// The fewest candidates in order whose probabilities add up to the mass, at least one
+fn keep_mass(candidates: &mut Vec<Candidate>, mass: f64) {
+    let mut total = 0.0;
+    let mut keep = candidates.len();
+    for (index, (_, p)) in candidates.iter().enumerate() {
+        total += p;
+        if total >= mass {
+            keep = index + 1;
+            break;
+        }
+    }
+    candidates.truncate(keep.max(1));
+}
+
+// Locally typical sampling, the candidates whose surprise is closest to the entropy
+fn typical(candidates: &mut Vec<Candidate>, typical_p: f64) {
+    let entropy: f64 = candidates
+        .iter()
+        .filter(|(_, p)| *p > 0.0)
+        .map(|(_, p)| -p * p.ln())
+        .sum();
+    candidates.sort_by(|a, b| {
+        let score = |p: f64| (-p.ln() - entropy).abs();
+        score(a.1).total_cmp(&score(b.1))
+    });
+    keep_mass(candidates, typical_p);
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+}
+
+pub struct Sampler {
+    config: SamplingConfig,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, config: SamplingConfig) -> Self {
+        Sampler {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        let logits: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        let mut logits: Vec<(u32, f64)> = logits
+            .iter()
+            .enumerate()
+            .map(|(token, logit)| (token as u32, *logit as f64))
+            .collect();
+
+        if self.config.temperature <= 0.0 {
+            let (token, _) = logits
+                .iter()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .ok_or_else(|| anyhow::anyhow!("no logits to sample"))?;
+            return Ok(*token);
+        }
+
+        if self.config.top_k > 0 && self.config.top_k < logits.len() {
+            logits.sort_by(|a, b| b.1.total_cmp(&a.1));
+            logits.truncate(self.config.top_k);
+        }
+
+        // the filters work on the probabilities at temperature 1
+        let mut candidates = softmax(&logits);
+        if self.config.typical_p > 0.0 && self.config.typical_p < 1.0 {
+            typical(&mut candidates, self.config.typical_p);
+        }
+        if self.config.top_p > 0.0 && self.config.top_p < 1.0 {
+            keep_mass(&mut candidates, self.config.top_p);
+        }
+        if self.config.min_p > 0.0 {
+            let floor = candidates.first().map(|(_, p)| *p).unwrap_or(0.0) * self.config.min_p;
+            let kept = candidates.iter().filter(|(_, p)| *p >= floor).count();
+            candidates.truncate(kept.max(1));
+        }
+
+        // p^(1/temperature) is the probability at the temperature, relative to the first
+        let first = candidates.first().map(|(_, p)| p.ln()).unwrap_or(0.0);
+        let weights = WeightedIndex::new(
+            candidates
+                .iter()
+                .map(|(_, p)| ((p.ln() - first) / self.config.temperature).exp()),
+        )?;
+        Ok(candidates[weights.sample(&mut self.rng)].0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    fn candidates() -> Vec<Candidate> {
+        vec![(0, 0.5), (1, 0.3), (2, 0.2)]
+    }
+
+    fn tokens(candidates: &[Candidate]) -> Vec<u32> {
+        candidates.iter().map(|(token, _)| *token).collect()
+    }
+
+    // Logits of the probabilities
+    fn logits(probabilities: &[f32]) -> Tensor {
+        let logits: Vec<f32> = probabilities.iter().map(|p| p.ln()).collect();
+        Tensor::new(logits.as_slice(), &Device::Cpu).unwrap()
+    }
+
+    fn draws(config: SamplingConfig, probabilities: &[f32]) -> Vec<u32> {
+        let mut sampler = Sampler::new(42, config);
+        let logits = logits(probabilities);
+        (0..200).map(|_| sampler.sample(&logits).unwrap()).collect()
+    }
+
+    #[test]
+    fn softmax_sorts_the_probabilities() {
+        let candidates = softmax(&[(0, 0.0), (1, 3f64.ln())]);
+        assert_eq!(tokens(&candidates), vec![1, 0]);
+        assert!((candidates[0].1 - 0.75).abs() < 1e-9);
+        assert!((candidates[1].1 - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn keep_mass_keeps_the_fewest_reaching_it() {
+        for (mass, kept) in [(0.0, 1), (0.1, 1), (0.6, 2), (0.9, 3), (1.5, 3)] {
+            let mut candidates = candidates();
+            keep_mass(&mut candidates, mass);
+            assert_eq!(candidates.len(), kept, "mass {}", mass);
+        }
+    }
+
+    #[test]
+    fn typical_keeps_the_surprise_closest_to_the_entropy() {
+        // the entropy is 1.03 nats, the 0.3 candidate is the most typical
+        let mut kept = candidates();
+        typical(&mut kept, 0.2);
+        assert_eq!(tokens(&kept), vec![1]);
+
+        let mut kept = candidates();
+        typical(&mut kept, 0.5);
+        assert_eq!(tokens(&kept), vec![0, 1]);
+    }
+
+    #[test]
+    fn zero_temperature_takes_the_most_likely() {
+        let config = SamplingConfig::default().with_temperature(0.0);
+        assert!(draws(config, &[0.2, 0.5, 0.3])
+            .iter()
+            .all(|&token| token == 1));
+    }
+
+    #[test]
+    fn top_k_limits_the_candidates() {
+        let config = SamplingConfig::new(1.0, 1, 1.0, 0.0, 1.0);
+        assert!(draws(config, &[0.2, 0.5, 0.3])
+            .iter()
+            .all(|&token| token == 1));
+
+        let config = SamplingConfig::new(1.0, 2, 1.0, 0.0, 1.0);
+        let drawn = draws(config, &[0.2, 0.5, 0.3]);
+        assert!(drawn.iter().all(|&token| token != 0));
+        assert!(drawn.contains(&2));
+    }
+
+    #[test]
+    fn top_p_and_min_p_drop_the_tail() {
+        let config = SamplingConfig::new(1.0, 0, 0.6, 0.0, 1.0);
+        assert!(draws(config, &[0.5, 0.3, 0.2])
+            .iter()
+            .all(|&token| token != 2));
+
+        // a floor of half the most likely
+        let config = SamplingConfig::new(1.0, 0, 1.0, 0.5, 1.0);
+        let drawn = draws(config, &[0.5, 0.3, 0.2]);
+        assert!(drawn.iter().all(|&token| token != 2));
+        assert!(drawn.contains(&1));
+    }
+
+    #[test]
+    fn a_seed_repeats_the_draws() {
+        let config = SamplingConfig::new(1.0, 0, 1.0, 0.0, 1.0);
+        let probabilities = [0.25, 0.25, 0.25, 0.25];
+        assert_eq!(draws(config, &probabilities), draws(config, &probabilities));
+        let drawn = draws(config, &probabilities);
+        assert!((0..4).all(|token| drawn.contains(&token)));
+    }
+}
@@ -1,58 +1,241 @@
+/*
+ * sd_automatic.rs
+ * ---------------
+ * Automatic1111 stable diffusion web ui api with the sampler, steps, CFG, checkpoint and LoRA
+ * settings of the api, a health check of the server and retries of failed requests.
+*/
+
+use crate::external_apis::check_url;
 use crate::scale_image;
 use crate::stable_diffusion::SDConfig;
 use crate::stable_diffusion::StableDiffusionVersion;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use base64::engine::general_purpose;
 use base64::Engine;
 use image::ImageBuffer;
 use image::Rgb;
+use log::{error, info, warn};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-pub async fn sd_auto(
-    config: SDConfig,
-) -> Result<Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>, anyhow::Error> {
-    let client = Client::new();
+// The server is checked before the first request
+static HEALTH_CHECKED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone)]
+pub struct AutomaticOptions {
+    pub sampler: String,
+    pub steps: usize,   // 0 uses the steps of the sd config
+    pub cfg_scale: f64, // 0 uses the guidance scale of the sd config
+    pub model: String,  // empty picks the checkpoint for the sd version
+    pub loras: Vec<(String, f32)>,
+    pub retries: usize,
+    pub timeout: Duration,
+}
+
+impl AutomaticOptions {
+    pub fn new() -> Self {
+        AutomaticOptions {
+            sampler: "Euler a".to_string(),
+            steps: 0,
+            cfg_scale: 0.0,
+            model: String::new(),
+            loras: Vec::new(),
+            retries: 2,
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+impl Default for AutomaticOptions {
+    fn default() -> Self {
+        AutomaticOptions::new()
+    }
+}
+
+// LoRAs as name:weight separated by commas, the weight defaults to 1.0
+pub fn parse_loras(loras: &str) -> Vec<(String, f32)> {
+    loras
+        .split(',')
+        .map(|lora| lora.trim())
+        .filter(|lora| !lora.is_empty())
+        .map(|lora| match lora.rsplit_once(':') {
+            Some((name, weight)) => match weight.trim().parse::<f32>() {
+                Ok(weight) => (name.trim().to_string(), weight),
+                Err(_) => (lora.to_string(), 1.0),
+            },
+            None => (lora.to_string(), 1.0),
+        })
+        .collect()
+}
 
-    let model = match config.sd_version {
-        StableDiffusionVersion::Custom => config.custom_model.as_deref().unwrap_or("sd_xl_turbo_1.0.safetensors"),
+// The web ui loads LoRAs from <lora:name:weight> tags in the prompt
+pub fn lora_prompt(prompt: &str, loras: &[(String, f32)]) -> String {
+    loras
+        .iter()
+        .fold(prompt.to_string(), |prompt, (name, weight)| {
+            format!("{} <lora:{}:{}>", prompt, name, weight)
+        })
+}
+
+fn default_model(config: &SDConfig) -> &str {
+    match config.sd_version {
+        StableDiffusionVersion::Custom => config
+            .custom_model
+            .as_deref()
+            .filter(|model| !model.is_empty())
+            .unwrap_or("sd_xl_turbo_1.0.safetensors"),
         StableDiffusionVersion::V1_5 => "v1-5-pruned-emaonly.ckpt",
         StableDiffusionVersion::V2_1 => "v2-1_768-ema-pruned.ckpt",
         StableDiffusionVersion::Xl => "stabilityai/stable-diffusion-xl-1024-1.0.ckpt",
         StableDiffusionVersion::Turbo => "sd_xl_turbo_1.0_fp16.safetensors",
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AutomaticModel {
+    title: String,
+    model_name: String,
+}
+
+// Check the server is up and return the titles of its checkpoints
+pub async fn sd_auto_health(host: &str) -> Result<Vec<String>> {
+    check_url(host).map_err(|e| anyhow!(e))?;
+    let response = Client::new()
+        .get(format!("{}/sdapi/v1/sd-models", host.trim_end_matches('/')))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(|e| anyhow!("Automatic1111 server {} is not reachable: {}", host, e))?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Automatic1111 server {} health check failed with {}",
+            host,
+            response.status()
+        ));
+    }
+    let models: Vec<AutomaticModel> = response.json().await?;
+    Ok(models
+        .into_iter()
+        .flat_map(|model| [model.title, model.model_name])
+        .collect())
+}
+
+async fn check_health(host: &str, model: &str) {
+    match sd_auto_health(host).await {
+        Ok(models) => {
+            HEALTH_CHECKED.store(true, Ordering::SeqCst);
+            if !models.iter().any(|name| name.starts_with(model)) {
+                warn!(
+                    "Automatic1111 checkpoint {} not found on {}, available: {}",
+                    model,
+                    host,
+                    models.join(", ")
+                );
+            } else {
+                info!(
+                    "Automatic1111 server {} is up with checkpoint {}",
+                    host, model
+                );
+            }
+        }
+        Err(e) => error!("{}", e),
+    }
+}
+
+pub async fn sd_auto(
+    config: SDConfig,
+    host: &str,
+    options: &AutomaticOptions,
+) -> Result<Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>, anyhow::Error> {
+    check_url(host).map_err(|e| anyhow!(e))?;
+    let client = Client::new();
+
+    let model = if options.model.is_empty() {
+        default_model(&config).to_string()
+    } else {
+        options.model.clone()
+    };
+    if !HEALTH_CHECKED.load(Ordering::SeqCst) {
+        check_health(host, &model).await;
+    }
+
+    let steps = if options.steps > 0 {
+        options.steps
+    } else {
+        config.n_steps.unwrap_or(20)
+    };
+    let cfg_scale = if options.cfg_scale > 0.0 {
+        options.cfg_scale
+    } else {
+        config.guidance_scale.unwrap_or(3.0)
     };
 
     let payload = AutomaticPayload {
-        prompt: config.prompt,
-        negative_prompt: config.uncond_prompt,
-        steps: config.n_steps.unwrap_or(20),
+        prompt: lora_prompt(&config.prompt, &options.loras),
+        negative_prompt: config.uncond_prompt.clone(),
+        steps,
         width: config.width.unwrap_or(1280),
         height: config.height.unwrap_or(720),
-        cfg_scale: config.guidance_scale.unwrap_or(3.0),
-        sampler_index: "Euler a".to_string(),
-        seed:config.seed.unwrap_or_else(rand::random) as u64,
+        cfg_scale,
+        sampler_name: options.sampler.clone(),
+        seed: config.seed.unwrap_or_else(rand::random) as u64,
         n_iter: config.num_samples,
         batch_size: 1,
         override_settings: OverrideSettings {
-            sd_model_checkpoint: model.to_string(),
+            sd_model_checkpoint: model.clone(),
         },
     };
 
-    let response = client
-        .post("http://earth:7860/sdapi/v1/txt2img")
-        //.post("http://127.0.0.1:7860/sdapi/v1/txt2img")
-        .json(&payload)
-        .send()
-        .await?;
+    let mut attempt = 0;
+    let response_json: serde_json::Value = loop {
+        let result = async {
+            let response = client
+                .post(format!("{}/sdapi/v1/txt2img", host.trim_end_matches('/')))
+                .timeout(options.timeout)
+                .json(&payload)
+                .send()
+                .await?;
+            let status = response.status();
+            let response_json: serde_json::Value = response.json().await?;
+            if !status.is_success() {
+                return Err(anyhow!(
+                    "Automatic1111 request failed with {}: {}",
+                    status,
+                    response_json["detail"]
+                        .as_str()
+                        .or(response_json["error"].as_str())
+                        .unwrap_or("unknown error")
+                ));
+            }
+            Ok(response_json)
+        }
+        .await;
+
+        match result {
+            Ok(response_json) => break response_json,
+            Err(e) if attempt < options.retries => {
+                attempt += 1;
+                warn!("{}, retrying {}/{}", e, attempt, options.retries);
+                tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                check_health(host, &model).await;
+            }
+            Err(e) => return Err(e),
+        }
+    };
 
-    let response_json: serde_json::Value = response.json().await?;
-    let image_data = response_json["images"].as_array().unwrap();
+    let image_data = response_json["images"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Automatic1111 response has no images"))?;
 
     let mut images = Vec::new();
     for image_base64 in image_data {
-        let image_bytes = general_purpose::STANDARD
-            .decode(image_base64.as_str().unwrap())
-            .unwrap();
+        let image_bytes = general_purpose::STANDARD.decode(
+            image_base64
+                .as_str()
+                .ok_or_else(|| anyhow!("Automatic1111 image is not base64"))?,
+        )?;
         let image = image::load_from_memory(&image_bytes)?;
         let image_rgb8 = image.to_rgb8();
         images.push(image_rgb8);
@@ -86,7 +269,7 @@ struct AutomaticPayload {
     width: usize,
     height: usize,
     cfg_scale: f64,
-    sampler_index: String,
+    sampler_name: String,
     seed: u64,
     n_iter: usize,
     batch_size: usize,
@@ -0,0 +1,193 @@
+/*
+ * sd_comfyui.rs
+ * -------------
+ * ComfyUI image generation, queues a txt2img workflow on the ComfyUI server and
+ * downloads the images once the prompt is done.
+*/
+
+use crate::external_apis::check_url;
+use crate::scale_image;
+use crate::stable_diffusion::{SDConfig, StableDiffusionVersion};
+use anyhow::{anyhow, Result};
+use image::{ImageBuffer, Rgb};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+// How long to wait for ComfyUI to finish a prompt
+const COMFYUI_TIMEOUT: Duration = Duration::from_secs(300);
+const COMFYUI_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn checkpoint(config: &SDConfig) -> String {
+    match config.sd_version {
+        StableDiffusionVersion::Custom => config
+            .custom_model
+            .clone()
+            .unwrap_or_else(|| "sd_xl_turbo_1.0.safetensors".to_string()),
+        StableDiffusionVersion::V1_5 => "v1-5-pruned-emaonly.ckpt".to_string(),
+        StableDiffusionVersion::V2_1 => "v2-1_768-ema-pruned.ckpt".to_string(),
+        StableDiffusionVersion::Xl => "sd_xl_base_1.0.safetensors".to_string(),
+        StableDiffusionVersion::Turbo => "sd_xl_turbo_1.0_fp16.safetensors".to_string(),
+    }
+}
+
+// Default txt2img workflow in the ComfyUI API format
+pub fn default_workflow() -> Value {
+    json!({
+        "3": {
+            "class_type": "KSampler",
+            "inputs": {
+                "seed": "{{seed}}",
+                "steps": "{{steps}}",
+                "cfg": "{{cfg}}",
+                "sampler_name": "euler_ancestral",
+                "scheduler": "normal",
+                "denoise": 1.0,
+                "model": ["4", 0],
+                "positive": ["6", 0],
+                "negative": ["7", 0],
+                "latent_image": ["5", 0]
+            }
+        },
+        "4": {
+            "class_type": "CheckpointLoaderSimple",
+            "inputs": { "ckpt_name": "{{checkpoint}}" }
+        },
+        "5": {
+            "class_type": "EmptyLatentImage",
+            "inputs": { "width": "{{width}}", "height": "{{height}}", "batch_size": "{{batch_size}}" }
+        },
+        "6": {
+            "class_type": "CLIPTextEncode",
+            "inputs": { "text": "{{prompt}}", "clip": ["4", 1] }
+        },
+        "7": {
+            "class_type": "CLIPTextEncode",
+            "inputs": { "text": "{{negative_prompt}}", "clip": ["4", 1] }
+        },
+        "8": {
+            "class_type": "VAEDecode",
+            "inputs": { "samples": ["3", 0], "vae": ["4", 2] }
+        },
+        "9": {
+            "class_type": "SaveImage",
+            "inputs": { "filename_prefix": "rsllm", "images": ["8", 0] }
+        }
+    })
+}
+
+// Replace the {{placeholder}} strings of the workflow with the config values
+pub fn fill_workflow(workflow: &Value, config: &SDConfig, seed: u64) -> Value {
+    let value_for = |placeholder: &str| -> Option<Value> {
+        match placeholder {
+            "{{prompt}}" => Some(json!(config.prompt)),
+            "{{negative_prompt}}" => Some(json!(config.uncond_prompt)),
+            "{{seed}}" => Some(json!(seed)),
+            "{{steps}}" => Some(json!(config.n_steps.unwrap_or(20))),
+            "{{cfg}}" => Some(json!(config.guidance_scale.unwrap_or(3.0))),
+            "{{width}}" => Some(json!(config.width.unwrap_or(1280))),
+            "{{height}}" => Some(json!(config.height.unwrap_or(720))),
+            "{{batch_size}}" => Some(json!(config.num_samples.max(1))),
+            "{{checkpoint}}" => Some(json!(checkpoint(config))),
+            _ => None,
+        }
+    };
+
+    match workflow {
+        Value::String(s) => value_for(s).unwrap_or_else(|| workflow.clone()),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|v| fill_workflow(v, config, seed))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), fill_workflow(v, config, seed)))
+                .collect(),
+        ),
+        _ => workflow.clone(),
+    }
+}
+
+pub async fn sd_comfyui(
+    config: SDConfig,
+    host: &str,
+    workflow_file: &str,
+) -> Result<Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>> {
+    check_url(host).map_err(|e| anyhow!(e))?;
+    let client = Client::new();
+    let host = host.trim_end_matches('/');
+
+    let workflow = if workflow_file.is_empty() {
+        default_workflow()
+    } else {
+        let contents = std::fs::read_to_string(workflow_file)
+            .map_err(|e| anyhow!("Failed to read ComfyUI workflow {}: {}", workflow_file, e))?;
+        serde_json::from_str(&contents)?
+    };
+    let seed = config.seed.unwrap_or_else(rand::random).unsigned_abs() as u64;
+    let prompt = fill_workflow(&workflow, &config, seed);
+
+    let response: Value = client
+        .post(format!("{}/prompt", host))
+        .json(&json!({ "prompt": prompt, "client_id": "rsllm" }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let prompt_id = response["prompt_id"]
+        .as_str()
+        .ok_or_else(|| anyhow!("ComfyUI did not return a prompt id: {}", response))?
+        .to_string();
+
+    // poll the history until the prompt has outputs
+    let started = std::time::Instant::now();
+    let outputs = loop {
+        let history: Value = client
+            .get(format!("{}/history/{}", host, prompt_id))
+            .send()
+            .await?
+            .json()
+            .await?;
+        if let Some(outputs) = history[&prompt_id].get("outputs") {
+            break outputs.clone();
+        }
+        if started.elapsed() > COMFYUI_TIMEOUT {
+            return Err(anyhow!("ComfyUI prompt {} timed out", prompt_id));
+        }
+        tokio::time::sleep(COMFYUI_POLL_INTERVAL).await;
+    };
+
+    let mut images = Vec::new();
+    for output in outputs.as_object().into_iter().flat_map(|o| o.values()) {
+        for image in output["images"].as_array().into_iter().flatten() {
+            let bytes = client
+                .get(format!("{}/view", host))
+                .query(&[
+                    ("filename", image["filename"].as_str().unwrap_or_default()),
+                    ("subfolder", image["subfolder"].as_str().unwrap_or_default()),
+                    ("type", image["type"].as_str().unwrap_or("output")),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            images.push(image::load_from_memory(&bytes)?.to_rgb8());
+        }
+    }
+
+    Ok(images
+        .into_iter()
+        .map(|image| {
+            scale_image(
+                image,
+                config.scaled_width,
+                config.scaled_height,
+                config.image_position.clone(),
+            )
+        })
+        .collect())
+}
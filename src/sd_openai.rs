@@ -0,0 +1,114 @@
+/*
+ * sd_openai.rs
+ * ------------
+ * OpenAI image generation (DALL-E and gpt-image) for running without a local GPU.
+*/
+
+use crate::external_apis::check_url;
+use crate::scale_image;
+use crate::secrets::secret;
+use crate::stable_diffusion::SDConfig;
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose;
+use base64::Engine;
+use image::{ImageBuffer, Rgb};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::Value;
+
+const OPENAI_IMAGES_URL: &str = "https://api.openai.com/v1/images/generations";
+
+#[derive(Debug, Serialize)]
+struct ImageRequest {
+    model: String,
+    prompt: String,
+    n: usize,
+    size: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    quality: String,
+    // gpt-image models always return base64 and reject the response_format field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct OpenAIImageOptions {
+    pub model: String,
+    pub size: String,
+    pub quality: String,
+}
+
+pub async fn sd_openai(
+    config: SDConfig,
+    options: &OpenAIImageOptions,
+) -> Result<Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>> {
+    check_url(OPENAI_IMAGES_URL).map_err(|e| anyhow!(e))?;
+    let api_key = secret("OPENAI_API_KEY")
+        .ok_or_else(|| anyhow!("OPENAI_API_KEY is not set for the openai image backend"))?;
+    let client = Client::new();
+
+    // dall-e-3 only generates one image per request
+    let n = if options.model == "dall-e-3" {
+        1
+    } else {
+        config.num_samples.max(1)
+    };
+    let request = ImageRequest {
+        model: options.model.clone(),
+        prompt: config.prompt.clone(),
+        n,
+        size: options.size.clone(),
+        quality: options.quality.clone(),
+        response_format: if options.model.starts_with("dall-e") {
+            Some("b64_json".to_string())
+        } else {
+            None
+        },
+    };
+
+    let response = client
+        .post(OPENAI_IMAGES_URL)
+        .bearer_auth(api_key.expose())
+        .json(&request)
+        .send()
+        .await?;
+    let status = response.status();
+    let response_json: Value = response.json().await?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "OpenAI image request failed with {}: {}",
+            status,
+            response_json["error"]["message"]
+                .as_str()
+                .unwrap_or("unknown error")
+        ));
+    }
+
+    let mut images = Vec::new();
+    for data in response_json["data"].as_array().into_iter().flatten() {
+        let image_bytes = if let Some(b64) = data["b64_json"].as_str() {
+            general_purpose::STANDARD.decode(b64)?
+        } else if let Some(url) = data["url"].as_str() {
+            client.get(url).send().await?.bytes().await?.to_vec()
+        } else {
+            continue;
+        };
+        images.push(image::load_from_memory(&image_bytes)?.to_rgb8());
+    }
+
+    if images.is_empty() {
+        return Err(anyhow!("OpenAI image response had no images"));
+    }
+
+    Ok(images
+        .into_iter()
+        .map(|image| {
+            scale_image(
+                image,
+                config.scaled_width,
+                config.scaled_height,
+                config.image_position.clone(),
+            )
+        })
+        .collect())
+}
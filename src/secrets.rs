@@ -0,0 +1,325 @@
+/*
+ * secrets.rs
+ * ----------
+ * The tokens and API keys, OPENAI_API_KEY, TWITCH_AUTH and DISCORD_TOKEN, from the providers
+ * of --secrets-providers in order: the environment, a file of NAME=value lines that can be
+ * age encrypted, and the OS keychain. Every value handed out is remembered so the logger
+ * replaces it with [REDACTED], a token pasted into an error message or a request dump never
+ * reaches the terminal or the session summary. Encrypted files and the keychain need the
+ * secrets feature.
+*/
+
+use crate::args::Args;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+
+// The secrets rsllm reads, resolved and logged at startup
+pub const SECRET_NAMES: [&str; 3] = ["OPENAI_API_KEY", "TWITCH_AUTH", "DISCORD_TOKEN"];
+// Passphrase of a --secrets-file encrypted with age -p
+pub const PASSPHRASE_ENV: &str = "RSLLM_SECRETS_PASSPHRASE";
+
+const REDACTED: &str = "[REDACTED]";
+// Shorter values would redact ordinary words of the logs
+const MIN_REDACT_LEN: usize = 6;
+
+static STORE: Lazy<Mutex<SecretStore>> = Lazy::new(|| Mutex::new(SecretStore::new()));
+// The values the logger redacts
+static REDACT_VALUES: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+// A token or key, printing it with {} or {:?} shows [REDACTED]
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+
+    // The value itself, only for the request that needs it
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecretProvider {
+    Env,
+    File,
+    Keychain,
+}
+
+impl std::str::FromStr for SecretProvider {
+    type Err = String;
+
+    fn from_str(provider: &str) -> Result<Self, String> {
+        match provider.trim().to_lowercase().as_str() {
+            "env" | "environment" => Ok(SecretProvider::Env),
+            "file" => Ok(SecretProvider::File),
+            "keychain" | "keyring" => Ok(SecretProvider::Keychain),
+            _ => Err(format!("Invalid secrets provider {}", provider)),
+        }
+    }
+}
+
+impl SecretProvider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SecretProvider::Env => "env",
+            SecretProvider::File => "file",
+            SecretProvider::Keychain => "keychain",
+        }
+    }
+}
+
+// NAME=value lines, blank lines and # comments are skipped, quotes around a value removed
+fn parse_secrets(contents: &str) -> HashMap<String, Secret> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (name, value) = line.split_once('=')?;
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            Some((name.trim().to_string(), Secret::new(value)))
+        })
+        .filter(|(name, value)| !name.is_empty() && !value.expose().is_empty())
+        .collect()
+}
+
+fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(b"age-encryption.org/")
+        || contents.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
+}
+
+// An age file, binary or armored, with the identities of --secrets-identity or the
+// passphrase of RSLLM_SECRETS_PASSPHRASE
+#[cfg(feature = "secrets")]
+fn decrypt(contents: &[u8], identity_file: &str) -> Result<String> {
+    use std::io::Read;
+
+    let decryptor = age::Decryptor::new(age::armor::ArmoredReader::new(contents))
+        .map_err(|e| anyhow!("Not an age file: {}", e))?;
+    let mut plaintext = String::new();
+    if decryptor.is_scrypt() {
+        let passphrase = std::env::var(PASSPHRASE_ENV)
+            .map_err(|_| anyhow!("{} is not set for the passphrase", PASSPHRASE_ENV))?;
+        remember(&passphrase);
+        let identity = age::scrypt::Identity::new(passphrase.into());
+        decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))?
+            .read_to_string(&mut plaintext)?;
+    } else {
+        if identity_file.is_empty() {
+            return Err(anyhow!("--secrets-identity is needed to decrypt it"));
+        }
+        let identities = age::IdentityFile::from_file(identity_file.to_string())
+            .map_err(|e| anyhow!("Failed to read {}: {}", identity_file, e))?
+            .into_identities()?;
+        decryptor
+            .decrypt(identities.iter().map(|identity| identity.as_ref() as _))?
+            .read_to_string(&mut plaintext)?;
+    }
+    Ok(plaintext)
+}
+
+#[cfg(not(feature = "secrets"))]
+fn decrypt(_contents: &[u8], _identity_file: &str) -> Result<String> {
+    Err(anyhow!(
+        "Encrypted secrets need the secrets feature, build with --features secrets"
+    ))
+}
+
+// The password of the generic keychain item of the service with the name as the account
+#[cfg(feature = "secrets")]
+fn keychain_secret(service: &str, name: &str) -> Option<Secret> {
+    match keyring::Entry::new(service, name).and_then(|entry| entry.get_password()) {
+        Ok(value) if !value.is_empty() => Some(Secret::new(&value)),
+        Ok(_) | Err(keyring::Error::NoEntry) => None,
+        Err(e) => {
+            warn!("Failed to read {} from the keychain: {}", name, e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "secrets"))]
+fn keychain_secret(_service: &str, _name: &str) -> Option<Secret> {
+    None
+}
+
+#[derive(Debug, Clone)]
+pub struct SecretStore {
+    providers: Vec<SecretProvider>,
+    file: HashMap<String, Secret>,
+    service: String, // of the keychain items
+    resolved: HashMap<String, Option<(Secret, SecretProvider)>>,
+}
+
+impl Default for SecretStore {
+    fn default() -> Self {
+        SecretStore {
+            providers: vec![SecretProvider::Env],
+            file: HashMap::new(),
+            service: "rsllm".to_string(),
+            resolved: HashMap::new(),
+        }
+    }
+}
+
+impl SecretStore {
+    // The environment only, as before there were providers
+    pub fn new() -> Self {
+        SecretStore::default()
+    }
+
+    pub fn with_providers(mut self, providers: Vec<SecretProvider>) -> Self {
+        self.providers = providers;
+        self
+    }
+
+    pub fn with_file(mut self, contents: &str) -> Self {
+        self.file = parse_secrets(contents);
+        self
+    }
+
+    pub fn with_service(mut self, service: &str) -> Self {
+        self.service = service.to_string();
+        self
+    }
+
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let mut providers = Vec::new();
+        for provider in args
+            .secrets_providers
+            .split(',')
+            .filter(|p| !p.trim().is_empty())
+        {
+            let provider = provider.parse::<SecretProvider>().map_err(|e| anyhow!(e))?;
+            if provider == SecretProvider::Keychain && !cfg!(feature = "secrets") {
+                return Err(anyhow!(
+                    "The keychain provider needs the secrets feature, build with --features secrets"
+                ));
+            }
+            if !providers.contains(&provider) {
+                providers.push(provider);
+            }
+        }
+        let mut store = SecretStore::new()
+            .with_providers(providers)
+            .with_service(&args.secrets_service);
+        if !args.secrets_file.is_empty() {
+            if !store.providers.contains(&SecretProvider::File) {
+                warn!(
+                    "--secrets-file {} is not read, file is not in --secrets-providers",
+                    args.secrets_file
+                );
+                return Ok(store);
+            }
+            let contents = std::fs::read(&args.secrets_file)
+                .map_err(|e| anyhow!("Failed to read {}: {}", args.secrets_file, e))?;
+            let contents = if is_encrypted(&contents) {
+                decrypt(&contents, &args.secrets_identity)
+                    .map_err(|e| anyhow!("Failed to decrypt {}: {}", args.secrets_file, e))?
+            } else {
+                String::from_utf8_lossy(&contents).to_string()
+            };
+            store = store.with_file(&contents);
+        }
+        Ok(store)
+    }
+
+    fn lookup(&self, provider: SecretProvider, name: &str) -> Option<Secret> {
+        match provider {
+            SecretProvider::Env => std::env::var(name)
+                .ok()
+                .filter(|value| !value.is_empty())
+                .map(|value| Secret::new(&value)),
+            SecretProvider::File => self.file.get(name).cloned(),
+            SecretProvider::Keychain => keychain_secret(&self.service, name),
+        }
+    }
+
+    // The secret from the first provider that has it, asked once per name
+    pub fn get(&mut self, name: &str) -> Option<(Secret, SecretProvider)> {
+        if let Some(resolved) = self.resolved.get(name) {
+            return resolved.clone();
+        }
+        let resolved = self.providers.iter().find_map(|provider| {
+            self.lookup(*provider, name)
+                .map(|secret| (secret, *provider))
+        });
+        self.resolved.insert(name.to_string(), resolved.clone());
+        resolved
+    }
+}
+
+// A value for the logger to redact, a Twitch token also without its oauth: prefix
+fn remember(value: &str) {
+    let mut values = REDACT_VALUES.write().unwrap();
+    for value in [value, value.trim_start_matches("oauth:")] {
+        if value.len() >= MIN_REDACT_LEN && !values.iter().any(|known| known == value) {
+            values.push(value.to_string());
+        }
+    }
+    // the longest first, so a value containing another is redacted whole
+    values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+}
+
+// Read the secrets of --secrets-providers, the rest of the session asks secret() for them
+pub fn init_secrets(args: &Args) -> Result<()> {
+    let mut store = SecretStore::from_args(args)?;
+    for name in SECRET_NAMES {
+        if let Some((secret, provider)) = store.get(name) {
+            remember(secret.expose());
+            info!("{} is set from the {} provider", name, provider.name());
+        }
+    }
+    *STORE.lock().unwrap() = store;
+    Ok(())
+}
+
+// A secret by name, None when no provider has it
+pub fn secret(name: &str) -> Option<Secret> {
+    let secret = STORE.lock().unwrap().get(name).map(|(secret, _)| secret);
+    if let Some(secret) = &secret {
+        remember(secret.expose());
+    }
+    secret
+}
+
+// The provider a secret comes from, for the dry run
+pub fn secret_provider(name: &str) -> Option<SecretProvider> {
+    STORE
+        .lock()
+        .unwrap()
+        .get(name)
+        .map(|(_, provider)| provider)
+}
+
+// The text with every secret handed out replaced
+pub fn redact(text: &str) -> String {
+    let values = REDACT_VALUES.read().unwrap();
+    values
+        .iter()
+        .filter(|value| text.contains(value.as_str()))
+        .fold(text.to_string(), |text, value| {
+            text.replace(value, REDACTED)
+        })
+}
@@ -0,0 +1,623 @@
+/*
+ * segmenter.rs
+ * ------------
+ * Segmentation of the LLM token stream into paragraphs for the image and speech pipeline.
+ * The strategy is chosen with --segmenter, segmentation drives both the TTS pacing and
+ * how well the images match the text.
+*/
+
+use crate::args::Args;
+use crate::chapters::{similarity, topic_words};
+use crate::{clean_tts_text, count_tokens};
+use std::collections::HashSet;
+
+// Result of pushing a token into a segmenter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    // the token was added to the current paragraph
+    Token,
+    // the token completed a paragraph, head ends the paragraph and tail starts the next one
+    Paragraph {
+        paragraph: String,
+        head: String,
+        tail: String,
+        newline: bool,
+    },
+}
+
+pub trait Segmenter: Send {
+    // Add a token, returns the completed paragraph when the token ends one
+    fn push_token(&mut self, token: &str) -> Segment;
+
+    // Flush the remaining tokens at the end of the stream as the last paragraph
+    fn finish(&mut self) -> Option<String>;
+
+    // Number of paragraphs completed so far
+    fn paragraph_count(&self) -> usize;
+}
+
+// Create the segmenter selected by --segmenter
+pub fn create_segmenter(args: &Args) -> Box<dyn Segmenter> {
+    let max_tokens = if args.segment_tokens > 0 {
+        args.segment_tokens
+    } else {
+        args.sd_max_length
+    };
+    match args.segmenter.to_lowercase().as_str() {
+        "sentence" => Box::new(SentenceSegmenter::new(args.segment_sentences, max_tokens)),
+        "tokens" => Box::new(TokenCountSegmenter::new(max_tokens)),
+        "semantic" => Box::new(SemanticSegmenter::new(
+            args.segment_similarity,
+            args.segment_max_sentences,
+            max_tokens,
+        )),
+        "default" => Box::new(ParagraphSegmenter::new(args.sd_max_length)),
+        other => {
+            log::error!("Unknown segmenter {}, using the default segmenter", other);
+            Box::new(ParagraphSegmenter::new(args.sd_max_length))
+        }
+    }
+}
+
+// Find where to split a token at a paragraph boundary, the delimiter stays with the
+// first part except for a newline
+pub fn split_position(token: &str) -> usize {
+    let mut split_pos = token.len();
+    let mut found_delimiter = false;
+    for delimiter in ['\n', ',', '?', '!', ']'] {
+        if let Some(pos) = token.find(delimiter) {
+            let end_pos = if delimiter == '\n' { pos } else { pos + 1 };
+            split_pos = split_pos.min(end_pos);
+            found_delimiter = true;
+            break;
+        }
+    }
+    // Handle '.' and ' ' delimiters when nothing else matched
+    if !found_delimiter {
+        if let Some(pos) = token.find('.') {
+            split_pos = split_pos.min(pos + 1);
+        } else if let Some(pos) = token.find(' ') {
+            split_pos = split_pos.min(pos + 1);
+        }
+    }
+    split_pos
+}
+
+// Split a token at the paragraph boundary, returns (head, tail, newline) without the newline
+pub fn split_token(token: &str) -> (&str, &str, bool) {
+    split_at_position(token, split_position(token))
+}
+
+fn split_at_position(token: &str, split_pos: usize) -> (&str, &str, bool) {
+    let (mut first, mut second) = token.split_at(split_pos);
+    let mut newline = false;
+    if first.ends_with('\n') {
+        first = &first[..first.len() - 1];
+        newline = true;
+    } else if second.starts_with('\n') {
+        second = &second[1..];
+        newline = true;
+    }
+    (first, second, newline)
+}
+
+// Position after the first sentence end in the token, a newline is not kept with the head
+pub fn sentence_end_position(token: &str) -> Option<usize> {
+    let pos = token.find(['.', '?', '!', '\n'])?;
+    if token[pos..].starts_with('\n') {
+        Some(pos)
+    } else {
+        Some(pos + 1)
+    }
+}
+
+// Remove the markdown bold markers from a paragraph
+pub fn strip_markdown(paragraph: &str) -> String {
+    let mut paragraph = paragraph.to_string();
+    while paragraph.contains("**") {
+        paragraph = paragraph.replace("**", "");
+    }
+    paragraph
+}
+
+// Tokens of the paragraph being built, shared by the segmenters
+#[derive(Default)]
+struct ParagraphBuffer {
+    tokens: Vec<String>,
+    paragraph_count: usize,
+}
+
+impl ParagraphBuffer {
+    fn text(&self) -> String {
+        self.tokens.join("")
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    // Close the paragraph with the head of the token and start the next with the tail
+    fn complete(&mut self, head: &str, tail: &str, newline: bool) -> Segment {
+        self.tokens.push(head.to_string());
+        let paragraph = strip_markdown(&self.text());
+        let newline = newline || self.tokens.len() >= 80;
+
+        self.tokens.clear();
+        if !tail.is_empty() {
+            self.tokens.push(tail.to_string());
+        }
+        self.paragraph_count += 1;
+
+        Segment::Paragraph {
+            paragraph,
+            head: head.to_string(),
+            tail: tail.to_string(),
+            newline,
+        }
+    }
+
+    fn finish(&mut self) -> Option<String> {
+        let paragraph = self.text();
+        self.tokens.clear();
+
+        if clean_tts_text(&paragraph).is_empty() {
+            return None;
+        }
+
+        self.paragraph_count += 1;
+        Some(strip_markdown(&paragraph))
+    }
+}
+
+// The original segmentation, splits on newlines and punctuation once the paragraph
+// is long enough for the SD prompt
+pub struct ParagraphSegmenter {
+    buffer: ParagraphBuffer,
+    sd_max_length: usize,
+}
+
+impl ParagraphSegmenter {
+    pub fn new(sd_max_length: usize) -> Self {
+        ParagraphSegmenter {
+            buffer: ParagraphBuffer::default(),
+            sd_max_length,
+        }
+    }
+
+    // Check if the token ends the current paragraph
+    pub fn is_boundary(&self, token: &str) -> bool {
+        let text = self.buffer.text();
+        let token_len = count_tokens(&text);
+        let sentence_end = token.contains('.')
+            || token.contains('?')
+            || token.contains('\n')
+            || token.contains(']')
+            || token.contains('!');
+
+        (token.contains('\n') && !clean_tts_text(&text).is_empty())
+            || (token_len as f32 > self.sd_max_length as f32 / 1.8 && sentence_end)
+            || (token_len >= self.sd_max_length && token.contains(' '))
+    }
+}
+
+impl Segmenter for ParagraphSegmenter {
+    fn push_token(&mut self, token: &str) -> Segment {
+        if self.buffer.is_empty() || !self.is_boundary(token) {
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        }
+
+        let (head, tail, newline) = split_token(token);
+        self.buffer.complete(head, tail, newline)
+    }
+
+    fn finish(&mut self) -> Option<String> {
+        self.buffer.finish()
+    }
+
+    fn paragraph_count(&self) -> usize {
+        self.buffer.paragraph_count
+    }
+}
+
+// Splits after a fixed number of sentences, or earlier if the paragraph gets too long
+pub struct SentenceSegmenter {
+    buffer: ParagraphBuffer,
+    sentences: usize,
+    max_tokens: usize,
+    sentence_count: usize,
+}
+
+impl SentenceSegmenter {
+    pub fn new(sentences: usize, max_tokens: usize) -> Self {
+        SentenceSegmenter {
+            buffer: ParagraphBuffer::default(),
+            sentences: sentences.max(1),
+            max_tokens,
+            sentence_count: 0,
+        }
+    }
+}
+
+impl Segmenter for SentenceSegmenter {
+    fn push_token(&mut self, token: &str) -> Segment {
+        let Some(pos) = sentence_end_position(token) else {
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        };
+
+        let (head, tail, newline) = split_at_position(token, pos);
+        let text = format!("{}{}", self.buffer.text(), head);
+        if clean_tts_text(&text).is_empty() {
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        }
+
+        self.sentence_count += 1;
+        let too_long = count_tokens(&text) >= self.max_tokens;
+        if self.sentence_count < self.sentences && !too_long && !newline {
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        }
+
+        self.sentence_count = 0;
+        self.buffer.complete(head, tail, newline)
+    }
+
+    fn finish(&mut self) -> Option<String> {
+        self.sentence_count = 0;
+        self.buffer.finish()
+    }
+
+    fn paragraph_count(&self) -> usize {
+        self.buffer.paragraph_count
+    }
+}
+
+// Splits at the first word boundary after the token count is reached
+pub struct TokenCountSegmenter {
+    buffer: ParagraphBuffer,
+    max_tokens: usize,
+}
+
+impl TokenCountSegmenter {
+    pub fn new(max_tokens: usize) -> Self {
+        TokenCountSegmenter {
+            buffer: ParagraphBuffer::default(),
+            max_tokens: max_tokens.max(1),
+        }
+    }
+}
+
+impl Segmenter for TokenCountSegmenter {
+    fn push_token(&mut self, token: &str) -> Segment {
+        let Some(pos) = token.find('\n').or_else(|| token.find(char::is_whitespace)) else {
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        };
+
+        // a newline from the LLM ends the paragraph before the token count is reached
+        let (head, tail, newline) = split_at_position(token, pos);
+        let text = self.buffer.text();
+        let ends_paragraph = newline && !clean_tts_text(&format!("{}{}", text, head)).is_empty();
+        if count_tokens(&text) < self.max_tokens && !ends_paragraph {
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        }
+
+        self.buffer
+            .complete(head, tail.trim_start_matches(' '), newline)
+    }
+
+    fn finish(&mut self) -> Option<String> {
+        self.buffer.finish()
+    }
+
+    fn paragraph_count(&self) -> usize {
+        self.buffer.paragraph_count
+    }
+}
+
+// Groups sentences while they share topic words with the paragraph, a sentence on a
+// new topic starts a new paragraph so each image follows one subject
+pub struct SemanticSegmenter {
+    buffer: ParagraphBuffer,
+    threshold: f32,
+    max_sentences: usize,
+    max_tokens: usize,
+    sentence_start: usize,
+    sentence_count: usize,
+    paragraph_words: HashSet<String>,
+}
+
+impl SemanticSegmenter {
+    pub fn new(threshold: f32, max_sentences: usize, max_tokens: usize) -> Self {
+        SemanticSegmenter {
+            buffer: ParagraphBuffer::default(),
+            threshold,
+            max_sentences: max_sentences.max(1),
+            max_tokens,
+            sentence_start: 0,
+            sentence_count: 0,
+            paragraph_words: HashSet::new(),
+        }
+    }
+
+    // Text of the sentence being completed, from the end of the previous sentence
+    fn current_sentence(&self, head: &str) -> String {
+        let mut sentence = self.buffer.text()[self.sentence_start..].to_string();
+        sentence.push_str(head);
+        sentence
+    }
+}
+
+impl Segmenter for SemanticSegmenter {
+    fn push_token(&mut self, token: &str) -> Segment {
+        let Some(pos) = sentence_end_position(token) else {
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        };
+
+        let (head, tail, newline) = split_at_position(token, pos);
+        let sentence = self.current_sentence(head);
+        if clean_tts_text(&sentence).is_empty() {
+            // a newline right after a sentence still ends the paragraph
+            if newline && self.sentence_count > 0 {
+                self.sentence_count = 0;
+                self.sentence_start = 0;
+                self.paragraph_words.clear();
+                return self.buffer.complete(head, tail, newline);
+            }
+            self.buffer.tokens.push(token.to_string());
+            return Segment::Token;
+        }
+
+        let words = topic_words(&sentence);
+        let topic_shift = !words.is_empty()
+            && !self.paragraph_words.is_empty()
+            && similarity(&words, &self.paragraph_words) < self.threshold;
+        let too_long = count_tokens(&self.buffer.text()) >= self.max_tokens
+            || self.sentence_count >= self.max_sentences;
+
+        // a newline from the LLM or a single overlong sentence ends the paragraph here
+        if newline || (too_long && self.sentence_count == 0) {
+            self.sentence_count = 0;
+            self.sentence_start = 0;
+            self.paragraph_words.clear();
+            return self.buffer.complete(head, tail, newline);
+        }
+
+        if self.sentence_count > 0 && (topic_shift || too_long) {
+            // the new sentence starts the next paragraph, close the paragraph before it
+            let text = self.buffer.text();
+            let paragraph = strip_markdown(&text[..self.sentence_start]);
+            let rest = format!("{}{}", &text[self.sentence_start..], token);
+
+            self.buffer.tokens.clear();
+            self.buffer.tokens.push(rest);
+            self.buffer.paragraph_count += 1;
+
+            self.paragraph_words = words;
+            self.sentence_count = 1;
+            self.sentence_start = self.buffer.text().len() - tail.len();
+            return Segment::Paragraph {
+                paragraph,
+                head: String::new(),
+                tail: token.to_string(),
+                newline: false,
+            };
+        }
+
+        self.paragraph_words.extend(words);
+        self.sentence_count += 1;
+
+        self.buffer.tokens.push(token.to_string());
+        self.sentence_start = self.buffer.text().len() - tail.len();
+        Segment::Token
+    }
+
+    fn finish(&mut self) -> Option<String> {
+        self.sentence_start = 0;
+        self.sentence_count = 0;
+        self.paragraph_words.clear();
+        self.buffer.finish()
+    }
+
+    fn paragraph_count(&self) -> usize {
+        self.buffer.paragraph_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_all(segmenter: &mut dyn Segmenter, tokens: &[&str]) -> Vec<String> {
+        tokens
+            .iter()
+            .filter_map(|token| match segmenter.push_token(token) {
+                Segment::Paragraph { paragraph, .. } => Some(paragraph),
+                Segment::Token => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_position_at_delimiters() {
+        assert_eq!(split_position("Hello, world"), 6);
+        assert_eq!(split_position("Why? Because"), 4);
+        assert_eq!(split_position("end.\nNext"), 4);
+        assert_eq!(split_position("end. Next"), 4);
+        assert_eq!(split_position("two words"), 4);
+        assert_eq!(split_position("word"), 4);
+    }
+
+    #[test]
+    fn split_token_drops_the_newline() {
+        assert_eq!(split_token("foo\nbar"), ("foo", "bar", true));
+        assert_eq!(split_token("foo, bar"), ("foo,", " bar", false));
+        assert_eq!(split_token("\nbar"), ("", "bar", true));
+    }
+
+    #[test]
+    fn sentence_end_keeps_punctuation_not_newline() {
+        assert_eq!(sentence_end_position("done. Next"), Some(5));
+        assert_eq!(sentence_end_position("done\nNext"), Some(4));
+        assert_eq!(sentence_end_position("going on"), None);
+    }
+
+    #[test]
+    fn strip_markdown_removes_bold() {
+        assert_eq!(strip_markdown("**bold** text"), "bold text");
+        assert_eq!(strip_markdown("****"), "");
+    }
+
+    #[test]
+    fn paragraph_segmenter_splits_on_newline() {
+        let mut segmenter = ParagraphSegmenter::new(100);
+        let paragraphs = push_all(&mut segmenter, &["Hello", " there\n", "Next"]);
+        assert_eq!(paragraphs, vec!["Hello there"]);
+        assert_eq!(segmenter.finish(), Some("Next".to_string()));
+        assert_eq!(segmenter.paragraph_count(), 2);
+    }
+
+    #[test]
+    fn sentence_segmenter_groups_sentences() {
+        let mut segmenter = SentenceSegmenter::new(2, 100);
+        let paragraphs = push_all(&mut segmenter, &["One.", " Two.", " Three."]);
+        assert_eq!(paragraphs, vec!["One. Two."]);
+        assert_eq!(segmenter.finish(), Some(" Three.".to_string()));
+    }
+
+    #[test]
+    fn token_count_segmenter_splits_after_max_tokens() {
+        let mut segmenter = TokenCountSegmenter::new(2);
+        let paragraphs = push_all(&mut segmenter, &["one", " two", " three"]);
+        assert_eq!(paragraphs, vec!["one two"]);
+        assert_eq!(segmenter.finish(), Some("three".to_string()));
+    }
+
+    #[test]
+    fn token_count_segmenter_splits_on_newline_before_max_tokens() {
+        let mut segmenter = TokenCountSegmenter::new(100);
+        let paragraphs = push_all(&mut segmenter, &["Hello", " world", ".\n", "Next"]);
+        assert_eq!(paragraphs, vec!["Hello world."]);
+        assert_eq!(segmenter.finish(), Some("Next".to_string()));
+    }
+
+    #[test]
+    fn semantic_segmenter_splits_on_a_topic_shift() {
+        let mut segmenter = SemanticSegmenter::new(0.2, 10, 1000);
+        let paragraphs = push_all(
+            &mut segmenter,
+            &[
+                "Rockets launch satellites.",
+                " Rockets carry satellites.",
+                " Pasta tastes delicious.",
+            ],
+        );
+        assert_eq!(
+            paragraphs,
+            vec!["Rockets launch satellites. Rockets carry satellites."]
+        );
+        assert_eq!(
+            segmenter.finish(),
+            Some(" Pasta tastes delicious.".to_string())
+        );
+        assert_eq!(segmenter.paragraph_count(), 2);
+    }
+
+    #[test]
+    fn semantic_segmenter_keeps_sentences_sharing_words() {
+        let mut segmenter = SemanticSegmenter::new(0.2, 10, 1000);
+        let paragraphs = push_all(
+            &mut segmenter,
+            &[
+                "Rockets launch satellites.",
+                " Rockets carry satellites.",
+                " Satellites orbit rockets.",
+            ],
+        );
+        assert!(paragraphs.is_empty());
+        assert_eq!(
+            segmenter.finish(),
+            Some(
+                "Rockets launch satellites. Rockets carry satellites. Satellites orbit rockets."
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn semantic_segmenter_cuts_after_max_sentences() {
+        let mut segmenter = SemanticSegmenter::new(0.0, 2, 1000);
+        let paragraphs = push_all(
+            &mut segmenter,
+            &["Rockets fly.", " Rockets fly.", " Rockets fly."],
+        );
+        assert_eq!(paragraphs, vec!["Rockets fly. Rockets fly."]);
+        assert_eq!(segmenter.finish(), Some(" Rockets fly.".to_string()));
+    }
+
+    #[test]
+    fn semantic_segmenter_cuts_after_max_tokens() {
+        let mut segmenter = SemanticSegmenter::new(0.0, 10, 3);
+        let paragraphs = push_all(&mut segmenter, &["Rockets fly.", " Rockets fly."]);
+        assert_eq!(paragraphs, vec!["Rockets fly."]);
+        assert_eq!(segmenter.finish(), Some(" Rockets fly.".to_string()));
+
+        // a single sentence over the limit ends the paragraph with it
+        let paragraphs = push_all(
+            &mut segmenter,
+            &["Rockets", " launch", " satellites", " today."],
+        );
+        assert_eq!(paragraphs, vec!["Rockets launch satellites today."]);
+        assert_eq!(segmenter.finish(), None);
+    }
+
+    #[test]
+    fn semantic_segmenter_splits_on_newline() {
+        let mut segmenter = SemanticSegmenter::new(0.2, 10, 1000);
+        let paragraphs = push_all(
+            &mut segmenter,
+            &[
+                "Rockets launch satellites.\n",
+                "Rockets fly.",
+                "\n",
+                "Rockets",
+            ],
+        );
+        assert_eq!(
+            paragraphs,
+            vec!["Rockets launch satellites.", "Rockets fly."]
+        );
+        assert_eq!(segmenter.finish(), Some("Rockets".to_string()));
+        assert_eq!(segmenter.paragraph_count(), 3);
+    }
+
+    #[test]
+    fn semantic_segmenter_splits_multi_byte_text_at_the_sentence() {
+        let mut segmenter = SemanticSegmenter::new(0.2, 10, 1000);
+        let paragraphs = push_all(
+            &mut segmenter,
+            &[
+                "Raketen fliegen über",
+                " Köln. Crème",
+                " brûlée schmeckt köstlich.",
+                " Crème brûlée.",
+            ],
+        );
+        assert_eq!(paragraphs, vec!["Raketen fliegen über Köln."]);
+        assert_eq!(
+            segmenter.finish(),
+            Some(" Crème brûlée schmeckt köstlich. Crème brûlée.".to_string())
+        );
+    }
+
+    #[test]
+    fn finish_skips_an_empty_paragraph() {
+        let mut segmenter = TokenCountSegmenter::new(100);
+        assert_eq!(segmenter.finish(), None);
+        assert_eq!(segmenter.paragraph_count(), 0);
+    }
+}
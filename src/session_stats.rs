@@ -0,0 +1,282 @@
+/*
+ * session_stats.rs
+ * ----------------
+ * Totals of the whole run, printed and logged once when the show ends. The LLM iterations,
+ * the pipeline and the logger add to the counters as they go: tokens and generation time,
+ * paragraphs, images, seconds of speech, TTS characters and the error and warning messages.
+ * The summary prices the paid API use with the --cost-* rates and lists the messages logged
+ * most often.
+*/
+
+use crate::args::Args;
+use crate::event_log::log_event;
+use crate::secrets::redact;
+use log::{Level, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+static SESSION: Lazy<Mutex<SessionStats>> = Lazy::new(|| Mutex::new(SessionStats::new()));
+static LOGGED_MESSAGES: Lazy<Mutex<HashMap<String, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static SUMMARY_LOGGED: AtomicBool = AtomicBool::new(false);
+
+// Messages listed in the summary and the length they are cut to
+const TOP_ERRORS: usize = 10;
+const MESSAGE_MAX_CHARS: usize = 100;
+
+struct SessionStats {
+    started: Instant,
+    iterations: u64,
+    tokens: u64,
+    cached_tokens: u64,
+    api_tokens: u64, // generated by a remote API, priced by --cost-per-1k-tokens
+    generation_secs: f64,
+    generations: u64, // iterations with generated tokens
+    tps_min: Option<f64>,
+    tps_max: f64,
+    tps_sum: f64,
+    paragraphs: u64,
+    images: u64,
+    api_images: u64, // made by the OpenAI image backend
+    audio_ms: u64,
+    tts_chars: u64,
+    api_tts_chars: u64, // spoken by OpenAI TTS
+}
+
+impl SessionStats {
+    fn new() -> Self {
+        SessionStats {
+            started: Instant::now(),
+            iterations: 0,
+            tokens: 0,
+            cached_tokens: 0,
+            api_tokens: 0,
+            generation_secs: 0.0,
+            generations: 0,
+            tps_min: None,
+            tps_max: 0.0,
+            tps_sum: 0.0,
+            paragraphs: 0,
+            images: 0,
+            api_images: 0,
+            audio_ms: 0,
+            tts_chars: 0,
+            api_tts_chars: 0,
+        }
+    }
+}
+
+// An LLM iteration, cached answers are replayed and count apart from the generated tokens
+pub fn record_generation(tokens: usize, secs: f64, cached: bool, api: bool) {
+    let mut session = SESSION.lock().unwrap();
+    session.iterations += 1;
+    if cached {
+        session.cached_tokens += tokens as u64;
+        return;
+    }
+    session.tokens += tokens as u64;
+    if api {
+        session.api_tokens += tokens as u64;
+    }
+    if tokens == 0 || secs <= 0.0 {
+        return;
+    }
+    let tps = tokens as f64 / secs;
+    session.generation_secs += secs;
+    session.generations += 1;
+    session.tps_sum += tps;
+    session.tps_min = Some(session.tps_min.map_or(tps, |min| min.min(tps)));
+    session.tps_max = session.tps_max.max(tps);
+}
+
+pub fn record_paragraph() {
+    SESSION.lock().unwrap().paragraphs += 1;
+}
+
+pub fn record_images(count: usize, backend: &str) {
+    let mut session = SESSION.lock().unwrap();
+    session.images += count as u64;
+    if backend == "openai" {
+        session.api_images += count as u64;
+    }
+}
+
+pub fn record_audio(duration_ms: u64) {
+    SESSION.lock().unwrap().audio_ms += duration_ms;
+}
+
+pub fn record_tts(chars: usize, api: bool) {
+    let mut session = SESSION.lock().unwrap();
+    session.tts_chars += chars as u64;
+    if api {
+        session.api_tts_chars += chars as u64;
+    }
+}
+
+// Numbers in a message are masked so the repeats of one failure count together
+fn message_key(level: Level, message: &str) -> String {
+    let masked: String = message
+        .chars()
+        .map(|c| if c.is_ascii_digit() { '#' } else { c })
+        .take(MESSAGE_MAX_CHARS)
+        .collect();
+    format!("{} {}", level, masked)
+}
+
+fn record_message(level: Level, message: &str) {
+    *LOGGED_MESSAGES
+        .lock()
+        .unwrap()
+        .entry(message_key(level, message))
+        .or_insert(0) += 1;
+}
+
+// Logger counting the errors and warnings on their way to env_logger, with the secrets
+// redacted
+struct CountingLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for CountingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        // tokens and keys never reach the terminal or the summary
+        let message = record.args().to_string();
+        let redacted = redact(&message);
+        if record.level() <= Level::Warn {
+            record_message(record.level(), &redacted);
+        }
+        if redacted == message {
+            self.inner.log(record);
+        } else {
+            self.inner.log(
+                &Record::builder()
+                    .args(format_args!("{}", redacted))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Install env_logger behind the counting logger, the RUST_LOG filter still applies
+pub fn init_logger() {
+    // the session runtime counts from here
+    Lazy::force(&SESSION);
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter();
+    if log::set_boxed_logger(Box::new(CountingLogger { inner })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+fn top_errors() -> Vec<Value> {
+    let messages = LOGGED_MESSAGES.lock().unwrap();
+    let mut top: Vec<(&String, &u64)> = messages.iter().collect();
+    top.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    top.into_iter()
+        .take(TOP_ERRORS)
+        .map(|(message, count)| json!({ "message": message, "count": count }))
+        .collect()
+}
+
+pub fn session_summary(args: &Args) -> Value {
+    let session = SESSION.lock().unwrap();
+    let token_cost = session.api_tokens as f64 / 1000.0 * args.cost_per_1k_tokens;
+    let image_cost = session.api_images as f64 * args.cost_per_image;
+    let tts_cost = session.api_tts_chars as f64 / 1000.0 * args.cost_per_1k_tts_chars;
+    json!({
+        "runtime_secs": session.started.elapsed().as_secs(),
+        "iterations": session.iterations,
+        "tokens": session.tokens,
+        "cached_tokens": session.cached_tokens,
+        "tokens_per_second": {
+            "overall": if session.generation_secs > 0.0 {
+                session.tokens as f64 / session.generation_secs
+            } else {
+                0.0
+            },
+            // of the iterations, a slow start weighs as much as a long answer
+            "mean": if session.generations > 0 {
+                session.tps_sum / session.generations as f64
+            } else {
+                0.0
+            },
+            "min": session.tps_min.unwrap_or(0.0),
+            "max": session.tps_max,
+        },
+        "paragraphs": session.paragraphs,
+        "images": session.images,
+        "audio_minutes": session.audio_ms as f64 / 60_000.0,
+        "tts_chars": session.tts_chars,
+        "api_cost": {
+            "tokens": token_cost,
+            "images": image_cost,
+            "tts": tts_cost,
+            "total": token_cost + image_cost + tts_cost,
+        },
+        "top_errors": top_errors(),
+    })
+}
+
+// Print the summary and add it to the event log, only the first call of the run does
+pub fn log_session_summary(args: &Args) {
+    if SUMMARY_LOGGED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let summary = session_summary(args);
+    println!("\n============ SESSION SUMMARY ==========");
+    println!(
+        "{} iterations in {}s, {} tokens ({} cached) @ {:.2}tps (mean {:.2}, min {:.2}, max {:.2})",
+        summary["iterations"],
+        summary["runtime_secs"],
+        summary["tokens"],
+        summary["cached_tokens"],
+        summary["tokens_per_second"]["overall"]
+            .as_f64()
+            .unwrap_or(0.0),
+        summary["tokens_per_second"]["mean"].as_f64().unwrap_or(0.0),
+        summary["tokens_per_second"]["min"].as_f64().unwrap_or(0.0),
+        summary["tokens_per_second"]["max"].as_f64().unwrap_or(0.0),
+    );
+    println!(
+        "{} paragraphs, {} images, {:.2} minutes of audio, {} TTS characters",
+        summary["paragraphs"],
+        summary["images"],
+        summary["audio_minutes"].as_f64().unwrap_or(0.0),
+        summary["tts_chars"],
+    );
+    println!(
+        "API cost {:.4} (tokens {:.4}, images {:.4}, tts {:.4})",
+        summary["api_cost"]["total"].as_f64().unwrap_or(0.0),
+        summary["api_cost"]["tokens"].as_f64().unwrap_or(0.0),
+        summary["api_cost"]["images"].as_f64().unwrap_or(0.0),
+        summary["api_cost"]["tts"].as_f64().unwrap_or(0.0),
+    );
+    if let Some(errors) = summary["top_errors"].as_array().filter(|e| !e.is_empty()) {
+        println!("Top errors:");
+        for error in errors {
+            println!(
+                "  {:>5} {}",
+                error["count"],
+                error["message"].as_str().unwrap_or_default()
+            );
+        }
+    }
+    println!("=======================================");
+    log_event("pipeline", "session_summary", summary);
+}
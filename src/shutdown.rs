@@ -0,0 +1,203 @@
+/*
+ * shutdown.rs
+ * -----------
+ * Shutdown sequence and maintenance schedule from the --shutdown-config JSON file. The
+ * sequence is the goodbye announcement, the image prompt it is shown with and an outro music
+ * file played after it. The schedule has daily, weekday or one time entries that shut the
+ * channel down or restart it, a restart runs the same command line again so with a
+ * checkpoint file the show carries on where it stopped.
+*/
+
+use crate::args::Args;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use log::{error, info};
+use serde::Deserialize;
+
+// Exit code when the process can't run itself again, for a service manager to restart it
+pub const RESTART_EXIT_CODE: i32 = 75;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownAction {
+    Shutdown,
+    Restart,
+}
+
+impl std::str::FromStr for ShutdownAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, String> {
+        match action.trim().to_lowercase().as_str() {
+            "shutdown" | "stop" => Ok(ShutdownAction::Shutdown),
+            "restart" => Ok(ShutdownAction::Restart),
+            _ => Err(format!("Invalid shutdown action {}", action)),
+        }
+    }
+}
+
+// "04:00" every day, with days only on those weekdays, or "2026-10-20 04:00" once
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleEntry {
+    pub at: String,
+    pub action: ShutdownAction,
+    #[serde(default)]
+    pub days: Vec<String>, // mon to sun, empty is every day
+}
+
+impl ScheduleEntry {
+    // The first time of the entry after the time
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let at = self.at.trim();
+        if let Ok(once) = NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M") {
+            let once = Local.from_local_datetime(&once).earliest()?;
+            return (once > after).then_some(once);
+        }
+
+        let time = NaiveTime::parse_from_str(at, "%H:%M").ok()?;
+        let days: Vec<String> = self
+            .days
+            .iter()
+            .map(|day| day.trim().to_lowercase().chars().take(3).collect())
+            .collect();
+        (0..8)
+            .map(|offset| after.date_naive() + Duration::days(offset))
+            .filter(|date: &NaiveDate| {
+                let weekday = date.weekday().to_string().to_lowercase();
+                days.is_empty() || days.contains(&weekday)
+            })
+            .filter_map(|date| Local.from_local_datetime(&date.and_time(time)).earliest())
+            .find(|time| *time > after)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub announcement: String, // {persona} is the name of the persona
+    pub restart_announcement: String,
+    pub image_prompt: String, // empty is the image prompt of the persona
+    pub outro_music: String,  // wav or mp3 played after the announcement, empty is none
+    pub schedule: Vec<ScheduleEntry>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            announcement: "{persona} is Shutting Down the AI Channel, goodbye!".to_string(),
+            restart_announcement:
+                "{persona} is restarting the AI Channel for maintenance, back in a moment!"
+                    .to_string(),
+            image_prompt: String::new(),
+            outro_music: String::new(),
+            schedule: Vec::new(),
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read shutdown config {}: {}", path, e))?;
+        let config: ShutdownConfig = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Failed to parse shutdown config {}: {}", path, e))?;
+        for entry in &config.schedule {
+            if entry.next_after(Local::now()).is_none() {
+                error!("Shutdown schedule entry {} never happens", entry.at);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        if args.shutdown_config.is_empty() {
+            return ShutdownConfig::default();
+        }
+        ShutdownConfig::load(&args.shutdown_config).unwrap_or_else(|e| {
+            error!("{}, using the default shutdown", e);
+            ShutdownConfig::default()
+        })
+    }
+
+    pub fn announcement(&self, action: ShutdownAction, persona: &str) -> String {
+        let text = match action {
+            ShutdownAction::Shutdown => &self.announcement,
+            ShutdownAction::Restart => &self.restart_announcement,
+        };
+        text.replace("{persona}", persona)
+    }
+
+    // The closing image prompt, the persona's when not set
+    pub fn image_prompt<'a>(&'a self, persona_image_prompt: &'a str) -> &'a str {
+        if self.image_prompt.is_empty() {
+            persona_image_prompt
+        } else {
+            &self.image_prompt
+        }
+    }
+}
+
+// The next scheduled shutdown or restart
+pub struct ShutdownSchedule {
+    entries: Vec<ScheduleEntry>,
+    next: Option<(DateTime<Local>, ShutdownAction)>,
+}
+
+impl ShutdownSchedule {
+    pub fn new(entries: Vec<ScheduleEntry>, now: DateTime<Local>) -> Self {
+        let mut schedule = ShutdownSchedule {
+            entries,
+            next: None,
+        };
+        schedule.plan(now);
+        schedule
+    }
+
+    fn plan(&mut self, after: DateTime<Local>) {
+        self.next = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.next_after(after).map(|at| (at, entry.action)))
+            .min_by_key(|(at, _)| *at);
+        if let Some((at, action)) = self.next {
+            info!(
+                "Next scheduled {:?} at {}",
+                action,
+                at.format("%Y-%m-%d %H:%M")
+            );
+        }
+    }
+
+    // The action once its time has come, the entry after it is planned next
+    pub fn due(&mut self, now: DateTime<Local>) -> Option<ShutdownAction> {
+        let (at, action) = self.next?;
+        if now < at {
+            return None;
+        }
+        self.plan(now);
+        Some(action)
+    }
+}
+
+// Run the same command line again in place of this process
+pub fn restart_process() -> ! {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match std::env::current_exe() {
+        Ok(exe) => {
+            info!("Restarting {} {}", exe.display(), args.join(" "));
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                let e = std::process::Command::new(&exe).args(&args).exec();
+                error!("Failed to restart {}: {}", exe.display(), e);
+            }
+            #[cfg(not(unix))]
+            match std::process::Command::new(&exe).args(&args).spawn() {
+                Ok(_) => std::process::exit(0),
+                Err(e) => error!("Failed to restart {}: {}", exe.display(), e),
+            }
+        }
+        Err(e) => error!("Failed to find the executable to restart: {}", e),
+    }
+    std::process::exit(RESTART_EXIT_CODE);
+}
@@ -1,3 +1,4 @@
+use crate::run_record::record_model_files;
 use crate::scale_image;
 use crate::truncate_tokens;
 use candle_transformers::models::stable_diffusion;
@@ -5,8 +6,14 @@ use candle_transformers::models::stable_diffusion;
 use anyhow::{Error as E, Result};
 use candle_core::{DType, Device, IndexOp, Module, Tensor, D};
 use image::{ImageBuffer, Rgb};
-use log::debug;
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
 use tokenizers::Tokenizer;
+use tokio::sync::mpsc;
+
+// Tokens of the prompt kept for the CLIP text encoder, the rest is cut off
+pub const CLIP_MAX_TOKENS: usize = 77;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StableDiffusionVersion {
@@ -96,7 +103,11 @@ impl ModelFile {
     ) -> Result<std::path::PathBuf> {
         use candle_hf_hub::api::sync::Api;
         match filename {
-            Some(filename) => Ok(std::path::PathBuf::from(filename)),
+            Some(filename) => {
+                let filename = std::path::PathBuf::from(filename);
+                record_model_files("local", std::slice::from_ref(&filename));
+                Ok(filename)
+            }
             None => {
                 let (repo, path) = match self {
                     Self::Tokenizer => {
@@ -104,7 +115,9 @@ impl ModelFile {
                             StableDiffusionVersion::V1_5 | StableDiffusionVersion::V2_1 => {
                                 "openai/clip-vit-base-patch32"
                             }
-                            StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo | StableDiffusionVersion::Custom => {
+                            StableDiffusionVersion::Xl
+                            | StableDiffusionVersion::Turbo
+                            | StableDiffusionVersion::Custom => {
                                 // This seems similar to the patch32 version except some very small
                                 // difference in the split regex.
                                 "openai/clip-vit-large-patch14"
@@ -123,7 +136,9 @@ impl ModelFile {
                         // See https://github.com/huggingface/candle/issues/1060
                         if matches!(
                             version,
-                            StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo | StableDiffusionVersion::Custom,
+                            StableDiffusionVersion::Xl
+                                | StableDiffusionVersion::Turbo
+                                | StableDiffusionVersion::Custom,
                         ) && use_f16
                         {
                             (
@@ -136,90 +151,211 @@ impl ModelFile {
                     }
                 };
                 let filename = Api::new()?.model(repo.to_string()).get(path)?;
+                record_model_files(repo, std::slice::from_ref(&filename));
                 Ok(filename)
             }
         }
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn text_embeddings(
-    prompt: &str,
-    uncond_prompt: &str,
-    tokenizer: Option<String>,
-    clip_weights: Option<String>,
-    sd_version: StableDiffusionVersion,
-    sd_config: &stable_diffusion::StableDiffusionConfig,
-    use_f16: bool,
-    device: &Device,
-    dtype: DType,
-    use_guide_scale: bool,
-    first: bool,
-) -> Result<Tensor> {
-    let tokenizer_file = if first {
-        ModelFile::Tokenizer
-    } else {
-        ModelFile::Tokenizer2
-    };
-    let tokenizer = tokenizer_file.get(tokenizer, sd_version, use_f16)?;
-    let tokenizer = Tokenizer::from_file(tokenizer).map_err(E::msg)?;
-    let pad_id = match &sd_config.clip.pad_with {
-        Some(padding) => *tokenizer.get_vocab(true).get(padding.as_str()).unwrap(),
-        None => *tokenizer.get_vocab(true).get("<|endoftext|>").unwrap(),
-    };
-
-    // truncate the prompt to N tokens max length of input
-    let prompt = truncate_tokens(prompt, 77);
-
-    debug!("Stable Diffusion: Running with prompt \"{prompt}\".");
-    let mut tokens = tokenizer
-        .encode(prompt, true)
-        .map_err(E::msg)?
-        .get_ids()
-        .to_vec();
-    while tokens.len() < sd_config.clip.max_position_embeddings {
-        tokens.push(pad_id)
-    }
-    let tokens = Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?;
+// Tokenizer and CLIP transformer of one text encoder, SDXL has two
+struct TextEncoder {
+    tokenizer: Tokenizer,
+    pad_id: u32,
+    model: stable_diffusion::clip::ClipTextTransformer,
+}
 
-    debug!("Stable Diffusion: Building the Clip transformer.");
-    let clip_weights_file = if first {
-        ModelFile::Clip
-    } else {
-        ModelFile::Clip2
-    };
-    let clip_weights = clip_weights_file.get(clip_weights, sd_version, false)?;
-    let clip_config = if first {
-        &sd_config.clip
-    } else {
-        sd_config.clip2.as_ref().unwrap()
-    };
-    let text_model =
-        stable_diffusion::build_clip_transformer(clip_config, clip_weights, device, DType::F32)?;
+impl TextEncoder {
+    fn load(
+        tokenizer: Option<String>,
+        clip_weights: Option<String>,
+        sd_version: StableDiffusionVersion,
+        sd_config: &stable_diffusion::StableDiffusionConfig,
+        use_f16: bool,
+        device: &Device,
+        first: bool,
+    ) -> Result<Self> {
+        let tokenizer_file = if first {
+            ModelFile::Tokenizer
+        } else {
+            ModelFile::Tokenizer2
+        };
+        let tokenizer = tokenizer_file.get(tokenizer, sd_version, use_f16)?;
+        let tokenizer = Tokenizer::from_file(tokenizer).map_err(E::msg)?;
+        let pad_id = match &sd_config.clip.pad_with {
+            Some(padding) => *tokenizer.get_vocab(true).get(padding.as_str()).unwrap(),
+            None => *tokenizer.get_vocab(true).get("<|endoftext|>").unwrap(),
+        };
 
-    // catch errors and return without crashing
-    let text_embeddings = text_model.forward(&tokens)?;
+        debug!("Stable Diffusion: Building the Clip transformer.");
+        let clip_weights_file = if first {
+            ModelFile::Clip
+        } else {
+            ModelFile::Clip2
+        };
+        let clip_weights = clip_weights_file.get(clip_weights, sd_version, false)?;
+        let clip_config = if first {
+            &sd_config.clip
+        } else {
+            sd_config.clip2.as_ref().unwrap()
+        };
+        let model = stable_diffusion::build_clip_transformer(
+            clip_config,
+            clip_weights,
+            device,
+            DType::F32,
+        )?;
+
+        Ok(TextEncoder {
+            tokenizer,
+            pad_id,
+            model,
+        })
+    }
 
-    let text_embeddings = if use_guide_scale {
-        let mut uncond_tokens = tokenizer
-            .encode(uncond_prompt, true)
+    fn tokens(&self, prompt: &str, max_len: usize, device: &Device) -> Result<Tensor> {
+        let mut tokens = self
+            .tokenizer
+            .encode(prompt, true)
             .map_err(E::msg)?
             .get_ids()
             .to_vec();
-        while uncond_tokens.len() < sd_config.clip.max_position_embeddings {
-            uncond_tokens.push(pad_id)
+        while tokens.len() < max_len {
+            tokens.push(self.pad_id)
         }
+        Ok(Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?)
+    }
 
-        let uncond_tokens = Tensor::new(uncond_tokens.as_slice(), device)?.unsqueeze(0)?;
-        let uncond_embeddings = text_model.forward(&uncond_tokens)?;
+    fn text_embeddings(
+        &self,
+        prompt: &str,
+        uncond_prompt: &str,
+        sd_config: &stable_diffusion::StableDiffusionConfig,
+        device: &Device,
+        dtype: DType,
+        use_guide_scale: bool,
+    ) -> Result<Tensor> {
+        // truncate the prompt to N tokens max length of input
+        let prompt = truncate_tokens(prompt, CLIP_MAX_TOKENS);
+
+        debug!("Stable Diffusion: Running with prompt \"{prompt}\".");
+        let max_len = sd_config.clip.max_position_embeddings;
+        let tokens = self.tokens(&prompt, max_len, device)?;
+
+        // catch errors and return without crashing
+        let text_embeddings = self.model.forward(&tokens)?;
+
+        let text_embeddings = if use_guide_scale {
+            let uncond_tokens = self.tokens(uncond_prompt, max_len, device)?;
+            let uncond_embeddings = self.model.forward(&uncond_tokens)?;
+
+            Tensor::cat(&[uncond_embeddings, text_embeddings], 0)?.to_dtype(dtype)?
+        } else {
+            text_embeddings.to_dtype(dtype)?
+        };
+        Ok(text_embeddings)
+    }
+}
 
-        Tensor::cat(&[uncond_embeddings, text_embeddings], 0)?.to_dtype(dtype)?
-    } else {
-        text_embeddings.to_dtype(dtype)?
-    };
-    Ok(text_embeddings)
+// Settings that pick the weights and shapes of the loaded models, a change reloads them
+#[derive(Debug, Clone, PartialEq)]
+struct EngineKey {
+    sd_version: StableDiffusionVersion,
+    cpu: bool,
+    use_f16: bool,
+    use_flash_attn: bool,
+    sliced_attention_size: Option<usize>,
+    height: Option<usize>,
+    width: Option<usize>,
+    tokenizer: Option<String>,
+    clip_weights: Option<String>,
+    vae_weights: Option<String>,
+    unet_weights: Option<String>,
+}
+
+impl EngineKey {
+    fn from_config(config: &SDConfig) -> Self {
+        EngineKey {
+            sd_version: config.sd_version,
+            cpu: config.cpu,
+            use_f16: config.use_f16,
+            use_flash_attn: config.use_flash_attn,
+            sliced_attention_size: config.sliced_attention_size,
+            height: config.height,
+            width: config.width,
+            tokenizer: config.tokenizer.clone(),
+            clip_weights: config.clip_weights.clone(),
+            vae_weights: config.vae_weights.clone(),
+            unet_weights: config.unet_weights.clone(),
+        }
+    }
+}
+
+// Text encoders, autoencoder and unet kept loaded between paragraphs
+struct SDEngine {
+    key: EngineKey,
+    device: Device,
+    encoders: Vec<TextEncoder>,
+    vae: stable_diffusion::vae::AutoEncoderKL,
+    unet: stable_diffusion::unet_2d::UNet2DConditionModel,
+}
+
+impl SDEngine {
+    fn load(
+        key: EngineKey,
+        sd_config: &stable_diffusion::StableDiffusionConfig,
+        dtype: DType,
+    ) -> Result<Self> {
+        let load_start = std::time::Instant::now();
+        let device = candle_examples::device(key.cpu)?;
+
+        let which = match key.sd_version {
+            StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo => vec![true, false],
+            _ => vec![true],
+        };
+        let encoders = which
+            .iter()
+            .map(|first| {
+                TextEncoder::load(
+                    key.tokenizer.clone(),
+                    key.clip_weights.clone(),
+                    key.sd_version,
+                    sd_config,
+                    key.use_f16,
+                    &device,
+                    *first,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        debug!("Stable Diffusion: Building the autoencoder.");
+        let vae_weights =
+            ModelFile::Vae.get(key.vae_weights.clone(), key.sd_version, key.use_f16)?;
+        let vae = sd_config.build_vae(vae_weights, &device, dtype)?;
+
+        debug!("Stable Diffusion: Building the unet.");
+        let unet_weights =
+            ModelFile::Unet.get(key.unet_weights.clone(), key.sd_version, key.use_f16)?;
+        let unet = sd_config.build_unet(unet_weights, &device, 4, key.use_flash_attn, dtype)?;
+
+        info!(
+            "Stable Diffusion: Loaded the {:?} models in {:.2}s",
+            key.sd_version,
+            load_start.elapsed().as_secs_f32()
+        );
+        Ok(SDEngine {
+            key,
+            device,
+            encoders,
+            vae,
+            unet,
+        })
+    }
 }
 
+// The loaded models, the lock also keeps candle jobs from running at the same time
+static SD_ENGINE: Lazy<Mutex<Option<SDEngine>>> = Lazy::new(|| Mutex::new(None));
+
 fn image_preprocess<T: AsRef<std::path::Path>>(path: T) -> anyhow::Result<Tensor> {
     let img = image::io::Reader::open(path)?.decode()?;
     let (height, width) = (img.height() as usize, img.width() as usize);
@@ -267,6 +403,8 @@ pub struct SDConfig {
     pub scaled_height: Option<u32>,
     pub image_position: Option<String>,
     pub seed: Option<i32>,
+    // receives the intermediary and final images as soon as they are decoded
+    pub frame_tx: Option<mpsc::UnboundedSender<ImageBuffer<Rgb<u8>, Vec<u8>>>>,
 }
 
 impl SDConfig {
@@ -297,7 +435,8 @@ impl SDConfig {
             scaled_width: None,
             scaled_height: None,
             image_position: None,
-            seed: Some(-1)
+            seed: Some(-1),
+            frame_tx: None,
         }
     }
 }
@@ -382,7 +521,20 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
     };
 
     let scheduler = sd_config.build_scheduler(n_steps)?;
-    let device = candle_examples::device(config.cpu)?;
+
+    // load the models on the first image or when the model settings changed
+    let key = EngineKey::from_config(&config);
+    let mut engine_guard = SD_ENGINE.lock().unwrap();
+    if engine_guard.as_ref().map(|engine| &engine.key) != Some(&key) {
+        // free the old models before loading the new ones
+        *engine_guard = None;
+        *engine_guard = Some(SDEngine::load(key, &sd_config, dtype)?);
+    }
+    let engine = engine_guard.as_ref().unwrap();
+    let device = &engine.device;
+    let vae = &engine.vae;
+    let unet = &engine.unet;
+
     let mut seed_u32 = seed;
     if seed.is_some() && seed < Some(0) {
         seed_u32 = None;
@@ -392,26 +544,17 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
     }
     let use_guide_scale = guidance_scale > 1.0;
 
-    let which = match config.sd_version {
-        StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo => vec![true, false],
-        _ => vec![true],
-    };
-
-    let text_embeddings = which
+    let text_embeddings = engine
+        .encoders
         .iter()
-        .map(|first| {
-            text_embeddings(
+        .map(|encoder| {
+            encoder.text_embeddings(
                 &config.prompt,
                 &config.uncond_prompt,
-                config.tokenizer.clone(),
-                config.clip_weights.clone(),
-                config.sd_version,
                 &sd_config,
-                config.use_f16,
-                &device,
+                device,
                 dtype,
                 use_guide_scale,
-                *first,
             )
         })
         .collect::<Result<Vec<_>>>()?;
@@ -419,20 +562,13 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
     let text_embeddings = Tensor::cat(&text_embeddings, D::Minus1)?;
     debug!("Stable Diffusion: Text Embeddings - {text_embeddings:?}");
 
-    debug!("Stable Diffusion: Building the autoencoder.");
-    let vae_weights = ModelFile::Vae.get(config.vae_weights, config.sd_version, config.use_f16)?;
-    let vae = sd_config.build_vae(vae_weights, &device, dtype)?;
     let init_latent_dist = match &config.img2img {
         None => None,
         Some(image_buf) => {
-            let image_buf = image_preprocess(image_buf)?.to_device(&device)?;
+            let image_buf = image_preprocess(image_buf)?.to_device(device)?;
             Some(vae.encode(&image_buf)?)
         }
     };
-    debug!("Stable Diffusion: Building the unet.");
-    let unet_weights =
-        ModelFile::Unet.get(config.unet_weights, config.sd_version, config.use_f16)?;
-    let unet = sd_config.build_unet(unet_weights, &device, 4, config.use_flash_attn, dtype)?;
 
     let t_start = if config.img2img.is_some() {
         n_steps - (n_steps as f64 * config.img2img_strength) as usize
@@ -452,6 +588,21 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
     // array of image buffers to gather the results
     let mut images = Vec::with_capacity(config.num_samples);
 
+    // scale each image when it is decoded and send it on so the output can show it before
+    // the remaining steps and samples are done
+    let deliver = |image: ImageBuffer<Rgb<u8>, Vec<u8>>| {
+        let image = scale_image(
+            image,
+            config.scaled_width,
+            config.scaled_height,
+            config.image_position.clone(),
+        );
+        if let Some(frame_tx) = &config.frame_tx {
+            let _ = frame_tx.send(image.clone());
+        }
+        image
+    };
+
     for idx in 0..config.num_samples {
         let timesteps = scheduler.timesteps();
         let latents = match &init_latent_dist {
@@ -526,7 +677,7 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
                         None => anyhow::bail!("error saving image"),
                     };
 
-                images.push(image_u8);
+                images.push(deliver(image_u8));
             }
         }
 
@@ -553,20 +704,8 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
                 None => anyhow::bail!("error saving image"),
             };
 
-        images.push(image_u8);
+        images.push(deliver(image_u8));
     }
 
-    let scaled_images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>> = images
-        .into_iter()
-        .map(|image| {
-            scale_image(
-                image,
-                config.scaled_width,
-                config.scaled_height,
-                config.image_position.clone(),
-            )
-        })
-        .collect();
-
-    Ok(scaled_images)
+    Ok(images)
 }
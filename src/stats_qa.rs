@@ -0,0 +1,242 @@
+/*
+ * stats_qa.rs
+ * -----------
+ * Operator questions about the captured stream, like "why did program 3's bitrate drop at
+ * 14:02?". The time, programs and PIDs in the question select the stats history snapshots and
+ * the logged events that are put in the prompt, the LLM answers from them. Each session keeps
+ * its last turns so follow up questions work.
+*/
+
+use crate::args::Args;
+use crate::event_log::events_between;
+use crate::openai_api::{chat_completion, Message};
+use crate::probe::{stats_history, tr101290_totals};
+use crate::token_budget::{cut_to_tokens, fit_messages, BudgetPart, TokenBudget, QA_PARTS};
+use crate::{current_unix_timestamp_ms, ApiError};
+use chrono::{Local, NaiveTime, TimeZone};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// Limits of the retrieved context so it fits in the prompt
+const QA_MAX_SNAPSHOTS: usize = 60;
+const QA_MAX_EVENTS: usize = 100;
+
+const QA_SYSTEM_PROMPT: &str = "You are a broadcast stream analyst. Answer the operator's question using only the stats history snapshots and events provided with it. Bitrates are in bits per second and timestamps in ms since the Unix epoch, answer with local clock times. If the data doesn't explain what happened, say so and say what to check next.";
+
+// Time of day in the question like 14:02, 14:02:30 or 2:02pm, in ms since the epoch. A time
+// later than now is taken to be yesterday.
+pub fn question_time(question: &str, now_ms: u64) -> Option<u64> {
+    let lower = question.to_lowercase();
+    let is_time_char = |c: char| c.is_ascii_digit() || c == ':';
+    let mut rest = lower.as_str();
+    while let Some(start) = rest.find(is_time_char) {
+        rest = &rest[start..];
+        let end = rest.find(|c: char| !is_time_char(c)).unwrap_or(rest.len());
+        let (time_text, suffix) = rest.split_at(end);
+        rest = suffix;
+
+        let parts: Option<Vec<u32>> = time_text
+            .split(':')
+            .map(|part| part.parse::<u32>().ok())
+            .collect();
+        let (mut hour, minute, second) = match parts.as_deref() {
+            Some([hour, minute]) => (*hour, *minute, 0),
+            Some([hour, minute, second]) => (*hour, *minute, *second),
+            _ => continue,
+        };
+        let suffix = suffix.trim_start();
+        if suffix.starts_with("pm") && hour < 12 {
+            hour += 12;
+        } else if suffix.starts_with("am") && hour == 12 {
+            hour = 0;
+        }
+        let Some(time) = NaiveTime::from_hms_opt(hour, minute, second) else {
+            continue;
+        };
+
+        let now = Local.timestamp_millis_opt(now_ms as i64).single()?;
+        let mut date = now.date_naive();
+        if date.and_time(time) > now.naive_local() {
+            date = date.pred_opt()?;
+        }
+        let local = Local.from_local_datetime(&date.and_time(time)).earliest()?;
+        return Some(local.timestamp_millis() as u64);
+    }
+    None
+}
+
+// Programs and PIDs named in the question like "program 3", "pid 256" or "pid 0x100"
+pub fn question_filters(question: &str) -> (Vec<u16>, Vec<u16>) {
+    let words: Vec<String> = question
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_string())
+        .collect();
+    let mut programs = Vec::new();
+    let mut pids = Vec::new();
+    for pair in words.windows(2) {
+        let number = match pair[1].strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => pair[1].parse::<u16>().ok(),
+        };
+        let Some(number) = number else {
+            continue;
+        };
+        match pair[0].as_str() {
+            "program" | "programs" => programs.push(number),
+            "pid" | "pids" => pids.push(number),
+            _ => {}
+        }
+    }
+    (programs, pids)
+}
+
+// Keep the PIDs of the programs and PIDs asked about, all of them without filters
+fn filter_snapshot(snapshot: &Value, programs: &[u16], pids: &[u16]) -> Value {
+    if programs.is_empty() && pids.is_empty() {
+        return snapshot.clone();
+    }
+    let mut snapshot = snapshot.clone();
+    if let Some(entries) = snapshot["pids"].as_array_mut() {
+        entries.retain(|entry| {
+            let pid = entry["pid"].as_u64().unwrap_or(u64::MAX);
+            let program = entry["program_number"].as_u64().unwrap_or(u64::MAX);
+            pids.iter().any(|p| *p as u64 == pid) || programs.iter().any(|p| *p as u64 == program)
+        });
+    }
+    snapshot
+}
+
+// Evenly spaced items so the first and the last are always kept
+fn downsample(items: Vec<Value>, max: usize) -> Vec<Value> {
+    if items.len() <= max || max < 2 {
+        return items;
+    }
+    let step = (items.len() - 1) as f64 / (max - 1) as f64;
+    (0..max)
+        .map(|index| items[(index as f64 * step).round() as usize].clone())
+        .collect()
+}
+
+// Stats history and events around the time of the question
+pub fn build_context(question: &str, now_ms: u64, args: &Args) -> String {
+    let window_ms = args.qa_window_minutes * 60 * 1000;
+    let (since_ms, until_ms) = match question_time(question, now_ms) {
+        Some(time) => (time.saturating_sub(window_ms), time + window_ms),
+        None => (now_ms.saturating_sub(window_ms), now_ms),
+    };
+    let (programs, pids) = question_filters(question);
+
+    let snapshots: Vec<Value> = stats_history(since_ms, until_ms)
+        .iter()
+        .map(|snapshot| filter_snapshot(snapshot, &programs, &pids))
+        .collect();
+    let snapshots = downsample(snapshots, QA_MAX_SNAPSHOTS);
+
+    let events = events_between(&args.event_log, since_ms, until_ms);
+    let skip = events.len().saturating_sub(QA_MAX_EVENTS);
+
+    let lines = |items: &[Value]| {
+        if items.is_empty() {
+            "none".to_string()
+        } else {
+            items
+                .iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+    };
+    let clock = |ms: u64| {
+        Local
+            .timestamp_millis_opt(ms as i64)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    };
+
+    format!(
+        "Data from {} to {} (timestamps {} to {}).\nCurrent TR 101 290 counts: {}\nStats history:\n{}\nEvents:\n{}",
+        clock(since_ms),
+        clock(until_ms),
+        since_ms,
+        until_ms,
+        json!(tr101290_totals()),
+        lines(&snapshots),
+        lines(&events[skip..])
+    )
+}
+
+// Question and answer sessions of the control api
+pub struct StatsAnalyst {
+    sessions: HashMap<String, Vec<Message>>,
+}
+
+impl StatsAnalyst {
+    pub fn new() -> Self {
+        StatsAnalyst {
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self, session: &str) -> bool {
+        self.sessions.remove(session).is_some()
+    }
+
+    // Answer with the retrieved data, the earlier turns of the session are kept without it
+    pub async fn ask(
+        &mut self,
+        session: &str,
+        question: &str,
+        args: &Args,
+    ) -> Result<String, ApiError> {
+        let now_ms = current_unix_timestamp_ms().unwrap_or(0);
+        let history = self.sessions.entry(session.to_string()).or_default();
+
+        // the earlier turns and the retrieved data share what the answer and question leave
+        let budget = TokenBudget::for_model(args, &args.model, args.qa_max_tokens)
+            .reserve(QA_SYSTEM_PROMPT)
+            .reserve(question);
+        let mut earlier = history.clone();
+        fit_messages(
+            &mut earlier,
+            budget.allocation(BudgetPart::History, &QA_PARTS),
+        );
+        let context = cut_to_tokens(
+            &build_context(question, now_ms, args),
+            budget.allocation(BudgetPart::Rag, &QA_PARTS),
+        );
+
+        let mut messages = vec![Message {
+            role: "system".to_string(),
+            content: QA_SYSTEM_PROMPT.to_string(),
+        }];
+        messages.extend(earlier);
+        messages.push(Message {
+            role: "user".to_string(),
+            content: format!("{}\n\nQuestion: {}", context, question),
+        });
+
+        let answer = chat_completion(messages, args, args.qa_max_tokens, 0.2).await?;
+
+        history.push(Message {
+            role: "user".to_string(),
+            content: question.to_string(),
+        });
+        history.push(Message {
+            role: "assistant".to_string(),
+            content: answer.clone(),
+        });
+        let excess = history.len().saturating_sub(args.qa_history_turns * 2);
+        history.drain(..excess);
+
+        Ok(answer)
+    }
+}
+
+impl Default for StatsAnalyst {
+    fn default() -> Self {
+        StatsAnalyst::new()
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * stats_schema.rs
+ * ---------------
+ * Versioned JSON structure of the stats payloads. The stats message the LLM reads each
+ * iteration and the JSON lines of the probe are these structs serialized with serde, so
+ * prompt templates and downstream tools can rely on the field names. STATS_SCHEMA_VERSION is
+ * raised whenever a field is renamed, removed or changes its meaning, new fields don't.
+*/
+
+use crate::audio_class::AudioClassStatus;
+use crate::capture_paths::PathStats;
+use crate::fec::FecStats;
+use crate::loudness::LoudnessStatus;
+use crate::pcap_stats::PcapStats;
+use crate::stream_data::{ProgramSummary, Tr101290Errors, Tr101290Thresholds};
+use crate::system_stats::SystemStats;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+pub const STATS_SCHEMA_VERSION: u32 = 1;
+
+// A PID of the anomaly table, most anomalous first
+#[derive(Debug, Clone, Serialize)]
+pub struct PidStats {
+    pub pid: u16,
+    pub program_number: u16,
+    pub category: String,
+    pub bitrate: u32,
+    pub bitrate_avg: u32,
+    pub iat_avg_us: u64,
+    pub iat_max_us: u64,
+    pub errors: u32,
+    pub new_errors: u32, // since the last stats message
+    pub score: f64,
+}
+
+// The analyzed stream in the token budget of the network mode
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StreamStats {
+    pub packets: u64, // captured so far
+    pub programs: Vec<ProgramSummary>,
+    pub loudness: Option<LoudnessStatus>,
+    pub audio_class: Option<AudioClassStatus>,
+    pub transcript: Vec<String>, // speaker turns of the program audio, latest last
+    pub pid_count: usize,
+    pub pids: Vec<PidStats>,
+    pub samples: Vec<String>, // packets of the listed PIDs as JSON or hexdump
+    pub omitted: usize,       // entries left out for the token budget
+}
+
+impl StreamStats {
+    pub fn new(packets: u64) -> Self {
+        StreamStats {
+            packets,
+            ..Default::default()
+        }
+    }
+
+    // The stats with the text that comes from the stream passed through the prompt guard,
+    // None when the guard drops any of it
+    pub fn guarded(mut self, guard: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        self.transcript = self
+            .transcript
+            .iter()
+            .map(|turn| guard(turn))
+            .collect::<Option<Vec<_>>>()?;
+        self.samples = self
+            .samples
+            .iter()
+            .map(|sample| guard(sample))
+            .collect::<Option<Vec<_>>>()?;
+        Some(self)
+    }
+}
+
+// The latest stats a remote probe reported, without its PID map
+#[derive(Debug, Clone, Serialize)]
+pub struct SiteStats {
+    pub site: String,
+    pub age_ms: u64, // since the report was received
+    pub stale: bool, // no report for longer than --probe-stale-secs
+    pub stats: ProbeStats,
+}
+
+// The user message of an iteration with the stats, in place of the query
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsMessage {
+    pub schema_version: u32,
+    pub iteration: u64,
+    pub time: String, // local time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamStats>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sites: Vec<SiteStats>, // of the probes reporting to this instance
+    pub instructions: String,
+}
+
+impl StatsMessage {
+    pub fn new(iteration: u64, instructions: &str) -> Self {
+        StatsMessage {
+            schema_version: STATS_SCHEMA_VERSION,
+            iteration,
+            time: chrono::Local::now()
+                .format("%Y-%m-%d %H:%M:%S%.3f")
+                .to_string(),
+            system: None,
+            stream: None,
+            sites: Vec::new(),
+            instructions: instructions.to_string(),
+        }
+    }
+
+    pub fn with_system(mut self, system: Option<SystemStats>) -> Self {
+        self.system = system;
+        self
+    }
+
+    pub fn with_stream(mut self, stream: StreamStats) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    pub fn with_sites(mut self, sites: Vec<SiteStats>) -> Self {
+        self.sites = sites;
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    // Only the stats, without the iteration, time and instructions, for the response cache
+    pub fn window(&self) -> String {
+        json!({ "system": self.system, "stream": self.stream, "sites": self.sites }).to_string()
+    }
+}
+
+// A JSON line of the probe, the stream and system stats for export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeStats {
+    pub schema_version: u32,
+    pub timestamp: u64,
+    pub packets: u64,
+    pub is_mpegts: bool,
+    pub video_pid: Option<u16>,
+    pub video_codec: Option<String>,
+    pub audio_pid: Option<u16>,
+    pub audio_codec: Option<String>,
+    pub loudness: Option<LoudnessStatus>,
+    pub audio_class: Option<AudioClassStatus>,
+    pub tr101290: Tr101290Errors,
+    pub tr101290_thresholds: Tr101290Thresholds,
+    pub fec: FecStats,
+    pub paths: Option<Vec<PathStats>>,
+    pub pcap: Vec<PcapStats>,
+    pub timestamp_source: String,
+    pub clock_synchronized: Option<bool>, // None where the kernel clock state is unknown
+    pub pid_map: String,
+    pub programs: Vec<ProgramSummary>,
+    pub system: SystemStats,
+}
+
+impl ProbeStats {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
@@ -11,6 +11,7 @@ use log::{debug, error, info};
 use rtp::RtpReader;
 use rtp_rs as rtp;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::{fmt, sync::Arc, sync::Mutex};
 
 // global variable to store the MpegTS PID Map (initially empty)
@@ -63,9 +64,100 @@ pub fn get_pid_map() -> String {
     result
 }
 
+// Rates and errors of each PID in the PID map for the stats history
+pub fn pid_map_snapshot() -> Vec<Value> {
+    let pid_map = PID_MAP.lock().unwrap();
+    let mut pids: Vec<Value> = pid_map
+        .values()
+        .map(|stream_data| {
+            json!({
+                "pid": stream_data.pid,
+                "program_number": stream_data.program_number,
+                "stream_type": stream_data.stream_type,
+                "bitrate": stream_data.bitrate,
+                "bitrate_avg": stream_data.bitrate_avg,
+                "iat_avg": stream_data.iat_avg,
+                "iat_avg_ns": stream_data.iat_avg_ns,
+                "error_count": stream_data.error_count,
+                "count": stream_data.count,
+            })
+        })
+        .collect();
+    pids.sort_by_key(|pid| pid["pid"].as_u64());
+    pids
+}
+
+// Copies of the PID map entries without their packets
+pub fn pid_map_streams() -> Vec<StreamData> {
+    let pid_map = PID_MAP.lock().unwrap();
+    let mut streams: Vec<StreamData> = pid_map
+        .values()
+        .map(|stream_data| stream_data.as_ref().clone())
+        .collect();
+    streams.sort_by_key(|stream_data| stream_data.pid);
+    streams
+}
+
+// Video, audio or data from the stream type description of the PMT
+pub fn stream_category(stream_type: &str) -> &'static str {
+    let stream_type = stream_type.to_lowercase();
+    if stream_type.contains("video") {
+        "video"
+    } else if stream_type.contains("audio") || stream_type.contains("ac-3") {
+        "audio"
+    } else {
+        "data"
+    }
+}
+
+// Service level totals of a program
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProgramSummary {
+    pub program_number: u16,
+    pub pmt_pid: u16,
+    pub bitrate: u64,
+    pub video_bitrate: u64,
+    pub audio_bitrate: u64,
+    pub data_bitrate: u64,
+    pub pids: u32,
+    pub error_count: u32,
+    pub count: u64,
+}
+
+// Totals of the PID map for each program, program 0 holds the PIDs not yet assigned by a PMT
+pub fn program_summaries() -> Vec<ProgramSummary> {
+    let pid_map = PID_MAP.lock().unwrap();
+    let mut programs: AHashMap<u16, ProgramSummary> = AHashMap::new();
+    for stream_data in pid_map.values() {
+        let program = programs
+            .entry(stream_data.program_number)
+            .or_insert_with(|| ProgramSummary {
+                program_number: stream_data.program_number,
+                pmt_pid: stream_data.pmt_pid,
+                ..Default::default()
+            });
+        let bitrate = stream_data.bitrate as u64;
+        program.bitrate += bitrate;
+        match stream_category(&stream_data.stream_type) {
+            "video" => program.video_bitrate += bitrate,
+            "audio" => program.audio_bitrate += bitrate,
+            _ => program.data_bitrate += bitrate,
+        }
+        program.pids += 1;
+        program.error_count += stream_data.error_count;
+        program.count += stream_data.count as u64;
+    }
+    let mut programs: Vec<ProgramSummary> = programs.into_values().collect();
+    programs.sort_by_key(|program| program.program_number);
+    programs
+}
+
 // constant for PAT PID
 pub const PAT_PID: u16 = 0;
 pub const TS_PACKET_SIZE: usize = 188;
+// PCR inaccuracy limit of TR 101 290 in ns
+const PCR_ACCURACY_NS: f64 = 500.0;
+const PCR_TICKS_PER_NS: f64 = 0.027;
 
 pub struct PatEntry {
     pub program_number: u16,
@@ -100,6 +192,27 @@ impl fmt::Display for Codec {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioCodec {
+    Mpeg,
+    AacAdts,
+    AacLatm,
+    Ac3,
+    Eac3,
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioCodec::Mpeg => write!(f, "MPEG"),
+            AudioCodec::AacAdts => write!(f, "AAC"),
+            AudioCodec::AacLatm => write!(f, "AAC-LATM"),
+            AudioCodec::Ac3 => write!(f, "AC-3"),
+            AudioCodec::Eac3 => write!(f, "E-AC-3"),
+        }
+    }
+}
+
 // StreamData struct
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StreamData {
@@ -117,9 +230,15 @@ pub struct StreamData {
     pub iat_max: u64,
     pub iat_min: u64,
     pub iat_avg: u64,
+    pub iat_ns: u64,
+    pub iat_max_ns: u64,
+    pub iat_min_ns: u64,
+    pub iat_avg_ns: u64,
     pub error_count: u32,
     pub last_arrival_time: u64,
+    pub last_arrival_time_ns: u64,
     pub start_time: u64, // field for start time
+    pub start_time_ns: u64,
     pub total_bits: u64, // field for total bits
     pub count: u32,      // field for count
     #[serde(skip)]
@@ -155,9 +274,15 @@ impl Clone for StreamData {
             iat_max: self.iat_max,
             iat_min: self.iat_min,
             iat_avg: self.iat_avg,
+            iat_ns: self.iat_ns,
+            iat_max_ns: self.iat_max_ns,
+            iat_min_ns: self.iat_min_ns,
+            iat_avg_ns: self.iat_avg_ns,
             error_count: self.error_count,
             last_arrival_time: self.last_arrival_time,
+            last_arrival_time_ns: self.last_arrival_time_ns,
             start_time: self.start_time,
+            start_time_ns: self.start_time_ns,
             total_bits: self.total_bits,
             count: self.count,
             packet: Arc::new(Vec::new()), // Initialize as empty with Arc
@@ -188,7 +313,6 @@ impl StreamData {
         timestamp: u64,
         continuity_counter: u8,
     ) -> Self {
-        let last_arrival_time = current_unix_timestamp_ms().unwrap_or(0);
         StreamData {
             pid,
             pmt_pid: 0xFFFF,
@@ -204,9 +328,16 @@ impl StreamData {
             iat_max: 0,
             iat_min: 0,
             iat_avg: 0,
+            iat_ns: 0,
+            iat_max_ns: 0,
+            iat_min_ns: 0,
+            iat_avg_ns: 0,
             error_count: 0,
-            last_arrival_time,
-            start_time,    // Initialize start time
+            // set from the first packet, the capture clock can be far from the system one
+            last_arrival_time: 0,
+            last_arrival_time_ns: 0,
+            start_time, // Initialize start time
+            start_time_ns: start_time * 1_000_000,
             total_bits: 0, // Initialize total bits
             count: 0,      // Initialize count
             packet: packet,
@@ -250,6 +381,10 @@ impl StreamData {
     pub fn update_stream_type(&mut self, stream_type: String) {
         self.stream_type = stream_type;
     }
+    pub fn set_program(&mut self, program_number: u16, pmt_pid: u16) {
+        self.program_number = program_number;
+        self.pmt_pid = pmt_pid;
+    }
     pub fn increment_error_count(&mut self, error_count: u32) {
         self.error_count += error_count;
     }
@@ -281,14 +416,25 @@ impl StreamData {
         }
         self.continuity_counter = continuity_counter;
     }
-    pub fn update_stats(&mut self, packet_size: usize, arrival_time: u64) {
+    // Arrival time in ns, the ms fields follow the ns ones for the JSON consumers
+    pub fn update_stats(&mut self, packet_size: usize, arrival_time_ns: u64) {
         let bits = packet_size as u64 * 8; // Convert bytes to bits
 
-        // Elapsed time in milliseconds
-        let elapsed_time_ms = arrival_time.checked_sub(self.start_time).unwrap_or(0);
+        // The first packet starts the IAT and bitrate on the clock of the capture
+        if self.last_arrival_time_ns == 0 {
+            self.start_time_ns = arrival_time_ns;
+            self.start_time = arrival_time_ns / 1_000_000;
+            self.total_bits += bits;
+            self.last_arrival_time_ns = arrival_time_ns;
+            self.last_arrival_time = arrival_time_ns / 1_000_000;
+            return;
+        }
+
+        // Elapsed time in nanoseconds
+        let elapsed_time_ns = arrival_time_ns.saturating_sub(self.start_time_ns);
 
-        if elapsed_time_ms > 0 {
-            let elapsed_time_sec = elapsed_time_ms as f64 / 1000.0;
+        if elapsed_time_ns > 0 {
+            let elapsed_time_sec = elapsed_time_ns as f64 / 1_000_000_000.0;
             self.bitrate = (self.total_bits as f64 / elapsed_time_sec) as u32;
 
             // Bitrate max
@@ -308,28 +454,33 @@ impl StreamData {
         self.total_bits += bits; // Accumulate total bits
 
         // IAT calculation remains the same
-        let iat = arrival_time
-            .checked_sub(self.last_arrival_time)
-            .unwrap_or(0);
-        self.iat = iat;
+        let iat_ns = arrival_time_ns.saturating_sub(self.last_arrival_time_ns);
+        self.iat_ns = iat_ns;
 
         // IAT max
-        if iat > self.iat_max {
-            self.iat_max = iat;
+        if iat_ns > self.iat_max_ns {
+            self.iat_max_ns = iat_ns;
         }
 
         // IAT min
-        if iat < self.iat_min {
-            self.iat_min = iat;
+        if iat_ns < self.iat_min_ns {
+            self.iat_min_ns = iat_ns;
         }
 
         // IAT avg
-        self.iat_avg = (self.iat_avg + iat) / 2;
+        self.iat_avg_ns = (self.iat_avg_ns + iat_ns) / 2;
+
+        self.iat = self.iat_ns / 1_000_000;
+        self.iat_max = self.iat_max_ns / 1_000_000;
+        self.iat_min = self.iat_min_ns / 1_000_000;
+        self.iat_avg = self.iat_avg_ns / 1_000_000;
 
-        self.last_arrival_time = arrival_time;
+        self.last_arrival_time_ns = arrival_time_ns;
+        self.last_arrival_time = arrival_time_ns / 1_000_000;
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Tr101290Errors {
     // p1 errors
     pub ts_sync_byte_errors: u32,
@@ -425,6 +576,321 @@ pub fn tr101290_p2_check(packet: &[u8], errors: &mut Tr101290Errors) {
     // TODO: ... other checks, updating the respective counters ...
 }
 
+// TR 101 290 repetition limits in ms, the defaults are the limits of the standard
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Tr101290Thresholds {
+    pub pat_interval_ms: u64,
+    pub pmt_interval_ms: u64,
+    pub pcr_interval_ms: u64,
+    pub pts_interval_ms: u64,
+}
+
+impl Tr101290Thresholds {
+    pub fn new() -> Self {
+        Tr101290Thresholds {
+            pat_interval_ms: 500,
+            pmt_interval_ms: 500,
+            pcr_interval_ms: 40,
+            pts_interval_ms: 700,
+        }
+    }
+}
+
+impl Default for Tr101290Thresholds {
+    fn default() -> Self {
+        Tr101290Thresholds::new()
+    }
+}
+
+// First and latest PCR of a PID at their packet positions, the PCR accuracy is checked
+// against the rate between them
+#[derive(Debug, Clone, Copy)]
+struct PcrReference {
+    first: (u64, u64),
+    last: (u64, u64),
+}
+
+// Last arrival of the PAT, PMT, PCR and PTS for the TR 101 290 repetition checks
+pub struct Tr101290Timing {
+    pub thresholds: Tr101290Thresholds,
+    first_arrival: Option<u64>,
+    last_pat: Option<u64>,
+    last_pmt: Option<u64>,
+    last_pcr: AHashMap<u16, u64>,
+    last_pts: AHashMap<u16, u64>,
+    position: u64, // TS packets so far, null packets included
+    pcr_reference: AHashMap<u16, PcrReference>,
+}
+
+impl Tr101290Timing {
+    pub fn new(thresholds: Tr101290Thresholds) -> Self {
+        Tr101290Timing {
+            thresholds,
+            first_arrival: None,
+            last_pat: None,
+            last_pmt: None,
+            last_pcr: AHashMap::new(),
+            last_pts: AHashMap::new(),
+            position: 0,
+            pcr_reference: AHashMap::new(),
+        }
+    }
+
+    // A null packet, not checked but it takes its place in the stream for the PCR accuracy
+    pub fn count_null_packet(&mut self) {
+        self.position += 1;
+    }
+
+    // Check the interval since the last PAT, PMT, PCR or PTS the packet carries
+    pub fn check(
+        &mut self,
+        packet: &[u8],
+        pmt_pid: u16,
+        arrival_time: u64,
+        errors: &mut Tr101290Errors,
+    ) {
+        self.position += 1;
+        if packet.len() < TS_PACKET_SIZE || packet[0] != 0x47 {
+            return;
+        }
+        let pid = ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16;
+        let payload_start = (packet[1] & 0x40) != 0;
+        let first_arrival = *self.first_arrival.get_or_insert(arrival_time);
+
+        // a PAT that stopped or never came is an error every interval without it
+        let since_pat = arrival_time.saturating_sub(self.last_pat.unwrap_or(first_arrival));
+        if pid != PAT_PID && since_pat > self.thresholds.pat_interval_ms {
+            debug!(
+                "TR101290: no PAT for over {}ms",
+                self.thresholds.pat_interval_ms
+            );
+            errors.pat_errors += 1;
+            self.last_pat = Some(arrival_time);
+        }
+
+        if pid == PAT_PID && payload_start {
+            if interval_exceeded(
+                &mut self.last_pat,
+                arrival_time,
+                self.thresholds.pat_interval_ms,
+            ) {
+                debug!(
+                    "TR101290: PAT repetition over {}ms",
+                    self.thresholds.pat_interval_ms
+                );
+                errors.pat_errors += 1;
+            }
+        } else if pid == pmt_pid && payload_start {
+            if interval_exceeded(
+                &mut self.last_pmt,
+                arrival_time,
+                self.thresholds.pmt_interval_ms,
+            ) {
+                debug!(
+                    "TR101290: PMT repetition over {}ms",
+                    self.thresholds.pmt_interval_ms
+                );
+                errors.pmt_errors += 1;
+            }
+        }
+
+        if has_pcr(packet) {
+            let mut last = self.last_pcr.get(&pid).copied();
+            if interval_exceeded(&mut last, arrival_time, self.thresholds.pcr_interval_ms) {
+                debug!(
+                    "TR101290: PCR repetition over {}ms on PID {}",
+                    self.thresholds.pcr_interval_ms, pid
+                );
+                errors.pcr_repetition_errors += 1;
+            }
+            self.last_pcr.insert(pid, arrival_time);
+            if self.pcr_inaccurate(pid, packet) {
+                errors.pcr_accuracy_errors += 1;
+            }
+        }
+
+        if has_pts(packet) {
+            let mut last = self.last_pts.get(&pid).copied();
+            if interval_exceeded(&mut last, arrival_time, self.thresholds.pts_interval_ms) {
+                debug!(
+                    "TR101290: PTS interval over {}ms on PID {}",
+                    self.thresholds.pts_interval_ms, pid
+                );
+                errors.pts_errors += 1;
+            }
+            self.last_pts.insert(pid, arrival_time);
+        }
+    }
+
+    // Whether the PCR is further than PCR_ACCURACY_NS from where the rate of the earlier PCRs
+    // of the PID puts it at this packet position, a discontinuity starts over
+    fn pcr_inaccurate(&mut self, pid: u16, packet: &[u8]) -> bool {
+        let Some(pcr) = pcr_value(packet) else {
+            return false;
+        };
+        let current = (self.position, pcr);
+        let continues = !pcr_discontinuity(packet)
+            && self
+                .pcr_reference
+                .get(&pid)
+                .is_some_and(|reference| current.0 > reference.last.0 && pcr >= reference.last.1);
+        if !continues {
+            self.pcr_reference.insert(
+                pid,
+                PcrReference {
+                    first: current,
+                    last: current,
+                },
+            );
+            return false;
+        }
+        let Some(reference) = self.pcr_reference.get_mut(&pid) else {
+            return false;
+        };
+        let (first_position, first_pcr) = reference.first;
+        let (last_position, last_pcr) = reference.last;
+        reference.last = current;
+        if last_position == first_position {
+            return false;
+        }
+        let ticks_per_packet =
+            (last_pcr - first_pcr) as f64 / (last_position - first_position) as f64;
+        let expected = first_pcr as f64 + ticks_per_packet * (current.0 - first_position) as f64;
+        let inaccuracy_ns = (pcr as f64 - expected).abs() / PCR_TICKS_PER_NS;
+        if inaccuracy_ns > PCR_ACCURACY_NS {
+            debug!("TR101290: PCR of PID {} off by {:.0}ns", pid, inaccuracy_ns);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// PCR in 27 MHz units
+fn pcr_value(packet: &[u8]) -> Option<u64> {
+    if !has_pcr(packet) || packet.len() < 12 {
+        return None;
+    }
+    let base = (packet[6] as u64) << 25
+        | (packet[7] as u64) << 17
+        | (packet[8] as u64) << 9
+        | (packet[9] as u64) << 1
+        | (packet[10] as u64) >> 7;
+    let extension = ((packet[10] as u64 & 0x01) << 8) | packet[11] as u64;
+    Some(base * 300 + extension)
+}
+
+// Adaptation field with the discontinuity indicator set
+fn pcr_discontinuity(packet: &[u8]) -> bool {
+    (packet[3] & 0x20) != 0 && packet[4] > 0 && (packet[5] & 0x80) != 0
+}
+
+// Store the arrival time and return if it is further from the last one than the limit
+fn interval_exceeded(last: &mut Option<u64>, arrival_time: u64, limit_ms: u64) -> bool {
+    let exceeded = last.is_some_and(|last| arrival_time.saturating_sub(last) > limit_ms);
+    *last = Some(arrival_time);
+    exceeded
+}
+
+// Offset of the payload after the adaptation field, None without a payload
+pub fn payload_offset(packet: &[u8]) -> Option<usize> {
+    let adaptation_field_control = (packet[3] >> 4) & 0x03;
+    let offset = match adaptation_field_control {
+        0x01 => 4,
+        0x03 => 5 + packet[4] as usize,
+        _ => return None,
+    };
+    if offset < packet.len() {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+// Adaptation field with the PCR flag set
+pub fn has_pcr(packet: &[u8]) -> bool {
+    let adaptation_field_control = (packet[3] >> 4) & 0x03;
+    (adaptation_field_control & 0x02) != 0 && packet[4] > 0 && (packet[5] & 0x10) != 0
+}
+
+// Start of a PES packet with a PTS
+pub fn has_pts(packet: &[u8]) -> bool {
+    if (packet[1] & 0x40) == 0 {
+        return false;
+    }
+    let Some(offset) = payload_offset(packet) else {
+        return false;
+    };
+    let pes = &packet[offset..];
+    pes.len() > 7 && pes[0] == 0x00 && pes[1] == 0x00 && pes[2] == 0x01 && (pes[7] & 0x80) != 0
+}
+
+// PTS of a PES header in 90 kHz units
+pub fn pes_pts(pes: &[u8]) -> Option<u64> {
+    if pes.len() < 14 || pes[0..3] != [0x00, 0x00, 0x01] || (pes[7] & 0x80) == 0 {
+        return None;
+    }
+    let pts = &pes[9..14];
+    Some(
+        ((pts[0] as u64 >> 1) & 0x07) << 30
+            | (pts[1] as u64) << 22
+            | (pts[2] as u64 >> 1) << 15
+            | (pts[3] as u64) << 7
+            | (pts[4] as u64 >> 1),
+    )
+}
+
+// Collects the TS packets of a PID into whole PES packets
+pub struct PesAssembler {
+    pid: u16,
+    pes: Vec<u8>,
+}
+
+impl PesAssembler {
+    pub fn new(pid: u16) -> Self {
+        PesAssembler {
+            pid,
+            pes: Vec::new(),
+        }
+    }
+
+    pub fn pid(&self) -> u16 {
+        self.pid
+    }
+
+    // Add a TS packet, the previous PES is returned as its PTS and elementary stream data when
+    // this packet starts the next one
+    pub fn push(&mut self, packet: &[u8]) -> Option<(Option<u64>, Vec<u8>)> {
+        if packet.len() < TS_PACKET_SIZE || extract_pid(packet) != self.pid {
+            return None;
+        }
+        let offset = payload_offset(packet)?;
+        let unit_start = (packet[1] & 0x40) != 0;
+        let finished = if unit_start && !self.pes.is_empty() {
+            self.finish()
+        } else {
+            None
+        };
+        // without the start of a PES the payload can't be placed
+        if unit_start || !self.pes.is_empty() {
+            self.pes.extend_from_slice(&packet[offset..TS_PACKET_SIZE]);
+        }
+        finished
+    }
+
+    fn finish(&mut self) -> Option<(Option<u64>, Vec<u8>)> {
+        let pes = std::mem::take(&mut self.pes);
+        if pes.len() < 9 || pes[0..3] != [0x00, 0x00, 0x01] {
+            return None;
+        }
+        let data_start = 9 + pes[8] as usize;
+        if data_start > pes.len() {
+            return None;
+        }
+        Some((pes_pts(&pes), pes[data_start..].to_vec()))
+    }
+}
+
 // Implement a function to extract PID from a packet
 pub fn extract_pid(packet: &[u8]) -> u16 {
     if packet.len() < TS_PACKET_SIZE {
@@ -529,7 +995,8 @@ pub fn parse_pmt(packet: &[u8]) -> Pmt {
         extract_pid(packet),
         i
     );
-    while i + 5 <= packet.len() && i < 17 + section_length - 4 {
+    // the section length counts from byte 8, the entries end at the CRC
+    while i + 5 <= packet.len() && i < 8 + section_length - 4 {
         let stream_type = packet[i];
         let stream_pid = (((packet[i + 1] as u16) & 0x1F) << 8) | (packet[i + 2] as u16);
         let es_info_length = (((packet[i + 3] as usize) & 0x0F) << 8) | packet[i + 4] as usize;
@@ -561,7 +1028,7 @@ pub fn process_packet(
     tr101290_p2_check(packet, errors);
 
     let pid = stream_data_packet.pid;
-    let arrival_time = current_unix_timestamp_ms().unwrap_or(0);
+    let arrival_time_ns = stream_data_packet.last_arrival_time_ns;
 
     let mut pid_map = PID_MAP.lock().unwrap();
 
@@ -573,13 +1040,15 @@ pub fn process_packet(
         Some(stream_data_arc) => {
             // Existing StreamData instance found, update it
             let mut stream_data = Arc::clone(stream_data_arc);
-            Arc::make_mut(&mut stream_data).update_stats(packet.len(), arrival_time);
+            Arc::make_mut(&mut stream_data).update_stats(packet.len(), arrival_time_ns);
             Arc::make_mut(&mut stream_data).increment_count(1);
             if stream_data.pid != 0x1FFF && is_mpegts {
+                let error_count = stream_data.error_count;
                 Arc::make_mut(&mut stream_data)
                     .set_continuity_counter(stream_data_packet.continuity_counter);
+                errors.continuity_counter_errors += stream_data.error_count - error_count;
             }
-            let uptime = arrival_time - stream_data.start_time;
+            let uptime = (arrival_time_ns / 1_000_000).saturating_sub(stream_data.start_time);
 
             // print out each field of structure
             debug!("STATUS::PACKET:MODIFY[{}] pid: {} stream_type: {} bitrate: {} bitrate_max: {} bitrate_min: {} bitrate_avg: {} iat: {} iat_max: {} iat_min: {} iat_avg: {} errors: {} continuity_counter: {} timestamp: {} uptime: {} packet_offset: {}, packet_len: {}",
@@ -593,10 +1062,16 @@ pub fn process_packet(
             stream_data_packet.iat_avg = stream_data.iat_avg;
             stream_data_packet.iat_max = stream_data.iat_max;
             stream_data_packet.iat_min = stream_data.iat_min;
+            stream_data_packet.iat_ns = stream_data.iat_ns;
+            stream_data_packet.iat_avg_ns = stream_data.iat_avg_ns;
+            stream_data_packet.iat_max_ns = stream_data.iat_max_ns;
+            stream_data_packet.iat_min_ns = stream_data.iat_min_ns;
             stream_data_packet.stream_type = stream_data.stream_type.clone();
             stream_data_packet.start_time = stream_data.start_time;
+            stream_data_packet.start_time_ns = stream_data.start_time_ns;
             stream_data_packet.error_count = stream_data.error_count;
             stream_data_packet.last_arrival_time = stream_data.last_arrival_time;
+            stream_data_packet.last_arrival_time_ns = stream_data.last_arrival_time_ns;
             stream_data_packet.total_bits = stream_data.total_bits;
             stream_data_packet.count = stream_data.count;
 
@@ -619,7 +1094,7 @@ pub fn process_packet(
                     stream_data_packet.timestamp,
                     stream_data_packet.continuity_counter,
                 ));
-                Arc::make_mut(&mut stream_data).update_stats(packet.len(), arrival_time);
+                Arc::make_mut(&mut stream_data).update_stats(packet.len(), arrival_time_ns);
 
                 // print out each field of structure
                 info!("STATUS::PACKET:ADD[{}] pid: {} stream_type: {} bitrate: {} bitrate_max: {} bitrate_min: {} bitrate_avg: {} iat: {} iat_max: {} iat_min: {} iat_avg: {} errors: {} continuity_counter: {} timestamp: {} uptime: {}", stream_data.pid, stream_data.pid, stream_data.stream_type, stream_data.bitrate, stream_data.bitrate_max, stream_data.bitrate_min, stream_data.bitrate_avg, stream_data.iat, stream_data.iat_max, stream_data.iat_min, stream_data.iat_avg, stream_data.error_count, stream_data.continuity_counter, stream_data.timestamp, 0);
@@ -713,8 +1188,8 @@ pub fn update_pid_map(pmt_packet: &[u8], last_pat_packet: &[u8]) {
                         timestamp,
                         0,
                     ));
-                    // update stream_data stats
-                    Arc::make_mut(&mut stream_data).update_stats(pmt_packet.len(), timestamp);
+                    // the stats start with the first packet on the PID
+                    Arc::make_mut(&mut stream_data).set_program(program_number, pmt_pid);
 
                     // print out each field of structure
                     info!("STATUS::STREAM:CREATE[{}] pid: {} stream_type: {} bitrate: {} bitrate_max: {} bitrate_min: {} bitrate_avg: {} iat: {} iat_max: {} iat_min: {} iat_avg: {} errors: {} continuity_counter: {} timestamp: {} uptime: {}", stream_data.pid, stream_data.pid, stream_data.stream_type, stream_data.bitrate, stream_data.bitrate_max, stream_data.bitrate_min, stream_data.bitrate_avg, stream_data.iat, stream_data.iat_max, stream_data.iat_min, stream_data.iat_avg, stream_data.error_count, stream_data.continuity_counter, stream_data.timestamp, 0);
@@ -725,8 +1200,9 @@ pub fn update_pid_map(pmt_packet: &[u8], last_pat_packet: &[u8]) {
                     let stream_data_arc = pid_map.get_mut(&stream_pid).unwrap();
                     let mut stream_data = Arc::clone(stream_data_arc);
 
-                    // update the stream type
+                    // update the stream type and the program it belongs to
                     Arc::make_mut(&mut stream_data).update_stream_type(stream_type.to_string());
+                    Arc::make_mut(&mut stream_data).set_program(program_number, pmt_pid);
 
                     // print out each field of structure
                     debug!("STATUS::STREAM:UPDATE[{}] pid: {} stream_type: {} bitrate: {} bitrate_max: {} bitrate_min: {} bitrate_avg: {} iat: {} iat_max: {} iat_min: {} iat_avg: {} errors: {} continuity_counter: {} timestamp: {} uptime: {}", stream_data.pid, stream_data.pid, stream_data.stream_type, stream_data.bitrate, stream_data.bitrate_max, stream_data.bitrate_min, stream_data.bitrate_avg, stream_data.iat, stream_data.iat_max, stream_data.iat_min, stream_data.iat_avg, stream_data.error_count, stream_data.continuity_counter, stream_data.timestamp, 0);
@@ -769,6 +1245,31 @@ pub fn identify_video_pid(pmt_packet: &[u8]) -> Option<(u16, Codec)> {
     })
 }
 
+// The first audio PID of the PMT and its codec
+pub fn identify_audio_pid(pmt_packet: &[u8]) -> Option<(u16, AudioCodec)> {
+    let pmt = parse_pmt(pmt_packet);
+    pmt.entries.iter().find_map(|entry| {
+        let codec = match entry.stream_type {
+            0x03 | 0x04 => Some(AudioCodec::Mpeg),
+            0x0F => Some(AudioCodec::AacAdts),
+            0x11 => Some(AudioCodec::AacLatm),
+            0x81 => Some(AudioCodec::Ac3),
+            0x87 => Some(AudioCodec::Eac3),
+            _ => None,
+        };
+        codec.map(|c| (entry.stream_pid, c))
+    })
+}
+
+// PID of the SCTE-35 splice information in the PMT
+pub fn identify_scte35_pid(pmt_packet: &[u8]) -> Option<u16> {
+    let pmt = parse_pmt(pmt_packet);
+    pmt.entries
+        .iter()
+        .find(|entry| entry.stream_type == 0x86)
+        .map(|entry| entry.stream_pid)
+}
+
 // Check if the packet is MPEG-TS or SMPTE 2110
 pub fn is_mpegts_or_smpte2110(packet: &[u8]) -> i32 {
     // Check for MPEG-TS (starts with 0x47 sync byte)
@@ -822,6 +1323,7 @@ pub fn process_smpte2110_packet(
     packet: Arc<Vec<u8>>,
     _packet_size: usize,
     start_time: u64,
+    arrival_time_ns: u64,
     debug: bool,
 ) -> Vec<StreamData> {
     let mut streams = Vec::new();
@@ -873,8 +1375,7 @@ pub fn process_smpte2110_packet(
                 );
 
                 // Update StreamData stats and RTP fields
-                stream_data
-                    .update_stats(rtp_payload_length, current_unix_timestamp_ms().unwrap_or(0));
+                stream_data.update_stats(rtp_payload_length, arrival_time_ns);
                 stream_data.set_rtp_fields(
                     timestamp,
                     payload_type,
@@ -915,6 +1416,7 @@ pub fn process_mpegts_packet(
     packet: Arc<Vec<u8>>,
     packet_size: usize,
     start_time: u64,
+    arrival_time_ns: u64,
 ) -> Vec<StreamData> {
     let mut start = payload_offset;
     let mut read_size = packet_size;
@@ -948,7 +1450,7 @@ pub fn process_mpegts_packet(
                 timestamp,
                 continuity_counter,
             );
-            stream_data.update_stats(packet_size, current_unix_timestamp_ms().unwrap_or(0));
+            stream_data.update_stats(packet_size, arrival_time_ns);
             streams.push(stream_data);
         } else {
             error!("ProcessPacket: Not MPEG-TS");
@@ -959,3 +1461,113 @@ pub fn process_mpegts_packet(
 
     streams
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::current_unix_timestamp_ns;
+
+    const START_MS: u64 = 1_000;
+
+    fn stream_data() -> StreamData {
+        StreamData::new(
+            Arc::new(Vec::new()),
+            0,
+            0,
+            0x100,
+            "video".to_string(),
+            START_MS,
+            START_MS,
+            0,
+        )
+    }
+
+    #[test]
+    fn update_stats_keeps_ns_and_derives_ms() {
+        let mut stream_data = stream_data();
+        assert_eq!(stream_data.start_time_ns, 1_000_000_000);
+        assert_eq!(stream_data.last_arrival_time_ns, 0);
+        // the first packet only starts the clock, there is no IAT or bitrate yet
+        stream_data.update_stats(TS_PACKET_SIZE, 1_001_500_000);
+        assert_eq!(stream_data.start_time_ns, 1_001_500_000);
+        assert_eq!(stream_data.start_time, 1_001);
+        assert_eq!(stream_data.last_arrival_time_ns, 1_001_500_000);
+        assert_eq!(stream_data.last_arrival_time, 1_001);
+        assert_eq!(stream_data.iat_ns, 0);
+        assert_eq!(stream_data.iat_max_ns, 0);
+        assert_eq!(stream_data.bitrate, 0);
+        assert_eq!(stream_data.total_bits, 1504);
+
+        stream_data.update_stats(TS_PACKET_SIZE, 1_005_406_250);
+        assert_eq!(stream_data.iat_ns, 3_906_250);
+        assert_eq!(stream_data.iat_max_ns, 3_906_250);
+        assert_eq!(stream_data.iat_avg_ns, 1_953_125);
+        // the ms fields are the ns ones cut down
+        assert_eq!(stream_data.iat, 3);
+        assert_eq!(stream_data.iat_max, 3);
+        assert_eq!(stream_data.iat_avg, 1);
+        assert_eq!(stream_data.last_arrival_time_ns, 1_005_406_250);
+        assert_eq!(stream_data.last_arrival_time, 1_005);
+        // 1504 bits in the 1/256 s since the start
+        assert_eq!(stream_data.bitrate, 385_024);
+        assert_eq!(stream_data.bitrate_max, 385_024);
+        assert_eq!(stream_data.bitrate_avg, 192_512);
+        assert_eq!(stream_data.total_bits, 3008);
+    }
+
+    #[test]
+    fn update_stats_keeps_sub_ms_iat() {
+        let mut stream_data = stream_data();
+        stream_data.update_stats(TS_PACKET_SIZE, 1_001_000_000);
+        stream_data.update_stats(TS_PACKET_SIZE, 1_001_000_300);
+        assert_eq!(stream_data.iat_ns, 300);
+        assert_eq!(stream_data.iat, 0);
+        stream_data.update_stats(TS_PACKET_SIZE, 1_001_900_300);
+        assert_eq!(stream_data.iat_ns, 900_000);
+        assert_eq!(stream_data.iat_max_ns, 900_000);
+        assert_eq!(stream_data.iat_max, 0);
+    }
+
+    #[test]
+    fn update_stats_follows_the_capture_clock() {
+        let now_ns = current_unix_timestamp_ns().unwrap();
+        // a PHC on TAI ahead of the system clock and an unsynced NIC counter
+        for first_arrival_ns in [now_ns + 37_000_000_000, 5_000_000_000] {
+            let mut stream_data = stream_data();
+            stream_data.update_stats(TS_PACKET_SIZE, first_arrival_ns);
+            stream_data.update_stats(TS_PACKET_SIZE, first_arrival_ns + 3_906_250);
+            assert_eq!(stream_data.start_time_ns, first_arrival_ns);
+            assert_eq!(stream_data.iat_ns, 3_906_250);
+            assert_eq!(stream_data.iat_max_ns, 3_906_250);
+            assert_eq!(stream_data.bitrate, 385_024);
+        }
+    }
+
+    #[test]
+    fn continuity_counter_gaps_are_errors() {
+        let mut stream_data = stream_data();
+        for cc in [1, 2, 2, 3, 15, 0, 1] {
+            stream_data.set_continuity_counter(cc);
+        }
+        // 3 to 15 is the only gap, a repeat and the wrap are not
+        assert_eq!(stream_data.error_count, 1);
+    }
+
+    #[test]
+    fn reads_the_pcr_and_pts() {
+        let mut packet = vec![0xFF; TS_PACKET_SIZE];
+        packet[..4].copy_from_slice(&[0x47, 0x41, 0x00, 0x30]);
+        // PCR base 0x1_0000_0001 and extension 0x123
+        packet[4..12].copy_from_slice(&[0x07, 0x10, 0x80, 0x00, 0x00, 0x00, 0xFF, 0x23]);
+        assert!(has_pcr(&packet));
+        assert_eq!(pcr_value(&packet), Some(0x1_0000_0001 * 300 + 0x123));
+        assert!(!pcr_discontinuity(&packet));
+        packet[5] |= 0x80;
+        assert!(pcr_discontinuity(&packet));
+
+        let pes = [
+            0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0x80, 0x05, 0x21, 0x00, 0x01, 0x00, 0x07,
+        ];
+        assert_eq!(pes_pts(&pes), Some(3));
+    }
+}
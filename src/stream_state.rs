@@ -0,0 +1,81 @@
+/*
+ * stream_state.rs
+ * ---------------
+ * Snapshot of what the pipeline is putting on screen, the topic, chapter, persona, latest
+ * paragraph and its image prompt. The pipeline updates it as paragraphs go through and the
+ * Twitch chat worker reads it so answers to viewers can refer to what is being shown.
+*/
+
+use crate::current_unix_timestamp_ms;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::Mutex;
+
+static STREAM_STATE: Lazy<Mutex<StreamState>> = Lazy::new(|| Mutex::new(StreamState::default()));
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StreamState {
+    pub persona: String,
+    pub topic: String, // latest viewer requested topic
+    pub chapter: String,
+    pub paragraph: String,
+    pub image_prompt: String,
+    pub paragraph_count: usize,
+    pub updated_ms: u64, // unix time in ms of the last change
+}
+
+impl StreamState {
+    // Lines for an LLM prompt, the paragraph and image prompt cut to max_chars each
+    pub fn prompt_context(&self, max_chars: usize) -> String {
+        let mut context = String::new();
+        let fields = [
+            ("Persona", &self.persona),
+            ("Current topic", &self.topic),
+            ("Current chapter", &self.chapter),
+            ("On screen now", &self.paragraph),
+            ("Current image", &self.image_prompt),
+        ];
+        for (name, value) in fields {
+            let value = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+            if value.is_empty() {
+                continue;
+            }
+            let value = match value.char_indices().nth(max_chars) {
+                Some((end, _)) => format!("{}...", &value[..end]),
+                None => value,
+            };
+            context.push_str(&format!("{}: {}\n", name, value));
+        }
+        context
+    }
+}
+
+fn update(change: impl FnOnce(&mut StreamState)) {
+    let mut state = STREAM_STATE.lock().unwrap();
+    change(&mut state);
+    state.updated_ms = current_unix_timestamp_ms().unwrap_or(0);
+}
+
+pub fn stream_state() -> StreamState {
+    STREAM_STATE.lock().unwrap().clone()
+}
+
+pub fn set_stream_persona(persona: &str) {
+    update(|state| state.persona = persona.to_string());
+}
+
+pub fn set_stream_topic(topic: &str) {
+    update(|state| state.topic = topic.to_string());
+}
+
+pub fn set_stream_chapter(chapter: &str) {
+    update(|state| state.chapter = chapter.to_string());
+}
+
+pub fn set_stream_paragraph(paragraph_count: usize, paragraph: &str, image_prompt: &str) {
+    update(|state| {
+        state.paragraph_count = paragraph_count;
+        state.paragraph = paragraph.to_string();
+        state.image_prompt = image_prompt.to_string();
+    });
+}
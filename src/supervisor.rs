@@ -0,0 +1,256 @@
+/*
+ * supervisor.rs
+ * -------------
+ * Restarts the long running tasks, network capture, pipeline, output and Twitch chat, when
+ * they panic or stop with an error so a 24/7 channel keeps going. Restarts back off
+ * exponentially up to a maximum and the state of every supervised task is kept for the
+ * health reports.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::event_log::log_event;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+// A task that ran this long before failing starts over from the first backoff
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+static TASKS: Lazy<Mutex<Vec<TaskStatus>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartMode {
+    Never,
+    OnFailure, // panics and errors
+    Always,    // also when the task returns while still running
+}
+
+impl std::str::FromStr for RestartMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode.to_lowercase().as_str() {
+            "never" => Ok(RestartMode::Never),
+            "on-failure" | "on_failure" => Ok(RestartMode::OnFailure),
+            "always" => Ok(RestartMode::Always),
+            _ => Err(format!("Invalid restart policy {}", mode)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_restarts: u32, // 0 is unlimited
+}
+
+impl RestartPolicy {
+    pub fn new(
+        mode: RestartMode,
+        backoff: Duration,
+        max_backoff: Duration,
+        max_restarts: u32,
+    ) -> Self {
+        RestartPolicy {
+            mode,
+            backoff,
+            max_backoff,
+            max_restarts,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        let mode = args
+            .supervisor_restart
+            .parse::<RestartMode>()
+            .unwrap_or_else(|e| {
+                error!("{}, restarting on failure", e);
+                RestartMode::OnFailure
+            });
+        RestartPolicy::new(
+            mode,
+            Duration::from_millis(args.supervisor_backoff_ms),
+            Duration::from_millis(args.supervisor_max_backoff_ms),
+            args.supervisor_max_restarts,
+        )
+    }
+
+    fn restarts(&self, failed: bool) -> bool {
+        match self.mode {
+            RestartMode::Never => false,
+            RestartMode::OnFailure => failed,
+            RestartMode::Always => true,
+        }
+    }
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::new(
+            RestartMode::OnFailure,
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            0,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Stopped,
+    Failed, // gave up restarting
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub started_ms: u64, // unix time in ms of the latest start
+}
+
+// Snapshot of all the supervised tasks
+pub fn supervised_tasks() -> Vec<TaskStatus> {
+    TASKS.lock().unwrap().clone()
+}
+
+fn update_task(name: &str, change: impl FnOnce(&mut TaskStatus)) {
+    let mut tasks = TASKS.lock().unwrap();
+    let index = match tasks.iter().position(|task| task.name == name) {
+        Some(index) => index,
+        None => {
+            tasks.push(TaskStatus {
+                name: name.to_string(),
+                state: TaskState::Running,
+                restarts: 0,
+                last_error: None,
+                started_ms: 0,
+            });
+            tasks.len() - 1
+        }
+    };
+    change(&mut tasks[index]);
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Run the task made by start and make a new one by the policy when it panics or fails, until
+// running is cleared. The handle finishes when the task is no longer restarted.
+pub fn supervise<F, Fut>(
+    name: &str,
+    policy: RestartPolicy,
+    running: Arc<AtomicBool>,
+    mut start: F,
+) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let mut restarts = 0;
+        let mut backoff = policy.backoff;
+        loop {
+            update_task(&name, |task| {
+                task.state = TaskState::Running;
+                task.started_ms = current_unix_timestamp_ms().unwrap_or(0);
+            });
+            let started = Instant::now();
+            let result = match tokio::spawn(start()).await {
+                Ok(result) => result,
+                Err(e) if e.is_panic() => {
+                    Err(format!("panicked: {}", panic_message(e.into_panic())))
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            if !running.load(Ordering::SeqCst) {
+                update_task(&name, |task| task.state = TaskState::Stopped);
+                break;
+            }
+
+            let failed = result.is_err();
+            let error = result.err();
+            match &error {
+                Some(e) => error!("The {} task failed: {}", name, e),
+                None => info!("The {} task exited", name),
+            }
+            if !policy.restarts(failed) {
+                update_task(&name, |task| {
+                    task.state = if failed {
+                        TaskState::Failed
+                    } else {
+                        TaskState::Stopped
+                    };
+                    task.last_error = error.clone();
+                });
+                break;
+            }
+            // a stable run starts the count over, max restarts limits a crash loop
+            if started.elapsed() >= STABLE_RUN {
+                backoff = policy.backoff;
+                restarts = 0;
+            }
+            if policy.max_restarts > 0 && restarts >= policy.max_restarts {
+                error!(
+                    "The {} task failed {} restarts, giving up",
+                    name, policy.max_restarts
+                );
+                update_task(&name, |task| {
+                    task.state = TaskState::Failed;
+                    task.last_error = error.clone();
+                });
+                break;
+            }
+
+            restarts += 1;
+            warn!(
+                "Restarting the {} task in {} ms, restart {}",
+                name,
+                backoff.as_millis(),
+                restarts
+            );
+            log_event(
+                "supervisor",
+                "restart",
+                json!({
+                    "task": name,
+                    "restarts": restarts,
+                    "error": error,
+                    "backoff_ms": backoff.as_millis() as u64,
+                }),
+            );
+            update_task(&name, |task| {
+                task.state = TaskState::Restarting;
+                task.restarts = restarts;
+                task.last_error = error.clone();
+            });
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(policy.max_backoff.max(policy.backoff));
+            if !running.load(Ordering::SeqCst) {
+                update_task(&name, |task| task.state = TaskState::Stopped);
+                break;
+            }
+        }
+    })
+}
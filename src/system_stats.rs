@@ -11,7 +11,7 @@ static SYSTEM: Lazy<Mutex<(System, Instant)>> = Lazy::new(|| {
     Mutex::new((system, Instant::now()))
 });
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SystemStats {
     total_memory: u64,
     used_memory: u64,
@@ -28,14 +28,14 @@ pub struct SystemStats {
     network_stats: Vec<NetworkStats>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NetworkStats {
     name: String,
     received: u64,
     transmitted: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoadAverage {
     one: f64,
     five: f64,
@@ -95,3 +95,21 @@ pub fn get_system_stats() -> SystemStats {
         network_stats,
     }
 }
+
+impl SystemStats {
+    // One line summary for the stats ticker
+    pub fn summary(&self) -> String {
+        // sysinfo reports memory in KB
+        let gib = |kb: u64| kb as f64 / 1024.0 / 1024.0;
+        format!(
+            "CPU {:.0}% of {} cores | Memory {:.1}/{:.1} GiB | Load {:.2} {:.2} {:.2}",
+            self.cpu_usage,
+            self.cpu_count,
+            gib(self.used_memory),
+            gib(self.total_memory),
+            self.load_avg.one,
+            self.load_avg.five,
+            self.load_avg.fifteen
+        )
+    }
+}
@@ -0,0 +1,345 @@
+/*
+ * thumbnails.rs
+ * -------------
+ * Periodic thumbnails of the monitored H.264 video with the openh264 decoder. The TS packets
+ * of the video PID are collected into access units on a thread of their own and only intra
+ * coded pictures are decoded, they don't need the frames before them so the decoder stays
+ * idle between thumbnails. Thumbnails are kept with their capture time and PTS for the
+ * control API and a vision model, the latest is also the picture in picture frame.
+*/
+
+use crate::args::Args;
+use crate::current_unix_timestamp_ms;
+use crate::overlay::set_monitor_frame;
+use crate::stream_data::PesAssembler;
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use image::codecs::jpeg::JpegEncoder;
+use image::{imageops, ColorType, ImageBuffer, Rgb};
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use openh264::decoder::Decoder;
+use openh264::formats::YUVSource;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static THUMBNAILS: Lazy<Mutex<VecDeque<Thumbnail>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// TS packets queued for the decoder thread before new ones are dropped
+const FEED_QUEUE_SIZE: usize = 4096;
+const JPEG_QUALITY: u8 = 80;
+
+// H.264 NAL unit types
+const NAL_SLICE: u8 = 1;
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Thumbnail {
+    pub timestamp_ms: u64,
+    pub pts: Option<u64>, // 90 kHz PTS of the picture
+    pub pid: u16,
+    pub width: u32,
+    pub height: u32,
+    #[serde(skip)]
+    pub jpeg: Vec<u8>,
+}
+
+impl Thumbnail {
+    // The JPEG as a data URL, for the control API and the image input of a vision model
+    pub fn data_url(&self) -> String {
+        format!("data:image/jpeg;base64,{}", STANDARD.encode(&self.jpeg))
+    }
+}
+
+// Thumbnails kept, oldest first
+pub fn thumbnails() -> Vec<Thumbnail> {
+    THUMBNAILS.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn latest_thumbnail() -> Option<Thumbnail> {
+    THUMBNAILS.lock().unwrap().back().cloned()
+}
+
+// The last thumbnail taken at or before the time
+pub fn thumbnail_at(timestamp_ms: u64) -> Option<Thumbnail> {
+    THUMBNAILS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|thumbnail| thumbnail.timestamp_ms <= timestamp_ms)
+        .cloned()
+}
+
+fn store_thumbnail(thumbnail: Thumbnail, history: usize) {
+    let mut thumbnails = THUMBNAILS.lock().unwrap();
+    thumbnails.push_back(thumbnail);
+    while thumbnails.len() > history.max(1) {
+        thumbnails.pop_front();
+    }
+}
+
+// NAL units of Annex B data without their start codes
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index] == 0 && data[index + 1] == 0 && data[index + 2] == 1 {
+            starts.push(index + 3);
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, start)| {
+            let mut end = starts.get(n + 1).map_or(data.len(), |next| next - 3);
+            // the zero of a four byte start code belongs to the next one
+            while end > *start && data[end - 1] == 0 {
+                end -= 1;
+            }
+            &data[*start..end]
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+// Unsigned Exp-Golomb value at the bit position, moving the position past it
+fn read_ue(data: &[u8], bit: &mut usize) -> Option<u32> {
+    let read_bit = |bit: usize| -> Option<u32> {
+        let byte = data.get(bit / 8)?;
+        Some(((byte >> (7 - bit % 8)) & 1) as u32)
+    };
+    let mut zeros = 0;
+    while read_bit(*bit)? == 0 {
+        zeros += 1;
+        *bit += 1;
+        if zeros > 31 {
+            return None;
+        }
+    }
+    *bit += 1;
+    let mut value = 0u32;
+    for _ in 0..zeros {
+        value = (value << 1) | read_bit(*bit)?;
+        *bit += 1;
+    }
+    Some((1u32 << zeros) - 1 + value)
+}
+
+// An IDR or a slice of type I or SI, the pictures that decode on their own
+fn is_intra(nal: &[u8]) -> bool {
+    match nal[0] & 0x1F {
+        NAL_IDR => true,
+        NAL_SLICE => {
+            // first_mb_in_slice then slice_type, both near the start before any emulation
+            // prevention bytes could matter
+            let header = &nal[1..nal.len().min(8)];
+            let mut bit = 0;
+            read_ue(header, &mut bit).is_some()
+                && matches!(read_ue(header, &mut bit).map(|t| t % 5), Some(2) | Some(4))
+        }
+        _ => false,
+    }
+}
+
+fn with_start_codes<'a>(nals: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut data = Vec::new();
+    for nal in nals {
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(nal);
+    }
+    data
+}
+
+fn encode_jpeg(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Result<Vec<u8>> {
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
+
+pub struct ThumbnailExtractor {
+    pes: Option<PesAssembler>,
+    decoder: Decoder,
+    sps: Vec<u8>, // latest parameter sets with start codes
+    pps: Vec<u8>,
+    interval: Duration,
+    width: u32,
+    history: usize,
+    last: Option<Instant>,
+}
+
+impl ThumbnailExtractor {
+    pub fn new(interval: Duration, width: u32, history: usize) -> Result<Self> {
+        Ok(ThumbnailExtractor {
+            pes: None,
+            decoder: Decoder::new().map_err(|e| anyhow!("Failed to create decoder: {}", e))?,
+            sps: Vec::new(),
+            pps: Vec::new(),
+            interval,
+            width,
+            history,
+            last: None,
+        })
+    }
+
+    // Add a TS packet of the video PID, a change of PID starts over
+    pub fn push(&mut self, pid: u16, packet: &[u8]) {
+        if self.pes.as_ref().map(|pes| pes.pid()) != Some(pid) {
+            self.pes = Some(PesAssembler::new(pid));
+            self.sps.clear();
+            self.pps.clear();
+        }
+        let Some(pes) = self.pes.as_mut() else {
+            return;
+        };
+        if let Some((pts, data)) = pes.push(packet) {
+            self.access_unit(pid, pts, &data);
+        }
+    }
+
+    fn access_unit(&mut self, pid: u16, pts: Option<u64>, data: &[u8]) {
+        let nals = nal_units(data);
+        for (nal_type, parameter_set) in [(NAL_SPS, &mut self.sps), (NAL_PPS, &mut self.pps)] {
+            let nals = nals.iter().copied().filter(|nal| nal[0] & 0x1F == nal_type);
+            let data = with_start_codes(nals);
+            if !data.is_empty() {
+                *parameter_set = data;
+            }
+        }
+
+        let due = self
+            .last
+            .map_or(true, |last| last.elapsed() >= self.interval);
+        if !due
+            || self.sps.is_empty()
+            || self.pps.is_empty()
+            || !nals.iter().any(|nal| is_intra(nal))
+        {
+            return;
+        }
+        self.last = Some(Instant::now());
+
+        let mut picture = [self.sps.as_slice(), self.pps.as_slice()].concat();
+        picture.extend(with_start_codes(
+            nals.into_iter()
+                .filter(|nal| !matches!(nal[0] & 0x1F, NAL_SPS | NAL_PPS)),
+        ));
+        match self.decode(&picture) {
+            Ok(Some(image)) => self.thumbnail(pid, pts, image),
+            Ok(None) => debug!("No picture decoded from the intra frame of PID {}", pid),
+            Err(e) => {
+                error!("Failed to decode a thumbnail of PID {}: {}", pid, e);
+                // a fresh decoder for the next one
+                if let Ok(decoder) = Decoder::new() {
+                    self.decoder = decoder;
+                }
+            }
+        }
+    }
+
+    fn decode(&mut self, picture: &[u8]) -> Result<Option<ImageBuffer<Rgb<u8>, Vec<u8>>>> {
+        let Some(yuv) = self.decoder.decode(picture).map_err(|e| anyhow!("{}", e))? else {
+            return Ok(None);
+        };
+        let (width, height) = yuv.dimensions();
+        let mut rgb = vec![0; width * height * 3];
+        yuv.write_rgb8(&mut rgb);
+        Ok(ImageBuffer::from_raw(width as u32, height as u32, rgb))
+    }
+
+    fn thumbnail(&self, pid: u16, pts: Option<u64>, image: ImageBuffer<Rgb<u8>, Vec<u8>>) {
+        let (width, height) = image.dimensions();
+        let image = if self.width > 0 && self.width < width {
+            let thumbnail_height = (height as u64 * self.width as u64 / width as u64).max(1);
+            imageops::resize(
+                &image,
+                self.width,
+                thumbnail_height as u32,
+                imageops::FilterType::Triangle,
+            )
+        } else {
+            image
+        };
+        set_monitor_frame(&image);
+        match encode_jpeg(&image) {
+            Ok(jpeg) => store_thumbnail(
+                Thumbnail {
+                    timestamp_ms: current_unix_timestamp_ms().unwrap_or(0),
+                    pts,
+                    pid,
+                    width: image.width(),
+                    height: image.height(),
+                    jpeg,
+                },
+                self.history,
+            ),
+            Err(e) => error!("Failed to encode a thumbnail of PID {}: {}", pid, e),
+        }
+    }
+}
+
+// Hands the video packets from the capture loop to the decoder thread without blocking it
+pub struct ThumbnailFeed {
+    sender: SyncSender<(u16, Vec<u8>)>,
+    dropped: u64,
+}
+
+impl ThumbnailFeed {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.thumbnail_interval_ms == 0 || !args.ai_network_stats {
+            return None;
+        }
+        let mut extractor = match ThumbnailExtractor::new(
+            Duration::from_millis(args.thumbnail_interval_ms),
+            args.thumbnail_width,
+            args.thumbnail_history,
+        ) {
+            Ok(extractor) => extractor,
+            Err(e) => {
+                error!("{}, no thumbnails of the monitored stream", e);
+                return None;
+            }
+        };
+        let (sender, receiver): (_, Receiver<(u16, Vec<u8>)>) = sync_channel(FEED_QUEUE_SIZE);
+        std::thread::spawn(move || {
+            for (pid, packet) in receiver {
+                extractor.push(pid, &packet);
+            }
+        });
+        info!(
+            "Decoding thumbnails of the monitored H.264 video every {} ms",
+            args.thumbnail_interval_ms
+        );
+        Some(ThumbnailFeed { sender, dropped: 0 })
+    }
+
+    // Queue a TS packet of the video PID, dropped when the decoder is behind
+    pub fn push(&mut self, pid: u16, packet: &[u8]) {
+        match self.sender.try_send((pid, packet.to_vec())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped % 1000 == 1 {
+                    debug!(
+                        "Thumbnail decoder is behind, {} packets dropped",
+                        self.dropped
+                    );
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
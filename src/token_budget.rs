@@ -0,0 +1,358 @@
+/*
+ * token_budget.rs
+ * ---------------
+ * Token budgets of the prompt parts, history, stats context, chat context and the retrieved
+ * data snippets, from the context window of the model that gets the prompt. The window of
+ * each model comes from a table of the known ones or --llm-context-windows, what is left of
+ * it after the answer and the system prompt is split between the parts by
+ * --token-budget-shares.
+*/
+
+use crate::args::Args;
+use crate::count_tokens;
+use crate::openai_api::Message;
+use log::error;
+
+// The parts in the prompts of the main loop, twitch chat and the stats questions
+pub const MAIN_PARTS: [BudgetPart; 2] = [BudgetPart::History, BudgetPart::Stats];
+pub const CHAT_PARTS: [BudgetPart; 1] = [BudgetPart::Chat];
+pub const QA_PARTS: [BudgetPart; 2] = [BudgetPart::History, BudgetPart::Rag];
+
+// Context window of a model that is not in the table or the args
+const DEFAULT_CONTEXT_WINDOW: usize = 8192;
+
+// Known context windows by model name, the longest name contained in the model wins
+const CONTEXT_WINDOWS: [(&str, usize); 12] = [
+    ("mistral", 32768),
+    ("mixtral", 32768),
+    ("gemma", 8192),
+    ("llama-2", 4096),
+    ("llama2", 4096),
+    ("llama-3", 8192),
+    ("llama3", 8192),
+    ("gpt-3.5", 16385),
+    ("gpt-4", 8192),
+    ("gpt-4-turbo", 128000),
+    ("gpt-4o", 128000),
+    ("claude", 200000),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPart {
+    History,
+    Stats,
+    Chat,
+    Rag,
+}
+
+impl std::str::FromStr for BudgetPart {
+    type Err = String;
+
+    fn from_str(part: &str) -> Result<Self, String> {
+        match part.trim().to_lowercase().as_str() {
+            "history" => Ok(BudgetPart::History),
+            "stats" => Ok(BudgetPart::Stats),
+            "chat" => Ok(BudgetPart::Chat),
+            "rag" => Ok(BudgetPart::Rag),
+            _ => Err(format!("Invalid token budget part {}", part)),
+        }
+    }
+}
+
+impl BudgetPart {
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+// Share of each part, relative to the sum of the parts sharing a prompt
+#[derive(Debug, Clone, Copy)]
+pub struct BudgetShares([usize; 4]);
+
+impl std::str::FromStr for BudgetShares {
+    type Err = String;
+
+    // "history=60,stats=25,chat=10,rag=5", parts left out get no share
+    fn from_str(shares: &str) -> Result<Self, String> {
+        let mut parsed = [0; 4];
+        for entry in shares.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (part, share) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid token budget share {}", entry))?;
+            let share = share
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid token budget share {}", entry))?;
+            parsed[part.parse::<BudgetPart>()?.index()] = share;
+        }
+        Ok(BudgetShares(parsed))
+    }
+}
+
+impl BudgetShares {
+    pub fn from_args(args: &Args) -> Self {
+        args.token_budget_shares
+            .parse::<BudgetShares>()
+            .unwrap_or_else(|e| {
+                error!("{}, using the default shares", e);
+                BudgetShares::default()
+            })
+    }
+}
+
+impl Default for BudgetShares {
+    fn default() -> Self {
+        BudgetShares([60, 25, 10, 5])
+    }
+}
+
+// Context window of the model, the args override the table
+pub fn context_window(args: &Args, model: &str) -> usize {
+    let model = model.to_lowercase();
+    let configured: Vec<(String, usize)> = args
+        .llm_context_windows
+        .split(',')
+        .filter_map(|entry| {
+            let (name, tokens) = entry.split_once('=')?;
+            match tokens.trim().parse::<usize>() {
+                Ok(tokens) => Some((name.trim().to_lowercase(), tokens)),
+                Err(_) => {
+                    error!("Invalid llm context window {}", entry);
+                    None
+                }
+            }
+        })
+        .collect();
+    let known = CONTEXT_WINDOWS
+        .iter()
+        .map(|(name, tokens)| (name.to_string(), *tokens));
+
+    // the args are matched first, a longer name is a better match
+    let best = |windows: &mut dyn Iterator<Item = (String, usize)>| {
+        windows
+            .filter(|(name, _)| !name.is_empty() && model.contains(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, tokens)| tokens)
+    };
+    best(&mut configured.into_iter())
+        .or_else(|| best(&mut known.into_iter()))
+        .unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+// The model the main loop prompts
+pub fn main_model(args: &Args) -> &str {
+    if args.use_api || args.use_openai {
+        &args.model
+    } else {
+        &args.candle_llm
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBudget {
+    pub context_window: usize,
+    pub reserved: usize, // answer and the fixed parts of the prompt
+    shares: BudgetShares,
+}
+
+impl TokenBudget {
+    pub fn new(context_window: usize, reserved: usize, shares: BudgetShares) -> Self {
+        TokenBudget {
+            context_window,
+            reserved,
+            shares,
+        }
+    }
+
+    // Budget of a prompt to the model answering with up to max_tokens
+    pub fn for_model(args: &Args, model: &str, max_tokens: usize) -> Self {
+        TokenBudget::new(
+            context_window(args, model),
+            max_tokens,
+            BudgetShares::from_args(args),
+        )
+    }
+
+    // Reserve the tokens of a part of the prompt that is always sent, like the system prompt
+    pub fn reserve(mut self, text: &str) -> Self {
+        self.reserved += count_tokens(text);
+        self
+    }
+
+    pub fn available(&self) -> usize {
+        self.context_window.saturating_sub(self.reserved)
+    }
+
+    // Tokens of the part, split by the shares of the parts the prompt has
+    pub fn allocation(&self, part: BudgetPart, sharing: &[BudgetPart]) -> usize {
+        let total: usize = sharing.iter().map(|part| self.shares.0[part.index()]).sum();
+        if total == 0 {
+            return 0;
+        }
+        self.available() * self.shares.0[part.index()] / total
+    }
+}
+
+// The start of the text that fits the tokens, cut between words
+pub fn cut_to_tokens(text: &str, tokens: usize) -> String {
+    let mut used = 0;
+    let mut end = 0;
+    for word in text.split_inclusive(char::is_whitespace) {
+        used += count_tokens(word);
+        if used > tokens {
+            break;
+        }
+        end += word.len();
+    }
+    text[..end].to_string()
+}
+
+// Index of the oldest of the newest parts that fit the budget
+fn first_kept(tokens: &[usize], budget: usize) -> usize {
+    let mut used = 0;
+    let mut first = tokens.len();
+    for (index, tokens) in tokens.iter().enumerate().rev() {
+        if used + tokens > budget {
+            break;
+        }
+        used += tokens;
+        first = index;
+    }
+    first
+}
+
+// Drop the oldest texts until the rest fit the budget, the newest is cut down when it is too
+// long alone. Returns how many were dropped.
+pub fn fit_texts(texts: &mut Vec<String>, budget: usize) -> usize {
+    let tokens: Vec<usize> = texts.iter().map(|text| count_tokens(text)).collect();
+    let mut first = first_kept(&tokens, budget);
+    if first == texts.len() && first > 0 {
+        first -= 1;
+        texts[first] = cut_to_tokens(&texts[first], budget);
+    }
+    texts.drain(..first);
+    first
+}
+
+// fit_texts for chat messages
+pub fn fit_messages(messages: &mut Vec<Message>, budget: usize) -> usize {
+    let tokens: Vec<usize> = messages
+        .iter()
+        .map(|message| count_tokens(&message.content))
+        .collect();
+    let mut first = first_kept(&tokens, budget);
+    if first == messages.len() && first > 0 {
+        first -= 1;
+        messages[first].content = cut_to_tokens(&messages[first].content, budget);
+    }
+    messages.drain(..first);
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_the_shares() {
+        let shares: BudgetShares = "history=50, rag=25".parse().unwrap();
+        assert_eq!(shares.0, [50, 0, 0, 25]);
+        assert!("history=fifty".parse::<BudgetShares>().is_err());
+        assert!("history".parse::<BudgetShares>().is_err());
+        assert!("summary=10".parse::<BudgetShares>().is_err());
+        assert_eq!("".parse::<BudgetShares>().unwrap().0, [0; 4]);
+    }
+
+    #[test]
+    fn allocation_splits_what_is_left_by_the_shares() {
+        let budget = TokenBudget::new(1000, 200, BudgetShares::default());
+        assert_eq!(budget.available(), 800);
+        assert_eq!(budget.allocation(BudgetPart::History, &MAIN_PARTS), 564);
+        assert_eq!(budget.allocation(BudgetPart::Stats, &MAIN_PARTS), 235);
+        assert_eq!(budget.allocation(BudgetPart::Chat, &CHAT_PARTS), 800);
+        assert_eq!(budget.allocation(BudgetPart::Rag, &QA_PARTS), 61);
+    }
+
+    #[test]
+    fn allocation_without_shares_or_room() {
+        let shares: BudgetShares = "history=100".parse().unwrap();
+        let budget = TokenBudget::new(1000, 0, shares);
+        assert_eq!(budget.allocation(BudgetPart::Chat, &CHAT_PARTS), 0);
+        assert_eq!(budget.allocation(BudgetPart::History, &QA_PARTS), 1000);
+
+        let budget = TokenBudget::new(100, 90, BudgetShares::default()).reserve("one two three");
+        assert_eq!(budget.reserved, 93);
+        let budget = budget.reserve("a much longer system prompt");
+        assert_eq!(budget.available(), 0);
+        assert_eq!(budget.allocation(BudgetPart::History, &MAIN_PARTS), 0);
+    }
+
+    #[test]
+    fn context_window_of_the_model() {
+        let args = Args::parse_from(["rsllm"]);
+        assert_eq!(context_window(&args, "Mistral-7B-Instruct"), 32768);
+        // the longest known name wins
+        assert_eq!(context_window(&args, "gpt-4-turbo-preview"), 128000);
+        assert_eq!(context_window(&args, "gpt-4"), 8192);
+        assert_eq!(context_window(&args, "phi-2"), DEFAULT_CONTEXT_WINDOW);
+
+        let args = Args::parse_from([
+            "rsllm",
+            "--llm-context-windows",
+            "mistral=4096,phi=2048,bad=many",
+        ]);
+        assert_eq!(context_window(&args, "Mistral-7B-Instruct"), 4096);
+        assert_eq!(context_window(&args, "phi-2"), 2048);
+        assert_eq!(context_window(&args, "gemma-2b"), 8192);
+    }
+
+    #[test]
+    fn cuts_between_words() {
+        assert_eq!(cut_to_tokens("one two three four", 2), "one two ");
+        // a long word counts a token per four characters
+        assert_eq!(cut_to_tokens("abcdefghij klm", 3), "abcdefghij ");
+        assert_eq!(cut_to_tokens("abcdefghij klm", 2), "");
+        assert_eq!(cut_to_tokens("one two", 10), "one two");
+    }
+
+    #[test]
+    fn fit_texts_drops_the_oldest() {
+        let mut history = texts(&["a b c", "d e", "f"]);
+        assert_eq!(fit_texts(&mut history, 3), 1);
+        assert_eq!(history, texts(&["d e", "f"]));
+
+        let mut history = texts(&["a b", "c d"]);
+        assert_eq!(fit_texts(&mut history, 10), 0);
+        assert_eq!(history.len(), 2);
+
+        let mut history = Vec::new();
+        assert_eq!(fit_texts(&mut history, 0), 0);
+    }
+
+    #[test]
+    fn fit_texts_cuts_the_newest_when_it_is_too_long_alone() {
+        let mut history = texts(&["a b", "c d e f"]);
+        assert_eq!(fit_texts(&mut history, 2), 1);
+        assert_eq!(history, texts(&["c d "]));
+    }
+
+    #[test]
+    fn fit_messages_keeps_the_newest() {
+        let message = |content: &str| Message {
+            role: "user".to_string(),
+            content: content.to_string(),
+        };
+        let mut messages = vec![message("a b c"), message("d e"), message("f g h i")];
+        assert_eq!(fit_messages(&mut messages, 6), 1);
+        assert_eq!(messages[0].content, "d e");
+
+        let mut messages = vec![message("a b c d")];
+        assert_eq!(fit_messages(&mut messages, 2), 0);
+        assert_eq!(messages[0].content, "a b ");
+    }
+}
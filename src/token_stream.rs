@@ -0,0 +1,81 @@
+/*
+ * token_stream.rs
+ * ---------------
+ * Coalescing of the LLM token stream into batches. At high tokens per second a channel
+ * message and a stdout flush per token add up, the backends forward the tokens in batches
+ * flushed after --token-batch-size tokens or --token-batch-ms since the first token of the
+ * batch, whichever comes first. The tokens stay separate in the batch so the segmenter
+ * sees the same stream.
+*/
+
+use crate::args::Args;
+use crate::liveness::{mark_active, Subsystem};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::{timeout_at, Duration, Instant};
+
+pub type TokenBatch = Vec<String>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBatching {
+    pub max_tokens: usize,
+    pub max_ms: u64,
+}
+
+impl TokenBatching {
+    pub fn new(max_tokens: usize, max_ms: u64) -> Self {
+        TokenBatching {
+            max_tokens: max_tokens.max(1),
+            max_ms,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        TokenBatching::new(args.token_batch_size, args.token_batch_ms)
+    }
+
+    // One token per batch, as the stream was before batching
+    pub fn unbatched() -> Self {
+        TokenBatching::new(1, 0)
+    }
+}
+
+impl Default for TokenBatching {
+    fn default() -> Self {
+        TokenBatching::new(16, 25)
+    }
+}
+
+// Forward the tokens of the receiver to the sender in batches until the receiver closes,
+// a partial batch is sent when the stream ends
+pub async fn coalesce_tokens(
+    mut receiver: Receiver<String>,
+    sender: Sender<TokenBatch>,
+    batching: TokenBatching,
+) {
+    while let Some(token) = receiver.recv().await {
+        let deadline = Instant::now() + Duration::from_millis(batching.max_ms);
+        let mut batch = Vec::with_capacity(batching.max_tokens);
+        batch.push(token);
+
+        let mut closed = false;
+        while batch.len() < batching.max_tokens {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(token)) => batch.push(token),
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break, // the batch is old enough
+            }
+        }
+
+        mark_active(Subsystem::Llm);
+        if let Err(e) = sender.send(batch).await {
+            log::error!("Failed to send token batch: {}", e);
+            return;
+        }
+        if closed {
+            return;
+        }
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ * transcript.rs
+ * -------------
+ * Recent transcript of the monitored program audio. The captioner adds each line with the
+ * speaker the diarization gave it, consecutive lines of the same speaker are joined into one
+ * turn when the transcript is put in a prompt.
+*/
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+static TRANSCRIPT: Lazy<Mutex<VecDeque<TranscriptLine>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// Lines kept, the oldest are dropped
+const TRANSCRIPT_MAX_LINES: usize = 100;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptLine {
+    pub pid: u16,
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub speaker: Option<usize>, // from 1, none without diarization
+    pub text: String,
+}
+
+impl TranscriptLine {
+    pub fn speaker_label(&self) -> Option<String> {
+        self.speaker.map(|speaker| format!("Speaker {}", speaker))
+    }
+}
+
+impl fmt::Display for TranscriptLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.speaker_label() {
+            Some(label) => write!(f, "{}: {}", label, self.text),
+            None => write!(f, "{}", self.text),
+        }
+    }
+}
+
+pub fn push_transcript_line(line: TranscriptLine) {
+    let mut transcript = TRANSCRIPT.lock().unwrap();
+    transcript.push_back(line);
+    while transcript.len() > TRANSCRIPT_MAX_LINES {
+        transcript.pop_front();
+    }
+}
+
+// The latest lines, oldest first
+pub fn recent_transcript(max_lines: usize) -> Vec<TranscriptLine> {
+    let transcript = TRANSCRIPT.lock().unwrap();
+    let skip = transcript.len().saturating_sub(max_lines);
+    transcript.iter().skip(skip).cloned().collect()
+}
+
+// The lines as speaker turns, one per line of text
+pub fn transcript_turns(lines: &[TranscriptLine]) -> String {
+    let mut turns: Vec<(Option<usize>, String)> = Vec::new();
+    for line in lines {
+        match turns.last_mut() {
+            Some((speaker, text)) if *speaker == line.speaker && line.speaker.is_some() => {
+                text.push(' ');
+                text.push_str(&line.text);
+            }
+            _ => turns.push((line.speaker, line.text.clone())),
+        }
+    }
+    turns
+        .into_iter()
+        .map(|(speaker, text)| match speaker {
+            Some(speaker) => format!("Speaker {}: {}", speaker, text),
+            None => text,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
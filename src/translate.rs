@@ -0,0 +1,121 @@
+/*
+ * translate.rs
+ * ------------
+ * Translation of paragraphs so subtitles and speech can use different languages,
+ * for example English speech with Spanish subtitles. Uses either the OpenAI compatible
+ * LLM API or a LibreTranslate server.
+*/
+
+use crate::args::Args;
+use crate::external_apis::check_url;
+use crate::openai_api::{chat_completion, Message};
+use crate::ApiError;
+use log::debug;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+struct LibreTranslateResponse {
+    #[serde(rename = "translatedText")]
+    translated_text: String,
+}
+
+// Translate the text into the target language, an empty target returns the text unchanged
+pub async fn translate(text: &str, target: &str, args: &Args) -> Result<String, ApiError> {
+    if target.is_empty() || text.trim().is_empty() {
+        return Ok(text.to_string());
+    }
+
+    debug!("Translating to {}: {}", target, text);
+
+    match args.translate_api.as_str() {
+        "libretranslate" => translate_libretranslate(text, target, args).await,
+        "llm" => translate_llm(text, target, args).await,
+        _ => Err(ApiError::Error(format!(
+            "Invalid translate api {}, options are llm, libretranslate",
+            args.translate_api
+        ))),
+    }
+}
+
+// Translate with the OpenAI compatible chat completions API
+async fn translate_llm(text: &str, target: &str, args: &Args) -> Result<String, ApiError> {
+    let messages = vec![
+        Message {
+            role: "system".to_string(),
+            content: format!(
+                "You are a translator. Translate the text from the user into {}. Only output the translation without notes or quotes.",
+                target
+            ),
+        },
+        Message {
+            role: "user".to_string(),
+            content: text.to_string(),
+        },
+    ];
+
+    chat_completion(messages, args, args.max_tokens as usize, 0.0).await
+}
+
+// Translate with a LibreTranslate server
+async fn translate_libretranslate(
+    text: &str,
+    target: &str,
+    args: &Args,
+) -> Result<String, ApiError> {
+    check_url(&args.translate_host).map_err(ApiError::Error)?;
+    let request = json!({
+        "q": text,
+        "source": "auto",
+        "target": target,
+        "format": "text",
+    });
+
+    let response = Client::new()
+        .post(&args.translate_host)
+        .json(&request)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(ApiError::Error(format!(
+            "Translation request failed {}: {}",
+            response.status(),
+            response.text().await.unwrap_or_default()
+        )));
+    }
+
+    let translation: LibreTranslateResponse = response.json().await?;
+    Ok(translation.translated_text)
+}
+
+// Translate the paragraph for speech and subtitles, returns (speech, subtitles).
+// Falls back to the original text when a translation fails.
+pub async fn translate_outputs(paragraph: &str, args: &Args) -> (String, String) {
+    let speech = match translate(paragraph, &args.tts_language, args).await {
+        Ok(speech) => speech,
+        Err(e) => {
+            log::error!("Speech translation to {} failed: {}", args.tts_language, e);
+            paragraph.to_string()
+        }
+    };
+
+    let subtitles = if args.subtitle_language == args.tts_language {
+        speech.clone()
+    } else {
+        match translate(paragraph, &args.subtitle_language, args).await {
+            Ok(subtitles) => subtitles,
+            Err(e) => {
+                log::error!(
+                    "Subtitle translation to {} failed: {}",
+                    args.subtitle_language,
+                    e
+                );
+                paragraph.to_string()
+            }
+        }
+    };
+
+    (speech, subtitles)
+}
@@ -0,0 +1,604 @@
+/*
+ * ts_generator.rs
+ * ---------------
+ * Synthetic MPEG-TS and SMPTE ST 2110 packets for tests, demos and the benchmarks. The
+ * transport stream has a PAT, a PMT, PCRs and PES packets with running continuity counters
+ * and can carry deliberate errors: continuity counter gaps, PCR jitter and a missing PAT.
+ * It is either sent over UDP or fed into the capture channel in place of pcap, so the whole
+ * analysis runs without a broadcast source.
+*/
+
+use crate::args::Args;
+use crate::liveness::{mark_active, Subsystem};
+use crate::network_capture::{CapturedPacket, NetworkCapture};
+use crate::stream_data::{PAT_PID, TS_PACKET_SIZE};
+use log::{error, info};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+// MPEG-TS packets in a typical UDP datagram
+pub const TS_PACKETS_PER_DATAGRAM: usize = 7;
+
+// 27 MHz system clock of the PCR
+const PCR_CLOCK_HZ: u64 = 27_000_000;
+
+#[derive(Debug, Clone)]
+pub struct TsGeneratorConfig {
+    pub program_number: u16,
+    pub pmt_pid: u16,
+    pub streams: Vec<(u8, u16)>, // stream type and pid, the first stream carries the PCR
+    pub bitrate: u64,
+    pub psi_interval: usize, // packets between the PAT and PMT repetitions
+    pub pcr_interval: usize, // packets between the PCRs
+    pub cc_error_interval: usize, // packets between continuity counter gaps, 0 disables
+    pub pcr_jitter_us: u64,  // maximum jitter added to the PCRs
+    pub drop_pat: bool,      // leave the PAT out of the stream
+}
+
+impl TsGeneratorConfig {
+    // H.264 video and AAC audio at 5 Mbps
+    pub fn new() -> Self {
+        TsGeneratorConfig {
+            program_number: 1,
+            pmt_pid: 0x1000,
+            streams: vec![(0x1B, 0x100), (0x0F, 0x101)],
+            bitrate: 5_000_000,
+            psi_interval: 100,
+            pcr_interval: 20,
+            cc_error_interval: 0,
+            pcr_jitter_us: 0,
+            drop_pat: false,
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        TsGeneratorConfig {
+            bitrate: args.ts_generator_bitrate.max(1),
+            cc_error_interval: args.ts_generator_cc_errors,
+            pcr_jitter_us: args.ts_generator_pcr_jitter_us,
+            drop_pat: args.ts_generator_drop_pat,
+            ..TsGeneratorConfig::new()
+        }
+    }
+}
+
+impl Default for TsGeneratorConfig {
+    fn default() -> Self {
+        TsGeneratorConfig::new()
+    }
+}
+
+// CRC32 of the PSI sections, MPEG-2 polynomial without reflection
+pub fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// TS packet with the section after a zero pointer field, stuffed with 0xFF
+fn psi_packet(pid: u16, continuity_counter: u8, section: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0xFF; TS_PACKET_SIZE];
+    packet[0] = 0x47;
+    packet[1] = 0x40 | ((pid >> 8) as u8 & 0x1F); // payload unit start
+    packet[2] = pid as u8;
+    packet[3] = 0x10 | (continuity_counter & 0x0F); // payload only
+    packet[4] = 0x00; // pointer field
+    packet[5..5 + section.len()].copy_from_slice(section);
+    packet
+}
+
+// Section header and body with the section length and the CRC filled in
+fn psi_section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+    let section_length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xB0 | ((section_length >> 8) as u8 & 0x0F),
+        section_length as u8,
+        (id >> 8) as u8,
+        id as u8,
+        0xC1, // version 0, current
+        0x00, // section number
+        0x00, // last section number
+    ];
+    section.extend_from_slice(body);
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+pub fn pat_packet(program_number: u16, pmt_pid: u16, continuity_counter: u8) -> Vec<u8> {
+    let body = [
+        (program_number >> 8) as u8,
+        program_number as u8,
+        0xE0 | ((pmt_pid >> 8) as u8 & 0x1F),
+        pmt_pid as u8,
+    ];
+    psi_packet(PAT_PID, continuity_counter, &psi_section(0x00, 1, &body))
+}
+
+// PMT of the program with the (stream type, pid) of each elementary stream, the first stream
+// carries the PCR
+pub fn pmt_packet(
+    pmt_pid: u16,
+    program_number: u16,
+    streams: &[(u8, u16)],
+    continuity_counter: u8,
+) -> Vec<u8> {
+    let pcr_pid = streams.first().map_or(0x1FFF, |(_, pid)| *pid);
+    let mut body = vec![
+        0xE0 | ((pcr_pid >> 8) as u8 & 0x1F),
+        pcr_pid as u8,
+        0xF0, // no program info
+        0x00,
+    ];
+    for (stream_type, pid) in streams {
+        body.extend_from_slice(&[
+            *stream_type,
+            0xE0 | ((pid >> 8) as u8 & 0x1F),
+            *pid as u8,
+            0xF0, // no es info
+            0x00,
+        ]);
+    }
+    psi_packet(
+        pmt_pid,
+        continuity_counter,
+        &psi_section(0x02, program_number, &body),
+    )
+}
+
+// PES stream id of the stream type, audio, private data for AC-3 and video otherwise
+pub fn pes_stream_id(stream_type: u8) -> u8 {
+    match stream_type {
+        0x03 | 0x04 | 0x0F | 0x11 => 0xC0,
+        0x06 | 0x81 | 0x87 => 0xBD,
+        _ => 0xE0,
+    }
+}
+
+// PES packet of the pid, a unit start carries a PES header of the stream id with the PTS and
+// a PCR goes into an adaptation field
+pub fn pes_packet(
+    pid: u16,
+    stream_id: u8,
+    continuity_counter: u8,
+    pts: Option<u64>,
+    pcr: Option<u64>,
+) -> Vec<u8> {
+    let mut packet = vec![0u8; TS_PACKET_SIZE];
+    packet[0] = 0x47;
+    packet[1] = ((pid >> 8) as u8 & 0x1F) | if pts.is_some() { 0x40 } else { 0x00 };
+    packet[2] = pid as u8;
+    packet[3] = 0x10 | (continuity_counter & 0x0F);
+
+    let mut offset = 4;
+    if let Some(pcr) = pcr {
+        let base = pcr / 300;
+        let extension = pcr % 300;
+        packet[3] |= 0x20; // adaptation field and payload
+        packet[4..12].copy_from_slice(&[
+            0x07, // adaptation field length
+            0x10, // PCR flag
+            (base >> 25) as u8,
+            (base >> 17) as u8,
+            (base >> 9) as u8,
+            (base >> 1) as u8,
+            ((base as u8 & 0x01) << 7) | 0x7E | ((extension >> 8) as u8 & 0x01),
+            extension as u8,
+        ]);
+        offset = 12;
+    }
+    if let Some(pts) = pts {
+        let header = [
+            0x00,
+            0x00,
+            0x01,
+            stream_id,
+            0x00,
+            0x00, // unbounded length
+            0x80,
+            0x80, // PTS only
+            0x05,
+            0x21 | (((pts >> 30) as u8 & 0x07) << 1),
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) as u8 & 0xFE),
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) as u8 & 0xFE),
+        ];
+        packet[offset..offset + header.len()].copy_from_slice(&header);
+        offset += header.len();
+    }
+    // a payload pattern that differs per packet
+    for (i, byte) in packet.iter_mut().enumerate().skip(offset) {
+        *byte = (i as u8).wrapping_mul(31).wrapping_add(continuity_counter);
+    }
+    packet
+}
+
+// Transport stream of one program, PAT and PMT are repeated every psi_interval packets and
+// the PCR follows the packet clock of the bitrate
+pub struct TsGenerator {
+    pub config: TsGeneratorConfig,
+    packet_count: u64,
+    continuity: HashMap<u16, u8>,
+    cc_error_due: bool,
+    stream_packets: HashMap<u16, u64>,
+    pts: HashMap<u16, u64>,
+}
+
+impl TsGenerator {
+    pub fn new(config: TsGeneratorConfig) -> Self {
+        TsGenerator {
+            config,
+            packet_count: 0,
+            continuity: HashMap::new(),
+            cc_error_due: false,
+            stream_packets: HashMap::new(),
+            pts: HashMap::new(),
+        }
+    }
+
+    fn next_cc(&mut self, pid: u16) -> u8 {
+        let cc = self.continuity.entry(pid).or_insert(0x0F);
+        *cc = (*cc + 1) & 0x0F;
+        // skip a count for the continuity counter errors, the PAT passes it on to the next PID
+        if self.cc_error_due && pid != PAT_PID {
+            *cc = (*cc + 1) & 0x0F;
+            self.cc_error_due = false;
+        }
+        *cc
+    }
+
+    // PCR at the packet position of the stream, with the configured jitter
+    fn pcr(&self) -> u64 {
+        let bits = self.packet_count * TS_PACKET_SIZE as u64 * 8;
+        let pcr = (bits as u128 * PCR_CLOCK_HZ as u128 / self.config.bitrate as u128) as u64;
+        if self.config.pcr_jitter_us == 0 {
+            return pcr;
+        }
+        let jitter = self.config.pcr_jitter_us as i64 * (PCR_CLOCK_HZ / 1_000_000) as i64;
+        (pcr as i64 + rand::thread_rng().gen_range(-jitter..=jitter)).max(0) as u64
+    }
+
+    pub fn pat(&mut self) -> Vec<u8> {
+        let cc = self.next_cc(PAT_PID);
+        pat_packet(self.config.program_number, self.config.pmt_pid, cc)
+    }
+
+    pub fn pmt(&mut self) -> Vec<u8> {
+        let cc = self.next_cc(self.config.pmt_pid);
+        pmt_packet(
+            self.config.pmt_pid,
+            self.config.program_number,
+            &self.config.streams,
+            cc,
+        )
+    }
+
+    pub fn next_packet(&mut self) -> Vec<u8> {
+        if self.config.cc_error_interval > 0
+            && self.packet_count > 0
+            && self
+                .packet_count
+                .is_multiple_of(self.config.cc_error_interval as u64)
+        {
+            self.cc_error_due = true;
+        }
+        let index = (self.packet_count % self.config.psi_interval.max(3) as u64) as usize;
+        let packet = match index {
+            0 if !self.config.drop_pat => self.pat(),
+            1 => self.pmt(),
+            _ if self.config.streams.is_empty() => self.pmt(),
+            _ => {
+                let (stream_type, pid) = self.config.streams[index % self.config.streams.len()];
+                let pcr_pid = self.config.streams[0].1;
+                let cc = self.next_cc(pid);
+                let pcr = (pid == pcr_pid
+                    && self.packet_count % self.config.pcr_interval.max(1) as u64 <= 1)
+                    .then(|| self.pcr());
+                // a unit start with a 30 fps PTS every 16 packets of the stream
+                let stream_packet = self.stream_packets.entry(pid).or_insert(0);
+                let pts = if stream_packet.is_multiple_of(16) {
+                    let pts = self.pts.entry(pid).or_insert(0);
+                    *pts += 3000;
+                    Some(*pts)
+                } else {
+                    None
+                };
+                *stream_packet += 1;
+                pes_packet(pid, pes_stream_id(stream_type), cc, pts, pcr)
+            }
+        };
+        self.packet_count += 1;
+        packet
+    }
+
+    // UDP payload of TS_PACKETS_PER_DATAGRAM packets
+    pub fn next_datagram(&mut self) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(TS_PACKET_SIZE * TS_PACKETS_PER_DATAGRAM);
+        for _ in 0..TS_PACKETS_PER_DATAGRAM {
+            datagram.extend_from_slice(&self.next_packet());
+        }
+        datagram
+    }
+
+    // Time between the datagrams at the bitrate
+    pub fn datagram_interval(&self) -> Duration {
+        let bits = (TS_PACKET_SIZE * TS_PACKETS_PER_DATAGRAM * 8) as u64;
+        Duration::from_nanos(bits * 1_000_000_000 / self.config.bitrate.max(1))
+    }
+}
+
+// Datagram behind Ethernet, IPv4 and UDP headers like a captured frame
+pub fn capture_frame(datagram: &[u8], port: u16) -> Vec<u8> {
+    let mut frame = vec![0u8; 42];
+    let ip_length = (20 + 8 + datagram.len()) as u16;
+    frame[12..14].copy_from_slice(&[0x08, 0x00]); // IPv4
+    frame[14] = 0x45;
+    frame[16..18].copy_from_slice(&ip_length.to_be_bytes());
+    frame[22] = 64; // ttl
+    frame[23] = 17; // udp
+    frame[26..30].copy_from_slice(&[127, 0, 0, 1]);
+    frame[30..34].copy_from_slice(&[127, 0, 0, 1]);
+    frame[34..36].copy_from_slice(&port.to_be_bytes());
+    frame[36..38].copy_from_slice(&port.to_be_bytes());
+    frame[38..40].copy_from_slice(&(ip_length - 20).to_be_bytes());
+    frame.extend_from_slice(datagram);
+    frame
+}
+
+// Feed the generated stream into the capture channel in place of pcap, stopped through the
+// running flag of the capture like network_capture
+pub fn ts_generator_capture(
+    network_capture: &mut NetworkCapture,
+    config: TsGeneratorConfig,
+    ptx: mpsc::Sender<CapturedPacket>,
+) {
+    let running = Arc::new(AtomicBool::new(true));
+    let running_generator = running.clone();
+    let port = network_capture.source_port as u16;
+
+    let capture_task = tokio::spawn(async move {
+        let mut generator = TsGenerator::new(config);
+        let interval = generator.datagram_interval();
+        info!(
+            "Generating a synthetic MPEG-TS at {} bps into the capture",
+            generator.config.bitrate
+        );
+        let mut next = Instant::now();
+        while running_generator.load(Ordering::SeqCst) {
+            let frame = capture_frame(&generator.next_datagram(), port);
+            if ptx.send(CapturedPacket::new(frame)).await.is_err() {
+                break;
+            }
+            mark_active(Subsystem::Capture);
+            next += interval;
+            tokio::time::sleep_until(next).await;
+        }
+    });
+
+    network_capture.capture_task = Some(capture_task);
+    network_capture.running = running;
+}
+
+// Send the generated stream to the UDP target at the bitrate until running is cleared
+pub async fn send_udp(
+    config: TsGeneratorConfig,
+    target: &str,
+    running: Arc<AtomicBool>,
+) -> std::io::Result<()> {
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_multicast_ttl_v4(4)?;
+    socket.connect(target).await?;
+
+    let mut generator = TsGenerator::new(config);
+    let interval = generator.datagram_interval();
+    info!(
+        "Sending a synthetic MPEG-TS at {} bps to udp://{}",
+        generator.config.bitrate, target
+    );
+    let mut next = Instant::now();
+    while running.load(Ordering::SeqCst) {
+        if let Err(e) = socket.send(&generator.next_datagram()).await {
+            error!("Failed to send the synthetic MPEG-TS to {}: {}", target, e);
+        }
+        next += interval;
+        tokio::time::sleep_until(next).await;
+    }
+    Ok(())
+}
+
+// RTP datagram with an RFC 4175 payload header and a line of pixel data
+pub fn smpte2110_datagram(
+    sequence: u32,
+    timestamp: u32,
+    line_number: u16,
+    payload_length: usize,
+) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(12 + 8 + payload_length);
+    datagram.extend_from_slice(&[
+        0x80, // version 2
+        96,   // dynamic payload type
+        (sequence >> 8) as u8,
+        sequence as u8,
+    ]);
+    datagram.extend_from_slice(&timestamp.to_be_bytes());
+    datagram.extend_from_slice(&0x1234_5678u32.to_be_bytes()); // ssrc
+    datagram.extend_from_slice(&[(sequence >> 24) as u8, (sequence >> 16) as u8]);
+    datagram.extend_from_slice(&(payload_length as u16).to_be_bytes());
+    datagram.extend_from_slice(&[(line_number >> 8) as u8 & 0x7F, line_number as u8]);
+    datagram.extend_from_slice(&[0x00, 0x00]); // offset 0, no continuation
+    datagram.extend((0..payload_length).map(|i| (i as u8).wrapping_add(line_number as u8)));
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_headers::LinkType;
+    use crate::probe::StreamAnalyzer;
+    use crate::stream_data::{parse_pat, parse_pmt, Tr101290Errors};
+    use clap::Parser;
+
+    // a program of its own per test, the PID map of the analyzer is shared
+    fn config(pmt_pid: u16) -> TsGeneratorConfig {
+        TsGeneratorConfig {
+            pmt_pid,
+            streams: vec![(0x1B, pmt_pid + 1), (0x0F, pmt_pid + 2)],
+            ..TsGeneratorConfig::new()
+        }
+    }
+
+    // The PSI section after the pointer field, CRC included
+    fn section(packet: &[u8]) -> &[u8] {
+        let section_length = ((packet[6] as usize & 0x0F) << 8) | packet[7] as usize;
+        &packet[5..8 + section_length]
+    }
+
+    fn pid(packet: &[u8]) -> u16 {
+        ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16
+    }
+
+    // Run the generated stream through the analyzer, stamped at the generator bitrate
+    fn analyze(config: TsGeneratorConfig, seconds: u64) -> Tr101290Errors {
+        let args = Args::parse_from(["rsllm"]);
+        let mut analyzer = StreamAnalyzer::new(&args, 0);
+        let mut generator = TsGenerator::new(config);
+        let interval_ns = generator.datagram_interval().as_nanos() as u64;
+        let start_ns = 1_700_000_000_000_000_000;
+        for index in 0..seconds * 1_000_000_000 / interval_ns {
+            analyzer.process(CapturedPacket {
+                data: Arc::new(capture_frame(&generator.next_datagram(), 10000)),
+                timestamp_ns: start_ns + index * interval_ns,
+                path: 0,
+                link_type: LinkType::Ethernet,
+            });
+        }
+        analyzer.tr101290_errors.clone()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_E6E7);
+    }
+
+    #[test]
+    fn pat_and_pmt_crc() {
+        let pat = pat_packet(1, 0x1000, 0);
+        assert_eq!(crc32_mpeg2(section(&pat)), 0);
+        assert!(parse_pat(&pat)
+            .iter()
+            .any(|entry| entry.program_number == 1 && entry.pmt_pid == 0x1000));
+
+        let pmt = pmt_packet(0x1000, 1, &[(0x1B, 0x100), (0x0F, 0x101)], 0);
+        assert_eq!(crc32_mpeg2(section(&pmt)), 0);
+        let streams: Vec<(u8, u16)> = parse_pmt(&pmt)
+            .entries
+            .iter()
+            .map(|entry| (entry.stream_type, entry.stream_pid))
+            .collect();
+        assert_eq!(streams, vec![(0x1B, 0x100), (0x0F, 0x101)]);
+    }
+
+    #[test]
+    fn continuity_counter_gaps_every_interval() {
+        // the interval lands on the PAT, the gap goes to the PMT after it
+        let mut generator = TsGenerator::new(TsGeneratorConfig {
+            cc_error_interval: 100,
+            ..TsGeneratorConfig::new()
+        });
+        let mut last: HashMap<u16, u8> = HashMap::new();
+        let mut gaps = 0;
+        for _ in 0..1000 {
+            let packet = generator.next_packet();
+            let cc = packet[3] & 0x0F;
+            if let Some(previous) = last.insert(pid(&packet), cc) {
+                if cc != (previous + 1) & 0x0F {
+                    gaps += 1;
+                }
+            }
+        }
+        assert_eq!(gaps, 9);
+    }
+
+    #[test]
+    fn every_stream_starts_pes_packets() {
+        let mut generator = TsGenerator::new(TsGeneratorConfig::new());
+        let mut stream_ids: HashMap<u16, Vec<u8>> = HashMap::new();
+        for _ in 0..1000 {
+            let packet = generator.next_packet();
+            let pid = pid(&packet);
+            if matches!(pid, 0x100 | 0x101) && packet[1] & 0x40 != 0 {
+                let offset = if packet[3] & 0x20 != 0 {
+                    5 + packet[4] as usize
+                } else {
+                    4
+                };
+                assert_eq!(packet[offset..offset + 3], [0x00, 0x00, 0x01]);
+                stream_ids.entry(pid).or_default().push(packet[offset + 3]);
+            }
+        }
+        let video = &stream_ids[&0x100];
+        let audio = &stream_ids[&0x101];
+        assert!(video.len() > 10 && video.iter().all(|&id| id == 0xE0));
+        assert!(audio.len() > 10 && audio.iter().all(|&id| id == 0xC0));
+    }
+
+    #[test]
+    fn clean_stream_has_no_errors() {
+        let errors = analyze(config(0x200), 1);
+        assert_eq!(errors.continuity_counter_errors, 0);
+        assert_eq!(errors.pat_errors, 0);
+        assert_eq!(errors.pcr_accuracy_errors, 0);
+    }
+
+    #[test]
+    fn detects_continuity_counter_errors() {
+        let errors = analyze(
+            TsGeneratorConfig {
+                cc_error_interval: 100,
+                ..config(0x300)
+            },
+            1,
+        );
+        assert!(errors.continuity_counter_errors > 0);
+    }
+
+    #[test]
+    fn detects_pcr_jitter() {
+        let errors = analyze(
+            TsGeneratorConfig {
+                pcr_jitter_us: 100,
+                ..config(0x400)
+            },
+            1,
+        );
+        assert!(errors.pcr_accuracy_errors > 0);
+        assert_eq!(errors.continuity_counter_errors, 0);
+    }
+
+    #[test]
+    fn detects_a_missing_pat() {
+        let errors = analyze(
+            TsGeneratorConfig {
+                drop_pat: true,
+                ..config(0x500)
+            },
+            2,
+        );
+        assert!(errors.pat_errors > 0);
+    }
+}
@@ -0,0 +1,235 @@
+/*
+ * tts_text.rs
+ * -----------
+ * Text cleanup before speech synthesis. The text is NFKC normalized, typographic punctuation
+ * is mapped to plain punctuation the voices pause on, emoji are dropped or described, and the
+ * characters are limited to what the TTS backend can pronounce instead of stripping
+ * everything outside of ASCII.
+*/
+
+use crate::args::Args;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Characters a TTS backend can pronounce
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TtsCharset {
+    Unicode, // letters and numbers of any script
+    Latin,   // latin letters including accents
+    Ascii,   // transliterated to ASCII
+}
+
+impl TtsCharset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "unicode" => Some(TtsCharset::Unicode),
+            "latin" => Some(TtsCharset::Latin),
+            "ascii" => Some(TtsCharset::Ascii),
+            _ => None,
+        }
+    }
+
+    // Mimic3 and OpenAI have voices for many languages, metavoice only speaks English
+    pub fn for_backend(backend: &str) -> Self {
+        match backend {
+            "metavoice" => TtsCharset::Ascii,
+            _ => TtsCharset::Unicode,
+        }
+    }
+
+    pub fn allows(&self, c: char) -> bool {
+        if c.is_ascii_alphanumeric() || c.is_ascii_punctuation() || c.is_whitespace() {
+            return true;
+        }
+        match self {
+            TtsCharset::Unicode => c.is_alphanumeric() || is_combining_mark(c),
+            TtsCharset::Latin => is_latin_letter(c) || ('\u{0300}'..='\u{036F}').contains(&c),
+            TtsCharset::Ascii => false,
+        }
+    }
+}
+
+fn is_latin_letter(c: char) -> bool {
+    c.is_alphabetic()
+        && matches!(c,
+            '\u{00C0}'..='\u{024F}' // Latin-1 Supplement and Latin Extended-A/B
+            | '\u{1E00}'..='\u{1EFF}' // Latin Extended Additional
+        )
+}
+
+// What happens to emoji in the spoken text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmojiMode {
+    Remove,
+    Describe, // spoken by name, like "thumbs up"
+}
+
+impl EmojiMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "remove" => Some(EmojiMode::Remove),
+            "describe" => Some(EmojiMode::Describe),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TtsTextOptions {
+    pub charset: TtsCharset,
+    pub emoji: EmojiMode,
+}
+
+impl TtsTextOptions {
+    pub fn new() -> Self {
+        TtsTextOptions {
+            charset: TtsCharset::Unicode,
+            emoji: EmojiMode::Remove,
+        }
+    }
+
+    pub fn with_charset(mut self, charset: TtsCharset) -> Self {
+        self.charset = charset;
+        self
+    }
+
+    pub fn with_emoji(mut self, emoji: EmojiMode) -> Self {
+        self.emoji = emoji;
+        self
+    }
+
+    // Options for the backend speaking the text, --tts-charset overrides its whitelist
+    pub fn from_args(args: &Args, backend: &str) -> Self {
+        let charset = if args.tts_charset.is_empty() {
+            TtsCharset::for_backend(backend)
+        } else {
+            TtsCharset::from_name(&args.tts_charset).unwrap_or_else(|| {
+                log::error!(
+                    "Invalid TTS charset {}, using the {} default",
+                    args.tts_charset,
+                    backend
+                );
+                TtsCharset::for_backend(backend)
+            })
+        };
+        let emoji = EmojiMode::from_name(&args.tts_emoji).unwrap_or_else(|| {
+            log::error!("Invalid TTS emoji mode {}, removing emoji", args.tts_emoji);
+            EmojiMode::Remove
+        });
+        TtsTextOptions::new()
+            .with_charset(charset)
+            .with_emoji(emoji)
+    }
+}
+
+impl Default for TtsTextOptions {
+    fn default() -> Self {
+        TtsTextOptions::new()
+    }
+}
+
+// Plain punctuation for the typographic and CJK punctuation NFKC leaves alone
+pub fn map_punctuation(c: char) -> Option<&'static str> {
+    match c {
+        '‘' | '’' | '‚' | '‛' | '′' => Some("'"),
+        '“' | '”' | '„' | '‟' | '″' | '«' | '»' | '「' | '」' | '『' | '』' => {
+            Some("\"")
+        }
+        '–' | '—' | '―' | '−' => Some(", "),
+        '•' | '·' | '・' | '、' => Some(", "),
+        '。' | '｡' => Some(". "),
+        '¿' | '¡' => Some(""),
+        _ => None,
+    }
+}
+
+// Spoken name of an emoji, skin tones and other qualifiers are left out
+pub fn describe_emoji(grapheme: &str) -> Option<String> {
+    let emoji = emojis::get(grapheme)?;
+    let name = emoji.name().split(':').next().unwrap_or("").trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+fn is_emoji(grapheme: &str) -> bool {
+    grapheme
+        .chars()
+        .next()
+        .is_some_and(|c| !c.is_alphanumeric() && !c.is_ascii())
+        && emojis::get(grapheme).is_some()
+}
+
+// Clean the text for the TTS backend
+pub fn clean_text(input: &str, options: &TtsTextOptions) -> String {
+    let input = input.replace("<|im_end|>", "").replace("**", "");
+    let input: String = input.nfkc().collect();
+
+    // map, describe or drop each grapheme cluster so emoji sequences are handled whole
+    let mut text = String::with_capacity(input.len());
+    for grapheme in input.graphemes(true) {
+        if is_emoji(grapheme) {
+            if options.emoji == EmojiMode::Describe {
+                if let Some(name) = describe_emoji(grapheme) {
+                    text.push(' ');
+                    text.push_str(&name);
+                    text.push(' ');
+                }
+            }
+            continue;
+        }
+        for c in grapheme.chars() {
+            if let Some(mapped) = map_punctuation(c) {
+                text.push_str(mapped);
+            } else if options.charset == TtsCharset::Ascii && !c.is_ascii() {
+                if c.is_alphanumeric() {
+                    text.push_str(deunicode::deunicode_char(c).unwrap_or(""));
+                }
+            } else if options.charset.allows(c) {
+                text.push(c);
+            }
+        }
+    }
+
+    // remove strings of periods and extra spaces before punctuation
+    while text.contains("..") {
+        text = text.replace("..", ".");
+    }
+    let text = text
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .replace(" .", ".")
+        .replace(" ,", ",")
+        .replace(" ?", "?")
+        .replace(" !", "!")
+        .replace(" :", ":")
+        .replace(" ;", ";");
+
+    // split into sentences and remove punctuation at the start of them
+    let text = text
+        .split('.')
+        .map(|s| {
+            let s = s.trim();
+            s.strip_prefix(|c: char| c.is_ascii_punctuation())
+                .unwrap_or(s)
+        })
+        .collect::<Vec<&str>>()
+        .join(". ");
+
+    // remove trailing symbols but keep the sentence end for the intonation of the voice
+    let end = text.trim_end_matches(|c: char| !c.is_alphanumeric());
+    if end.is_empty() {
+        return String::new();
+    }
+    let stop = text[end.len()..]
+        .chars()
+        .find(|c| matches!(c, '.' | '?' | '!'));
+    match stop {
+        Some(stop) => format!("{}{}", end.trim_start(), stop),
+        None => end.trim_start().to_string(),
+    }
+}
@@ -1,6 +1,12 @@
 use crate::args::Args;
-use crate::candle_gemma::gemma;
-use crate::candle_mistral::mistral;
+use crate::llm_routing::{LlmBackend, LlmRoutes, LlmTask};
+use crate::llm_service::{LlmService, LlmSource};
+use crate::moderation::Moderation;
+use crate::openai_api::Message;
+use crate::sampling::SamplingConfig;
+use crate::stream_state::stream_state;
+use crate::token_budget::{fit_texts, BudgetPart, TokenBudget, CHAT_PARTS};
+use crate::token_stream::{TokenBatch, TokenBatching};
 use anyhow::Result;
 use rand::Rng;
 use rusqlite::{params, Connection};
@@ -9,12 +15,122 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc::{self};
 
+// Viewer tiers from the chat badges, higher tiers can use more commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ViewerTier {
+    Viewer,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl std::str::FromStr for ViewerTier {
+    type Err = String;
+
+    fn from_str(tier: &str) -> Result<Self, String> {
+        match tier.trim().to_lowercase().as_str() {
+            "viewer" | "everyone" => Ok(ViewerTier::Viewer),
+            "subscriber" | "sub" => Ok(ViewerTier::Subscriber),
+            "vip" => Ok(ViewerTier::Vip),
+            "moderator" | "mod" => Ok(ViewerTier::Moderator),
+            "broadcaster" => Ok(ViewerTier::Broadcaster),
+            _ => Err(format!("Invalid viewer tier {}", tier)),
+        }
+    }
+}
+
+impl ViewerTier {
+    // Highest tier of the badges on the message
+    pub fn from_badges(msg: &tmi::Privmsg<'_>) -> Self {
+        msg.badges()
+            .map(|badge| match badge.as_badge_data().name() {
+                "broadcaster" => ViewerTier::Broadcaster,
+                "moderator" => ViewerTier::Moderator,
+                "vip" => ViewerTier::Vip,
+                "subscriber" | "founder" => ViewerTier::Subscriber,
+                _ => ViewerTier::Viewer,
+            })
+            .max()
+            .unwrap_or(ViewerTier::Viewer)
+    }
+
+    // Lowest tier allowed to use a command from its argument, an invalid one leaves the
+    // command to moderators
+    pub fn required(tier: &str) -> Self {
+        tier.parse::<ViewerTier>().unwrap_or_else(|e| {
+            log::error!("{}, only moderators can use the command", e);
+            ViewerTier::Moderator
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ViewerTier::Viewer => "viewer",
+            ViewerTier::Subscriber => "subscriber",
+            ViewerTier::Vip => "vip",
+            ViewerTier::Moderator => "moderator",
+            ViewerTier::Broadcaster => "broadcaster",
+        }
+    }
+}
+
+// Reply for a viewer whose badges are below the tier of the command, None when permitted
+fn tier_refusal(msg: &tmi::Privmsg<'_>, command: &str, tier: &str) -> Option<String> {
+    let required = ViewerTier::required(tier);
+    if ViewerTier::from_badges(msg) >= required {
+        return None;
+    }
+    Some(format!(
+        "Sorry {}, {} needs the {} badge or higher.",
+        msg.sender().name(),
+        command,
+        required.name()
+    ))
+}
+
+// Voice the viewer picked with !voice
+fn user_voice(conn: &Connection, user_id: &str) -> Result<Option<String>> {
+    let mut statement = conn.prepare("SELECT voice FROM user_voices WHERE user_id = ?")?;
+    let mut rows = statement.query_map(params![user_id], |row| row.get(0))?;
+    Ok(rows.next().transpose()?)
+}
+
+fn set_user_voice(conn: &Connection, user_id: &str, voice: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO user_voices (user_id, voice) VALUES (?, ?)",
+        params![user_id, voice],
+    )?;
+    Ok(())
+}
+
+// Voices viewers can pick from --twitch-voices, empty allows any mimic3 voice name
+fn allowed_voices(args: &Args) -> Vec<String> {
+    args.twitch_voices
+        .split(',')
+        .map(|voice| voice.trim().to_string())
+        .filter(|voice| !voice.is_empty())
+        .collect()
+}
+
+// Prompt token of the chat model, an API takes the messages without any
+fn chat_token(backend: &LlmBackend, gemma: &'static str, mistral: &'static str) -> &'static str {
+    if backend.is_api() {
+        ""
+    } else if backend.model() == "gemma" {
+        gemma
+    } else {
+        mistral
+    }
+}
+
 pub async fn daemon(
     nick: String,
     token: String,
     channel: Vec<String>,
     running: Arc<AtomicBool>,
     twitch_tx: mpsc::Sender<String>,
+    llm_service: LlmService,
     args: Args,
 ) -> Result<()> {
     let credentials = match Some(nick).zip(Some(token)) {
@@ -36,7 +152,7 @@ pub async fn daemon(
     client.join_all(&channels).await?;
     log::info!("Joined the following channels: {}", channels.join(", "));
 
-    run(client, channels, running, twitch_tx, args).await
+    run(client, channels, running, twitch_tx, llm_service, args).await
 }
 
 async fn run(
@@ -44,8 +160,11 @@ async fn run(
     channels: Vec<tmi::Channel>,
     running: Arc<AtomicBool>,
     twitch_tx: mpsc::Sender<String>,
+    llm_service: LlmService,
     args: Args,
 ) -> Result<()> {
+    // the chat model, --twitch-model unless --llm-routes routes the chat elsewhere
+    let backend = LlmRoutes::from_args(&args).backend(LlmTask::Chat, &args);
     // create a semaphore so no more than one message is sent to the AI at a time
     let semaphore = tokio::sync::Semaphore::new(args.twitch_llm_concurrency as usize);
     while running.load(Ordering::SeqCst) {
@@ -55,7 +174,26 @@ async fn run(
             tmi::Message::Privmsg(msg) => {
                 // acquire the semaphore to send a message to the AI
                 let _chat_lock = semaphore.acquire().await.unwrap();
-                on_msg(&mut client, msg, &twitch_tx, args.clone()).await?
+                on_msg(
+                    &mut client,
+                    msg,
+                    &twitch_tx,
+                    &llm_service,
+                    &backend,
+                    args.clone(),
+                )
+                .await?
+            }
+            tmi::Message::UserNotice(notice) if notice.event_id() == "raid" => {
+                // the raiding channel is the sender of the notice
+                log::info!(
+                    "Twitch raid from {}: {}",
+                    notice.sender().name(),
+                    notice.system_message().unwrap_or_default()
+                );
+                twitch_tx
+                    .send(format!("!raid {}", notice.sender().name()))
+                    .await?;
             }
             tmi::Message::Reconnect => {
                 client.reconnect().await?;
@@ -72,6 +210,8 @@ async fn on_msg(
     client: &mut tmi::Client,
     msg: tmi::Privmsg<'_>,
     tx: &mpsc::Sender<String>,
+    llm_service: &LlmService,
+    backend: &LlmBackend,
     args: Args,
 ) -> Result<()> {
     log::debug!("\nTwitch Message: {:?}", msg);
@@ -96,6 +236,13 @@ async fn on_msg(
             )",
         [],
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_voices (
+                user_id TEXT PRIMARY KEY,
+                voice TEXT NOT NULL
+            )",
+        [],
+    )?;
 
     let user_id = msg.sender().name();
 
@@ -107,79 +254,63 @@ async fn on_msg(
 
     // send message to the LLM and get an answer to send back to the user.
     // also send the message to the main LLM loop to keep history context of the conversation
-    if !msg.text().starts_with("!help") && !msg.text().starts_with("!message") {
+    if !msg.text().starts_with("!help")
+        && !msg.text().starts_with("!message")
+        && !msg.text().starts_with("!persona")
+        && !msg.text().starts_with("!rewind")
+        && !msg.text().starts_with("!image")
+        && !msg.text().starts_with("!tts")
+        && !msg.text().starts_with("!voice")
+    {
         // LLM Thread
-        let (external_sender, mut external_receiver) = tokio::sync::mpsc::channel::<String>(100);
+        let (external_sender, mut external_receiver) =
+            tokio::sync::mpsc::channel::<TokenBatch>(100);
         let max_tokens = args.twitch_max_tokens_chat;
         let temperature = 0.8;
-        let quantized = false;
         let max_messages = args.twitch_chat_history;
+        let batching = TokenBatching::from_args(&args);
 
-        let system_start_token = if args.twitch_model == "gemma" {
-            "<start_of_turn>"
-        } else {
-            "<<SYS>>"
-        };
+        let system_start_token = chat_token(backend, "<start_of_turn>", "<<SYS>>");
 
-        let system_end_token = if args.twitch_model == "gemma" {
-            "<end_of_turn>"
-        } else {
-            "<</SYS>>"
-        };
+        let system_end_token = chat_token(backend, "<end_of_turn>", "<</SYS>>");
 
-        let assistant_start_token = if args.twitch_model == "gemma" {
-            "<start_of_turn>"
-        } else {
-            ""
-        };
+        let assistant_start_token = chat_token(backend, "<start_of_turn>", "");
 
-        let assistant_end_token = if args.twitch_model == "gemma" {
-            "<end_of_turn>"
-        } else {
-            ""
-        };
+        let assistant_end_token = chat_token(backend, "<end_of_turn>", "");
 
-        let start_token = if args.twitch_model == "gemma" {
-            "<start_of_turn>"
-        } else {
-            "[INST]"
-        };
+        let start_token = chat_token(backend, "<start_of_turn>", "[INST]");
 
-        let end_token = if args.twitch_model == "gemma" {
-            "<end_of_turn>"
-        } else {
-            "[/INST]"
-        };
+        let end_token = chat_token(backend, "<end_of_turn>", "[/INST]");
 
-        let bos_token = if args.twitch_model == "gemma" {
-            ""
-        } else {
-            "<s>"
-        };
+        let bos_token = chat_token(backend, "", "<s>");
 
-        let eos_token = if args.twitch_model == "gemma" {
-            ""
-        } else {
-            "</s>"
-        };
+        let eos_token = chat_token(backend, "", "</s>");
 
-        let user_name = if args.twitch_model == "gemma" {
-            "user"
-        } else {
-            ""
-        };
+        let user_name = chat_token(backend, "user", "");
 
-        let assistant_name = if args.twitch_model == "gemma" {
-            "model"
-        } else {
-            ""
-        };
+        let assistant_name = chat_token(backend, "model", "");
 
         // Truncate the chat_messages array to 3 messages max messages
         if chat_messages.len() > max_messages {
             chat_messages.truncate(max_messages);
         }
 
+        // what the stream is showing right now so answers can refer to it
+        let mut twitch_prompt = args.twitch_prompt.clone();
+        if args.twitch_context_chars > 0 {
+            let context = stream_state().prompt_context(args.twitch_context_chars);
+            if !context.is_empty() {
+                twitch_prompt.push_str(&format!(" What is on the stream right now:\n{}", context));
+            }
+        }
+
+        // the chat history gets the context the answer, prompt and question leave
+        let chat_budget = TokenBudget::for_model(&args, backend.model(), max_tokens)
+            .reserve(&twitch_prompt)
+            .reserve(msg.text())
+            .allocation(BudgetPart::Chat, &CHAT_PARTS);
+        fit_texts(&mut chat_messages, chat_budget);
+
         // build a string out of the chat_messages array of strings
         // that have each message in the format <s><start_of_turn>user {message}<end_of_turn></s>
         let mut chat_messages_history = String::new();
@@ -193,7 +324,7 @@ async fn on_msg(
             bos_token,
             system_start_token,
             assistant_name,
-            args.twitch_prompt.clone(),
+            twitch_prompt,
             system_end_token,
             eos_token,
             bos_token,
@@ -210,57 +341,64 @@ async fn on_msg(
 
         println!("\nTwitch sending msg_text:\n{}\n", msg_text);
 
-        let llm_thread = if args.twitch_model == "gemma" {
-            tokio::spawn(async move {
-                if let Err(e) = gemma(
-                    msg_text,
-                    max_tokens,
-                    temperature,
-                    quantized,
-                    Some("2b-it".to_string()),
-                    external_sender,
-                ) {
-                    eprintln!("Error running twitch gemma: {}", e);
-                }
-            })
-        } else if args.twitch_model == "mistral" {
-            tokio::spawn(async move {
-                if let Err(e) = mistral(
-                    msg_text,
-                    max_tokens,
-                    temperature,
-                    quantized,
-                    Some("auto".to_string()),
-                    external_sender,
-                ) {
-                    eprintln!("Error running twitch mistral: {}", e);
-                }
-            })
+        // an API backend gets the prompt and the question as messages
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: twitch_prompt.clone(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: format!(
+                    "{}twitch chat user {} asked {}",
+                    chat_messages_history,
+                    msg.sender().name(),
+                    msg.text()
+                ),
+            },
+        ];
+        let llm_thread = if backend.is_api() || ["gemma", "mistral"].contains(&backend.model()) {
+            backend.spawn_generation(
+                LlmSource::Twitch,
+                messages,
+                msg_text,
+                max_tokens,
+                SamplingConfig::from_args(&args).with_temperature(temperature),
+                external_sender,
+                batching,
+                llm_service,
+                &args,
+            )
         } else {
             // print message and error out
             eprintln!(
                 "Error: Invalid model specified for twitch chat {}",
-                args.twitch_model
+                backend.model()
             );
             tokio::spawn(async move {
                 external_sender
-                    .send("Error: Invalid model specified for twitch chat".to_string())
+                    .send(vec![
+                        "Error: Invalid model specified for twitch chat".to_string()
+                    ])
                     .await
                     .unwrap();
+                Ok(())
             })
         };
 
         // thread token collection and wait for it to finish
         let token_thread = tokio::spawn(async move {
             let mut tokens = String::new();
-            while let Some(received) = external_receiver.recv().await {
-                tokens.push_str(&received);
+            while let Some(batch) = external_receiver.recv().await {
+                tokens.push_str(&batch.concat());
             }
             tokens
         });
 
-        // wait for llm thread to finish
-        llm_thread.await?;
+        // wait for llm thread to finish, an API that is down leaves the answer empty
+        if let Err(e) = llm_thread.await? {
+            log::error!("Twitch chat answer failed: {}", e);
+        }
 
         let answer = token_thread.await?;
 
@@ -405,6 +543,228 @@ async fn on_msg(
         return Ok(());
     }
 
+    if msg.text().starts_with("!persona") {
+        let persona = msg.text().splitn(2, ' ').nth(1).unwrap_or("").trim();
+
+        if let Some(refusal) = tier_refusal(&msg, "!persona", &args.twitch_persona_tier) {
+            client
+                .privmsg(msg.channel(), &refusal)
+                .reply_to(msg.message_id())
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        if persona.is_empty() {
+            client
+                .privmsg(msg.channel(), "To switch personas type !persona <name>.")
+                .reply_to(msg.message_id())
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        std::io::stdout().flush().unwrap();
+        log::info!(
+            "Twitch recieved a persona switch to {} from {}",
+            persona,
+            msg.sender().name()
+        );
+        std::io::stdout().flush().unwrap();
+
+        // Send the persona switch to the main loop through mpsc channels
+        tx.send(format!("!persona {}", persona)).await?;
+
+        client
+            .privmsg(
+                msg.channel(),
+                &format!(
+                    "Thank you {}, switching to the {} persona after this story.",
+                    msg.sender().name(),
+                    persona
+                ),
+            )
+            .reply_to(msg.message_id())
+            .send()
+            .await?;
+
+        return Ok(());
+    }
+
+    if msg.text().starts_with("!rewind") {
+        if !args.twitch_rewind {
+            client
+                .privmsg(
+                    msg.channel(),
+                    "Rewinding the story is not enabled in this chat.",
+                )
+                .reply_to(msg.message_id())
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        log::info!(
+            "Twitch recieved a rewind {} from {}",
+            msg.text(),
+            msg.sender().name()
+        );
+
+        // Send the rewind to the main loop through mpsc channels
+        tx.send(msg.text().trim().to_string()).await?;
+
+        client
+            .privmsg(
+                msg.channel(),
+                &format!(
+                    "Thank you {}, rewinding the story to take it in a different direction.",
+                    msg.sender().name()
+                ),
+            )
+            .reply_to(msg.message_id())
+            .send()
+            .await?;
+
+        return Ok(());
+    }
+
+    if msg.text().starts_with("!image") {
+        let prompt = msg.text().splitn(2, ' ').nth(1).unwrap_or("").trim();
+
+        let reply = if !args.twitch_image || !args.sd_image {
+            "Image requests are not enabled in this chat.".to_string()
+        } else if let Some(refusal) = tier_refusal(&msg, "!image", &args.twitch_image_tier) {
+            refusal
+        } else if prompt.is_empty() {
+            "To request an image type !image <description>.".to_string()
+        } else {
+            let moderation = Moderation::from_args(&args, args.twitch_image_max_chars);
+            match moderation.check("image", msg.sender().name(), prompt) {
+                Ok(prompt) => {
+                    log::info!(
+                        "Twitch recieved an image request from {}: {}",
+                        msg.sender().name(),
+                        prompt
+                    );
+                    // Send the image request to the main loop through mpsc channels
+                    tx.send(format!("!image {} {}", msg.sender().name(), prompt))
+                        .await?;
+                    format!(
+                        "Thank you {}, your image is coming up next!",
+                        msg.sender().name()
+                    )
+                }
+                Err(reason) => format!(
+                    "Sorry {}, I can't make that image, {}.",
+                    msg.sender().name(),
+                    reason
+                ),
+            }
+        };
+
+        client
+            .privmsg(msg.channel(), &reply)
+            .reply_to(msg.message_id())
+            .send()
+            .await?;
+
+        return Ok(());
+    }
+
+    if msg.text().starts_with("!voice") {
+        let voice = msg.text().splitn(2, ' ').nth(1).unwrap_or("").trim();
+        let voices = allowed_voices(&args);
+
+        let reply = if !args.twitch_tts || !args.mimic3_tts {
+            "Reading messages aloud is not enabled in this chat.".to_string()
+        } else if let Some(refusal) = tier_refusal(&msg, "!voice", &args.twitch_voice_tier) {
+            refusal
+        } else if voice.is_empty() {
+            let current = user_voice(&conn, user_id)?.unwrap_or_else(|| args.mimic3_voice.clone());
+            if voices.is_empty() {
+                format!(
+                    "Your voice is {}, pick another with !voice <name>.",
+                    current
+                )
+            } else {
+                format!(
+                    "Your voice is {}, pick another with !voice <name> from {}.",
+                    current,
+                    voices.join(", ")
+                )
+            }
+        } else if (!voices.is_empty() && !voices.iter().any(|allowed| allowed == voice))
+            || voice.contains(char::is_whitespace)
+        {
+            format!(
+                "Sorry {}, {} is not one of the voices.",
+                msg.sender().name(),
+                voice
+            )
+        } else {
+            set_user_voice(&conn, user_id, voice)?;
+            log::info!("Twitch user {} picked the voice {}", user_id, voice);
+            format!(
+                "Thank you {}, your messages will be read with the {} voice.",
+                msg.sender().name(),
+                voice
+            )
+        };
+
+        client
+            .privmsg(msg.channel(), &reply)
+            .reply_to(msg.message_id())
+            .send()
+            .await?;
+
+        return Ok(());
+    }
+
+    if msg.text().starts_with("!tts") {
+        let text = msg.text().splitn(2, ' ').nth(1).unwrap_or("").trim();
+
+        let reply = if !args.twitch_tts || !args.mimic3_tts {
+            Some("Reading messages aloud is not enabled in this chat.".to_string())
+        } else if let Some(refusal) = tier_refusal(&msg, "!tts", &args.twitch_tts_tier) {
+            Some(refusal)
+        } else if text.is_empty() {
+            Some("To have a message read aloud type !tts <message>.".to_string())
+        } else {
+            let moderation = Moderation::from_args(&args, args.twitch_tts_max_chars);
+            match moderation.check("tts", msg.sender().name(), text) {
+                Ok(text) => {
+                    let voice =
+                        user_voice(&conn, user_id)?.unwrap_or_else(|| args.mimic3_voice.clone());
+                    log::info!(
+                        "Twitch recieved a tts message from {} with voice {}: {}",
+                        msg.sender().name(),
+                        voice,
+                        text
+                    );
+                    // Send the message to be read aloud to the main loop through mpsc channels
+                    tx.send(format!("!tts {} {} {}", msg.sender().name(), voice, text))
+                        .await?;
+                    None
+                }
+                Err(reason) => Some(format!(
+                    "Sorry {}, I can't read that out, {}.",
+                    msg.sender().name(),
+                    reason
+                )),
+            }
+        };
+
+        if let Some(reply) = reply {
+            client
+                .privmsg(msg.channel(), &reply)
+                .reply_to(msg.message_id())
+                .send()
+                .await?;
+        }
+
+        return Ok(());
+    }
+
     std::io::stdout().flush().unwrap();
     log::info!(
         "Twitch recieved a help message from {}",
@@ -412,11 +772,18 @@ async fn on_msg(
     );
     std::io::stdout().flush().unwrap();
 
+    let mut help = "To send a message to Alice type !message Alice <question>. You can switch personas with !persona <name>. You can also conversate with me by free typing in the chat! Enjoy the stories!".to_string();
+    if args.twitch_image {
+        help.push_str(" Request an image with !image <description>.");
+    }
+    if args.twitch_tts {
+        help.push_str(
+            " Have a message read aloud with !tts <message> and pick its voice with !voice <name>.",
+        );
+    }
+
     client
-        .privmsg(
-            msg.channel(),
-            "To send a message to Alice type !message Alice <question>. You can also conversate with me by free typing in the chat! Enjoy the stories!",
-        )
+        .privmsg(msg.channel(), &help)
         .reply_to(msg.message_id())
         .send()
         .await?;
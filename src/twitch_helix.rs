@@ -0,0 +1,266 @@
+/*
+ * twitch_helix.rs
+ * ---------------
+ * Keep the Twitch channel title and category in sync with the show. The stream state is
+ * checked for a new topic or chapter and the channel is updated through the Helix API, no
+ * more often than --twitch-stream-info-interval. Needs TWITCH_CLIENT_ID and a TWITCH_AUTH
+ * token with the channel:manage:broadcast scope.
+*/
+
+use crate::args::Args;
+use crate::event_log::log_event;
+use crate::external_apis::check_url;
+use crate::secrets::secret;
+use crate::stream_state::{stream_state, StreamState};
+use crate::ApiError;
+use log::{error, info};
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const HELIX_URL: &str = "https://api.twitch.tv/helix";
+const VALIDATE_URL: &str = "https://id.twitch.tv/oauth2/validate";
+const TITLE_MAX_CHARS: usize = 140;
+
+// How often the stream state is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Login of a user access token, an error when Twitch does not take it
+pub async fn validate_token(token: &str) -> Result<String, ApiError> {
+    check_url(VALIDATE_URL).map_err(ApiError::Error)?;
+    let response = Client::new()
+        .get(VALIDATE_URL)
+        .header(
+            "Authorization",
+            format!("OAuth {}", token.trim_start_matches("oauth:")),
+        )
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(ApiError::Error(format!(
+            "Twitch did not accept the token: {}",
+            response.status()
+        )));
+    }
+    let body: Value = response.json().await?;
+    Ok(body["login"].as_str().unwrap_or_default().to_string())
+}
+
+pub struct HelixClient {
+    client: Client,
+    client_id: String,
+    token: String,
+}
+
+impl HelixClient {
+    pub fn new(client_id: &str, token: &str) -> Self {
+        HelixClient {
+            client: Client::new(),
+            client_id: client_id.to_string(),
+            token: token.trim_start_matches("oauth:").to_string(),
+        }
+    }
+
+    fn request(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .header("Client-Id", &self.client_id)
+            .bearer_auth(&self.token)
+    }
+
+    async fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Value, ApiError> {
+        let url = format!("{}/{}", HELIX_URL, path);
+        check_url(&url).map_err(ApiError::Error)?;
+        let response = self
+            .request(self.client.get(&url).query(query))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(ApiError::Error(format!(
+                "Helix {} failed {}: {}",
+                path,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )));
+        }
+        Ok(response.json().await?)
+    }
+
+    // First id of the data array, None when nothing matched
+    async fn first_id(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<String>, ApiError> {
+        let value = self.get(path, query).await?;
+        Ok(value["data"][0]["id"].as_str().map(|id| id.to_string()))
+    }
+
+    pub async fn broadcaster_id(&self, login: &str) -> Result<String, ApiError> {
+        self.first_id("users", &[("login", login)])
+            .await?
+            .ok_or_else(|| ApiError::Error(format!("Twitch channel {} not found", login)))
+    }
+
+    pub async fn category_id(&self, name: &str) -> Result<Option<String>, ApiError> {
+        self.first_id("games", &[("name", name)]).await
+    }
+
+    pub async fn update_channel(
+        &self,
+        broadcaster_id: &str,
+        title: &str,
+        category_id: Option<&str>,
+    ) -> Result<(), ApiError> {
+        let url = format!("{}/channels", HELIX_URL);
+        check_url(&url).map_err(ApiError::Error)?;
+        let mut body = json!({ "title": title });
+        if let Some(category_id) = category_id {
+            body["game_id"] = json!(category_id);
+        }
+        let response = self
+            .request(self.client.patch(&url))
+            .query(&[("broadcaster_id", broadcaster_id)])
+            .json(&body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(ApiError::Error(format!(
+                "Helix channel update failed {}: {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Title and category for the stream state, None when there is nothing to title it with
+pub struct StreamInfo {
+    pub template: String,
+    pub categories: Vec<(String, String)>, // keyword and category, * matches anything
+}
+
+impl StreamInfo {
+    pub fn new(template: &str, category_map: &str) -> Self {
+        StreamInfo {
+            template: template.to_string(),
+            categories: category_map
+                .split(',')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(keyword, category)| {
+                    (keyword.trim().to_lowercase(), category.trim().to_string())
+                })
+                .filter(|(keyword, category)| !keyword.is_empty() && !category.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        StreamInfo::new(&args.twitch_title_template, &args.twitch_category_map)
+    }
+
+    // {subject} is the chapter, or the viewer topic before the first chapter
+    pub fn title(&self, state: &StreamState) -> Option<String> {
+        let subject = if state.chapter.is_empty() {
+            &state.topic
+        } else {
+            &state.chapter
+        };
+        if subject.trim().is_empty() {
+            return None;
+        }
+        let title = self
+            .template
+            .replace("{persona}", &state.persona)
+            .replace("{topic}", &state.topic)
+            .replace("{chapter}", &state.chapter)
+            .replace("{subject}", subject);
+        let title = title.split_whitespace().collect::<Vec<&str>>().join(" ");
+        Some(title.chars().take(TITLE_MAX_CHARS).collect())
+    }
+
+    pub fn category(&self, state: &StreamState) -> Option<&str> {
+        let text = format!("{} {}", state.topic, state.chapter).to_lowercase();
+        self.categories
+            .iter()
+            .find(|(keyword, _)| keyword == "*" || text.contains(keyword.as_str()))
+            .map(|(_, category)| category.as_str())
+    }
+}
+
+// Update the channel when the topic or chapter changes, at most once per
+// --twitch-stream-info-interval seconds
+pub fn spawn_stream_info_updater(
+    args: Args,
+    running: Arc<AtomicBool>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let client_id = std::env::var("TWITCH_CLIENT_ID").unwrap_or_default();
+        let token = secret("TWITCH_AUTH");
+        let Some(token) = token.filter(|_| !client_id.is_empty()) else {
+            error!("TWITCH_CLIENT_ID and TWITCH_AUTH are needed to update the stream info");
+            return;
+        };
+        let helix = HelixClient::new(&client_id, token.expose());
+        let broadcaster_id = match helix.broadcaster_id(&args.twitch_channel).await {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Failed to look up the Twitch channel: {}", e);
+                return;
+            }
+        };
+
+        let stream_info = StreamInfo::from_args(&args);
+        let interval = Duration::from_secs(args.twitch_stream_info_interval);
+        let mut last_title = String::new();
+        let mut last_update: Option<Instant> = None;
+        while running.load(Ordering::SeqCst) {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if last_update.is_some_and(|last| last.elapsed() < interval) {
+                continue;
+            }
+            let state = stream_state();
+            let Some(title) = stream_info.title(&state) else {
+                continue;
+            };
+            if title == last_title {
+                continue;
+            }
+
+            let category = stream_info.category(&state);
+            let category_id = match category {
+                Some(name) => match helix.category_id(name).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!("Failed to look up the Twitch category {}: {}", name, e);
+                        None
+                    }
+                },
+                None => None,
+            };
+            match helix
+                .update_channel(&broadcaster_id, &title, category_id.as_deref())
+                .await
+            {
+                Ok(()) => {
+                    info!(
+                        "Twitch stream info updated: {} in {}",
+                        title,
+                        category.unwrap_or("the same category")
+                    );
+                    log_event(
+                        "twitch",
+                        "stream_info",
+                        json!({ "title": title, "category": category }),
+                    );
+                    last_title = title;
+                }
+                Err(e) => error!("Failed to update the Twitch stream info: {}", e),
+            }
+            // failed updates wait for the interval too instead of retrying every poll
+            last_update = Some(Instant::now());
+        }
+    })
+}
@@ -0,0 +1,199 @@
+/*
+ * whisper.rs
+ * ----------
+ * Speech to text with the candle Whisper model. Audio is taken as 16 kHz mono samples of up
+ * to 30 seconds, turned into a log mel spectrogram and decoded greedily without timestamps.
+ * Audio the model judges to have no speech gives an empty transcript.
+*/
+
+use anyhow::{anyhow, Error as E, Result};
+use candle_core::{Device, IndexOp, Tensor, D};
+use candle_nn::{ops::softmax, VarBuilder};
+use candle_transformers::models::whisper::{
+    self as m, audio, model::Whisper as WhisperModel, Config,
+};
+use log::{debug, info};
+use tokenizers::Tokenizer;
+
+pub const WHISPER_SAMPLE_RATE: u32 = m::SAMPLE_RATE as u32;
+// Samples of audio in each frame of the mel spectrogram
+pub const MEL_HOP_LENGTH: usize = m::HOP_LENGTH;
+
+pub struct Whisper {
+    model: WhisperModel,
+    tokenizer: Tokenizer,
+    config: Config,
+    device: Device,
+    mel_filters: Vec<f32>,
+    prompt: Vec<u32>, // start of transcript, language and task tokens
+    eot_token: u32,
+    no_speech_token: Option<u32>,
+    suppress_tokens: Tensor,
+}
+
+fn token_id(tokenizer: &Tokenizer, token: &str) -> Result<u32> {
+    tokenizer
+        .token_to_id(token)
+        .ok_or_else(|| anyhow!("No token {} in the Whisper tokenizer", token))
+}
+
+fn hz_to_mel(hz: f64) -> f64 {
+    // linear below 1 kHz and logarithmic above, the Slaney mel scale
+    let log_step = 6.4f64.ln() / 27.0;
+    if hz < 1000.0 {
+        hz * 3.0 / 200.0
+    } else {
+        15.0 + (hz / 1000.0).ln() / log_step
+    }
+}
+
+fn mel_to_hz(mel: f64) -> f64 {
+    let log_step = 6.4f64.ln() / 27.0;
+    if mel < 15.0 {
+        mel * 200.0 / 3.0
+    } else {
+        1000.0 * ((mel - 15.0) * log_step).exp()
+    }
+}
+
+// Triangular mel filterbank of n_mels rows over the FFT bins, normalized to equal area like
+// the filters the model was trained with
+pub fn mel_filters(n_mels: usize) -> Vec<f32> {
+    let n_bins = m::N_FFT / 2 + 1;
+    let nyquist = m::SAMPLE_RATE as f64 / 2.0;
+    let max_mel = hz_to_mel(nyquist);
+    let points: Vec<f64> = (0..n_mels + 2)
+        .map(|i| mel_to_hz(max_mel * i as f64 / (n_mels + 1) as f64))
+        .collect();
+    let mut filters = vec![0f32; n_mels * n_bins];
+    for mel in 0..n_mels {
+        let (low, center, high) = (points[mel], points[mel + 1], points[mel + 2]);
+        let norm = 2.0 / (high - low);
+        for bin in 0..n_bins {
+            let hz = bin as f64 * nyquist / (n_bins - 1) as f64;
+            let rising = (hz - low) / (center - low);
+            let falling = (high - hz) / (high - center);
+            filters[mel * n_bins + bin] = (rising.min(falling).max(0.0) * norm) as f32;
+        }
+    }
+    filters
+}
+
+impl Whisper {
+    // Load the model from a hub repo like openai/whisper-tiny.en, the language token is only
+    // used by the multilingual models
+    pub fn load(model_id: &str, language: &str, cpu: bool) -> Result<Self> {
+        use candle_hf_hub::{api::sync::Api, Repo, RepoType};
+
+        let device = candle_examples::device(cpu)?;
+        let repo = Api::new()?.repo(Repo::new(model_id.to_string(), RepoType::Model));
+        let config: Config =
+            serde_json::from_str(&std::fs::read_to_string(repo.get("config.json")?)?)?;
+        let tokenizer = Tokenizer::from_file(repo.get("tokenizer.json")?).map_err(E::msg)?;
+        let weights = repo.get("model.safetensors")?;
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], m::DTYPE, &device)? };
+        let model = WhisperModel::load(&vb, config.clone())?;
+
+        let mut prompt = vec![token_id(&tokenizer, m::SOT_TOKEN)?];
+        if !model_id.ends_with(".en") && !language.is_empty() {
+            prompt.push(token_id(&tokenizer, &format!("<|{}|>", language))?);
+        }
+        prompt.push(token_id(&tokenizer, m::TRANSCRIBE_TOKEN)?);
+        prompt.push(token_id(&tokenizer, m::NO_TIMESTAMPS_TOKEN)?);
+        let eot_token = token_id(&tokenizer, m::EOT_TOKEN)?;
+        let no_speech_token = m::NO_SPEECH_TOKENS
+            .iter()
+            .find_map(|token| tokenizer.token_to_id(token));
+
+        let suppress: Vec<f32> = (0..config.vocab_size as u32)
+            .map(|token| {
+                if config.suppress_tokens.contains(&token) {
+                    f32::NEG_INFINITY
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let suppress_tokens = Tensor::new(suppress.as_slice(), &device)?;
+        let mel_filters = mel_filters(config.num_mel_bins);
+        info!("Whisper model {} loaded", model_id);
+
+        Ok(Whisper {
+            model,
+            tokenizer,
+            config,
+            device,
+            mel_filters,
+            prompt,
+            eot_token,
+            no_speech_token,
+            suppress_tokens,
+        })
+    }
+
+    pub fn num_mel_bins(&self) -> usize {
+        self.config.num_mel_bins
+    }
+
+    // Log mel spectrogram of 16 kHz mono audio in rows of mel bands, padded with silent frames
+    // after the 10 ms frames of the audio
+    pub fn log_mel(&self, samples: &[f32]) -> Vec<f32> {
+        audio::pcm_to_mel(&self.config, samples, &self.mel_filters)
+    }
+
+    // Transcript of 16 kHz mono audio, longer audio than the 30 seconds the model hears is cut
+    pub fn transcribe(&mut self, samples: &[f32]) -> Result<String> {
+        let samples = &samples[..samples.len().min(m::N_SAMPLES)];
+        if samples.is_empty() {
+            return Ok(String::new());
+        }
+        let mel = self.log_mel(samples);
+        let frames = mel.len() / self.config.num_mel_bins;
+        let mel = Tensor::from_vec(mel, (1, self.config.num_mel_bins, frames), &self.device)?;
+        let audio_features = self.model.encoder.forward(&mel, true)?;
+
+        let mut tokens = self.prompt.clone();
+        let max_tokens = self.config.max_target_positions / 2;
+        for step in 0..max_tokens {
+            let tokens_t = Tensor::new(tokens.as_slice(), &self.device)?.unsqueeze(0)?;
+            let ys = self
+                .model
+                .decoder
+                .forward(&tokens_t, &audio_features, step == 0)?;
+
+            if step == 0 {
+                if let Some(no_speech_token) = self.no_speech_token {
+                    let logits = self.model.decoder.final_linear(&ys.i(..1)?)?.i(0)?.i(0)?;
+                    let no_speech = softmax(&logits, D::Minus1)?
+                        .i(no_speech_token as usize)?
+                        .to_scalar::<f32>()?;
+                    if no_speech as f64 > m::NO_SPEECH_THRESHOLD {
+                        debug!("Whisper heard no speech, probability {:.2}", no_speech);
+                        return Ok(String::new());
+                    }
+                }
+            }
+
+            let (_, seq_len, _) = ys.dims3()?;
+            let logits = self
+                .model
+                .decoder
+                .final_linear(&ys.i((..1, seq_len - 1..))?)?
+                .i(0)?
+                .i(0)?;
+            let logits = logits.broadcast_add(&self.suppress_tokens)?;
+            let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
+            if next_token == self.eot_token {
+                break;
+            }
+            tokens.push(next_token);
+        }
+        self.model.reset_kv_cache();
+
+        let text = self
+            .tokenizer
+            .decode(&tokens[self.prompt.len()..], true)
+            .map_err(E::msg)?;
+        Ok(text.trim().to_string())
+    }
+}